@@ -147,6 +147,7 @@ find %{?buildroot}/%{_docdir} ! -type d -printf '%{_docdir}/%%P\n' > bootcdoclis
 %{_prefix}/libexec/libostree/ext/*
 %endif
 %{_unitdir}/*
+%{_prefix}/lib/systemd/catalog/bootc.catalog
 %{_mandir}/man*/bootc*
 
 %files -n system-reinstall-bootc
@@ -0,0 +1,35 @@
+//! Record the git commit bootc was built from, if known.
+
+use std::process::Command;
+
+/// Run git, returning its trimmed output on success.
+fn git(args: &[&str]) -> Option<String> {
+    let o = Command::new("git").args(args).output().ok()?;
+    if !o.status.success() {
+        return None;
+    }
+    let s = String::from_utf8(o.stdout).ok()?;
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_owned())
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=BOOTC_GIT_COMMIT");
+    // Allow packaging (e.g. from a source tarball) to provide this.
+    let commit = std::env::var("BOOTC_GIT_COMMIT")
+        .ok()
+        .or_else(|| git(&["rev-parse", "HEAD"]));
+    let Some(commit) = commit else {
+        return;
+    };
+    println!("cargo:rustc-env=BOOTC_GIT_COMMIT={commit}");
+    // Rebuild when a new commit is checked out or made.
+    let head = git(&["symbolic-ref", "-q", "HEAD"]);
+    for name in ["HEAD", "packed-refs"].into_iter().chain(head.as_deref()) {
+        // Missing files would cause the build script to always be rerun.
+        let p = git(&["rev-parse", "--git-path", name]);
+        if let Some(p) = p.filter(|p| std::path::Path::new(p).exists()) {
+            println!("cargo:rerun-if-changed={p}");
+        }
+    }
+}
@@ -11,13 +11,17 @@ use ostree_ext::ostree::Deployment;
 use ostree_ext::prelude::Cast;
 use ostree_ext::prelude::FileEnumeratorExt;
 use ostree_ext::prelude::FileExt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
 
 use crate::deploy::ImageState;
+use crate::kernel_cmdline::{CmdlineBuilder, Parameter};
 use crate::store::Storage;
 
 /// The relative path to the kernel arguments which may be embedded in an image.
 const KARGS_PATH: &str = "usr/lib/bootc/kargs.d";
+/// The relative path to Unified Kernel Images which may be embedded in an image.
+const UKI_PATH: &str = "boot/EFI/Linux";
 
 /// The kargs.d configuration file.
 #[derive(Deserialize)]
@@ -28,6 +32,102 @@ struct Config {
     /// Optional list of architectures (using the Rust naming conventions);
     /// if present and the current architecture doesn't match, the file is skipped.
     match_architectures: Option<Vec<String>>,
+    /// Optional list of CPU vendors, as in the `vendor_id` of `/proc/cpuinfo`
+    /// (e.g. `GenuineIntel` or `AuthenticAMD`).
+    match_cpu_vendor: Option<Vec<String>>,
+    /// Optional list of DMI product names (`/sys/class/dmi/id/product_name`),
+    /// in which `*` matches any sequence of characters.
+    match_dmi_product: Option<Vec<String>>,
+    /// Optional list of virtualization technologies as detected by
+    /// `systemd-detect-virt --vm` (e.g. `kvm`), where `none` matches bare metal.
+    match_virtualization: Option<Vec<String>>,
+}
+
+/// Properties of the system which kargs.d files can be matched against.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct HostProperties {
+    /// The architecture, using the Rust naming conventions
+    arch: String,
+    cpu_vendor: Option<String>,
+    dmi_product: Option<String>,
+    virtualization: Option<String>,
+}
+
+impl HostProperties {
+    /// Properties with only the architecture known; files with hardware matches
+    /// will not match.
+    pub(crate) fn for_arch(arch: &str) -> Self {
+        Self {
+            arch: arch.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Query the properties of the running system.
+    pub(crate) fn query() -> Self {
+        let cpu_vendor = std::fs::read_to_string("/proc/cpuinfo")
+            .ok()
+            .and_then(|s| parse_cpu_vendor(&s));
+        let dmi_product = std::fs::read_to_string("/sys/class/dmi/id/product_name")
+            .ok()
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty());
+        // This exits with an error when running on bare metal, but still prints `none`
+        let virtualization = Command::new("systemd-detect-virt")
+            .arg("--vm")
+            .output()
+            .ok()
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_owned())
+            .filter(|s| !s.is_empty());
+        let r = Self {
+            arch: std::env::consts::ARCH.to_owned(),
+            cpu_vendor,
+            dmi_product,
+            virtualization,
+        };
+        tracing::debug!("Host properties for kargs.d: {r:?}");
+        r
+    }
+
+    /// Properties for an installation: those of the running system, unless
+    /// installing a generic image which will be booted elsewhere (e.g. a disk
+    /// image written via loopback), in which case hardware matches are skipped.
+    pub(crate) fn for_install(generic_image: bool) -> Self {
+        if generic_image {
+            Self::for_arch(std::env::consts::ARCH)
+        } else {
+            Self::query()
+        }
+    }
+}
+
+/// Find the CPU vendor in the contents of `/proc/cpuinfo`.
+fn parse_cpu_vendor(cpuinfo: &str) -> Option<String> {
+    cpuinfo.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == "vendor_id").then(|| v.trim().to_owned())
+    })
+}
+
+/// Match a string against a pattern in which `*` matches any sequence of characters.
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // SAFETY: split always returns at least one element
+    let first = parts.next().unwrap();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        let Some(idx) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[idx + part.len()..];
+    }
+    rest.ends_with(last)
 }
 
 impl Config {
@@ -35,11 +135,38 @@ impl Config {
     fn filename_matches(name: &str) -> bool {
         matches!(Utf8Path::new(name).extension(), Some("toml"))
     }
+
+    /// Return true if the kargs apply to the host; every match which is
+    /// present must include the corresponding property of the host.
+    fn host_matches(&self, host: &HostProperties) -> bool {
+        fn matches(
+            values: &Option<Vec<String>>,
+            prop: Option<&str>,
+            f: impl Fn(&str, &str) -> bool,
+        ) -> bool {
+            values.as_ref().map_or(true, |values| {
+                prop.is_some_and(|prop| values.iter().any(|v| f(v, prop)))
+            })
+        }
+        let eq = |a: &str, b: &str| a == b;
+        matches(&self.match_architectures, Some(&host.arch), eq)
+            && matches(&self.match_cpu_vendor, host.cpu_vendor.as_deref(), eq)
+            && matches(
+                &self.match_dmi_product,
+                host.dmi_product.as_deref(),
+                glob_matches,
+            )
+            && matches(
+                &self.match_virtualization,
+                host.virtualization.as_deref(),
+                eq,
+            )
+    }
 }
 
 /// Load and parse all bootc kargs.d files in the specified root, returning
 /// a combined list.
-pub(crate) fn get_kargs_in_root(d: &Dir, sys_arch: &str) -> Result<Vec<String>> {
+pub(crate) fn get_kargs_in_root(d: &Dir, host: &HostProperties) -> Result<Vec<String>> {
     // If the directory doesn't exist, that's OK.
     let Some(d) = d.open_dir_optional(KARGS_PATH)?.map(DirUtf8::from_cap_std) else {
         return Ok(Default::default());
@@ -48,7 +175,7 @@ pub(crate) fn get_kargs_in_root(d: &Dir, sys_arch: &str) -> Result<Vec<String>>
     let entries = d.filenames_filtered_sorted(|_, name| Config::filename_matches(name))?;
     for name in entries {
         let buf = d.read_to_string(&name)?;
-        let kargs = parse_kargs_toml(&buf, sys_arch).with_context(|| format!("Parsing {name}"))?;
+        let kargs = parse_kargs_toml(&buf, host).with_context(|| format!("Parsing {name}"))?;
         ret.extend(kargs)
     }
     Ok(ret)
@@ -58,21 +185,21 @@ pub(crate) fn get_kargs_in_root(d: &Dir, sys_arch: &str) -> Result<Vec<String>>
 pub(crate) fn get_kargs_from_ostree_root(
     repo: &ostree::Repo,
     root: &ostree::RepoFile,
-    sys_arch: &str,
+    host: &HostProperties,
 ) -> Result<Vec<String>> {
     let kargsd = root.resolve_relative_path(KARGS_PATH);
     let kargsd = kargsd.downcast_ref::<ostree::RepoFile>().expect("downcast");
     if !kargsd.query_exists(gio::Cancellable::NONE) {
         return Ok(Default::default());
     }
-    get_kargs_from_ostree(repo, kargsd, sys_arch)
+    get_kargs_from_ostree(repo, kargsd, host)
 }
 
 /// Load kargs.d files from the target dir
 fn get_kargs_from_ostree(
     repo: &ostree::Repo,
     fetched_tree: &ostree::RepoFile,
-    sys_arch: &str,
+    host: &HostProperties,
 ) -> Result<Vec<String>> {
     let cancellable = gio::Cancellable::NONE;
     let queryattrs = "standard::name,standard::type";
@@ -100,13 +227,20 @@ fn get_kargs_from_ostree(
         let mut reader =
             ostree_ext::prelude::InputStreamExtManual::into_read(file_content.unwrap());
         let s = std::io::read_to_string(&mut reader)?;
-        let parsed_kargs =
-            parse_kargs_toml(&s, sys_arch).with_context(|| format!("Parsing {name}"))?;
+        let parsed_kargs = parse_kargs_toml(&s, host).with_context(|| format!("Parsing {name}"))?;
         ret.extend(parsed_kargs);
     }
     Ok(ret)
 }
 
+/// Return the kernel arguments of a deployment from its bootloader configuration.
+pub(crate) fn deployment_kargs(deployment: &Deployment) -> Vec<String> {
+    ostree::Deployment::bootconfig(deployment)
+        .and_then(|bootconfig| ostree::BootconfigParser::get(&bootconfig, "options"))
+        .map(|options| options.split_whitespace().map(|s| s.to_owned()).collect())
+        .unwrap_or_default()
+}
+
 /// Compute the kernel arguments for the new deployment. This starts from the booted
 /// karg, but applies the diff between the bootc karg files in /usr/lib/bootc/kargs.d
 /// between the booted deployment and the new one.
@@ -117,20 +251,14 @@ pub(crate) fn get_kargs(
 ) -> Result<Vec<String>> {
     let cancellable = gio::Cancellable::NONE;
     let repo = &sysroot.repo();
-    let mut kargs = vec![];
-    let sys_arch = std::env::consts::ARCH;
+    let host = &HostProperties::query();
 
     // Get the kargs used for the merge in the bootloader config
-    if let Some(bootconfig) = ostree::Deployment::bootconfig(merge_deployment) {
-        if let Some(options) = ostree::BootconfigParser::get(&bootconfig, "options") {
-            let options = options.split_whitespace().map(|s| s.to_owned());
-            kargs.extend(options);
-        }
-    };
+    let kargs = deployment_kargs(merge_deployment);
 
     // Get the kargs in kargs.d of the merge
     let merge_root = &crate::utils::deployment_fd(sysroot, merge_deployment)?;
-    let existing_kargs = get_kargs_in_root(merge_root, sys_arch)?;
+    let existing_kargs = get_kargs_in_root(merge_root, host)?;
 
     // Get the kargs in kargs.d of the pending image
     let (fetched_tree, _) = repo.read_commit(fetched.ostree_commit.as_str(), cancellable)?;
@@ -141,50 +269,213 @@ pub(crate) fn get_kargs(
     // A special case: if there's no kargs.d directory in the pending (fetched) image,
     // then we can just use the combined current kargs + kargs from booted
     if !fetched_tree.query_exists(cancellable) {
-        kargs.extend(existing_kargs);
-        return Ok(kargs);
+        let mut merged = CmdlineBuilder::new();
+        merged.extend(&kargs).extend(&existing_kargs);
+        return Ok(merged.into_vec());
     }
 
     // Fetch the kernel arguments from the new root
-    let remote_kargs = get_kargs_from_ostree(repo, &fetched_tree, sys_arch)?;
+    let remote_kargs = get_kargs_from_ostree(repo, &fetched_tree, host)?;
 
     // get the diff between the existing and remote kargs
-    let mut added_kargs = remote_kargs
-        .clone()
-        .into_iter()
-        .filter(|item| !existing_kargs.contains(item))
-        .collect::<Vec<_>>();
-    let removed_kargs = existing_kargs
-        .clone()
-        .into_iter()
-        .filter(|item| !remote_kargs.contains(item))
-        .collect::<Vec<_>>();
-
+    let delta = KargsDelta::new(&existing_kargs, &remote_kargs);
     tracing::debug!(
         "kargs: added={:?} removed={:?}",
-        &added_kargs,
-        removed_kargs
+        &delta.added,
+        &delta.removed
     );
 
-    // apply the diff to the system kargs
-    kargs.retain(|x| !removed_kargs.contains(x));
-    kargs.append(&mut added_kargs);
+    // apply the diff to the system kargs; the added kargs take precedence
+    Ok(delta.apply(&kargs))
+}
+
+/// The difference between two lists of kernel arguments.
+#[derive(Debug, Default, Clone, Serialize, PartialEq, Eq)]
+pub(crate) struct KargsDelta {
+    pub(crate) added: Vec<String>,
+    pub(crate) removed: Vec<String>,
+}
+
+impl KargsDelta {
+    /// Compute the arguments added and removed when going from `from` to `to`.
+    pub(crate) fn new(from: &[String], to: &[String]) -> Self {
+        let added = to.iter().filter(|k| !from.contains(k)).cloned().collect();
+        let removed = from.iter().filter(|k| !to.contains(k)).cloned().collect();
+        Self { added, removed }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Apply this difference to `kargs`; the added arguments take precedence.
+    pub(crate) fn apply(&self, kargs: &[String]) -> Vec<String> {
+        let mut merged = CmdlineBuilder::new();
+        merged
+            .extend(kargs.iter().filter(|x| !self.removed.contains(x)))
+            .extend(&self.added);
+        merged.into_vec()
+    }
+}
+
+/// Append kernel arguments, skipping any which are already present.
+pub(crate) fn append_kargs(kargs: &mut Vec<String>, new: &[String]) {
+    for k in new {
+        if !kargs.contains(k) {
+            kargs.push(k.clone());
+        }
+    }
+}
+
+/// Delete kernel arguments. An argument with a value (`key=value`) must match
+/// exactly; an argument without a value removes all arguments with that key.
+/// It is an error if an argument to delete is not present.
+pub(crate) fn delete_kargs(kargs: &mut Vec<String>, del: &[String]) -> Result<()> {
+    for d in del {
+        let target = Parameter::from(d.as_str());
+        let orig_len = kargs.len();
+        kargs.retain(|k| {
+            if target.value.is_some() {
+                k != d
+            } else {
+                Parameter::from(k.as_str()).key != target.key
+            }
+        });
+        if kargs.len() == orig_len {
+            anyhow::bail!("Kernel argument not found: {d}");
+        }
+    }
+    Ok(())
+}
+
+/// Parse kernel arguments as edited by the user; one per line, with blank lines
+/// and lines starting with `#` ignored.
+pub(crate) fn parse_edited_kargs(s: &str) -> Vec<String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Print the changes between two lists of kernel arguments, returning
+/// whether there were any.
+pub(crate) fn print_kargs_diff(
+    mut out: impl std::io::Write,
+    old: &[String],
+    new: &[String],
+) -> Result<bool> {
+    let mut changed = false;
+    for k in old.iter().filter(|k| !new.contains(k)) {
+        writeln!(out, "- {k}")?;
+        changed = true;
+    }
+    for k in new.iter().filter(|k| !old.contains(k)) {
+        writeln!(out, "+ {k}")?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Returns true if the root ships a Unified Kernel Image, in which case the
+/// kernel arguments are embedded in (and signed as part of) the UKI.
+pub(crate) fn root_has_uki(root: &Dir) -> Result<bool> {
+    let Some(d) = root.open_dir_optional(UKI_PATH)? else {
+        return Ok(false);
+    };
+    for ent in d.entries()? {
+        let name = ent?.file_name();
+        if Utf8Path::new(&name.to_string_lossy()).extension() == Some("efi") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Set kernel arguments, replacing any existing arguments with the same key;
+/// arguments without a value are appended if not present.
+pub(crate) fn replace_kargs(kargs: &mut Vec<String>, new: &[String]) {
+    for k in new {
+        let param = Parameter::from(k.as_str());
+        if param.value.is_some() {
+            kargs.retain(|existing| Parameter::from(existing.as_str()).key != param.key);
+        }
+        if !kargs.contains(k) {
+            kargs.push(k.clone());
+        }
+    }
+}
 
-    Ok(kargs)
+/// The kernel arguments which may be applied live: each is equivalent to a runtime
+/// setting, and maps to the file (relative to `/`) to which its value is written.
+const LIVE_KARGS: &[(&str, &str)] = &[
+    ("loglevel", "proc/sys/kernel/printk"),
+    ("panic", "proc/sys/kernel/panic"),
+    ("nmi_watchdog", "proc/sys/kernel/nmi_watchdog"),
+    (
+        "transparent_hugepage",
+        "sys/kernel/mm/transparent_hugepage/enabled",
+    ),
+    (
+        "sysctl.kernel.panic_on_oops",
+        "proc/sys/kernel/panic_on_oops",
+    ),
+    ("sysctl.vm.swappiness", "proc/sys/vm/swappiness"),
+];
+
+/// The runtime setting equivalent to a kernel argument in [`LIVE_KARGS`], as the
+/// path of the file relative to `/` and the value to write.
+fn live_setting(k: &str) -> Option<(&'static str, &str)> {
+    let (key, value) = k.split_once('=')?;
+    LIVE_KARGS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, path)| (*path, value))
+}
+
+/// Apply kernel arguments which have a writable runtime equivalent (see
+/// [`LIVE_KARGS`]) in `root` (i.e. `/`) to the running system.
+/// Anything else would require a reboot; such arguments are returned.
+pub(crate) fn apply_kargs_live(root: &Dir, kargs: &[String]) -> Result<Vec<String>> {
+    use cap_std_ext::cap_std::fs::PermissionsExt;
+    use std::io::Write;
+
+    let mut unapplied = Vec::new();
+    for k in kargs {
+        let Some((path, value)) = live_setting(k) else {
+            unapplied.push(k.clone());
+            continue;
+        };
+        let writable = root
+            .metadata_optional(path)?
+            .filter(|m| m.is_file() && m.permissions().mode() & 0o200 != 0)
+            .is_some();
+        if !writable {
+            unapplied.push(k.clone());
+            continue;
+        }
+        let mut f = root.open_with(
+            path,
+            cap_std_ext::cap_std::fs::OpenOptions::new().write(true),
+        )?;
+        f.write_all(value.as_bytes())
+            .with_context(|| format!("Writing {path}"))?;
+    }
+    Ok(unapplied)
 }
 
 /// This parses a bootc kargs.d toml file, returning the resulting
-/// vector of kernel arguments. Architecture matching is performed using
-/// `sys_arch`.
-fn parse_kargs_toml(contents: &str, sys_arch: &str) -> Result<Vec<String>> {
+/// vector of kernel arguments. Architecture and hardware matching is
+/// performed using `host`.
+fn parse_kargs_toml(contents: &str, host: &HostProperties) -> Result<Vec<String>> {
     let de: Config = toml::from_str(contents)?;
-    // if arch specified, apply kargs only if the arch matches
-    // if arch not specified, apply kargs unconditionally
-    let matched = de
-        .match_architectures
-        .map(|arches| arches.iter().any(|s| s == sys_arch))
-        .unwrap_or(true);
-    let r = if matched { de.kargs } else { Vec::new() };
+    // if matches are specified, apply kargs only if they all match
+    // if not, apply kargs unconditionally
+    let r = if de.host_matches(host) {
+        de.kargs
+    } else {
+        Vec::new()
+    };
     Ok(r)
 }
 
@@ -202,10 +493,12 @@ mod tests {
         // no arch specified, kargs ensure that kargs are applied unconditionally
         let sys_arch = "x86_64";
         let file_content = r##"kargs = ["console=tty0", "nosmt"]"##.to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap();
+        let parsed_kargs =
+            parse_kargs_toml(&file_content, &HostProperties::for_arch(sys_arch)).unwrap();
         assert_eq!(parsed_kargs, ["console=tty0", "nosmt"]);
         let sys_arch = "aarch64";
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap();
+        let parsed_kargs =
+            parse_kargs_toml(&file_content, &HostProperties::for_arch(sys_arch)).unwrap();
         assert_eq!(parsed_kargs, ["console=tty0", "nosmt"]);
 
         // one arch matches and one doesn't, ensure that kargs are only applied for the matching arch
@@ -214,13 +507,15 @@ mod tests {
 match-architectures = ["x86_64"]
 "##
         .to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap();
+        let parsed_kargs =
+            parse_kargs_toml(&file_content, &HostProperties::for_arch(sys_arch)).unwrap();
         assert_eq!(parsed_kargs, [] as [String; 0]);
         let file_content = r##"kargs = ["console=tty0", "nosmt"]
 match-architectures = ["aarch64"]
 "##
         .to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap();
+        let parsed_kargs =
+            parse_kargs_toml(&file_content, &HostProperties::for_arch(sys_arch)).unwrap();
         assert_eq!(parsed_kargs, ["console=tty0", "nosmt"]);
 
         // multiple arch specified, ensure that kargs are applied to both archs
@@ -229,21 +524,228 @@ match-architectures = ["aarch64"]
 match-architectures = ["x86_64", "aarch64"]
 "##
         .to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap();
+        let parsed_kargs =
+            parse_kargs_toml(&file_content, &HostProperties::for_arch(sys_arch)).unwrap();
         assert_eq!(parsed_kargs, ["console=tty0", "nosmt"]);
         let sys_arch = "aarch64";
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap();
+        let parsed_kargs =
+            parse_kargs_toml(&file_content, &HostProperties::for_arch(sys_arch)).unwrap();
         assert_eq!(parsed_kargs, ["console=tty0", "nosmt"]);
     }
 
+    #[test]
+    /// Verify that kargs are only applied to matching hardware
+    fn test_hardware_match() {
+        let host = HostProperties {
+            arch: "x86_64".into(),
+            cpu_vendor: Some("GenuineIntel".into()),
+            dmi_product: Some("ThinkPad X1 Carbon Gen 9".into()),
+            virtualization: Some("none".into()),
+        };
+        let vm = HostProperties {
+            virtualization: Some("kvm".into()),
+            dmi_product: Some("Standard PC (Q35 + ICH9, 2009)".into()),
+            ..host.clone()
+        };
+        let parse = |contents: &str, host: &HostProperties| -> Vec<String> {
+            parse_kargs_toml(contents, host).unwrap()
+        };
+
+        let contents = indoc::indoc! { r#"
+            kargs = ["intel_iommu=on"]
+            match-cpu-vendor = ["GenuineIntel"]
+        "# };
+        assert_eq!(parse(contents, &host), ["intel_iommu=on"]);
+        assert!(parse(contents, &HostProperties::for_arch("x86_64")).is_empty());
+
+        let contents = indoc::indoc! { r#"
+            kargs = ["i915.enable_psr=0"]
+            match-dmi-product = ["ThinkPad X1*", "Latitude *"]
+            match-virtualization = ["none"]
+        "# };
+        assert_eq!(parse(contents, &host), ["i915.enable_psr=0"]);
+        assert!(parse(contents, &vm).is_empty());
+
+        let contents = indoc::indoc! { r#"
+            kargs = ["console=ttyS0"]
+            match-architectures = ["aarch64"]
+            match-virtualization = ["kvm", "qemu"]
+        "# };
+        assert!(parse(contents, &vm).is_empty());
+        let vm = HostProperties {
+            arch: "aarch64".into(),
+            ..vm
+        };
+        assert_eq!(parse(contents, &vm), ["console=ttyS0"]);
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("ThinkPad X1", "ThinkPad X1"));
+        assert!(!glob_matches("ThinkPad X1", "ThinkPad X1 Carbon"));
+        assert!(glob_matches("ThinkPad*", "ThinkPad X1"));
+        assert!(glob_matches("*X1*", "ThinkPad X1 Carbon"));
+        assert!(glob_matches("a*b*c", "abc"));
+        assert!(!glob_matches("a*a", "a"));
+        assert!(glob_matches("*", ""));
+    }
+
+    #[test]
+    fn test_parse_cpu_vendor() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: AuthenticAMD\ncpu family\t: 25\n";
+        assert_eq!(parse_cpu_vendor(cpuinfo).as_deref(), Some("AuthenticAMD"));
+        assert_eq!(parse_cpu_vendor("processor\t: 0\n"), None);
+    }
+
     #[test]
     /// Verify some error cases
     fn test_invalid() {
         let test_invalid_extra = r#"kargs = ["console=tty0", "nosmt"]\nfoo=bar"#;
-        assert!(parse_kargs_toml(test_invalid_extra, "x86_64").is_err());
+        assert!(parse_kargs_toml(test_invalid_extra, &HostProperties::for_arch("x86_64")).is_err());
 
         let test_missing = r#"foo=bar"#;
-        assert!(parse_kargs_toml(test_missing, "x86_64").is_err());
+        assert!(parse_kargs_toml(test_missing, &HostProperties::for_arch("x86_64")).is_err());
+    }
+
+    #[test]
+    fn test_edit_kargs() -> Result<()> {
+        let v = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut kargs = v(&["root=UUID=abc", "console=tty0", "console=ttyS0", "quiet"]);
+        append_kargs(&mut kargs, &v(&["quiet", "nosmt"]));
+        assert_eq!(
+            kargs,
+            [
+                "root=UUID=abc",
+                "console=tty0",
+                "console=ttyS0",
+                "quiet",
+                "nosmt"
+            ]
+        );
+        delete_kargs(&mut kargs, &v(&["console=ttyS0"]))?;
+        assert_eq!(kargs, ["root=UUID=abc", "console=tty0", "quiet", "nosmt"]);
+        append_kargs(&mut kargs, &v(&["console=ttyS1"]));
+        delete_kargs(&mut kargs, &v(&["console"]))?;
+        assert_eq!(kargs, ["root=UUID=abc", "quiet", "nosmt"]);
+        assert!(delete_kargs(&mut kargs, &v(&["console"])).is_err());
+
+        assert_eq!(
+            parse_edited_kargs("# comment\nquiet\n\n  nosmt  \nfoo=\"a b\"\n"),
+            ["quiet", "nosmt", "foo=\"a b\""]
+        );
+
+        let mut out = Vec::new();
+        let changed = print_kargs_diff(&mut out, &v(&["a", "b"]), &v(&["a", "c"]))?;
+        assert!(changed);
+        assert_eq!(String::from_utf8(out)?, "- b\n+ c\n");
+        assert!(!print_kargs_diff(std::io::sink(), &v(&["a"]), &v(&["a"]))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kargs_delta() {
+        let v = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let delta = KargsDelta::new(&v(&["rw", "console=ttyS0"]), &v(&["rw", "quiet"]));
+        assert_eq!(delta.added, ["quiet"]);
+        assert_eq!(delta.removed, ["console=ttyS0"]);
+        assert!(!delta.is_empty());
+        assert_eq!(
+            delta.apply(&v(&["root=UUID=abc", "rw", "console=ttyS0", "nosmt"])),
+            ["root=UUID=abc", "rw", "nosmt", "quiet"]
+        );
+        assert!(KargsDelta::new(&v(&["rw"]), &v(&["rw"])).is_empty());
+    }
+
+    #[test]
+    fn test_root_has_uki() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(!root_has_uki(&td)?);
+        td.create_dir_all(UKI_PATH)?;
+        td.write(format!("{UKI_PATH}/README"), "")?;
+        assert!(!root_has_uki(&td)?);
+        td.write(format!("{UKI_PATH}/linux.efi"), "")?;
+        assert!(root_has_uki(&td)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_kargs_live() -> Result<()> {
+        use cap_std_ext::cap_std::fs::{Permissions, PermissionsExt};
+
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("sys/module/foo_bar/parameters")?;
+        td.write("sys/module/foo_bar/parameters/enable", "N")?;
+        td.create_dir_all("proc/sys/vm")?;
+        td.write("proc/sys/vm/swappiness", "60")?;
+        td.write("proc/sys/vm/overcommit_memory", "0")?;
+        td.create_dir_all("proc/sys/kernel")?;
+        td.write("proc/sys/kernel/printk", "4\t4\t1\t7")?;
+        td.write("proc/sys/kernel/panic_on_oops", "0")?;
+        td.set_permissions(
+            "proc/sys/kernel/panic_on_oops",
+            Permissions::from_mode(0o444),
+        )?;
+        let kargs = [
+            "foo-bar.enable=Y",
+            "quiet",
+            "sysctl.vm.swappiness=10",
+            "sysctl.vm.overcommit_memory=1",
+            "sysctl.kernel.panic_on_oops=1",
+            "loglevel=7",
+        ]
+        .map(ToOwned::to_owned);
+        let unapplied = apply_kargs_live(&td, &kargs)?;
+        assert_eq!(
+            unapplied,
+            [
+                "foo-bar.enable=Y",
+                "quiet",
+                "sysctl.vm.overcommit_memory=1",
+                "sysctl.kernel.panic_on_oops=1"
+            ]
+        );
+        // Only allowlisted settings are written
+        assert_eq!(
+            td.read_to_string("sys/module/foo_bar/parameters/enable")?,
+            "N"
+        );
+        assert_eq!(td.read_to_string("proc/sys/vm/overcommit_memory")?, "0");
+        assert_eq!(td.read_to_string("proc/sys/vm/swappiness")?, "10");
+        assert!(td
+            .read_to_string("proc/sys/kernel/printk")?
+            .starts_with('7'));
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_setting() {
+        assert_eq!(
+            live_setting("sysctl.vm.swappiness=1"),
+            Some(("proc/sys/vm/swappiness", "1"))
+        );
+        assert_eq!(
+            live_setting("transparent_hugepage=madvise"),
+            Some(("sys/kernel/mm/transparent_hugepage/enabled", "madvise"))
+        );
+        assert_eq!(live_setting("sysctl.net.ipv4.ip_forward=1"), None);
+        assert_eq!(live_setting("foo.bar=1"), None);
+        assert_eq!(live_setting("quiet"), None);
+        assert_eq!(live_setting("console=ttyS0"), None);
+    }
+
+    #[test]
+    fn test_replace_kargs() {
+        let mut kargs = ["quiet", "sysctl.vm.swappiness=60", "console=tty0"]
+            .map(ToOwned::to_owned)
+            .to_vec();
+        replace_kargs(
+            &mut kargs,
+            &["sysctl.vm.swappiness=10", "quiet", "nosmt"].map(ToOwned::to_owned),
+        );
+        assert_eq!(
+            kargs,
+            ["quiet", "console=tty0", "sysctl.vm.swappiness=10", "nosmt"]
+        );
     }
 
     #[context("writing test kargs")]
@@ -265,17 +767,32 @@ match-architectures = ["x86_64", "aarch64"]
         let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
 
         // No directory
-        assert_eq!(get_kargs_in_root(&td, "x86_64").unwrap().len(), 0);
+        assert_eq!(
+            get_kargs_in_root(&td, &HostProperties::for_arch("x86_64"))
+                .unwrap()
+                .len(),
+            0
+        );
         // Empty directory
         td.create_dir_all("usr/lib/bootc/kargs.d")?;
-        assert_eq!(get_kargs_in_root(&td, "x86_64").unwrap().len(), 0);
+        assert_eq!(
+            get_kargs_in_root(&td, &HostProperties::for_arch("x86_64"))
+                .unwrap()
+                .len(),
+            0
+        );
         // Non-toml file
         td.write("usr/lib/bootc/kargs.d/somegarbage", "garbage")?;
-        assert_eq!(get_kargs_in_root(&td, "x86_64").unwrap().len(), 0);
+        assert_eq!(
+            get_kargs_in_root(&td, &HostProperties::for_arch("x86_64"))
+                .unwrap()
+                .len(),
+            0
+        );
 
         write_test_kargs(&td)?;
 
-        let args = get_kargs_in_root(&td, "x86_64").unwrap();
+        let args = get_kargs_in_root(&td, &HostProperties::for_arch("x86_64")).unwrap();
         similar_asserts::assert_eq!(args, ["console=tty0", "nosmt", "console=ttyS1"]);
 
         Ok(())
@@ -344,7 +861,7 @@ match-architectures = ["x86_64", "aarch64"]
             if !fetched_tree.query_exists(cancellable) {
                 return Ok(Default::default());
             }
-            get_kargs_from_ostree(repo, &fetched_tree, sys_arch)
+            get_kargs_from_ostree(repo, &fetched_tree, &HostProperties::for_arch(sys_arch))
         };
 
         // rootfs is empty
@@ -0,0 +1,234 @@
+//! # Boot counting
+//!
+//! A newly staged deployment gets a limited number of boot attempts. Once the
+//! system reaches `boot-complete.target`, `bootc boot-success` (run by
+//! `bootc-boot-success.service`) marks the boot as good; if no attempt gets
+//! there, the bootloader falls back to the previous deployment, and
+//! `bootc boot-success` then makes it the default again, as with `bootc rollback`.
+//!
+//! - systemd-boot: A new default entry in the ESP gets a boot counter, see
+//!   [`crate::bootloader::sync_systemd_boot_entries`]; it is blessed by
+//!   `systemd-bless-boot.service`.
+//! - GRUB: With `boot-counting = true` in `[install.grub]`, the `user.cfg`
+//!   written at installation decrements `bootc_boot_counter` in the GRUB
+//!   environment block on each boot, and boots the second entry (the previous
+//!   deployment) once it reaches zero.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::{Dir, OpenOptions};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::bootloader::{BOOT_TRIES, SYSTEMD_BOOT_STAMP};
+use crate::spec::BootOrder;
+use crate::store::Storage;
+
+/// The GRUB environment block, relative to the boot filesystem
+const GRUBENV: &str = "grub2/grubenv";
+/// The GRUB `user.cfg`, relative to the boot filesystem
+const GRUB_USER_CFG: &str = "grub2/user.cfg";
+/// The GRUB environment variable holding the boot attempts left
+const GRUB_COUNTER: &str = "bootc_boot_counter";
+const GRUBENV_HEADER: &str = "# GRUB Environment Block\n";
+/// The environment block is a fixed size, padded with `#`
+const GRUBENV_SIZE: usize = 1024;
+
+/// Appended to the GRUB `user.cfg` with `boot-counting = true`.
+pub(crate) const GRUB_COUNTING_CFG: &str = r#"# Boot counting; see `bootc boot-success`
+if [ -n "${bootc_boot_counter}" ]; then
+  if [ "${bootc_boot_counter}" = "0" -o "${bootc_boot_counter}" = "-1" ]; then
+    set default=1
+    set bootc_boot_counter=-1
+  else
+    insmod increment
+    decrement bootc_boot_counter
+  fi
+  save_env bootc_boot_counter
+fi
+"#;
+
+/// The variables of a GRUB environment block.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct GrubEnv {
+    vars: Vec<(String, String)>,
+}
+
+impl GrubEnv {
+    fn parse(buf: &str) -> Result<Self> {
+        let body = buf
+            .strip_prefix(GRUBENV_HEADER)
+            .ok_or_else(|| anyhow::anyhow!("Invalid GRUB environment block"))?;
+        let vars = body
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .filter_map(|l| l.split_once('='))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        Ok(Self { vars })
+    }
+
+    fn get(&self, k: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(name, _)| name == k)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn set(&mut self, k: &str, v: &str) {
+        match self.vars.iter_mut().find(|(name, _)| name == k) {
+            Some((_, value)) => *value = v.to_owned(),
+            None => self.vars.push((k.to_owned(), v.to_owned())),
+        }
+    }
+
+    /// Remove a variable, returning whether it was set.
+    fn unset(&mut self, k: &str) -> bool {
+        let n = self.vars.len();
+        self.vars.retain(|(name, _)| name != k);
+        n != self.vars.len()
+    }
+
+    fn serialize(&self) -> Result<String> {
+        let mut r = GRUBENV_HEADER.to_owned();
+        for (k, v) in self.vars.iter() {
+            r.push_str(&format!("{k}={v}\n"));
+        }
+        anyhow::ensure!(
+            r.len() <= GRUBENV_SIZE,
+            "GRUB environment block exceeds {GRUBENV_SIZE} bytes"
+        );
+        r.push_str(&"#".repeat(GRUBENV_SIZE - r.len()));
+        Ok(r)
+    }
+}
+
+/// Whether the GRUB configuration in the boot filesystem counts boot attempts.
+fn grub_counting_enabled(boot: &Dir) -> Result<bool> {
+    let Some(f) = boot.open_optional(GRUB_USER_CFG)? else {
+        return Ok(false);
+    };
+    let cfg = std::io::read_to_string(f)?;
+    Ok(cfg.contains(GRUB_COUNTER) && boot.try_exists(GRUBENV)?)
+}
+
+fn read_grubenv(boot: &Dir) -> Result<GrubEnv> {
+    GrubEnv::parse(&boot.read_to_string(GRUBENV)?).with_context(|| format!("Parsing {GRUBENV}"))
+}
+
+/// Update the GRUB environment block in place, like GRUB itself does, since
+/// it cannot follow a newly allocated file.
+fn write_grubenv(boot: &Dir, env: &GrubEnv) -> Result<()> {
+    let buf = env.serialize()?;
+    let mut f = boot
+        .open_with(GRUBENV, OpenOptions::new().write(true))
+        .with_context(|| format!("Opening {GRUBENV}"))?;
+    f.write_all(buf.as_bytes())?;
+    f.sync_all()?;
+    Ok(())
+}
+
+/// Give a newly staged deployment a limited number of boot attempts.
+#[context("Arming boot counter")]
+pub(crate) fn arm(physical_root: &Dir) -> Result<()> {
+    // The ESP is synchronized at shutdown, which adds the boot counter
+    if physical_root.try_exists(SYSTEMD_BOOT_STAMP)? {
+        return Ok(());
+    }
+    let boot = &crate::bootloader::open_boot(physical_root)?;
+    if !grub_counting_enabled(boot)? {
+        tracing::debug!("Boot counting is not enabled");
+        return Ok(());
+    }
+    let mut env = read_grubenv(boot)?;
+    env.set(GRUB_COUNTER, &BOOT_TRIES.to_string());
+    write_grubenv(boot, &env)
+}
+
+/// Implementation of `bootc boot-success`: disarm the boot counter, and if the
+/// bootloader fell back from a deployment which failed to boot, make the booted
+/// deployment the default.
+#[context("Marking boot as successful")]
+pub(crate) async fn boot_success(sysroot: &Storage) -> Result<()> {
+    let physical_root = &sysroot.physical_root;
+    let boot = &crate::bootloader::open_boot(physical_root)?;
+    let grubenv = grub_counting_enabled(boot)?
+        .then(|| read_grubenv(boot))
+        .transpose()?;
+    let fell_back = grubenv
+        .as_ref()
+        .is_some_and(|env| env.get(GRUB_COUNTER) == Some("-1"))
+        || crate::bootloader::systemd_boot_fell_back(physical_root)?;
+    if fell_back {
+        let (_, _, host) = crate::status::get_status_require_booted(sysroot)?;
+        // The default is the failed deployment until we make the booted one the default
+        if host.spec.boot_order == BootOrder::Rollback {
+            let msg =
+                "The default deployment failed to boot; making the booted deployment the default";
+            crate::journal::journal_send(
+                libsystemd::logging::Priority::Warning,
+                msg,
+                [("MESSAGE_ID", crate::journal::BOOT_FALLBACK_ID)].into_iter(),
+            );
+            println!("{msg}");
+            crate::deploy::rollback(sysroot).await?;
+        }
+    }
+    if let Some(mut env) = grubenv {
+        if env.unset(GRUB_COUNTER) {
+            write_grubenv(boot, &env)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn test_grubenv() -> Result<()> {
+        let buf = format!(
+            "{GRUBENV_HEADER}saved_entry=ostree-1\nboot_success=1\n{}",
+            "#".repeat(900)
+        );
+        let mut env = GrubEnv::parse(&buf)?;
+        assert_eq!(env.get("saved_entry"), Some("ostree-1"));
+        assert_eq!(env.get(GRUB_COUNTER), None);
+        env.set(GRUB_COUNTER, "3");
+        env.set("boot_success", "0");
+        let out = env.serialize()?;
+        assert_eq!(out.len(), GRUBENV_SIZE);
+        assert!(out.starts_with(&format!(
+            "{GRUBENV_HEADER}saved_entry=ostree-1\nboot_success=0\nbootc_boot_counter=3\n#"
+        )));
+        assert_eq!(GrubEnv::parse(&out)?, env);
+        assert!(env.unset(GRUB_COUNTER));
+        assert!(!env.unset(GRUB_COUNTER));
+        assert!(GrubEnv::parse("foo=bar\n").is_err());
+        env.set("big", &"x".repeat(GRUBENV_SIZE));
+        assert!(env.serialize().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_grub_counting() -> Result<()> {
+        let boot = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(!grub_counting_enabled(boot)?);
+        boot.create_dir_all("grub2")?;
+        boot.write(GRUBENV, GrubEnv::default().serialize()?)?;
+        boot.write(GRUB_USER_CFG, "set timeout=3\n")?;
+        assert!(!grub_counting_enabled(boot)?);
+        boot.write(GRUB_USER_CFG, format!("set timeout=3\n{GRUB_COUNTING_CFG}"))?;
+        assert!(grub_counting_enabled(boot)?);
+
+        let mut env = read_grubenv(boot)?;
+        env.set(GRUB_COUNTER, "3");
+        write_grubenv(boot, &env)?;
+        assert_eq!(boot.metadata(GRUBENV)?.len(), GRUBENV_SIZE as u64);
+        assert_eq!(read_grubenv(boot)?.get(GRUB_COUNTER), Some("3"));
+        Ok(())
+    }
+}
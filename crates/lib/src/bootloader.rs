@@ -1,16 +1,205 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
 use std::process::Command;
 
 use anyhow::{anyhow, bail, Context, Result};
 use bootc_utils::CommandRunExt;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::PermissionsExt;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
+use serde::Serialize;
 
-use bootc_blockdev::PartitionTable;
+use bootc_blockdev::{Partition, PartitionTable};
 use bootc_mount as mount;
 
+use crate::install::config::GrubConfig;
+
 /// The name of the mountpoint for efi (as a subdirectory of /boot, or at the toplevel)
 pub(crate) const EFI_DIR: &str = "efi";
 
+/// Where bootupd looks for the update payloads in the source root.
+const BOOTUPD_UPDATES: &str = "usr/lib/bootupd/updates";
+/// If present in the source root, bootupd will install static GRUB configs.
+const BOOTUPD_GRUB_STATIC: &str = "usr/lib/bootupd/grub2-static";
+
+/// Sourced by both the traditional and the bootupd static GRUB configurations,
+/// after the defaults are set and before the menu entries are generated.
+const GRUB_USER_CFG: &str = "boot/grub2/user.cfg";
+/// The default GRUB superuser, as used by `grub2-setpassword`.
+const GRUB_DEFAULT_SUPERUSER: &str = "root";
+
+/// The GPT partition type of the EFI System Partition
+const ESP_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+/// Where we mount the ESP on a booted system if it isn't mounted already
+const ESP_MOUNT: &str = "/run/bootc/esp";
+/// Where mirrors of the ESP are mounted while they are synchronized
+const ESP_MIRROR_MOUNT: &str = "/run/bootc/esp-mirror";
+/// The partition UUIDs of ESPs on other disks which mirror the primary one,
+/// one per line, relative to the physical root.
+pub(crate) const ESP_MIRRORS: &str = "ostree/bootc/esp-mirrors";
+/// If present in the physical root, systemd-boot is in use and the boot
+/// entries need to be synchronized into the ESP.
+pub(crate) const SYSTEMD_BOOT_STAMP: &str = "ostree/bootc/systemd-boot";
+/// The prefix for boot entries written by ostree
+const OSTREE_ENTRY_PREFIX: &str = "ostree-";
+const LOADER_ENTRIES: &str = "loader/entries";
+const LOADER_CONF: &str = "loader/loader.conf";
+/// Kernels and initramfs images live here, in both /boot and the ESP
+const OSTREE_BOOT_DIR: &str = "ostree";
+/// Boot attempts for a new deployment before the bootloader considers it bad and
+/// falls back to the next one; see <https://systemd.io/AUTOMATIC_BOOT_ASSESSMENT/>.
+pub(crate) const BOOT_TRIES: u32 = 3;
+
+/// The bootloader installation mechanism.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BootloaderBackend {
+    Bootupd,
+    Zipl,
+    SystemdBoot,
+}
+
+/// A description of all changes that installing the bootloader will make.
+/// This is computed without mutating anything; [`execute_plan`] then
+/// just runs the commands. Installation only adds or overwrites files, it
+/// never removes any.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BootloaderPlan {
+    pub(crate) backend: BootloaderBackend,
+    /// The target block device
+    pub(crate) device: Utf8PathBuf,
+    /// Files (or directories, ending in `/`) which will be written; relative
+    /// paths are relative to the target root
+    pub(crate) writes: Vec<Utf8PathBuf>,
+    /// GRUB configuration and environment files which will change
+    pub(crate) grub_config: Vec<Utf8PathBuf>,
+    /// BLS entries which are used to configure the bootloader
+    pub(crate) bls_entries: Vec<Utf8PathBuf>,
+    /// EFI variables which may be modified
+    pub(crate) efivars: Vec<String>,
+    /// The commands which will be executed, in order
+    pub(crate) commands: Vec<Vec<String>>,
+}
+
+impl BootloaderPlan {
+    fn new(backend: BootloaderBackend, device: &Utf8Path) -> Self {
+        Self {
+            backend,
+            device: device.to_owned(),
+            writes: Default::default(),
+            grub_config: Default::default(),
+            bls_entries: Default::default(),
+            efivars: Default::default(),
+            commands: Default::default(),
+        }
+    }
+}
+
+/// Recursively gather the regular files in `dir`, relative to `base`.
+fn collect_files(dir: &Utf8Path, base: &Utf8Path, out: &mut Vec<Utf8PathBuf>) -> Result<()> {
+    for e in dir.read_dir_utf8()? {
+        let e = e?;
+        let ty = e.file_type()?;
+        if ty.is_dir() {
+            collect_files(e.path(), base, out)?;
+        } else {
+            // SAFETY: We're iterating from base
+            out.push(e.path().strip_prefix(base).unwrap().to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Compute the changes that `bootupctl backend install` will make.
+#[context("Computing bootupd plan")]
+pub(crate) fn plan_bootupd(
+    device: &Utf8Path,
+    rootfs: &Utf8Path,
+    srcroot: &Utf8Path,
+    generic_image: bool,
+) -> Result<BootloaderPlan> {
+    let verbose = std::env::var_os("BOOTC_BOOTLOADER_DEBUG").map(|_| "-vvvv");
+    // bootc defaults to only targeting the platform boot method.
+    let bootupd_opts = (!generic_image).then_some(["--update-firmware", "--auto"]);
+
+    let mut plan = BootloaderPlan::new(BootloaderBackend::Bootupd, device);
+    let efi_target = Utf8Path::new("boot").join(EFI_DIR);
+    let updates = srcroot.join(BOOTUPD_UPDATES);
+    let efi_updates = updates.join("EFI");
+    let mut vendors = Vec::new();
+    if efi_updates.try_exists()? {
+        let mut files = Vec::new();
+        collect_files(&efi_updates, &updates, &mut files)?;
+        files.sort();
+        plan.writes
+            .extend(files.into_iter().map(|f| efi_target.join(f)));
+        for e in efi_updates.read_dir_utf8()? {
+            let e = e?;
+            if e.file_type()?.is_dir() && e.file_name() != "BOOT" {
+                vendors.push(e.file_name().to_owned());
+            }
+        }
+        vendors.sort();
+        if !generic_image {
+            plan.efivars
+                .extend(["BootOrder", "Boot####"].into_iter().map(ToOwned::to_owned));
+        }
+    }
+    if updates.join("BIOS.json").try_exists()? {
+        plan.writes.push("boot/grub2/i386-pc/".into());
+    }
+    if srcroot.join(BOOTUPD_GRUB_STATIC).try_exists()? {
+        plan.grub_config
+            .extend(["boot/grub2/grub.cfg", "boot/grub2/bootuuid.cfg"].map(Utf8PathBuf::from));
+        for vendor in vendors {
+            let vendordir = efi_target.join("EFI").join(vendor);
+            plan.grub_config.push(vendordir.join("grub.cfg"));
+            plan.grub_config.push(vendordir.join("bootuuid.cfg"));
+        }
+    }
+    plan.writes.push("boot/bootupd-state.json".into());
+
+    let cmd = ["bootupctl", "backend", "install", "--write-uuid"]
+        .into_iter()
+        .chain(verbose)
+        .chain(bootupd_opts.iter().copied().flatten())
+        .chain(["--src-root", srcroot.as_str()])
+        .chain(["--device", device.as_str(), rootfs.as_str()])
+        .map(ToOwned::to_owned)
+        .collect();
+    plan.commands.push(cmd);
+    Ok(plan)
+}
+
+/// Run the commands in a plan with the provided executor.
+fn execute_plan_with(
+    plan: &BootloaderPlan,
+    mut run: impl FnMut(&[String]) -> Result<()>,
+) -> Result<()> {
+    for cmd in plan.commands.iter() {
+        run(cmd)?;
+    }
+    Ok(())
+}
+
+/// Apply a previously computed bootloader plan.
+#[context("Executing bootloader plan")]
+pub(crate) fn execute_plan(plan: &BootloaderPlan) -> Result<()> {
+    execute_plan_with(plan, |cmd| {
+        let (bin, args) = cmd
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty command in plan"))?;
+        Command::new(bin)
+            .args(args)
+            .log_debug()
+            .run_inherited_with_cmd_context()
+    })
+}
+
 #[context("Installing bootloader")]
 pub(crate) fn install_via_bootupd(
     device: &PartitionTable,
@@ -18,33 +207,53 @@ pub(crate) fn install_via_bootupd(
     configopts: &crate::install::InstallConfigOpts,
     deployment_path: &str,
 ) -> Result<()> {
-    let verbose = std::env::var_os("BOOTC_BOOTLOADER_DEBUG").map(|_| "-vvvv");
-    // bootc defaults to only targeting the platform boot method.
-    let bootupd_opts = (!configopts.generic_image).then_some(["--update-firmware", "--auto"]);
-
     let srcroot = rootfs.join(deployment_path);
-    let devpath = device.path();
+    let plan = plan_bootupd(device.path(), rootfs, &srcroot, configopts.generic_image)?;
     println!("Installing bootloader via bootupd");
-    Command::new("bootupctl")
-        .args(["backend", "install", "--write-uuid"])
-        .args(verbose)
-        .args(bootupd_opts.iter().copied().flatten())
-        .args(["--src-root", srcroot.as_str()])
-        .args(["--device", devpath.as_str(), rootfs.as_str()])
-        .log_debug()
-        .run_inherited_with_cmd_context()
+    execute_plan(&plan)
 }
 
-#[context("Installing bootloader using zipl")]
-pub(crate) fn install_via_zipl(device: &PartitionTable, boot_uuid: &str) -> Result<()> {
+/// The relevant keys parsed from a BLS config.
+#[derive(Debug, PartialEq, Eq)]
+struct BlsBootEntry<'a> {
+    kernel: &'a str,
+    initrd: &'a str,
+    options: &'a str,
+}
+
+fn parse_bls_for_zipl(bls_conf: &str) -> Result<BlsBootEntry<'_>> {
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut options = None;
+
+    for line in bls_conf.lines() {
+        match line.split_once(char::is_whitespace) {
+            Some(("linux", val)) => kernel = Some(val.trim().trim_start_matches('/')),
+            Some(("initrd", val)) => initrd = Some(val.trim().trim_start_matches('/')),
+            Some(("options", val)) => options = Some(val.trim()),
+            _ => (),
+        }
+    }
+
+    let kernel = kernel.ok_or_else(|| anyhow!("missing 'linux' key in default BLS config"))?;
+    let initrd = initrd.ok_or_else(|| anyhow!("missing 'initrd' key in default BLS config"))?;
+    let options = options.ok_or_else(|| anyhow!("missing 'options' key in default BLS config"))?;
+    Ok(BlsBootEntry {
+        kernel,
+        initrd,
+        options,
+    })
+}
+
+/// Compute the changes that running `zipl` will make.
+#[context("Computing zipl plan")]
+pub(crate) fn plan_zipl(device_path: &Utf8Path, boot_uuid: &str) -> Result<BootloaderPlan> {
     // Identify the target boot partition from UUID
     let fs = mount::inspect_filesystem_by_uuid(boot_uuid)?;
     let boot_dir = Utf8Path::new(&fs.target);
     let maj_min = fs.maj_min;
 
     // Ensure that the found partition is a part of the target device
-    let device_path = device.path();
-
     let partitions = bootc_blockdev::list_dev(device_path)?
         .children
         .with_context(|| format!("no partition found on {device_path}"))?;
@@ -76,39 +285,916 @@ pub(crate) fn install_via_zipl(device: &PartitionTable, boot_uuid: &str) -> Resu
     let bls_path = bls_dir.join(bls_entry);
     let bls_conf =
         std::fs::read_to_string(&bls_path).with_context(|| format!("reading {bls_path}"))?;
+    let entry = parse_bls_for_zipl(&bls_conf)?;
 
-    let mut kernel = None;
-    let mut initrd = None;
-    let mut options = None;
+    let image = boot_dir.join(entry.kernel).canonicalize_utf8()?;
+    let ramdisk = boot_dir.join(entry.initrd).canonicalize_utf8()?;
 
-    for line in bls_conf.lines() {
+    let mut plan = BootloaderPlan::new(BootloaderBackend::Zipl, device_path);
+    plan.bls_entries.push(bls_path);
+    plan.writes.push(boot_dir.join("bootmap"));
+    let offset = boot_part_offset.to_string();
+    let cmd = [
+        "zipl",
+        "--target",
+        boot_dir.as_str(),
+        "--image",
+        image.as_str(),
+        "--ramdisk",
+        ramdisk.as_str(),
+        "--parameters",
+        entry.options,
+        "--targetbase",
+        device_path.as_str(),
+        "--targettype",
+        "SCSI",
+        "--targetblocksize",
+        "512",
+        "--targetoffset",
+        offset.as_str(),
+        "--add-files",
+        "--verbose",
+    ]
+    .map(ToOwned::to_owned)
+    .into();
+    plan.commands.push(cmd);
+    Ok(plan)
+}
+
+#[context("Installing bootloader using zipl")]
+pub(crate) fn install_via_zipl(device: &PartitionTable, boot_uuid: &str) -> Result<()> {
+    let plan = plan_zipl(device.path(), boot_uuid)?;
+    // Execute the zipl command to install bootloader
+    println!("Running zipl on {}", plan.device);
+    execute_plan(&plan)
+}
+
+/// Find the EFI System Partition in a partition table.
+fn find_esp(device: &PartitionTable) -> Option<&Partition> {
+    device
+        .partitions
+        .iter()
+        .find(|p| p.parttype.eq_ignore_ascii_case(ESP_GUID))
+}
+
+/// Run the provided function with the ESP device mounted at `target`, unless
+/// something is already mounted there.
+fn with_esp_mounted<T>(
+    esp: &Utf8Path,
+    target: &Utf8Path,
+    f: impl FnOnce(&Dir) -> Result<T>,
+) -> Result<T> {
+    let mounted = mount::inspect_filesystem(target).is_ok();
+    if !mounted {
+        std::fs::create_dir_all(target)?;
+        mount::mount(esp.as_str(), target)?;
+    }
+    let r = Dir::open_ambient_dir(target, cap_std::ambient_authority())
+        .map_err(Into::into)
+        .and_then(|d| f(&d));
+    if !mounted {
+        Command::new("umount")
+            .arg(target.as_str())
+            .run_capture_stderr()?;
+    }
+    r
+}
+
+/// Compute the changes that `bootctl install` will make. The ESP must be
+/// mounted at `esp`.
+#[context("Computing systemd-boot plan")]
+pub(crate) fn plan_systemd_boot(
+    device: &Utf8Path,
+    esp: &Utf8Path,
+    generic_image: bool,
+) -> Result<BootloaderPlan> {
+    let mut plan = BootloaderPlan::new(BootloaderBackend::SystemdBoot, device);
+    let esp_target = Utf8Path::new("boot").join(EFI_DIR);
+    plan.writes
+        .extend(["EFI/systemd/", "EFI/BOOT/", "loader/", "ostree/"].map(|p| esp_target.join(p)));
+    plan.bls_entries.push(esp_target.join(LOADER_ENTRIES));
+    if !generic_image {
+        plan.efivars
+            .extend(["BootOrder", "Boot####"].into_iter().map(ToOwned::to_owned));
+    }
+    let cmd = ["bootctl", "install", "--esp-path", esp.as_str()]
+        .into_iter()
+        .chain(generic_image.then_some("--no-variables"))
+        .map(ToOwned::to_owned)
+        .collect();
+    plan.commands.push(cmd);
+    Ok(plan)
+}
+
+/// A boot entry written by ostree, rewritten for the ESP.
+#[derive(Debug, PartialEq, Eq)]
+struct EspEntry {
+    /// The entry contents, with paths relative to the ESP
+    contents: String,
+    /// The kernel and initramfs files, relative to the boot filesystem
+    files: Vec<String>,
+    /// ostree gives the default deployment the highest version
+    version: u64,
+}
+
+/// Map a `linux` or `initrd` path written by ostree to a path relative
+/// to the boot filesystem; if /boot is not a separate filesystem, the
+/// paths start with `/boot`.
+fn boot_relative(p: &str) -> &str {
+    let p = p.trim_start_matches('/');
+    p.strip_prefix("boot/").unwrap_or(p)
+}
+
+fn parse_entry_for_esp(conf: &str) -> Result<EspEntry> {
+    let mut contents = String::new();
+    let mut files = Vec::new();
+    let mut version = 0;
+    let mut has_kernel = false;
+    for line in conf.lines() {
         match line.split_once(char::is_whitespace) {
-            Some(("linux", val)) => kernel = Some(val.trim().trim_start_matches('/')),
-            Some(("initrd", val)) => initrd = Some(val.trim().trim_start_matches('/')),
-            Some(("options", val)) => options = Some(val.trim()),
-            _ => (),
+            Some((k @ ("linux" | "initrd"), v)) => {
+                has_kernel |= k == "linux";
+                let p = boot_relative(v.trim());
+                writeln!(contents, "{k} /{p}")?;
+                files.push(p.to_owned());
+            }
+            Some(("version", v)) => {
+                version = v.trim().parse().unwrap_or_default();
+                writeln!(contents, "{line}")?;
+            }
+            _ => writeln!(contents, "{line}")?,
         }
     }
+    anyhow::ensure!(has_kernel, "missing 'linux' key in BLS config");
+    Ok(EspEntry {
+        contents,
+        files,
+        version,
+    })
+}
 
-    let kernel = kernel.ok_or_else(|| anyhow!("missing 'linux' key in default BLS config"))?;
-    let initrd = initrd.ok_or_else(|| anyhow!("missing 'initrd' key in default BLS config"))?;
-    let options = options.ok_or_else(|| anyhow!("missing 'options' key in default BLS config"))?;
+/// The id of an entry file name, i.e. without the boot counter (`+LEFT` or `+LEFT-DONE`).
+fn entry_id(name: &str) -> Cow<'_, str> {
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    let Some((id, counter)) = name
+        .strip_suffix(".conf")
+        .and_then(|stem| stem.rsplit_once('+'))
+    else {
+        return Cow::Borrowed(name);
+    };
+    let (left, done) = counter.split_once('-').unwrap_or((counter, "0"));
+    if is_number(left) && is_number(done) {
+        Cow::Owned(format!("{id}.conf"))
+    } else {
+        Cow::Borrowed(name)
+    }
+}
 
-    let image = boot_dir.join(kernel).canonicalize_utf8()?;
-    let ramdisk = boot_dir.join(initrd).canonicalize_utf8()?;
+/// The boot attempts left for an entry file name with a boot counter (`+LEFT` or `+LEFT-DONE`).
+fn tries_left(name: &str) -> Option<u32> {
+    if entry_id(name) == name {
+        return None;
+    }
+    let (_, counter) = name.strip_suffix(".conf")?.rsplit_once('+')?;
+    let left = counter.split_once('-').map_or(counter, |(left, _)| left);
+    left.parse().ok()
+}
 
-    // Execute the zipl command to install bootloader
-    println!("Running zipl on {device_path}");
-    Command::new("zipl")
-        .args(["--target", boot_dir.as_str()])
-        .args(["--image", image.as_str()])
-        .args(["--ramdisk", ramdisk.as_str()])
-        .args(["--parameters", options])
-        .args(["--targetbase", device_path.as_str()])
-        .args(["--targettype", "SCSI"])
-        .args(["--targetblocksize", "512"])
-        .args(["--targetoffset", &boot_part_offset.to_string()])
-        .args(["--add-files", "--verbose"])
-        .log_debug()
-        .run_inherited_with_cmd_context()
+/// Whether a boot entry in the ESP ran out of boot attempts, i.e. systemd-boot
+/// considers it bad and falls back to another entry.
+fn has_exhausted_entry(esp: &Dir) -> Result<bool> {
+    let Some(entries) = esp.open_dir_optional(LOADER_ENTRIES)? else {
+        return Ok(false);
+    };
+    for ent in entries.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with(OSTREE_ENTRY_PREFIX) && tries_left(name) == Some(0) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Replace the `default` key in a systemd-boot `loader.conf`, preserving other settings.
+fn update_loader_conf(conf: &str, default: &str) -> String {
+    let mut r = conf
+        .lines()
+        .filter(|l| l.split_whitespace().next() != Some("default"))
+        .fold(String::new(), |mut r, l| {
+            r.push_str(l);
+            r.push('\n');
+            r
+        });
+    r.push_str(&format!("default {default}\n"));
+    r
+}
+
+/// Mirror the boot entries written by ostree in `boot` (the boot filesystem) into
+/// `esp`, along with the kernels and initramfs images they reference, since
+/// systemd-boot only reads the ESP. The entry for the default deployment becomes
+/// the default, and entries and files which are no longer referenced are removed.
+///
+/// The existing entries and files stay in place until the new ones are written
+/// and flushed, so that an interruption at any point leaves a bootable ESP. A new
+/// default entry replacing a previous one gets a boot counter, so that systemd-boot
+/// falls back to the previous deployment if it never boots successfully.
+#[context("Synchronizing systemd-boot entries")]
+pub(crate) fn sync_systemd_boot_entries(boot: &Dir, esp: &Dir) -> Result<()> {
+    let mut entries = Vec::new();
+    for ent in boot.read_dir(LOADER_ENTRIES)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !(name.starts_with(OSTREE_ENTRY_PREFIX) && name.ends_with(".conf")) {
+            continue;
+        }
+        let conf = boot.read_to_string(format!("{LOADER_ENTRIES}/{name}"))?;
+        let entry = parse_entry_for_esp(&conf).with_context(|| format!("Parsing {name}"))?;
+        entries.push((name.to_owned(), entry));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let Some((default, _)) = entries.iter().max_by_key(|(_, e)| e.version) else {
+        bail!("No boot entries found in {LOADER_ENTRIES}");
+    };
+
+    let mut referenced = HashSet::new();
+    for f in entries.iter().flat_map(|(_, e)| e.files.iter()) {
+        let mut components = Utf8Path::new(f).components();
+        if let (Some(a), Some(b)) = (components.next(), components.next()) {
+            if a.as_str() == OSTREE_BOOT_DIR {
+                referenced.insert(b.as_str().to_owned());
+            }
+        }
+        let size = boot
+            .metadata(f)
+            .with_context(|| format!("Querying {f}"))?
+            .len();
+        // Kernel directories are named by checksum, so an existing file with the
+        // same size is the same file.
+        if esp.metadata_optional(f)?.is_some_and(|m| m.len() == size) {
+            continue;
+        }
+        if let Some(parent) = Utf8Path::new(f).parent() {
+            esp.create_dir_all(parent)?;
+        }
+        let mut src = boot.open(f)?;
+        esp.atomic_replace_with(f, |w| std::io::copy(&mut src, w))
+            .with_context(|| format!("Copying {f}"))?;
+        let copied = esp.metadata(f)?.len();
+        if copied != size {
+            bail!("Copying {f}: wrote {copied} bytes, expected {size}");
+        }
+    }
+
+    // The current entries, by id, with their file name and contents
+    esp.create_dir_all(LOADER_ENTRIES)?;
+    let mut existing = HashMap::new();
+    for ent in esp.read_dir(LOADER_ENTRIES)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with(OSTREE_ENTRY_PREFIX) {
+            let contents = esp.read_to_string(format!("{LOADER_ENTRIES}/{name}"))?;
+            existing.insert(entry_id(name).into_owned(), (name.to_owned(), contents));
+        }
+    }
+    let mut written = HashSet::new();
+    for (name, entry) in entries.iter() {
+        let filename = match existing.get(name) {
+            // Keep the boot counter of an unchanged entry
+            Some((filename, contents)) if *contents == entry.contents => filename.clone(),
+            Some(_) | None if name == default && !existing.is_empty() => {
+                let stem = name.trim_end_matches(".conf");
+                format!("{stem}+{BOOT_TRIES}.conf")
+            }
+            _ => name.clone(),
+        };
+        esp.atomic_write(format!("{LOADER_ENTRIES}/{filename}"), &entry.contents)?;
+        written.insert(filename);
+    }
+    // Flush the new files before making their entry the default
+    rustix::fs::syncfs(esp).context("syncfs")?;
+    let loader_conf = esp.read_to_string(LOADER_CONF).or_else(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Ok(String::new())
+        } else {
+            Err(e)
+        }
+    })?;
+    esp.atomic_write(LOADER_CONF, update_loader_conf(&loader_conf, default))?;
+    rustix::fs::syncfs(esp).context("syncfs")?;
+
+    for ent in esp.read_dir(LOADER_ENTRIES)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with(OSTREE_ENTRY_PREFIX) && !written.contains(name) {
+            esp.remove_file(format!("{LOADER_ENTRIES}/{name}"))?;
+        }
+    }
+    if let Some(d) = esp.open_dir_optional(OSTREE_BOOT_DIR)? {
+        for ent in d.entries()? {
+            let ent = ent?;
+            let name = ent.file_name();
+            if name.to_str().is_some_and(|n| !referenced.contains(n)) {
+                d.remove_all_optional(&name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[context("Installing bootloader using systemd-boot")]
+pub(crate) fn install_via_systemd_boot(
+    device: &PartitionTable,
+    rootfs: &Utf8Path,
+    configopts: &crate::install::InstallConfigOpts,
+) -> Result<()> {
+    let espdev = find_esp(device)
+        .ok_or_else(|| anyhow!("No EFI System Partition found on {}", device.path()))?;
+    let bootfs = rootfs.join("boot");
+    let target = bootfs.join(EFI_DIR);
+    let boot = Dir::open_ambient_dir(&bootfs, cap_std::ambient_authority())?;
+    println!("Installing systemd-boot");
+    with_esp_mounted(espdev.path(), &target, |esp| {
+        let plan = plan_systemd_boot(device.path(), &target, configopts.generic_image)?;
+        execute_plan(&plan)?;
+        sync_systemd_boot_entries(&boot, esp)
+    })?;
+    let root = Dir::open_ambient_dir(rootfs, cap_std::ambient_authority())?;
+    root.create_dir_all("ostree/bootc")?;
+    root.atomic_write(SYSTEMD_BOOT_STAMP, "")?;
+    Ok(())
+}
+
+/// Run the provided function with the ESP of the booted system (or of the disk
+/// image being operated on) mounted.
+fn with_host_esp<T>(f: impl FnOnce(&Dir) -> Result<T>) -> Result<T> {
+    // When operating on a disk image, its ESP is never mounted
+    if let Some(target) = crate::offline::target() {
+        let device = bootc_blockdev::partitions_of(&target.device)?;
+        let espdev = find_esp(&device).ok_or_else(|| anyhow!("No EFI System Partition found"))?;
+        return with_esp_mounted(espdev.path(), Utf8Path::new(ESP_MOUNT), f);
+    }
+    let efi = Utf8Path::new("/boot").join(EFI_DIR);
+    if mount::inspect_filesystem(&efi).is_ok() {
+        let esp = Dir::open_ambient_dir(&efi, cap_std::ambient_authority())?;
+        return f(&esp);
+    }
+    let root = mount::inspect_filesystem(Utf8Path::new("/sysroot"))?;
+    for parent in bootc_blockdev::find_parent_devices(&root.source)? {
+        let device = bootc_blockdev::partitions_of(Utf8Path::new(&parent))?;
+        if let Some(espdev) = find_esp(&device) {
+            return with_esp_mounted(espdev.path(), Utf8Path::new(ESP_MOUNT), f);
+        }
+    }
+    bail!("No EFI System Partition found")
+}
+
+/// Synchronize the boot entries into the ESP if systemd-boot is in use; this is
+/// run after a rollback and after a staged deployment is finalized (via
+/// `bootc internals sync-systemd-boot`).
+#[context("Updating systemd-boot entries")]
+pub(crate) fn update_systemd_boot(physical_root: &Dir) -> Result<()> {
+    if !physical_root.try_exists(SYSTEMD_BOOT_STAMP)? {
+        tracing::debug!("systemd-boot not in use");
+        return Ok(());
+    }
+    let boot = open_boot(physical_root)?;
+    with_host_esp(|esp| {
+        sync_systemd_boot_entries(&boot, esp)?;
+        // The kernels live in the ESP, so the mirrors are out of date now
+        sync_esp_mirrors(physical_root, esp)
+    })
+}
+
+/// Open the boot filesystem of the booted system (or of the disk image being operated on).
+pub(crate) fn open_boot(physical_root: &Dir) -> Result<Dir> {
+    let boot = if crate::offline::target().is_some() {
+        physical_root.open_dir("boot")?
+    } else {
+        Dir::open_ambient_dir("/boot", cap_std::ambient_authority())?
+    };
+    Ok(boot)
+}
+
+/// Whether systemd-boot is in use and fell back from an entry which failed to boot.
+pub(crate) fn systemd_boot_fell_back(physical_root: &Dir) -> Result<bool> {
+    if !physical_root.try_exists(SYSTEMD_BOOT_STAMP)? {
+        return Ok(false);
+    }
+    with_host_esp(has_exhausted_entry)
+}
+
+/// Make the contents of `dst` identical to `src`, only writing files which changed.
+fn sync_dir(src: &Dir, dst: &Dir) -> Result<()> {
+    let mut names = HashSet::new();
+    for ent in src.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let ty = ent.file_type()?;
+        let existing = dst.symlink_metadata_optional(&name)?;
+        if ty.is_dir() {
+            if existing.as_ref().is_some_and(|m| !m.is_dir()) {
+                dst.remove_file(&name)?;
+            }
+            dst.create_dir_all(&name)?;
+            sync_dir(&src.open_dir(&name)?, &dst.open_dir(&name)?)?;
+        } else if ty.is_file() {
+            let contents = src.read(&name)?;
+            match existing {
+                Some(m) if m.is_dir() => dst.remove_dir_all(&name)?,
+                Some(m) if m.len() == contents.len() as u64 && dst.read(&name)? == contents => {
+                    names.insert(name);
+                    continue;
+                }
+                _ => {}
+            }
+            dst.atomic_write(&name, contents)?;
+        } else {
+            // The ESP is FAT, so there's nothing else
+            continue;
+        }
+        names.insert(name);
+    }
+    for ent in dst.entries()? {
+        let name = ent?.file_name();
+        if !names.contains(&name) {
+            dst.remove_all_optional(&name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the contents of the primary ESP to the mirrors recorded at installation time.
+#[context("Synchronizing ESP mirrors")]
+fn sync_esp_mirrors(physical_root: &Dir, esp: &Dir) -> Result<()> {
+    let Some(f) = physical_root.open_optional(ESP_MIRRORS)? else {
+        return Ok(());
+    };
+    let mirrors = std::io::read_to_string(f)?;
+    for uuid in mirrors.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let dev = Utf8PathBuf::from(format!("/dev/disk/by-partuuid/{uuid}"));
+        with_esp_mounted(&dev, Utf8Path::new(ESP_MIRROR_MOUNT), |mirror| {
+            sync_dir(esp, mirror)?;
+            rustix::fs::syncfs(mirror).context("syncfs")?;
+            Ok(())
+        })
+        .with_context(|| format!("Synchronizing ESP mirror {uuid}"))?;
+        println!("Synchronized ESP mirror {uuid}");
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc internals sync-esp`.
+pub(crate) fn sync_host_esp_mirrors(physical_root: &Dir) -> Result<()> {
+    if !physical_root.try_exists(ESP_MIRRORS)? {
+        println!("No ESP mirrors configured");
+        return Ok(());
+    }
+    with_host_esp(|esp| sync_esp_mirrors(physical_root, esp))
+}
+
+/// The path of the removable media loader, which both bootupd and `bootctl`
+/// install into the ESP.
+fn efi_fallback_loader() -> Result<&'static str> {
+    match std::env::consts::ARCH {
+        "x86_64" => Ok(r"\EFI\BOOT\BOOTX64.EFI"),
+        "aarch64" => Ok(r"\EFI\BOOT\BOOTAA64.EFI"),
+        "riscv64" => Ok(r"\EFI\BOOT\BOOTRISCV64.EFI"),
+        o => bail!("Unsupported architecture for ESP mirroring: {o}"),
+    }
+}
+
+/// Copy the installed bootloader from the primary ESP of `device` to the ESPs of the
+/// `mirrors`, record them so that they are kept up to date, and register each of them
+/// as an EFI boot entry (unless `generic_image` is set).
+#[context("Installing ESP mirrors")]
+pub(crate) fn install_esp_mirrors(
+    device: &PartitionTable,
+    physical_root: &Dir,
+    mirrors: &[PartitionTable],
+    generic_image: bool,
+) -> Result<()> {
+    let espdev = find_esp(device)
+        .ok_or_else(|| anyhow!("No EFI System Partition found on {}", device.path()))?;
+    let mut uuids = String::new();
+    // The disk and partition number of each mirror ESP
+    let mut mirror_esps = Vec::new();
+    for mirror in mirrors {
+        let (i, mirror_esp) = mirror
+            .partitions
+            .iter()
+            .enumerate()
+            .find(|(_, p)| p.parttype.eq_ignore_ascii_case(ESP_GUID))
+            .ok_or_else(|| anyhow!("No EFI System Partition found on {}", mirror.path()))?;
+        let uuid = mirror_esp
+            .uuid
+            .as_deref()
+            .ok_or_else(|| anyhow!("No partition UUID for {}", mirror_esp.node))?;
+        writeln!(uuids, "{}", uuid.to_ascii_lowercase())?;
+        mirror_esps.push((mirror.path(), i + 1));
+    }
+    physical_root.create_dir_all("ostree/bootc")?;
+    physical_root.atomic_write(ESP_MIRRORS, uuids)?;
+    with_esp_mounted(espdev.path(), Utf8Path::new(ESP_MOUNT), |esp| {
+        sync_esp_mirrors(physical_root, esp)
+    })?;
+    if generic_image {
+        return Ok(());
+    }
+    let loader = efi_fallback_loader()?;
+    for (disk, partno) in mirror_esps {
+        Command::new("efibootmgr")
+            .args(["--create", "--disk", disk.as_str(), "--part"])
+            .arg(partno.to_string())
+            .args(["--loader", loader, "--label"])
+            .arg(format!("ESP mirror ({disk})"))
+            .log_debug()
+            .run_capture_stderr()?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc internals bootloader-plan`.
+pub(crate) fn print_plan(
+    device: &Utf8Path,
+    rootfs: &Utf8Path,
+    srcroot: &Utf8Path,
+    generic_image: bool,
+    boot_uuid: Option<&str>,
+) -> Result<()> {
+    let plan = if cfg!(target_arch = "s390x") {
+        let boot_uuid = boot_uuid.ok_or_else(|| anyhow!("--boot-uuid is required for zipl"))?;
+        plan_zipl(device, boot_uuid)?
+    } else {
+        plan_bootupd(device, rootfs, srcroot, generic_image)?
+    };
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &plan)?;
+    Ok(())
+}
+
+/// Render the GRUB `user.cfg` for the provided configuration, if there is anything to set.
+fn render_grub_user_cfg(config: &GrubConfig) -> Result<Option<String>> {
+    let mut r = String::new();
+    if let Some(password) = config.password.as_deref() {
+        let superuser = config
+            .superuser
+            .as_deref()
+            .unwrap_or(GRUB_DEFAULT_SUPERUSER);
+        if superuser.is_empty()
+            || !superuser
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        {
+            bail!("Invalid GRUB superuser: {superuser}");
+        }
+        if !password.starts_with("grub.pbkdf2.") || password.contains(char::is_whitespace) {
+            bail!("The GRUB password must be a PBKDF2 hash generated by grub2-mkpasswd-pbkdf2");
+        }
+        writeln!(r, "set superusers=\"{superuser}\"")?;
+        writeln!(r, "export superusers")?;
+        writeln!(r, "password_pbkdf2 {superuser} {password}")?;
+    } else if config.superuser.is_some() {
+        bail!("A GRUB superuser requires a password");
+    }
+    if let Some(timeout) = config.timeout {
+        writeln!(r, "set timeout={timeout}")?;
+    }
+    match config.hide_menu {
+        Some(true) => writeln!(r, "set timeout_style=hidden")?,
+        Some(false) => writeln!(r, "set timeout_style=menu")?,
+        None => {}
+    }
+    if config.boot_counting == Some(true) {
+        r.push_str(crate::bootcount::GRUB_COUNTING_CFG);
+    }
+    if r.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "# Generated by bootc install from the install.grub configuration\n{r}"
+    )))
+}
+
+/// Write the GRUB password and menu configuration; this must be called after
+/// GRUB has been installed.
+#[context("Writing GRUB configuration")]
+pub(crate) fn write_grub_user_config(physical_root: &Dir, config: &GrubConfig) -> Result<()> {
+    let Some(contents) = render_grub_user_cfg(config)? else {
+        return Ok(());
+    };
+    let dir = Utf8Path::new(GRUB_USER_CFG).parent().unwrap();
+    if !physical_root.try_exists(dir)? {
+        bail!("GRUB configuration directory /{dir} not found");
+    }
+    // This contains the password hash, so keep it private
+    physical_root.atomic_write_with_perms(
+        GRUB_USER_CFG,
+        contents,
+        cap_std::fs::Permissions::from_mode(0o600),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_grub_user_cfg() -> Result<()> {
+        assert_eq!(render_grub_user_cfg(&GrubConfig::default())?, None);
+        let c = GrubConfig {
+            password: Some("grub.pbkdf2.sha512.10000.AA.BB".into()),
+            timeout: Some(3),
+            hide_menu: Some(true),
+            ..Default::default()
+        };
+        let expected = indoc::indoc! { r#"
+            # Generated by bootc install from the install.grub configuration
+            set superusers="root"
+            export superusers
+            password_pbkdf2 root grub.pbkdf2.sha512.10000.AA.BB
+            set timeout=3
+            set timeout_style=hidden
+        "# };
+        assert_eq!(render_grub_user_cfg(&c)?.unwrap(), expected);
+        let c = GrubConfig {
+            boot_counting: Some(true),
+            ..Default::default()
+        };
+        assert!(render_grub_user_cfg(&c)?
+            .unwrap()
+            .ends_with(crate::bootcount::GRUB_COUNTING_CFG));
+
+        for invalid in [
+            GrubConfig {
+                password: Some("hunter2".into()),
+                ..Default::default()
+            },
+            GrubConfig {
+                superuser: Some("admin".into()),
+                ..Default::default()
+            },
+            GrubConfig {
+                superuser: Some("a b".into()),
+                password: Some("grub.pbkdf2.sha512.10000.AA.BB".into()),
+                ..Default::default()
+            },
+        ] {
+            assert!(render_grub_user_cfg(&invalid).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_bls_for_zipl() {
+        let conf = indoc::indoc! { "
+            title Fedora
+            version 6.10.0
+            linux /ostree/default-abc/vmlinuz-6.10.0
+            initrd /ostree/default-abc/initramfs-6.10.0.img
+            options root=UUID=1234 rw ostree=/ostree/boot.1/default/abc/0
+        " };
+        let entry = parse_bls_for_zipl(conf).unwrap();
+        assert_eq!(
+            entry,
+            BlsBootEntry {
+                kernel: "ostree/default-abc/vmlinuz-6.10.0",
+                initrd: "ostree/default-abc/initramfs-6.10.0.img",
+                options: "root=UUID=1234 rw ostree=/ostree/boot.1/default/abc/0",
+            }
+        );
+        assert!(parse_bls_for_zipl("title Fedora\n").is_err());
+    }
+
+    #[test]
+    fn test_plan_bootupd() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let srcroot = Utf8Path::from_path(td.path()).unwrap();
+        let updates = srcroot.join(BOOTUPD_UPDATES);
+        std::fs::create_dir_all(updates.join("EFI/fedora"))?;
+        std::fs::create_dir_all(updates.join("EFI/BOOT"))?;
+        std::fs::write(updates.join("EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(updates.join("EFI/BOOT/BOOTX64.EFI"), "shim")?;
+        std::fs::write(updates.join("BIOS.json"), "{}")?;
+        std::fs::create_dir_all(srcroot.join(BOOTUPD_GRUB_STATIC))?;
+
+        let device = Utf8Path::new("/dev/vda");
+        let rootfs = Utf8Path::new("/target");
+        let plan = plan_bootupd(device, rootfs, srcroot, false)?;
+        assert_eq!(plan.backend, BootloaderBackend::Bootupd);
+        assert_eq!(
+            plan.writes,
+            [
+                "boot/efi/EFI/BOOT/BOOTX64.EFI",
+                "boot/efi/EFI/fedora/shimx64.efi",
+                "boot/grub2/i386-pc/",
+                "boot/bootupd-state.json"
+            ]
+            .map(Utf8PathBuf::from)
+        );
+        assert_eq!(
+            plan.grub_config,
+            [
+                "boot/grub2/grub.cfg",
+                "boot/grub2/bootuuid.cfg",
+                "boot/efi/EFI/fedora/grub.cfg",
+                "boot/efi/EFI/fedora/bootuuid.cfg"
+            ]
+            .map(Utf8PathBuf::from)
+        );
+        assert_eq!(plan.efivars, ["BootOrder", "Boot####"]);
+        assert_eq!(plan.commands.len(), 1);
+        assert!(plan.commands[0].iter().any(|v| v == "--update-firmware"));
+
+        // Generic images don't touch the firmware
+        let plan = plan_bootupd(device, rootfs, srcroot, true)?;
+        assert!(plan.efivars.is_empty());
+        assert!(!plan.commands[0].iter().any(|v| v == "--update-firmware"));
+
+        // And verify the executor just runs the planned commands in order
+        let mut executed = Vec::new();
+        execute_plan_with(&plan, |cmd| {
+            executed.push(cmd.to_vec());
+            Ok(())
+        })?;
+        assert_eq!(executed, plan.commands);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_systemd_boot() -> Result<()> {
+        let device = Utf8Path::new("/dev/vda");
+        let esp = Utf8Path::new("/target/boot/efi");
+        let plan = plan_systemd_boot(device, esp, false)?;
+        assert_eq!(plan.backend, BootloaderBackend::SystemdBoot);
+        assert_eq!(
+            plan.commands,
+            [["bootctl", "install", "--esp-path", "/target/boot/efi"].map(ToOwned::to_owned)]
+        );
+        assert_eq!(plan.efivars, ["BootOrder", "Boot####"]);
+        let plan = plan_systemd_boot(device, esp, true)?;
+        assert!(plan.efivars.is_empty());
+        assert_eq!(plan.commands[0].last().unwrap(), "--no-variables");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_loader_conf() {
+        assert_eq!(
+            update_loader_conf("", "ostree-2.conf"),
+            "default ostree-2.conf\n"
+        );
+        assert_eq!(
+            update_loader_conf("timeout 3\ndefault ostree-1.conf\n", "ostree-2.conf"),
+            "timeout 3\ndefault ostree-2.conf\n"
+        );
+    }
+
+    #[test]
+    fn test_entry_id() {
+        for (name, id) in [
+            ("ostree-1.conf", "ostree-1.conf"),
+            ("ostree-1+3.conf", "ostree-1.conf"),
+            ("ostree-1-default+0-3.conf", "ostree-1-default.conf"),
+            ("ostree-1+a.conf", "ostree-1+a.conf"),
+            ("ostree-1+3-.conf", "ostree-1+3-.conf"),
+            ("ostree-1+3", "ostree-1+3"),
+        ] {
+            assert_eq!(entry_id(name), id, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_exhausted_entry() -> Result<()> {
+        for (name, left) in [
+            ("ostree-1.conf", None),
+            ("ostree-1+3.conf", Some(3)),
+            ("ostree-1+0-3.conf", Some(0)),
+            ("ostree-1+a.conf", None),
+        ] {
+            assert_eq!(tries_left(name), left, "{name}");
+        }
+        let esp = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(!has_exhausted_entry(esp)?);
+        esp.create_dir_all(LOADER_ENTRIES)?;
+        esp.write(format!("{LOADER_ENTRIES}/ostree-1.conf"), "")?;
+        esp.write(format!("{LOADER_ENTRIES}/ostree-2+1-2.conf"), "")?;
+        assert!(!has_exhausted_entry(esp)?);
+        esp.write(format!("{LOADER_ENTRIES}/other+0.conf"), "")?;
+        assert!(!has_exhausted_entry(esp)?);
+        esp.rename(
+            format!("{LOADER_ENTRIES}/ostree-2+1-2.conf"),
+            esp,
+            format!("{LOADER_ENTRIES}/ostree-2+0-3.conf"),
+        )?;
+        assert!(has_exhausted_entry(esp)?);
+        Ok(())
+    }
+
+    fn write_entry(boot: &Dir, n: u32, csum: &str) -> Result<()> {
+        let kdir = format!("ostree/default-{csum}");
+        boot.create_dir_all(&kdir)?;
+        boot.write(format!("{kdir}/vmlinuz-6.10.0"), format!("kernel {csum}"))?;
+        boot.write(
+            format!("{kdir}/initramfs-6.10.0.img"),
+            format!("initrd {csum}"),
+        )?;
+        let conf = format!(
+            "title Fedora ({n})\nversion {n}\nlinux /boot/{kdir}/vmlinuz-6.10.0\ninitrd /boot/{kdir}/initramfs-6.10.0.img\noptions root=UUID=1234 ostree=/ostree/boot.1/default/{csum}/0\n"
+        );
+        boot.write(format!("{LOADER_ENTRIES}/ostree-{n}.conf"), conf)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_systemd_boot_entries() -> Result<()> {
+        let boot = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let esp = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        boot.create_dir_all(LOADER_ENTRIES)?;
+        esp.create_dir_all("loader")?;
+        esp.write(LOADER_CONF, "timeout 3\n")?;
+        write_entry(&boot, 1, "aaa")?;
+        write_entry(&boot, 2, "bbb")?;
+        assert!(sync_systemd_boot_entries(&boot, &esp).is_ok());
+        let entry = esp.read_to_string(format!("{LOADER_ENTRIES}/ostree-2.conf"))?;
+        assert!(entry.contains("linux /ostree/default-bbb/vmlinuz-6.10.0\n"));
+        assert!(entry.contains("options root=UUID=1234"));
+        assert_eq!(
+            esp.read_to_string("ostree/default-aaa/initramfs-6.10.0.img")?,
+            "initrd aaa"
+        );
+        assert_eq!(
+            esp.read_to_string(LOADER_CONF)?,
+            "timeout 3\ndefault ostree-2.conf\n"
+        );
+
+        // Simulate an upgrade which prunes the old deployment
+        boot.remove_file(format!("{LOADER_ENTRIES}/ostree-1.conf"))?;
+        boot.remove_file(format!("{LOADER_ENTRIES}/ostree-2.conf"))?;
+        write_entry(&boot, 1, "bbb")?;
+        write_entry(&boot, 2, "ccc")?;
+        sync_systemd_boot_entries(&boot, &esp)?;
+        assert!(!esp.try_exists("ostree/default-aaa")?);
+        assert!(esp.try_exists("ostree/default-ccc/vmlinuz-6.10.0")?);
+        let entry = esp.read_to_string(format!("{LOADER_ENTRIES}/ostree-1.conf"))?;
+        assert!(entry.contains("default-bbb"));
+        assert!(esp
+            .read_to_string(LOADER_CONF)?
+            .ends_with("default ostree-2.conf\n"));
+        // The new default is boot counted, and the previous one is gone
+        let entry = esp.read_to_string(format!("{LOADER_ENTRIES}/ostree-2+3.conf"))?;
+        assert!(entry.contains("default-ccc"));
+        assert!(!esp.try_exists(format!("{LOADER_ENTRIES}/ostree-2.conf"))?);
+
+        // Once blessed by systemd-bless-boot, the entry keeps its name
+        esp.rename(
+            format!("{LOADER_ENTRIES}/ostree-2+3.conf"),
+            &esp,
+            format!("{LOADER_ENTRIES}/ostree-2.conf"),
+        )?;
+        sync_systemd_boot_entries(&boot, &esp)?;
+        assert!(esp.try_exists(format!("{LOADER_ENTRIES}/ostree-2.conf"))?);
+        assert!(!esp.try_exists(format!("{LOADER_ENTRIES}/ostree-2+3.conf"))?);
+
+        // Entries without a kernel are rejected
+        boot.write(format!("{LOADER_ENTRIES}/ostree-3.conf"), "title broken\n")?;
+        assert!(sync_systemd_boot_entries(&boot, &esp).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_dir() -> Result<()> {
+        let src = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let dst = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        src.create_dir_all("EFI/BOOT")?;
+        src.write("EFI/BOOT/BOOTX64.EFI", "shim")?;
+        src.write("EFI/fedora", "now a file")?;
+        dst.create_dir_all("EFI/fedora")?;
+        dst.write("EFI/fedora/grub.cfg", "stale")?;
+        dst.write("EFI/BOOT", "now a directory")?;
+        dst.write("stale", "stale")?;
+        sync_dir(src, dst)?;
+        assert_eq!(dst.read_to_string("EFI/BOOT/BOOTX64.EFI")?, "shim");
+        assert_eq!(dst.read_to_string("EFI/fedora")?, "now a file");
+        assert!(!dst.try_exists("stale")?);
+        // Unchanged files are left alone
+        let mtime = dst.metadata("EFI/BOOT/BOOTX64.EFI")?.modified()?;
+        src.write("EFI/BOOT/BOOTX64.EFI", "shim")?;
+        sync_dir(src, dst)?;
+        assert_eq!(dst.metadata("EFI/BOOT/BOOTX64.EFI")?.modified()?, mtime);
+        Ok(())
+    }
 }
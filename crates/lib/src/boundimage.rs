@@ -4,22 +4,43 @@
 //! for "logically bound" container images. These container images are
 //! pre-pulled (and in the future, pinned) before a new image root
 //! is considered ready.
+//!
+//! Named volumes used by bound `.container` files, or defined by bound
+//! `.volume` files, are created when installing so that workloads
+//! start correctly on first boot.
+//!
+//! An image repository can be pinned with `bootc image pin`; while pinned,
+//! new deployments referencing another tag of it are given the pinned
+//! version instead of fetching that tag.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 
 use anyhow::{Context, Result};
 use camino::Utf8Path;
 use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
 use fn_error_context::context;
 use ostree_ext::containers_image_proxy;
 use ostree_ext::ostree::Deployment;
+use serde::Serialize;
 
 use crate::imgstorage::PullMode;
+use crate::podman::ImageListEntry;
+use crate::spec::BoundImagePin;
 use crate::store::Storage;
 
 /// The path in a root for bound images; this directory should only contain
-/// symbolic links to `.container` or `.image` files.
+/// symbolic links to `.container`, `.image` or `.volume` files.
 const BOUND_IMAGE_DIR: &str = "usr/lib/bootc/bound-images.d";
 
+/// The pinned bound images, relative to the physical root.
+const PINS_PATH: &str = "ostree/bootc/bound-image-pins.json";
+
+/// Bound images held at a specific version, keyed by repository.
+pub(crate) type BoundImagePins = BTreeMap<String, BoundImagePin>;
+
 /// A subset of data parsed from a `.image` or `.container` file with
 /// the minimal information necessary to fetch the image.
 ///
@@ -31,16 +52,45 @@ pub(crate) struct BoundImage {
     pub(crate) auth_file: Option<String>,
 }
 
+/// A bound image, the deployments referencing it, and its state in the
+/// bootc container storage.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct BoundImageEntry {
+    pub(crate) image: String,
+    /// The deployments referencing this image, e.g. `booted`
+    pub(crate) deployments: Vec<String>,
+    pub(crate) present: bool,
+    pub(crate) digest: Option<String>,
+    /// The size of the stored image, including layers shared with other images
+    pub(crate) size: Option<u64>,
+}
+
+/// A named volume to create at install time, from a bound `.volume` file
+/// or a `Volume=` entry of a bound `.container` file.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct BoundVolume {
+    pub(crate) name: String,
+    /// Passed as `--label`
+    pub(crate) labels: Vec<String>,
+    /// Passed as `--opt`
+    pub(crate) options: Vec<String>,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct ResolvedBoundImage {
     pub(crate) image: String,
     pub(crate) digest: String,
 }
 
-/// Given a deployment, pull all container images it references.
-pub(crate) async fn pull_bound_images(sysroot: &Storage, deployment: &Deployment) -> Result<()> {
+/// Given a deployment, pull all container images it references, using `authfile`
+/// (if set) instead of the host's registry authentication.
+pub(crate) async fn pull_bound_images(
+    sysroot: &Storage,
+    deployment: &Deployment,
+    authfile: Option<&Utf8Path>,
+) -> Result<()> {
     let bound_images = query_bound_images_for_deployment(sysroot, deployment)?;
-    pull_images(sysroot, bound_images).await
+    pull_images(sysroot, bound_images, authfile).await
 }
 
 #[context("Querying bound images")]
@@ -52,8 +102,280 @@ pub(crate) fn query_bound_images_for_deployment(
     query_bound_images(deployment_root)
 }
 
-#[context("Querying bound images")]
-pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
+/// Podman always records unpinned references with a tag; add the default
+/// one if missing.
+pub(crate) fn normalize_image_name(image: &str) -> Cow<'_, str> {
+    let has_tag = image
+        .rsplit('/')
+        .next()
+        .is_some_and(|last| last.contains(':'));
+    if image.contains('@') || has_tag {
+        Cow::Borrowed(image)
+    } else {
+        Cow::Owned(format!("{image}:latest"))
+    }
+}
+
+/// Find the entry in the bootc container storage for a bound image
+/// reference; a digest-pinned reference also matches by digest.
+pub(crate) fn find_stored<'a>(
+    image: &str,
+    stored: &'a [ImageListEntry],
+) -> Option<&'a ImageListEntry> {
+    let name = normalize_image_name(image);
+    let pinned_digest = image.split_once('@').map(|(_, d)| d);
+    stored.iter().find(|entry| {
+        let by_name = entry.names.iter().flatten().any(|n| n == name.as_ref());
+        let by_digest = pinned_digest.is_some_and(|d| {
+            entry.digest.as_deref() == Some(d) || entry.digests.iter().flatten().any(|e| e == d)
+        });
+        by_name || by_digest
+    })
+}
+
+/// The repository of an image reference, i.e. without its tag or digest.
+pub(crate) fn image_repository(image: &str) -> &str {
+    let name = image.split_once('@').map_or(image, |(name, _)| name);
+    match name.rsplit_once(':') {
+        // A `:` before the last `/` separates a registry port
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => name,
+    }
+}
+
+/// The pin applying to `image`, if its repository is pinned.
+pub(crate) fn pin_for<'a>(image: &str, pins: &'a BoundImagePins) -> Option<&'a BoundImagePin> {
+    pins.get(image_repository(image))
+}
+
+#[context("Loading bound image pins")]
+pub(crate) fn load_pins(root: &Dir) -> Result<BoundImagePins> {
+    let Some(f) = root.open_optional(PINS_PATH)? else {
+        return Ok(BoundImagePins::default());
+    };
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {PINS_PATH}"))
+}
+
+fn store_pins(root: &Dir, pins: &BoundImagePins) -> Result<()> {
+    root.create_dir_all(crate::store::BOOTC_ROOT)?;
+    root.atomic_write(PINS_PATH, serde_json::to_vec(pins)?)
+        .with_context(|| format!("Writing {PINS_PATH}"))
+}
+
+/// Implementation of `bootc image pin`.
+#[context("Pinning {image}")]
+pub(crate) async fn pin(sysroot: &Storage, image: &str) -> Result<()> {
+    let repository = image_repository(image);
+    let bound = list_bound_images(sysroot).await?;
+    if !bound
+        .iter()
+        .any(|e| image_repository(&e.image) == repository)
+    {
+        anyhow::bail!("{repository} is not a logically bound image of any deployment");
+    }
+    let stored = sysroot.get_ensure_imgstore()?.list_images().await?;
+    let digest = find_stored(image, &stored)
+        .and_then(|e| e.digest.clone())
+        .ok_or_else(|| anyhow::anyhow!("{image} is not present in the bootc storage"))?;
+    let mut pins = load_pins(&sysroot.physical_root)?;
+    pins.insert(
+        repository.to_owned(),
+        BoundImagePin {
+            image: image.to_owned(),
+            digest: digest.clone(),
+        },
+    );
+    store_pins(&sysroot.physical_root, &pins)?;
+    println!("Pinned {repository} at {digest}");
+    Ok(())
+}
+
+/// Implementation of `bootc image unpin`; references of the repository which
+/// were held at the pinned version are fetched again.
+#[context("Unpinning {image}")]
+pub(crate) async fn unpin(sysroot: &Storage, image: &str) -> Result<()> {
+    let repository = image_repository(image);
+    let mut pins = load_pins(&sysroot.physical_root)?;
+    let Some(pin) = pins.remove(repository) else {
+        anyhow::bail!("{repository} is not pinned");
+    };
+    store_pins(&sysroot.physical_root, &pins)?;
+    let imgstore = sysroot.get_ensure_imgstore()?;
+    for entry in list_bound_images(sysroot).await? {
+        let held = image_repository(&entry.image) == repository
+            && entry.image != pin.image
+            && !entry.image.contains('@');
+        if held {
+            let desc = format!("Fetching bound image: {}", entry.image);
+            crate::utils::async_task_with_spinner(
+                &desc,
+                imgstore.pull(&entry.image, PullMode::Always, None),
+            )
+            .await?;
+        }
+    }
+    println!("Unpinned {repository}");
+    Ok(())
+}
+
+/// The images which are kept when pruning the bootc storage: the bound images of
+/// all deployments, and the pinned versions of bound images.
+pub(crate) fn gc_roots(sysroot: &Storage) -> Result<Vec<String>> {
+    let mut roots = Vec::new();
+    for deployment in sysroot.deployments() {
+        let bound = query_bound_images_for_deployment(sysroot, &deployment)?;
+        roots.extend(bound.into_iter().map(|img| img.image));
+    }
+    let pins = load_pins(&sysroot.physical_root)?;
+    roots.extend(
+        pins.into_iter()
+            .map(|(repository, pin)| format!("{repository}@{}", pin.digest)),
+    );
+    Ok(roots)
+}
+
+/// Filters for `bootc image prune`; images matching all of them are removed.
+/// The images returned by [`gc_roots`] are always kept.
+#[derive(Debug, Default)]
+pub(crate) struct PruneFilters {
+    /// Only remove images created before this time
+    pub(crate) older_than: Option<DateTime<Utc>>,
+    /// Only remove images without a name
+    pub(crate) dangling: bool,
+}
+
+/// Select the stored images to remove.
+fn prune_candidates(
+    stored: Vec<ImageListEntry>,
+    roots: &[&str],
+    filters: &PruneFilters,
+) -> Vec<ImageListEntry> {
+    unreferenced_images(stored, roots)
+        .into_iter()
+        .filter(|e| !filters.dangling || e.names.as_deref().unwrap_or_default().is_empty())
+        .filter(|e| match filters.older_than {
+            // Images of unknown age are kept
+            Some(cutoff) => e.created.is_some_and(|c| c < cutoff.timestamp()),
+            None => true,
+        })
+        .collect()
+}
+
+/// Implementation of `bootc image prune`.
+#[context("Pruning bound images")]
+pub(crate) async fn prune(sysroot: &Storage, filters: PruneFilters) -> Result<()> {
+    let Some(imgstore) = sysroot.get_imgstore_if_exists()? else {
+        println!("No images stored");
+        return Ok(());
+    };
+    let roots = gc_roots(sysroot)?;
+    let roots = roots.iter().map(String::as_str).collect::<Vec<_>>();
+    let before = imgstore.disk_usage()?;
+    let pruned = prune_candidates(imgstore.list_images().await?, &roots, &filters);
+    if pruned.is_empty() {
+        println!("No images to prune");
+        return Ok(());
+    }
+    imgstore.remove(&pruned).await?;
+    for entry in pruned.iter() {
+        let name = entry
+            .names
+            .as_deref()
+            .and_then(|n| n.first())
+            .unwrap_or(&entry.id);
+        println!("Removed: {name}");
+    }
+    let after = imgstore.disk_usage()?;
+    println!(
+        "Pruned images: {} (storage size: {} -> {}, freed: {})",
+        pruned.len(),
+        indicatif::HumanBytes(before),
+        indicatif::HumanBytes(after),
+        indicatif::HumanBytes(before.saturating_sub(after))
+    );
+    Ok(())
+}
+
+/// Stored images not referenced by any of `roots`.
+pub(crate) fn unreferenced_images(
+    stored: Vec<ImageListEntry>,
+    roots: &[&str],
+) -> Vec<ImageListEntry> {
+    let referenced = roots
+        .iter()
+        .filter_map(|image| find_stored(image, &stored))
+        .map(|entry| entry.id.clone())
+        .collect::<std::collections::HashSet<_>>();
+    stored
+        .into_iter()
+        .filter(|entry| !referenced.contains(&entry.id))
+        .collect()
+}
+
+/// Gather the bound images of all deployments, keyed by image.
+fn bound_images_by_image(
+    bound: impl IntoIterator<Item = (String, Vec<BoundImage>)>,
+    stored: &[ImageListEntry],
+) -> Vec<BoundImageEntry> {
+    let mut by_image = BTreeMap::<String, Vec<String>>::new();
+    for (deployment, images) in bound {
+        for image in images {
+            let deployments = by_image.entry(image.image).or_default();
+            if !deployments.contains(&deployment) {
+                deployments.push(deployment.clone());
+            }
+        }
+    }
+    by_image
+        .into_iter()
+        .map(|(image, deployments)| {
+            let found = find_stored(&image, stored);
+            BoundImageEntry {
+                present: found.is_some(),
+                digest: found.and_then(|e| e.digest.clone()),
+                size: found.and_then(|e| e.size),
+                image,
+                deployments,
+            }
+        })
+        .collect()
+}
+
+/// List the bound images of all deployments, and their state in the bootc
+/// container storage.
+#[context("Listing bound images")]
+pub(crate) async fn list_bound_images(sysroot: &Storage) -> Result<Vec<BoundImageEntry>> {
+    let booted = sysroot.booted_deployment();
+    let (deployments, _) = crate::status::get_status(sysroot, booted.as_ref())?;
+    let slots = [
+        ("staged", deployments.staged),
+        ("booted", booted),
+        ("rollback", deployments.rollback),
+    ]
+    .into_iter()
+    .filter_map(|(name, d)| d.map(|d| (name.to_owned(), d)))
+    .chain(deployments.other.into_iter().map(|d| {
+        let name = format!("{}.{}", d.csum(), d.deployserial());
+        (name, d)
+    }));
+    let mut bound = Vec::new();
+    for (name, deployment) in slots {
+        bound.push((
+            name,
+            query_bound_images_for_deployment(sysroot, &deployment)?,
+        ));
+    }
+    let stored = match sysroot.get_imgstore_if_exists()? {
+        Some(imgstore) => imgstore.list_images().await?,
+        None => Vec::new(),
+    };
+    Ok(bound_images_by_image(bound, &stored))
+}
+
+/// Read the files linked from the bound images directory, returning
+/// their names and contents.
+fn read_bound_files(root: &Dir) -> Result<Vec<(String, String)>> {
     let spec_dir = BOUND_IMAGE_DIR;
     let Some(bound_images_dir) = root.open_dir_optional(spec_dir)? else {
         tracing::debug!("Missing {spec_dir}");
@@ -63,7 +385,7 @@ pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
     // handle absolute symlinks.
     let absroot = &root.open_dir_rooted_ext(".")?;
 
-    let mut bound_images = Vec::new();
+    let mut r = Vec::new();
 
     for entry in bound_images_dir
         .entries()
@@ -82,17 +404,35 @@ pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
             anyhow::bail!("Not a symlink: {file_name}");
         }
 
-        //parse the file contents
         let path = Utf8Path::new(spec_dir).join(file_name);
         let file_contents = absroot.read_to_string(&path)?;
+        r.push((file_name.to_owned(), file_contents));
+    }
+    // Directory iteration order is arbitrary
+    r.sort();
+    Ok(r)
+}
 
+#[context("Querying bound images")]
+pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
+    let mut bound_images = Vec::new();
+
+    for (file_name, file_contents) in read_bound_files(root)? {
+        //parse the file contents
+        let path = Utf8Path::new(BOUND_IMAGE_DIR).join(&file_name);
         let file_ini = tini::Ini::from_string(&file_contents).context("Parse to ini")?;
-        let file_extension = Utf8Path::new(file_name).extension();
+        let file_extension = Utf8Path::new(&file_name).extension();
         let bound_image = match file_extension {
             Some("image") => parse_image_file(&file_ini).with_context(|| format!("Parsing {path}")),
             Some("container") => {
                 parse_container_file(&file_ini).with_context(|| format!("Parsing {path}"))
             }
+            Some("volume") => {
+                // Volumes don't reference images; but validate them here too
+                parse_volume_file(&file_name, &file_contents)
+                    .with_context(|| format!("Parsing {path}"))?;
+                continue;
+            }
             _ => anyhow::bail!("Invalid file extension: {file_name}"),
         }?;
 
@@ -102,6 +442,89 @@ pub(crate) fn query_bound_images(root: &Dir) -> Result<Vec<BoundImage>> {
     Ok(bound_images)
 }
 
+#[context("Querying bound volumes")]
+pub(crate) fn query_bound_volumes(root: &Dir) -> Result<Vec<BoundVolume>> {
+    let files = read_bound_files(root)?;
+    let mut volumes: Vec<BoundVolume> = Vec::new();
+    // Volume definitions take precedence over references
+    for (file_name, file_contents) in files.iter() {
+        if Utf8Path::new(file_name).extension() == Some("volume") {
+            let volume = parse_volume_file(file_name, file_contents)
+                .with_context(|| format!("Parsing {file_name}"))?;
+            if !volumes.iter().any(|v| v.name == volume.name) {
+                volumes.push(volume);
+            }
+        }
+    }
+    for (file_name, file_contents) in files.iter() {
+        if Utf8Path::new(file_name).extension() != Some("container") {
+            continue;
+        }
+        for name in container_named_volumes(file_contents) {
+            if !volumes.iter().any(|v| v.name == name) {
+                volumes.push(BoundVolume {
+                    name,
+                    labels: Vec::new(),
+                    options: Vec::new(),
+                });
+            }
+        }
+    }
+    Ok(volumes)
+}
+
+/// Return the names of the systemd services which Quadlet generates from
+/// the bound `.container` and `.image` files.
+#[context("Querying bound image units")]
+pub(crate) fn query_bound_units(root: &Dir) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for (file_name, file_contents) in read_bound_files(root)? {
+        let path = Utf8Path::new(&file_name);
+        let (section, suffix) = match path.extension() {
+            Some("container") => ("Container", ""),
+            Some("image") => ("Image", "-image"),
+            _ => continue,
+        };
+        let file_ini = tini::Ini::from_string(&file_contents)
+            .with_context(|| format!("Parsing {file_name}"))?;
+        let name = match file_ini.get::<String>(section, "ServiceName") {
+            Some(name) => name,
+            // SAFETY: The file has an extension
+            None => format!("{}{suffix}", path.file_stem().unwrap()),
+        };
+        r.push(format!("{name}.service"));
+    }
+    Ok(r)
+}
+
+/// Create the volumes in the container storage at `storage_root`
+/// (normally `/var/lib/containers/storage` of the target).
+#[context("Creating bound volumes")]
+pub(crate) fn create_volumes(storage_root: &Utf8Path, volumes: &[BoundVolume]) -> Result<()> {
+    use bootc_utils::CommandRunExt;
+
+    let runroot = tempfile::tempdir()?;
+    for volume in volumes {
+        let mut cmd = std::process::Command::new("podman");
+        cmd.arg("--root")
+            .arg(storage_root)
+            .arg("--runroot")
+            .arg(runroot.path())
+            .args(["volume", "create", "--ignore"]);
+        for label in volume.labels.iter() {
+            cmd.args(["--label", label]);
+        }
+        for opt in volume.options.iter() {
+            cmd.args(["--opt", opt]);
+        }
+        cmd.arg(&volume.name);
+        cmd.run_capture_stderr()
+            .with_context(|| format!("Creating volume {}", volume.name))?;
+    }
+    println!("Bound volumes created: {}", volumes.len());
+    Ok(())
+}
+
 impl ResolvedBoundImage {
     #[context("resolving bound image {}", src.image)]
     pub(crate) async fn from_image(src: &BoundImage) -> Result<Self> {
@@ -134,6 +557,83 @@ fn parse_image_file(file_contents: &tini::Ini) -> Result<BoundImage> {
     Ok(bound_image)
 }
 
+/// All values of a possibly repeated key in a section of a unit file.
+fn unit_values(contents: &str, section: &str, key: &str) -> Vec<String> {
+    let mut in_section = false;
+    let mut r = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = name == section;
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+        if let Some((k, v)) = line.split_once('=') {
+            if k.trim() == key {
+                r.push(v.trim().to_owned());
+            }
+        }
+    }
+    r
+}
+
+fn parse_volume_file(file_name: &str, contents: &str) -> Result<BoundVolume> {
+    let last = |key| unit_values(contents, "Volume", key).pop();
+    let name = match last("VolumeName") {
+        Some(name) => parse_spec_value(&name).context("Invalid VolumeName value")?,
+        // This is the quadlet default
+        None => format!(
+            "systemd-{}",
+            file_name.strip_suffix(".volume").unwrap_or(file_name)
+        ),
+    };
+    match last("Driver").as_deref() {
+        None | Some("local") => {}
+        Some("image") => anyhow::bail!("Image-backed volumes are not supported by bound volumes"),
+        Some(o) => anyhow::bail!("Unsupported volume driver: {o}"),
+    }
+    let labels = unit_values(contents, "Volume", "Label")
+        .iter()
+        .flat_map(|l| {
+            l.split_whitespace()
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>()
+        })
+        .map(|l| parse_spec_value(&l))
+        .collect::<Result<Vec<_>>>()?;
+    let mut options = Vec::new();
+    for (key, opt) in [("Type", "type"), ("Device", "device"), ("Options", "o")] {
+        if let Some(v) = last(key) {
+            options.push(format!("{opt}={}", parse_spec_value(&v)?));
+        }
+    }
+    Ok(BoundVolume {
+        name,
+        labels,
+        options,
+    })
+}
+
+/// The named volumes of a `.container` file; volumes referencing a
+/// `.volume` unit, host paths and anonymous volumes are skipped.
+fn container_named_volumes(contents: &str) -> Vec<String> {
+    unit_values(contents, "Container", "Volume")
+        .into_iter()
+        .filter_map(|v| {
+            let (source, _) = v.split_once(':')?;
+            let valid = source.chars().next()?.is_ascii_alphanumeric()
+                && source
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+            (valid && !source.ends_with(".volume")).then(|| source.to_owned())
+        })
+        .collect()
+}
+
 fn parse_container_file(file_contents: &tini::Ini) -> Result<BoundImage> {
     let image: String = file_contents
         .get("Container", "Image")
@@ -147,19 +647,60 @@ fn parse_container_file(file_contents: &tini::Ini) -> Result<BoundImage> {
 pub(crate) async fn pull_images(
     sysroot: &Storage,
     bound_images: Vec<crate::boundimage::BoundImage>,
+    authfile: Option<&Utf8Path>,
 ) -> Result<()> {
     // Always initialize the img store to ensure labels are set when upgrading
     let imgstore = sysroot.get_ensure_imgstore()?;
     if bound_images.is_empty() {
         return Ok(());
     }
-    pull_images_impl(imgstore, bound_images).await
+    let pins = load_pins(&sysroot.physical_root)?;
+    let mut unpinned = Vec::new();
+    for bound_image in bound_images {
+        let held = match pin_for(&bound_image.image, &pins) {
+            Some(pin) => hold_pinned(imgstore, &bound_image.image, pin).await?,
+            None => false,
+        };
+        if !held {
+            unpinned.push(bound_image);
+        }
+    }
+    if unpinned.is_empty() {
+        return Ok(());
+    }
+    pull_images_impl(imgstore, unpinned, authfile).await
+}
+
+/// Point `image` at the pinned version in the bootc storage instead of fetching
+/// it. Returns false if `image` is a digest reference, which cannot be held.
+async fn hold_pinned(
+    imgstore: &crate::imgstorage::Storage,
+    image: &str,
+    pin: &BoundImagePin,
+) -> Result<bool> {
+    if let Some((_, digest)) = image.split_once('@') {
+        if digest != pin.digest {
+            crate::utils::medium_visibility_warning(&format!(
+                "Bound image {image} references a digest and cannot be held at pinned {}",
+                pin.digest
+            ));
+        }
+        return Ok(false);
+    }
+    let pinned = format!("{}@{}", image_repository(image), pin.digest);
+    if !imgstore.exists(&pinned).await? {
+        anyhow::bail!("Pinned image {pinned} is not present in the bootc storage");
+    }
+    imgstore.tag(&pinned, image).await?;
+    println!("Holding bound image {image} at pinned {}", pin.digest);
+    Ok(true)
 }
 
 #[context("Pulling bound images")]
 pub(crate) async fn pull_images_impl(
     imgstore: &crate::imgstorage::Storage,
     bound_images: Vec<crate::boundimage::BoundImage>,
+    authfile: Option<&Utf8Path>,
 ) -> Result<()> {
     let n = bound_images.len();
     tracing::debug!("Pulling bound images: {n}");
@@ -173,7 +714,7 @@ pub(crate) async fn pull_images_impl(
         let desc = format!("Fetching bound image: {image}");
         crate::utils::async_task_with_spinner(&desc, async move {
             imgstore
-                .pull(&bound_image.image, PullMode::IfNotExists)
+                .pull(&bound_image.image, PullMode::IfNotExists, authfile)
                 .await
         })
         .await?;
@@ -228,6 +769,245 @@ mod tests {
     use super::*;
     use cap_std_ext::cap_std;
 
+    fn stored(id: &str, names: &[&str], digest: &str) -> ImageListEntry {
+        ImageListEntry {
+            id: id.into(),
+            names: Some(names.iter().map(|&n| n.into()).collect()),
+            digest: Some(digest.into()),
+            digests: Some(vec![digest.into()]),
+            size: Some(1024),
+            created: None,
+        }
+    }
+
+    #[test]
+    fn test_find_stored() {
+        assert_eq!(normalize_image_name("quay.io/foo"), "quay.io/foo:latest");
+        assert_eq!(
+            normalize_image_name("localhost:5000/foo"),
+            "localhost:5000/foo:latest"
+        );
+        assert_eq!(
+            normalize_image_name("quay.io/foo@sha256:1"),
+            "quay.io/foo@sha256:1"
+        );
+        let images = [
+            stored("a", &["quay.io/foo:latest"], "sha256:1"),
+            stored("b", &[], "sha256:2"),
+        ];
+        assert_eq!(find_stored("quay.io/foo", &images).unwrap().id, "a");
+        assert_eq!(
+            find_stored("quay.io/bar@sha256:2", &images).unwrap().id,
+            "b"
+        );
+        assert!(find_stored("quay.io/foo:v2", &images).is_none());
+    }
+
+    #[test]
+    fn test_image_repository() {
+        for (image, expected) in [
+            ("quay.io/example/app", "quay.io/example/app"),
+            ("quay.io/example/app:v1", "quay.io/example/app"),
+            ("quay.io/example/app@sha256:1111", "quay.io/example/app"),
+            ("quay.io/example/app:v1@sha256:1111", "quay.io/example/app"),
+            ("localhost:5000/app", "localhost:5000/app"),
+            ("localhost:5000/app:v2", "localhost:5000/app"),
+        ] {
+            assert_eq!(image_repository(image), expected, "{image}");
+        }
+        let pins = BoundImagePins::from([(
+            "quay.io/example/app".to_owned(),
+            BoundImagePin {
+                image: "quay.io/example/app:v1".into(),
+                digest: "sha256:1111".into(),
+            },
+        )]);
+        assert!(pin_for("quay.io/example/app:v2", &pins).is_some());
+        assert!(pin_for("quay.io/example/db:v1", &pins).is_none());
+    }
+
+    #[test]
+    fn test_load_pins() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(load_pins(&td)?.is_empty());
+        let pins = BoundImagePins::from([(
+            "quay.io/example/app".to_owned(),
+            BoundImagePin {
+                image: "quay.io/example/app:v1".into(),
+                digest: "sha256:1111".into(),
+            },
+        )]);
+        store_pins(&td, &pins)?;
+        assert_eq!(load_pins(&td)?, pins);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreferenced_images() {
+        let images = vec![
+            stored("a", &["quay.io/foo:latest", "quay.io/foo:v1"], "sha256:1"),
+            stored("b", &["quay.io/bar:latest"], "sha256:2"),
+            stored("c", &[], "sha256:3"),
+        ];
+        let garbage = unreferenced_images(images, &["quay.io/foo"]);
+        let ids = garbage.iter().map(|e| e.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["b", "c"]);
+    }
+
+    #[test]
+    fn test_bound_images_by_image() {
+        let img = |s: &str| BoundImage::new(s.into(), None).unwrap();
+        let bound = [
+            (
+                "staged".to_owned(),
+                vec![img("quay.io/foo"), img("quay.io/bar:v2")],
+            ),
+            ("booted".to_owned(), vec![img("quay.io/foo")]),
+        ];
+        let images = [stored("a", &["quay.io/foo:latest"], "sha256:1")];
+        let r = bound_images_by_image(bound, &images);
+        assert_eq!(
+            r,
+            [
+                BoundImageEntry {
+                    image: "quay.io/bar:v2".into(),
+                    deployments: vec!["staged".into()],
+                    present: false,
+                    digest: None,
+                    size: None,
+                },
+                BoundImageEntry {
+                    image: "quay.io/foo".into(),
+                    deployments: vec!["staged".into(), "booted".into()],
+                    present: true,
+                    digest: Some("sha256:1".into()),
+                    size: Some(1024),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_volume_file() -> Result<()> {
+        let v = parse_volume_file("data.volume", "[Volume]\n")?;
+        assert_eq!(v.name, "systemd-data");
+        let v = parse_volume_file(
+            "data.volume",
+            indoc::indoc! { r#"
+            [Unit]
+            Description=Data
+            [Volume]
+            VolumeName=appdata
+            Label=app=foo tier=db
+            Label=backup=yes
+            Type=tmpfs
+            Options=size=100m
+            "# },
+        )?;
+        assert_eq!(
+            v,
+            BoundVolume {
+                name: "appdata".into(),
+                labels: vec!["app=foo".into(), "tier=db".into(), "backup=yes".into()],
+                options: vec!["type=tmpfs".into(), "o=size=100m".into()],
+            }
+        );
+        assert!(parse_volume_file("x.volume", "[Volume]\nDriver=image\nImage=foo\n").is_err());
+        assert!(parse_volume_file("x.volume", "[Volume]\nVolumeName=%N\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_container_named_volumes() {
+        let c = indoc::indoc! { r#"
+            [Container]
+            Image=quay.io/foo/foo:latest
+            Volume=appdata:/var/lib/app:Z
+            Volume=cache.volume:/var/cache/app
+            Volume=/srv/host:/srv
+            Volume=%h/data:/data
+            Volume=/anonymous
+            # Volume=commented:/x
+            [Service]
+            Volume=notcontainer:/x
+        "# };
+        assert_eq!(container_named_volumes(c), ["appdata"]);
+    }
+
+    #[test]
+    fn test_query_bound_volumes() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(query_bound_volumes(td)?.is_empty());
+        td.create_dir_all(BOUND_IMAGE_DIR)?;
+        td.create_dir_all("usr/share/containers/systemd")?;
+        td.write(
+            "usr/share/containers/systemd/app.container",
+            "[Container]\nImage=quay.io/foo/foo:latest\nVolume=appdata:/data\nVolume=logs:/logs\n",
+        )?;
+        td.write(
+            "usr/share/containers/systemd/appdata.volume",
+            "[Volume]\nVolumeName=appdata\nLabel=app=foo\n",
+        )?;
+        for f in ["app.container", "appdata.volume"] {
+            td.symlink_contents(
+                format!("/usr/share/containers/systemd/{f}"),
+                format!("{BOUND_IMAGE_DIR}/{f}"),
+            )?;
+        }
+        let volumes = query_bound_volumes(td)?;
+        assert_eq!(
+            volumes,
+            [
+                BoundVolume {
+                    name: "appdata".into(),
+                    labels: vec!["app=foo".into()],
+                    options: Vec::new(),
+                },
+                BoundVolume {
+                    name: "logs".into(),
+                    labels: Vec::new(),
+                    options: Vec::new(),
+                },
+            ]
+        );
+        // Volumes don't contribute images
+        let images = query_bound_images(td)?;
+        assert_eq!(images.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_bound_units() -> Result<()> {
+        let td = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(query_bound_units(td)?.is_empty());
+        td.create_dir_all(BOUND_IMAGE_DIR)?;
+        td.create_dir_all("usr/share/containers/systemd")?;
+        let files = [
+            (
+                "app.container",
+                "[Container]\nImage=quay.io/foo/app:latest\n",
+            ),
+            (
+                "agent.container",
+                "[Container]\nImage=quay.io/foo/agent:latest\nServiceName=monitoring\n",
+            ),
+            ("base.image", "[Image]\nImage=quay.io/foo/base:latest\n"),
+            ("data.volume", "[Volume]\n"),
+        ];
+        for (f, contents) in files {
+            td.write(format!("usr/share/containers/systemd/{f}"), contents)?;
+            td.symlink_contents(
+                format!("/usr/share/containers/systemd/{f}"),
+                format!("{BOUND_IMAGE_DIR}/{f}"),
+            )?;
+        }
+        assert_eq!(
+            query_bound_units(td)?,
+            ["monitoring.service", "app.service", "base-image.service"]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_parse_spec_dir() -> Result<()> {
         const CONTAINER_IMAGE_DIR: &str = "usr/share/containers/systemd";
@@ -389,4 +1169,43 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_prune_candidates() {
+        let t = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+        let created = |mut e: ImageListEntry, s: &str| {
+            e.created = Some(t(s).timestamp());
+            e
+        };
+        let all = || {
+            vec![
+                created(
+                    stored("a", &["quay.io/example/app:1.0"], "sha256:aaaa"),
+                    "2024-01-01T00:00:00Z",
+                ),
+                created(stored("b", &[], "sha256:bbbb"), "2024-01-01T00:00:00Z"),
+                created(
+                    stored("c", &["quay.io/example/db:latest"], "sha256:cccc"),
+                    "2025-01-01T00:00:00Z",
+                ),
+                stored("d", &["quay.io/example/other:latest"], "sha256:dddd"),
+            ]
+        };
+        let ids = |r: Vec<ImageListEntry>| r.into_iter().map(|e| e.id).collect::<Vec<_>>();
+        let roots = ["quay.io/example/app:1.0", "quay.io/example/db"];
+
+        // Bound and pinned images are always kept
+        let f = PruneFilters::default();
+        assert_eq!(ids(prune_candidates(all(), &roots, &f)), ["b", "d"]);
+        let f = PruneFilters {
+            dangling: true,
+            ..Default::default()
+        };
+        assert_eq!(ids(prune_candidates(all(), &roots, &f)), ["b"]);
+        let f = PruneFilters {
+            older_than: Some(t("2024-06-01T00:00:00Z")),
+            ..Default::default()
+        };
+        assert_eq!(ids(prune_candidates(all(), &roots, &f)), ["b"]);
+    }
 }
@@ -6,6 +6,8 @@ use std::{
 };
 
 use anyhow::Result;
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::{self, fs::Dir};
 use clap::{Parser, Subcommand};
 
 use rustix::fs::CWD;
@@ -245,7 +247,26 @@ where
                 println!("{}", image_id.to_id());
             }
             OciCommand::Pull { ref image, name } => {
-                let (sha256, verity) = composefs_oci::pull(&repo, image, name.as_deref()).await?;
+                // composefs-oci runs its own image proxy, which doesn't know about the
+                // registry TLS configuration; if there is one, copy the image locally first.
+                let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+                let certdir = if image.starts_with("docker://") {
+                    ostree_ext::container::registry_certificate_directory(root, image)?
+                } else {
+                    None
+                };
+                let tmp = tempfile::tempdir_in("/var/tmp")?;
+                let local = format!("oci:{}", tmp.path().display());
+                let src = if let Some(certdir) = certdir {
+                    std::process::Command::new("skopeo")
+                        .args(["copy", "--preserve-digests", "--cert-dir", certdir.as_str()])
+                        .args([image, &local])
+                        .run_inherited_with_cmd_context()?;
+                    &local
+                } else {
+                    image
+                };
+                let (sha256, verity) = composefs_oci::pull(&repo, src, name.as_deref()).await?;
 
                 println!("sha256 {}", hex::encode(sha256));
                 println!("verity {}", verity.to_hex());
@@ -0,0 +1,341 @@
+//! # Pre-flight checks for upgrades
+//!
+//! Before `bootc upgrade` fetches and stages an update, a set of checks is run;
+//! if any of them fails, the upgrade is aborted (unless `--skip-checks` is given).
+//!
+//! - `disk-space`: The update (i.e. the layers to fetch) must fit in `/sysroot`,
+//!   leaving at least `min-free` available.
+//! - `battery`: When not on AC power, the battery must be charged to at least
+//!   `min-battery` percent.
+//! - `script:NAME`: Executables in `/usr/lib/bootc/checks.d/` (or `/etc/bootc/checks.d/`,
+//!   with the same precedence and masking as hooks) must succeed. They are invoked
+//!   with the image and the number of bytes to fetch in `BOOTC_*` environment variables;
+//!   the last line of their output is used as the reason for a failure.
+//!
+//! This can be configured in `/etc/bootc/checks.toml` (or `/usr/lib/bootc/checks.toml`):
+//!
+//! ```toml
+//! [checks]
+//! # Space which must remain free after fetching an update, in MiB or with a suffix
+//! min-free = "1G"
+//! # The minimum battery charge in percent when not on AC power; 0 disables the check
+//! min-battery = 20
+//! ```
+//!
+//! Each failure is logged to the journal with `MESSAGE_ID=c7b1e1a0f2a44f4f9d2f8e4d4e7a6c51`,
+//! with the check in `BOOTC_CHECK` and the reason in `BOOTC_CHECK_REASON`, and
+//! sent as a `CheckFailed` event to the `--progress-fd` (with `--json-fd-version 2`).
+
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::Deserialize;
+
+use crate::progress_jsonl::{Event, ProgressWriter};
+use crate::spec::ImageReference;
+
+/// The name of the configuration file and section; see [`crate::hostconfig`]
+const CONFIG_NAME: &str = "checks";
+/// Check script directories, in order of precedence
+const CHECK_DIRS: &[&str] = &["etc/bootc/checks.d", "usr/lib/bootc/checks.d"];
+const POWER_SUPPLY: &str = "sys/class/power_supply";
+/// The default minimum battery charge in percent
+const DEFAULT_MIN_BATTERY: u8 = 20;
+
+/// The `[checks]` section.
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ChecksConfigFile {
+    min_free: Option<String>,
+    min_battery: Option<u8>,
+}
+
+/// The effective check configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ChecksConfig {
+    /// Bytes which must remain free after fetching an update
+    pub(crate) min_free: u64,
+    /// The minimum battery charge in percent when not on AC power
+    pub(crate) min_battery: u8,
+}
+
+impl Default for ChecksConfig {
+    fn default() -> Self {
+        Self {
+            min_free: 0,
+            min_battery: DEFAULT_MIN_BATTERY,
+        }
+    }
+}
+
+fn parse_config(buf: &str) -> Result<ChecksConfig> {
+    let c: ChecksConfigFile = crate::hostconfig::parse_section(buf, CONFIG_NAME)?;
+    let mut r = ChecksConfig::default();
+    if let Some(v) = c.min_free.as_deref() {
+        r.min_free = bootc_blockdev::parse_size_mib(v)
+            .with_context(|| format!("Invalid size: {v}"))?
+            * 1024
+            * 1024;
+    }
+    if let Some(v) = c.min_battery {
+        anyhow::ensure!(v <= 100, "Invalid min-battery: {v}");
+        r.min_battery = v;
+    }
+    Ok(r)
+}
+
+/// Load the check configuration from the target root.
+#[context("Loading checks configuration")]
+pub(crate) fn load_config(root: &Dir) -> Result<ChecksConfig> {
+    crate::hostconfig::load(root, CONFIG_NAME, parse_config)
+}
+
+/// A failed check.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CheckFailure {
+    /// The identifier of the check, e.g. `disk-space` or `script:NAME`
+    pub(crate) check: String,
+    /// A description of why the check failed
+    pub(crate) reason: String,
+}
+
+impl std::fmt::Display for CheckFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.check, self.reason)
+    }
+}
+
+fn check_disk_space(avail: u64, bytes_to_fetch: u64, min_free: u64) -> Option<CheckFailure> {
+    let required = bytes_to_fetch.saturating_add(min_free);
+    (required > avail).then(|| CheckFailure {
+        check: "disk-space".into(),
+        reason: format!(
+            "Insufficient free space (available: {}, required: {} for the update and {} to remain free)",
+            ostree_ext::glib::format_size(avail),
+            ostree_ext::glib::format_size(bytes_to_fetch),
+            ostree_ext::glib::format_size(min_free),
+        ),
+    })
+}
+
+fn read_attr(d: &Dir, name: &str) -> Result<Option<String>> {
+    let Some(f) = d.open_optional(name)? else {
+        return Ok(None);
+    };
+    Ok(Some(std::io::read_to_string(f)?.trim().to_owned()))
+}
+
+/// Check the battery charge when running on battery power, as described by
+/// `sys/class/power_supply` in `root`.
+fn check_battery(root: &Dir, min_battery: u8) -> Result<Option<CheckFailure>> {
+    if min_battery == 0 {
+        return Ok(None);
+    }
+    let Some(supplies) = root.open_dir_optional(POWER_SUPPLY)? else {
+        return Ok(None);
+    };
+    let mut on_ac = false;
+    let mut capacity = None;
+    for ent in supplies.entries()? {
+        let ent = ent?;
+        let Ok(d) = supplies.open_dir(ent.file_name()) else {
+            continue;
+        };
+        match read_attr(&d, "type")?.as_deref() {
+            Some("Mains" | "USB") => {
+                on_ac |= read_attr(&d, "online")?.as_deref() == Some("1");
+            }
+            // Batteries of peripherals (e.g. a mouse) have a `Device` scope
+            Some("Battery") if read_attr(&d, "scope")?.as_deref() != Some("Device") => {
+                if let Some(v) = read_attr(&d, "capacity")?.and_then(|v| v.parse::<u8>().ok()) {
+                    capacity = capacity.max(Some(v));
+                }
+            }
+            _ => {}
+        }
+    }
+    let r = match capacity {
+        Some(v) if !on_ac && v < min_battery => Some(CheckFailure {
+            check: "battery".into(),
+            reason: format!("On battery power at {v}% (minimum: {min_battery}%)"),
+        }),
+        _ => None,
+    };
+    Ok(r)
+}
+
+/// Run a check script, returning the last line of its output on failure.
+fn run_script(path: &str, env: &[(&str, String)]) -> Result<Option<CheckFailure>> {
+    tracing::debug!("Running check {path}");
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let output = Command::new(path)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Running {path}"))?;
+    if output.status.success() {
+        return Ok(None);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = [stderr.as_ref(), stdout.as_ref()]
+        .into_iter()
+        .find_map(|s| s.lines().map(str::trim).rev().find(|l| !l.is_empty()))
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| format!("Failed: {}", output.status));
+    Ok(Some(CheckFailure {
+        check: format!("script:{name}"),
+        reason,
+    }))
+}
+
+/// Run all checks before fetching `bytes_to_fetch` bytes of `imgref` into `repo`.
+/// Fails with all failed checks.
+#[context("Running pre-flight checks")]
+pub(crate) async fn run(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    bytes_to_fetch: u64,
+    prog: &ProgressWriter,
+) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    let config = load_config(root)?;
+    let mut failures = Vec::new();
+
+    let stat = rustix::fs::fstatvfs(repo.dfd_borrow())?;
+    let avail = stat.f_frsize * stat.f_bavail;
+    failures.extend(check_disk_space(avail, bytes_to_fetch, config.min_free));
+    failures.extend(check_battery(root, config.min_battery)?);
+    let env = [
+        ("BOOTC_IMAGE", imgref.image.clone()),
+        ("BOOTC_IMAGE_TRANSPORT", imgref.transport.clone()),
+        ("BOOTC_BYTES_TO_FETCH", bytes_to_fetch.to_string()),
+    ];
+    for script in crate::hooks::find_executables(root, CHECK_DIRS)? {
+        failures.extend(run_script(&script, &env)?);
+    }
+
+    for failure in failures.iter() {
+        crate::journal::journal_send(
+            libsystemd::logging::Priority::Warning,
+            &format!("Pre-flight check failed: {failure}"),
+            [
+                ("MESSAGE_ID", crate::journal::CHECK_FAILED_ID),
+                ("BOOTC_CHECK", failure.check.as_str()),
+                ("BOOTC_CHECK_REASON", failure.reason.as_str()),
+            ]
+            .into_iter(),
+        );
+        prog.send(Event::CheckFailed {
+            check: failure.check.as_str().into(),
+            reason: failure.reason.as_str().into(),
+        })
+        .await;
+    }
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let failures = failures
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    anyhow::bail!("{failures}\n(use --skip-checks to override)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        assert_eq!(parse_config("")?, ChecksConfig::default());
+        let c = parse_config(indoc::indoc! { r#"
+            [checks]
+            min-free = "2G"
+            min-battery = 0
+        "# })?;
+        assert_eq!(
+            c,
+            ChecksConfig {
+                min_free: 2 << 30,
+                min_battery: 0,
+            }
+        );
+        assert!(parse_config("[checks]\nmin-battery = 101").is_err());
+        assert!(parse_config("[checks]\nmin-free = \"lots\"").is_err());
+        assert!(parse_config("[checks]\nfoo = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_disk_space() {
+        assert!(check_disk_space(100, 50, 50).is_none());
+        let f = check_disk_space(100, 50, 51).unwrap();
+        assert_eq!(f.check, "disk-space");
+        assert!(check_disk_space(100, u64::MAX, 1).is_some());
+    }
+
+    fn write_supply(root: &Dir, name: &str, attrs: &[(&str, &str)]) -> Result<()> {
+        let path = format!("{POWER_SUPPLY}/{name}");
+        root.create_dir_all(&path)?;
+        for (k, v) in attrs {
+            root.write(format!("{path}/{k}"), format!("{v}\n"))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_battery() -> Result<()> {
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        // No power supplies at all, e.g. a VM
+        assert!(check_battery(td, 20)?.is_none());
+        write_supply(td, "BAT0", &[("type", "Battery"), ("capacity", "15")])?;
+        write_supply(
+            td,
+            "hidpp_battery_0",
+            &[("type", "Battery"), ("scope", "Device"), ("capacity", "90")],
+        )?;
+        write_supply(td, "AC", &[("type", "Mains"), ("online", "0")])?;
+        let f = check_battery(td, 20)?.unwrap();
+        assert_eq!(f.check, "battery");
+        assert_eq!(f.reason, "On battery power at 15% (minimum: 20%)");
+        assert!(check_battery(td, 10)?.is_none());
+        assert!(check_battery(td, 0)?.is_none());
+        write_supply(td, "AC", &[("online", "1")])?;
+        assert!(check_battery(td, 20)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_script() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let exec = std::fs::Permissions::from_mode(0o755);
+        let pass = td.path().join("pass");
+        std::fs::write(&pass, "#!/bin/sh\ntest \"$BOOTC_IMAGE\" = foo\n")?;
+        std::fs::set_permissions(&pass, exec.clone())?;
+        let fail = td.path().join("50-metered");
+        std::fs::write(
+            &fail,
+            "#!/bin/sh\necho checking\necho 'Network is metered' >&2\nexit 1\n",
+        )?;
+        std::fs::set_permissions(&fail, exec)?;
+        let env = [("BOOTC_IMAGE", "foo".to_owned())];
+        assert!(run_script(pass.to_str().unwrap(), &env)?.is_none());
+        assert_eq!(
+            run_script(fail.to_str().unwrap(), &env)?.unwrap(),
+            CheckFailure {
+                check: "script:50-metered".into(),
+                reason: "Network is metered".into()
+            }
+        );
+        Ok(())
+    }
+}
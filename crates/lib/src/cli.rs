@@ -3,9 +3,8 @@
 //! Command line tool to manage bootable ostree-based containers.
 
 use std::ffi::{CString, OsStr, OsString};
-use std::io::Seek;
-use std::os::unix::process::CommandExt;
-use std::process::Command;
+use std::io::{Seek, Write};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
 
 use anyhow::{ensure, Context, Result};
 use camino::Utf8PathBuf;
@@ -27,9 +26,13 @@ use ostree_ext::ostree;
 use schemars::schema_for;
 use serde::{Deserialize, Serialize};
 
-use crate::deploy::RequiredHostSpec;
+use crate::deploy::{PullOptions, RequiredHostSpec};
+use crate::events::StatusChangeReason;
 use crate::lints;
-use crate::progress_jsonl::{ProgressWriter, RawProgressFd};
+use crate::offline::TargetImageOpts;
+use crate::progress_jsonl::{ProgressVersion, ProgressWriter, RawProgressFd};
+use crate::reboot::SoftRebootMode;
+use crate::reconcile::Operation;
 use crate::spec::Host;
 use crate::spec::ImageReference;
 use crate::utils::sigpolicy_from_opt;
@@ -43,6 +46,18 @@ pub(crate) struct ProgressOptions {
     /// format, where each value is separated by a newline.
     #[clap(long, hide = true)]
     pub(crate) progress_fd: Option<RawProgressFd>,
+
+    /// The version of the progress protocol to use with `--progress-fd`.
+    ///
+    /// Version 2 adds per-layer, phase transition and retry events.
+    #[clap(
+        long,
+        hide = true,
+        value_enum,
+        default_value = "1",
+        requires = "progress_fd"
+    )]
+    pub(crate) json_fd_version: ProgressVersion,
 }
 
 impl TryFrom<ProgressOptions> for ProgressWriter {
@@ -54,12 +69,106 @@ impl TryFrom<ProgressOptions> for ProgressWriter {
             .map(TryInto::try_into)
             .transpose()?
             .unwrap_or_default();
-        Ok(r)
+        Ok(r.with_version(value.json_fd_version))
+    }
+}
+
+/// Shared options for TLS communication with registries. These take precedence
+/// over the global configuration in `/etc/ostree/registry-tls.json`.
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct RegistryTlsOpts {
+    /// Path to a PEM bundle of additional certificate authorities to trust for the registry.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) tls_ca_bundle: Option<Utf8PathBuf>,
+
+    /// Path to a PEM client certificate to present to the registry (for mutual TLS).
+    #[clap(long, value_name = "PATH", requires = "tls_client_key")]
+    pub(crate) tls_client_cert: Option<Utf8PathBuf>,
+
+    /// Path to the PEM private key for `--tls-client-cert`.
+    #[clap(long, value_name = "PATH", requires = "tls_client_cert")]
+    pub(crate) tls_client_key: Option<Utf8PathBuf>,
+}
+
+impl RegistryTlsOpts {
+    /// Set the process-wide registry TLS configuration, if any options were provided.
+    fn apply(&self) -> Result<()> {
+        let config = ostree_container::RegistryTlsConfig {
+            ca_bundle: self.tls_ca_bundle.clone(),
+            client_cert: self.tls_client_cert.clone(),
+            client_key: self.tls_client_key.clone(),
+        };
+        if config.is_empty() {
+            return Ok(());
+        }
+        ostree_container::set_registry_tls_override(config)
+    }
+}
+
+/// Shared options for authenticating to registries.
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct RegistryAuthOpts {
+    /// Path to a container authentication file (see `containers-auth.json(5)`); this
+    /// takes precedence over the `registry-auth` secret and `/etc/ostree/auth.json`.
+    #[clap(long, value_name = "PATH")]
+    pub(crate) auth_file: Option<Utf8PathBuf>,
+}
+
+impl RegistryAuthOpts {
+    /// Use the authentication file for fetching images, if one was provided.
+    pub(crate) fn apply(&self, pull: &mut PullOptions) -> Result<()> {
+        if let Some(path) = self.auth_file.as_ref() {
+            std::fs::metadata(path).with_context(|| format!("Accessing authfile {path}"))?;
+            pull.authfile = Some(path.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Shared options for downloading images, e.g. to avoid saturating a constrained uplink.
+#[derive(Debug, Default, Clone, Parser, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct FetchOpts {
+    /// The number of image layers to download concurrently (default 1).
+    #[clap(long, value_name = "N")]
+    #[serde(default)]
+    pub(crate) fetch_jobs: Option<NonZeroUsize>,
+
+    /// Limit the total bandwidth used to download images, in bytes per second with an
+    /// optional `K`, `M` or `G` suffix (powers of 1024), e.g. `2M`.
+    #[clap(long, value_name = "RATE", value_parser = parse_bandwidth)]
+    #[serde(default)]
+    pub(crate) limit_bandwidth: Option<NonZeroU64>,
+}
+
+impl FetchOpts {
+    /// Use the download options for fetching images.
+    pub(crate) fn apply(&self, pull: &mut PullOptions) {
+        pull.fetch_jobs = self.fetch_jobs;
+        pull.bandwidth_limit = self.limit_bandwidth;
     }
 }
 
+/// Parse a bandwidth in bytes per second, e.g. `500K` or `2M`.
+fn parse_bandwidth(s: &str) -> Result<NonZeroU64> {
+    let (n, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let n = n
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .and_then(NonZeroU64::new)
+        .ok_or_else(|| anyhow::anyhow!("Invalid bandwidth: {s}"))?;
+    Ok(n)
+}
+
 /// Perform an upgrade operation
 #[derive(Debug, Parser, PartialEq, Eq)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub(crate) struct UpgradeOpts {
     /// Don't display progress
     #[clap(long)]
@@ -69,19 +178,102 @@ pub(crate) struct UpgradeOpts {
     ///
     /// This only downloads an updated manifest and image configuration (i.e. typically kilobyte-sized metadata)
     /// as opposed to the image layers.
+    ///
+    /// This may be run as an unprivileged user; in that case the update metadata
+    /// is not cached on disk.
     #[clap(long, conflicts_with = "apply")]
     pub(crate) check: bool,
 
     /// Restart or reboot into the new target image.
     ///
-    /// Currently, this option always reboots.  In the future this command
-    /// will detect the case where no kernel changes are queued, and perform
-    /// a userspace-only restart.
-    #[clap(long, conflicts_with = "check")]
+    /// By default this performs a full reboot; see `--soft-reboot`.
+    #[clap(long, conflicts_with_all = ["check", "target_image"])]
     pub(crate) apply: bool,
 
+    /// With `--apply`, only restart userspace (`systemctl soft-reboot`) if the kernel,
+    /// initramfs and kernel arguments are unchanged. With `auto`, a full reboot is
+    /// performed otherwise; with `required`, this is an error.
+    #[clap(long, requires = "apply")]
+    pub(crate) soft_reboot: Option<SoftRebootMode>,
+
+    /// Download the updated image without staging it.
+    ///
+    /// Use `bootc upgrade finalize` to stage the downloaded image later, without network access.
+    #[clap(long, conflicts_with_all = ["check", "apply"])]
+    pub(crate) download_only: bool,
+
+    /// Allow staging an image which is older than the booted one, as determined
+    /// by its `containers.bootc.generation` label, version or creation timestamp.
+    #[clap(long)]
+    pub(crate) allow_downgrade: bool,
+
+    /// Allow upgrading a deployment which was locked to a digest via `bootc switch --lock-digest`.
+    /// If an update to the tracked tag is staged, its deployment is no longer locked.
+    #[clap(long)]
+    pub(crate) unlock: bool,
+
+    /// Don't run the pre-flight checks (e.g. for free disk space and battery charge)
+    /// before fetching and staging an update.
+    #[clap(long, conflicts_with = "check")]
+    pub(crate) skip_checks: bool,
+
+    /// Don't stage the update if the rollout policy in `/etc/bootc/rollout.toml`
+    /// (channel delay, wave, update windows and health gates) would hold it.
+    #[clap(long, conflicts_with_all = ["check", "download_only"])]
+    pub(crate) respect_rollout: bool,
+
+    /// Select this platform (`OS/ARCH[/VARIANT]`, e.g. `linux/arm64`) from a multi-platform
+    /// image instead of the one matching the host. By default, the platform recorded by a
+    /// previous `--platform` is used.
+    #[clap(long, value_name = "PLATFORM")]
+    pub(crate) platform: Option<String>,
+
+    #[clap(flatten)]
+    pub(crate) tls: RegistryTlsOpts,
+
+    #[clap(flatten)]
+    pub(crate) auth: RegistryAuthOpts,
+
+    #[clap(flatten)]
+    pub(crate) fetch: FetchOpts,
+
     #[clap(flatten)]
     pub(crate) progress: ProgressOptions,
+
+    #[clap(flatten)]
+    pub(crate) target: TargetImageOpts,
+
+    #[clap(subcommand)]
+    pub(crate) cmd: Option<UpgradeCmd>,
+}
+
+/// Subcommands of `bootc upgrade`
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum UpgradeCmd {
+    /// Stage an image previously fetched with `bootc upgrade --download-only`.
+    ///
+    /// This writes the deployment and boot entries without accessing the network.
+    Finalize(UpgradeFinalizeOpts),
+}
+
+/// Options for `bootc upgrade finalize`
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct UpgradeFinalizeOpts {
+    /// Restart or reboot into the new target image.
+    #[clap(long)]
+    pub(crate) apply: bool,
+
+    /// With `--apply`, only restart userspace if possible; see `bootc upgrade --soft-reboot`.
+    #[clap(long, requires = "apply")]
+    pub(crate) soft_reboot: Option<SoftRebootMode>,
+
+    /// Allow staging an image which is older than the booted one.
+    #[clap(long)]
+    pub(crate) allow_downgrade: bool,
+
+    /// Don't run the pre-flight checks before staging; see `bootc upgrade --skip-checks`.
+    #[clap(long)]
+    pub(crate) skip_checks: bool,
 }
 
 /// Perform an switch operation
@@ -93,12 +285,14 @@ pub(crate) struct SwitchOpts {
 
     /// Restart or reboot into the new target image.
     ///
-    /// Currently, this option always reboots.  In the future this command
-    /// will detect the case where no kernel changes are queued, and perform
-    /// a userspace-only restart.
-    #[clap(long)]
+    /// By default this performs a full reboot; see `--soft-reboot`.
+    #[clap(long, conflicts_with = "target_image")]
     pub(crate) apply: bool,
 
+    /// With `--apply`, only restart userspace if possible; see `bootc upgrade --soft-reboot`.
+    #[clap(long, requires = "apply")]
+    pub(crate) soft_reboot: Option<SoftRebootMode>,
+
     /// The transport; e.g. oci, oci-archive, containers-storage.  Defaults to `registry`.
     #[clap(long, default_value = "registry")]
     pub(crate) transport: String,
@@ -118,18 +312,64 @@ pub(crate) struct SwitchOpts {
     /// Don't create a new deployment, but directly mutate the booted state.
     /// This is hidden because it's not something we generally expect to be done,
     /// but this can be used in e.g. Anaconda %post to fixup
-    #[clap(long, hide = true)]
+    #[clap(long, hide = true, conflicts_with = "target_image")]
     pub(crate) mutate_in_place: bool,
 
+    /// Retarget the booted deployment to the new image reference without fetching
+    /// or creating a new deployment, if its manifest digest is identical to the
+    /// booted image (e.g. when changing the registry mirror hostname).
+    ///
+    /// The origin is rewritten and kernel arguments are merged in place. If the digest
+    /// differs, this falls back to a regular switch. This is refused if a deployment
+    /// is already staged.
+    #[clap(long, conflicts_with = "mutate_in_place")]
+    pub(crate) in_place: bool,
+
     /// Retain reference to currently booted image
     #[clap(long)]
     pub(crate) retain: bool,
 
+    /// If the target image declares a preferred stateroot via the `containers.bootc.stateroot`
+    /// label which differs from the current one, create that stateroot (if necessary) and
+    /// deploy into it. Without this option, the image is deployed into the current stateroot.
+    #[clap(long)]
+    pub(crate) auto_stateroot: bool,
+
+    /// How to initialize `/var` when `--auto-stateroot` creates a new stateroot.
+    #[clap(long, value_enum, default_value_t, requires = "auto_stateroot")]
+    pub(crate) stateroot_var: crate::deploy::StaterootVarPolicy,
+
+    /// The target must be of the form `IMAGE[:TAG]@sha256:...`; fetch and deploy exactly
+    /// that digest, while recording the tag in the origin for reference.
+    ///
+    /// `bootc upgrade` will refuse to change the deployment unless `--unlock` is passed;
+    /// a later `bootc switch` without this option also removes the lock.
+    #[clap(long, conflicts_with_all = ["mutate_in_place", "in_place"])]
+    pub(crate) lock_digest: bool,
+
+    /// Select this platform (`OS/ARCH[/VARIANT]`, e.g. `linux/arm64`) from a multi-platform
+    /// image instead of the one matching the host. It is recorded in the deployment, and
+    /// used by later upgrades.
+    #[clap(long, value_name = "PLATFORM")]
+    pub(crate) platform: Option<String>,
+
     /// Target image to use for the next boot.
     pub(crate) target: String,
 
+    #[clap(flatten)]
+    pub(crate) tls: RegistryTlsOpts,
+
+    #[clap(flatten)]
+    pub(crate) auth: RegistryAuthOpts,
+
+    #[clap(flatten)]
+    pub(crate) fetch: FetchOpts,
+
     #[clap(flatten)]
     pub(crate) progress: ProgressOptions,
+
+    #[clap(flatten)]
+    pub(crate) target_image: TargetImageOpts,
 }
 
 /// Options controlling rollback
@@ -137,11 +377,302 @@ pub(crate) struct SwitchOpts {
 pub(crate) struct RollbackOpts {
     /// Restart or reboot into the rollback image.
     ///
-    /// Currently, this option always reboots.  In the future this command
-    /// will detect the case where no kernel changes are queued, and perform
-    /// a userspace-only restart.
+    /// By default this performs a full reboot; see `--soft-reboot`.
     #[clap(long)]
     pub(crate) apply: bool,
+
+    /// With `--apply`, only restart userspace if possible; see `bootc upgrade --soft-reboot`.
+    #[clap(long, requires = "apply")]
+    pub(crate) soft_reboot: Option<SoftRebootMode>,
+
+    /// Only roll back if a monitored unit fails after booting into a new deployment.
+    ///
+    /// The units and the monitoring window are configured in `/etc/bootc/health.toml`;
+    /// this is run by `bootc-health-check.service`. With `--apply`, the system is
+    /// only rebooted if a rollback was queued.
+    #[clap(long)]
+    pub(crate) auto_on_failure: bool,
+}
+
+/// Options for `bootc reboot`
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct RebootOpts {
+    /// Only restart userspace (`systemctl soft-reboot`); this fails unless the kernel,
+    /// initramfs and kernel arguments of the queued deployment are unchanged.
+    #[clap(long)]
+    pub(crate) soft: bool,
+}
+
+/// Options shared by the kernel argument operations
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct KargsCommonOpts {
+    /// Also apply the changes to the running system where this is safe; see
+    /// `bootc kargs apply-live`.
+    #[clap(long, conflicts_with = "target_image")]
+    pub(crate) apply_live: bool,
+}
+
+/// Operations on kernel arguments
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum KargsOpts {
+    /// Append kernel arguments; arguments which are already present are skipped.
+    Append {
+        #[clap(required = true)]
+        kargs: Vec<String>,
+
+        #[clap(flatten)]
+        common: KargsCommonOpts,
+
+        #[clap(flatten)]
+        target: TargetImageOpts,
+    },
+    /// Delete kernel arguments. An argument of the form `key=value` must match exactly;
+    /// `key` deletes all arguments with that key.
+    Delete {
+        #[clap(required = true)]
+        kargs: Vec<String>,
+
+        #[clap(flatten)]
+        target: TargetImageOpts,
+    },
+    /// Edit the kernel arguments in `$EDITOR`, one per line.
+    Edit {
+        #[clap(flatten)]
+        common: KargsCommonOpts,
+
+        #[clap(flatten)]
+        target: TargetImageOpts,
+    },
+    /// Set kernel arguments for the next boot, and apply them to the running system
+    /// where they have a runtime equivalent.
+    ///
+    /// Arguments replace any existing ones with the same key. The arguments which can be
+    /// applied live are `loglevel`, `panic`, `nmi_watchdog`, `transparent_hugepage`,
+    /// `sysctl.kernel.panic_on_oops` and `sysctl.vm.swappiness`; any others are reported
+    /// and only take effect after a reboot.
+    ApplyLive {
+        #[clap(required = true)]
+        kargs: Vec<String>,
+    },
+}
+
+/// Options for the `usr-overlay` command
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct UsrOverlayOpts {
+    /// Store the overlay persistently and re-apply it on boot (for the same deployment).
+    #[clap(long)]
+    pub(crate) persistent: bool,
+
+    /// Back the transient overlay with a tmpfs limited to this size, e.g. `512M` or `10%`
+    /// (of the system memory).
+    #[clap(long, conflicts_with = "persistent")]
+    pub(crate) size_limit: Option<String>,
+
+    #[clap(subcommand)]
+    pub(crate) cmd: Option<UsrOverlayCmd>,
+}
+
+/// Subcommands for `usr-overlay`
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum UsrOverlayCmd {
+    /// Discard a persistent overlay; if it is mounted, it is removed on the next boot.
+    Reset,
+}
+
+/// Operations on host secrets
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum SecretsOpts {
+    /// Store a secret, replacing any existing value.
+    ///
+    /// The value is read from standard input unless `--from-file` is given.
+    Set {
+        /// The name of the secret; may contain ASCII letters, digits, `-`, `_` and `.`.
+        name: String,
+
+        /// Read the value from this file.
+        #[clap(long)]
+        from_file: Option<Utf8PathBuf>,
+
+        /// Also make the secret available to containers via `podman secret`.
+        #[clap(long)]
+        podman: bool,
+    },
+    /// List the stored secrets; values are never shown.
+    List {
+        #[clap(long)]
+        #[arg(default_value_t)]
+        format: ImageListFormat,
+    },
+    /// Remove a secret, including any podman secret created from it.
+    #[clap(alias = "rm")]
+    Remove {
+        /// The name of the secret.
+        name: String,
+    },
+}
+
+/// Operations on local modifications to /etc
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum EtcOpts {
+    /// List the files in /etc which differ from the image defaults in /usr/etc.
+    Diff {
+        #[clap(long)]
+        #[arg(default_value_t)]
+        format: ImageListFormat,
+    },
+    /// Drop local modifications, restoring the image default.
+    ///
+    /// Files added locally are removed; directories are reset recursively.
+    Reset {
+        /// Paths in /etc to reset.
+        #[clap(required = true)]
+        paths: Vec<Utf8PathBuf>,
+    },
+}
+
+/// Operations on deployments
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum DeploymentOpts {
+    /// Pin a deployment, so that it is kept when new deployments are created.
+    ///
+    /// Pinned deployments, and the images they use, are never garbage collected.
+    Pin {
+        /// The deployment: `booted`, `rollback`, its index as shown by `ostree admin status`,
+        /// or (a prefix of) its commit checksum, optionally followed by `.<serial>`.
+        id: crate::deploy::DeploymentId,
+    },
+    /// Unpin a deployment, allowing it to be garbage collected.
+    Unpin {
+        /// The deployment: `booted`, `rollback`, its index as shown by `ostree admin status`,
+        /// or (a prefix of) its commit checksum, optionally followed by `.<serial>`.
+        id: crate::deploy::DeploymentId,
+    },
+}
+
+/// Operations on system extensions
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum ExtensionOpts {
+    /// List the stored extensions, and whether they support the booted image.
+    List {
+        #[clap(long)]
+        #[arg(default_value_t)]
+        format: ImageListFormat,
+    },
+    /// Store a new extension; it is not enabled.
+    Add {
+        /// Path to the extension image; its name is the file name without `.raw`.
+        path: Utf8PathBuf,
+    },
+    /// Replace a stored extension with a new version of it.
+    ///
+    /// If the extension is enabled, the new version must support the booted image.
+    Update {
+        /// Path to the extension image; its name is the file name without `.raw`.
+        path: Utf8PathBuf,
+        /// Also apply the new version to the running system.
+        #[clap(long)]
+        now: bool,
+    },
+    /// Enable an extension, so that it is merged into `/usr` at boot.
+    ///
+    /// This is refused if the extension does not support the booted image.
+    Enable {
+        /// The name of the extension.
+        name: String,
+        /// Also merge the extension into the running system.
+        #[clap(long)]
+        now: bool,
+    },
+    /// Disable an extension.
+    Disable {
+        /// The name of the extension.
+        name: String,
+        /// Also unmerge the extension from the running system.
+        #[clap(long)]
+        now: bool,
+    },
+    /// Remove a stored extension, which must not be enabled.
+    #[clap(alias = "rm")]
+    Remove {
+        /// The name of the extension.
+        name: String,
+    },
+    /// Remove the extensions which are not enabled and do not support any deployed image.
+    Prune,
+}
+
+/// Operations on stateroots
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum StaterootOpts {
+    /// List the stateroots, with their deployments and disk usage.
+    List {
+        #[clap(long)]
+        #[arg(default_value_t)]
+        format: ImageListFormat,
+    },
+    /// Create a new, empty stateroot.
+    Create {
+        /// The name of the stateroot; may contain ASCII letters, digits, `-`, `_` and `.`.
+        name: String,
+    },
+    /// Delete a stateroot, including its `/var`; this is refused if any deployment uses it.
+    #[clap(alias = "rm")]
+    Delete {
+        /// The name of the stateroot.
+        name: String,
+    },
+}
+
+/// Operations on offline update bundles
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum UpdateBundleOpts {
+    /// Create an update bundle from a container image.
+    ///
+    /// This does not require a bootc system; it only needs `skopeo`.
+    Create {
+        /// The transport; e.g. oci, oci-archive, containers-storage.  Defaults to `registry`.
+        #[clap(long, default_value = "registry")]
+        transport: String,
+
+        /// The container image; systems which apply the bundle will track it for later updates.
+        image: String,
+
+        /// Write the bundle to this path.
+        output: Utf8PathBuf,
+
+        /// Sign the bundle with this PEM private key.
+        #[clap(long)]
+        sign_key: Option<Utf8PathBuf>,
+    },
+    /// Queue the image in an update bundle for the next boot, without network access.
+    ///
+    /// Logically bound images must already be present.
+    Apply {
+        /// Path to the bundle.
+        bundle: Utf8PathBuf,
+
+        /// Require the bundle to be signed by the private key corresponding to
+        /// this PEM public key.
+        ///
+        /// This is required if the host verifies image signatures.
+        #[clap(long)]
+        verify_key: Option<Utf8PathBuf>,
+
+        /// Don't display progress
+        #[clap(long)]
+        quiet: bool,
+
+        /// Restart or reboot into the new target image.
+        ///
+        /// Currently, this option always reboots.  In the future this command
+        /// will detect the case where no kernel changes are queued, and perform
+        /// a userspace-only restart.
+        #[clap(long)]
+        apply: bool,
+
+        #[clap(flatten)]
+        progress: ProgressOptions,
+    },
 }
 
 /// Perform an edit operation
@@ -154,6 +685,14 @@ pub(crate) struct EditOpts {
     /// Don't display progress
     #[clap(long)]
     pub(crate) quiet: bool,
+
+    /// Print the operations needed to reach the new specification as JSON, without
+    /// performing them.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+
+    #[clap(flatten)]
+    pub(crate) target: TargetImageOpts,
 }
 
 #[derive(Debug, Clone, ValueEnum, PartialEq, Eq)]
@@ -194,6 +733,51 @@ pub(crate) struct StatusOpts {
     /// Include additional fields in human readable format.
     #[clap(long, short = 'v')]
     pub(crate) verbose: bool,
+
+    /// Compute the disk space used by each deployment and each layer of its image,
+    /// split into exclusive and shared usage; the layers are only shown with
+    /// `--verbose`. This walks the whole repository and may be slow.
+    #[clap(long)]
+    pub(crate) show_usage: bool,
+
+    /// Show the layers of the staged and booted images, from local metadata.
+    /// Only supported for human readable output.
+    #[clap(long)]
+    pub(crate) layers: bool,
+}
+
+/// Show the files changed between deployments
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct DiffOpts {
+    /// Compare with the staged deployment; this is the default.
+    #[clap(long, conflicts_with_all = ["rollback", "image"])]
+    pub(crate) staged: bool,
+
+    /// Compare with the rollback deployment.
+    #[clap(long, conflicts_with = "image")]
+    pub(crate) rollback: bool,
+
+    /// Compare with an image in the bootc storage, as listed by `bootc image list`.
+    pub(crate) image: Option<String>,
+
+    /// Only show changes in this directory, e.g. `/usr/lib`.
+    #[clap(long)]
+    pub(crate) path: Option<Utf8PathBuf>,
+
+    #[clap(long)]
+    #[arg(default_value_t)]
+    pub(crate) format: ImageListFormat,
+}
+
+impl DiffOpts {
+    pub(crate) fn target(&self) -> crate::diff::DiffTarget {
+        use crate::diff::DiffTarget;
+        match (self.rollback, self.image.as_deref()) {
+            (true, _) => DiffTarget::Rollback,
+            (false, Some(image)) => DiffTarget::Image(image.to_owned()),
+            (false, None) => DiffTarget::Staged,
+        }
+    }
 }
 
 #[derive(Debug, clap::Subcommand, PartialEq, Eq)]
@@ -210,6 +794,13 @@ pub(crate) enum InstallOpts {
     /// complex such as RAID, LVM, LUKS etc.
     #[cfg(feature = "install-to-disk")]
     ToDisk(crate::install::InstallToDiskOpts),
+    /// Install to a new disk image file, producing a raw or qcow2 artifact.
+    ///
+    /// This is a convenience wrapper for `install to-disk --via-loopback` which also
+    /// allocates the file, optionally converts it to qcow2 and compresses it, and
+    /// writes a JSON manifest describing the disk image.
+    #[cfg(feature = "install-to-disk")]
+    ToLoopbackImage(crate::install::loopback::InstallToLoopbackImageOpts),
     /// Install to an externally created filesystem structure.
     ///
     /// In this variant of installation, the root filesystem alongside any necessary
@@ -286,10 +877,26 @@ pub(crate) enum ContainerOpts {
         #[clap(long)]
         skip: Vec<String>,
 
+        /// Run the targeted lints even if `/usr/lib/bootc/lint-config.toml` skips them
+        /// by default.
+        #[clap(long, value_name = "LINT")]
+        include: Vec<String>,
+
+        /// Override the type of a lint, taking precedence over the lint configuration.
+        ///
+        /// Example: --severity var-log=fatal
+        #[clap(long, value_name = "LINT=TYPE", value_parser = lints::parse_severity_override)]
+        severity: Vec<(String, lints::LintType)>,
+
         /// Don't truncate the output. By default, only a limited number of entries are
         /// shown for each lint, followed by a count of remaining entries.
         #[clap(long)]
         no_truncate: bool,
+
+        /// The output format. The JSON output of a lint run has a stable structure, with
+        /// the name, type, status, message and number of truncated entries of each lint.
+        #[clap(long, default_value_t)]
+        format: lints::LintFormat,
     },
 }
 
@@ -366,6 +973,15 @@ pub(crate) enum ImageOpts {
         #[arg(default_value_t)]
         list_format: ImageListFormat,
     },
+    /// List the logically bound images of all deployments.
+    ///
+    /// For each image, this shows the deployments referencing it, and whether
+    /// it is present in the bootc storage along with its digest and size.
+    ListBound {
+        #[clap(long = "format")]
+        #[arg(default_value_t)]
+        list_format: ImageListFormat,
+    },
     /// Copy a container image from the bootc storage to `containers-storage:`.
     ///
     /// The source and target are both optional; if both are left unspecified,
@@ -378,6 +994,14 @@ pub(crate) enum ImageOpts {
     /// It is also possible to copy an image other than the currently booted one by
     /// specifying `--source`.
     ///
+    /// ## Copying all images
+    ///
+    /// With `--all`, the booted, staged and rollback images are copied along with all
+    /// logically bound images, each keeping its original name. This is useful for
+    /// replicating a system's images to an air-gapped environment. In this mode,
+    /// `--target` may be `containers-storage` (the default) or `oci-archive:<directory>`,
+    /// which writes one archive per image into the directory.
+    ///
     /// ## Pulling images
     ///
     /// At the current time there is no explicit support for pulling images other than indirectly
@@ -391,12 +1015,76 @@ pub(crate) enum ImageOpts {
         /// The destination; if not specified, then the default is to push to `containers-storage:localhost/bootc`;
         /// this will make the image accessible via e.g. `podman run localhost/bootc` and for builds.
         target: Option<String>,
+
+        /// Copy all host images and logically bound images.
+        #[clap(long, conflicts_with = "source")]
+        all: bool,
     },
     /// Copy a container image from the default `containers-storage:` to the bootc-owned container storage.
     PullFromDefaultStorage {
         /// The image to pull
+        #[clap(required_unless_present = "all")]
+        image: Option<String>,
+
+        /// Copy all logically bound images referenced by the deployments.
+        #[clap(long, conflicts_with = "image")]
+        all: bool,
+    },
+    /// Hold a logically bound image at its currently stored version.
+    ///
+    /// While a repository is pinned, new deployments whose bound images reference
+    /// another tag of it (e.g. after `bootc upgrade`) use the pinned version
+    /// instead of fetching that tag. `bootc status` shows pinned images, and
+    /// whether the deployment references a different version.
+    Pin {
+        /// The bound image reference, e.g. `quay.io/example/app:1.0`
         image: String,
     },
+    /// Remove the pin of a logically bound image, fetching the versions
+    /// referenced by the deployments.
+    Unpin {
+        /// The bound image reference or repository
+        image: String,
+    },
+    /// Remove images from the bootc storage used for logically bound images.
+    ///
+    /// Images which are neither bound to a deployment nor pinned, and which match
+    /// all of the given filters, are removed.  The size of the storage before and
+    /// after is shown.
+    ///
+    /// Note that such images are also removed automatically whenever a new
+    /// deployment is staged.
+    Prune {
+        /// Keep the logically bound images of all deployments, and pinned images.
+        /// This is always done; the option is accepted for compatibility.
+        #[clap(long, default_value_t = true)]
+        keep_bound: bool,
+
+        /// Only remove images created longer ago than this, e.g. `30d`
+        /// (with a unit of `s`, `m`, `h`, `d` or `w`).
+        #[clap(long, value_name = "DURATION", value_parser = bootc_utils::parse_duration)]
+        older_than: Option<chrono::Duration>,
+
+        /// Only remove images without a name.
+        #[clap(long)]
+        dangling: bool,
+    },
+    /// Export the booted deployment as a container image, e.g. to capture a
+    /// configured machine as a golden image.
+    ///
+    /// The image keeps the runtime configuration and labels of the booted image.
+    /// With `--include-etc`, local modifications to /etc become part of the image
+    /// defaults; machine-specific files such as `/etc/machine-id` and SSH host
+    /// keys are left out.  Local state in /var is never included.
+    Export {
+        /// The destination, e.g. `docker://quay.io/example/golden:latest` or
+        /// `oci-archive:/var/tmp/golden.tar`.
+        target: String,
+
+        /// Include local modifications to /etc.
+        #[clap(long)]
+        include_etc: bool,
+    },
     /// Wrapper for selected `podman image` subcommands in bootc storage.
     #[clap(subcommand)]
     Cmd(ImageCmdOpts),
@@ -416,10 +1104,21 @@ pub(crate) enum FsverityOpts {
         /// Path to file
         path: Utf8PathBuf,
     },
-    /// Enable fsverity on the target file.
+    /// Enable fsverity on the target file, or if no file is provided, on all
+    /// ostree and composefs objects which lack it (where supported).
     Enable {
-        /// Ptah to file
-        path: Utf8PathBuf,
+        /// Path to file
+        path: Option<Utf8PathBuf>,
+        /// Also configure the ostree repository to require fsverity for all objects.
+        #[clap(long, conflicts_with = "path")]
+        require: bool,
+    },
+    /// Show the expected and actual fsverity state of the ostree and composefs
+    /// object stores.
+    Status {
+        /// Output in JSON format
+        #[clap(long)]
+        json: bool,
     },
 }
 
@@ -434,6 +1133,8 @@ pub(crate) enum InternalsOpts {
         late_dir: Option<Utf8PathBuf>,
     },
     FixupEtcFstab,
+    /// Mount the persistent /usr overlay, if any; invoked at boot.
+    ApplyDevOverlay,
     /// Should only be used by `make update-generated`
     PrintJsonSchema {
         #[clap(long)]
@@ -442,19 +1143,83 @@ pub(crate) enum InternalsOpts {
     #[clap(subcommand)]
     Fsverity(FsverityOpts),
     /// Perform consistency checking.
-    Fsck,
+    Fsck {
+        /// Attempt to repair problems: enable fsverity on objects missing it,
+        /// and remove damaged composefs content so it is downloaded again.
+        #[clap(long)]
+        repair: bool,
+    },
     /// Perform cleanup actions
     Cleanup,
-    Relabel {
+    /// Mark the booted deployment as validated; invoked once
+    /// `boot-complete.target` is reached.
+    MarkValidated,
+    /// Relabel `/etc` and `/var` if the SELinux policy changed with the booted
+    /// deployment; invoked at boot.
+    RelabelAfterPolicyChange,
+    /// Add IMA signatures to all files of the staged (or booted) deployment
+    /// using `evmctl`, and stage the signed result.
+    ImaSign {
+        /// Path to the IMA private key
         #[clap(long)]
-        /// Relabel using this path as root
-        as_path: Option<Utf8PathBuf>,
+        key: Utf8PathBuf,
 
-        /// Relabel this path
-        path: Utf8PathBuf,
+        /// Digest algorithm
+        #[clap(long, default_value = "sha256")]
+        algorithm: String,
+
+        /// Replace any existing IMA signatures
+        #[clap(long)]
+        overwrite: bool,
     },
-    /// Proxy frontend for the `ostree-ext` CLI.
-    OstreeExt {
+    /// Prune objects, images and splitstreams in the composefs repository
+    /// which are not referenced by any deployment or named reference.
+    ///
+    /// This also happens automatically after deploying or rolling back if there
+    /// are composefs deployments (in `/sysroot/state/deploy`).
+    CleanupComposefs {
+        /// Only print what would be pruned.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Export an image from the composefs repository as an OCI image layout,
+    /// with uncompressed layers. Without arguments, list the images which
+    /// can be exported.
+    RepoExport {
+        /// The image: a named reference, or the digest of its configuration
+        #[clap(requires = "target")]
+        image: Option<String>,
+
+        /// Destination, as `oci:<directory>` or `oci-archive:<path>`
+        target: Option<crate::store::composefs_export::ExportTarget>,
+
+        /// Name to record for the image in the OCI layout (`org.opencontainers.image.ref.name`)
+        #[clap(long)]
+        ref_name: Option<String>,
+    },
+    /// Print when this host would apply an update published at the given time,
+    /// according to its rollout configuration (channel, waves, windows and health gates).
+    SimulateRollout {
+        /// Publication time of the hypothetical update (RFC 3339); defaults to now.
+        #[clap(long)]
+        published: Option<String>,
+        /// Simulate as if this host were in the given (zero-based) wave.
+        #[clap(long)]
+        wave: Option<u32>,
+    },
+    /// Print a JSON line for each status change event as it happens; see
+    /// the documentation for `/ostree/bootc/events`.
+    WatchEvents,
+    Relabel {
+        #[clap(long)]
+        /// Relabel using this path as root
+        as_path: Option<Utf8PathBuf>,
+
+        /// Relabel this path
+        path: Utf8PathBuf,
+    },
+    /// Proxy frontend for the `ostree-ext` CLI.
+    OstreeExt {
         #[clap(allow_hyphen_values = true)]
         args: Vec<OsString>,
     },
@@ -490,6 +1255,66 @@ pub(crate) enum InternalsOpts {
         // The stateroot
         stateroot: String,
     },
+    /// Compute the changes installing the bootloader would make, without
+    /// making them, and print them as JSON.
+    BootloaderPlan {
+        /// The target block device
+        #[clap(long)]
+        device: Utf8PathBuf,
+
+        /// The root of the deployment providing the bootloader binaries
+        #[clap(long, default_value = "/")]
+        src_root: Utf8PathBuf,
+
+        /// Install all bootloader types and skip changes to the system firmware
+        #[clap(long)]
+        generic_image: bool,
+
+        /// The filesystem UUID of the boot partition (required for zipl)
+        #[clap(long)]
+        boot_uuid: Option<String>,
+
+        /// The mounted target root filesystem
+        rootfs: Utf8PathBuf,
+    },
+    /// Copy the boot entries and kernels into the ESP if systemd-boot is in use;
+    /// run at shutdown after a staged deployment is finalized.
+    SyncSystemdBoot,
+    /// Copy the contents of the EFI system partition to the mirrors created by
+    /// `bootc install to-disk --mirror-esp`.
+    SyncEsp,
+    /// Run the upgrade hooks of the given phase for the staged deployment.
+    RunHooks {
+        phase: crate::hooks::HookPhase,
+    },
+    /// Mount an image read-only, for inspection without booting it.
+    ///
+    /// The image is looked up in the composefs repository (by name or configuration
+    /// digest), and then in the ostree store (by reference, or `booted`, `staged` or
+    /// `rollback` for a deployment). Images in the ostree store which are not deployed
+    /// are checked out into /var/tmp/bootc-mount-image. Use `umount` to unmount it.
+    MountImage {
+        image: String,
+        mountpoint: Utf8PathBuf,
+    },
+    /// Split a root filesystem (e.g. a bootc image mounted into a build stage)
+    /// into content-stable layers, grouping files by the package owning them
+    /// according to the rpm database, and write the result to `dest`.
+    Rechunk {
+        /// Path to the root filesystem.
+        rootfs: Utf8PathBuf,
+        /// Destination image reference, e.g. `oci:/output` or `containers-storage:localhost/os`.
+        dest: String,
+        /// Maximum number of layers to generate.
+        #[clap(long)]
+        max_layers: Option<NonZeroU32>,
+        /// Additional labels, in the form `key=value`.
+        #[clap(long = "label")]
+        labels: Vec<String>,
+        /// Path to a JSON-formatted OCI image configuration to use as a base.
+        #[clap(long)]
+        config: Option<Utf8PathBuf>,
+    },
     /// Initiate a reboot the same way we would after --apply; intended
     /// primarily for testing.
     Reboot,
@@ -571,6 +1396,19 @@ pub(crate) enum Opt {
         merges happen when new deployments are created.
     "#})]
     Rollback(RollbackOpts),
+    /// Reboot into the deployment queued for the next boot.
+    ///
+    /// With `--soft`, only userspace is restarted (`systemctl soft-reboot`), which
+    /// requires that the kernel, initramfs and kernel arguments are unchanged;
+    /// `bootc status` shows whether this is the case for the staged deployment.
+    Reboot(RebootOpts),
+    /// Mark the current boot as successful.
+    ///
+    /// This is invoked by `bootc-boot-success.service` once `boot-complete.target` is
+    /// reached, and resets the boot counter of a newly staged deployment. If the bootloader
+    /// fell back to the previous deployment because the default one repeatedly failed to
+    /// boot, the booted deployment is made the default again (as with `bootc rollback`).
+    BootSuccess,
     /// Apply full changes to the host specification.
     ///
     /// This command operates very similarly to `kubectl apply`; if invoked interactively,
@@ -597,6 +1435,27 @@ pub(crate) enum Opt {
     ///
     /// Invoke e.g. `bootc status --json`, and check if `status.booted` is not `null`.
     Status(StatusOpts),
+    /// Show the files added, removed and modified between the booted deployment and
+    /// the staged or rollback deployment, or an image in the bootc storage.
+    ///
+    /// Directories are shown with a trailing `/`; the contents of added and removed
+    /// directories are not listed.  Note that the image defaults for `/etc` are in
+    /// `/usr/etc`; for local changes to `/etc` see `bootc etc diff`.
+    Diff(DiffOpts),
+    /// Show the recorded upgrade, switch and rollback operations.
+    ///
+    /// Each entry has the digests of the booted image and of the image for the
+    /// next boot, who ran the operation, and whether it succeeded.  Only the most
+    /// recent 100 operations are kept.
+    History {
+        #[clap(long)]
+        #[arg(default_value_t)]
+        format: ImageListFormat,
+
+        /// Only show the last N operations
+        #[clap(long, short = 'n')]
+        limit: Option<usize>,
+    },
     /// Adds a transient writable overlayfs on `/usr` that will be discarded on reboot.
     ///
     /// ## Use cases
@@ -617,8 +1476,62 @@ pub(crate) enum Opt {
     /// Almost always, a system process will hold a reference to the open mount point.
     /// You can however invoke `umount -l /usr` to perform a "lazy unmount".
     ///
+    /// ## Persistent overlays
+    ///
+    /// With `--persistent`, the overlay is instead stored in `/ostree/bootc/dev-overlay`
+    /// and re-applied on boot, as long as the same deployment is booted.  Use
+    /// `bootc usr-overlay reset` to discard it; if it is currently mounted, it is
+    /// discarded on the next boot.
+    ///
     #[clap(alias = "usroverlay")]
-    UsrOverlay,
+    UsrOverlay(UsrOverlayOpts),
+    /// Change the kernel arguments for the next boot.
+    ///
+    /// A new deployment is staged from the pending deployment (the staged one if any,
+    /// otherwise the booted one) with the modified kernel arguments.  Kernel arguments
+    /// embedded in a Unified Kernel Image cannot be changed this way.
+    #[clap(subcommand)]
+    Kargs(KargsOpts),
+    /// Create and apply single-file update bundles, for systems without network access.
+    ///
+    /// A bundle holds the image as an `oci-archive` along with metadata and an optional
+    /// signature.  After applying a bundle, the system tracks the image it was created
+    /// from, so later updates can be fetched online as usual.
+    #[clap(subcommand)]
+    UpdateBundle(UpdateBundleOpts),
+    /// Manage host-specific secrets such as registry credentials or join tokens.
+    ///
+    /// Secrets are stored in `/sysroot/ostree/bootc/secrets`, readable only by root.
+    /// They are not part of the image, and are preserved across updates and rollbacks.
+    ///
+    /// The secret named `registry-auth` is used as the registry authentication file
+    /// (in the format of `containers-auth.json(5)`) when fetching host images and
+    /// logically bound images, taking precedence over `/etc/ostree/auth.json`.
+    #[clap(subcommand)]
+    Secrets(SecretsOpts),
+    /// Inspect and reset local modifications to /etc.
+    ///
+    /// On upgrade, /etc is the result of a three-way merge: local modifications are
+    /// kept, and take precedence over changes to the defaults in the new image.
+    #[clap(subcommand)]
+    Etc(EtcOpts),
+    /// Manage stateroots.
+    ///
+    /// A stateroot holds a `/var` and the deployments using it; separate stateroots
+    /// allow fully separate operating system installations sharing a root filesystem.
+    #[clap(subcommand)]
+    Stateroot(StaterootOpts),
+    /// Manage individual deployments.
+    #[clap(subcommand)]
+    Deployment(DeploymentOpts),
+    /// Manage system extensions (systemd-sysext images) layered onto the host.
+    ///
+    /// Extensions are stored in `/sysroot/ostree/bootc/extensions`, and are preserved
+    /// across updates and rollbacks.  An extension can declare the host image versions
+    /// it supports via `BOOTC_HOST_VERSION_MIN` and `BOOTC_HOST_VERSION_MAX` in its
+    /// extension-release file.
+    #[clap(subcommand)]
+    Extension(ExtensionOpts),
     /// Install the running container to a target.
     ///
     /// ## Understanding installations
@@ -700,14 +1613,40 @@ pub(crate) fn ensure_self_unshared_mount_namespace() -> Result<()> {
 /// TODO drain this and the above into SysrootLock
 #[context("Acquiring sysroot")]
 pub(crate) async fn get_locked_sysroot() -> Result<ostree_ext::sysroot::SysrootLock> {
-    prepare_for_write()?;
-    let sysroot = ostree::Sysroot::new_default();
+    // When operating on a disk image, we've already set up what we need.
+    let sysroot = if let Some(target) = crate::offline::target() {
+        ostree::Sysroot::new(Some(&gio::File::for_path(&target.sysroot)))
+    } else {
+        prepare_for_write()?;
+        ostree::Sysroot::new_default()
+    };
     sysroot.set_mount_namespace_in_use();
     let sysroot = ostree_ext::sysroot::SysrootLock::new_from_sysroot(&sysroot).await?;
     sysroot.load(gio::Cancellable::NONE)?;
     Ok(sysroot)
 }
 
+/// Load global storage state for read-only access (e.g. checking for updates).
+/// When running as root this is the same as [`get_storage`]; otherwise the sysroot
+/// is loaded without locking or entering a private mount namespace, and the caller
+/// must not perform any mutation.
+#[context("Initializing storage (read-only)")]
+pub(crate) async fn get_storage_readonly() -> Result<crate::store::Storage> {
+    if rustix::process::getuid().is_root() {
+        return get_storage().await;
+    }
+    anyhow::ensure!(
+        ostree_booted()?,
+        "This command requires an ostree-booted host system"
+    );
+    let global_run = Dir::open_ambient_dir("/run", cap_std::ambient_authority())?;
+    let sysroot = ostree::Sysroot::new_default();
+    sysroot.load(gio::Cancellable::NONE)?;
+    // SAFETY: We never write via this handle
+    let sysroot = ostree_ext::sysroot::SysrootLock::from_assumed_locked(&sysroot);
+    crate::store::Storage::new(sysroot, &global_run)
+}
+
 /// Load global storage state, expecting that we're booted into a bootc system.
 #[context("Initializing storage")]
 pub(crate) async fn get_storage() -> Result<crate::store::Storage> {
@@ -778,13 +1717,72 @@ fn prepare_for_write() -> Result<()> {
     Ok(())
 }
 
+/// Check whether the host rollout policy holds the fetched update, returning the reason.
+fn rollout_held(
+    repo: &ostree::Repo,
+    fetched: &crate::deploy::ImageState,
+    enabled: bool,
+) -> Result<Option<String>> {
+    if !enabled {
+        return Ok(None);
+    }
+    let config =
+        ostree_container::store::query_image_commit(repo, &fetched.ostree_commit)?.configuration;
+    let published = crate::downgrade::ImageAge::from_config(&config).created;
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    crate::rollout::check_update(root, published)
+}
+
 /// Implementation of the `bootc upgrade` CLI command.
 #[context("Upgrading")]
 async fn upgrade(opts: UpgradeOpts) -> Result<()> {
-    let sysroot = &get_storage().await?;
+    if let Some(UpgradeCmd::Finalize(opts)) = opts.cmd {
+        return upgrade_finalize(opts).await;
+    }
+    opts.tls.apply()?;
+    let mut pull_opts = PullOptions::default();
+    opts.auth.apply(&mut pull_opts)?;
+    opts.fetch.apply(&mut pull_opts);
+    let _target = crate::offline::open_requested(&opts.target)?;
+    // Checking for updates only requires read access, so that it can be used
+    // by unprivileged monitoring agents.
+    let readonly = opts.check && !rustix::process::getuid().is_root();
+    let sysroot = &if opts.check {
+        get_storage_readonly().await?
+    } else {
+        get_storage().await?
+    };
     let repo = &sysroot.repo();
     let (booted_deployment, _deployments, host) =
         crate::status::get_status_require_booted(sysroot)?;
+    crate::clock::warn_if_behind_booted(&host)?;
+    // The most recent deployment determines whether we're locked to a digest
+    let locked = sysroot
+        .staged_deployment()
+        .map(|d| crate::deploy::origin_locked_digest(&d))
+        .unwrap_or_else(|| crate::deploy::origin_locked_digest(&booted_deployment))?;
+    if let Some(digest) = locked.as_deref() {
+        if opts.check {
+            println!("Deployment is locked to {digest}; upgrading requires --unlock");
+        } else if !opts.unlock {
+            anyhow::bail!(
+                "Deployment is locked to {digest} (via `bootc switch --lock-digest`); pass --unlock to upgrade"
+            );
+        }
+    }
+    let platform = match opts.platform {
+        Some(p) => Some(p),
+        None => {
+            sysroot
+                .staged_deployment()
+                .map(|d| crate::deploy::origin_platform(&d))
+                .unwrap_or_else(|| crate::deploy::origin_platform(&booted_deployment))?
+                .0
+        }
+    };
+    if let Some(platform) = platform.as_deref() {
+        pull_opts.platform = Some(crate::platform::parse(platform)?);
+    }
     let imgref = host.spec.image.as_ref();
     let prog: ProgressWriter = opts.progress.try_into()?;
 
@@ -823,8 +1821,15 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     let mut changed = false;
     if opts.check {
         let imgref = imgref.clone().into();
-        let mut imp = crate::deploy::new_importer(repo, &imgref).await?;
-        match imp.prepare().await? {
+        let mut imp = crate::deploy::new_importer(repo, &imgref, &pull_opts).await?;
+        if readonly {
+            imp.set_readonly();
+        }
+        match imp
+            .prepare()
+            .await
+            .map_err(crate::clock::annotate_registry_error)?
+        {
             PrepareResult::AlreadyPresent(_) => {
                 println!("No changes in: {imgref:#}");
             }
@@ -835,6 +1840,14 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
                     println!("  Version: {version}");
                 }
                 println!("  Digest: {}", r.manifest_digest);
+                if let Some(d) = booted_image.as_ref().and_then(|b| {
+                    crate::downgrade::check(
+                        &crate::downgrade::ImageAge::from_config(&b.configuration),
+                        &crate::downgrade::ImageAge::from_config(&r.config),
+                    )
+                }) {
+                    println!("  Downgrade: {d} (requires --allow-downgrade)");
+                }
                 changed = true;
                 if let Some(previous_image) = booted_image.as_ref() {
                     let diff =
@@ -844,7 +1857,16 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
             }
         }
     } else {
-        let fetched = crate::deploy::pull(repo, imgref, None, opts.quiet, prog.clone()).await?;
+        let fetched = crate::deploy::pull(
+            repo,
+            imgref,
+            None,
+            opts.quiet,
+            !opts.skip_checks,
+            &pull_opts,
+            prog.clone(),
+        )
+        .await?;
         let staged_digest = staged_image.map(|s| s.digest().expect("valid digest in status"));
         let fetched_digest = &fetched.manifest_digest;
         tracing::debug!("staged: {staged_digest:?}");
@@ -861,13 +1883,34 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
             println!("Staged update present, not changed.");
 
             if opts.apply {
-                crate::reboot::reboot()?;
+                crate::lifecycle::record_finalizing(sysroot)?;
+                crate::reboot::reboot_into_default(sysroot, opts.soft_reboot)?;
             }
         } else if booted_unchanged {
             println!("No update available.")
+        } else if opts.download_only {
+            println!("Downloaded update: {imgref:#}");
+            if let Some(version) = fetched.version.as_deref() {
+                println!("  Version: {version}");
+            }
+            println!("  Digest: {fetched_digest}");
+            println!("Use `bootc upgrade finalize` to stage it.");
+        } else if let Some(reason) = rollout_held(repo, &fetched, opts.respect_rollout)? {
+            println!("Update held by rollout policy: {reason}");
+            println!("  Digest: {fetched_digest}");
         } else {
+            let downgrade = crate::downgrade::check_target(
+                repo,
+                booted_image.as_deref(),
+                &fetched,
+                opts.allow_downgrade,
+            )?;
             let osname = booted_deployment.osname();
-            crate::deploy::stage(sysroot, &osname, &fetched, &spec, prog.clone()).await?;
+            crate::deploy::stage(sysroot, &osname, &fetched, &spec, &pull_opts, prog.clone())
+                .await?;
+            if let Some(downgrade) = downgrade {
+                crate::downgrade::record(sysroot, &fetched, &downgrade)?;
+            }
             changed = true;
             if let Some(prev) = booted_image.as_ref() {
                 if let Some(fetched_manifest) = fetched.get_manifest(repo)? {
@@ -879,10 +1922,14 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
         }
     }
     if changed {
-        sysroot.update_mtime()?;
+        // In read-only mode, we didn't write anything
+        if !readonly {
+            sysroot.status_changed(StatusChangeReason::Upgrade)?;
+        }
 
         if opts.apply {
-            crate::reboot::reboot()?;
+            crate::lifecycle::record_finalizing(sysroot)?;
+            crate::reboot::reboot_into_default(sysroot, opts.soft_reboot)?;
         }
     } else {
         tracing::debug!("No changes");
@@ -891,17 +1938,112 @@ async fn upgrade(opts: UpgradeOpts) -> Result<()> {
     Ok(())
 }
 
+/// Implementation of `bootc upgrade finalize`.
+#[context("Finalizing upgrade")]
+async fn upgrade_finalize(opts: UpgradeFinalizeOpts) -> Result<()> {
+    let sysroot = &get_storage().await?;
+    let repo = &sysroot.repo();
+    let (booted_deployment, _deployments, host) =
+        crate::status::get_status_require_booted(sysroot)?;
+    // Keep the platform selected via `--platform` when staging
+    let mut pull_opts = PullOptions::default();
+    if let Some(platform) = crate::deploy::origin_platform(&booted_deployment)?.0 {
+        pull_opts.platform = Some(crate::platform::parse(&platform)?);
+    }
+    let spec = RequiredHostSpec::from_spec(&host.spec)?;
+    let imgref = spec.image;
+    let Some(fetched) = crate::deploy::query_pulled(repo, imgref)? else {
+        anyhow::bail!(
+            "No downloaded image for {imgref:#}; use `bootc upgrade --download-only` first"
+        );
+    };
+    let fetched_digest = &fetched.manifest_digest;
+    let staged_digest = host
+        .status
+        .staged
+        .as_ref()
+        .and_then(|s| s.image.as_ref())
+        .map(|s| s.digest())
+        .transpose()?;
+    let booted_image = host
+        .status
+        .booted
+        .map(|b| b.query_image(repo))
+        .transpose()?
+        .flatten();
+    if staged_digest.as_ref() == Some(fetched_digest) {
+        println!("Staged update present, not changed.");
+    } else if booted_image
+        .as_ref()
+        .is_some_and(|img| &img.manifest_digest == fetched_digest)
+    {
+        println!("No update available.");
+        return Ok(());
+    } else {
+        let downgrade = crate::downgrade::check_target(
+            repo,
+            booted_image.as_deref(),
+            &fetched,
+            opts.allow_downgrade,
+        )?;
+        // The update was already fetched, so there is nothing to account for
+        if !opts.skip_checks {
+            crate::checks::run(repo, imgref, 0, &ProgressWriter::default()).await?;
+        }
+        let osname = booted_deployment.osname();
+        crate::deploy::stage(
+            sysroot,
+            &osname,
+            &fetched,
+            &spec,
+            &pull_opts,
+            ProgressWriter::default(),
+        )
+        .await?;
+        if let Some(downgrade) = downgrade {
+            crate::downgrade::record(sysroot, &fetched, &downgrade)?;
+        }
+        sysroot.status_changed(StatusChangeReason::Upgrade)?;
+    }
+    if opts.apply {
+        crate::lifecycle::record_finalizing(sysroot)?;
+        crate::reboot::reboot_into_default(sysroot, opts.soft_reboot)?;
+    }
+    Ok(())
+}
+
 /// Implementation of the `bootc switch` CLI command.
 #[context("Switching")]
 async fn switch(opts: SwitchOpts) -> Result<()> {
+    opts.tls.apply()?;
+    let mut pull_opts = PullOptions::default();
+    opts.auth.apply(&mut pull_opts)?;
+    opts.fetch.apply(&mut pull_opts);
+    if let Some(platform) = opts.platform.as_deref() {
+        pull_opts.platform = Some(crate::platform::parse(platform)?);
+    }
     let transport = ostree_container::Transport::try_from(opts.transport.as_str())?;
+    // With --lock-digest, the origin tracks the tag while we fetch the digest
+    let (name, locked_digest) = if opts.lock_digest {
+        let (tagged, digest) = crate::deploy::split_digested_reference(&opts.target)?;
+        (tagged, Some(digest))
+    } else {
+        (opts.target.as_str(), None)
+    };
     let imgref = ostree_container::ImageReference {
         transport,
-        name: opts.target.to_string(),
+        name: name.to_string(),
     };
     let sigverify = sigpolicy_from_opt(opts.enforce_container_sigpolicy);
     let target = ostree_container::OstreeImageReference { sigverify, imgref };
     let target = ImageReference::from(target);
+    let fetch_target = match locked_digest.as_ref() {
+        Some(digest) => ImageReference {
+            image: format!("{}@{digest}", crate::deploy::strip_tag(name)),
+            ..target.clone()
+        },
+        None => target.clone(),
+    };
     let prog: ProgressWriter = opts.progress.try_into()?;
 
     // If we're doing an in-place mutation, we shortcut most of the rest of the work here
@@ -921,10 +2063,12 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
 
     let cancellable = gio::Cancellable::NONE;
 
+    let _target = crate::offline::open_requested(&opts.target_image)?;
     let sysroot = &get_storage().await?;
     let repo = &sysroot.repo();
     let (booted_deployment, _deployments, host) =
         crate::status::get_status_require_booted(sysroot)?;
+    crate::clock::warn_if_behind_booted(&host)?;
 
     let new_spec = {
         let mut new_spec = host.spec.clone();
@@ -932,14 +2076,44 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
         new_spec
     };
 
-    if new_spec == host.spec {
+    let booted_locked = crate::deploy::origin_locked_digest(&booted_deployment)?;
+    if new_spec == host.spec && booted_locked == locked_digest.as_ref().map(|d| d.to_string()) {
         println!("Image specification is unchanged.");
         return Ok(());
     }
-    let new_spec = RequiredHostSpec::from_spec(&new_spec)?;
-
-    let fetched = crate::deploy::pull(repo, &target, None, opts.quiet, prog.clone()).await?;
+    let mut new_spec = RequiredHostSpec::from_spec(&new_spec)?;
+    new_spec.locked_digest = locked_digest.as_ref();
+
+    if opts.in_place {
+        match crate::deploy::switch_inplace_nofetch(sysroot, &booted_deployment, &target).await? {
+            None => {
+                sysroot.status_changed(StatusChangeReason::Switch)?;
+                println!("Updated booted deployment to {target} (image unchanged)");
+                return Ok(());
+            }
+            Some(digest) => {
+                println!("Image digest differs ({digest}); performing a regular switch");
+            }
+        }
+    }
 
+    let fetched = crate::deploy::pull(
+        repo,
+        &fetch_target,
+        None,
+        opts.quiet,
+        false,
+        &pull_opts,
+        prog.clone(),
+    )
+    .await?;
+    if let Some(digest) = locked_digest.as_ref() {
+        anyhow::ensure!(
+            &fetched.manifest_digest == digest,
+            "Fetched digest {} does not match requested {digest}",
+            fetched.manifest_digest
+        );
+    }
     if !opts.retain {
         // By default, we prune the previous ostree ref so it will go away after later upgrades
         if let Some(booted_origin) = booted_deployment.origin() {
@@ -951,13 +2125,46 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
         }
     }
 
-    let stateroot = booted_deployment.osname();
-    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec, prog.clone()).await?;
-
-    sysroot.update_mtime()?;
+    let booted_stateroot = booted_deployment.osname();
+    let stateroot = match crate::deploy::image_preferred_stateroot(repo, &fetched)? {
+        Some(preferred) if preferred != booted_stateroot.as_str() => {
+            if opts.auto_stateroot {
+                crate::deploy::prepare_stateroot(
+                    sysroot,
+                    &booted_stateroot,
+                    &preferred,
+                    opts.stateroot_var,
+                )?;
+                preferred
+            } else {
+                eprintln!(
+                    "warning: Image prefers stateroot {preferred}; deploying into current stateroot {booted_stateroot} (use --auto-stateroot to change)"
+                );
+                booted_stateroot.to_string()
+            }
+        }
+        _ => booted_stateroot.to_string(),
+    };
+    // A new stateroot has no merge deployment; carry over /etc from the booted system.
+    let merge_deployment = sysroot
+        .merge_deployment(Some(&stateroot))
+        .or_else(|| Some(booted_deployment.clone()));
+    crate::deploy::stage_with_merge(
+        sysroot,
+        merge_deployment,
+        &stateroot,
+        &fetched,
+        &new_spec,
+        &pull_opts,
+        prog.clone(),
+    )
+    .await?;
+
+    sysroot.status_changed(StatusChangeReason::Switch)?;
 
     if opts.apply {
-        crate::reboot::reboot()?;
+        crate::lifecycle::record_finalizing(sysroot)?;
+        crate::reboot::reboot_into_default(sysroot, opts.soft_reboot)?;
     }
 
     Ok(())
@@ -967,10 +2174,13 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
 #[context("Rollback")]
 async fn rollback(opts: RollbackOpts) -> Result<()> {
     let sysroot = &get_storage().await?;
+    if opts.auto_on_failure {
+        return crate::health::auto_on_failure(sysroot, opts.apply, opts.soft_reboot).await;
+    }
     crate::deploy::rollback(sysroot).await?;
 
     if opts.apply {
-        crate::reboot::reboot()?;
+        crate::reboot::reboot_into_default(sysroot, opts.soft_reboot)?;
     }
 
     Ok(())
@@ -979,15 +2189,24 @@ async fn rollback(opts: RollbackOpts) -> Result<()> {
 /// Implementation of the `bootc edit` CLI command.
 #[context("Editing spec")]
 async fn edit(opts: EditOpts) -> Result<()> {
+    let _target = crate::offline::open_requested(&opts.target)?;
     let sysroot = &get_storage().await?;
     let repo = &sysroot.repo();
 
     let (booted_deployment, _deployments, host) =
         crate::status::get_status_require_booted(sysroot)?;
+    // The kernel arguments of the deployment which will be booted next
+    let current_kargs = crate::bootc_kargs::deployment_kargs(
+        &sysroot
+            .staged_deployment()
+            .unwrap_or_else(|| booted_deployment.clone()),
+    );
     let new_host: Host = if let Some(filename) = opts.filename {
         let mut r = std::io::BufReader::new(std::fs::File::open(filename)?);
         serde_yaml::from_reader(&mut r)?
     } else {
+        let mut host = host.clone();
+        host.spec.kernel_arguments = Some(current_kargs.clone());
         let tmpf = tempfile::NamedTempFile::new()?;
         serde_yaml::to_writer(std::io::BufWriter::new(tmpf.as_file()), &host)?;
         crate::utils::spawn_editor(&tmpf)?;
@@ -995,43 +2214,156 @@ async fn edit(opts: EditOpts) -> Result<()> {
         serde_yaml::from_reader(&mut tmpf.as_file())?
     };
 
-    if new_host.spec == host.spec {
+    let plan = crate::reconcile::plan(&host.spec, &current_kargs, &new_host.spec)?;
+    if opts.dry_run {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &plan)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    if plan.is_empty() {
         println!("Edit cancelled, no changes made.");
         return Ok(());
     }
-    host.spec.verify_transition(&new_host.spec)?;
-    let new_spec = RequiredHostSpec::from_spec(&new_host.spec)?;
 
     let prog = ProgressWriter::default();
-
-    // We only support two state transitions right now; switching the image,
-    // or flipping the bootloader ordering.
-    if host.spec.boot_order != new_host.spec.boot_order {
-        return crate::deploy::rollback(sysroot).await;
+    for op in plan.operations {
+        match op {
+            Operation::Rollback => crate::deploy::rollback(sysroot).await?,
+            Operation::Stage { image, kargs } => {
+                let new_spec = RequiredHostSpec {
+                    image: &image,
+                    locked_digest: None,
+                    kargs: kargs.as_ref(),
+                };
+                let pull_opts = PullOptions::default();
+                let fetched = crate::deploy::pull(
+                    repo,
+                    &image,
+                    None,
+                    opts.quiet,
+                    false,
+                    &pull_opts,
+                    prog.clone(),
+                )
+                .await?;
+                // TODO gc old layers here
+                let stateroot = booted_deployment.osname();
+                crate::deploy::stage(
+                    sysroot,
+                    &stateroot,
+                    &fetched,
+                    &new_spec,
+                    &pull_opts,
+                    prog.clone(),
+                )
+                .await?;
+            }
+            Operation::SetKargs { kargs, delta } => {
+                let base = sysroot
+                    .staged_deployment()
+                    .unwrap_or_else(|| booted_deployment.clone());
+                for k in delta.added {
+                    println!("+{k}");
+                }
+                for k in delta.removed {
+                    println!("-{k}");
+                }
+                crate::deploy::stage_kargs(sysroot, &base, kargs).await?;
+                println!("Queued kernel arguments for next boot.");
+            }
+        }
     }
 
-    let fetched = crate::deploy::pull(repo, new_spec.image, None, opts.quiet, prog.clone()).await?;
+    sysroot.status_changed(StatusChangeReason::Edit)?;
+
+    Ok(())
+}
+
+/// Implementation of `bootc kargs`
+#[context("Editing kernel arguments")]
+async fn kargs(opts: KargsOpts) -> Result<()> {
+    let target = match &opts {
+        KargsOpts::Append { target, .. }
+        | KargsOpts::Delete { target, .. }
+        | KargsOpts::Edit { target, .. } => Some(target),
+        KargsOpts::ApplyLive { .. } => None,
+    };
+    let _target = target.map(crate::offline::open_requested).transpose()?;
+    let sysroot = &get_storage().await?;
+    let booted = sysroot.require_booted_deployment()?;
+    let base = sysroot.staged_deployment().unwrap_or(booted);
+    let base_root = crate::utils::deployment_fd(sysroot, &base)?;
+    if crate::bootc_kargs::root_has_uki(&base_root)? {
+        anyhow::bail!(
+            "The kernel arguments are embedded in the Unified Kernel Image and cannot be changed at runtime"
+        );
+    }
 
-    // TODO gc old layers here
+    let current = crate::bootc_kargs::deployment_kargs(&base);
+    let mut kargs = current.clone();
+    // With `apply-live`, all of the given arguments are applied even if already queued
+    let mut requested = None;
+    let apply_live = match opts {
+        KargsOpts::Append {
+            kargs: new, common, ..
+        } => {
+            crate::bootc_kargs::append_kargs(&mut kargs, &new);
+            common.apply_live
+        }
+        KargsOpts::Delete { kargs: del, .. } => {
+            crate::bootc_kargs::delete_kargs(&mut kargs, &del)?;
+            false
+        }
+        KargsOpts::ApplyLive { kargs: new } => {
+            crate::bootc_kargs::replace_kargs(&mut kargs, &new);
+            requested = Some(new);
+            true
+        }
+        KargsOpts::Edit { common, .. } => {
+            let tmpf = tempfile::NamedTempFile::new()?;
+            {
+                let mut w = std::io::BufWriter::new(tmpf.as_file());
+                writeln!(w, "# Kernel arguments, one per line")?;
+                for k in current.iter() {
+                    writeln!(w, "{k}")?;
+                }
+                w.flush()?;
+            }
+            crate::utils::spawn_editor(&tmpf)?;
+            let buf = std::fs::read_to_string(tmpf.path())?;
+            kargs = crate::bootc_kargs::parse_edited_kargs(&buf);
+            common.apply_live
+        }
+    };
 
-    let stateroot = booted_deployment.osname();
-    crate::deploy::stage(sysroot, &stateroot, &fetched, &new_spec, prog.clone()).await?;
+    let changed = crate::bootc_kargs::print_kargs_diff(std::io::stdout().lock(), &current, &kargs)?;
+    let added = requested.unwrap_or_else(|| {
+        kargs
+            .iter()
+            .filter(|k| !current.contains(k))
+            .cloned()
+            .collect::<Vec<_>>()
+    });
+    if changed {
+        crate::deploy::stage_kargs(sysroot, &base, kargs).await?;
+        sysroot.status_changed(StatusChangeReason::Kargs)?;
+        println!("Queued kernel arguments for next boot.");
+    } else {
+        println!("No changes in kernel arguments.");
+    }
 
-    sysroot.update_mtime()?;
+    if apply_live && !added.is_empty() {
+        let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        let unapplied = crate::bootc_kargs::apply_kargs_live(root, &added)?;
+        for k in unapplied {
+            println!("Not applied live (requires reboot): {k}");
+        }
+    }
 
     Ok(())
 }
 
-/// Implementation of `bootc usroverlay`
-async fn usroverlay() -> Result<()> {
-    // This is just a pass-through today.  At some point we may make this a libostree API
-    // or even oxidize it.
-    Err(Command::new("ostree")
-        .args(["admin", "unlock"])
-        .exec()
-        .into())
-}
-
 /// Perform process global initialization. This should be called as early as possible
 /// in the standard `main` function.
 pub fn global_init() -> Result<()> {
@@ -1064,6 +2396,10 @@ where
     I: IntoIterator,
     I::Item: Into<OsString> + Clone,
 {
+    let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+    if crate::version::json_requested(&args)? {
+        return crate::version::print_json();
+    }
     run_from_opt(Opt::parse_including_static(args)).await
 }
 
@@ -1117,21 +2453,114 @@ impl Opt {
 async fn run_from_opt(opt: Opt) -> Result<()> {
     let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
     match opt {
-        Opt::Upgrade(opts) => upgrade(opts).await,
-        Opt::Switch(opts) => switch(opts).await,
-        Opt::Rollback(opts) => rollback(opts).await,
+        Opt::Upgrade(opts) => {
+            let check = opts.check;
+            let r = upgrade(opts).await;
+            if let (false, Err(e)) = (check, &r) {
+                crate::history::record_failure(crate::history::Operation::Upgrade, e);
+            }
+            r
+        }
+        Opt::Switch(opts) => {
+            let r = switch(opts).await;
+            if let Err(e) = &r {
+                crate::history::record_failure(crate::history::Operation::Switch, e);
+            }
+            r
+        }
+        Opt::Rollback(opts) => {
+            let r = rollback(opts).await;
+            if let Err(e) = &r {
+                crate::history::record_failure(crate::history::Operation::Rollback, e);
+            }
+            r
+        }
+        Opt::Reboot(opts) => {
+            let sysroot = &get_storage().await?;
+            let soft = opts.soft.then_some(SoftRebootMode::Required);
+            crate::lifecycle::record_finalizing(sysroot)?;
+            crate::reboot::reboot_into_default(sysroot, soft)
+        }
+        Opt::BootSuccess => {
+            let sysroot = &get_storage().await?;
+            crate::bootcount::boot_success(sysroot).await
+        }
         Opt::Edit(opts) => edit(opts).await,
-        Opt::UsrOverlay => usroverlay().await,
+        Opt::Kargs(opts) => kargs(opts).await,
+        Opt::UpdateBundle(opts) => match opts {
+            UpdateBundleOpts::Create {
+                transport,
+                image,
+                output,
+                sign_key,
+            } => crate::update_bundle::create(&transport, &image, &output, sign_key.as_deref()),
+            UpdateBundleOpts::Apply {
+                bundle,
+                verify_key,
+                quiet,
+                apply,
+                progress,
+            } => {
+                let prog: ProgressWriter = progress.try_into()?;
+                crate::update_bundle::apply(&bundle, verify_key.as_deref(), quiet, prog).await?;
+                if apply {
+                    crate::reboot::reboot()?;
+                }
+                Ok(())
+            }
+        },
+        Opt::Secrets(opts) => match opts {
+            SecretsOpts::Set {
+                name,
+                from_file,
+                podman,
+            } => crate::secrets::set(&name, from_file.as_deref(), podman).await,
+            SecretsOpts::List { format } => crate::secrets::list(format).await,
+            SecretsOpts::Remove { name } => crate::secrets::remove(&name).await,
+        },
+        Opt::Etc(opts) => match opts {
+            EtcOpts::Diff { format } => crate::etc::diff(format).await,
+            EtcOpts::Reset { paths } => crate::etc::reset(&paths).await,
+        },
+        Opt::Stateroot(opts) => match opts {
+            StaterootOpts::List { format } => crate::stateroot::list(format).await,
+            StaterootOpts::Create { name } => crate::stateroot::create(&name).await,
+            StaterootOpts::Delete { name } => crate::stateroot::delete(&name).await,
+        },
+        Opt::Deployment(opts) => {
+            let sysroot = &get_storage().await?;
+            match opts {
+                DeploymentOpts::Pin { id } => crate::deploy::set_pinned(sysroot, &id, true),
+                DeploymentOpts::Unpin { id } => crate::deploy::set_pinned(sysroot, &id, false),
+            }
+        }
+        Opt::Extension(opts) => match opts {
+            ExtensionOpts::List { format } => crate::extensions::list(format).await,
+            ExtensionOpts::Add { path } => crate::extensions::store(&path, false, false).await,
+            ExtensionOpts::Update { path, now } => crate::extensions::store(&path, true, now).await,
+            ExtensionOpts::Enable { name, now } => crate::extensions::enable(&name, now).await,
+            ExtensionOpts::Disable { name, now } => crate::extensions::disable(&name, now).await,
+            ExtensionOpts::Remove { name } => crate::extensions::remove(&name).await,
+            ExtensionOpts::Prune => crate::extensions::prune().await,
+        },
+        Opt::UsrOverlay(opts) => match opts.cmd {
+            Some(UsrOverlayCmd::Reset) => crate::usroverlay::reset(),
+            None if opts.persistent => crate::usroverlay::persistent(),
+            None => crate::usroverlay::transient(opts.size_limit.as_deref()),
+        },
         Opt::Container(opts) => match opts {
             ContainerOpts::Lint {
                 rootfs,
                 fatal_warnings,
                 list,
                 skip,
+                include,
+                severity,
                 no_truncate,
+                format,
             } => {
                 if list {
-                    return lints::lint_list(std::io::stdout().lock());
+                    return lints::lint_list(std::io::stdout().lock(), format);
                 }
                 let warnings = if fatal_warnings {
                     lints::WarningDisposition::FatalWarnings
@@ -1145,14 +2574,19 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 };
 
                 let root = &Dir::open_ambient_dir(rootfs, cap_std::ambient_authority())?;
-                let skip = skip.iter().map(|s| s.as_str());
+                let overrides = lints::LintOverrides {
+                    skip,
+                    include,
+                    severity,
+                };
                 lints::lint(
                     root,
                     warnings,
                     root_type,
-                    skip,
+                    &overrides,
                     std::io::stdout().lock(),
                     no_truncate,
+                    format,
                 )?;
                 Ok(())
             }
@@ -1162,16 +2596,51 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 list_type,
                 list_format,
             } => crate::image::list_entrypoint(list_type, list_format).await,
-            ImageOpts::CopyToStorage { source, target } => {
-                crate::image::push_entrypoint(source.as_deref(), target.as_deref()).await
+            ImageOpts::ListBound { list_format } => {
+                crate::image::list_bound_entrypoint(list_format).await
             }
-            ImageOpts::PullFromDefaultStorage { image } => {
+            ImageOpts::CopyToStorage {
+                source,
+                target,
+                all: false,
+            } => crate::image::push_entrypoint(source.as_deref(), target.as_deref()).await,
+            ImageOpts::CopyToStorage {
+                target, all: true, ..
+            } => crate::image::push_all_entrypoint(target.as_deref()).await,
+            ImageOpts::PullFromDefaultStorage { image, all } => {
+                let Some(image) = image.filter(|_| !all) else {
+                    return crate::image::pull_all_from_host_storage().await;
+                };
                 let sysroot = get_storage().await?;
                 sysroot
                     .get_ensure_imgstore()?
                     .pull_from_host_storage(&image)
                     .await
             }
+            ImageOpts::Pin { image } => {
+                let sysroot = &get_storage().await?;
+                crate::boundimage::pin(sysroot, &image).await
+            }
+            ImageOpts::Unpin { image } => {
+                let sysroot = &get_storage().await?;
+                crate::boundimage::unpin(sysroot, &image).await
+            }
+            ImageOpts::Prune {
+                keep_bound: _,
+                older_than,
+                dangling,
+            } => {
+                let sysroot = &get_storage().await?;
+                let filters = crate::boundimage::PruneFilters {
+                    older_than: older_than.map(|d| chrono::Utc::now() - d),
+                    dangling,
+                };
+                crate::boundimage::prune(sysroot, filters).await
+            }
+            ImageOpts::Export {
+                target,
+                include_etc,
+            } => crate::export::export(&target, include_etc).await,
             ImageOpts::Cmd(opt) => {
                 let storage = get_storage().await?;
                 let imgstore = storage.get_ensure_imgstore()?;
@@ -1194,6 +2663,10 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
         Opt::Install(opts) => match opts {
             #[cfg(feature = "install-to-disk")]
             InstallOpts::ToDisk(opts) => crate::install::install_to_disk(opts).await,
+            #[cfg(feature = "install-to-disk")]
+            InstallOpts::ToLoopbackImage(opts) => {
+                crate::install::loopback::install_to_loopback_image(opts).await
+            }
             InstallOpts::ToFilesystem(opts) => {
                 crate::install::install_to_filesystem(opts, false, crate::install::Cleanup::Skip)
                     .await
@@ -1214,6 +2687,10 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
             crate::install::exec_in_host_mountns(args.as_slice())
         }
         Opt::Status(opts) => super::status::status(opts).await,
+        Opt::Diff(opts) => {
+            crate::diff::diff(opts.target(), opts.path.as_deref(), opts.format).await
+        }
+        Opt::History { format, limit } => crate::history::show(format, limit).await,
         Opt::Internals(opts) => match opts {
             InternalsOpts::SystemdGenerator {
                 normal_dir,
@@ -1256,25 +2733,93 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     println!("{digest}");
                     Ok(())
                 }
-                FsverityOpts::Enable { path } => {
+                FsverityOpts::Enable {
+                    path: Some(path), ..
+                } => {
                     let fd =
                         std::fs::File::open(&path).with_context(|| format!("Reading {path}"))?;
                     // Note this is not robust to forks, we're not using the _maybe_copy variant
                     fsverity::enable_verity_with_retry::<fsverity::Sha256HashValue>(&fd)?;
                     Ok(())
                 }
+                FsverityOpts::Enable {
+                    path: None,
+                    require,
+                } => {
+                    let sysroot = &get_storage().await?;
+                    crate::fsck::verity_enable_all(sysroot, require).await
+                }
+                FsverityOpts::Status { json } => {
+                    let sysroot = &get_storage().await?;
+                    let status = crate::fsck::verity_status(sysroot).await?;
+                    let mut out = std::io::stdout().lock();
+                    if json {
+                        serde_json::to_writer_pretty(&mut out, &status)?;
+                        writeln!(out)?;
+                        Ok(())
+                    } else {
+                        crate::fsck::render_verity_status(out, &status)
+                    }
+                }
             },
             InternalsOpts::Cfs { args } => {
                 let sysroot = &get_storage().await?;
                 crate::cfsctl::run_from_iter(sysroot, args.iter()).await
             }
+            InternalsOpts::BootloaderPlan {
+                device,
+                src_root,
+                generic_image,
+                boot_uuid,
+                rootfs,
+            } => crate::bootloader::print_plan(
+                &device,
+                &rootfs,
+                &src_root,
+                generic_image,
+                boot_uuid.as_deref(),
+            ),
+            InternalsOpts::SyncSystemdBoot => {
+                let sysroot = &Dir::open_ambient_dir("/sysroot", cap_std::ambient_authority())?;
+                crate::bootloader::update_systemd_boot(sysroot)
+            }
+            InternalsOpts::SyncEsp => {
+                let sysroot = &Dir::open_ambient_dir("/sysroot", cap_std::ambient_authority())?;
+                crate::bootloader::sync_host_esp_mirrors(sysroot)
+            }
+            InternalsOpts::RunHooks { phase } => crate::hooks::run_for_staged(phase).await,
+            InternalsOpts::MountImage { image, mountpoint } => {
+                crate::image::mount_image(&image, &mountpoint)
+            }
+            InternalsOpts::Rechunk {
+                rootfs,
+                dest,
+                max_layers,
+                labels,
+                config,
+            } => {
+                let dest = ostree_container::ImageReference::try_from(dest.as_str())?;
+                let labels = labels
+                    .iter()
+                    .map(|l| {
+                        l.split_once('=')
+                            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                            .ok_or_else(|| {
+                                anyhow::anyhow!("Invalid label (expected key=value): {l}")
+                            })
+                    })
+                    .collect::<Result<_>>()?;
+                crate::rechunk::rechunk(&rootfs, &dest, max_layers, labels, config.as_deref()).await
+            }
             InternalsOpts::Reboot => crate::reboot::reboot(),
-            InternalsOpts::Fsck => {
+            InternalsOpts::Fsck { repair } => {
                 let sysroot = &get_storage().await?;
-                crate::fsck::fsck(&sysroot, std::io::stdout().lock()).await?;
+                let opts = crate::fsck::FsckOptions { repair };
+                crate::fsck::fsck(&sysroot, opts, std::io::stdout().lock()).await?;
                 Ok(())
             }
             InternalsOpts::FixupEtcFstab => crate::deploy::fixup_etc_fstab(&root),
+            InternalsOpts::ApplyDevOverlay => crate::usroverlay::apply(),
             InternalsOpts::PrintJsonSchema { of } => {
                 let schema = match of {
                     SchemaType::Host => schema_for!(crate::spec::Host),
@@ -1288,6 +2833,54 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 let sysroot = get_storage().await?;
                 crate::deploy::cleanup(&sysroot).await
             }
+            InternalsOpts::MarkValidated => {
+                let sysroot = &get_storage().await?;
+                crate::lifecycle::mark_validated(sysroot)
+            }
+            InternalsOpts::RelabelAfterPolicyChange => {
+                let sysroot = &get_storage().await?;
+                crate::relabel::relabel_booted(sysroot)
+            }
+            InternalsOpts::ImaSign {
+                key,
+                algorithm,
+                overwrite,
+            } => {
+                let sysroot = &get_storage().await?;
+                crate::ima::sign_deployment(sysroot, key, algorithm, overwrite).await
+            }
+            InternalsOpts::CleanupComposefs { dry_run } => {
+                let sysroot = &get_storage().await?;
+                let Some(report) = crate::store::composefs_gc::gc(sysroot, dry_run)? else {
+                    println!("No composefs repository");
+                    return Ok(());
+                };
+                crate::store::composefs_gc::print_report(std::io::stdout().lock(), &report, dry_run)
+            }
+            InternalsOpts::RepoExport {
+                image,
+                target,
+                ref_name,
+            } => {
+                let sysroot = &get_storage().await?;
+                match (image, target) {
+                    (Some(image), Some(target)) => crate::store::composefs_export::export(
+                        sysroot,
+                        &image,
+                        ref_name.as_deref(),
+                        &target,
+                    ),
+                    _ => crate::store::composefs_export::print_images(
+                        sysroot,
+                        std::io::stdout().lock(),
+                    ),
+                }
+            }
+            InternalsOpts::SimulateRollout { published, wave } => {
+                let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+                crate::rollout::simulate_rollout(root, published.as_deref(), wave)
+            }
+            InternalsOpts::WatchEvents => crate::events::watch(),
             InternalsOpts::Relabel { as_path, path } => {
                 let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
                 let path = path.strip_prefix("/")?;
@@ -1411,7 +3004,9 @@ mod tests {
                 format: None,
                 format_version: None,
                 booted: false,
-                verbose: false
+                verbose: false,
+                show_usage: false,
+                layers: false,
             })
         ));
         assert!(matches!(
@@ -1433,6 +3028,649 @@ mod tests {
             Opt::parse_including_static(["bootc", "status", "-v"]),
             Opt::Status(StatusOpts { verbose: true, .. })
         ));
+
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "switch", "--in-place", "quay.io/example/foo"]),
+            Opt::Switch(SwitchOpts { in_place: true, .. })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "edit", "-f", "host.yaml", "--dry-run"]),
+            Opt::Edit(EditOpts { dry_run: true, .. })
+        ));
+        assert!(
+            Opt::try_parse_from(["bootc", "upgrade", "--tls-client-cert=/etc/pki/client.pem"])
+                .is_err()
+        );
+        match Opt::parse_including_static([
+            "bootc",
+            "switch",
+            "--tls-ca-bundle=/etc/pki/ca.pem",
+            "--tls-client-cert=/etc/pki/client.pem",
+            "--tls-client-key=/etc/pki/client.key",
+            "quay.io/example/foo",
+        ]) {
+            Opt::Switch(opts) => {
+                assert_eq!(
+                    opts.tls.tls_ca_bundle.as_deref().unwrap(),
+                    "/etc/pki/ca.pem"
+                );
+                assert_eq!(
+                    opts.tls.tls_client_key.as_deref().unwrap(),
+                    "/etc/pki/client.key"
+                );
+            }
+            o => panic!("Expected switch opts, not {o:?}"),
+        }
+        assert!(Opt::try_parse_from([
+            "bootc",
+            "switch",
+            "--in-place",
+            "--mutate-in-place",
+            "quay.io/example/foo"
+        ])
+        .is_err());
+
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "usroverlay"]),
+            Opt::UsrOverlay(UsrOverlayOpts {
+                persistent: false,
+                size_limit: None,
+                cmd: None
+            })
+        );
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "usr-overlay", "--size-limit=512M"]),
+            Opt::UsrOverlay(UsrOverlayOpts {
+                size_limit: Some(ref s),
+                ..
+            }) if s == "512M"
+        ));
+        assert!(
+            Opt::try_parse_from(["bootc", "usr-overlay", "--persistent", "--size-limit=1G"])
+                .is_err()
+        );
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "usr-overlay", "--persistent"]),
+            Opt::UsrOverlay(UsrOverlayOpts {
+                persistent: true,
+                cmd: None,
+                ..
+            })
+        ));
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "kargs", "append", "--apply-live", "quiet"]),
+            Opt::Kargs(KargsOpts::Append {
+                kargs: vec!["quiet".into()],
+                common: KargsCommonOpts { apply_live: true },
+                target: Default::default(),
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "kargs", "delete"]).is_err());
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "kargs",
+                "apply-live",
+                "sysctl.vm.swappiness=10"
+            ]),
+            Opt::Kargs(KargsOpts::ApplyLive {
+                kargs: vec!["sysctl.vm.swappiness=10".into()],
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "kargs", "apply-live"]).is_err());
+        assert!(Opt::try_parse_from([
+            "bootc",
+            "kargs",
+            "apply-live",
+            "--target-image",
+            "disk.img",
+            "quiet"
+        ])
+        .is_err());
+        assert!(
+            Opt::try_parse_from(["bootc", "kargs", "delete", "--apply-live", "quiet"]).is_err()
+        );
+
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "usr-overlay", "reset"]),
+            Opt::UsrOverlay(UsrOverlayOpts {
+                cmd: Some(UsrOverlayCmd::Reset),
+                ..
+            })
+        ));
+
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "image",
+                "copy-to-storage",
+                "--all",
+                "--target=oci-archive:/var/tmp/export"
+            ]),
+            Opt::Image(ImageOpts::CopyToStorage {
+                source: None,
+                target: Some("oci-archive:/var/tmp/export".into()),
+                all: true
+            })
+        );
+        assert!(Opt::try_parse_from([
+            "bootc",
+            "image",
+            "copy-to-storage",
+            "--all",
+            "--source=quay.io/example/foo"
+        ])
+        .is_err());
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "image", "pull-from-default-storage", "--all"]),
+            Opt::Image(ImageOpts::PullFromDefaultStorage {
+                image: None,
+                all: true
+            })
+        ));
+        assert!(Opt::try_parse_from(["bootc", "image", "pull-from-default-storage"]).is_err());
+
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "update-bundle",
+                "create",
+                "--sign-key=/etc/pki/bundle.pem",
+                "quay.io/example/os:latest",
+                "os.bundle"
+            ]),
+            Opt::UpdateBundle(UpdateBundleOpts::Create {
+                transport: "registry".into(),
+                image: "quay.io/example/os:latest".into(),
+                output: "os.bundle".into(),
+                sign_key: Some("/etc/pki/bundle.pem".into()),
+            })
+        );
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "update-bundle", "apply", "--apply", "os.bundle"]),
+            Opt::UpdateBundle(UpdateBundleOpts::Apply { bundle, apply: true, verify_key: None, .. }) if bundle == "os.bundle"
+        ));
+        assert!(matches!(
+            Opt::parse_including_static([
+                "bootc",
+                "secrets",
+                "set",
+                "--podman",
+                "--from-file=/root/auth.json",
+                "registry-auth"
+            ]),
+            Opt::Secrets(SecretsOpts::Set { name, from_file: Some(_), podman: true }) if name == "registry-auth"
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "secrets", "rm", "token"]),
+            Opt::Secrets(SecretsOpts::Remove { name }) if name == "token"
+        ));
+    }
+
+    #[test]
+    fn test_parse_stateroot() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "stateroot", "list"]),
+            Opt::Stateroot(StaterootOpts::List {
+                format: ImageListFormat::Table
+            })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "stateroot", "create", "alt"]),
+            Opt::Stateroot(StaterootOpts::Create { name }) if name == "alt"
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "stateroot", "rm", "alt"]),
+            Opt::Stateroot(StaterootOpts::Delete { name }) if name == "alt"
+        ));
+    }
+
+    #[test]
+    fn test_parse_deployment() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "deployment", "pin", "booted"]),
+            Opt::Deployment(DeploymentOpts::Pin {
+                id: crate::deploy::DeploymentId::Booted
+            })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "deployment", "unpin", "2"]),
+            Opt::Deployment(DeploymentOpts::Unpin {
+                id: crate::deploy::DeploymentId::Index(2)
+            })
+        ));
+        assert!(Opt::try_parse_from(["bootc", "deployment", "pin", "not-a-checksum"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_extension() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "extension", "add", "/tmp/tools.raw"]),
+            Opt::Extension(ExtensionOpts::Add { path }) if path == "/tmp/tools.raw"
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "extension", "enable", "--now", "tools"]),
+            Opt::Extension(ExtensionOpts::Enable { name, now: true }) if name == "tools"
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "extension", "prune"]),
+            Opt::Extension(ExtensionOpts::Prune)
+        ));
+    }
+
+    #[test]
+    fn test_parse_upgrade_finalize() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "upgrade", "--download-only"]),
+            Opt::Upgrade(UpgradeOpts {
+                download_only: true,
+                cmd: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "upgrade", "finalize", "--apply"]),
+            Opt::Upgrade(UpgradeOpts {
+                cmd: Some(UpgradeCmd::Finalize(UpgradeFinalizeOpts {
+                    apply: true,
+                    ..
+                })),
+                ..
+            })
+        ));
+        assert!(Opt::try_parse_from(["bootc", "upgrade", "--download-only", "--apply"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_skip_checks() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "upgrade", "--skip-checks"]),
+            Opt::Upgrade(UpgradeOpts {
+                skip_checks: true,
+                ..
+            })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "upgrade", "finalize", "--skip-checks"]),
+            Opt::Upgrade(UpgradeOpts {
+                cmd: Some(UpgradeCmd::Finalize(UpgradeFinalizeOpts {
+                    skip_checks: true,
+                    ..
+                })),
+                ..
+            })
+        ));
+        assert!(Opt::try_parse_from(["bootc", "upgrade", "--check", "--skip-checks"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_auth_file() {
+        let o = Opt::parse_including_static([
+            "bootc",
+            "switch",
+            "--auth-file",
+            "/run/auth.json",
+            "foo",
+        ]);
+        let Opt::Switch(opts) = o else {
+            panic!("Expected switch")
+        };
+        assert_eq!(
+            opts.auth.auth_file.as_deref(),
+            Some(camino::Utf8Path::new("/run/auth.json"))
+        );
+        let o = Opt::parse_including_static(["bootc", "upgrade"]);
+        let Opt::Upgrade(opts) = o else {
+            panic!("Expected upgrade")
+        };
+        assert!(opts.auth.auth_file.is_none());
+    }
+
+    #[test]
+    fn test_parse_lock_digest() {
+        let o = Opt::parse_including_static([
+            "bootc",
+            "switch",
+            "--lock-digest",
+            "quay.io/exampleos/os:stable@sha256:0000000000000000000000000000000000000000000000000000000000000000",
+        ]);
+        let Opt::Switch(opts) = o else {
+            panic!("Expected switch")
+        };
+        assert!(opts.lock_digest);
+        assert!(
+            Opt::try_parse_from(["bootc", "switch", "--lock-digest", "--in-place", "foo"]).is_err()
+        );
+        let o = Opt::parse_including_static(["bootc", "upgrade", "--unlock"]);
+        let Opt::Upgrade(opts) = o else {
+            panic!("Expected upgrade")
+        };
+        assert!(opts.unlock);
+    }
+
+    #[test]
+    fn test_parse_platform() {
+        let o = Opt::parse_including_static([
+            "bootc",
+            "switch",
+            "--platform",
+            "linux/arm64",
+            "quay.io/exampleos/os:stable",
+        ]);
+        let Opt::Switch(opts) = o else {
+            panic!("Expected switch")
+        };
+        assert_eq!(opts.platform.as_deref(), Some("linux/arm64"));
+        let o = Opt::parse_including_static(["bootc", "upgrade", "--platform=linux/amd64"]);
+        let Opt::Upgrade(opts) = o else {
+            panic!("Expected upgrade")
+        };
+        assert_eq!(opts.platform.as_deref(), Some("linux/amd64"));
+        let o = Opt::parse_including_static(["bootc", "upgrade"]);
+        let Opt::Upgrade(opts) = o else {
+            panic!("Expected upgrade")
+        };
+        assert!(opts.platform.is_none());
+    }
+
+    #[test]
+    fn test_parse_fetch_opts() {
+        let o = Opt::parse_including_static([
+            "bootc",
+            "upgrade",
+            "--fetch-jobs=4",
+            "--limit-bandwidth=2M",
+        ]);
+        let Opt::Upgrade(opts) = o else {
+            panic!("Expected upgrade")
+        };
+        assert_eq!(opts.fetch.fetch_jobs, NonZeroUsize::new(4));
+        assert_eq!(opts.fetch.limit_bandwidth, NonZeroU64::new(2 << 20));
+        let o = Opt::parse_including_static(["bootc", "upgrade"]);
+        let Opt::Upgrade(opts) = o else {
+            panic!("Expected upgrade")
+        };
+        assert_eq!(opts.fetch, FetchOpts::default());
+        assert!(Opt::try_parse_from(["bootc", "upgrade", "--fetch-jobs=0"]).is_err());
+
+        assert_eq!(parse_bandwidth("1000").unwrap().get(), 1000);
+        assert_eq!(parse_bandwidth("500k").unwrap().get(), 500 << 10);
+        assert_eq!(parse_bandwidth("1G").unwrap().get(), 1 << 30);
+        for v in ["", "0", "M", "-1K", "1T", "1.5M"] {
+            assert!(parse_bandwidth(v).is_err(), "{v}");
+        }
+    }
+
+    #[test]
+    fn test_parse_mount_image() {
+        let o =
+            Opt::parse_including_static(["bootc", "internals", "mount-image", "staged", "/mnt"]);
+        assert_eq!(
+            o,
+            Opt::Internals(InternalsOpts::MountImage {
+                image: "staged".into(),
+                mountpoint: "/mnt".into(),
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "internals", "mount-image", "staged"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_diff() {
+        use crate::diff::DiffTarget;
+        let o = Opt::parse_including_static(["bootc", "diff"]);
+        let Opt::Diff(opts) = o else {
+            panic!("Expected diff")
+        };
+        assert_eq!(opts.target(), DiffTarget::Staged);
+        let o = Opt::parse_including_static(["bootc", "diff", "--rollback", "--path", "/usr/lib"]);
+        let Opt::Diff(opts) = o else {
+            panic!("Expected diff")
+        };
+        assert_eq!(opts.target(), DiffTarget::Rollback);
+        assert_eq!(opts.path, Some("/usr/lib".into()));
+        let o = Opt::parse_including_static(["bootc", "diff", "quay.io/example/os:latest"]);
+        let Opt::Diff(opts) = o else {
+            panic!("Expected diff")
+        };
+        assert_eq!(
+            opts.target(),
+            DiffTarget::Image("quay.io/example/os:latest".into())
+        );
+        assert!(Opt::try_parse_from(["bootc", "diff", "--staged", "--rollback"]).is_err());
+        assert!(Opt::try_parse_from(["bootc", "diff", "--rollback", "example"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_history() {
+        let o = Opt::parse_including_static(["bootc", "history"]);
+        assert_eq!(
+            o,
+            Opt::History {
+                format: ImageListFormat::Table,
+                limit: None
+            }
+        );
+        let o = Opt::parse_including_static(["bootc", "history", "-n", "5", "--format", "json"]);
+        assert_eq!(
+            o,
+            Opt::History {
+                format: ImageListFormat::Json,
+                limit: Some(5)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_etc() {
+        let o = Opt::parse_including_static(["bootc", "etc", "diff", "--format", "json"]);
+        assert_eq!(
+            o,
+            Opt::Etc(EtcOpts::Diff {
+                format: ImageListFormat::Json
+            })
+        );
+        let o =
+            Opt::parse_including_static(["bootc", "etc", "reset", "/etc/ssh/sshd_config", "pam.d"]);
+        assert_eq!(
+            o,
+            Opt::Etc(EtcOpts::Reset {
+                paths: vec!["/etc/ssh/sshd_config".into(), "pam.d".into()]
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "etc", "reset"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_ima_sign() {
+        let o = Opt::parse_including_static([
+            "bootc",
+            "internals",
+            "ima-sign",
+            "--key",
+            "/etc/keys/ima.pem",
+        ]);
+        assert_eq!(
+            o,
+            Opt::Internals(InternalsOpts::ImaSign {
+                key: "/etc/keys/ima.pem".into(),
+                algorithm: "sha256".into(),
+                overwrite: false,
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "internals", "ima-sign"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_fsverity() {
+        let o =
+            Opt::try_parse_from(["bootc", "internals", "fsverity", "enable", "--require"]).unwrap();
+        assert_eq!(
+            o,
+            Opt::Internals(InternalsOpts::Fsverity(FsverityOpts::Enable {
+                path: None,
+                require: true
+            }))
+        );
+        assert!(Opt::try_parse_from([
+            "bootc",
+            "internals",
+            "fsverity",
+            "enable",
+            "--require",
+            "/foo"
+        ])
+        .is_err());
+        let o =
+            Opt::try_parse_from(["bootc", "internals", "fsverity", "status", "--json"]).unwrap();
+        assert_eq!(
+            o,
+            Opt::Internals(InternalsOpts::Fsverity(FsverityOpts::Status { json: true }))
+        );
+    }
+
+    #[test]
+    fn test_parse_rechunk() {
+        let o = Opt::try_parse_from([
+            "bootc",
+            "internals",
+            "rechunk",
+            "--max-layers=32",
+            "--label=version=42",
+            "/rootfs",
+            "oci:/output",
+        ])
+        .unwrap();
+        assert_eq!(
+            o,
+            Opt::Internals(InternalsOpts::Rechunk {
+                rootfs: "/rootfs".into(),
+                dest: "oci:/output".into(),
+                max_layers: NonZeroU32::new(32),
+                labels: vec!["version=42".into()],
+                config: None,
+            })
+        );
+        assert!(Opt::try_parse_from([
+            "bootc",
+            "internals",
+            "rechunk",
+            "--max-layers=0",
+            "/rootfs",
+            "oci:/output"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_soft_reboot() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "upgrade", "--apply", "--soft-reboot=auto"]),
+            Opt::Upgrade(UpgradeOpts {
+                apply: true,
+                soft_reboot: Some(SoftRebootMode::Auto),
+                ..
+            })
+        ));
+        // Requires --apply
+        assert!(Opt::try_parse_from(["bootc", "switch", "--soft-reboot=required", "foo"]).is_err());
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "reboot", "--soft"]),
+            Opt::Reboot(RebootOpts { soft: true })
+        ));
+    }
+
+    #[test]
+    fn test_parse_boot_success() {
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "boot-success"]),
+            Opt::BootSuccess
+        );
+        assert!(Opt::try_parse_from(["bootc", "boot-success", "foo"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_rollback_auto_on_failure() {
+        let o = Opt::parse_including_static(["bootc", "rollback", "--auto-on-failure", "--apply"]);
+        let Opt::Rollback(opts) = o else {
+            panic!("Expected rollback, got {o:?}");
+        };
+        assert!(opts.auto_on_failure);
+        assert!(opts.apply);
+        let o = Opt::parse_including_static(["bootc", "rollback"]);
+        assert!(matches!(
+            o,
+            Opt::Rollback(RollbackOpts {
+                auto_on_failure: false,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_image_pin() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "image", "pin", "quay.io/example/app:1.0"]),
+            Opt::Image(ImageOpts::Pin { image }) if image == "quay.io/example/app:1.0"
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "image", "unpin", "quay.io/example/app"]),
+            Opt::Image(ImageOpts::Unpin { image }) if image == "quay.io/example/app"
+        ));
+    }
+
+    #[test]
+    fn test_parse_image_prune() {
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "image", "prune"]),
+            Opt::Image(ImageOpts::Prune {
+                keep_bound: true,
+                older_than: None,
+                dangling: false
+            })
+        );
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "image", "prune", "--keep-bound"]),
+            Opt::Image(ImageOpts::Prune {
+                keep_bound: true,
+                older_than: None,
+                dangling: false
+            })
+        );
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "image",
+                "prune",
+                "--older-than",
+                "30d",
+                "--dangling"
+            ]),
+            Opt::Image(ImageOpts::Prune {
+                keep_bound: true,
+                older_than: Some(chrono::Duration::days(30)),
+                dangling: true
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "image", "prune", "--older-than", "30"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_image_export() {
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "image",
+                "export",
+                "--include-etc",
+                "oci-archive:/var/tmp/golden.tar"
+            ]),
+            Opt::Image(ImageOpts::Export {
+                target: "oci-archive:/var/tmp/golden.tar".into(),
+                include_etc: true,
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "image", "export"]).is_err());
     }
 
     #[test]
@@ -0,0 +1,301 @@
+//! # System clock sanity checks
+//!
+//! Several verification paths compare timestamps against the system clock:
+//! registry TLS certificates, and the creation time of update bundles.
+//! A wrong clock (common on hosts without an RTC battery, or before time
+//! synchronization has completed) makes these fail in ways that look like
+//! trust problems. This module distinguishes the two, and allows configuring
+//! a tolerance in `/etc/bootc/clock.toml` (or `/usr/lib/bootc/clock.toml`):
+//!
+//! ```toml
+//! [clock]
+//! # Timestamps up to this far in the future are accepted
+//! max-skew = "5m"
+//! # Refuse to verify timestamps or contact registries until the clock is synchronized
+//! require-sync = true
+//! ```
+
+use anyhow::Result;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use chrono::{DateTime, Duration, Utc};
+use fn_error_context::context;
+use ostree_ext::container::{self as ostree_container, RegistryErrorKind};
+use serde::Deserialize;
+
+use crate::spec::Host;
+
+/// The name of the configuration file and section; see [`crate::hostconfig`]
+const CONFIG_NAME: &str = "clock";
+/// Written by systemd-timesyncd
+const TIMESYNC_DIR: &str = "run/systemd/timesync";
+/// Present once systemd-timesyncd has synchronized the clock
+const TIMESYNC_SYNCHRONIZED: &str = "run/systemd/timesync/synchronized";
+/// The default tolerance for timestamps in the future
+const DEFAULT_MAX_SKEW: &str = "5m";
+
+/// The `[clock]` section.
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ClockConfigFile {
+    max_skew: Option<String>,
+    require_sync: Option<bool>,
+}
+
+/// The effective clock configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ClockConfig {
+    /// How far in the future a timestamp may be
+    pub(crate) max_skew: Duration,
+    /// Whether the clock must be known to be synchronized
+    pub(crate) require_sync: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            max_skew: bootc_utils::parse_duration(DEFAULT_MAX_SKEW).unwrap(),
+            require_sync: false,
+        }
+    }
+}
+
+/// A timestamp could not be checked or was rejected because of the system
+/// clock; this is distinct from a trust (e.g. signature) failure.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum ClockError {
+    #[error("System clock is not synchronized, and clock.require-sync is enabled")]
+    NotSynchronized,
+    #[error("{what} timestamp {ts} is later than the system clock ({now}) plus the allowed skew of {}s; the system clock is probably wrong{}", .skew.num_seconds(), unsynchronized_note(.unsynchronized))]
+    InFuture {
+        what: String,
+        ts: DateTime<Utc>,
+        now: DateTime<Utc>,
+        skew: Duration,
+        unsynchronized: bool,
+    },
+}
+
+fn unsynchronized_note(unsynchronized: &bool) -> &'static str {
+    if *unsynchronized {
+        " (it is not synchronized)"
+    } else {
+        ""
+    }
+}
+
+fn parse_config(buf: &str) -> Result<ClockConfig> {
+    let c: ClockConfigFile = crate::hostconfig::parse_section(buf, CONFIG_NAME)?;
+    let mut r = ClockConfig::default();
+    if let Some(skew) = c.max_skew.as_deref() {
+        r.max_skew = bootc_utils::parse_duration(skew)?;
+        anyhow::ensure!(r.max_skew >= Duration::zero(), "Negative max-skew: {skew}");
+    }
+    if let Some(v) = c.require_sync {
+        r.require_sync = v;
+    }
+    Ok(r)
+}
+
+/// Load the clock configuration from the target root.
+#[context("Loading clock configuration")]
+pub(crate) fn load_config(root: &Dir) -> Result<ClockConfig> {
+    crate::hostconfig::load(root, CONFIG_NAME, parse_config)
+}
+
+/// Whether systemd-timesyncd has synchronized the clock, or `None` if it
+/// is not in use.
+pub(crate) fn sync_status(root: &Dir) -> Result<Option<bool>> {
+    if !root.try_exists(TIMESYNC_DIR)? {
+        return Ok(None);
+    }
+    Ok(Some(root.try_exists(TIMESYNC_SYNCHRONIZED)?))
+}
+
+/// With `require-sync`, check that the clock is not known to be unsynchronized.
+fn check_sync(config: &ClockConfig, synced: Option<bool>) -> Result<(), ClockError> {
+    if config.require_sync && synced == Some(false) {
+        return Err(ClockError::NotSynchronized);
+    }
+    Ok(())
+}
+
+/// Check that `ts` is not in the future relative to `now`.
+pub(crate) fn check_timestamp(
+    what: &str,
+    ts: DateTime<Utc>,
+    now: DateTime<Utc>,
+    config: &ClockConfig,
+    synced: Option<bool>,
+) -> Result<(), ClockError> {
+    check_sync(config, synced)?;
+    if ts > now + config.max_skew {
+        return Err(ClockError::InFuture {
+            what: what.to_owned(),
+            ts,
+            now,
+            skew: config.max_skew,
+            unsynchronized: synced == Some(false),
+        });
+    }
+    Ok(())
+}
+
+/// Check a timestamp against the system clock, using the host configuration.
+pub(crate) fn verify_timestamp(what: &str, ts: DateTime<Utc>) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let config = load_config(root)?;
+    let synced = sync_status(root)?;
+    check_timestamp(
+        what,
+        ts,
+        std::time::SystemTime::now().into(),
+        &config,
+        synced,
+    )?;
+    Ok(())
+}
+
+/// Check the clock before contacting a registry, whose TLS certificates are
+/// validated against it; this fails if `require-sync` is set and the clock is
+/// not synchronized.
+pub(crate) fn verify_synchronized() -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let config = load_config(root)?;
+    check_sync(&config, sync_status(root)?)?;
+    Ok(())
+}
+
+/// If `now` is before the booted image was built (less the allowed skew),
+/// return a warning; the clock is almost certainly wrong.
+fn clock_behind_warning(
+    built: DateTime<Utc>,
+    now: DateTime<Utc>,
+    config: &ClockConfig,
+) -> Option<String> {
+    (now + config.max_skew < built).then(|| {
+        format!("System clock ({now}) is earlier than the creation of the booted image ({built}); certificate and signature checks may fail")
+    })
+}
+
+/// Warn if the system clock is behind the booted image's build time.
+pub(crate) fn warn_if_behind_booted(host: &Host) -> Result<()> {
+    let Some(built) = host
+        .status
+        .booted
+        .as_ref()
+        .and_then(|b| b.image.as_ref())
+        .and_then(|i| i.timestamp)
+    else {
+        return Ok(());
+    };
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let config = load_config(root)?;
+    if let Some(msg) = clock_behind_warning(built, std::time::SystemTime::now().into(), &config) {
+        crate::journal::journal_print(libsystemd::logging::Priority::Warning, &msg);
+    }
+    Ok(())
+}
+
+/// Like [`ostree_container::annotate_registry_error`], but for certificate
+/// validity failures, also report whether the clock is synchronized.
+pub(crate) fn annotate_registry_error(e: anyhow::Error) -> anyhow::Error {
+    let is_validity = RegistryErrorKind::classify(&e) == Some(RegistryErrorKind::Validity);
+    let e = ostree_container::annotate_registry_error(e);
+    if !is_validity {
+        return e;
+    }
+    let synced = Dir::open_ambient_dir("/", cap_std::ambient_authority())
+        .ok()
+        .and_then(|root| sync_status(&root).ok().flatten());
+    if synced == Some(false) {
+        e.context(format!("System clock ({}) is not synchronized", Utc::now()))
+    } else {
+        e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    fn t(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        assert_eq!(parse_config("")?, ClockConfig::default());
+        let c = parse_config("[clock]\nmax-skew = \"1h\"\nrequire-sync = true\n")?;
+        assert_eq!(c.max_skew, Duration::hours(1));
+        assert!(c.require_sync);
+        assert!(parse_config("[clock]\nskew = \"1h\"\n").is_err());
+        assert!(parse_config("[clock]\nmax-skew = \"-1h\"\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_status() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(sync_status(&td)?, None);
+        td.create_dir_all(TIMESYNC_DIR)?;
+        assert_eq!(sync_status(&td)?, Some(false));
+        td.write(TIMESYNC_SYNCHRONIZED, "")?;
+        assert_eq!(sync_status(&td)?, Some(true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        let now = t("2025-03-01T12:00:00Z");
+        let config = ClockConfig::default();
+        check_timestamp("Bundle", t("2025-03-01T12:04:00Z"), now, &config, None).unwrap();
+        check_timestamp("Bundle", t("2020-01-01T00:00:00Z"), now, &config, None).unwrap();
+        match check_timestamp(
+            "Bundle",
+            t("2025-03-01T13:00:00Z"),
+            now,
+            &config,
+            Some(false),
+        ) {
+            Err(e @ ClockError::InFuture { .. }) => {
+                assert!(e.to_string().contains("not synchronized"))
+            }
+            o => panic!("Unexpected {o:?}"),
+        }
+        let config = ClockConfig {
+            require_sync: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            check_timestamp("Bundle", now, now, &config, Some(false)),
+            Err(ClockError::NotSynchronized)
+        );
+        check_timestamp("Bundle", now, now, &config, Some(true)).unwrap();
+        // Without systemd-timesyncd, the sync status is unknown
+        check_timestamp("Bundle", now, now, &config, None).unwrap();
+    }
+
+    #[test]
+    fn test_check_sync() {
+        check_sync(&ClockConfig::default(), Some(false)).unwrap();
+        let config = ClockConfig {
+            require_sync: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            check_sync(&config, Some(false)),
+            Err(ClockError::NotSynchronized)
+        );
+        check_sync(&config, Some(true)).unwrap();
+        check_sync(&config, None).unwrap();
+    }
+
+    #[test]
+    fn test_clock_behind() {
+        let config = ClockConfig::default();
+        let built = t("2025-03-01T12:00:00Z");
+        assert!(clock_behind_warning(built, t("2025-03-01T11:58:00Z"), &config).is_none());
+        assert!(clock_behind_warning(built, t("1970-01-01T00:00:00Z"), &config).is_some());
+    }
+}
@@ -0,0 +1,308 @@
+//! # Configuration compatibility checks
+//!
+//! Before an upgrade is queued, hosts may run commands (e.g. `sshd -t` or
+//! `nginx -t`) inside the new deployment, against the `/etc` it will boot
+//! with: the new image defaults plus local modifications. If any check fails,
+//! the staged deployment is removed. Checks are configured in
+//! `/etc/bootc/config-checks.toml`, or shipped by the new image in
+//! `/usr/lib/bootc/config-checks.toml`:
+//!
+//! ```toml
+//! [[checks]]
+//! name = "sshd"
+//! command = ["/usr/sbin/sshd", "-t"]
+//! ```
+
+use std::fmt::Write as _;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::Deserialize;
+
+/// Host configuration, which takes precedence
+const HOST_CONFIG: &str = "etc/bootc/config-checks.toml";
+/// Configuration shipped in the new image
+const IMAGE_CONFIG: &str = "usr/lib/bootc/config-checks.toml";
+
+/// The toplevel configuration file.
+#[derive(Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigChecks {
+    #[serde(default)]
+    pub(crate) checks: Vec<ConfigCheck>,
+}
+
+/// A single check.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigCheck {
+    /// A short name used in the report
+    pub(crate) name: String,
+    /// The command and its arguments
+    pub(crate) command: Vec<String>,
+}
+
+/// A locally modified path in `/etc`, as reported by `ostree admin config-diff`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum EtcChange {
+    Modified(String),
+    Added(String),
+    Deleted(String),
+}
+
+/// A failed check.
+#[derive(Debug, PartialEq, Eq)]
+struct CheckFailure {
+    name: String,
+    status: String,
+    output: String,
+}
+
+fn parse_config(buf: &str) -> Result<ConfigChecks> {
+    let c: ConfigChecks = toml::from_str(buf)?;
+    for check in c.checks.iter() {
+        anyhow::ensure!(!check.name.is_empty(), "Empty check name");
+        anyhow::ensure!(
+            !check.command.is_empty(),
+            "Empty command for check {}",
+            check.name
+        );
+    }
+    Ok(c)
+}
+
+/// Load the checks from the host root, or else from the new deployment.
+#[context("Loading configuration checks")]
+pub(crate) fn load_config(host_root: &Dir, new_root: &Dir) -> Result<ConfigChecks> {
+    for (root, path) in [(host_root, HOST_CONFIG), (new_root, IMAGE_CONFIG)] {
+        let Some(f) = root.open_optional(path)? else {
+            continue;
+        };
+        let buf = std::io::read_to_string(f)?;
+        return parse_config(&buf).with_context(|| format!("Parsing {path}"));
+    }
+    Ok(ConfigChecks::default())
+}
+
+fn parse_config_diff(buf: &str) -> Result<Vec<EtcChange>> {
+    buf.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let (kind, path) = l
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow::anyhow!("Invalid config-diff line: {l}"))?;
+            let path = path.trim().trim_start_matches('/').to_owned();
+            match kind {
+                "M" => Ok(EtcChange::Modified(path)),
+                "A" => Ok(EtcChange::Added(path)),
+                "D" => Ok(EtcChange::Deleted(path)),
+                o => anyhow::bail!("Unknown config-diff change type: {o}"),
+            }
+        })
+        .collect()
+}
+
+impl EtcChange {
+    /// The path, relative to `/etc`.
+    pub(crate) fn path(&self) -> &str {
+        match self {
+            Self::Modified(p) | Self::Added(p) | Self::Deleted(p) => p,
+        }
+    }
+}
+
+/// Query the local modifications to the booted `/etc`, relative to the image
+/// defaults in `/usr/etc`.
+#[context("Querying local changes to /etc")]
+pub(crate) fn local_etc_changes() -> Result<Vec<EtcChange>> {
+    let diff = Command::new("ostree")
+        .args(["admin", "config-diff"])
+        .run_get_string()?;
+    parse_config_diff(&diff)
+}
+
+/// Populate `etc` with the new image defaults plus local modifications to
+/// the booted `/etc`, approximating the result of the merge at finalization.
+#[context("Computing merged /etc")]
+fn prepare_merged_etc(new_root: &Dir, etc: &Dir, etc_path: &str) -> Result<()> {
+    Command::new("cp")
+        .args(["-a", "--reflink=auto", "usr/etc/.", etc_path])
+        .cwd_dir(new_root.try_clone()?)
+        .run_capture_stderr()?;
+    for change in local_etc_changes()? {
+        match change {
+            EtcChange::Modified(p) | EtcChange::Added(p) => {
+                if let Some(parent) = std::path::Path::new(&p).parent() {
+                    if !parent.as_os_str().is_empty() {
+                        etc.create_dir_all(parent)?;
+                    }
+                }
+                etc.remove_all_optional(&p)?;
+                Command::new("cp")
+                    .args(["-a", "--no-target-directory"])
+                    .arg(format!("/etc/{p}"))
+                    .arg(format!("{etc_path}/{p}"))
+                    .run_capture_stderr()?;
+            }
+            EtcChange::Deleted(p) => {
+                etc.remove_all_optional(&p)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run `check` chrooted into `new_root`, in a private mount namespace with
+/// `etc_path` mounted over its `/etc`.
+fn run_check(check: &ConfigCheck, new_root: &Dir, etc_path: &str) -> Result<Option<CheckFailure>> {
+    let root = new_root.try_clone()?;
+    // All paths are converted ahead of time; allocating is not safe after fork.
+    let etc_path = std::ffi::CString::new(etc_path)?;
+    let mut cmd = Command::new(&check.command[0]);
+    cmd.args(&check.command[1..]);
+    cmd.stdin(std::process::Stdio::null());
+    // SAFETY: Only async-signal-safe system calls are made in the child,
+    // and none of them allocate.
+    unsafe {
+        cmd.pre_exec(move || {
+            use rustix::mount::{
+                mount_bind, mount_change, mount_recursive_bind, MountPropagationFlags,
+            };
+            rustix::thread::unshare(rustix::thread::UnshareFlags::NEWNS)?;
+            // Ensure none of our mounts propagate back to the host
+            mount_change(
+                c"/",
+                MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+            )?;
+            rustix::process::fchdir(&root)?;
+            mount_bind(etc_path.as_c_str(), c"etc")?;
+            mount_recursive_bind(c"/proc", c"proc")?;
+            mount_recursive_bind(c"/dev", c"dev")?;
+            rustix::process::chroot(c".")?;
+            rustix::process::chdir(c"/")?;
+            Ok(())
+        })
+    };
+    let o = cmd
+        .output()
+        .with_context(|| format!("Running check {}", check.name))?;
+    if o.status.success() {
+        return Ok(None);
+    }
+    let mut output = String::from_utf8_lossy(&o.stderr).into_owned();
+    output.push_str(&String::from_utf8_lossy(&o.stdout));
+    Ok(Some(CheckFailure {
+        name: check.name.clone(),
+        status: o.status.to_string(),
+        output,
+    }))
+}
+
+fn render_failures(failures: &[CheckFailure]) -> String {
+    let mut r = String::from("Configuration checks failed for the new deployment:\n");
+    for failure in failures {
+        writeln!(r, "  {}: {}", failure.name, failure.status).unwrap();
+        for line in failure.output.lines() {
+            writeln!(r, "    {line}").unwrap();
+        }
+    }
+    r
+}
+
+/// Run the configured checks against the new deployment; returns an error
+/// with a report if any of them failed.
+#[context("Running configuration checks")]
+pub(crate) fn run_checks(host_root: &Dir, new_root: &Dir) -> Result<()> {
+    let config = load_config(host_root, new_root)?;
+    if config.checks.is_empty() {
+        return Ok(());
+    }
+    let td = tempfile::tempdir()?;
+    let etc_path = td
+        .path()
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 tempdir"))?;
+    let etc = Dir::open_ambient_dir(etc_path, cap_std_ext::cap_std::ambient_authority())?;
+    prepare_merged_etc(new_root, &etc, etc_path)?;
+    let mut failures = Vec::new();
+    for check in config.checks.iter() {
+        tracing::debug!("Running configuration check {}", check.name);
+        if let Some(failure) = run_check(check, new_root, etc_path)? {
+            failures.push(failure);
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("{}", render_failures(&failures).trim_end());
+    }
+    println!("Configuration checks passed: {}", config.checks.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        assert_eq!(parse_config("")?, ConfigChecks::default());
+        let c =
+            parse_config("[[checks]]\nname = \"sshd\"\ncommand = [\"/usr/sbin/sshd\", \"-t\"]\n")?;
+        assert_eq!(c.checks.len(), 1);
+        assert_eq!(c.checks[0].command, ["/usr/sbin/sshd", "-t"]);
+        assert!(parse_config("[[checks]]\nname = \"sshd\"\ncommand = []\n").is_err());
+        assert!(parse_config("[[checks]]\nname = \"x\"\nexec = [\"true\"]\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_config() -> Result<()> {
+        let host = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let new = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(load_config(host, new)?.checks.is_empty());
+        new.create_dir_all("usr/lib/bootc")?;
+        new.write(
+            IMAGE_CONFIG,
+            "[[checks]]\nname = \"image\"\ncommand = [\"true\"]\n",
+        )?;
+        assert_eq!(load_config(host, new)?.checks[0].name, "image");
+        host.create_dir_all("etc/bootc")?;
+        host.write(HOST_CONFIG, "")?;
+        assert!(load_config(host, new)?.checks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_diff() -> Result<()> {
+        let diff = "M    ssh/sshd_config\nA    nginx/conf.d/site.conf\nD    motd\n\n";
+        assert_eq!(
+            parse_config_diff(diff)?,
+            [
+                EtcChange::Modified("ssh/sshd_config".into()),
+                EtcChange::Added("nginx/conf.d/site.conf".into()),
+                EtcChange::Deleted("motd".into()),
+            ]
+        );
+        assert!(parse_config_diff("X    foo\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_failures() {
+        let r = render_failures(&[CheckFailure {
+            name: "sshd".into(),
+            status: "exit status: 255".into(),
+            output: "line 3: Bad configuration option\n".into(),
+        }]);
+        similar_asserts::assert_eq!(
+            r,
+            "Configuration checks failed for the new deployment:\n  sshd: exit status: 255\n    line 3: Bad configuration option\n"
+        );
+    }
+}
@@ -4,23 +4,30 @@
 
 use std::collections::HashSet;
 use std::io::{BufRead, Write};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::str::FromStr;
 
 use anyhow::Ok;
 use anyhow::{anyhow, Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::Utf8PathBuf;
 use cap_std::fs::{Dir, MetadataExt};
 use cap_std_ext::cap_std;
+use cap_std_ext::cmdext::CapStdExtCommandExt;
 use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
 use ostree::{gio, glib};
 use ostree_container::OstreeImageReference;
 use ostree_ext::container as ostree_container;
 use ostree_ext::container::store::{ImageImporter, ImportProgress, PrepareResult, PreparedImport};
-use ostree_ext::oci_spec::image::{Descriptor, Digest};
+use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::oci_spec::image::{Descriptor, Digest, Platform};
 use ostree_ext::ostree::Deployment;
 use ostree_ext::ostree::{self, Sysroot};
 use ostree_ext::sysroot::SysrootLock;
 use ostree_ext::tokio_util::spawn_blocking_cancellable_flatten;
 
+use crate::bootc_kargs::KargsDelta;
 use crate::progress_jsonl::{Event, ProgressWriter, SubTaskBytes, SubTaskStep};
 use crate::spec::ImageReference;
 use crate::spec::{BootOrder, HostSpec};
@@ -34,9 +41,37 @@ const BASE_IMAGE_PREFIX: &str = "ostree/container/baseimage/bootc";
 /// Set on an ostree commit if this is a derived commit
 const BOOTC_DERIVED_KEY: &str = "bootc.derived";
 
+/// Origin group for bootc-specific metadata
+const ORIGIN_BOOTC_GROUP: &str = "bootc";
+/// Origin key holding the digest a deployment is locked to (see `bootc switch --lock-digest`)
+const ORIGIN_LOCKED_DIGEST: &str = "locked-digest";
+/// Origin key holding the platform selected via `--platform`
+const ORIGIN_PLATFORM: &str = "platform";
+/// Origin key holding the platforms provided by the image, as a comma-separated list
+const ORIGIN_AVAILABLE_PLATFORMS: &str = "available-platforms";
+
 /// Variant of HostSpec but required to be filled out
 pub(crate) struct RequiredHostSpec<'a> {
     pub(crate) image: &'a ImageReference,
+    /// If set, the image reference tracks a tag but is locked to this digest.
+    pub(crate) locked_digest: Option<&'a Digest>,
+    /// A change to apply to the kernel arguments computed for the new deployment.
+    pub(crate) kargs: Option<&'a KargsDelta>,
+}
+
+/// Options for fetching images which are given for a single invocation (e.g. on
+/// the command line), rather than configured on the host.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PullOptions {
+    /// A container authentication file, used instead of the `registry-auth` secret
+    /// and the global authfile.
+    pub(crate) authfile: Option<Utf8PathBuf>,
+    /// Selected from multi-platform images instead of the host platform.
+    pub(crate) platform: Option<Platform>,
+    /// The number of image layers to download concurrently.
+    pub(crate) fetch_jobs: Option<NonZeroUsize>,
+    /// A limit on the bandwidth used to download image layers, in bytes per second.
+    pub(crate) bandwidth_limit: Option<NonZeroU64>,
 }
 
 /// State of a locally fetched image
@@ -54,10 +89,95 @@ impl<'a> RequiredHostSpec<'a> {
             .image
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("Missing image in specification"))?;
-        Ok(Self { image })
+        Ok(Self {
+            image,
+            locked_digest: None,
+            kargs: None,
+        })
+    }
+}
+
+/// Strip the tag (if any) from an image name; e.g. `quay.io/foo:bar` becomes `quay.io/foo`.
+/// A port in the registry host (`localhost:5000/foo`) is preserved.
+pub(crate) fn strip_tag(name: &str) -> &str {
+    let last = name.rfind('/').map(|i| i + 1).unwrap_or_default();
+    match name[last..].rfind(':') {
+        Some(i) => &name[..last + i],
+        None => name,
+    }
+}
+
+/// Split a reference of the form `name[:tag]@sha256:...` into the
+/// tag reference and the digest.
+pub(crate) fn split_digested_reference(name: &str) -> Result<(&str, Digest)> {
+    let (tagged, digest) = name
+        .split_once('@')
+        .ok_or_else(|| anyhow!("Expected a digested image reference (IMAGE@sha256:...): {name}"))?;
+    anyhow::ensure!(!tagged.is_empty(), "Missing image name in {name}");
+    let digest = Digest::from_str(digest).with_context(|| format!("Parsing digest in {name}"))?;
+    Ok((tagged, digest))
+}
+
+/// Return the digest a deployment is locked to, if any.
+pub(crate) fn origin_locked_digest(deployment: &Deployment) -> Result<Option<String>> {
+    let Some(origin) = deployment.origin() else {
+        return Ok(None);
+    };
+    let r = origin.optional_string(ORIGIN_BOOTC_GROUP, ORIGIN_LOCKED_DIGEST)?;
+    Ok(r.map(|s| s.to_string()))
+}
+
+/// Record the platform selected via `--platform` (if any) in `origin`, along with
+/// the platforms provided by the image if they can be queried.
+pub(crate) fn set_origin_platform(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    origin: &glib::KeyFile,
+    opts: &PullOptions,
+) {
+    let Some(platform) = opts.platform.as_ref() else {
+        return;
+    };
+    origin.set_string(
+        ORIGIN_BOOTC_GROUP,
+        ORIGIN_PLATFORM,
+        &crate::platform::format(platform),
+    );
+    match crate::platform::query_available(repo, imgref, opts) {
+        Ok(available) if !available.is_empty() => {
+            let available = available
+                .iter()
+                .map(crate::platform::format)
+                .collect::<Vec<_>>()
+                .join(",");
+            origin.set_string(ORIGIN_BOOTC_GROUP, ORIGIN_AVAILABLE_PLATFORMS, &available);
+        }
+        Ok(_) => {}
+        Err(e) => tracing::debug!("Failed to query platforms of {imgref:#}: {e:#}"),
     }
 }
 
+/// Return the platform selected for a deployment via `--platform` (if any), and
+/// the platforms its image provides (if known).
+pub(crate) fn origin_platform(deployment: &Deployment) -> Result<(Option<String>, Vec<String>)> {
+    let Some(origin) = deployment.origin() else {
+        return Ok(Default::default());
+    };
+    let platform = origin
+        .optional_string(ORIGIN_BOOTC_GROUP, ORIGIN_PLATFORM)?
+        .map(|s| s.to_string());
+    let available = origin
+        .optional_string(ORIGIN_BOOTC_GROUP, ORIGIN_AVAILABLE_PLATFORMS)?
+        .map(|s| {
+            s.split(',')
+                .filter(|p| !p.is_empty())
+                .map(ToOwned::to_owned)
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((platform, available))
+}
+
 impl From<ostree_container::store::LayeredImageState> for ImageState {
     fn from(value: ostree_container::store::LayeredImageState) -> Self {
         let version = value.version().map(|v| v.to_owned());
@@ -85,10 +205,34 @@ impl ImageState {
 pub(crate) async fn new_importer(
     repo: &ostree::Repo,
     imgref: &ostree_container::OstreeImageReference,
+    opts: &PullOptions,
 ) -> Result<ostree_container::store::ImageImporter> {
-    let config = Default::default();
-    let mut imp = ostree_container::store::ImageImporter::new(repo, imgref, config).await?;
+    if imgref.imgref.transport == ostree_container::Transport::Registry {
+        crate::clock::verify_synchronized()?;
+    }
+    let mut config = ostree_container::store::ImageProxyConfig::default();
+    // An authfile given on the command line takes precedence over a host-level pull
+    // secret, which in turn takes precedence over the global authfile
+    if let Some(authfile) = opts.authfile.as_ref() {
+        config.authfile = Some(authfile.clone().into());
+    } else {
+        config.auth_data = crate::secrets::open_registry_auth(repo)?;
+    }
+    let mut imp = ostree_container::store::ImageImporter::new_with_platform(
+        repo,
+        imgref,
+        config,
+        opts.platform.as_ref(),
+    )
+    .await
+    .map_err(crate::clock::annotate_registry_error)?;
     imp.require_bootable();
+    if let Some(jobs) = opts.fetch_jobs {
+        imp.set_fetch_jobs(jobs);
+    }
+    if let Some(limit) = opts.bandwidth_limit {
+        imp.set_bandwidth_limit(limit);
+    }
     Ok(imp)
 }
 
@@ -181,6 +325,7 @@ async fn handle_layer_progress_print(
 
     let mut subtasks = vec![];
     let mut subtask: SubTaskBytes = Default::default();
+    let mut layer_digest = String::new();
     loop {
         tokio::select! {
             // Always handle layer changes first.
@@ -206,6 +351,14 @@ async fn handle_layer_progress_print(
                             bytes: 0,
                             bytes_total: layer_size,
                         };
+                        layer_digest = layer.digest().to_string();
+                        prog.send(Event::Layer {
+                            digest: layer_digest.as_str().into(),
+                            layer_type: layer_type.into(),
+                            bytes: 0,
+                            bytes_total: layer_size,
+                            completed: false,
+                        }).await;
                     } else {
                         byte_bar.set_position(layer_size);
                         layers_bar.inc(1);
@@ -213,6 +366,13 @@ async fn handle_layer_progress_print(
                         // Emit an event where bytes == total to signal completion.
                         subtask.bytes = layer_size;
                         subtasks.push(subtask.clone());
+                        prog.send(Event::Layer {
+                            digest: layer.digest().to_string().into(),
+                            layer_type: layer_type.into(),
+                            bytes: layer_size,
+                            bytes_total: layer_size,
+                            completed: true,
+                        }).await;
                         prog.send(Event::ProgressBytes {
                             task: "pulling".into(),
                             description: format!("Pulling Image: {digest}").into(),
@@ -243,6 +403,13 @@ async fn handle_layer_progress_print(
                 if let Some(bytes) = bytes {
                     byte_bar.set_position(bytes.fetched);
                     subtask.bytes = byte_bar.position();
+                    prog.send_lossy(Event::Layer {
+                        digest: layer_digest.as_str().into(),
+                        layer_type: subtask.subtask.clone(),
+                        bytes: bytes.fetched,
+                        bytes_total: bytes.total,
+                        completed: false,
+                    }).await;
                     prog.send_lossy(Event::ProgressBytes {
                         task: "pulling".into(),
                         description: format!("Pulling Image: {digest}").into(),
@@ -280,6 +447,11 @@ async fn handle_layer_progress_print(
     // Since the progress notifier closed, we know import has started
     // use as a heuristic to begin import progress
     // Cannot be lossy or it is dropped
+    prog.send(Event::Phase {
+        phase: "importing".into(),
+        description: "Importing Image".into(),
+    })
+    .await;
     prog.send(Event::ProgressSteps {
         task: "importing".into(),
         description: "Importing Image".into(),
@@ -304,19 +476,23 @@ async fn handle_layer_progress_print(
 /// Gather all bound images in all deployments, then prune the image store,
 /// using the gathered images as the roots (that will not be GC'd).
 pub(crate) async fn prune_container_store(sysroot: &Storage) -> Result<()> {
-    let deployments = sysroot.deployments();
-    let mut all_bound_images = Vec::new();
-    for deployment in deployments {
-        let bound = crate::boundimage::query_bound_images_for_deployment(sysroot, &deployment)?;
-        all_bound_images.extend(bound.into_iter());
-    }
+    let roots = crate::boundimage::gc_roots(sysroot)?;
     // Convert to a hashset of just the image names
-    let image_names = HashSet::from_iter(all_bound_images.iter().map(|img| img.image.as_str()));
+    let image_names = HashSet::from_iter(roots.iter().map(String::as_str));
     let pruned = sysroot
         .get_ensure_imgstore()?
         .prune_except_roots(&image_names)
         .await?;
-    tracing::debug!("Pruned images: {}", pruned.len());
+    if !pruned.is_empty() {
+        let size = pruned.iter().filter_map(|e| e.size).sum::<u64>();
+        println!(
+            "Pruned bound images: {} (size: {})",
+            pruned.len(),
+            indicatif::HumanBytes(size)
+        );
+    } else {
+        tracing::debug!("No bound images to prune");
+    }
     Ok(())
 }
 
@@ -339,15 +515,21 @@ pub(crate) async fn prepare_for_pull(
     repo: &ostree::Repo,
     imgref: &ImageReference,
     target_imgref: Option<&OstreeImageReference>,
+    opts: &PullOptions,
 ) -> Result<PreparedPullResult> {
     let imgref_canonicalized = imgref.clone().canonicalize()?;
     tracing::debug!("Canonicalized image reference: {imgref_canonicalized:#}");
     let ostree_imgref = &OstreeImageReference::from(imgref_canonicalized);
-    let mut imp = new_importer(repo, ostree_imgref).await?;
+    crate::platform::preflight(repo, imgref, opts)?;
+    let mut imp = new_importer(repo, ostree_imgref, opts).await?;
     if let Some(target) = target_imgref {
         imp.set_target(target);
     }
-    let prep = match imp.prepare().await? {
+    let prep = match imp
+        .prepare()
+        .await
+        .map_err(crate::clock::annotate_registry_error)?
+    {
         PrepareResult::AlreadyPresent(c) => {
             println!("No changes in {imgref:#} => {}", c.manifest_digest);
             return Ok(PreparedPullResult::AlreadyPresent(Box::new((*c).into())));
@@ -385,6 +567,11 @@ pub(crate) async fn pull_from_prepared(
     let layer_byte_progress = prepared_image.imp.request_layer_progress();
     let digest = prepared_image.digest.clone();
     let digest_imp = prepared_image.digest.clone();
+    prog.send(Event::Phase {
+        phase: "pulling".into(),
+        description: format!("Pulling Image: {digest}").into(),
+    })
+    .await;
 
     let printer = tokio::task::spawn(async move {
         handle_layer_progress_print(
@@ -400,7 +587,11 @@ pub(crate) async fn pull_from_prepared(
         )
         .await
     });
-    let import = prepared_image.imp.import(prepared_image.prep).await;
+    let import = prepared_image
+        .imp
+        .import(prepared_image.prep)
+        .await
+        .map_err(crate::clock::annotate_registry_error);
     let prog = printer.await?;
     // Both the progress and the import are done, so import is done as well
     prog.send(Event::ProgressSteps {
@@ -433,21 +624,76 @@ pub(crate) async fn pull_from_prepared(
 }
 
 /// Wrapper for pulling a container image, wiring up status output.
+///
+/// Transient failures are retried with exponential backoff; layers which were
+/// fetched by a previous attempt are not fetched again.
+///
+/// If `checks` is set, the pre-flight checks are run before fetching an update.
 pub(crate) async fn pull(
     repo: &ostree::Repo,
     imgref: &ImageReference,
     target_imgref: Option<&OstreeImageReference>,
     quiet: bool,
+    checks: bool,
+    opts: &PullOptions,
     prog: ProgressWriter,
 ) -> Result<Box<ImageState>> {
-    match prepare_for_pull(repo, imgref, target_imgref).await? {
-        PreparedPullResult::AlreadyPresent(existing) => Ok(existing),
-        PreparedPullResult::Ready(prepared_image_meta) => {
-            Ok(pull_from_prepared(imgref, quiet, prog, prepared_image_meta).await?)
+    let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let config = crate::retry::load_config(rootfs)?;
+    let mut checks = checks;
+    let mut attempt = 1;
+    loop {
+        let e = match prepare_for_pull(repo, imgref, target_imgref, opts).await {
+            std::result::Result::Ok(PreparedPullResult::AlreadyPresent(existing)) => {
+                return Ok(existing)
+            }
+            std::result::Result::Ok(PreparedPullResult::Ready(prepared_image_meta)) => {
+                if checks {
+                    crate::checks::run(repo, imgref, prepared_image_meta.bytes_to_fetch, &prog)
+                        .await?;
+                    // A retry resumes this fetch, so there is no need to check again
+                    checks = false;
+                }
+                match pull_from_prepared(imgref, quiet, prog.clone(), prepared_image_meta).await {
+                    std::result::Result::Ok(r) => return Ok(r),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        if attempt > config.retries || !crate::retry::is_transient(&e) {
+            return Err(e);
         }
+        attempt += 1;
+        let delay = config.delay_for(attempt);
+        let error = format!("{e:#}");
+        eprintln!(
+            "Pulling {imgref:#} failed: {error}; retrying in {}s (attempt {attempt} of {})",
+            delay.as_secs(),
+            config.retries + 1
+        );
+        prog.send(Event::Retry {
+            task: "pulling".into(),
+            id: imgref.to_string().into(),
+            attempt,
+            error: error.into(),
+        })
+        .await;
+        tokio::time::sleep(delay).await;
     }
 }
 
+/// Look up an image which has already been pulled, without any network access.
+#[context("Querying pulled image")]
+pub(crate) fn query_pulled(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+) -> Result<Option<Box<ImageState>>> {
+    let imgref = OstreeImageReference::from(imgref.clone().canonicalize()?);
+    let state = ostree_container::store::query_image(repo, &imgref.imgref)?;
+    Ok(state.map(|s| Box::new((*s).into())))
+}
+
 pub(crate) async fn wipe_ostree(sysroot: Sysroot) -> Result<()> {
     tokio::task::spawn_blocking(move || {
         sysroot
@@ -462,6 +708,8 @@ pub(crate) async fn wipe_ostree(sysroot: Sysroot) -> Result<()> {
 pub(crate) async fn cleanup(sysroot: &Storage) -> Result<()> {
     let bound_prune = prune_container_store(sysroot);
 
+    crate::store::composefs_gc::auto_gc(sysroot)?;
+
     // We create clones (just atomic reference bumps) here to move to the thread.
     let repo = sysroot.repo();
     let sysroot = sysroot.sysroot.clone();
@@ -503,6 +751,16 @@ pub(crate) async fn cleanup(sysroot: &Storage) -> Result<()> {
                     "Pruned images: {} (layers: {}, objsize: {})",
                     pruned.n_images, pruned.n_layers, size
                 );
+                crate::journal::journal_event(
+                    libsystemd::logging::Priority::Info,
+                    crate::journal::GC_ID,
+                    &format!("Pruned {} images ({size})", pruned.n_images),
+                    &[
+                        ("BOOTC_PRUNED_IMAGES", pruned.n_images.to_string()),
+                        ("BOOTC_PRUNED_LAYERS", pruned.n_layers.to_string()),
+                        ("BOOTC_PRUNED_BYTES", pruned.objsize.to_string()),
+                    ],
+                );
             } else {
                 tracing::debug!("Nothing to prune");
             }
@@ -532,24 +790,52 @@ async fn deploy(
     stateroot: &str,
     image: &ImageState,
     origin: &glib::KeyFile,
+    kargs_delta: Option<&KargsDelta>,
 ) -> Result<Deployment> {
     // Compute the kernel argument overrides. In practice today this API is always expecting
     // a merge deployment. The kargs code also always looks at the booted root (which
     // is a distinct minor issue, but not super important as right now the install path
     // doesn't use this API).
     let override_kargs = if let Some(deployment) = merge_deployment {
-        Some(crate::bootc_kargs::get_kargs(sysroot, &deployment, image)?)
+        let kargs = crate::bootc_kargs::get_kargs(sysroot, &deployment, image)?;
+        Some(match kargs_delta {
+            Some(delta) => delta.apply(&kargs),
+            None => kargs,
+        })
     } else {
         None
     };
+    deploy_commit(
+        sysroot,
+        merge_deployment,
+        stateroot,
+        &image.ostree_commit,
+        origin,
+        override_kargs,
+    )
+    .await
+}
+
+/// Stage a deployment of an ostree commit, optionally overriding the kernel arguments.
+/// When operating on a disk image, the deployment is instead written directly as
+/// the new default.
+pub(crate) async fn deploy_commit(
+    sysroot: &Storage,
+    merge_deployment: Option<&Deployment>,
+    stateroot: &str,
+    ostree_commit: &str,
+    origin: &glib::KeyFile,
+    override_kargs: Option<Vec<String>>,
+) -> Result<Deployment> {
     // Clone all the things to move to worker thread
     let sysroot_clone = sysroot.sysroot.clone();
     // ostree::Deployment is incorrectly !Send 😢 so convert it to an integer
     let merge_deployment = merge_deployment.map(|d| d.index() as usize);
     let stateroot = stateroot.to_string();
-    let ostree_commit = image.ostree_commit.to_string();
+    let ostree_commit = ostree_commit.to_string();
     // GKeyFile also isn't Send! So we serialize that as a string...
     let origin_data = origin.to_data();
+    let offline = sysroot.is_offline();
     let r = async_task_with_spinner(
         "Deploying",
         spawn_blocking_cancellable_flatten(move |cancellable| -> Result<_> {
@@ -569,6 +855,25 @@ async fn deploy(
             let merge_deployment = merge_deployment.map(|m| &deployments[m]);
             let origin = glib::KeyFile::new();
             origin.load_from_data(&origin_data, glib::KeyFileFlags::NONE)?;
+            if offline {
+                let d = sysroot.deploy_tree_with_options(
+                    stateroot.as_deref(),
+                    &ostree_commit,
+                    Some(&origin),
+                    merge_deployment,
+                    Some(&opts),
+                    Some(cancellable),
+                )?;
+                // This retains the merge deployment, which will become the rollback.
+                sysroot.simple_write_deployment(
+                    stateroot.as_deref(),
+                    &d,
+                    merge_deployment,
+                    ostree::SysrootSimpleWriteDeploymentFlags::NONE,
+                    Some(cancellable),
+                )?;
+                return Ok(0);
+            }
             let d = sysroot.stage_tree_with_options(
                 stateroot.as_deref(),
                 &ostree_commit,
@@ -581,12 +886,42 @@ async fn deploy(
         }),
     )
     .await?;
+    if offline {
+        crate::bootloader::update_systemd_boot(&sysroot.physical_root)?;
+        // SAFETY: We just wrote this as the default deployment
+        return Ok(sysroot.deployments().into_iter().next().unwrap());
+    }
     // SAFETY: We must have a staged deployment
     let staged = sysroot.staged_deployment().unwrap();
     assert_eq!(staged.index(), r);
+    crate::lifecycle::record(sysroot, &staged, crate::spec::DeploymentState::Staged)?;
     Ok(staged)
 }
 
+/// Stage a copy of `base` (the staged deployment, or the booted one) with new
+/// kernel arguments.
+#[context("Staging kernel arguments")]
+pub(crate) async fn stage_kargs(
+    sysroot: &Storage,
+    base: &Deployment,
+    kargs: Vec<String>,
+) -> Result<()> {
+    let origin = base
+        .origin()
+        .ok_or_else(|| anyhow!("Deployment {} is missing an origin", base.csum()))?;
+    let merge_deployment = sysroot.booted_deployment();
+    deploy_commit(
+        sysroot,
+        merge_deployment.as_ref(),
+        &base.osname(),
+        &base.csum(),
+        &origin,
+        Some(kargs),
+    )
+    .await?;
+    Ok(())
+}
+
 #[context("Generating origin")]
 fn origin_from_imageref(imgref: &ImageReference) -> Result<glib::KeyFile> {
     let origin = glib::KeyFile::new();
@@ -600,12 +935,38 @@ fn origin_from_imageref(imgref: &ImageReference) -> Result<glib::KeyFile> {
 }
 
 /// Stage (queue deployment of) a fetched container image.
-#[context("Staging")]
 pub(crate) async fn stage(
     sysroot: &Storage,
     stateroot: &str,
     image: &ImageState,
     spec: &RequiredHostSpec<'_>,
+    opts: &PullOptions,
+    prog: ProgressWriter,
+) -> Result<()> {
+    let merge_deployment = sysroot.merge_deployment(Some(stateroot));
+    stage_with_merge(
+        sysroot,
+        merge_deployment,
+        stateroot,
+        image,
+        spec,
+        opts,
+        prog,
+    )
+    .await
+}
+
+/// Stage a fetched container image, merging `/etc` from the provided deployment.
+/// This is used when switching into a different stateroot, which has no merge
+/// deployment of its own.
+#[context("Staging")]
+pub(crate) async fn stage_with_merge(
+    sysroot: &Storage,
+    merge_deployment: Option<Deployment>,
+    stateroot: &str,
+    image: &ImageState,
+    spec: &RequiredHostSpec<'_>,
+    opts: &PullOptions,
     prog: ProgressWriter,
 ) -> Result<()> {
     let mut subtask = SubTaskStep {
@@ -615,6 +976,11 @@ pub(crate) async fn stage(
         completed: false,
     };
     let mut subtasks = vec![];
+    prog.send(Event::Phase {
+        phase: "staging".into(),
+        description: "Deploying Image".into(),
+    })
+    .await;
     prog.send(Event::ProgressSteps {
         task: "staging".into(),
         description: "Deploying Image".into(),
@@ -629,8 +995,6 @@ pub(crate) async fn stage(
             .collect(),
     })
     .await;
-    let merge_deployment = sysroot.merge_deployment(Some(stateroot));
-
     subtask.completed = true;
     subtasks.push(subtask.clone());
     subtask.subtask = "deploying".into();
@@ -652,14 +1016,41 @@ pub(crate) async fn stage(
     })
     .await;
     let origin = origin_from_imageref(spec.image)?;
+    if let Some(digest) = spec.locked_digest {
+        origin.set_string(
+            ORIGIN_BOOTC_GROUP,
+            ORIGIN_LOCKED_DIGEST,
+            digest.to_string().as_str(),
+        );
+    }
+    set_origin_platform(&sysroot.repo(), spec.image, &origin, opts);
     let deployment = crate::deploy::deploy(
         sysroot,
         merge_deployment.as_ref(),
         stateroot,
         image,
         &origin,
+        spec.kargs,
     )
     .await?;
+    if !sysroot.is_offline() {
+        let host_root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        let new_root = &crate::utils::deployment_fd(sysroot, &deployment)?;
+        let r = crate::configcheck::run_checks(host_root, new_root).and_then(|_| {
+            crate::hooks::run(sysroot, crate::hooks::HookPhase::PostStage, &deployment)
+        });
+        if let Err(e) = r {
+            // Don't leave a deployment queued that will fail to boot correctly
+            let deployments = sysroot
+                .deployments()
+                .into_iter()
+                .filter(|d| !d.is_staged())
+                .collect::<Vec<_>>();
+            sysroot.write_deployments(&deployments, gio::Cancellable::NONE)?;
+            return Err(e);
+        }
+    }
+    crate::relabel::schedule_if_policy_changed(sysroot, merge_deployment.as_ref(), &deployment)?;
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
@@ -681,7 +1072,7 @@ pub(crate) async fn stage(
             .collect(),
     })
     .await;
-    crate::boundimage::pull_bound_images(sysroot, &deployment).await?;
+    crate::boundimage::pull_bound_images(sysroot, &deployment, opts.authfile.as_deref()).await?;
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
@@ -703,12 +1094,26 @@ pub(crate) async fn stage(
             .collect(),
     })
     .await;
+    prog.send(Event::Phase {
+        phase: "cleanup".into(),
+        description: "Removing old images".into(),
+    })
+    .await;
     crate::deploy::cleanup(sysroot).await?;
+    crate::journal::journal_event(
+        libsystemd::logging::Priority::Notice,
+        crate::journal::STAGED_ID,
+        &format!("Staged image for next boot: {}", image.manifest_digest),
+        &crate::journal::deployment_fields(&sysroot.repo(), &deployment),
+    );
     println!("Queued for next boot: {:#}", spec.image);
     if let Some(version) = image.version.as_deref() {
         println!("  Version: {version}");
     }
     println!("  Digest: {}", image.manifest_digest);
+    if !sysroot.is_offline() {
+        crate::extensions::warn_unsupported(&sysroot.physical_root, image.version.as_deref())?;
+    }
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
@@ -727,19 +1132,224 @@ pub(crate) async fn stage(
     })
     .await;
 
+    // Give the new deployment a limited number of boot attempts
+    crate::bootcount::arm(&sysroot.physical_root)?;
+
     // Unconditionally create or update /run/reboot-required to signal a reboot is needed.
     // This is monitored by kured (Kubernetes Reboot Daemon).
-    let run_dir = Dir::open_ambient_dir("/run", cap_std::ambient_authority())?;
-    run_dir
-        .atomic_write("reboot-required", b"")
-        .context("Creating /run/reboot-required")?;
+    if !sysroot.is_offline() {
+        let run_dir = Dir::open_ambient_dir("/run", cap_std::ambient_authority())?;
+        run_dir
+            .atomic_write("reboot-required", b"")
+            .context("Creating /run/reboot-required")?;
+    }
+
+    prog.send(Event::Phase {
+        phase: "complete".into(),
+        description: "Queued for next boot".into(),
+    })
+    .await;
+
+    Ok(())
+}
+
+/// How to initialize `/var` for a stateroot newly created as part of a switch.
+#[derive(clap::ValueEnum, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum StaterootVarPolicy {
+    /// Start with an empty `/var`.
+    #[default]
+    Fresh,
+    /// Copy (reflinking if possible) the current `/var` into the new stateroot.
+    Copy,
+}
+
+/// Verify that a stateroot name is usable as a single path component.
+fn validate_stateroot_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains("..") {
+        anyhow::bail!("Invalid stateroot name: {name:?}");
+    }
+    Ok(())
+}
+
+/// Return the stateroot the image prefers to be deployed into, if it declares one.
+/// The label comes from the image, and so an invalid name is an error.
+#[context("Querying preferred stateroot")]
+pub(crate) fn image_preferred_stateroot(
+    repo: &ostree::Repo,
+    image: &ImageState,
+) -> Result<Option<String>> {
+    let imgstate = ostree_container::store::query_image_commit(repo, &image.ostree_commit)?;
+    let r = labels_of_config(&imgstate.configuration)
+        .and_then(|labels| labels.get(crate::metadata::STATEROOT_LABEL))
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty());
+    if let Some(name) = r.as_deref() {
+        validate_stateroot_name(name)
+            .with_context(|| format!("Invalid label {}", crate::metadata::STATEROOT_LABEL))?;
+    }
+    Ok(r)
+}
+
+/// Create the stateroot `stateroot` if it doesn't exist, initializing its `/var`
+/// from the stateroot `current` according to the provided policy.
+#[context("Preparing stateroot {stateroot}")]
+pub(crate) fn prepare_stateroot(
+    sysroot: &Storage,
+    current: &str,
+    stateroot: &str,
+    var_policy: StaterootVarPolicy,
+) -> Result<()> {
+    validate_stateroot_name(stateroot)?;
+    let stateroot_path = format!("ostree/deploy/{stateroot}");
+    if sysroot.physical_root.try_exists(&stateroot_path)? {
+        println!("Using existing stateroot: {stateroot}");
+    } else {
+        sysroot.init_osname(stateroot, gio::Cancellable::NONE)?;
+        println!("Created stateroot: {stateroot}");
+        if var_policy == StaterootVarPolicy::Copy {
+            std::process::Command::new("cp")
+                .args([
+                    "-a",
+                    "--reflink=auto",
+                    &format!("ostree/deploy/{current}/var/."),
+                    &format!("{stateroot_path}/var/"),
+                ])
+                .cwd_dir(sysroot.physical_root.try_clone()?)
+                .run_capture_stderr()?;
+            println!("Copied /var from stateroot {current}");
+        }
+    }
+
+    let var_policy = match var_policy {
+        StaterootVarPolicy::Fresh => "fresh",
+        StaterootVarPolicy::Copy => "copy",
+    };
+    crate::journal::journal_send(
+        libsystemd::logging::Priority::Info,
+        &format!("Switching stateroot {current} => {stateroot} (var: {var_policy})"),
+        [
+            ("MESSAGE_ID", crate::journal::STATEROOT_ID),
+            ("BOOTC_STATEROOT", stateroot),
+            ("BOOTC_PREVIOUS_STATEROOT", current),
+            ("BOOTC_STATEROOT_VAR_POLICY", var_policy),
+        ]
+        .into_iter(),
+    );
+    Ok(())
+}
+
+/// A deployment given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DeploymentId {
+    /// The booted deployment
+    Booted,
+    /// The rollback deployment
+    Rollback,
+    /// The index in the list of all deployments, as used by `ostree admin`
+    Index(usize),
+    /// A prefix of the commit checksum, and optionally the deployment serial
+    Commit {
+        checksum: String,
+        serial: Option<u32>,
+    },
+}
+
+impl std::str::FromStr for DeploymentId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "booted" => return Ok(Self::Booted),
+            "rollback" => return Ok(Self::Rollback),
+            _ => {}
+        }
+        if s.chars().all(|c| c.is_ascii_digit()) {
+            return Ok(Self::Index(s.parse()?));
+        }
+        let (checksum, serial) = match s.split_once('.') {
+            Some((checksum, serial)) => (checksum, Some(serial.parse()?)),
+            None => (s, None),
+        };
+        if checksum.is_empty() || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid deployment: {s}");
+        }
+        Ok(Self::Commit {
+            checksum: checksum.to_owned(),
+            serial,
+        })
+    }
+}
+
+/// Find the deployment matching `id`, given the commit checksum and serial of each
+/// deployment, and the indices of the booted and rollback deployments (if any).
+fn find_deployment(
+    deployments: &[(String, u32)],
+    booted: Option<usize>,
+    rollback: Option<usize>,
+    id: &DeploymentId,
+) -> Result<usize> {
+    match id {
+        DeploymentId::Booted => booted.ok_or_else(|| anyhow!("Not booted into a deployment")),
+        DeploymentId::Rollback => rollback.ok_or_else(|| anyhow!("No rollback deployment")),
+        DeploymentId::Index(i) if *i < deployments.len() => Ok(*i),
+        DeploymentId::Index(i) => Err(anyhow!(
+            "No deployment at index {i}; there are {}",
+            deployments.len()
+        )),
+        DeploymentId::Commit { checksum, serial } => {
+            let mut found = deployments.iter().enumerate().filter(|(_, (c, s))| {
+                c.starts_with(checksum.as_str()) && serial.is_none_or(|serial| serial == *s)
+            });
+            match (found.next(), found.next()) {
+                (Some((i, _)), None) => Ok(i),
+                (None, _) => Err(anyhow!("No deployment matches {checksum}")),
+                (Some(_), Some(_)) => Err(anyhow!(
+                    "Multiple deployments match {checksum}; specify the serial or more of the checksum"
+                )),
+            }
+        }
+    }
+}
 
+/// Implementation of `bootc deployment pin` and `bootc deployment unpin`. Pinned
+/// deployments are never garbage collected, nor are the images they use.
+#[context("Setting deployment pin")]
+pub(crate) fn set_pinned(sysroot: &Storage, id: &DeploymentId, pinned: bool) -> Result<()> {
+    let mut deployments = sysroot.deployments();
+    let ids = deployments
+        .iter()
+        // SAFETY: The deployserial is really unsigned
+        .map(|d| (d.csum().to_string(), d.deployserial().try_into().unwrap()))
+        .collect::<Vec<_>>();
+    let position = |d: Option<ostree::Deployment>| {
+        d.and_then(|d| deployments.iter().position(|v| v.equal(&d)))
+    };
+    let booted = position(sysroot.booted_deployment());
+    let rollback = match id {
+        // Only query the status if needed, as it requires being booted
+        DeploymentId::Rollback => {
+            let (_, related, _) = crate::status::get_status_require_booted(sysroot)?;
+            position(related.rollback)
+        }
+        _ => None,
+    };
+    let deployment = deployments.swap_remove(find_deployment(&ids, booted, rollback, id)?);
+    let name = format!("{}.{}", deployment.csum(), deployment.deployserial());
+    if deployment.is_staged() {
+        anyhow::bail!("Cannot pin the staged deployment {name}");
+    }
+    let verb = if pinned { "pinned" } else { "unpinned" };
+    if deployment.is_pinned() == pinned {
+        println!("Deployment {name} is already {verb}");
+        return Ok(());
+    }
+    sysroot.deployment_set_pinned(&deployment, pinned)?;
+    println!("Deployment {name} is now {verb}");
     Ok(())
 }
 
 /// Implementation of rollback functionality
 pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
-    const ROLLBACK_JOURNAL_ID: &str = "26f3b1eb24464d12aa5e7b544a6b5468";
     let repo = &sysroot.repo();
     let (booted_deployment, deployments, host) = crate::status::get_status_require_booted(sysroot)?;
 
@@ -764,20 +1374,15 @@ pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
         .query_image(repo)?
         .ok_or_else(|| anyhow!("Rollback is not container image based"))?;
     let msg = format!("Rolling back to image: {}", rollback_image.manifest_digest);
-    libsystemd::logging::journal_send(
-        libsystemd::logging::Priority::Info,
-        &msg,
-        [
-            ("MESSAGE_ID", ROLLBACK_JOURNAL_ID),
-            (
-                "BOOTC_MANIFEST_DIGEST",
-                rollback_image.manifest_digest.as_ref(),
-            ),
-        ]
-        .into_iter(),
-    )?;
     // SAFETY: If there's a rollback status, then there's a deployment
     let rollback_deployment = deployments.rollback.expect("rollback deployment");
+    crate::journal::journal_event(
+        libsystemd::logging::Priority::Info,
+        crate::journal::ROLLBACK_ID,
+        &msg,
+        &crate::journal::deployment_fields(repo, &rollback_deployment),
+    );
+    let booted = booted_deployment.clone();
     let new_deployments = if reverting {
         [booted_deployment, rollback_deployment]
     } else {
@@ -789,13 +1394,16 @@ pub(crate) async fn rollback(sysroot: &Storage) -> Result<()> {
         .collect::<Vec<_>>();
     tracing::debug!("Writing new deployments: {new_deployments:?}");
     sysroot.write_deployments(&new_deployments, gio::Cancellable::NONE)?;
+    crate::bootloader::update_systemd_boot(&sysroot.physical_root)?;
+    crate::store::composefs_gc::auto_gc(sysroot)?;
+    crate::lifecycle::record_rollback(sysroot, &booted, reverting)?;
     if reverting {
         println!("Next boot: current deployment");
     } else {
         println!("Next boot: rollback deployment");
     }
 
-    sysroot.update_mtime()?;
+    sysroot.status_changed(crate::events::StatusChangeReason::Rollback)?;
 
     Ok(())
 }
@@ -864,6 +1472,67 @@ pub(crate) fn switch_origin_inplace(root: &Dir, imgref: &ImageReference) -> Resu
     Ok(newest_deployment)
 }
 
+/// An in-place switch rewrites the origin of the booted deployment, which a
+/// staged deployment would silently override at the next reboot; refuse it.
+fn ensure_inplace_switch_unstaged(staged: Option<&str>) -> Result<()> {
+    if let Some(csum) = staged {
+        anyhow::bail!(
+            "A deployment ({csum}) is already staged; use `bootc switch` without `--in-place`, or `bootc rollback` to discard it"
+        );
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc switch --in-place`: retarget the booted deployment
+/// to a new image reference without fetching, as long as the manifest digest of the
+/// target is identical to that of the booted image. The origin file is rewritten
+/// and the kernel arguments are merged in place.
+///
+/// Returns `None` on success, or the target digest if it differs from the booted image.
+#[context("Switching in place")]
+pub(crate) async fn switch_inplace_nofetch(
+    sysroot: &Storage,
+    booted: &ostree::Deployment,
+    imgref: &ImageReference,
+) -> Result<Option<Digest>> {
+    let cancellable = gio::Cancellable::NONE;
+    let staged = sysroot.staged_deployment().map(|d| d.csum());
+    ensure_inplace_switch_unstaged(staged.as_deref())?;
+    let repo = &sysroot.repo();
+    let booted_commit = booted.csum();
+    let booted_image: ImageState =
+        (*ostree_container::store::query_image_commit(repo, &booted_commit)?).into();
+
+    let target = &OstreeImageReference::from(imgref.clone().canonicalize()?);
+    // This only fetches the manifest, not any layers
+    let (_, target_digest) = ostree_container::fetch_manifest(target)
+        .await
+        .map_err(crate::clock::annotate_registry_error)?;
+    if target_digest != booted_image.manifest_digest {
+        return Ok(Some(target_digest));
+    }
+
+    // Write the image ref so that the new image reference is known to be present
+    let target_ref = ostree_container::store::ref_for_image(&target.imgref)?;
+    repo.set_ref_immediate(None, &target_ref, Some(booted_commit.as_str()), cancellable)?;
+
+    let origin = origin_from_imageref(imgref)?;
+    sysroot.write_origin_file(booted, Some(&origin), cancellable)?;
+
+    let current_kargs = booted
+        .bootconfig()
+        .and_then(|b| b.get("options"))
+        .map(|s| s.to_string())
+        .unwrap_or_default();
+    let kargs = crate::bootc_kargs::get_kargs(sysroot, booted, &booted_image)?.join(" ");
+    if kargs != current_kargs {
+        tracing::debug!("Updating kargs: {current_kargs} => {kargs}");
+        sysroot.deployment_set_kargs_in_place(booted, Some(&kargs), cancellable)?;
+    }
+
+    Ok(None)
+}
+
 /// A workaround for https://github.com/ostreedev/ostree/issues/3193
 /// as generated by anaconda.
 #[context("Updating /etc/fstab for anaconda+composefs")]
@@ -987,6 +1656,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_inplace_switch_staged() {
+        ensure_inplace_switch_unstaged(None).unwrap();
+        let e = ensure_inplace_switch_unstaged(Some("abc123")).unwrap_err();
+        assert!(e.to_string().contains("abc123"), "{e}");
+    }
+
+    #[test]
+    fn test_deployment_id() -> Result<()> {
+        assert_eq!("booted".parse::<DeploymentId>()?, DeploymentId::Booted);
+        assert_eq!("1".parse::<DeploymentId>()?, DeploymentId::Index(1));
+        assert_eq!(
+            "abc1.0".parse::<DeploymentId>()?,
+            DeploymentId::Commit {
+                checksum: "abc1".into(),
+                serial: Some(0)
+            }
+        );
+        for invalid in ["", "xyz", "abc.x", ".0"] {
+            assert!(invalid.parse::<DeploymentId>().is_err(), "{invalid}");
+        }
+
+        let deployments = [
+            ("abc123".to_owned(), 0),
+            ("abc123".to_owned(), 1),
+            ("def456".to_owned(), 0),
+        ];
+        let find = |s: &str| find_deployment(&deployments, Some(0), None, &s.parse().unwrap());
+        assert_eq!(find("2")?, 2);
+        assert!(find("3").is_err());
+        assert_eq!(find("de")?, 2);
+        assert_eq!(find("abc123.1")?, 1);
+        assert!(find("abc").is_err());
+        assert!(find("fff").is_err());
+        assert_eq!(find("booted")?, 0);
+        assert!(find("rollback").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_fixup_etc_fstab_default() -> Result<()> {
         let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
@@ -1040,4 +1748,35 @@ UUID=6907-17CA          /boot/efi               vfat    umask=0077,shortname=win
         assert_eq!(tempdir.read_to_string("etc/fstab")?, modified);
         Ok(())
     }
+
+    #[test]
+    fn test_split_digested_reference() -> Result<()> {
+        let digest = "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+        let name = format!("quay.io/exampleos/os:stable@{digest}");
+        let (tagged, d) = split_digested_reference(&name)?;
+        assert_eq!(tagged, "quay.io/exampleos/os:stable");
+        assert_eq!(d.to_string(), digest);
+        assert!(split_digested_reference("quay.io/exampleos/os:stable").is_err());
+        assert!(split_digested_reference("quay.io/exampleos/os@sha256:foo").is_err());
+        assert!(split_digested_reference(&format!("@{digest}")).is_err());
+        for (input, expected) in [
+            ("quay.io/exampleos/os:stable", "quay.io/exampleos/os"),
+            ("quay.io/exampleos/os", "quay.io/exampleos/os"),
+            ("localhost:5000/os:latest", "localhost:5000/os"),
+            ("localhost:5000/os", "localhost:5000/os"),
+        ] {
+            assert_eq!(strip_tag(input), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_stateroot_name() {
+        for valid in ["default", "fedora-41", "os_2", "a.b"] {
+            validate_stateroot_name(valid).unwrap();
+        }
+        for invalid in ["", ".", "..", ".hidden", "a/b", "../../x", "a..b"] {
+            assert!(validate_stateroot_name(invalid).is_err(), "{invalid}");
+        }
+    }
 }
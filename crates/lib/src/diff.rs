@@ -0,0 +1,352 @@
+//! # File-level differences between deployments
+//!
+//! `bootc diff` lists the files added, removed and modified between the booted
+//! deployment and the staged or rollback deployment, or an image in the bootc
+//! storage.
+//!
+//! Deployments and images in the ostree store are compared commit to commit
+//! with [`ostree_ext::diff`]. Images in the composefs repository are mounted,
+//! and their `/usr` compared with that of the booted deployment.
+
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::{self, fs::Dir, fs::MetadataExt as _};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use comfy_table::{presets::NOTHING, Table};
+use fn_error_context::context;
+use ostree_ext::diff::FileTreeDiff;
+use serde::Serialize;
+
+use crate::cli::ImageListFormat;
+use crate::store::Storage;
+
+/// Where composefs images are temporarily mounted for comparison
+const COMPOSEFS_MOUNT: &str = "/run/bootc/diff-image";
+/// The only part of a composefs image which is compared
+const COMPOSEFS_DIFF_ROOT: &str = "/usr";
+
+/// What to compare the booted deployment with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DiffTarget {
+    Staged,
+    Rollback,
+    /// An image in the bootc storage
+    Image(String),
+}
+
+/// The output of `bootc diff`.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct DiffOutput {
+    /// The compared ostree commits or composefs images
+    from: String,
+    to: String,
+    /// Paths; directories have a trailing `/`
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<String>,
+}
+
+impl DiffOutput {
+    /// Convert a diff computed under `subdir` (if any).
+    fn new(from: String, to: String, diff: FileTreeDiff, subdir: Option<&str>) -> Self {
+        let prefix = subdir.unwrap_or_default().trim_end_matches('/');
+        let paths = |files: BTreeSet<String>, dirs: BTreeSet<String>| {
+            let mut r = files
+                .into_iter()
+                .map(|p| format!("{prefix}{p}"))
+                .chain(dirs.into_iter().map(|p| format!("{prefix}{p}/")))
+                .collect::<Vec<_>>();
+            r.sort();
+            r
+        };
+        Self {
+            from,
+            to,
+            added: paths(diff.added_files, diff.added_dirs),
+            removed: paths(diff.removed_files, diff.removed_dirs),
+            modified: paths(diff.changed_files, diff.changed_dirs),
+        }
+    }
+
+    fn print(&self, format: ImageListFormat) -> Result<()> {
+        match format {
+            ImageListFormat::Table => {
+                let mut rows = [
+                    ("added", &self.added),
+                    ("removed", &self.removed),
+                    ("modified", &self.modified),
+                ]
+                .into_iter()
+                .flat_map(|(change, paths)| paths.iter().map(move |p| (p, change)))
+                .collect::<Vec<_>>();
+                rows.sort();
+                let mut table = Table::new();
+                table.load_preset(NOTHING).set_header(["CHANGE", "PATH"]);
+                for (path, change) in rows {
+                    table.add_row([change, path.as_str()]);
+                }
+                println!("{table}");
+            }
+            ImageListFormat::Json => {
+                let mut stdout = std::io::stdout();
+                serde_json::to_writer_pretty(&mut stdout, self)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether two regular files have the same content.
+fn same_content(a: &Dir, b: &Dir, name: &str) -> Result<bool> {
+    let mut a = std::io::BufReader::new(a.open(name)?);
+    let mut b = std::io::BufReader::new(b.open(name)?);
+    let mut bufa = [0u8; 8192];
+    let mut bufb = [0u8; 8192];
+    loop {
+        let n = a.read(&mut bufa)?;
+        if n == 0 {
+            return Ok(b.read(&mut bufb)? == 0);
+        }
+        b.read_exact(&mut bufb[..n])?;
+        if bufa[..n] != bufb[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Compare two directory trees, in the same way as [`ostree_ext::diff::diff`]:
+/// files are modified if their type, ownership, mode or content differ.
+fn diff_dirs(prefix: &str, diff: &mut FileTreeDiff, from: &Dir, to: &Dir) -> Result<()> {
+    for ent in from.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        let path = format!("{prefix}{name}");
+        let from_meta = ent.metadata()?;
+        let Some(to_meta) = to.symlink_metadata_optional(name)? else {
+            if from_meta.is_dir() {
+                diff.removed_dirs.insert(path);
+            } else {
+                diff.removed_files.insert(path);
+            }
+            continue;
+        };
+        let same_owner = from_meta.uid() == to_meta.uid()
+            && from_meta.gid() == to_meta.gid()
+            && from_meta.mode() == to_meta.mode();
+        match (from_meta.is_dir(), to_meta.is_dir()) {
+            (true, true) => {
+                if !same_owner {
+                    diff.changed_dirs.insert(path.clone());
+                }
+                let from = from.open_dir(name)?;
+                let to = to.open_dir(name)?;
+                diff_dirs(&format!("{path}/"), diff, &from, &to)?;
+            }
+            (true, false) => {
+                diff.removed_dirs.insert(path.clone());
+                diff.added_files.insert(path);
+            }
+            (false, true) => {
+                diff.removed_files.insert(path.clone());
+                diff.added_dirs.insert(path);
+            }
+            (false, false) => {
+                // Compare the types first, since reading the contents depends on them
+                let same = from_meta.file_type() == to_meta.file_type()
+                    && same_owner
+                    && from_meta.len() == to_meta.len()
+                    && if from_meta.is_symlink() {
+                        from.read_link_contents(name)? == to.read_link_contents(name)?
+                    } else if from_meta.is_file() {
+                        same_content(from, to, name)?
+                    } else {
+                        from_meta.rdev() == to_meta.rdev()
+                    };
+                if !same {
+                    diff.changed_files.insert(path);
+                }
+            }
+        }
+    }
+    for ent in to.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 filename: {name:?}"))?;
+        if from.symlink_metadata_optional(name)?.is_some() {
+            continue;
+        }
+        let path = format!("{prefix}{name}");
+        if ent.file_type()?.is_dir() {
+            diff.added_dirs.insert(path);
+        } else {
+            diff.added_files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Compare `subdir` (relative) between two roots.
+fn diff_roots(from: &Dir, to: &Dir, subdir: &str) -> Result<FileTreeDiff> {
+    let mut diff = FileTreeDiff {
+        subdir: Some(format!("/{subdir}")),
+        ..Default::default()
+    };
+    match (
+        from.open_dir_optional(subdir)?,
+        to.open_dir_optional(subdir)?,
+    ) {
+        (Some(from), Some(to)) => diff_dirs("/", &mut diff, &from, &to)?,
+        (None, None) => anyhow::bail!("No such directory: /{subdir}"),
+        (Some(_), None) => {
+            diff.removed_dirs.insert(String::new());
+        }
+        (None, Some(_)) => {
+            diff.added_dirs.insert(String::new());
+        }
+    }
+    Ok(diff)
+}
+
+/// Normalize the path filter for a composefs image, which must be in `/usr`.
+fn composefs_subdir(path: Option<&Utf8Path>) -> Result<String> {
+    let path = path.unwrap_or(Utf8Path::new(COMPOSEFS_DIFF_ROOT));
+    let rel = path
+        .strip_prefix("/")
+        .map_err(|_| anyhow::anyhow!("Path must be absolute: {path}"))?;
+    let rel = rel.as_str().trim_end_matches('/');
+    anyhow::ensure!(
+        rel.split('/').next() == Some("usr") && !rel.split('/').any(|c| c == ".."),
+        "Only paths in {COMPOSEFS_DIFF_ROOT} can be compared for composefs images: {path}"
+    );
+    Ok(rel.to_owned())
+}
+
+/// Mount the composefs image with the given configuration digest, run `f` with
+/// the mounted root, and unmount it again.
+fn with_composefs_mount<T>(
+    storage: &Storage,
+    config: &str,
+    f: impl FnOnce(&Dir) -> Result<T>,
+) -> Result<T> {
+    let cfs = storage.get_ensure_composefs()?;
+    std::fs::create_dir_all(COMPOSEFS_MOUNT)?;
+    composefs_oci::mount(&cfs, config, COMPOSEFS_MOUNT, None)
+        .with_context(|| format!("Mounting {config}"))?;
+    let r = Dir::open_ambient_dir(COMPOSEFS_MOUNT, cap_std::ambient_authority())
+        .map_err(Into::into)
+        .and_then(|d| f(&d));
+    Command::new("umount")
+        .args(["-l", COMPOSEFS_MOUNT])
+        .run_capture_stderr()?;
+    r
+}
+
+/// Implementation of `bootc diff`.
+#[context("Computing diff")]
+pub(crate) async fn diff(
+    target: DiffTarget,
+    path: Option<&Utf8Path>,
+    format: ImageListFormat,
+) -> Result<()> {
+    let storage = &crate::cli::get_storage().await?;
+    let booted = storage.require_booted_deployment()?;
+    let from = booted.csum().to_string();
+    let commit = match &target {
+        DiffTarget::Staged => storage
+            .staged_deployment()
+            .map(|d| d.csum().to_string())
+            .ok_or_else(|| anyhow::anyhow!("No staged deployment"))?,
+        DiffTarget::Rollback => storage
+            .query_deployments_for(None)
+            .1
+            .map(|d| d.csum().to_string())
+            .ok_or_else(|| anyhow::anyhow!("No rollback deployment"))?,
+        DiffTarget::Image(image) => {
+            if let Some(config) =
+                crate::store::composefs_export::find_image(&storage.physical_root, image)?
+            {
+                let subdir = composefs_subdir(path)?;
+                let booted_root = crate::utils::deployment_fd(storage, &booted)?;
+                let diff = with_composefs_mount(storage, &config, |root| {
+                    diff_roots(&booted_root, root, &subdir)
+                })?;
+                let subdir = format!("/{subdir}");
+                return DiffOutput::new(from, config, diff, Some(&subdir)).print(format);
+            }
+            crate::image::ostree_image_commit(&storage.repo(), image)?
+                .ok_or_else(|| anyhow::anyhow!("No such image: {image}"))?
+        }
+    };
+    let subdir = path.map(|p| p.as_str());
+    let diff = ostree_ext::diff::diff(&storage.repo(), &from, &commit, subdir)?;
+    DiffOutput::new(from, commit, diff, subdir).print(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn test_composefs_subdir() -> Result<()> {
+        assert_eq!(composefs_subdir(None)?, "usr");
+        assert_eq!(composefs_subdir(Some("/usr/lib/".into()))?, "usr/lib");
+        for p in ["/etc", "usr/lib", "/usr/../etc", "/usrx"] {
+            assert!(composefs_subdir(Some(p.into())).is_err(), "{p}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_roots() -> Result<()> {
+        let from = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let to = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        for d in [from, to] {
+            d.create_dir_all("usr/lib/same")?;
+            d.write("usr/lib/same/file", "same")?;
+            d.write("usr/lib/changed", "old")?;
+            d.symlink_contents("same/file", "usr/lib/link")?;
+        }
+        to.write("usr/lib/changed", "new")?;
+        from.create_dir_all("usr/share/gone")?;
+        from.write("usr/bin-removed", "")?;
+        to.create_dir_all("usr/share/new")?;
+        to.write("usr/share/new/file", "")?;
+        to.write("usr/bin-added", "")?;
+        to.remove_file("usr/lib/link")?;
+        to.symlink_contents("changed", "usr/lib/link")?;
+        // A symlink replaced by a regular file of the same length
+        from.symlink_contents("changed", "usr/lib/retyped")?;
+        to.write("usr/lib/retyped", "1234567")?;
+
+        let diff = diff_roots(from, to, "usr")?;
+        let out = DiffOutput::new("a".into(), "b".into(), diff, Some("/usr"));
+        assert_eq!(
+            out,
+            DiffOutput {
+                from: "a".into(),
+                to: "b".into(),
+                added: vec!["/usr/bin-added".into(), "/usr/share/new/".into()],
+                removed: vec!["/usr/bin-removed".into(), "/usr/share/gone/".into()],
+                modified: vec![
+                    "/usr/lib/changed".into(),
+                    "/usr/lib/link".into(),
+                    "/usr/lib/retyped".into()
+                ],
+            }
+        );
+        assert!(diff_roots(from, to, "opt").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+//! # Downgrade protection
+//!
+//! Staging an update to the booted image which is older than it is refused
+//! unless `--allow-downgrade` is given; this is not checked when switching
+//! to another image. Images are compared by the first of these
+//! which is set in both and differs:
+//!
+//! - the `containers.bootc.generation` label, a monotonically increasing counter
+//! - the version label (see [`ostree_container::version_for_config`])
+//! - the creation timestamp
+//!
+//! An allowed downgrade is logged to the journal and recorded in the
+//! lifecycle of the staged deployment.
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use ostree_ext::container as ostree_container;
+use ostree_ext::oci_spec::image::ImageConfiguration;
+use ostree_ext::ostree;
+
+use crate::deploy::ImageState;
+use crate::store::Storage;
+
+/// A label holding a monotonically increasing counter, which takes precedence
+/// over the version and creation timestamp.
+pub(crate) const GENERATION_LABEL: &str = "containers.bootc.generation";
+
+/// The properties used to order images.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ImageAge {
+    pub(crate) generation: Option<u64>,
+    pub(crate) version: Option<String>,
+    pub(crate) created: Option<DateTime<Utc>>,
+}
+
+impl ImageAge {
+    pub(crate) fn from_config(config: &ImageConfiguration) -> Self {
+        let generation = config
+            .config()
+            .as_ref()
+            .and_then(|c| c.labels().as_ref())
+            .and_then(|l| l.get(GENERATION_LABEL))
+            .and_then(|v| v.trim().parse().ok());
+        let version = ostree_container::version_for_config(config).map(ToOwned::to_owned);
+        let created = config
+            .created()
+            .as_deref()
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|v| v.with_timezone(&Utc));
+        Self {
+            generation,
+            version,
+            created,
+        }
+    }
+}
+
+/// A target image which is older than the booted one.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Downgrade {
+    /// The property which was compared
+    pub(crate) field: &'static str,
+    /// Its value in the booted image
+    pub(crate) from: String,
+    /// Its value in the target image
+    pub(crate) to: String,
+}
+
+impl std::fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} -> {}", self.field, self.from, self.to)
+    }
+}
+
+/// Compare two version strings; runs of digits are compared numerically,
+/// other runs of alphanumeric characters lexically, and separators are ignored.
+pub(crate) fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn segments(v: &str) -> impl Iterator<Item = &str> {
+        let mut rest = v;
+        std::iter::from_fn(move || {
+            rest = rest.trim_start_matches(|c: char| !c.is_ascii_alphanumeric());
+            let first = rest.chars().next()?;
+            let end = if first.is_ascii_digit() {
+                rest.find(|c: char| !c.is_ascii_digit())
+            } else {
+                rest.find(|c: char| !c.is_ascii_alphabetic())
+            }
+            .unwrap_or(rest.len());
+            let (seg, next) = rest.split_at(end);
+            rest = next;
+            Some(seg)
+        })
+    }
+    let mut a = segments(a);
+    let mut b = segments(b);
+    loop {
+        let (x, y) = match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(x), Some(y)) => (x, y),
+        };
+        let xnum = x.starts_with(|c: char| c.is_ascii_digit());
+        let ynum = y.starts_with(|c: char| c.is_ascii_digit());
+        let ord = match (xnum, ynum) {
+            (true, true) => {
+                let x = x.trim_start_matches('0');
+                let y = y.trim_start_matches('0');
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            // Numeric segments are newer
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => x.cmp(y),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+/// Return the downgrade, if `target` is older than `booted`.
+pub(crate) fn check(booted: &ImageAge, target: &ImageAge) -> Option<Downgrade> {
+    if let (Some(from), Some(to)) = (booted.generation, target.generation) {
+        if from != to {
+            return (to < from).then(|| Downgrade {
+                field: "generation",
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+    }
+    if let (Some(from), Some(to)) = (booted.version.as_deref(), target.version.as_deref()) {
+        match compare_versions(to, from) {
+            Ordering::Less => {
+                return Some(Downgrade {
+                    field: "version",
+                    from: from.to_owned(),
+                    to: to.to_owned(),
+                })
+            }
+            Ordering::Greater => return None,
+            Ordering::Equal => {}
+        }
+    }
+    if let (Some(from), Some(to)) = (booted.created, target.created) {
+        return (to < from).then(|| Downgrade {
+            field: "created",
+            from: from.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            to: to.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        });
+    }
+    None
+}
+
+/// Verify that the fetched image is not older than the booted one, unless
+/// `allow` is set; returns the downgrade if there is one.
+pub(crate) fn check_target(
+    repo: &ostree::Repo,
+    booted: Option<&ostree_container::store::LayeredImageState>,
+    target: &ImageState,
+    allow: bool,
+) -> Result<Option<Downgrade>> {
+    let Some(booted) = booted else {
+        return Ok(None);
+    };
+    let target_config =
+        ostree_container::store::query_image_commit(repo, &target.ostree_commit)?.configuration;
+    let downgrade = check(
+        &ImageAge::from_config(&booted.configuration),
+        &ImageAge::from_config(&target_config),
+    );
+    match downgrade {
+        Some(d) if !allow => {
+            anyhow::bail!("Refusing to downgrade ({d}); use --allow-downgrade to override")
+        }
+        Some(d) => {
+            crate::utils::medium_visibility_warning(&format!("Downgrading: {d}"));
+            Ok(Some(d))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Record an allowed downgrade to the staged deployment.
+pub(crate) fn record(sysroot: &Storage, target: &ImageState, downgrade: &Downgrade) -> Result<()> {
+    crate::journal::journal_send(
+        libsystemd::logging::Priority::Notice,
+        &format!(
+            "Staged downgrade to {}: {downgrade}",
+            target.manifest_digest
+        ),
+        [
+            ("MESSAGE_ID", crate::journal::DOWNGRADE_ID),
+            ("BOOTC_MANIFEST_DIGEST", target.manifest_digest.as_ref()),
+            ("BOOTC_DOWNGRADE_FIELD", downgrade.field),
+            ("BOOTC_DOWNGRADE_FROM", downgrade.from.as_str()),
+            ("BOOTC_DOWNGRADE_TO", downgrade.to.as_str()),
+        ]
+        .into_iter(),
+    );
+    crate::lifecycle::record_downgrade(sysroot, &downgrade.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_versions() {
+        use Ordering::*;
+        for (a, b, expected) in [
+            ("1.0", "1.0", Equal),
+            ("42.20250301.0", "42.20250215.1", Greater),
+            ("1.10", "1.9", Greater),
+            ("1.0.1", "1.0", Greater),
+            ("1.01", "1.1", Equal),
+            ("2.0-rc1", "2.0-rc2", Less),
+            ("10", "9", Greater),
+            ("1.a", "1.1", Less),
+        ] {
+            assert_eq!(compare_versions(a, b), expected, "{a} {b}");
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let t = |s: &str| Some(s.parse::<DateTime<Utc>>().unwrap());
+        let booted = ImageAge {
+            generation: Some(7),
+            version: Some("42.1".into()),
+            created: t("2025-03-01T00:00:00Z"),
+        };
+        assert_eq!(check(&booted, &booted), None);
+        assert_eq!(check(&ImageAge::default(), &booted), None);
+
+        // The generation takes precedence
+        let target = ImageAge {
+            generation: Some(6),
+            version: Some("43.0".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&booted, &target).unwrap().to_string(),
+            "generation 7 -> 6"
+        );
+        let target = ImageAge {
+            generation: Some(8),
+            version: Some("41.0".into()),
+            ..Default::default()
+        };
+        assert_eq!(check(&booted, &target), None);
+
+        let target = ImageAge {
+            version: Some("41.9".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&booted, &target).unwrap().to_string(),
+            "version 42.1 -> 41.9"
+        );
+
+        // Equal versions fall back to the creation timestamp
+        let target = ImageAge {
+            version: Some("42.1".into()),
+            created: t("2025-02-01T00:00:00Z"),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&booted, &target),
+            Some(Downgrade {
+                field: "created",
+                from: "2025-03-01T00:00:00Z".into(),
+                to: "2025-02-01T00:00:00Z".into(),
+            })
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! # Local modifications to `/etc`
+//!
+//! On each upgrade, `/etc` is the result of a three-way merge: the defaults
+//! shipped by the new image (in `/usr/etc`), plus the changes made locally
+//! relative to the defaults of the previous image. `bootc etc diff` reports
+//! those local changes, and `bootc etc reset` drops them, restoring the image
+//! default.
+//!
+//! A locally modified file is kept as is, so any change to its default in a
+//! new image is not applied; for critical configuration this is detected by
+//! the `etc-shadowed` fsck check.
+
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use comfy_table::{presets::NOTHING, Table};
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::cli::ImageListFormat;
+use crate::configcheck::EtcChange;
+
+/// Paths in `/etc` for which a local modification shadowing a change in the
+/// image is reported as an error, as it may affect security or the ability to
+/// log in.
+const CRITICAL_PATHS: &[&str] = &[
+    "crypto-policies",
+    "nsswitch.conf",
+    "pam.d",
+    "security",
+    "selinux",
+    "ssh/sshd_config",
+    "sudoers",
+    "sudoers.d",
+];
+
+/// A local change, as output by `bootc etc diff`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct EtcChangeEntry<'a> {
+    change: &'static str,
+    path: &'a str,
+}
+
+impl<'a> From<&'a EtcChange> for EtcChangeEntry<'a> {
+    fn from(c: &'a EtcChange) -> Self {
+        let change = match c {
+            EtcChange::Modified(_) => "modified",
+            EtcChange::Added(_) => "added",
+            EtcChange::Deleted(_) => "deleted",
+        };
+        Self {
+            change,
+            path: c.path(),
+        }
+    }
+}
+
+fn is_critical(path: &str) -> bool {
+    CRITICAL_PATHS.iter().any(|c| {
+        path.strip_prefix(c)
+            .is_some_and(|r| r.is_empty() || r.starts_with('/'))
+    })
+}
+
+/// Whether the image default for `path` (relative to `/etc`) differs between
+/// two deployment roots.
+fn default_changed(old: &Dir, new: &Dir, path: &str) -> Result<bool> {
+    let p = &format!("usr/etc/{path}");
+    let r = match (
+        old.symlink_metadata_optional(p)?,
+        new.symlink_metadata_optional(p)?,
+    ) {
+        (None, None) => false,
+        (Some(a), Some(b)) if a.is_symlink() && b.is_symlink() => {
+            old.read_link_contents(p)? != new.read_link_contents(p)?
+        }
+        (Some(a), Some(b)) if a.is_file() && b.is_file() => {
+            a.len() != b.len() || old.read(p)? != new.read(p)?
+        }
+        (Some(a), Some(b)) => a.file_type() != b.file_type(),
+        _ => true,
+    };
+    Ok(r)
+}
+
+/// Find local changes to critical files in `/etc` whose default differs between
+/// the `old` and `new` deployment roots; the local change shadows that difference.
+#[context("Finding shadowed files in /etc")]
+pub(crate) fn find_shadowed(changes: &[EtcChange], old: &Dir, new: &Dir) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for change in changes.iter().filter(|c| is_critical(c.path())) {
+        if default_changed(old, new, change.path())? {
+            r.push(format!("/etc/{}", change.path()));
+        }
+    }
+    Ok(r)
+}
+
+/// Implementation of `bootc etc diff`.
+pub(crate) async fn diff(format: ImageListFormat) -> Result<()> {
+    let sysroot = crate::cli::get_storage().await?;
+    sysroot.require_booted_deployment()?;
+    let changes = crate::configcheck::local_etc_changes()?;
+    let entries = changes.iter().map(EtcChangeEntry::from).collect::<Vec<_>>();
+    match format {
+        ImageListFormat::Table => {
+            let mut table = Table::new();
+            table.load_preset(NOTHING).set_header(["CHANGE", "PATH"]);
+            for e in entries {
+                table.add_row([e.change.to_owned(), format!("/etc/{}", e.path)]);
+            }
+            println!("{table}");
+        }
+        ImageListFormat::Json => {
+            let mut stdout = std::io::stdout();
+            serde_json::to_writer_pretty(&mut stdout, &entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Normalize a path given on the command line to be relative to `/etc`.
+fn etc_relative_path(p: &Utf8Path) -> Result<&str> {
+    let rel = if p.is_absolute() {
+        p.strip_prefix("/etc")
+            .map_err(|_| anyhow::anyhow!("Not a path in /etc: {p}"))?
+    } else {
+        p.strip_prefix("etc").unwrap_or(p)
+    };
+    let rel = rel.as_str().trim_end_matches('/');
+    if rel.is_empty() || rel.split('/').any(|c| c == "..") {
+        anyhow::bail!("Invalid path in /etc: {p}");
+    }
+    Ok(rel)
+}
+
+/// Restore the image default of `rel` (relative to `/etc`) in `root`, removing it
+/// if the image has no default.
+fn reset_path(root: &Dir, rel: &str) -> Result<()> {
+    let etc_path = format!("etc/{rel}");
+    let default_path = format!("usr/etc/{rel}");
+    let has_default = root.symlink_metadata_optional(&default_path)?.is_some();
+    if has_default {
+        if let Some(parent) = Utf8Path::new(&etc_path).parent() {
+            if !root.try_exists(parent)? {
+                anyhow::bail!("Missing /{parent}; reset it first");
+            }
+        }
+    }
+    root.remove_all_optional(&etc_path)?;
+    if has_default {
+        Command::new("cp")
+            .args(["-a", "--no-target-directory", &default_path, &etc_path])
+            .cwd_dir(root.try_clone()?)
+            .run_capture_stderr()?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc etc reset`.
+#[context("Resetting /etc")]
+pub(crate) async fn reset(paths: &[camino::Utf8PathBuf]) -> Result<()> {
+    let sysroot = crate::cli::get_storage().await?;
+    sysroot.require_booted_deployment()?;
+    let rels = paths
+        .iter()
+        .map(|p| etc_relative_path(p))
+        .collect::<Result<Vec<_>>>()?;
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    for rel in rels {
+        reset_path(root, rel).with_context(|| format!("Resetting /etc/{rel}"))?;
+        println!("Reset: /etc/{rel}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_tempfile;
+
+    #[test]
+    fn test_is_critical() {
+        assert!(is_critical("pam.d"));
+        assert!(is_critical("pam.d/system-auth"));
+        assert!(is_critical("ssh/sshd_config"));
+        assert!(!is_critical("pam.dx"));
+        assert!(!is_critical("ssh/ssh_config"));
+        assert!(!is_critical("motd"));
+    }
+
+    #[test]
+    fn test_find_shadowed() -> Result<()> {
+        let old = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let new = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        for d in [old, new] {
+            d.create_dir_all("usr/etc/pam.d")?;
+            d.create_dir_all("usr/etc/ssh")?;
+            d.write("usr/etc/motd", "hello")?;
+            d.write("usr/etc/pam.d/login", "auth")?;
+        }
+        old.write("usr/etc/ssh/sshd_config", "PermitRootLogin yes")?;
+        new.write("usr/etc/ssh/sshd_config", "PermitRootLogin no")?;
+        new.write("usr/etc/sudoers", "root ALL=(ALL) ALL")?;
+        new.write("usr/etc/motd", "changed")?;
+        let changes = [
+            EtcChange::Modified("ssh/sshd_config".into()),
+            EtcChange::Modified("pam.d/login".into()),
+            EtcChange::Added("sudoers".into()),
+            EtcChange::Modified("motd".into()),
+        ];
+        assert_eq!(
+            find_shadowed(&changes, old, new)?,
+            ["/etc/ssh/sshd_config", "/etc/sudoers"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_etc_relative_path() {
+        for (p, expected) in [
+            ("/etc/ssh/sshd_config", "ssh/sshd_config"),
+            ("etc/motd", "motd"),
+            ("pam.d/", "pam.d"),
+        ] {
+            assert_eq!(etc_relative_path(p.into()).unwrap(), expected);
+        }
+        for p in ["/usr/etc/motd", "/etc", "", "../passwd", "/etc/../shadow"] {
+            assert!(etc_relative_path(p.into()).is_err(), "{p}");
+        }
+    }
+
+    #[test]
+    fn test_reset_path() -> Result<()> {
+        let root = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        root.create_dir_all("usr/etc/ssh")?;
+        root.create_dir_all("etc/ssh")?;
+        root.write("usr/etc/ssh/sshd_config", "default")?;
+        root.write("etc/ssh/sshd_config", "modified")?;
+        root.write("etc/added.conf", "local")?;
+        reset_path(root, "ssh/sshd_config")?;
+        assert_eq!(root.read_to_string("etc/ssh/sshd_config")?, "default");
+        reset_path(root, "added.conf")?;
+        assert!(!root.try_exists("etc/added.conf")?);
+        // A deleted directory is restored as a whole
+        root.create_dir_all("usr/etc/pam.d")?;
+        root.write("usr/etc/pam.d/login", "auth")?;
+        reset_path(root, "pam.d")?;
+        assert_eq!(root.read_to_string("etc/pam.d/login")?, "auth");
+        // The parent directory must exist
+        root.create_dir_all("usr/etc/foo")?;
+        root.write("usr/etc/foo/bar", "")?;
+        assert!(reset_path(root, "foo/bar").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! # Status change events
+//!
+//! Whenever bootc changes the state shown by `bootc status` (e.g. an update is
+//! staged or a rollback is queued), it atomically replaces the file
+//! `/ostree/bootc/events/status.json` with a [`StatusEvent`] carrying an increasing
+//! generation number. Local agents can watch the `/ostree/bootc/events` directory
+//! with inotify (for `IN_MOVED_TO`) instead of polling `bootc status`; see
+//! [`wait_for_event`].
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+/// The events directory, relative to the physical root.
+pub(crate) const EVENTS_SUBDIR: &str = "ostree/bootc/events";
+/// The events directory on a booted system.
+pub const EVENTS_DIR: &str = "/ostree/bootc/events";
+/// The file holding the most recent event.
+const STATUS_EVENT: &str = "status.json";
+
+/// The operation which changed the system status.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StatusChangeReason {
+    /// An update was staged
+    Upgrade,
+    /// A different image was staged or the booted image reference changed
+    Switch,
+    /// The host specification was edited
+    Edit,
+    /// The rollback deployment was queued
+    Rollback,
+    /// Kernel arguments were changed
+    Kargs,
+}
+
+/// A change to the system status.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct StatusEvent {
+    /// Incremented on every change
+    pub generation: u64,
+    /// The operation which made the change
+    pub reason: StatusChangeReason,
+    /// When the change happened
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Read the most recent event from the events directory, if any.
+pub fn read_latest(dir: &Dir) -> Result<Option<StatusEvent>> {
+    let Some(f) = dir.open_optional(STATUS_EVENT)? else {
+        return Ok(None);
+    };
+    let ev = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {STATUS_EVENT}"))?;
+    Ok(Some(ev))
+}
+
+/// Record a status change in the provided physical root.
+#[context("Recording status change event")]
+pub(crate) fn record(root: &Dir, reason: StatusChangeReason) -> Result<StatusEvent> {
+    root.create_dir_all(EVENTS_SUBDIR)?;
+    let dir = root.open_dir(EVENTS_SUBDIR)?;
+    let generation = read_latest(&dir)
+        .unwrap_or_else(|e| {
+            // Don't let a corrupted file block further events
+            tracing::warn!("{e:#}");
+            None
+        })
+        .map(|e| e.generation)
+        .unwrap_or_default()
+        + 1;
+    let ev = StatusEvent {
+        generation,
+        reason,
+        timestamp: std::time::SystemTime::now().into(),
+    };
+    dir.atomic_write(STATUS_EVENT, serde_json::to_vec(&ev)?)?;
+    Ok(ev)
+}
+
+/// Block until an event with a generation newer than `after` is recorded in
+/// `path` (normally [`EVENTS_DIR`]), and return it. If `after` is `None`, the
+/// current event is returned if there is one.
+///
+/// The directory is created along with the first event; until then there
+/// are no events, and we wait for it to appear.
+#[context("Waiting for status change in {path}")]
+pub fn wait_for_event(path: &Utf8Path, after: Option<u64>) -> Result<StatusEvent> {
+    use rustix::fs::inotify;
+
+    let fd = inotify::init(inotify::CreateFlags::CLOEXEC)?;
+    let mut buf = [0u8; 4096];
+    // Don't create it ourselves; e.g. /sysroot is normally mounted read-only.
+    while !path.as_std_path().try_exists()? {
+        let parent = path
+            .ancestors()
+            .skip(1)
+            .find(|p| p.is_dir())
+            .unwrap_or(Utf8Path::new("."));
+        let wd = inotify::add_watch(
+            &fd,
+            parent.as_std_path(),
+            inotify::WatchFlags::CREATE | inotify::WatchFlags::MOVED_TO,
+        )?;
+        // Check again after adding the watch, so we can't miss its creation.
+        if !path.as_std_path().try_exists()? {
+            rustix::io::read(&fd, &mut buf)?;
+        }
+        inotify::remove_watch(&fd, wd)?;
+    }
+    inotify::add_watch(
+        &fd,
+        path.as_std_path(),
+        inotify::WatchFlags::MOVED_TO | inotify::WatchFlags::CLOSE_WRITE,
+    )?;
+    let dir = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+    loop {
+        // Check after adding the watch, so we can't miss a change.
+        if let Some(ev) = read_latest(&dir)? {
+            if after.is_none_or(|g| ev.generation > g) {
+                return Ok(ev);
+            }
+        }
+        // We don't need to parse the events; only our file lives here.
+        rustix::io::read(&fd, &mut buf)?;
+    }
+}
+
+/// Implementation of `bootc internals watch-events`; prints each event
+/// as a JSON line.
+pub(crate) fn watch() -> Result<()> {
+    use std::io::Write;
+
+    let path = Utf8Path::new(EVENTS_DIR);
+    let mut generation = None;
+    let mut stdout = std::io::stdout().lock();
+    loop {
+        let ev = wait_for_event(path, generation)?;
+        generation = Some(ev.generation);
+        serde_json::to_writer(&mut stdout, &ev)?;
+        writeln!(stdout)?;
+        stdout.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let a = record(&td, StatusChangeReason::Upgrade)?;
+        assert_eq!(a.generation, 1);
+        let b = record(&td, StatusChangeReason::Rollback)?;
+        assert_eq!(b.generation, 2);
+        let dir = td.open_dir(EVENTS_SUBDIR)?;
+        assert_eq!(read_latest(&dir)?.unwrap(), b);
+        let raw = dir.read_to_string(STATUS_EVENT)?;
+        assert!(raw.contains(r#""reason":"rollback""#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_event() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let td = Dir::open_ambient_dir(tmp.path(), cap_std::ambient_authority())?;
+        let path = Utf8Path::from_path(tmp.path()).unwrap().join(EVENTS_SUBDIR);
+        // Without any events, the directory doesn't exist until the first one
+        let waiter = std::thread::spawn({
+            let path = path.clone();
+            move || wait_for_event(&path, None)
+        });
+        let first = record(&td, StatusChangeReason::Switch)?;
+        assert_eq!(waiter.join().unwrap()?, first);
+        // An existing event is returned immediately
+        assert_eq!(wait_for_event(&path, None)?, first);
+        let waiter = std::thread::spawn({
+            let path = path.clone();
+            move || wait_for_event(&path, Some(1))
+        });
+        let second = record(&td, StatusChangeReason::Kargs)?;
+        assert_eq!(waiter.join().unwrap()?, second);
+        Ok(())
+    }
+}
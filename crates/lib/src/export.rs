@@ -0,0 +1,197 @@
+//! # Exporting the running system as a container image
+//!
+//! `bootc image export` re-encapsulates the booted deployment into a container
+//! image, so that a configured machine can be captured as a golden image. The
+//! runtime configuration (labels, command etc.) of the booted image is kept.
+//!
+//! With `--include-etc`, the local modifications to `/etc` (as reported by
+//! `bootc etc diff`) are added to a new commit first, as changes to the image
+//! defaults in `/usr/etc`. Machine-specific state such as the machine ID and
+//! SSH host keys is always left out.
+
+use std::os::fd::AsRawFd;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use fn_error_context::context;
+use ostree_ext::container::{self as ostree_container, ImageReference};
+use ostree_ext::ostree::{self, gio};
+
+use crate::configcheck::EtcChange;
+
+/// Paths in `/etc` which identify a machine, and are never exported.
+const MACHINE_SPECIFIC: &[&str] = &[
+    "machine-id",
+    "hostname",
+    ".pwd.lock",
+    ".updated",
+    "ssh/ssh_host_*",
+];
+
+/// Whether `path` (relative to `/etc`) matches one of the patterns, which may end
+/// in a `*` wildcard.
+fn is_excluded(path: &str, patterns: &[&str]) -> bool {
+    patterns.iter().any(|p| match p.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix) && !path[prefix.len()..].contains('/'),
+        None => path == *p || path.starts_with(&format!("{p}/")),
+    })
+}
+
+/// Split the changes to export into the paths to copy and the paths to delete.
+fn plan_etc_changes(changes: Vec<EtcChange>) -> (Vec<String>, Vec<String>) {
+    let mut copy = Vec::new();
+    let mut delete = Vec::new();
+    for change in changes {
+        match change {
+            EtcChange::Modified(p) | EtcChange::Added(p) => copy.push(p),
+            EtcChange::Deleted(p) => delete.push(p),
+        }
+    }
+    let keep = |p: &String| !is_excluded(p, MACHINE_SPECIFIC);
+    copy.retain(keep);
+    delete.retain(keep);
+    // A changed directory is copied as a whole, so skip anything under it.
+    copy.sort();
+    copy.dedup_by(|b, a| b.starts_with(&format!("{a}/")));
+    (copy, delete)
+}
+
+/// Write a new commit from `rev` with the local changes to `/etc` applied to
+/// `/usr/etc`, returning its checksum; or `None` if there are no changes.
+#[context("Committing changes to /etc")]
+fn commit_with_etc(repo: &ostree::Repo, rev: &str) -> Result<Option<String>> {
+    let cancellable = gio::Cancellable::NONE;
+    let (copy, delete) = plan_etc_changes(crate::configcheck::local_etc_changes()?);
+    if copy.is_empty() && delete.is_empty() {
+        return Ok(None);
+    }
+
+    let tempdir = tempfile::tempdir_in("/var/tmp")?;
+    let td = Dir::open_ambient_dir(tempdir.path(), cap_std::ambient_authority())?;
+    td.create_dir("etc")?;
+    // The directory itself must match the default, as its metadata is written too.
+    let etc_path = tempdir.path().join("etc");
+    for cmd in ["chmod", "chown"] {
+        Command::new(cmd)
+            .arg("--reference=/usr/etc")
+            .arg(&etc_path)
+            .run_capture_stderr()?;
+    }
+    if !copy.is_empty() {
+        Command::new("cp")
+            .args(["-a", "--parents", "--target-directory"])
+            .arg(&etc_path)
+            .arg("--")
+            .args(&copy)
+            .current_dir("/etc")
+            .run_capture_stderr()?;
+    }
+
+    let txn = repo.auto_transaction(cancellable)?;
+    let (commit_v, _) = repo.load_commit(rev)?;
+    let mt = ostree::MutableTree::from_commit(repo, rev)?;
+    let usr_etc = mt.walk(&["usr", "etc"], 0)?;
+    for path in delete.iter() {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (parent.split('/').collect::<Vec<_>>(), name),
+            None => (Vec::new(), path.as_str()),
+        };
+        usr_etc
+            .walk(&parent, 0)
+            .and_then(|d| d.remove(name, true))
+            .with_context(|| format!("Removing /usr/etc/{path}"))?;
+    }
+    let modifier = ostree::RepoCommitModifier::new(ostree::RepoCommitModifierFlags::empty(), None);
+    modifier.set_sepolicy_from_commit(repo, rev, cancellable)?;
+    repo.write_dfd_to_mtree(
+        td.as_raw_fd(),
+        "etc",
+        &usr_etc,
+        Some(&modifier),
+        cancellable,
+    )
+    .context("Writing /etc to mtree")?;
+    let root = repo
+        .write_mtree(&mt, cancellable)
+        .context("Writing mtree")?;
+    let root = root.downcast::<ostree::RepoFile>().unwrap();
+    let metadata = commit_v.child_value(0);
+    let commit = repo
+        .write_commit_with_time(
+            None,
+            None,
+            None,
+            Some(&metadata),
+            &root,
+            ostree::commit_get_timestamp(&commit_v),
+            cancellable,
+        )
+        .context("Writing commit")?;
+    txn.commit(cancellable)?;
+    println!(
+        "Including {} changed and {} deleted paths in /etc",
+        copy.len(),
+        delete.len()
+    );
+    Ok(Some(commit.to_string()))
+}
+
+/// Implementation of `bootc image export`.
+#[context("Exporting booted deployment")]
+pub(crate) async fn export(target: &str, include_etc: bool) -> Result<()> {
+    let dest = ImageReference::try_from(target).context("Parsing target image")?;
+    let sysroot = crate::cli::get_storage().await?;
+    let booted = sysroot.require_booted_deployment()?;
+    let repo = &sysroot.repo();
+    let booted_commit = booted.csum().to_string();
+    let configuration = ostree_container::store::query_image_commit(repo, &booted_commit)
+        .map(|s| s.configuration)
+        .context("Querying booted image")?;
+
+    let commit = if include_etc {
+        commit_with_etc(repo, &booted_commit)?.unwrap_or(booted_commit)
+    } else {
+        booted_commit
+    };
+
+    let labels = configuration
+        .config()
+        .as_ref()
+        .and_then(|c| c.labels().clone())
+        .map(|l| l.into_iter().collect());
+    let config = ostree_container::Config { labels, cmd: None };
+    let mut opts = ostree_container::ExportOpts::default();
+    opts.container_config = configuration.config().clone();
+    opts.directory_chunk_size = Some(ostree_ext::chunking::DEFAULT_DIRECTORY_CHUNK_SIZE);
+    println!("Exporting {commit} to {dest} ...");
+    let digest =
+        ostree_ext::container::encapsulate(repo, &commit, &config, Some(opts), &dest).await?;
+    println!("Pushed: {dest} {digest}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_etc_changes() {
+        assert!(is_excluded("machine-id", MACHINE_SPECIFIC));
+        assert!(is_excluded("ssh/ssh_host_ed25519_key", MACHINE_SPECIFIC));
+        assert!(!is_excluded("ssh/sshd_config", MACHINE_SPECIFIC));
+        assert!(!is_excluded("machine-info", MACHINE_SPECIFIC));
+
+        let (copy, delete) = plan_etc_changes(vec![
+            EtcChange::Modified("machine-id".into()),
+            EtcChange::Added("ssh/ssh_host_rsa_key".into()),
+            EtcChange::Modified("ssh/sshd_config".into()),
+            EtcChange::Added("myapp".into()),
+            EtcChange::Added("myapp/config.toml".into()),
+            EtcChange::Deleted("motd".into()),
+        ]);
+        assert_eq!(copy, ["myapp", "ssh/sshd_config"]);
+        assert_eq!(delete, ["motd"]);
+    }
+}
@@ -0,0 +1,638 @@
+//! # Host extensions
+//!
+//! Implementation of `bootc extension`, which manages
+//! [systemd-sysext](https://www.freedesktop.org/software/systemd/man/latest/systemd-sysext.html)
+//! images. These are useful to add debugging tools or vendor software to a host
+//! without building a derived image.
+//!
+//! Extension images are stored in the physical root (`/sysroot/ostree/bootc/extensions`),
+//! so they are preserved across updates and rollbacks. Enabling one links it
+//! into `/var/lib/extensions`, from where `systemd-sysext` merges it into `/usr`
+//! at boot.
+//!
+//! An extension may declare the host image versions it supports in its
+//! extension-release file, via `BOOTC_HOST_VERSION_MIN` (inclusive) and
+//! `BOOTC_HOST_VERSION_MAX` (exclusive). These are compared with the version
+//! label of the host image in the same way as for downgrade protection.
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use comfy_table::{presets::NOTHING, Table};
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::cli::ImageListFormat;
+use crate::spec::{ExtensionStatus, Host};
+use crate::task::Task;
+
+/// The extensions directory, relative to the physical root.
+const EXTENSIONS: &str = "ostree/bootc/extensions";
+/// Holds a copy of the extension-release file of each extension.
+const RELEASE: &str = ".release";
+/// The directory read by systemd-sysext, relative to the root.
+const SYSEXT_DIR: &str = "var/lib/extensions";
+/// The physical root as seen from the booted system.
+const SYSROOT: &str = "/sysroot";
+/// The file extension of sysext disk images.
+const RAW_SUFFIX: &str = ".raw";
+
+/// The fields of an extension-release file used by bootc.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ExtensionRelease {
+    /// The version of the extension itself (`SYSEXT_VERSION_ID`)
+    pub(crate) version: Option<String>,
+    /// The lowest supported host image version
+    pub(crate) min_host_version: Option<String>,
+    /// The lowest unsupported host image version
+    pub(crate) max_host_version: Option<String>,
+}
+
+impl ExtensionRelease {
+    /// Parse an extension-release file, which uses the os-release format.
+    pub(crate) fn parse(contents: &str) -> Self {
+        let mut r = Self::default();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            let Some((k, v)) = line.split_once('=') else {
+                continue;
+            };
+            let v = v.trim();
+            let v = v
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(v);
+            if v.is_empty() {
+                continue;
+            }
+            let field = match k.trim() {
+                "SYSEXT_VERSION_ID" => &mut r.version,
+                "BOOTC_HOST_VERSION_MIN" => &mut r.min_host_version,
+                "BOOTC_HOST_VERSION_MAX" => &mut r.max_host_version,
+                _ => continue,
+            };
+            *field = Some(v.to_owned());
+        }
+        r
+    }
+
+    /// Whether the extension supports a host image with the given version. If the
+    /// extension declares a version range, an image without a version is unsupported.
+    pub(crate) fn supports(&self, host_version: Option<&str>) -> bool {
+        use std::cmp::Ordering;
+        if self.min_host_version.is_none() && self.max_host_version.is_none() {
+            return true;
+        }
+        let Some(host_version) = host_version else {
+            return false;
+        };
+        let cmp = crate::downgrade::compare_versions;
+        self.min_host_version
+            .as_deref()
+            .is_none_or(|min| cmp(host_version, min) != Ordering::Less)
+            && self
+                .max_host_version
+                .as_deref()
+                .is_none_or(|max| cmp(host_version, max) == Ordering::Less)
+    }
+
+    /// The supported host image versions, e.g. `42..43`.
+    fn host_versions(&self) -> String {
+        let min = self.min_host_version.as_deref().unwrap_or_default();
+        let max = self.max_host_version.as_deref().unwrap_or_default();
+        if min.is_empty() && max.is_empty() {
+            "any".to_owned()
+        } else {
+            format!("{min}..{max}")
+        }
+    }
+}
+
+/// Information about a stored extension.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ExtensionInfo {
+    /// The name of the extension
+    pub(crate) name: String,
+    /// The version of the extension, if any
+    pub(crate) version: Option<String>,
+    /// The lowest supported host image version, if declared
+    pub(crate) min_host_version: Option<String>,
+    /// The lowest unsupported host image version, if declared
+    pub(crate) max_host_version: Option<String>,
+    /// Whether the extension is enabled
+    pub(crate) enabled: bool,
+    /// Whether the extension supports the booted image
+    pub(crate) compatible: bool,
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') {
+        anyhow::bail!("Invalid extension name: {name:?}");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        anyhow::bail!("Invalid character {c:?} in extension name {name}");
+    }
+    Ok(())
+}
+
+/// The symlink target in [`SYSEXT_DIR`] for an enabled extension.
+fn link_target(name: &str) -> String {
+    format!("{SYSROOT}/{EXTENSIONS}/{name}{RAW_SUFFIX}")
+}
+
+/// Whether the extension is enabled, i.e. linked into [`SYSEXT_DIR`] by us.
+fn is_enabled(root: &Dir, name: &str) -> Result<bool> {
+    let link = format!("{SYSEXT_DIR}/{name}{RAW_SUFFIX}");
+    if !root
+        .symlink_metadata_optional(&link)?
+        .is_some_and(|m| m.is_symlink())
+    {
+        return Ok(false);
+    }
+    Ok(root.read_link_contents(&link)? == std::path::Path::new(&link_target(name)))
+}
+
+/// Return the stored extension and its metadata, if present.
+fn get_in(physical_root: &Dir, name: &str) -> Result<Option<ExtensionRelease>> {
+    validate_name(name)?;
+    let Some(d) = physical_root.open_dir_optional(EXTENSIONS)? else {
+        return Ok(None);
+    };
+    if !d.try_exists(format!("{name}{RAW_SUFFIX}"))? {
+        return Ok(None);
+    }
+    let release = d
+        .open_optional(format!("{RELEASE}/{name}"))?
+        .map(|mut f| std::io::read_to_string(&mut f))
+        .transpose()?
+        .unwrap_or_default();
+    Ok(Some(ExtensionRelease::parse(&release)))
+}
+
+/// List the stored extensions, sorted by name; compatibility is relative to `host_version`.
+pub(crate) fn list_in(
+    physical_root: &Dir,
+    root: &Dir,
+    host_version: Option<&str>,
+) -> Result<Vec<ExtensionInfo>> {
+    let Some(d) = physical_root.open_dir_optional(EXTENSIONS)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        if !ent.file_type()?.is_file() {
+            continue;
+        }
+        let name = ent.file_name();
+        let Some(name) = name.to_str().and_then(|n| n.strip_suffix(RAW_SUFFIX)) else {
+            continue;
+        };
+        if validate_name(name).is_err() {
+            continue;
+        }
+        let Some(release) = get_in(physical_root, name)? else {
+            continue;
+        };
+        r.push(ExtensionInfo {
+            name: name.to_owned(),
+            compatible: release.supports(host_version),
+            version: release.version,
+            min_host_version: release.min_host_version,
+            max_host_version: release.max_host_version,
+            enabled: is_enabled(root, name)?,
+        });
+    }
+    r.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(r)
+}
+
+/// Store an extension image along with its extension-release file, replacing
+/// any previous version.
+#[context("Storing extension {name}")]
+fn store_in(physical_root: &Dir, name: &str, src: &Utf8Path, release: &str) -> Result<()> {
+    physical_root.create_dir_all(format!("{EXTENSIONS}/{RELEASE}"))?;
+    let d = physical_root.open_dir(EXTENSIONS)?;
+    let mut f = std::fs::File::open(src).with_context(|| format!("Opening {src}"))?;
+    d.atomic_replace_with(format!("{name}{RAW_SUFFIX}"), |w| std::io::copy(&mut f, w))?;
+    d.atomic_write(format!("{RELEASE}/{name}"), release)?;
+    Ok(())
+}
+
+/// Link a stored extension into [`SYSEXT_DIR`].
+#[context("Enabling extension {name}")]
+fn enable_in(root: &Dir, name: &str) -> Result<()> {
+    let link = format!("{SYSEXT_DIR}/{name}{RAW_SUFFIX}");
+    if root.symlink_metadata_optional(&link)?.is_some() {
+        if is_enabled(root, name)? {
+            return Ok(());
+        }
+        anyhow::bail!("/{link} exists and is not managed by bootc");
+    }
+    root.create_dir_all(SYSEXT_DIR)?;
+    root.symlink_contents(link_target(name), &link)?;
+    Ok(())
+}
+
+/// Unlink an extension from [`SYSEXT_DIR`]; returns whether it was enabled.
+#[context("Disabling extension {name}")]
+fn disable_in(root: &Dir, name: &str) -> Result<bool> {
+    if !is_enabled(root, name)? {
+        return Ok(false);
+    }
+    root.remove_file(format!("{SYSEXT_DIR}/{name}{RAW_SUFFIX}"))?;
+    Ok(true)
+}
+
+/// Remove stored extensions which are not enabled and support none of `host_versions`,
+/// returning their names.
+fn prune_in(
+    physical_root: &Dir,
+    root: &Dir,
+    host_versions: &[Option<&str>],
+) -> Result<Vec<String>> {
+    let mut pruned = Vec::new();
+    for ext in list_in(physical_root, root, None)? {
+        let release = ExtensionRelease {
+            version: None,
+            min_host_version: ext.min_host_version,
+            max_host_version: ext.max_host_version,
+        };
+        if ext.enabled || host_versions.iter().any(|&v| release.supports(v)) {
+            continue;
+        }
+        let d = physical_root.open_dir(EXTENSIONS)?;
+        d.remove_file(format!("{}{RAW_SUFFIX}", ext.name))?;
+        d.remove_file_optional(format!("{RELEASE}/{}", ext.name))?;
+        pruned.push(ext.name);
+    }
+    Ok(pruned)
+}
+
+/// Read the extension-release file from an extension image.
+#[context("Reading extension-release from {path}")]
+fn read_release(path: &Utf8Path, name: &str) -> Result<String> {
+    let td = tempfile::tempdir()?;
+    let dest = td.path().join("extension-release");
+    Task::new(format!("Inspecting {path}"), "systemd-dissect")
+        .arg("--copy-from")
+        .arg(path)
+        .arg(format!(
+            "/usr/lib/extension-release.d/extension-release.{name}"
+        ))
+        .arg(&dest)
+        .quiet()
+        .run()?;
+    std::fs::read_to_string(&dest).map_err(Into::into)
+}
+
+/// The name of an extension, from the file name of its image.
+fn name_of(path: &Utf8Path) -> Result<&str> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.strip_suffix(RAW_SUFFIX))
+        .ok_or_else(|| anyhow::anyhow!("Expected a {RAW_SUFFIX} image: {path}"))?;
+    validate_name(name)?;
+    Ok(name)
+}
+
+fn open_root() -> Result<Dir> {
+    Dir::open_ambient_dir("/", cap_std::ambient_authority()).context("Opening /")
+}
+
+/// The versions of the booted, staged and other deployed images.
+fn deployed_versions(host: &Host) -> Vec<Option<&str>> {
+    let status = &host.status;
+    [&status.staged, &status.booted, &status.rollback]
+        .into_iter()
+        .flatten()
+        .chain(status.other_deployments.iter())
+        .map(|e| e.image.as_ref().and_then(|i| i.version.as_deref()))
+        .collect()
+}
+
+/// The version of the booted image.
+fn booted_version(host: &Host) -> Option<&str> {
+    host.status
+        .booted
+        .as_ref()
+        .and_then(|e| e.image.as_ref())
+        .and_then(|i| i.version.as_deref())
+}
+
+fn get_host(storage: &crate::store::Storage) -> Result<Host> {
+    let booted = storage.booted_deployment();
+    let (_, host) = crate::status::get_status(storage, booted.as_ref())?;
+    Ok(host)
+}
+
+fn refresh() -> Result<()> {
+    Task::new("Refreshing system extensions", "systemd-sysext")
+        .arg("refresh")
+        .run()
+}
+
+/// Add the stored extensions to the host status.
+pub(crate) fn apply_to_host(physical_root: &Dir, host: &mut Host) -> Result<()> {
+    let root = open_root()?;
+    let staged_version = host
+        .status
+        .staged
+        .as_ref()
+        .map(|e| e.image.as_ref().and_then(|i| i.version.clone()));
+    let extensions = list_in(physical_root, &root, booted_version(host))?;
+    host.status.extensions = extensions
+        .into_iter()
+        .map(|ext| {
+            let release = ExtensionRelease {
+                version: None,
+                min_host_version: ext.min_host_version,
+                max_host_version: ext.max_host_version,
+            };
+            ExtensionStatus {
+                staged_compatible: staged_version
+                    .as_ref()
+                    .map(|v| release.supports(v.as_deref())),
+                name: ext.name,
+                version: ext.version,
+                enabled: ext.enabled,
+                compatible: ext.compatible,
+            }
+        })
+        .collect();
+    Ok(())
+}
+
+/// Warn about enabled extensions which do not support a newly staged image.
+pub(crate) fn warn_unsupported(physical_root: &Dir, version: Option<&str>) -> Result<()> {
+    let root = open_root()?;
+    for ext in list_in(physical_root, &root, version)? {
+        if ext.enabled && !ext.compatible {
+            crate::utils::medium_visibility_warning(&format!(
+                "Enabled extension {} does not support the staged image",
+                ext.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc extension list`.
+pub(crate) async fn list(format: ImageListFormat) -> Result<()> {
+    let storage = crate::cli::get_storage().await?;
+    let host = get_host(&storage)?;
+    let extensions = list_in(&storage.physical_root, &open_root()?, booted_version(&host))?;
+    match format {
+        ImageListFormat::Table => {
+            let mut table = Table::new();
+            table.load_preset(NOTHING).set_header([
+                "NAME",
+                "VERSION",
+                "HOST VERSIONS",
+                "ENABLED",
+                "COMPATIBLE",
+            ]);
+            for ext in extensions {
+                let host_versions = ExtensionRelease {
+                    version: None,
+                    min_host_version: ext.min_host_version,
+                    max_host_version: ext.max_host_version,
+                }
+                .host_versions();
+                table.add_row([
+                    ext.name,
+                    ext.version.unwrap_or_default(),
+                    host_versions,
+                    ext.enabled.to_string(),
+                    ext.compatible.to_string(),
+                ]);
+            }
+            println!("{table}");
+        }
+        ImageListFormat::Json => {
+            let mut stdout = std::io::stdout();
+            serde_json::to_writer_pretty(&mut stdout, &extensions)?;
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc extension add` and `bootc extension update`.
+pub(crate) async fn store(path: &Utf8Path, update: bool, now: bool) -> Result<()> {
+    let name = name_of(path)?;
+    let storage = crate::cli::get_storage().await?;
+    let host = get_host(&storage)?;
+    let root = open_root()?;
+    match (get_in(&storage.physical_root, name)?.is_some(), update) {
+        (true, false) => anyhow::bail!("Extension {name} already exists"),
+        (false, true) => anyhow::bail!("No such extension: {name}"),
+        _ => {}
+    }
+    let release_contents = read_release(path, name)?;
+    let release = ExtensionRelease::parse(&release_contents);
+    let enabled = is_enabled(&root, name)?;
+    if enabled && !release.supports(booted_version(&host)) {
+        anyhow::bail!(
+            "Extension {name} is enabled, and the new version does not support the booted image (requires host version {})",
+            release.host_versions()
+        );
+    }
+    store_in(&storage.physical_root, name, path, &release_contents)?;
+    println!(
+        "Stored extension {name} {}",
+        release.version.as_deref().unwrap_or_default()
+    );
+    if enabled && now {
+        refresh()?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc extension enable`.
+#[context("Enabling extension {name}")]
+pub(crate) async fn enable(name: &str, now: bool) -> Result<()> {
+    let storage = crate::cli::get_storage().await?;
+    let release = get_in(&storage.physical_root, name)?
+        .ok_or_else(|| anyhow::anyhow!("No such extension: {name}"))?;
+    let host = get_host(&storage)?;
+    if !release.supports(booted_version(&host)) {
+        anyhow::bail!(
+            "Extension {name} does not support the booted image (requires host version {})",
+            release.host_versions()
+        );
+    }
+    enable_in(&open_root()?, name)?;
+    println!("Enabled extension {name}");
+    if now {
+        refresh()?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc extension disable`.
+pub(crate) async fn disable(name: &str, now: bool) -> Result<()> {
+    validate_name(name)?;
+    if !disable_in(&open_root()?, name)? {
+        anyhow::bail!("Extension {name} is not enabled");
+    }
+    println!("Disabled extension {name}");
+    if now {
+        refresh()?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc extension remove`.
+#[context("Removing extension {name}")]
+pub(crate) async fn remove(name: &str) -> Result<()> {
+    let storage = crate::cli::get_storage().await?;
+    if get_in(&storage.physical_root, name)?.is_none() {
+        anyhow::bail!("No such extension: {name}");
+    }
+    if is_enabled(&open_root()?, name)? {
+        anyhow::bail!("Extension {name} is enabled");
+    }
+    let d = storage.physical_root.open_dir(EXTENSIONS)?;
+    d.remove_file(format!("{name}{RAW_SUFFIX}"))?;
+    d.remove_file_optional(format!("{RELEASE}/{name}"))?;
+    println!("Removed extension {name}");
+    Ok(())
+}
+
+/// Implementation of `bootc extension prune`.
+pub(crate) async fn prune() -> Result<()> {
+    let storage = crate::cli::get_storage().await?;
+    let host = get_host(&storage)?;
+    let pruned = prune_in(
+        &storage.physical_root,
+        &open_root()?,
+        &deployed_versions(&host),
+    )?;
+    for name in pruned.iter() {
+        println!("Removed extension {name}");
+    }
+    if pruned.is_empty() {
+        println!("No unused extensions");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_release() {
+        let release = ExtensionRelease::parse(indoc::indoc! {r#"
+            # Comment
+            ID=fedora
+            SYSEXT_VERSION_ID="1.2"
+            BOOTC_HOST_VERSION_MIN='42.20250301'
+            BOOTC_HOST_VERSION_MAX=43
+            SYSEXT_LEVEL=
+        "#});
+        assert_eq!(
+            release,
+            ExtensionRelease {
+                version: Some("1.2".into()),
+                min_host_version: Some("42.20250301".into()),
+                max_host_version: Some("43".into()),
+            }
+        );
+        assert_eq!(release.host_versions(), "42.20250301..43");
+        assert_eq!(ExtensionRelease::parse("ID=_any\n").host_versions(), "any");
+    }
+
+    #[test]
+    fn test_supports() {
+        let any = ExtensionRelease::default();
+        assert!(any.supports(None));
+        assert!(any.supports(Some("1")));
+        let release = ExtensionRelease {
+            min_host_version: Some("42.1".into()),
+            max_host_version: Some("43".into()),
+            ..Default::default()
+        };
+        for (version, expected) in [
+            (None, false),
+            (Some("42.0"), false),
+            (Some("42.1"), true),
+            (Some("42.20250301.0"), true),
+            (Some("43"), false),
+            (Some("43.1"), false),
+        ] {
+            assert_eq!(release.supports(version), expected, "{version:?}");
+        }
+    }
+
+    #[test]
+    fn test_name_of() {
+        assert_eq!(
+            name_of("/tmp/debug-tools.raw".into()).unwrap(),
+            "debug-tools"
+        );
+        for invalid in ["/tmp/debug-tools", "/tmp/.raw", "/tmp/a b.raw"] {
+            assert!(name_of(invalid.into()).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_extensions() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let physical_root = td.open_dir(".")?;
+        td.create_dir("root")?;
+        let root = td.open_dir("root")?;
+        assert!(list_in(&physical_root, &root, None)?.is_empty());
+
+        let src = tempfile::NamedTempFile::new()?;
+        let src = Utf8Path::from_path(src.path()).unwrap();
+        store_in(&physical_root, "tools", src, "SYSEXT_VERSION_ID=1\n")?;
+        store_in(
+            &physical_root,
+            "vendor",
+            src,
+            "BOOTC_HOST_VERSION_MIN=42\nBOOTC_HOST_VERSION_MAX=43\n",
+        )?;
+        let exts = list_in(&physical_root, &root, Some("41.1"))?;
+        assert_eq!(
+            exts.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(),
+            ["tools", "vendor"]
+        );
+        assert_eq!(exts[0].version.as_deref(), Some("1"));
+        assert!(exts[0].compatible && !exts[1].compatible);
+
+        enable_in(&root, "tools")?;
+        assert_eq!(
+            root.read_link_contents("var/lib/extensions/tools.raw")?,
+            std::path::Path::new("/sysroot/ostree/bootc/extensions/tools.raw")
+        );
+        assert!(list_in(&physical_root, &root, None)?[0].enabled);
+        // Enabling is idempotent, but foreign files are not replaced
+        enable_in(&root, "tools")?;
+        root.write("var/lib/extensions/vendor.raw", "")?;
+        assert!(enable_in(&root, "vendor").is_err());
+        assert!(!disable_in(&root, "vendor")?);
+        root.remove_file("var/lib/extensions/vendor.raw")?;
+
+        // Enabled extensions and those supporting a deployed image are kept
+        assert!(prune_in(&physical_root, &root, &[Some("42.5")])?.is_empty());
+        assert!(disable_in(&root, "tools")?);
+        assert!(!disable_in(&root, "tools")?);
+        assert_eq!(
+            prune_in(&physical_root, &root, &[Some("41.1")])?,
+            ["vendor"]
+        );
+        assert!(get_in(&physical_root, "vendor")?.is_none());
+        assert!(get_in(&physical_root, "tools")?.is_some());
+        Ok(())
+    }
+}
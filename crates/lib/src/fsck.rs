@@ -11,18 +11,21 @@ use std::future::Future;
 use std::num::NonZeroUsize;
 use std::pin::Pin;
 
+use anyhow::Context as _;
 use bootc_utils::collect_until;
 use camino::Utf8PathBuf;
 use cap_std::fs::{Dir, MetadataExt as _};
 use cap_std_ext::cap_std;
 use cap_std_ext::dirext::CapStdExtDirExt;
+use composefs::fsverity::{FsVerityHashValue, Sha512HashValue};
 use fn_error_context::context;
 use linkme::distributed_slice;
 use ostree_ext::ostree_prepareroot::Tristate;
 use ostree_ext::{composefs, ostree};
 use serde::{Deserialize, Serialize};
 
-use crate::store::Storage;
+use crate::store::composefs_gc::STATE_DEPLOY;
+use crate::store::{Storage, COMPOSEFS};
 
 use std::os::fd::AsFd;
 
@@ -57,8 +60,16 @@ impl FsckError {
     }
 }
 
-type FsckFn = fn(&Storage) -> FsckResult;
-type AsyncFsckFn = fn(&Storage) -> Pin<Box<dyn Future<Output = FsckResult> + '_>>;
+/// Options for an fsck run.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct FsckOptions {
+    /// Attempt to repair problems that are found.
+    pub(crate) repair: bool,
+}
+
+type FsckFn = fn(&Storage, &FsckOptions) -> FsckResult;
+type AsyncFsckFn =
+    for<'a> fn(&'a Storage, &'a FsckOptions) -> Pin<Box<dyn Future<Output = FsckResult> + 'a>>;
 #[derive(Debug)]
 enum FsckFnImpl {
     Sync(FsckFn),
@@ -105,7 +116,7 @@ static CHECK_RESOLVCONF: FsckCheck =
 ///
 /// But at the current time fsck is an experimental feature that we should only be running
 /// in our CI.
-fn check_resolvconf(storage: &Storage) -> FsckResult {
+fn check_resolvconf(storage: &Storage, _opts: &FsckOptions) -> FsckResult {
     // For now we only check the booted deployment.
     if storage.booted_deployment().is_none() {
         return fsck_ok();
@@ -121,6 +132,38 @@ fn check_resolvconf(storage: &Storage) -> FsckResult {
     fsck_ok()
 }
 
+#[distributed_slice(FSCK_CHECKS)]
+static CHECK_ETC_SHADOWED: FsckCheck =
+    FsckCheck::new("etc-shadowed", 6, FsckFnImpl::Sync(check_etc_shadowed));
+/// Verify that local modifications to critical files in /etc do not shadow
+/// changes to their defaults between the booted deployment and the staged one
+/// (or, if none is staged, between the rollback deployment and the booted one).
+/// Such files are kept as modified by the three-way merge of /etc, and can
+/// be dropped with `bootc etc reset`.
+fn check_etc_shadowed(storage: &Storage, _opts: &FsckOptions) -> FsckResult {
+    let Some(booted) = storage.booted_deployment() else {
+        return fsck_ok();
+    };
+    let (old, new) = if let Some(staged) = storage.staged_deployment() {
+        (booted, staged)
+    } else if let Some(rollback) = storage.query_deployments_for(None).1 {
+        (rollback, booted)
+    } else {
+        return fsck_ok();
+    };
+    let old = crate::utils::deployment_fd(storage, &old)?;
+    let new = crate::utils::deployment_fd(storage, &new)?;
+    let changes = crate::configcheck::local_etc_changes()?;
+    let shadowed = crate::etc::find_shadowed(&changes, &old, &new)?;
+    match format_list(
+        "Locally modified files in /etc shadow changes to their defaults in the image",
+        shadowed.iter(),
+    ) {
+        Some(err) => fsck_err(err),
+        None => fsck_ok(),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum VerityState {
@@ -171,7 +214,7 @@ fn verity_state_of_objects(
         } else {
             disabled += 1;
             if expected {
-                missing.push(format!("{prefix}{name}"));
+                missing.push(format!("{prefix}/{name}"));
             }
         }
     }
@@ -235,11 +278,43 @@ async fn verity_state_of_all_objects(
 #[distributed_slice(FSCK_CHECKS)]
 static CHECK_FSVERITY: FsckCheck =
     FsckCheck::new("fsverity", 10, FsckFnImpl::Async(check_fsverity));
-fn check_fsverity(storage: &Storage) -> Pin<Box<dyn Future<Output = FsckResult> + '_>> {
-    Box::pin(check_fsverity_inner(storage))
+fn check_fsverity<'a>(
+    storage: &'a Storage,
+    opts: &'a FsckOptions,
+) -> Pin<Box<dyn Future<Output = FsckResult> + 'a>> {
+    Box::pin(check_fsverity_inner(storage, opts))
+}
+
+/// Enable fsverity on the provided ostree objects.
+#[context("Enabling fsverity on objects")]
+fn reseal_ostree_objects(repo: &ostree::Repo, objects: &[String]) -> anyhow::Result<()> {
+    let objdir = Dir::reopen_dir(&repo.dfd_borrow())?.open_dir("objects")?;
+    for obj in objects {
+        let f = objdir.open(obj).with_context(|| format!("Opening {obj}"))?;
+        composefs::fsverity::enable_verity_with_retry::<composefs::fsverity::Sha256HashValue>(
+            f.as_fd(),
+        )
+        .with_context(|| format!("Enabling fsverity on {obj}"))?;
+    }
+    Ok(())
+}
+
+/// Format an error message listing the first few items.
+fn format_list<'a>(header: &str, items: impl Iterator<Item = &'a String>) -> Option<String> {
+    let (first, rest) = collect_until(items, const { NonZeroUsize::new(5).unwrap() })?;
+    let mut err = format!("{header}:\n");
+    for item in first {
+        // SAFETY: Writing into a String
+        writeln!(err, "  {item}").unwrap();
+    }
+    if rest > 0 {
+        // SAFETY: Writing into a String
+        writeln!(err, "  ...and {rest} more").unwrap();
+    }
+    Some(err)
 }
 
-async fn check_fsverity_inner(storage: &Storage) -> FsckResult {
+async fn check_fsverity_inner(storage: &Storage, opts: &FsckOptions) -> FsckResult {
     let repo = &storage.repo();
     let verity_state = ostree_ext::fsverity::is_verity_enabled(repo)?;
     tracing::debug!(
@@ -251,25 +326,443 @@ async fn check_fsverity_inner(storage: &Storage) -> FsckResult {
     let verity_found_state =
         verity_state_of_all_objects(&storage.repo(), verity_state.desired == Tristate::Enabled)
             .await?;
-    let Some((missing, rest)) = collect_until(
-        verity_found_state.missing.iter(),
-        const { NonZeroUsize::new(5).unwrap() },
-    ) else {
+    let missing = &verity_found_state.missing;
+    if missing.is_empty() {
         return fsck_ok();
-    };
-    let mut err = String::from("fsverity enabled, but objects without fsverity:\n");
-    for obj in missing {
-        // SAFETY: Writing into a String
-        writeln!(err, "  {obj}").unwrap();
     }
-    if rest > 0 {
-        // SAFETY: Writing into a String
-        writeln!(err, "  ...and {rest} more").unwrap();
+    if opts.repair {
+        reseal_ostree_objects(repo, missing)?;
+        println!("Enabled fsverity on {} objects", missing.len());
+        return fsck_ok();
     }
+    // SAFETY: We checked above that this is non-empty
+    let err = format_list(
+        "fsverity enabled, but objects without fsverity",
+        missing.iter(),
+    )
+    .unwrap();
     fsck_err(err)
 }
 
-pub(crate) async fn fsck(storage: &Storage, mut output: impl std::io::Write) -> anyhow::Result<()> {
+/// The state of objects in the composefs repository.
+#[derive(Debug, Default)]
+struct ComposefsObjectsState {
+    /// Count of objects with fsverity
+    enabled: u64,
+    /// Count of objects without fsverity
+    disabled: u64,
+    /// Objects which should have fsverity but do not
+    missing: Vec<String>,
+    /// Objects whose fsverity digest does not match their name
+    corrupt: Vec<String>,
+}
+
+/// Check the fsverity state of all objects in a composefs repository. Objects
+/// are named by their fsverity digest, so when fsverity is enabled we can
+/// also verify their content.
+#[context("Computing composefs object state")]
+fn composefs_objects_state(repo: &Dir, expected: bool) -> anyhow::Result<ComposefsObjectsState> {
+    let mut r = ComposefsObjectsState::default();
+    let Some(objects) = repo.open_dir_optional("objects")? else {
+        return Ok(r);
+    };
+    for ent in objects.entries()? {
+        let ent = ent?;
+        if !ent.file_type()?.is_dir() {
+            continue;
+        }
+        let prefix = ent.file_name();
+        let Some(prefix) = prefix.to_str() else {
+            continue;
+        };
+        let subdir = ent.open_dir()?;
+        for obj in subdir.entries()? {
+            let obj = obj?;
+            if !obj.file_type()?.is_file() {
+                continue;
+            }
+            let name = obj.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let path = format!("{prefix}/{name}");
+            let f = subdir.open(name)?;
+            match composefs::fsverity::measure_verity_opt::<Sha512HashValue>(f.as_fd())? {
+                Some(digest) if digest.to_hex() != format!("{prefix}{name}") => {
+                    r.enabled += 1;
+                    r.corrupt.push(path)
+                }
+                Some(_) => r.enabled += 1,
+                None => {
+                    r.disabled += 1;
+                    if expected {
+                        r.missing.push(path)
+                    }
+                }
+            }
+        }
+    }
+    r.missing.sort();
+    r.corrupt.sort();
+    Ok(r)
+}
+
+#[distributed_slice(FSCK_CHECKS)]
+static CHECK_COMPOSEFS_OBJECTS: FsckCheck = FsckCheck::new(
+    "composefs-objects",
+    15,
+    FsckFnImpl::Sync(check_composefs_objects),
+);
+/// Verify that objects in the composefs repository have fsverity enabled (if required),
+/// and that their digest matches. With `--repair`, fsverity is enabled on objects
+/// missing it, and corrupted objects are removed; the streams referencing them will
+/// then be found by the `composefs-streams` check.
+fn check_composefs_objects(storage: &Storage, opts: &FsckOptions) -> FsckResult {
+    let Some(repo) = storage.physical_root.open_dir_optional(COMPOSEFS)? else {
+        return fsck_ok();
+    };
+    // This mirrors the logic in get_ensure_composefs
+    let expected = ostree_ext::fsverity::is_verity_enabled(&storage.repo())?.enabled;
+    let mut state = composefs_objects_state(&repo, expected)?;
+    if opts.repair {
+        for obj in std::mem::take(&mut state.missing) {
+            let path = format!("objects/{obj}");
+            let f = repo.open(&path)?;
+            composefs::fsverity::enable_verity_with_retry::<Sha512HashValue>(f.as_fd())
+                .with_context(|| format!("Enabling fsverity on {path}"))?;
+            let digest = composefs::fsverity::measure_verity::<Sha512HashValue>(f.as_fd())?;
+            if digest.to_hex() != obj.replace('/', "") {
+                state.corrupt.push(obj);
+            } else {
+                println!("Enabled fsverity on composefs object {obj}");
+            }
+        }
+        for obj in std::mem::take(&mut state.corrupt) {
+            repo.remove_file_optional(format!("objects/{obj}"))?;
+            println!("Removed corrupted composefs object {obj}");
+        }
+    }
+    let mut errs = Vec::new();
+    errs.extend(format_list(
+        "fsverity required, but composefs objects without fsverity",
+        state.missing.iter(),
+    ));
+    errs.extend(format_list(
+        "composefs objects with mismatched fsverity digest",
+        state.corrupt.iter(),
+    ));
+    if errs.is_empty() {
+        fsck_ok()
+    } else {
+        fsck_err(errs.concat())
+    }
+}
+
+impl VerityState {
+    fn from_counts(enabled: u64, disabled: u64) -> Self {
+        match (enabled, disabled) {
+            (_, 0) => Self::Enabled,
+            (0, _) => Self::Disabled,
+            (e, d) => Self::Inconsistent((e, d)),
+        }
+    }
+}
+
+/// The fsverity coverage of an object store.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ObjectStoreVerity {
+    /// Whether fsverity is expected on all objects
+    pub(crate) expected: bool,
+    /// Count of objects with fsverity
+    pub(crate) enabled: u64,
+    /// Count of objects without fsverity
+    pub(crate) disabled: u64,
+    pub(crate) state: VerityState,
+}
+
+impl ObjectStoreVerity {
+    fn new(expected: bool, enabled: u64, disabled: u64) -> Self {
+        Self {
+            expected,
+            enabled,
+            disabled,
+            state: VerityState::from_counts(enabled, disabled),
+        }
+    }
+}
+
+/// The fsverity state of the ostree and composefs object stores.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct VerityStatus {
+    /// The `ex-integrity.fsverity` value in the ostree repository config
+    pub(crate) ostree_config: String,
+    /// Whether the ostree repository is marked as having fsverity on all objects
+    pub(crate) ostree_sealed: bool,
+    pub(crate) ostree: ObjectStoreVerity,
+    /// Only present if there is a composefs repository
+    pub(crate) composefs: Option<ObjectStoreVerity>,
+}
+
+/// Compute the fsverity coverage of the ostree and composefs object stores.
+#[context("Computing fsverity status")]
+pub(crate) async fn verity_status(storage: &Storage) -> anyhow::Result<VerityStatus> {
+    let repo = &storage.repo();
+    let repo_state = ostree_ext::fsverity::is_verity_enabled(repo)?;
+    let ostree_config = match repo_state.desired {
+        Tristate::Enabled => "yes",
+        Tristate::Disabled => "no",
+        Tristate::Maybe => "maybe",
+    }
+    .to_owned();
+    let objects = verity_state_of_all_objects(repo, false).await?;
+    let ostree = ObjectStoreVerity::new(
+        repo_state.desired == Tristate::Enabled,
+        objects.enabled,
+        objects.disabled,
+    );
+    let composefs = storage
+        .physical_root
+        .open_dir_optional(COMPOSEFS)?
+        .map(|d| {
+            // This mirrors the logic in get_ensure_composefs
+            let state = composefs_objects_state(&d, false)?;
+            anyhow::Ok(ObjectStoreVerity::new(
+                repo_state.enabled,
+                state.enabled,
+                state.disabled,
+            ))
+        })
+        .transpose()?;
+    Ok(VerityStatus {
+        ostree_config,
+        ostree_sealed: repo_state.enabled,
+        ostree,
+        composefs,
+    })
+}
+
+fn render_object_store_verity(
+    mut out: impl std::io::Write,
+    name: &str,
+    v: &ObjectStoreVerity,
+) -> anyhow::Result<()> {
+    let state = match v.state {
+        VerityState::Enabled => "enabled",
+        VerityState::Disabled => "disabled",
+        VerityState::Inconsistent(_) => "partial",
+    };
+    let expected = if v.expected { "required" } else { "optional" };
+    writeln!(
+        out,
+        "{name}: {state} ({expected}); objects: {} with fsverity, {} without",
+        v.enabled, v.disabled
+    )?;
+    Ok(())
+}
+
+/// Print the fsverity status in human readable form.
+pub(crate) fn render_verity_status(
+    mut out: impl std::io::Write,
+    status: &VerityStatus,
+) -> anyhow::Result<()> {
+    writeln!(
+        out,
+        "ostree config: fsverity={} sealed={}",
+        status.ostree_config, status.ostree_sealed
+    )?;
+    render_object_store_verity(&mut out, "ostree", &status.ostree)?;
+    if let Some(composefs) = status.composefs.as_ref() {
+        render_object_store_verity(&mut out, "composefs", composefs)?;
+    }
+    if status.ostree.expected && status.ostree.disabled > 0 {
+        writeln!(
+            out,
+            "warning: fsverity is required, but some objects lack it; use `bootc internals fsck --repair`"
+        )?;
+    }
+    Ok(())
+}
+
+/// Try to enable fsverity on each of the given files, returning the number
+/// of successes and the first error, if any.
+fn try_enable_verity<H: FsVerityHashValue>(
+    d: &Dir,
+    objects: &[String],
+) -> (u64, u64, Option<anyhow::Error>) {
+    let mut enabled = 0;
+    let mut failed = 0;
+    let mut first_err = None;
+    for obj in objects {
+        let r = d.open(obj).map_err(anyhow::Error::from).and_then(|f| {
+            composefs::fsverity::enable_verity_with_retry::<H>(f.as_fd())
+                .map_err(anyhow::Error::from)
+        });
+        match r.with_context(|| format!("Enabling fsverity on {obj}")) {
+            Ok(()) => enabled += 1,
+            Err(e) => {
+                failed += 1;
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    (enabled, failed, first_err)
+}
+
+/// Enable fsverity on objects in the ostree and composefs object stores which
+/// lack it. This is opportunistic: objects on which fsverity cannot be enabled
+/// (e.g. because the filesystem does not support it) are reported and skipped.
+///
+/// With `require`, the ostree repository is also configured to require fsverity
+/// (which also applies to composefs); this fails if it cannot be enabled everywhere.
+#[context("Enabling fsverity")]
+pub(crate) async fn verity_enable_all(storage: &Storage, require: bool) -> anyhow::Result<()> {
+    let repo = &storage.repo();
+    let missing = verity_state_of_all_objects(repo, true).await?.missing;
+    let objdir = Dir::reopen_dir(&repo.dfd_borrow())?.open_dir("objects")?;
+    let (enabled, failed, err) =
+        try_enable_verity::<composefs::fsverity::Sha256HashValue>(&objdir, &missing);
+    println!("ostree: enabled fsverity on {enabled} objects");
+    if let Some(err) = err {
+        println!("ostree: failed to enable fsverity on {failed} objects: {err:#}");
+    }
+
+    if let Some(cfs) = storage.physical_root.open_dir_optional(COMPOSEFS)? {
+        let state = composefs_objects_state(&cfs, true)?;
+        let objects = cfs.open_dir("objects")?;
+        let (enabled, failed, err) = try_enable_verity::<Sha512HashValue>(&objects, &state.missing);
+        println!("composefs: enabled fsverity on {enabled} objects");
+        if let Some(err) = err {
+            println!("composefs: failed to enable fsverity on {failed} objects: {err:#}");
+        }
+        // Objects are named by their digest, so now that they have fsverity
+        // we may find damaged ones.
+        let corrupt = composefs_objects_state(&cfs, false)?.corrupt;
+        if let Some(msg) = format_list(
+            "composefs objects with mismatched fsverity digest (use `bootc internals fsck --repair`)",
+            corrupt.iter(),
+        ) {
+            print!("{msg}");
+        }
+    }
+
+    if require {
+        ostree_ext::fsverity::ensure_verity(repo).await?;
+        println!("ostree: fsverity is now required");
+    }
+    Ok(())
+}
+
+/// Streams holding OCI content (layers and configs) are named by the sha256
+/// digest of their content, possibly with a prefix. Return that digest, if any.
+fn stream_content_digest(name: &str) -> Option<&str> {
+    let digest = name.rsplit_once("sha256:").map(|(_, v)| v).unwrap_or(name);
+    (digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit())).then_some(digest)
+}
+
+#[distributed_slice(FSCK_CHECKS)]
+static CHECK_COMPOSEFS_STREAMS: FsckCheck = FsckCheck::new(
+    "composefs-streams",
+    20,
+    FsckFnImpl::Sync(check_composefs_streams),
+);
+/// Verify that the content of the splitstreams in the composefs repository matches
+/// their digest. With `--repair`, damaged streams are removed so that the next pull
+/// of the image downloads them again.
+fn check_composefs_streams(storage: &Storage, opts: &FsckOptions) -> FsckResult {
+    let Some(repodir) = storage.physical_root.open_dir_optional(COMPOSEFS)? else {
+        return fsck_ok();
+    };
+    let Some(streams) = repodir.open_dir_optional("streams")? else {
+        return fsck_ok();
+    };
+    let repo = storage.get_ensure_composefs()?;
+    let mut damaged = Vec::new();
+    for ent in streams.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let Some(expected) = stream_content_digest(name) else {
+            continue;
+        };
+        let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())?;
+        let found = repo
+            .merge_splitstream(name, None, &mut hasher)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| Ok(hex::encode(&*hasher.finish()?)));
+        let damage = match found {
+            Ok(found) if found == expected => continue,
+            Ok(found) => format!("{name}: found digest {found}"),
+            Err(e) => {
+                tracing::debug!("Reading stream {name}: {e:#}");
+                format!("{name}: {e}")
+            }
+        };
+        if opts.repair {
+            streams.remove_file_optional(name)?;
+            println!("Removed damaged composefs stream {name}");
+        }
+        damaged.push(damage);
+    }
+    if damaged.is_empty() {
+        return fsck_ok();
+    }
+    if opts.repair {
+        println!("Damaged streams will be downloaded again on the next pull of the image");
+        return fsck_ok();
+    }
+    // SAFETY: We checked above that this is non-empty
+    fsck_err(format_list("composefs streams with mismatched content", damaged.iter()).unwrap())
+}
+
+/// Find deployment state directories for which there is no composefs image.
+fn orphaned_deployment_states(physical_root: &Dir) -> anyhow::Result<Vec<String>> {
+    let Some(d) = physical_root.open_dir_optional(STATE_DEPLOY)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let image = format!("{COMPOSEFS}/images/{name}");
+        if physical_root.symlink_metadata_optional(&image)?.is_none() {
+            r.push(name.to_owned());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+#[distributed_slice(FSCK_CHECKS)]
+static CHECK_DEPLOYMENT_STATE: FsckCheck = FsckCheck::new(
+    "composefs-deployment-state",
+    25,
+    FsckFnImpl::Sync(check_deployment_state),
+);
+/// Detect deployment state directories (holding `/etc` and `/var`) whose image is gone.
+/// These are not removed automatically, as they may hold data that needs to be recovered.
+fn check_deployment_state(storage: &Storage, _opts: &FsckOptions) -> FsckResult {
+    let orphaned = orphaned_deployment_states(&storage.physical_root)?;
+    match format_list(
+        &format!("deployment state without a composefs image (in /sysroot/{STATE_DEPLOY})"),
+        orphaned.iter(),
+    ) {
+        Some(err) => fsck_err(err),
+        None => fsck_ok(),
+    }
+}
+
+pub(crate) async fn fsck(
+    storage: &Storage,
+    opts: FsckOptions,
+    mut output: impl std::io::Write,
+) -> anyhow::Result<()> {
     let mut checks = FSCK_CHECKS.static_slice().iter().collect::<Vec<_>>();
     checks.sort_by(|a, b| a.ordering.cmp(&b.ordering));
 
@@ -277,8 +770,8 @@ pub(crate) async fn fsck(storage: &Storage, mut output: impl std::io::Write) ->
     for check in checks.iter() {
         let name = check.name;
         let r = match check.f {
-            FsckFnImpl::Sync(f) => f(&storage),
-            FsckFnImpl::Async(f) => f(&storage).await,
+            FsckFnImpl::Sync(f) => f(&storage, &opts),
+            FsckFnImpl::Async(f) => f(&storage, &opts).await,
         };
         match r {
             Ok(Ok(())) => {
@@ -312,3 +805,73 @@ pub(crate) async fn fsck(storage: &Storage, mut output: impl std::io::Write) ->
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_content_digest() {
+        let digest = "a".repeat(64);
+        assert_eq!(stream_content_digest(&digest), Some(digest.as_str()));
+        assert_eq!(
+            stream_content_digest(&format!("oci-layer-sha256:{digest}")),
+            Some(digest.as_str())
+        );
+        assert_eq!(stream_content_digest("config"), None);
+        assert_eq!(stream_content_digest(&"z".repeat(64)), None);
+    }
+
+    #[test]
+    fn test_orphaned_deployment_states() -> anyhow::Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(orphaned_deployment_states(&td)?.is_empty());
+        td.create_dir_all(format!("{STATE_DEPLOY}/aa01/etc"))?;
+        td.create_dir_all(format!("{STATE_DEPLOY}/aa02/var"))?;
+        td.create_dir_all(format!("{COMPOSEFS}/images"))?;
+        td.symlink_contents("../objects/aa/01", format!("{COMPOSEFS}/images/aa01"))?;
+        assert_eq!(orphaned_deployment_states(&td)?, ["aa02"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verity_status() -> anyhow::Result<()> {
+        assert_eq!(VerityState::from_counts(0, 0), VerityState::Enabled);
+        assert_eq!(VerityState::from_counts(0, 3), VerityState::Disabled);
+        assert_eq!(
+            VerityState::from_counts(2, 3),
+            VerityState::Inconsistent((2, 3))
+        );
+
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("objects/aa")?;
+        td.write("objects/aa/01", "foo")?;
+        td.write("objects/aa/02", "bar")?;
+        let state = composefs_objects_state(&td, false)?;
+        assert_eq!((state.enabled, state.disabled), (0, 2));
+        assert!(state.missing.is_empty());
+        assert_eq!(
+            composefs_objects_state(&td, true)?.missing,
+            ["aa/01", "aa/02"]
+        );
+
+        let status = VerityStatus {
+            ostree_config: "yes".into(),
+            ostree_sealed: false,
+            ostree: ObjectStoreVerity::new(true, 10, 2),
+            composefs: Some(ObjectStoreVerity::new(false, 0, 2)),
+        };
+        let mut out = Vec::new();
+        render_verity_status(&mut out, &status)?;
+        similar_asserts::assert_eq!(
+            String::from_utf8(out)?,
+            indoc::indoc! { "
+                ostree config: fsverity=yes sealed=false
+                ostree: partial (required); objects: 10 with fsverity, 2 without
+                composefs: disabled (optional); objects: 0 with fsverity, 2 without
+                warning: fsverity is required, but some objects lack it; use `bootc internals fsck --repair`
+            "}
+        );
+        Ok(())
+    }
+}
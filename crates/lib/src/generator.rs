@@ -1,13 +1,22 @@
 use std::io::BufRead;
 
 use anyhow::{Context, Result};
+use camino::Utf8Path;
 use cap_std::fs::Dir;
 use cap_std_ext::{cap_std, dirext::CapStdExtDirExt};
 use fn_error_context::context;
 use ostree_ext::container_utils::is_ostree_booted_in;
 use rustix::{fd::AsFd, fs::StatVfsMountFlags};
 
+use crate::usroverlay::{DEV_OVERLAY, DEV_OVERLAY_UNIT};
+
 const EDIT_UNIT: &str = "bootc-fstab-edit.service";
+/// The bootc image storage, as configured as an additional image store
+const BOUND_IMAGE_STORAGE: &str = "/usr/lib/bootc/storage";
+/// The container storage configuration for services of bound images, relative to the root
+const BOUND_STORAGE_CONF: &str = "run/bootc/bound-images/storage.conf";
+/// The drop-in pointing the services of bound images at [`BOUND_STORAGE_CONF`]
+const BOUND_DROPIN: &str = "50-bootc-bound-image.conf";
 const FSTAB_ANACONDA_STAMP: &str = "Created by anaconda";
 pub(crate) const BOOTC_EDITED_STAMP: &str = "Updated by bootc-fstab-edit.service";
 
@@ -47,8 +56,93 @@ pub(crate) fn fstab_generator_impl(root: &Dir, unit_dir: &Dir) -> Result<bool> {
     Ok(false)
 }
 
+/// Generate a unit to re-apply a persistent `bootc usr-overlay`, if one exists.
+#[context("bootc dev overlay generator")]
+pub(crate) fn dev_overlay_generator_impl(root: &Dir, unit_dir: &Dir) -> Result<bool> {
+    if !is_ostree_booted_in(root)? {
+        return Ok(false);
+    }
+    if !root.try_exists(format!("sysroot/{DEV_OVERLAY}"))? {
+        return Ok(false);
+    }
+    generate_dev_overlay_unit(unit_dir)?;
+    Ok(true)
+}
+
+/// Generate the container storage configuration of the host, with the bootc
+/// image storage added as an additional image store.
+fn bound_storage_conf(root: &Dir) -> Result<String> {
+    let mut conf = [
+        "etc/containers/storage.conf",
+        "usr/share/containers/storage.conf",
+    ]
+    .into_iter()
+    .find_map(|p| root.read_to_string(p).ok())
+    .map(|s| toml::from_str::<toml::Table>(&s))
+    .transpose()
+    .context("Parsing storage.conf")?
+    .unwrap_or_default();
+    let storage = conf
+        .entry("storage")
+        .or_insert_with(|| toml::Table::new().into());
+    if let Some(storage) = storage.as_table_mut() {
+        storage.entry("driver").or_insert_with(|| "overlay".into());
+    }
+    let stores = storage
+        .as_table_mut()
+        .and_then(|t| {
+            t.entry("options")
+                .or_insert_with(|| toml::Table::new().into())
+                .as_table_mut()
+        })
+        .and_then(|t| {
+            t.entry("additionalimagestores")
+                .or_insert_with(|| toml::value::Array::new().into())
+                .as_array_mut()
+        })
+        .ok_or_else(|| anyhow::anyhow!("Invalid storage.conf"))?;
+    if !stores
+        .iter()
+        .any(|v| v.as_str() == Some(BOUND_IMAGE_STORAGE))
+    {
+        stores.push(BOUND_IMAGE_STORAGE.into());
+    }
+    Ok(toml::to_string(&conf)?)
+}
+
+/// Point the services Quadlet generates for bound images at the bootc image storage,
+/// so that `.container` files don't need to configure it themselves.
+#[context("bootc bound images generator")]
+pub(crate) fn bound_images_generator_impl(root: &Dir, unit_dir: &Dir) -> Result<bool> {
+    if !is_ostree_booted_in(root)? {
+        return Ok(false);
+    }
+    let units = crate::boundimage::query_bound_units(root)?;
+    if units.is_empty() {
+        return Ok(false);
+    }
+    let conf = bound_storage_conf(root)?;
+    let conf_dir = Utf8Path::new(BOUND_STORAGE_CONF).parent().unwrap();
+    root.create_dir_all(conf_dir)?;
+    root.atomic_write(BOUND_STORAGE_CONF, conf)?;
+    let dropin = format!("[Service]\nEnvironment=CONTAINERS_STORAGE_CONF=/{BOUND_STORAGE_CONF}\n");
+    for unit in units {
+        let d = format!("{unit}.d");
+        unit_dir.create_dir_all(&d)?;
+        unit_dir.atomic_write(format!("{d}/{BOUND_DROPIN}"), &dropin)?;
+    }
+    Ok(true)
+}
+
 /// Main entrypoint for the generator
 pub(crate) fn generator(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    let updated = dev_overlay_generator_impl(root, unit_dir)?;
+    tracing::trace!("Generated dev overlay: {updated}");
+    // An invalid bound image definition shouldn't break the rest of the boot
+    match bound_images_generator_impl(root, unit_dir) {
+        Ok(updated) => tracing::trace!("Generated bound images: {updated}"),
+        Err(e) => tracing::warn!("{e:#}"),
+    }
     // Right now we only do something if the root is a read-only overlayfs (a composefs really)
     let st = rustix::fs::fstatfs(root.as_fd())?;
     if st.f_type != libc::OVERLAYFS_SUPER_MAGIC {
@@ -87,6 +181,35 @@ ExecStart=bootc internals fixup-etc-fstab\n\
     Ok(())
 }
 
+/// Generate a unit which mounts the persistent /usr overlay early in boot.
+fn generate_dev_overlay_unit(unit_dir: &Dir) -> Result<()> {
+    unit_dir.atomic_write(
+        DEV_OVERLAY_UNIT,
+        format!(
+            "[Unit]\n\
+Description=Apply persistent /usr overlay\n\
+DefaultDependencies=no\n\
+RequiresMountsFor=/sysroot\n\
+After=systemd-remount-fs.service\n\
+Before=local-fs.target shutdown.target\n\
+ConditionPathIsDirectory=/sysroot/{DEV_OVERLAY}\n\
+\n\
+[Service]\n\
+Type=oneshot\n\
+RemainAfterExit=yes\n\
+ExecStart=bootc internals apply-dev-overlay\n\
+"
+        ),
+    )?;
+    let target = "local-fs.target.wants";
+    unit_dir.create_dir_all(target)?;
+    unit_dir.symlink(
+        &format!("../{DEV_OVERLAY_UNIT}"),
+        &format!("{target}/{DEV_OVERLAY_UNIT}"),
+    )?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,6 +232,91 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_generator_dev_overlay() -> Result<()> {
+        use ostree_ext::container_utils::OSTREE_BOOTED;
+
+        let tempdir = fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+        assert!(!dev_overlay_generator_impl(&tempdir, unit_dir)?);
+        tempdir.create_dir_all(format!("sysroot/{DEV_OVERLAY}"))?;
+        // Not booted via ostree
+        assert!(!dev_overlay_generator_impl(&tempdir, unit_dir)?);
+        assert_eq!(unit_dir.entries()?.count(), 0);
+
+        tempdir.atomic_write(OSTREE_BOOTED, "ostree booted")?;
+        assert!(dev_overlay_generator_impl(&tempdir, unit_dir)?);
+        assert!(unit_dir.try_exists(format!("local-fs.target.wants/{DEV_OVERLAY_UNIT}"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bound_storage_conf() -> Result<()> {
+        let tempdir = fixture()?;
+        let conf: toml::Table = toml::from_str(&bound_storage_conf(&tempdir)?)?;
+        assert_eq!(conf["storage"]["driver"].as_str(), Some("overlay"));
+        assert_eq!(
+            conf["storage"]["options"]["additionalimagestores"]
+                .as_array()
+                .unwrap()
+                .as_slice(),
+            [toml::Value::from(BOUND_IMAGE_STORAGE)]
+        );
+
+        tempdir.create_dir_all("etc/containers")?;
+        tempdir.atomic_write(
+            "etc/containers/storage.conf",
+            indoc::indoc! { r#"
+                [storage]
+                driver = "overlay"
+                runroot = "/run/containers/storage"
+                graphroot = "/var/lib/containers/storage"
+
+                [storage.options]
+                additionalimagestores = ["/usr/share/containers/storage"]
+            "# },
+        )?;
+        let conf: toml::Table = toml::from_str(&bound_storage_conf(&tempdir)?)?;
+        assert_eq!(
+            conf["storage"]["graphroot"].as_str(),
+            Some("/var/lib/containers/storage")
+        );
+        assert_eq!(
+            conf["storage"]["options"]["additionalimagestores"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generator_bound_images() -> Result<()> {
+        use ostree_ext::container_utils::OSTREE_BOOTED;
+
+        let tempdir = fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+        tempdir.atomic_write(OSTREE_BOOTED, "ostree booted")?;
+        assert!(!bound_images_generator_impl(&tempdir, unit_dir)?);
+
+        tempdir.create_dir_all("usr/lib/bootc/bound-images.d")?;
+        tempdir.create_dir_all("usr/share/containers/systemd")?;
+        tempdir.atomic_write(
+            "usr/share/containers/systemd/app.container",
+            "[Container]\nImage=quay.io/example/app:latest\n",
+        )?;
+        tempdir.symlink_contents(
+            "/usr/share/containers/systemd/app.container",
+            "usr/lib/bootc/bound-images.d/app.container",
+        )?;
+        assert!(bound_images_generator_impl(&tempdir, unit_dir)?);
+        let dropin = unit_dir.read_to_string(format!("app.service.d/{BOUND_DROPIN}"))?;
+        assert!(dropin.contains(&format!("CONTAINERS_STORAGE_CONF=/{BOUND_STORAGE_CONF}")));
+        assert!(tempdir.try_exists(BOUND_STORAGE_CONF)?);
+        Ok(())
+    }
+
     #[cfg(test)]
     mod test {
         use super::*;
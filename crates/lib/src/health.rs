@@ -0,0 +1,297 @@
+//! # Automatic rollback on failed services
+//!
+//! After booting into a new deployment, `bootc rollback --auto-on-failure`
+//! (run by the optional `bootc-health-check.service`) monitors a set of units
+//! for a window after boot. If any of them fails, the rollback deployment is
+//! queued as the default (as with `bootc rollback`), optionally rebooting into
+//! it; the reason is recorded in the deployment lifecycle shown by `bootc status`.
+//!
+//! This is configured in `/etc/bootc/health.toml` (or `/usr/lib/bootc/health.toml`):
+//!
+//! ```toml
+//! [health]
+//! # The units which must not fail
+//! units = ["httpd.service", "app.service"]
+//! # How long after boot to monitor them
+//! window = "5m"
+//! # Reboot into the rollback deployment immediately
+//! reboot = false
+//! ```
+
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use serde::Deserialize;
+
+use crate::spec::{BootOrder, DeploymentState, Host};
+use crate::store::Storage;
+
+/// The name of the configuration file and section; see [`crate::hostconfig`]
+const CONFIG_NAME: &str = "health";
+/// The default time after boot during which units are monitored
+const DEFAULT_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How often the state of the monitored units is queried
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The `[health]` section.
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct HealthConfigFile {
+    units: Option<Vec<String>>,
+    window: Option<String>,
+    reboot: Option<bool>,
+}
+
+/// The effective health check configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct HealthConfig {
+    /// The units which must not fail
+    pub(crate) units: Vec<String>,
+    /// How long after boot the units are monitored
+    pub(crate) window: Duration,
+    /// Whether to reboot after queueing a rollback
+    pub(crate) reboot: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            units: Vec::new(),
+            window: DEFAULT_WINDOW,
+            reboot: false,
+        }
+    }
+}
+
+fn parse_config(buf: &str) -> Result<HealthConfig> {
+    let c: HealthConfigFile = crate::hostconfig::parse_section(buf, CONFIG_NAME)?;
+    let mut r = HealthConfig::default();
+    if let Some(v) = c.units {
+        r.units = v;
+    }
+    if let Some(v) = c.window.as_deref() {
+        r.window = bootc_utils::parse_duration(v)?
+            .to_std()
+            .with_context(|| format!("Invalid duration: {v}"))?;
+    }
+    if let Some(v) = c.reboot {
+        r.reboot = v;
+    }
+    Ok(r)
+}
+
+/// Load the health check configuration from the target root.
+#[context("Loading health check configuration")]
+pub(crate) fn load_config(root: &Dir) -> Result<HealthConfig> {
+    crate::hostconfig::load(root, CONFIG_NAME, parse_config)
+}
+
+/// The state of a unit, as shown by `systemctl show`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UnitState {
+    id: String,
+    active_state: String,
+    result: String,
+}
+
+/// Parse the output of `systemctl show --property=Id,ActiveState,Result`,
+/// which has a block of properties per unit separated by empty lines.
+fn parse_unit_states(buf: &str) -> Vec<UnitState> {
+    let mut r = Vec::new();
+    for block in buf.split("\n\n").filter(|b| !b.trim().is_empty()) {
+        let mut state = UnitState::default();
+        for (k, v) in block.lines().filter_map(|l| l.split_once('=')) {
+            match k {
+                "Id" => state.id = v.to_owned(),
+                "ActiveState" => state.active_state = v.to_owned(),
+                "Result" => state.result = v.to_owned(),
+                _ => {}
+            }
+        }
+        r.push(state);
+    }
+    r
+}
+
+/// Describe the first failed unit, if any.
+fn find_failure(states: &[UnitState]) -> Option<String> {
+    states
+        .iter()
+        .find(|s| s.active_state == "failed")
+        .map(|s| format!("{} failed (result: {})", s.id, s.result))
+}
+
+fn query_units(units: &[String]) -> Result<Vec<UnitState>> {
+    let buf = Command::new("systemctl")
+        .args(["show", "--property=Id,ActiveState,Result", "--"])
+        .args(units)
+        .run_get_string()?;
+    Ok(parse_unit_states(&buf))
+}
+
+/// Whether the booted deployment was booted for the first time after `boot_time`,
+/// i.e. this is the first boot after an upgrade, and it can be rolled back.
+fn is_first_boot(host: &Host, boot_time: DateTime<Utc>) -> bool {
+    if host.spec.boot_order != BootOrder::Default || host.status.rollback.is_none() {
+        return false;
+    }
+    host.status
+        .booted
+        .as_ref()
+        .and_then(|b| {
+            b.lifecycle
+                .iter()
+                .find(|t| t.state == DeploymentState::Booted)
+        })
+        .is_some_and(|t| t.timestamp >= boot_time)
+}
+
+/// Implementation of `bootc rollback --auto-on-failure`.
+#[context("Monitoring units for automatic rollback")]
+pub(crate) async fn auto_on_failure(
+    sysroot: &Storage,
+    apply: bool,
+    soft: Option<crate::reboot::SoftRebootMode>,
+) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let config = load_config(root)?;
+    if config.units.is_empty() {
+        println!("No units to monitor");
+        return Ok(());
+    }
+    let since_boot = crate::utils::uptime()?;
+    let boot_time = Utc::now() - chrono::Duration::from_std(since_boot)?;
+    let (booted, _, mut host) = crate::status::get_status_require_booted(sysroot)?;
+    crate::lifecycle::apply_to_host(sysroot, &mut host)?;
+    if !is_first_boot(&host, boot_time) {
+        println!("Not the first boot of an upgrade; not monitoring units");
+        return Ok(());
+    }
+
+    let Some(mut remaining) = config.window.checked_sub(since_boot) else {
+        println!("Monitoring window has already elapsed");
+        return Ok(());
+    };
+    tracing::debug!("Monitoring {:?} for {remaining:?}", config.units);
+    let failure = loop {
+        if let Some(failure) = find_failure(&query_units(&config.units)?) {
+            break Some(failure);
+        }
+        if remaining.is_zero() {
+            break None;
+        }
+        let delay = POLL_INTERVAL.min(remaining);
+        tokio::time::sleep(delay).await;
+        remaining -= delay;
+    };
+    let Some(failure) = failure else {
+        println!("All monitored units are healthy");
+        return Ok(());
+    };
+
+    let msg = format!("Rolling back: {failure}");
+    crate::journal::journal_send(
+        libsystemd::logging::Priority::Warning,
+        &msg,
+        [
+            ("MESSAGE_ID", crate::journal::AUTO_ROLLBACK_ID),
+            ("BOOTC_HEALTH_FAILURE", failure.as_str()),
+        ]
+        .into_iter(),
+    );
+    println!("{msg}");
+    crate::deploy::rollback(sysroot).await?;
+    crate::lifecycle::record_reason(sysroot, &booted, &failure)?;
+    if apply || config.reboot {
+        crate::reboot::reboot_into_default(sysroot, soft)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        assert_eq!(parse_config("")?, HealthConfig::default());
+        let c = parse_config(indoc::indoc! { r#"
+            [health]
+            units = ["httpd.service", "app.service"]
+            window = "10m"
+            reboot = true
+        "# })?;
+        assert_eq!(
+            c,
+            HealthConfig {
+                units: vec!["httpd.service".into(), "app.service".into()],
+                window: Duration::from_secs(600),
+                reboot: true,
+            }
+        );
+        assert!(parse_config("[health]\nwindow = \"soon\"").is_err());
+        assert!(parse_config("[health]\nfoo = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unit_states() {
+        let buf = indoc::indoc! { "
+            Id=httpd.service
+            ActiveState=active
+            Result=success
+
+            Id=app.service
+            ActiveState=failed
+            Result=exit-code
+        " };
+        let states = parse_unit_states(buf);
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].active_state, "active");
+        assert_eq!(
+            find_failure(&states).as_deref(),
+            Some("app.service failed (result: exit-code)")
+        );
+        assert_eq!(find_failure(&states[..1]), None);
+    }
+
+    #[test]
+    fn test_is_first_boot() {
+        use crate::spec::LifecycleTransition;
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.rollback = host.status.booted.clone();
+        let t = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+        let boot_time = t("2025-03-01T12:00:00Z");
+        assert!(!is_first_boot(&host, boot_time));
+        host.status.booted.as_mut().unwrap().lifecycle = vec![
+            LifecycleTransition {
+                state: DeploymentState::Staged,
+                timestamp: t("2025-03-01T11:00:00Z"),
+                downgrade: None,
+                reason: None,
+            },
+            LifecycleTransition {
+                state: DeploymentState::Booted,
+                timestamp: t("2025-03-01T12:00:30Z"),
+                downgrade: None,
+                reason: None,
+            },
+        ];
+        assert!(is_first_boot(&host, boot_time));
+        // A later boot of the same deployment
+        assert!(!is_first_boot(&host, t("2025-03-02T12:00:00Z")));
+        // A rollback is already queued
+        host.spec.boot_order = BootOrder::Rollback;
+        assert!(!is_first_boot(&host, boot_time));
+        // Nothing to roll back to
+        host.spec.boot_order = BootOrder::Default;
+        host.status.rollback = None;
+        assert!(!is_first_boot(&host, boot_time));
+    }
+}
@@ -0,0 +1,310 @@
+//! # Update history
+//!
+//! Every upgrade, switch and rollback is recorded in `/ostree/bootc/history.json`
+//! along with the image digests before and after, who initiated it, and whether
+//! it succeeded; `bootc history` shows the recorded operations. Only the most
+//! recent entries are kept.
+//!
+//! Successful operations are recorded when the status change is signaled (see
+//! [`crate::store::Storage::status_changed`]), before any reboot. Failures are
+//! recorded by the CLI once the operation returns.
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use comfy_table::{presets::NOTHING, Table};
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::ostree::{self, gio};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::ImageListFormat;
+use crate::events::StatusChangeReason;
+
+/// The history file, relative to the physical root.
+const HISTORY_PATH: &str = "ostree/bootc/history.json";
+/// How many entries to keep.
+const MAX_ENTRIES: usize = 100;
+/// How many characters of a digest to show in the table output
+const SHORT_DIGEST_LEN: usize = 12;
+
+/// An operation which changes the deployment used for the next boot.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Operation {
+    Upgrade,
+    Switch,
+    Rollback,
+}
+
+impl Operation {
+    fn from_reason(reason: StatusChangeReason) -> Option<Self> {
+        match reason {
+            StatusChangeReason::Upgrade => Some(Self::Upgrade),
+            StatusChangeReason::Switch => Some(Self::Switch),
+            StatusChangeReason::Rollback => Some(Self::Rollback),
+            StatusChangeReason::Edit | StatusChangeReason::Kargs => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Upgrade => "upgrade",
+            Self::Switch => "switch",
+            Self::Rollback => "rollback",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A recorded operation.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HistoryEntry {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) operation: Operation,
+    /// The digest of the booted image
+    pub(crate) from: Option<String>,
+    /// The digest of the image used for the next boot after the operation
+    pub(crate) to: Option<String>,
+    /// The user or systemd unit which ran the operation
+    pub(crate) initiator: String,
+    pub(crate) success: bool,
+    /// The error, for failed operations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct History {
+    entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    /// Add an entry, dropping the oldest ones beyond the limit.
+    fn push(&mut self, entry: HistoryEntry) {
+        self.entries.push(entry);
+        let excess = self.entries.len().saturating_sub(MAX_ENTRIES);
+        self.entries.drain(..excess);
+    }
+}
+
+fn load(root: &Dir) -> Result<History> {
+    let Some(f) = root.open_optional(HISTORY_PATH)? else {
+        return Ok(History::default());
+    };
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {HISTORY_PATH}"))
+}
+
+fn store(root: &Dir, history: &History) -> Result<()> {
+    root.create_dir_all(crate::store::BOOTC_ROOT)?;
+    root.atomic_write(HISTORY_PATH, serde_json::to_vec(history)?)
+        .with_context(|| format!("Writing {HISTORY_PATH}"))
+}
+
+fn append(sysroot: &ostree::Sysroot, entry: HistoryEntry) -> Result<()> {
+    let root = &crate::utils::sysroot_dir(sysroot)?;
+    let mut history = load(root)?;
+    history.push(entry);
+    store(root, &history)
+}
+
+/// Find the systemd unit in the contents of `/proc/self/cgroup`.
+fn unit_from_cgroup(cgroup: &str) -> Option<&str> {
+    cgroup
+        .lines()
+        .filter_map(|l| l.rsplit_once(':').map(|(_, path)| path))
+        .flat_map(|path| path.rsplit('/'))
+        .find(|c| c.ends_with(".service"))
+}
+
+/// Describe who is running this process: the systemd unit when run by systemd,
+/// otherwise the (possibly `sudo`-ing) user.
+fn initiator() -> String {
+    if std::env::var_os("INVOCATION_ID").is_some() {
+        let cgroup = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+        if let Some(unit) = unit_from_cgroup(&cgroup) {
+            return format!("unit:{unit}");
+        }
+    }
+    ["SUDO_USER", "USER"]
+        .into_iter()
+        .find_map(|k| std::env::var(k).ok().filter(|v| !v.is_empty()))
+        .map(|u| format!("user:{u}"))
+        .unwrap_or_else(|| format!("uid:{}", rustix::process::getuid().as_raw()))
+}
+
+/// The image digest of a deployment, if it is container based.
+fn deployment_digest(repo: &ostree::Repo, deployment: &ostree::Deployment) -> Option<String> {
+    ostree_container::store::query_image_commit(repo, &deployment.csum())
+        .ok()
+        .map(|s| s.manifest_digest.to_string())
+}
+
+/// The digests of the booted image and the image for the next boot.
+fn current_digests(sysroot: &ostree::Sysroot) -> (Option<String>, Option<String>) {
+    let repo = &sysroot.repo();
+    let booted = sysroot
+        .booted_deployment()
+        .and_then(|d| deployment_digest(repo, &d));
+    let next = sysroot
+        .deployments()
+        .first()
+        .and_then(|d| deployment_digest(repo, d));
+    (booted, next)
+}
+
+/// Record a successful operation; called when the status changes.
+#[context("Recording update history")]
+pub(crate) fn record_change(sysroot: &ostree::Sysroot, reason: StatusChangeReason) -> Result<()> {
+    let Some(operation) = Operation::from_reason(reason) else {
+        return Ok(());
+    };
+    // Pick up new deployments or reordering
+    sysroot.load(gio::Cancellable::NONE)?;
+    let (from, to) = current_digests(sysroot);
+    let entry = HistoryEntry {
+        timestamp: Utc::now(),
+        operation,
+        from,
+        to,
+        initiator: initiator(),
+        success: true,
+        error: None,
+    };
+    append(sysroot, entry)
+}
+
+/// Record a failed operation. This is best-effort, and does not wait for the
+/// sysroot lock.
+pub(crate) fn record_failure(operation: Operation, err: &anyhow::Error) {
+    let r = (|| -> Result<()> {
+        if !rustix::process::getuid().is_root() || !ostree_ext::container_utils::ostree_booted()? {
+            return Ok(());
+        }
+        let sysroot = ostree::Sysroot::new_default();
+        sysroot.load(gio::Cancellable::NONE)?;
+        let (from, _) = current_digests(&sysroot);
+        let entry = HistoryEntry {
+            timestamp: Utc::now(),
+            operation,
+            from,
+            to: None,
+            initiator: initiator(),
+            success: false,
+            error: Some(format!("{err:#}")),
+        };
+        append(&sysroot, entry)
+    })();
+    if let Err(e) = r {
+        tracing::warn!("Failed to record update history: {e:#}");
+    }
+}
+
+fn short_digest(digest: Option<&str>) -> String {
+    let Some(digest) = digest else {
+        return "-".to_owned();
+    };
+    let hex = digest.split_once(':').map_or(digest, |(_, h)| h);
+    hex.chars().take(SHORT_DIGEST_LEN).collect()
+}
+
+/// Implementation of `bootc history`.
+#[context("Showing update history")]
+pub(crate) async fn show(format: ImageListFormat, limit: Option<usize>) -> Result<()> {
+    let sysroot = crate::cli::get_storage_readonly().await?;
+    let mut history = load(&crate::utils::sysroot_dir(&sysroot)?)?;
+    if let Some(limit) = limit {
+        let excess = history.entries.len().saturating_sub(limit);
+        history.entries.drain(..excess);
+    }
+    match format {
+        ImageListFormat::Table => {
+            let mut table = Table::new();
+            table.load_preset(NOTHING).set_header([
+                "TIME",
+                "OPERATION",
+                "RESULT",
+                "FROM",
+                "TO",
+                "INITIATOR",
+            ]);
+            for e in history.entries.iter() {
+                table.add_row([
+                    e.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    e.operation.to_string(),
+                    if e.success { "success" } else { "failed" }.to_owned(),
+                    short_digest(e.from.as_deref()),
+                    short_digest(e.to.as_deref()),
+                    e.initiator.clone(),
+                ]);
+            }
+            println!("{table}");
+        }
+        ImageListFormat::Json => {
+            let mut stdout = std::io::stdout();
+            serde_json::to_writer_pretty(&mut stdout, &history.entries)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    fn entry(n: i64) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: DateTime::from_timestamp(n, 0).unwrap(),
+            operation: Operation::Upgrade,
+            from: Some("sha256:aaaa".into()),
+            to: Some("sha256:bbbb".into()),
+            initiator: "user:root".into(),
+            success: true,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let mut history = History::default();
+        for n in 0..(MAX_ENTRIES as i64 + 5) {
+            history.push(entry(n));
+        }
+        assert_eq!(history.entries.len(), MAX_ENTRIES);
+        assert_eq!(history.entries[0], entry(5));
+
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load(td)?, History::default());
+        store(td, &history)?;
+        assert_eq!(load(td)?, history);
+
+        let v = serde_json::to_value(entry(0))?;
+        assert_eq!(v["operation"], "upgrade");
+        assert!(v.get("error").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unit_from_cgroup() {
+        assert_eq!(
+            unit_from_cgroup("0::/system.slice/bootc-fetch-apply-updates.service\n"),
+            Some("bootc-fetch-apply-updates.service")
+        );
+        assert_eq!(
+            unit_from_cgroup("0::/user.slice/user-0.slice/session-1.scope\n"),
+            None
+        );
+        assert_eq!(
+            short_digest(Some("sha256:0123456789abcdef")),
+            "0123456789ab"
+        );
+        assert_eq!(short_digest(None), "-");
+    }
+}
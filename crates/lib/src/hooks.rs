@@ -0,0 +1,266 @@
+//! # Upgrade hooks
+//!
+//! Executables in `/usr/lib/bootc/hooks.d/` (or `/etc/bootc/hooks.d/`, where
+//! a file of the same name takes precedence, and a symlink to `/dev/null`
+//! disables it) are run at fixed points of an upgrade, for example to re-seal
+//! disk encryption keys with `systemd-cryptenroll` when the kernel changes.
+//!
+//! Each hook is invoked with the phase as its single argument, metadata about
+//! the new deployment as JSON on stdin, and the same metadata in `BOOTC_*`
+//! environment variables. Hooks must ignore phases they do not know about.
+//!
+//! - `post-stage`: after a deployment was staged by `bootc upgrade` or `bootc switch`.
+//!   If a hook fails, the staged deployment is removed.
+//! - `pre-finalize`: at shutdown, before the staged deployment is finalized.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::ostree;
+use serde::Serialize;
+
+use crate::store::Storage;
+
+/// Hook directories, in order of precedence
+const HOOK_DIRS: &[&str] = &["etc/bootc/hooks.d", "usr/lib/bootc/hooks.d"];
+
+/// A point in the upgrade process at which hooks are run.
+#[derive(clap::ValueEnum, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum HookPhase {
+    /// A deployment was staged
+    PostStage,
+    /// The staged deployment is about to be finalized at shutdown
+    PreFinalize,
+}
+
+impl std::fmt::Display for HookPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::PostStage => "post-stage",
+            Self::PreFinalize => "pre-finalize",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Metadata about the new deployment passed to hooks.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct HookMetadata {
+    phase: HookPhase,
+    stateroot: String,
+    /// The deployment checksum and serial
+    deployment: String,
+    /// The absolute path to the deployment root
+    deployment_path: String,
+    image: Option<String>,
+    image_digest: Option<String>,
+    /// Whether the kernel or initramfs differ from the booted deployment
+    boot_changed: bool,
+}
+
+impl HookMetadata {
+    fn new(sysroot: &Storage, phase: HookPhase, deployment: &ostree::Deployment) -> Result<Self> {
+        let image = deployment
+            .origin()
+            .map(|o| o.optional_string("origin", ostree_container::deploy::ORIGIN_CONTAINER))
+            .transpose()?
+            .flatten()
+            .map(|s| s.to_string());
+        let image_digest = image
+            .as_ref()
+            .map(|_| {
+                ostree_container::store::query_image_commit(&sysroot.repo(), &deployment.csum())
+            })
+            .transpose()?
+            .map(|s| s.manifest_digest.to_string());
+        let boot_changed = sysroot
+            .booted_deployment()
+            .map_or(true, |b| b.bootcsum() != deployment.bootcsum());
+        Ok(Self {
+            phase,
+            stateroot: deployment.osname().to_string(),
+            deployment: format!("{}.{}", deployment.csum(), deployment.deployserial()),
+            deployment_path: format!("/sysroot/{}", sysroot.deployment_dirpath(deployment)),
+            image,
+            image_digest,
+            boot_changed,
+        })
+    }
+
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        let mut r = vec![
+            ("BOOTC_HOOK_PHASE", self.phase.to_string()),
+            ("BOOTC_STATEROOT", self.stateroot.clone()),
+            ("BOOTC_DEPLOYMENT", self.deployment.clone()),
+            ("BOOTC_DEPLOYMENT_PATH", self.deployment_path.clone()),
+            (
+                "BOOTC_BOOT_CHANGED",
+                u8::from(self.boot_changed).to_string(),
+            ),
+        ];
+        if let Some(image) = self.image.as_ref() {
+            r.push(("BOOTC_IMAGE", image.clone()));
+        }
+        if let Some(digest) = self.image_digest.as_ref() {
+            r.push(("BOOTC_IMAGE_DIGEST", digest.clone()));
+        }
+        r
+    }
+}
+
+/// Find the hooks in the target root, sorted by name, as absolute paths.
+fn find_hooks(root: &Dir) -> Result<Vec<String>> {
+    find_executables(root, HOOK_DIRS)
+}
+
+/// Find the executables in `dirs` (in order of precedence) of the target root,
+/// sorted by name, as absolute paths.
+pub(crate) fn find_executables(root: &Dir, dirs: &[&str]) -> Result<Vec<String>> {
+    let mut hooks = std::collections::BTreeMap::new();
+    for dir in dirs {
+        let Some(d) = root.open_dir_optional(dir)? else {
+            continue;
+        };
+        for ent in d.entries()? {
+            let ent = ent?;
+            let name = ent.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.starts_with('.') || hooks.contains_key(name) {
+                continue;
+            }
+            let meta = d.symlink_metadata(name)?;
+            if meta.is_symlink() {
+                // Like systemd, a symlink to /dev/null masks a hook of the same name
+                let masked = d.read_link_contents(name)?.as_os_str() == "/dev/null";
+                hooks.insert(name.to_owned(), (!masked).then(|| format!("/{dir}/{name}")));
+                continue;
+            }
+            if !meta.is_file() || meta.mode() & 0o111 == 0 {
+                tracing::debug!("Ignoring non-executable hook {dir}/{name}");
+                continue;
+            }
+            hooks.insert(name.to_owned(), Some(format!("/{dir}/{name}")));
+        }
+    }
+    Ok(hooks.into_values().flatten().collect())
+}
+
+fn run_hook(path: &str, metadata: &HookMetadata) -> Result<()> {
+    tracing::debug!("Running hook {path}");
+    let mut child = Command::new(path)
+        .arg(metadata.phase.to_string())
+        .envs(metadata.environment())
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Spawning {path}"))?;
+    let buf = serde_json::to_vec(metadata)?;
+    let mut stdin = child.stdin.take().unwrap();
+    match stdin.write_all(&buf) {
+        // The hook may not read its input
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        r => r?,
+    }
+    drop(stdin);
+    let status = child.wait()?;
+    anyhow::ensure!(status.success(), "Hook {path} failed: {status}");
+    Ok(())
+}
+
+/// Run the hooks for the given phase and deployment. All hooks are run,
+/// even if one fails.
+#[context("Running {phase} hooks")]
+pub(crate) fn run(
+    sysroot: &Storage,
+    phase: HookPhase,
+    deployment: &ostree::Deployment,
+) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    let hooks = find_hooks(root)?;
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let metadata = HookMetadata::new(sysroot, phase, deployment)?;
+    let mut failures = Vec::new();
+    for hook in hooks.iter() {
+        if let Err(e) = run_hook(hook, &metadata) {
+            tracing::error!("{e:#}");
+            failures.push(format!("{e:#}"));
+        }
+    }
+    anyhow::ensure!(failures.is_empty(), "{}", failures.join("\n"));
+    Ok(())
+}
+
+/// Implementation of `bootc internals run-hooks`, for phases which are
+/// triggered outside of bootc (i.e. from systemd units).
+pub(crate) async fn run_for_staged(phase: HookPhase) -> Result<()> {
+    let sysroot = &crate::cli::get_storage().await?;
+    let Some(staged) = sysroot.staged_deployment() else {
+        tracing::debug!("No staged deployment");
+        return Ok(());
+    };
+    run(sysroot, phase, &staged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std::fs::PermissionsExt;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn test_find_hooks() -> Result<()> {
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(find_hooks(td)?.is_empty());
+        td.create_dir_all("usr/lib/bootc/hooks.d")?;
+        td.create_dir_all("etc/bootc/hooks.d")?;
+        let exec = cap_std::fs::Permissions::from_mode(0o755);
+        for name in ["50-reseal", "10-first", "60-masked", ".hidden"] {
+            let path = format!("usr/lib/bootc/hooks.d/{name}");
+            td.write(&path, "#!/bin/sh\n")?;
+            td.set_permissions(&path, exec.clone())?;
+        }
+        td.write("usr/lib/bootc/hooks.d/README", "not a hook")?;
+        td.write("etc/bootc/hooks.d/50-reseal", "#!/bin/sh\n")?;
+        td.set_permissions("etc/bootc/hooks.d/50-reseal", exec.clone())?;
+        td.symlink_contents("/dev/null", "etc/bootc/hooks.d/60-masked")?;
+        assert_eq!(
+            find_hooks(td)?,
+            [
+                "/usr/lib/bootc/hooks.d/10-first",
+                "/etc/bootc/hooks.d/50-reseal"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment() {
+        let m = HookMetadata {
+            phase: HookPhase::PostStage,
+            stateroot: "default".into(),
+            deployment: "abcd.0".into(),
+            deployment_path: "/sysroot/ostree/deploy/default/deploy/abcd.0".into(),
+            image: None,
+            image_digest: None,
+            boot_changed: true,
+        };
+        let env = m.environment();
+        assert!(env.contains(&("BOOTC_HOOK_PHASE", "post-stage".into())));
+        assert!(env.contains(&("BOOTC_BOOT_CHANGED", "1".into())));
+        assert!(!env.iter().any(|(k, _)| *k == "BOOTC_IMAGE"));
+        let v = serde_json::to_value(&m).unwrap();
+        assert_eq!(v["phase"], "post-stage");
+        assert_eq!(v["bootChanged"], true);
+    }
+}
@@ -0,0 +1,89 @@
+//! # Host configuration files
+//!
+//! Features such as health checks or pull retries are configured by a TOML
+//! file `<name>.toml` holding a single `[<name>]` section. An administrator
+//! can provide it in `/etc/bootc`, overriding the default which may be shipped
+//! by the image in `/usr/lib/bootc`.
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use serde::de::DeserializeOwned;
+
+/// Configuration directories, in order of precedence
+const CONFIG_DIRS: &[&str] = &["etc/bootc", "usr/lib/bootc"];
+
+/// Parse the `[name]` section of a configuration file, which must not have
+/// any other toplevel keys. A missing section yields the default.
+pub(crate) fn parse_section<T: DeserializeOwned + Default>(buf: &str, name: &str) -> Result<T> {
+    let mut c: toml::Table = toml::from_str(buf)?;
+    let section = c.remove(name);
+    if let Some(k) = c.keys().next() {
+        anyhow::bail!("unknown field `{k}`, expected `{name}`");
+    }
+    match section {
+        Some(v) => Ok(v.try_into()?),
+        None => Ok(T::default()),
+    }
+}
+
+/// Find the first `<name>.toml` in the configuration directories of `root`
+/// and parse it with `parse`, or return the default if there is none.
+pub(crate) fn load<T: Default>(
+    root: &Dir,
+    name: &str,
+    parse: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    for dir in CONFIG_DIRS {
+        let path = format!("{dir}/{name}.toml");
+        let Some(f) = root.open_optional(&path)? else {
+            continue;
+        };
+        let buf = std::io::read_to_string(f)?;
+        return parse(&buf).with_context(|| format!("Parsing {path}"));
+    }
+    Ok(T::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, Default, PartialEq, Eq)]
+    #[serde(deny_unknown_fields)]
+    struct Example {
+        value: Option<u32>,
+    }
+
+    fn parse(buf: &str) -> Result<Example> {
+        parse_section(buf, "example")
+    }
+
+    #[test]
+    fn test_parse_section() -> Result<()> {
+        assert_eq!(parse("")?, Example::default());
+        assert_eq!(parse("[example]\nvalue = 1\n")?.value, Some(1));
+        assert!(parse("[example]\nfoo = 1\n").is_err());
+        assert!(parse("[other]\nvalue = 1\n").is_err());
+        assert!(parse("value = 1\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load(&td, "example", parse)?, Example::default());
+        td.create_dir_all("usr/lib/bootc")?;
+        td.write("usr/lib/bootc/example.toml", "[example]\nvalue = 1\n")?;
+        assert_eq!(load(&td, "example", parse)?.value, Some(1));
+        td.create_dir_all("etc/bootc")?;
+        td.write("etc/bootc/example.toml", "[example]\nvalue = 2\n")?;
+        assert_eq!(load(&td, "example", parse)?.value, Some(2));
+        td.write("etc/bootc/example.toml", "[example]\nvalue = \"x\"\n")?;
+        let e = load(&td, "example", parse).unwrap_err();
+        assert!(format!("{e:#}").contains("etc/bootc/example.toml"), "{e:#}");
+        Ok(())
+    }
+}
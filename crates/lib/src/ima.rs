@@ -0,0 +1,79 @@
+//! # IMA signing of deployments
+//!
+//! `bootc internals ima-sign` adds IMA signatures (`security.ima`) to every
+//! regular file of the deployment which will be booted next, by rewriting its
+//! ostree commit with [`ostree_ext::ima::ima_sign`] and staging the result with
+//! the same origin and kernel arguments. This allows booting with IMA appraisal
+//! enforced.
+//!
+//! With composefs, the image mounted at boot is generated from the commit,
+//! including its extended attributes; the signed commit is checked to have no
+//! unsigned files before it is staged.
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use fn_error_context::context;
+use ostree_ext::ostree;
+
+use crate::store::Storage;
+
+/// How many unsigned files to report
+const MAX_UNSIGNED_REPORTED: usize = 5;
+
+/// Sign the staged deployment (or the booted one, if none is staged) with an IMA
+/// key, and stage the signed commit.
+#[context("Signing deployment with IMA")]
+pub(crate) async fn sign_deployment(
+    sysroot: &Storage,
+    key: Utf8PathBuf,
+    algorithm: String,
+    overwrite: bool,
+) -> Result<()> {
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let booted = sysroot.require_booted_deployment()?;
+    let base = sysroot
+        .staged_deployment()
+        .unwrap_or_else(|| booted.clone());
+    let repo = &sysroot.repo();
+    let opts = ostree_ext::ima::ImaOpts {
+        algorithm,
+        key,
+        overwrite,
+    };
+
+    let csum = base.csum();
+    let tx = repo.auto_transaction(cancellable)?;
+    let signed = ostree_ext::ima::ima_sign(repo, &csum, &opts)?;
+    tx.commit(cancellable)?;
+    if signed == csum.as_str() {
+        println!("Deployment {csum} is already signed");
+        return Ok(());
+    }
+    let unsigned = ostree_ext::ima::find_unsigned(repo, &signed, MAX_UNSIGNED_REPORTED)?;
+    if !unsigned.is_empty() {
+        anyhow::bail!(
+            "Signed commit {signed} has files without an IMA signature: {}",
+            unsigned.join(", ")
+        );
+    }
+
+    let origin = base
+        .origin()
+        .ok_or_else(|| anyhow::anyhow!("Deployment {csum} is missing an origin"))?;
+    let kargs = base
+        .bootconfig()
+        .and_then(|b| b.get("options"))
+        .map(|s| s.split_ascii_whitespace().map(ToOwned::to_owned).collect())
+        .context("Deployment is missing kernel arguments")?;
+    crate::deploy::deploy_commit(
+        sysroot,
+        Some(&booted),
+        &base.osname(),
+        &signed,
+        &origin,
+        Some(kargs),
+    )
+    .await?;
+    println!("Staged IMA signed commit {signed} (from {csum})");
+    Ok(())
+}
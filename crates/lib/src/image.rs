@@ -2,24 +2,33 @@
 //!
 //! APIs for operating on container images in the bootc storage.
 
-use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, bail, Context, Result};
 use bootc_utils::CommandRunExt;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::{self, fs::Dir};
 use clap::ValueEnum;
 use comfy_table::{presets::NOTHING, Table};
 use fn_error_context::context;
-use ostree_ext::container::{ImageReference, Transport};
+use ostree_ext::container::{self as ostree_container, ImageReference, Transport};
+use ostree_ext::ostree::{self, gio};
 use serde::Serialize;
 
 use crate::{
     boundimage::query_bound_images,
     cli::{ImageListFormat, ImageListType},
     imgstorage::ensure_floating_c_storage_initialized,
+    task::Task,
 };
 
 /// The name of the image we push to containers-storage if nothing is specified.
 const IMAGE_DEFAULT: &str = "localhost/bootc";
 
+/// Images from the ostree store which are not deployed are checked out here
+/// by `bootc internals mount-image`.
+const MOUNT_CHECKOUTS: &str = "/var/tmp/bootc-mount-image";
+
 #[derive(Clone, Serialize, ValueEnum)]
 enum ImageListTypeColumn {
     Host,
@@ -124,6 +133,45 @@ pub(crate) async fn list_entrypoint(
     Ok(())
 }
 
+/// Implementation of `bootc image list-bound`.
+#[context("Listing bound images")]
+pub(crate) async fn list_bound_entrypoint(list_format: ImageListFormat) -> Result<()> {
+    let sysroot = crate::cli::get_storage().await?;
+    let images = crate::boundimage::list_bound_images(&sysroot).await?;
+
+    match list_format {
+        ImageListFormat::Table => {
+            let mut table = Table::new();
+
+            table
+                .load_preset(NOTHING)
+                .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+                .set_header(["REPOSITORY", "DEPLOYMENTS", "PRESENT", "DIGEST", "SIZE"]);
+
+            for image in images {
+                table.add_row([
+                    image.image,
+                    image.deployments.join(","),
+                    if image.present { "yes" } else { "no" }.to_owned(),
+                    image.digest.unwrap_or_default(),
+                    image
+                        .size
+                        .map(|s| indicatif::HumanBytes(s).to_string())
+                        .unwrap_or_default(),
+                ]);
+            }
+
+            println!("{table}");
+        }
+        ImageListFormat::Json => {
+            let mut stdout = std::io::stdout();
+            serde_json::to_writer_pretty(&mut stdout, &images)?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Implementation of `bootc image push-to-storage`.
 #[context("Pushing image")]
 pub(crate) async fn push_entrypoint(source: Option<&str>, target: Option<&str>) -> Result<()> {
@@ -168,6 +216,138 @@ pub(crate) async fn push_entrypoint(source: Option<&str>, target: Option<&str>)
     Ok(())
 }
 
+/// The destination for `bootc image copy-to-storage --all`.
+#[derive(Debug, PartialEq, Eq)]
+enum CopyAllTarget {
+    /// The default `containers-storage:`
+    ContainerStorage,
+    /// A directory, which will hold an `oci-archive:` per image
+    OciArchiveDir(Utf8PathBuf),
+}
+
+impl CopyAllTarget {
+    fn parse(target: Option<&str>) -> Result<Self> {
+        match target {
+            None | Some("containers-storage") | Some("containers-storage:") => {
+                Ok(Self::ContainerStorage)
+            }
+            Some(t) => match t.strip_prefix("oci-archive:") {
+                Some(dir) if !dir.is_empty() => Ok(Self::OciArchiveDir(dir.into())),
+                _ => bail!("Unsupported target for --all: {t} (expected containers-storage or oci-archive:<directory>)"),
+            },
+        }
+    }
+}
+
+/// The file name used for an image when copying to an archive directory.
+fn archive_name(image: &str) -> String {
+    let name = image.replace(['/', ':', '@'], "_");
+    format!("{name}.tar")
+}
+
+/// Gather the host images of all deployments (booted, staged and rollback), and the
+/// logically bound images they reference.
+fn images_for_copy_all(
+    sysroot: &crate::store::Storage,
+) -> Result<(Vec<ImageReference>, BTreeSet<String>)> {
+    let (_, host) = crate::status::get_status(sysroot, sysroot.booted_deployment().as_ref())?;
+    let mut host_images = Vec::new();
+    for entry in [
+        host.status.booted.as_ref(),
+        host.status.staged.as_ref(),
+        host.status.rollback.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        let Some(image) = entry.image.as_ref() else {
+            continue;
+        };
+        let imgref = ImageReference {
+            transport: Transport::try_from(image.image.transport.as_str())?,
+            name: image.image.image.clone(),
+        };
+        if !host_images.contains(&imgref) {
+            host_images.push(imgref);
+        }
+    }
+    let mut logical = BTreeSet::new();
+    for deployment in sysroot.deployments() {
+        let bound = crate::boundimage::query_bound_images_for_deployment(sysroot, &deployment)?;
+        logical.extend(bound.into_iter().map(|b| b.image));
+    }
+    Ok((host_images, logical))
+}
+
+/// Implementation of `bootc image copy-to-storage --all`.
+#[context("Copying all images")]
+pub(crate) async fn push_all_entrypoint(target: Option<&str>) -> Result<()> {
+    let target = CopyAllTarget::parse(target)?;
+    let sysroot = crate::cli::get_storage().await?;
+    let repo = &sysroot.repo();
+    let (host_images, logical) = images_for_copy_all(&sysroot)?;
+    match &target {
+        CopyAllTarget::ContainerStorage => ensure_floating_c_storage_initialized(),
+        CopyAllTarget::OciArchiveDir(dir) => {
+            std::fs::create_dir_all(dir).with_context(|| format!("Creating {dir}"))?
+        }
+    }
+
+    for source in host_images.iter() {
+        let dest = match &target {
+            CopyAllTarget::ContainerStorage => ImageReference {
+                transport: Transport::ContainerStorage,
+                name: source.name.clone(),
+            },
+            CopyAllTarget::OciArchiveDir(dir) => ImageReference {
+                transport: Transport::OciArchive,
+                name: dir.join(archive_name(&source.name)).into_string(),
+            },
+        };
+        let mut opts = ostree_ext::container::store::ExportToOCIOpts::default();
+        opts.progress_to_stdout = true;
+        println!("Copying host image {source} to {dest} ...");
+        let r = ostree_ext::container::store::export(repo, source, &dest, Some(opts)).await?;
+        println!("Pushed: {dest} {r}");
+    }
+
+    if !logical.is_empty() {
+        let imgstore = sysroot.get_ensure_imgstore()?;
+        for image in logical.iter() {
+            let dest = match &target {
+                CopyAllTarget::ContainerStorage => None,
+                CopyAllTarget::OciArchiveDir(dir) => {
+                    Some(format!("oci-archive:{}", dir.join(archive_name(image))))
+                }
+            };
+            println!("Copying logically bound image {image} ...");
+            imgstore.push_to(image, dest.as_deref()).await?;
+        }
+    }
+
+    println!(
+        "Copied {} host images and {} logically bound images",
+        host_images.len(),
+        logical.len()
+    );
+    Ok(())
+}
+
+/// Implementation of `bootc image pull-from-default-storage --all`: copy the logically
+/// bound images of all deployments from the default container storage.
+#[context("Pulling all bound images from default storage")]
+pub(crate) async fn pull_all_from_host_storage() -> Result<()> {
+    let sysroot = crate::cli::get_storage().await?;
+    let (_, logical) = images_for_copy_all(&sysroot)?;
+    let imgstore = sysroot.get_ensure_imgstore()?;
+    for image in logical.iter() {
+        println!("Copying {image} ...");
+        imgstore.pull_from_host_storage(image).await?;
+    }
+    println!("Copied {} logically bound images", logical.len());
+    Ok(())
+}
+
 /// Thin wrapper for invoking `podman image <X>` but set up for our internal
 /// image store (as distinct from /var/lib/containers default).
 pub(crate) async fn imgcmd_entrypoint(
@@ -180,3 +360,130 @@ pub(crate) async fn imgcmd_entrypoint(
     cmd.args(args);
     cmd.run_capture_stderr()
 }
+
+/// Find the deployment for a slot name: `booted`, `staged` or `rollback`.
+fn deployment_for_slot(sysroot: &ostree::Sysroot, name: &str) -> Option<ostree::Deployment> {
+    match name {
+        "booted" => sysroot.booted_deployment(),
+        "staged" => sysroot.staged_deployment(),
+        "rollback" => sysroot.query_deployments_for(None).1,
+        _ => None,
+    }
+}
+
+/// Find the merge commit of `image` in the ostree container store; the image
+/// may be given with or without its transport.
+pub(crate) fn ostree_image_commit(repo: &ostree::Repo, image: &str) -> Result<Option<String>> {
+    for imgref in ostree_container::store::list_images(repo)? {
+        let Ok(parsed) = ImageReference::try_from(imgref.as_str()) else {
+            continue;
+        };
+        if imgref != image && parsed.name != image {
+            continue;
+        }
+        if let Some(state) = ostree_container::store::query_image(repo, &parsed)? {
+            return Ok(Some(state.merge_commit));
+        }
+    }
+    Ok(None)
+}
+
+/// Implementation of `bootc internals mount-image`. This runs in the mount
+/// namespace of the caller, so that the mount is visible on the host.
+#[context("Mounting {image}")]
+pub(crate) fn mount_image(image: &str, mountpoint: &Utf8Path) -> Result<()> {
+    crate::cli::require_root(false)?;
+    let sysroot = ostree::Sysroot::new_default();
+    sysroot.load(gio::Cancellable::NONE)?;
+    let repo = &sysroot.repo();
+    let physical_root = Dir::open_ambient_dir("/sysroot", cap_std::ambient_authority())?;
+
+    if let Some(config) = crate::store::composefs_export::find_image(&physical_root, image)? {
+        let mut cfs = crate::store::ComposefsRepository::open_path(
+            &physical_root.open_dir(crate::store::COMPOSEFS)?,
+            ".",
+        )?;
+        cfs.set_insecure(!ostree_ext::fsverity::is_verity_enabled(repo)?.enabled);
+        composefs_oci::mount(&cfs, &config, mountpoint.as_str(), None)?;
+        println!("Mounted composefs image {config} on {mountpoint}");
+        return Ok(());
+    }
+
+    let deployment = deployment_for_slot(&sysroot, image);
+    let commit = match deployment.as_ref() {
+        Some(d) => d.csum().to_string(),
+        None => {
+            ostree_image_commit(repo, image)?.ok_or_else(|| anyhow!("No such image: {image}"))?
+        }
+    };
+    // Prefer the root of an existing deployment over a new checkout
+    let deployment = deployment.or_else(|| {
+        sysroot
+            .deployments()
+            .into_iter()
+            .find(|d| d.csum() == commit)
+    });
+    let src = if let Some(d) = deployment {
+        format!("/sysroot/{}", sysroot.deployment_dirpath(&d))
+    } else {
+        let dest = format!("{MOUNT_CHECKOUTS}/{commit}");
+        if !Utf8Path::new(&dest).try_exists()? {
+            let tmp = format!("{dest}.tmp");
+            std::fs::create_dir_all(MOUNT_CHECKOUTS)?;
+            if Utf8Path::new(&tmp).try_exists()? {
+                std::fs::remove_dir_all(&tmp)?;
+            }
+            Task::new("Checking out", "ostree")
+                .args([
+                    "checkout",
+                    "--repo=/sysroot/ostree/repo",
+                    commit.as_str(),
+                    tmp.as_str(),
+                ])
+                .run()?;
+            std::fs::rename(&tmp, &dest)?;
+        }
+        dest
+    };
+    Task::new("Mounting", "mount")
+        .args(["--bind", "-o", "ro", src.as_str(), mountpoint.as_str()])
+        .quiet()
+        .run()?;
+    println!("Mounted {commit} on {mountpoint}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_all_target() {
+        assert_eq!(
+            CopyAllTarget::parse(None).unwrap(),
+            CopyAllTarget::ContainerStorage
+        );
+        assert_eq!(
+            CopyAllTarget::parse(Some("containers-storage")).unwrap(),
+            CopyAllTarget::ContainerStorage
+        );
+        assert_eq!(
+            CopyAllTarget::parse(Some("oci-archive:/var/tmp/export")).unwrap(),
+            CopyAllTarget::OciArchiveDir("/var/tmp/export".into())
+        );
+        assert!(CopyAllTarget::parse(Some("oci-archive:")).is_err());
+        assert!(CopyAllTarget::parse(Some("docker://quay.io/foo")).is_err());
+    }
+
+    #[test]
+    fn test_archive_name() {
+        assert_eq!(
+            archive_name("quay.io/example/app:latest"),
+            "quay.io_example_app_latest.tar"
+        );
+        assert_eq!(
+            archive_name("quay.io/example/app@sha256:abc"),
+            "quay.io_example_app_sha256_abc.tar"
+        );
+    }
+}
@@ -35,9 +35,16 @@ const SUBCMD_ARGV_CHUNKING: usize = 100;
 pub(crate) const STORAGE_ALIAS_DIR: &str = "/run/bootc/storage";
 /// We pass this via /proc/self/fd to the child process.
 const STORAGE_RUN_FD: i32 = 3;
+/// The registry authentication secret, if any, is passed via this fd.
+const AUTHFILE_FD: i32 = 4;
 
 const LABELED: &str = ".bootc_labeled";
 
+/// The default container storage (/var/lib/containers/), in the syntax
+/// accepted by the `containers-storage:` transport.
+const HOST_STORAGE: &str =
+    "containers-storage:[overlay@/var/lib/containers/storage+/run/containers/storage]";
+
 /// The path to the image storage, relative to the bootc root directory.
 pub(crate) const SUBPATH: &str = "storage";
 /// The path to the "runroot" with transient runtime state; this is
@@ -286,31 +293,40 @@ impl Storage {
         .map_err(Into::into)
     }
 
+    /// Remove all images not referenced by one of `roots`, returning the
+    /// removed images.
     #[context("Pruning")]
-    pub(crate) async fn prune_except_roots(&self, roots: &HashSet<&str>) -> Result<Vec<String>> {
+    pub(crate) async fn prune_except_roots(
+        &self,
+        roots: &HashSet<&str>,
+    ) -> Result<Vec<crate::podman::ImageListEntry>> {
         let all_images = self.list_images().await?;
         tracing::debug!("Images total: {}", all_images.len(),);
-        let mut garbage = Vec::new();
-        for image in all_images {
-            if image
-                .names
-                .iter()
-                .flatten()
-                .any(|name| !roots.contains(name.as_str()))
-            {
-                garbage.push(image.id);
-            }
-        }
-        tracing::debug!("Images to prune: {}", garbage.len());
-        for garbage in garbage.chunks(SUBCMD_ARGV_CHUNKING) {
+        let roots = roots.iter().copied().collect::<Vec<_>>();
+        let pruned = crate::boundimage::unreferenced_images(all_images, &roots);
+        tracing::debug!("Images to prune: {}", pruned.len());
+        self.remove(&pruned).await?;
+        Ok(pruned)
+    }
+
+    /// Remove the given images.
+    #[context("Removing images")]
+    pub(crate) async fn remove(&self, images: &[crate::podman::ImageListEntry]) -> Result<()> {
+        let ids = images.iter().map(|e| e.id.as_str()).collect::<Vec<_>>();
+        for ids in ids.chunks(SUBCMD_ARGV_CHUNKING) {
             let mut cmd = self.new_image_cmd()?;
             cmd.stdin(Stdio::null());
             cmd.stdout(Stdio::null());
             cmd.arg("rm");
-            cmd.args(garbage);
+            cmd.args(ids);
             AsyncCommand::from(cmd).run().await?;
         }
-        Ok(garbage)
+        Ok(())
+    }
+
+    /// The physical space used by the storage.
+    pub(crate) fn disk_usage(&self) -> Result<u64> {
+        crate::store::accounting::dir_size(&self.storage_root)
     }
 
     /// Return true if the image exists in the storage.
@@ -322,9 +338,26 @@ impl Storage {
         Ok(cmd.status().await?.success())
     }
 
+    /// Add the name `dest` to the stored image `src`, moving it from any other image.
+    #[context("Tagging {src} as {dest}")]
+    pub(crate) async fn tag(&self, src: &str, dest: &str) -> Result<()> {
+        let mut cmd = self.new_image_cmd()?;
+        cmd.stdin(Stdio::null());
+        cmd.stdout(Stdio::null());
+        cmd.args(["tag", src, dest]);
+        AsyncCommand::from(cmd).run().await?;
+        Ok(())
+    }
+
     /// Fetch the image if it is not already present; return whether
-    /// or not the image was fetched.
-    pub(crate) async fn pull(&self, image: &str, mode: PullMode) -> Result<bool> {
+    /// or not the image was fetched. If `authfile` is set, it is used
+    /// instead of the host's registry authentication.
+    pub(crate) async fn pull(
+        &self,
+        image: &str,
+        mode: PullMode,
+        authfile: Option<&Utf8Path>,
+    ) -> Result<bool> {
         match mode {
             PullMode::IfNotExists => {
                 if self.exists(image).await? {
@@ -334,18 +367,34 @@ impl Storage {
             }
             PullMode::Always => {}
         };
+        crate::clock::verify_synchronized()?;
         let mut cmd = self.new_image_cmd()?;
         cmd.stdin(Stdio::null());
         cmd.stdout(Stdio::null());
         cmd.args(["pull", image]);
-        let authfile = ostree_ext::globals::get_global_authfile(&self.sysroot)?
-            .map(|(authfile, _fd)| authfile);
+        // An authfile given on the command line takes precedence over a host-level pull
+        // secret, which in turn takes precedence over the global authfile
         if let Some(authfile) = authfile {
             cmd.args(["--authfile", authfile.as_str()]);
+        } else if let Some(auth) = crate::secrets::open_registry_auth_in(&self.sysroot)? {
+            cmd.take_fd_n(Arc::new(OwnedFd::from(auth)), AUTHFILE_FD);
+            cmd.args(["--authfile", &format!("/proc/self/fd/{AUTHFILE_FD}")]);
+        } else if let Some((authfile, _fd)) =
+            ostree_ext::globals::get_global_authfile(&self.sysroot)?
+        {
+            cmd.args(["--authfile", authfile.as_str()]);
+        }
+        if let Some(certdir) =
+            ostree_ext::container::registry_certificate_directory(&self.sysroot, image)?
+        {
+            cmd.args(["--cert-dir", certdir.as_str()]);
         }
         tracing::debug!("Pulling image: {image}");
         let mut cmd = AsyncCommand::from(cmd);
-        cmd.run().await.context("Failed to pull image")?;
+        cmd.run()
+            .await
+            .map_err(crate::clock::annotate_registry_error)
+            .context("Failed to pull image")?;
         Ok(true)
     }
 
@@ -372,6 +421,40 @@ impl Storage {
         Ok(())
     }
 
+    /// Copy an image from a local source (in `transport:name` form, e.g. an `oci:`
+    /// image layout) to this storage as `image`, preserving its digest.
+    #[context("Copying {src} to {image}")]
+    pub(crate) async fn copy_from(&self, src: &str, image: &str) -> Result<()> {
+        let mut cmd = Command::new("skopeo");
+        cmd.stdin(Stdio::null());
+        cmd.stdout(Stdio::null());
+        bind_storage_roots(&mut cmd, &self.storage_root, &self.run)?;
+        let storage_dest = &format!(
+            "containers-storage:[overlay@{STORAGE_ALIAS_DIR}+/proc/self/fd/{STORAGE_RUN_FD}]"
+        );
+        cmd.args(["copy", "--preserve-digests", "--remove-signatures", src])
+            .arg(format!("{storage_dest}{image}"));
+        let mut cmd = AsyncCommand::from(cmd);
+        cmd.run().await?;
+        Ok(())
+    }
+
+    /// Copy an image from this storage to the provided destination (in `transport:name`
+    /// form), or to the default container storage (/var/lib/containers/) if unset.
+    #[context("Pushing {image}")]
+    pub(crate) async fn push_to(&self, image: &str, dest: Option<&str>) -> Result<()> {
+        let dest = dest
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| format!("{HOST_STORAGE}{image}"));
+        let mut cmd = self.new_image_cmd()?;
+        cmd.stdin(Stdio::null());
+        cmd.stdout(Stdio::null());
+        cmd.args(["push", "--remove-signatures", image, dest.as_str()]);
+        let mut cmd = AsyncCommand::from(cmd);
+        cmd.run().await?;
+        Ok(())
+    }
+
     fn subpath() -> Utf8PathBuf {
         Utf8Path::new(crate::store::BOOTC_ROOT).join(SUBPATH)
     }
@@ -10,8 +10,12 @@
 pub(crate) mod baseline;
 pub(crate) mod completion;
 pub(crate) mod config;
+#[cfg(feature = "install-to-disk")]
+pub(crate) mod loopback;
 mod osbuild;
 pub(crate) mod osconfig;
+mod payload;
+mod report;
 
 use std::collections::HashMap;
 use std::io::Write;
@@ -55,7 +59,7 @@ use self::baseline::InstallBlockDeviceOpts;
 use crate::boundimage::{BoundImage, ResolvedBoundImage};
 use crate::containerenv::ContainerExecutionInfo;
 use crate::deploy::{prepare_for_pull, pull_from_prepared, PreparedImportMeta, PreparedPullResult};
-use crate::kernel_cmdline::Cmdline;
+use crate::kernel_cmdline::{Cmdline, CmdlineBuilder};
 use crate::lsm;
 use crate::progress_jsonl::ProgressWriter;
 use crate::spec::ImageReference;
@@ -67,7 +71,6 @@ use bootc_mount::Filesystem;
 /// The toplevel boot directory
 const BOOT: &str = "boot";
 /// Directory for transient runtime state
-#[cfg(feature = "install-to-disk")]
 const RUN_BOOTC: &str = "/run/bootc";
 /// The default path for the host rootfs
 const ALONGSIDE_ROOT_MOUNT: &str = "/target";
@@ -151,6 +154,32 @@ pub(crate) struct InstallSourceOpts {
     /// in the previous paragraph. See skopeo(1) for accepted formats.
     #[clap(long)]
     pub(crate) source_imgref: Option<String>,
+
+    /// Install from a local payload directory (e.g. on installation media), without
+    /// a registry or container storage. It must contain an OCI image layout of the
+    /// image in `image/`, and may contain the logically bound images in `bound-images/`,
+    /// each tagged with the image reference from its `.image` or `.container` file.
+    /// All content is verified against its digest.
+    #[clap(long, value_name = "PATH", conflicts_with = "source_imgref")]
+    #[serde(default)]
+    pub(crate) source_payload: Option<Utf8PathBuf>,
+
+    /// Path to a container authentication file (see `containers-auth.json(5)`), used
+    /// for fetching the source image and logically bound images.
+    #[clap(long, value_name = "PATH")]
+    #[serde(default)]
+    pub(crate) auth_file: Option<Utf8PathBuf>,
+
+    /// Select this platform (`OS/ARCH[/VARIANT]`, e.g. `linux/arm64`) from a multi-platform
+    /// source image instead of the one matching the host. It is recorded in the installed
+    /// deployment, and used by upgrades.
+    #[clap(long, value_name = "PLATFORM")]
+    #[serde(default)]
+    pub(crate) platform: Option<String>,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub(crate) fetch: crate::cli::FetchOpts,
 }
 
 #[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -200,11 +229,70 @@ pub(crate) struct InstallConfigOpts {
     #[clap(long)]
     root_ssh_authorized_keys: Option<Utf8PathBuf>,
 
+    /// The path to a first boot provisioning file: either cloud-init user-data
+    /// (e.g. starting with `#cloud-config`) or an Ignition config.
+    ///
+    /// cloud-init user-data is written as a NoCloud seed to `/var/lib/cloud/seed/nocloud`,
+    /// and an Ignition config to `/boot/ignition/config.ign` along with the
+    /// `/boot/ignition.firstboot` stamp. The target image must include the corresponding
+    /// tool; for Ignition, its bootloader configuration must add the `ignition.firstboot`
+    /// kernel argument while the stamp exists.
+    #[clap(long)]
+    provisioning_file: Option<Utf8PathBuf>,
+
+    /// Enroll a trusted sigstore public key for container image signature verification,
+    /// in the form `SCOPE=PATH`, where `SCOPE` is a registry or repository (e.g. `quay.io/example`).
+    /// This option can be provided multiple times.
+    ///
+    /// The key is written to `/etc/pki/containers` and a `sigstoreSigned` requirement
+    /// for the scope is added to `/etc/containers/policy.json` in the target.
+    #[clap(long, value_name = "SCOPE=PATH")]
+    trusted_sigstore_key: Option<Vec<String>>,
+
+    /// Enroll a trusted GPG public key for container image signature verification,
+    /// in the form `SCOPE=PATH`. This option can be provided multiple times.
+    ///
+    /// The key is written to `/etc/pki/containers` and a `signedBy` requirement
+    /// for the scope is added to `/etc/containers/policy.json` in the target.
+    #[clap(long, value_name = "SCOPE=PATH")]
+    trusted_gpg_key: Option<Vec<String>>,
+
+    /// Enroll a trusted GPG public key for an ostree remote, in the form `REMOTE=PATH`.
+    /// This option can be provided multiple times.
+    ///
+    /// The remote must already be configured in `/etc/ostree/remotes.d` in the target image;
+    /// the key is written to `/etc/pki/ostree` and set as its `gpgkeypath`.
+    #[clap(long, value_name = "REMOTE=PATH")]
+    ostree_remote_gpg_key: Option<Vec<String>>,
+
+    /// Set the timezone of the installed system (e.g. `Europe/Berlin`), by
+    /// linking `/etc/localtime` into `/usr/share/zoneinfo`.
+    ///
+    /// This overrides `timezone` in the install configuration.
+    #[clap(long)]
+    timezone: Option<String>,
+
+    /// Set the locale of the installed system (e.g. `en_US.UTF-8`), written as `LANG`
+    /// to `/etc/locale.conf`.
+    ///
+    /// This overrides `locale` in the install configuration.
+    #[clap(long)]
+    locale: Option<String>,
+
+    /// Set the virtual console keymap of the installed system (e.g. `de-nodeadkeys`),
+    /// written as `KEYMAP` to `/etc/vconsole.conf`.
+    ///
+    /// This overrides `keymap` in the install configuration.
+    #[clap(long)]
+    keymap: Option<String>,
+
     /// Perform configuration changes suitable for a "generic" disk image.
     /// At the moment:
     ///
     /// - All bootloader types will be installed
     /// - Changes to the system firmware will be skipped
+    /// - Kernel arguments from `kargs.d` which match hardware properties
+    ///   (e.g. `match-cpu-vendor`) are skipped, as the host is not the target
     #[clap(long)]
     #[serde(default)]
     pub(crate) generic_image: bool,
@@ -218,6 +306,12 @@ pub(crate) struct InstallConfigOpts {
     /// The stateroot name to use. Defaults to `default`.
     #[clap(long)]
     pub(crate) stateroot: Option<String>,
+
+    /// Print the install report as JSON once done; it is always written
+    /// to `/run/bootc/install-report.json`.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) json: bool,
 }
 
 #[cfg(feature = "install-to-disk")]
@@ -259,6 +353,10 @@ pub(crate) enum ReplaceMode {
     /// system binaries after the reboot into the target system; this can be done
     /// with code in the new target system, or manually.
     Alongside,
+    /// Install into a new stateroot of an existing ostree-based system, preserving
+    /// its deployments and bootloader.  Both operating systems will be present
+    /// in the boot menu, with the new one as the default.
+    DualBoot,
 }
 
 impl std::fmt::Display for ReplaceMode {
@@ -289,8 +387,8 @@ pub(crate) struct InstallTargetFilesystemOpts {
     #[clap(long)]
     pub(crate) boot_mount_spec: Option<String>,
 
-    /// Initialize the system in-place; at the moment, only one mode for this is implemented.
-    /// In the future, it may also be supported to set up an explicit "dual boot" system.
+    /// Initialize the system in-place, or install alongside an existing
+    /// ostree-based system (using a distinct `--stateroot`) to set up a "dual boot" system.
     #[clap(long)]
     pub(crate) replace: Option<ReplaceMode>,
 
@@ -303,6 +401,14 @@ pub(crate) struct InstallTargetFilesystemOpts {
     /// is then the responsibility of the invoking code to perform those operations.
     #[clap(long)]
     pub(crate) skip_finalize: bool,
+
+    /// The target is the top level of an empty btrfs filesystem; create the subvolumes
+    /// `root`, `var` and `home` in it, and install into `root`.
+    ///
+    /// `/var` and `/var/home` are mounted from the other subvolumes via `/etc/fstab`, and
+    /// `root` is made the default subvolume.
+    #[clap(long, conflicts_with_all = ["replace", "root_mount_spec"])]
+    pub(crate) btrfs_subvolumes: bool,
 }
 
 #[derive(Debug, Clone, clap::Parser, PartialEq, Eq)]
@@ -377,11 +483,23 @@ pub(crate) struct State {
     pub(crate) install_config: Option<config::InstallConfiguration>,
     /// The parsed contents of the authorized_keys (not the file path)
     pub(crate) root_ssh_authorized_keys: Option<String>,
+    /// The validated first boot provisioning file
+    pub(crate) provisioning_file: Option<osconfig::ProvisioningFile>,
+    /// Public keys to enroll into the target's trust stores
+    pub(crate) trusted_keys: Vec<osconfig::TrustedKey>,
+    /// Timezone, locale and keymap for the target
+    pub(crate) system_settings: osconfig::SystemSettings,
+    /// Options for fetching the source image and logically bound images
+    pub(crate) pull_opts: crate::deploy::PullOptions,
     #[allow(dead_code)]
     pub(crate) host_is_container: bool,
     /// The root filesystem of the running container
     pub(crate) container_root: Dir,
+    /// The payload directory we're installing from, if any
+    pub(crate) payload: Option<payload::Payload>,
     pub(crate) tempdir: TempDir,
+    /// When preparing the install started, for the install report
+    pub(crate) started: std::time::Instant,
 }
 
 impl State {
@@ -555,6 +673,36 @@ impl InstallAleph {
     }
 }
 
+/// The path of a local OCI image layout (`oci:` or `oci-archive:`), without the
+/// optional image name or index which may follow it.
+fn oci_layout_path(imageref: &ostree_container::ImageReference) -> Option<&str> {
+    match imageref.transport {
+        ostree_container::Transport::OciDir | ostree_container::Transport::OciArchive => {
+            imageref.name.split(':').next()
+        }
+        _ => None,
+    }
+}
+
+/// The name of the image the installed system will track. This defaults to the
+/// source image; for a local OCI image layout, whose path is meaningless on the
+/// installed system, the image name in the layout is used if it is a full reference.
+fn target_imgname<'a>(source: &'a SourceInfo, target_imgref: Option<&'a str>) -> &'a str {
+    if let Some(name) = target_imgref {
+        return name;
+    }
+    let name = source.imageref.name.as_str();
+    if let Some(path) = oci_layout_path(&source.imageref) {
+        match name[path.len()..].strip_prefix(':') {
+            Some(refname) if refname.contains('/') => return refname,
+            _ => crate::utils::medium_visibility_warning(&format!(
+                "No --target-imgref provided; the installed system will track the OCI image at {path}"
+            )),
+        }
+    }
+    name
+}
+
 impl SourceInfo {
     // Inspect container information and convert it to an ostree image reference
     // that pulls from containers-storage.
@@ -582,9 +730,20 @@ impl SourceInfo {
     #[context("Creating source info from a given imageref")]
     pub(crate) fn from_imageref(imageref: &str, root: &Dir) -> Result<Self> {
         let imageref = ostree_container::ImageReference::try_from(imageref)?;
+        if let Some(path) = oci_layout_path(&imageref) {
+            if !Path::new(path).try_exists()? {
+                anyhow::bail!("No such OCI image: {path}");
+            }
+        }
         Self::new(imageref, None, root, false)
     }
 
+    /// Whether the source is a local OCI image layout (`oci:` or `oci-archive:`),
+    /// which needs neither container storage nor network access.
+    pub(crate) fn is_oci_layout(&self) -> bool {
+        oci_layout_path(&self.imageref).is_some()
+    }
+
     fn have_selinux_from_repo(root: &Dir) -> Result<bool> {
         let cancellable = ostree::gio::Cancellable::NONE;
 
@@ -705,12 +864,26 @@ async fn initialize_ostree_root(
     // so only create it via init_osname if it doesn't exist
     // (ideally this would be handled by init_osname)
     let stateroot_path = format!("ostree/deploy/{stateroot}");
-    if !sysroot_dir.try_exists(stateroot_path)? {
+    if !sysroot_dir.try_exists(&stateroot_path)? {
         sysroot
             .init_osname(stateroot, cancellable)
             .context("initializing stateroot")?;
     }
 
+    // A separate /var filesystem is mounted over the stateroot /var at boot; mount it
+    // there now so that the deployment populates it (and it is labeled along with the
+    // rest of the ostree directory), rather than the directory it would shadow.
+    if let Some(var) = root_setup.mounts.iter().find(|m| m.target == "/var") {
+        let target = root_setup
+            .physical_root_path
+            .join(format!("{stateroot_path}/var"));
+        let mut task = Task::new("Mounting /var", "mount");
+        if let Some(options) = var.options.as_deref() {
+            task = task.args(["-o", options]);
+        }
+        task.args([var.source.as_str(), target.as_str()]).run()?;
+    }
+
     state.tempdir.create_dir("temp-run")?;
     let temp_run = state.tempdir.open_dir("temp-run")?;
 
@@ -761,6 +934,7 @@ async fn install_container(
     root_setup: &RootSetup,
     sysroot: &ostree::Sysroot,
     has_ostree: bool,
+    report: &mut report::InstallReport,
 ) -> Result<(ostree::Deployment, InstallAleph)> {
     let sepolicy = state.load_policy()?;
     let sepolicy = sepolicy.as_ref();
@@ -799,12 +973,20 @@ async fn install_container(
     let repo = &sysroot.repo();
     repo.set_disable_fsync(true);
 
-    let pulled_image = match prepare_for_pull(repo, &spec_imgref, Some(&state.target_imgref))
-        .await?
+    let pulled_image = match prepare_for_pull(
+        repo,
+        &spec_imgref,
+        Some(&state.target_imgref),
+        &state.pull_opts,
+    )
+    .await?
     {
         PreparedPullResult::AlreadyPresent(existing) => existing,
-        PreparedPullResult::Ready(image_meta) => {
+        PreparedPullResult::Ready(mut image_meta) => {
             check_disk_space(root_setup.physical_root.as_fd(), &image_meta, &spec_imgref)?;
+            // Overlap unpacking each layer with downloading the next one; this matters
+            // most when provisioning a fresh system, where everything is fetched.
+            image_meta.imp.set_prefetch_layers();
             pull_from_prepared(&spec_imgref, false, ProgressWriter::default(), image_meta).await?
         }
     };
@@ -820,7 +1002,7 @@ async fn install_container(
     let kargsd = crate::bootc_kargs::get_kargs_from_ostree_root(
         &sysroot.repo(),
         merged_ostree_root.downcast_ref().unwrap(),
-        std::env::consts::ARCH,
+        &crate::bootc_kargs::HostProperties::for_install(state.config_opts.generic_image),
     )?;
     let kargsd = kargsd.iter().map(|s| s.as_str());
 
@@ -832,19 +1014,19 @@ async fn install_container(
         .into_iter()
         .flatten()
         .map(|s| s.as_str());
-    // Final kargs, in order:
+    // Final kargs, in increasing order of precedence:
     // - root filesystem kargs
     // - install config kargs
     // - kargs.d from container image
     // - args specified on the CLI
-    let kargs = root_setup
-        .kargs
-        .iter()
-        .map(|v| v.as_str())
-        .chain(install_config_kargs)
-        .chain(kargsd)
-        .chain(state.config_opts.karg.iter().flatten().map(|v| v.as_str()))
-        .collect::<Vec<_>>();
+    let mut kargs = CmdlineBuilder::new();
+    kargs
+        .extend(&root_setup.kargs)
+        .extend(install_config_kargs)
+        .extend(kargsd)
+        .extend(state.config_opts.karg.iter().flatten());
+    let kargs = kargs.into_vec();
+    let kargs = kargs.iter().map(|v| v.as_str()).collect::<Vec<_>>();
     let mut options = ostree_container::deploy::DeployOpts::default();
     options.kargs = Some(kargs.as_slice());
     options.target_imgref = Some(&state.target_imgref);
@@ -862,6 +1044,14 @@ async fn install_container(
         .into_iter()
         .next()
         .ok_or_else(|| anyhow::anyhow!("Failed to find deployment"))?;
+    if state.pull_opts.platform.is_some() {
+        let origin = deployment
+            .origin()
+            .ok_or_else(|| anyhow::anyhow!("Missing origin"))?;
+        let target = ImageReference::from(state.target_imgref.clone());
+        crate::deploy::set_origin_platform(repo, &target, &origin, &state.pull_opts);
+        sysroot.write_origin_file(&deployment, Some(&origin), gio::Cancellable::NONE)?;
+    }
     // SAFETY: There must be a path
     let path = sysroot.deployment_dirpath(&deployment);
     let root = root_setup
@@ -894,22 +1084,50 @@ async fn install_container(
         }
     }
 
-    // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
+    // Write the entries for /boot and /var to /etc/fstab.  TODO: Encourage OSes to use the karg?
     // Or better bind this with the grub data.
-    // We omit it if the boot mountspec argument was empty
-    if let Some(boot) = root_setup.boot.as_ref() {
-        if !boot.source.is_empty() {
-            crate::lsm::atomic_replace_labeled(&root, "etc/fstab", 0o644.into(), sepolicy, |w| {
-                writeln!(w, "{}", boot.to_fstab()).map_err(Into::into)
-            })?;
-        }
+    // We omit /boot if the boot mountspec argument was empty
+    let fstab_entries = root_setup
+        .boot
+        .iter()
+        .chain(root_setup.mounts.iter())
+        .filter(|m| !m.source.is_empty())
+        .collect::<Vec<_>>();
+    if !fstab_entries.is_empty() {
+        crate::lsm::atomic_replace_labeled(&root, "etc/fstab", 0o644.into(), sepolicy, |w| {
+            for m in fstab_entries {
+                writeln!(w, "{}", m.to_fstab())?;
+            }
+            Ok(())
+        })?;
     }
 
     if let Some(contents) = state.root_ssh_authorized_keys.as_deref() {
         osconfig::inject_root_ssh_authorized_keys(&root, sepolicy, contents)?;
     }
 
+    if !state.trusted_keys.is_empty() {
+        osconfig::inject_trusted_keys(&root, sepolicy, &state.trusted_keys)?;
+    }
+
+    if !state.system_settings.is_empty() {
+        osconfig::inject_system_settings(&root, sepolicy, &state.system_settings)?;
+    }
+
+    if let Some(provisioning) = state.provisioning_file.as_ref() {
+        osconfig::inject_provisioning_file(
+            &root_setup.physical_root,
+            state.stateroot(),
+            sepolicy,
+            provisioning,
+        )?;
+    }
+
     let aleph = InstallAleph::new(&src_imageref, &imgstate, &state.selinux_state)?;
+    report.image = Some(aleph.image.clone());
+    report.digest = Some(imgstate.manifest_digest.to_string());
+    report.version = aleph.version.clone();
+    report.kargs = kargs.iter().map(|s| s.to_string()).collect();
     Ok((deployment, aleph))
 }
 
@@ -960,7 +1178,14 @@ pub(crate) struct RootSetup {
     /// True if we should skip finalizing
     skip_finalize: bool,
     boot: Option<MountSpec>,
+    /// Additional filesystems, such as a separate /var
+    mounts: Vec<MountSpec>,
     kargs: Vec<String>,
+    /// True if we are installing alongside the deployments of another stateroot,
+    /// whose bootloader we must preserve
+    dual_boot: bool,
+    /// Additional disks holding a mirror of the ESP
+    esp_mirrors: Vec<bootc_blockdev::PartitionTable>,
 }
 
 fn require_boot_uuid(spec: &MountSpec) -> Result<&str> {
@@ -1157,14 +1382,22 @@ pub(crate) fn setup_sys_mount(fstype: &str, fspath: &str) -> Result<()> {
 async fn verify_target_fetch(
     tmpdir: &Dir,
     imgref: &ostree_container::OstreeImageReference,
+    pull_opts: &crate::deploy::PullOptions,
 ) -> Result<()> {
     let tmpdir = &TempDir::new_in(&tmpdir)?;
     let tmprepo = &ostree::Repo::create_at_dir(tmpdir.as_fd(), ".", ostree::RepoMode::Bare, None)
         .context("Init tmp repo")?;
 
     tracing::trace!("Verifying fetch for {imgref}");
-    let mut imp =
-        ostree_container::store::ImageImporter::new(tmprepo, imgref, Default::default()).await?;
+    let mut config = ostree_container::store::ImageProxyConfig::default();
+    config.authfile = pull_opts.authfile.clone().map(Into::into);
+    let mut imp = ostree_container::store::ImageImporter::new_with_platform(
+        tmprepo,
+        imgref,
+        config,
+        pull_opts.platform.as_ref(),
+    )
+    .await?;
     use ostree_container::store::PrepareResult;
     let prep = match imp.prepare().await? {
         // SAFETY: It's impossible that the image was already fetched into this newly created temporary repository
@@ -1182,12 +1415,31 @@ async fn prepare_install(
     target_opts: InstallTargetOpts,
 ) -> Result<Arc<State>> {
     tracing::trace!("Preparing install");
+    let started = std::time::Instant::now();
     let rootfs = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())
         .context("Opening /")?;
 
+    let mut pull_opts = crate::deploy::PullOptions::default();
+    if let Some(path) = source_opts.auth_file.as_ref() {
+        std::fs::metadata(path).with_context(|| format!("Accessing authfile {path}"))?;
+        pull_opts.authfile = Some(path.clone());
+    }
+    if let Some(platform) = source_opts.platform.as_deref() {
+        pull_opts.platform = Some(crate::platform::parse(platform)?);
+    }
+    source_opts.fetch.apply(&mut pull_opts);
+
     let host_is_container = crate::containerenv::is_container(&rootfs);
-    let external_source = source_opts.source_imgref.is_some();
-    let source = match source_opts.source_imgref {
+    let payload = source_opts
+        .source_payload
+        .as_deref()
+        .map(payload::Payload::open)
+        .transpose()?;
+    let source_imgref = source_opts
+        .source_imgref
+        .or_else(|| payload.as_ref().map(|p| p.source_imgref()));
+    let external_source = source_imgref.is_some();
+    let source = match source_imgref {
         None => {
             ensure!(host_is_container, "Either --source-imgref must be defined or this command must be executed inside a podman container.");
 
@@ -1229,10 +1481,12 @@ async fn prepare_install(
         );
     }
     let target_sigverify = sigpolicy_from_opt(target_opts.enforce_container_sigpolicy);
-    let target_imgname = target_opts
+    // The image in a payload is typically annotated with its registry name
+    let target_imgref_opt = target_opts
         .target_imgref
         .as_deref()
-        .unwrap_or(source.imageref.name.as_str());
+        .or_else(|| payload.as_ref().and_then(|p| p.image_ref.as_deref()));
+    let target_imgname = target_imgname(&source, target_imgref_opt);
     let target_transport =
         ostree_container::Transport::try_from(target_opts.target_transport.as_str())?;
     let target_imgref = ostree_container::OstreeImageReference {
@@ -1261,7 +1515,7 @@ async fn prepare_install(
     osbuild::adjust_for_bootc_image_builder(&rootfs, &tempdir)?;
 
     if target_opts.run_fetch_check {
-        verify_target_fetch(&tempdir, &target_imgref).await?;
+        verify_target_fetch(&tempdir, &target_imgref, &pull_opts).await?;
     }
 
     // Even though we require running in a container, the mounts we create should be specific
@@ -1279,6 +1533,8 @@ async fn prepare_install(
     println!("Installing image: {:#}", &target_imgref);
     if let Some(digest) = source.digest.as_deref() {
         println!("Digest: {digest}");
+    } else if let Some(payload) = payload.as_ref() {
+        println!("Digest: {}", payload.image_digest);
     }
 
     let install_config = config::load_config()?;
@@ -1309,6 +1565,50 @@ async fn prepare_install(
         .as_ref()
         .map(|p| std::fs::read_to_string(p).with_context(|| format!("Reading {p}")))
         .transpose()?;
+    // Same for the provisioning file, which is also validated.
+    let provisioning_file = config_opts
+        .provisioning_file
+        .as_ref()
+        .map(|p| {
+            let contents = std::fs::read_to_string(p).with_context(|| format!("Reading {p}"))?;
+            osconfig::ProvisioningFile::parse(contents).with_context(|| format!("Parsing {p}"))
+        })
+        .transpose()?;
+    // Same for any trusted keys.
+    let trusted_keys = [
+        (
+            osconfig::TrustedKeyType::Sigstore,
+            &config_opts.trusted_sigstore_key,
+        ),
+        (osconfig::TrustedKeyType::Gpg, &config_opts.trusted_gpg_key),
+        (
+            osconfig::TrustedKeyType::OstreeRemoteGpg,
+            &config_opts.ostree_remote_gpg_key,
+        ),
+    ]
+    .into_iter()
+    .flat_map(|(ty, args)| args.iter().flatten().map(move |arg| (ty, arg)))
+    .map(|(ty, arg)| osconfig::TrustedKey::from_arg(ty, arg))
+    .collect::<Result<Vec<_>>>()?;
+    // The command line takes precedence over the install configuration.
+    let system_settings = {
+        let c = install_config.as_ref();
+        osconfig::SystemSettings {
+            timezone: config_opts
+                .timezone
+                .clone()
+                .or_else(|| c.and_then(|c| c.timezone.clone())),
+            locale: config_opts
+                .locale
+                .clone()
+                .or_else(|| c.and_then(|c| c.locale.clone())),
+            keymap: config_opts
+                .keymap
+                .clone()
+                .or_else(|| c.and_then(|c| c.keymap.clone())),
+        }
+    };
+    system_settings.validate()?;
 
     // Create our global (read-only) state which gets wrapped in an Arc
     // so we can pass it to worker threads too. Right now this just
@@ -1321,9 +1621,15 @@ async fn prepare_install(
         install_config,
         prepareroot_config,
         root_ssh_authorized_keys,
+        provisioning_file,
+        trusted_keys,
+        system_settings,
+        pull_opts,
         container_root: rootfs,
+        payload,
         tempdir,
         host_is_container,
+        started,
     });
 
     Ok(state)
@@ -1341,23 +1647,50 @@ async fn install_with_sysroot(
     bound_images: BoundImages,
     has_ostree: bool,
     imgstore: &crate::imgstorage::Storage,
+    report: &mut report::InstallReport,
 ) -> Result<()> {
+    let bootloader = state.install_config.as_ref().and_then(|c| c.bootloader);
+    let grub_config = state.install_config.as_ref().and_then(|c| c.grub.as_ref());
+    if grub_config.is_some()
+        && (cfg!(target_arch = "s390x") || bootloader == Some(config::Bootloader::SystemdBoot))
+    {
+        anyhow::bail!("install.grub is only supported when installing GRUB via bootupd");
+    }
+
     // And actually set up the container in that root, returning a deployment and
     // the aleph state (see below).
-    let (deployment, aleph) = install_container(state, rootfs, &sysroot, has_ostree).await?;
+    let (deployment, aleph) =
+        install_container(state, rootfs, &sysroot, has_ostree, report).await?;
     // Write the aleph data that captures the system state at the time of provisioning for aid in future debugging.
-    rootfs
-        .physical_root
-        .atomic_replace_with(BOOTC_ALEPH_PATH, |f| {
-            anyhow::Ok(aleph.to_canon_json_writer(f)?)
-        })
-        .context("Writing aleph version")?;
+    // When dual booting, the data for the original installation is kept.
+    if !(rootfs.dual_boot && rootfs.physical_root.try_exists(BOOTC_ALEPH_PATH)?) {
+        rootfs
+            .physical_root
+            .atomic_replace_with(BOOTC_ALEPH_PATH, |f| {
+                anyhow::Ok(aleph.to_canon_json_writer(f)?)
+            })
+            .context("Writing aleph version")?;
+    }
 
     let deployment_path = sysroot.deployment_dirpath(&deployment);
-
-    if cfg!(target_arch = "s390x") {
+    report.finish_phase("deploy");
+
+    if rootfs.dual_boot {
+        // The existing bootloader reads the entries that ostree generates for
+        // the deployments of all stateroots, so it only needs to be kept.
+        println!("Preserving existing bootloader");
+        report.bootloader = Some(report::ReportBootloader::Preserved);
+    } else if cfg!(target_arch = "s390x") {
         // TODO: Integrate s390x support into install_via_bootupd
         crate::bootloader::install_via_zipl(&rootfs.device_info, boot_uuid)?;
+        report.bootloader = Some(report::ReportBootloader::Zipl);
+    } else if bootloader == Some(config::Bootloader::SystemdBoot) {
+        crate::bootloader::install_via_systemd_boot(
+            &rootfs.device_info,
+            &rootfs.physical_root_path,
+            &state.config_opts,
+        )?;
+        report.bootloader = Some(report::ReportBootloader::SystemdBoot);
     } else {
         crate::bootloader::install_via_bootupd(
             &rootfs.device_info,
@@ -1365,11 +1698,25 @@ async fn install_with_sysroot(
             &state.config_opts,
             &deployment_path.as_str(),
         )?;
+        if let Some(grub_config) = grub_config {
+            crate::bootloader::write_grub_user_config(&rootfs.physical_root, grub_config)?;
+        }
+        report.bootloader = Some(report::ReportBootloader::Bootupd);
+    }
+    if !rootfs.esp_mirrors.is_empty() {
+        crate::bootloader::install_esp_mirrors(
+            &rootfs.device_info,
+            &rootfs.physical_root,
+            &rootfs.esp_mirrors,
+            state.config_opts.generic_image,
+        )?;
     }
     tracing::debug!("Installed bootloader");
+    report.finish_phase("bootloader");
 
     tracing::debug!("Perfoming post-deployment operations");
 
+    let create_volumes = !matches!(bound_images, BoundImages::Skip);
     match bound_images {
         BoundImages::Skip => {}
         BoundImages::Resolved(resolved_bound_images) => {
@@ -1380,11 +1727,37 @@ async fn install_with_sysroot(
             }
         }
         BoundImages::Unresolved(bound_images) => {
-            crate::boundimage::pull_images_impl(imgstore, bound_images)
-                .await
-                .context("pulling bound images")?;
+            crate::boundimage::pull_images_impl(
+                imgstore,
+                bound_images,
+                state.pull_opts.authfile.as_deref(),
+            )
+            .await
+            .context("pulling bound images")?;
+        }
+        BoundImages::Payload => {
+            // SAFETY: This is only used when installing from a payload
+            let payload = state.payload.as_ref().unwrap();
+            // The bound images are those of the deployed image, not of the
+            // (installer) environment we're running in.
+            let images =
+                crate::boundimage::query_bound_images_for_deployment(sysroot, &deployment)?;
+            for (src, image) in payload.resolve_bound_images(&images)? {
+                imgstore.copy_from(&src, &image).await?;
+            }
+        }
+    }
+    if create_volumes {
+        let volumes = crate::boundimage::query_bound_volumes(&state.container_root)?;
+        if !volumes.is_empty() {
+            let storage_root = rootfs.physical_root_path.join(format!(
+                "ostree/deploy/{}/var/lib/containers/storage",
+                deployment.osname()
+            ));
+            crate::boundimage::create_volumes(&storage_root, &volumes)?;
         }
     }
+    report.finish_phase("bound-images");
 
     Ok(())
 }
@@ -1393,12 +1766,15 @@ enum BoundImages {
     Skip,
     Resolved(Vec<ResolvedBoundImage>),
     Unresolved(Vec<BoundImage>),
+    /// Copied from the payload directory
+    Payload,
 }
 
 impl BoundImages {
     async fn from_state(state: &State) -> Result<Self> {
         let bound_images = match state.config_opts.bound_images {
             BoundImagesOpt::Skip => BoundImages::Skip,
+            _ if state.payload.is_some() => BoundImages::Payload,
             others => {
                 let queried_images = crate::boundimage::query_bound_images(&state.container_root)?;
                 match others {
@@ -1429,7 +1805,7 @@ async fn install_to_filesystem_impl(
     state: &State,
     rootfs: &mut RootSetup,
     cleanup: Cleanup,
-) -> Result<()> {
+) -> Result<report::InstallReport> {
     if matches!(state.selinux_state, SELinuxFinalState::ForceTargetDisabled) {
         rootfs.kargs.push("selinux=0".to_string());
     }
@@ -1455,6 +1831,20 @@ async fn install_to_filesystem_impl(
         .ok_or_else(|| anyhow!("No uuid for boot/root"))?;
     tracing::debug!("boot uuid={boot_uuid}");
 
+    let mut report = report::InstallReport::new(state.started);
+    report.stateroot = state.stateroot().to_owned();
+    report.selinux = state.selinux_state.to_aleph();
+    report.device = rootfs.device_info.device.clone();
+    report.partitions = rootfs
+        .device_info
+        .partitions
+        .iter()
+        .map(Into::into)
+        .collect();
+    report.root_uuid = rootfs.rootfs_uuid.clone();
+    report.boot_uuid = boot_uuid.to_owned();
+    report.finish_phase("prepare");
+
     let bound_images = BoundImages::from_state(state).await?;
 
     // Initialize the ostree sysroot (repo, stateroot, etc.)
@@ -1470,6 +1860,7 @@ async fn install_to_filesystem_impl(
             bound_images,
             has_ostree,
             &imgstore,
+            &mut report,
         )
         .await?;
 
@@ -1488,13 +1879,24 @@ async fn install_to_filesystem_impl(
 
     // Finalize mounted filesystems
     if !rootfs.skip_finalize {
-        let bootfs = rootfs.boot.as_ref().map(|_| ("boot", "boot"));
-        for (fsname, fs) in std::iter::once(("root", ".")).chain(bootfs) {
+        let bootfs = rootfs.boot.as_ref().map(|_| ("boot", "boot".to_owned()));
+        // A /var subvolume of the root filesystem is finalized along with it
+        let varfs = rootfs
+            .mounts
+            .iter()
+            .any(|m| m.target == "/var" && m.get_source_uuid() != rootfs.rootfs_uuid.as_deref())
+            .then(|| ("var", format!("ostree/deploy/{}/var", state.stateroot())));
+        for (fsname, fs) in std::iter::once(("root", ".".to_owned()))
+            .chain(bootfs)
+            .chain(varfs)
+        {
             finalize_filesystem(fsname, &rootfs.physical_root, fs)?;
         }
     }
+    report.finish_phase("finalize");
+    report.write(state.config_opts.json)?;
 
-    Ok(())
+    Ok(report)
 }
 
 fn installation_complete() {
@@ -1504,7 +1906,13 @@ fn installation_complete() {
 /// Implementation of the `bootc install to-disk` CLI command.
 #[context("Installing to disk")]
 #[cfg(feature = "install-to-disk")]
-pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
+pub(crate) async fn install_to_disk(opts: InstallToDiskOpts) -> Result<()> {
+    install_to_disk_impl(opts).await.map(drop)
+}
+
+/// Install to a block device (or file via loopback), returning the install report.
+#[cfg(feature = "install-to-disk")]
+async fn install_to_disk_impl(mut opts: InstallToDiskOpts) -> Result<report::InstallReport> {
     let mut block_opts = opts.block_opts;
     let target_blockdev_meta = block_opts
         .device
@@ -1547,7 +1955,7 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
         (rootfs, loopback_dev)
     };
 
-    install_to_filesystem_impl(&state, &mut rootfs, Cleanup::Skip).await?;
+    let report = install_to_filesystem_impl(&state, &mut rootfs, Cleanup::Skip).await?;
 
     // Drop all data about the root except the bits we need to ensure any file descriptors etc. are closed.
     let (root_path, luksdev) = rootfs.into_storage();
@@ -1574,7 +1982,7 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
 
     installation_complete();
 
-    Ok(())
+    Ok(report)
 }
 
 #[context("Verifying empty rootfs")]
@@ -1625,6 +2033,26 @@ fn remove_all_in_dir_no_xdev(d: &Dir, mount_err: bool) -> Result<()> {
     anyhow::Ok(())
 }
 
+/// Verify that `rootfs` holds an ostree-based system we can install alongside of,
+/// in a `stateroot` which has no deployments.
+#[context("Verifying target for dual boot")]
+fn require_dual_boot_target(rootfs: &Dir, stateroot: &str) -> Result<()> {
+    if !rootfs.try_exists("ostree/repo")? {
+        anyhow::bail!("Installing for dual boot requires an existing ostree-based system");
+    }
+    let deploydir = format!("ostree/deploy/{stateroot}/deploy");
+    if let Some(d) = rootfs.open_dir_optional(&deploydir)? {
+        for e in d.entries()? {
+            if e?.file_type()?.is_dir() {
+                anyhow::bail!(
+                    "Stateroot {stateroot} has existing deployments; use a distinct --stateroot"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 #[context("Removing boot directory content")]
 fn clean_boot_directories(rootfs: &Dir, is_ostree: bool) -> Result<()> {
     let bootdir =
@@ -1723,6 +2151,88 @@ pub enum Cleanup {
     TriggerOnNextBoot,
 }
 
+/// The subvolumes created by `--btrfs-subvolumes`, with their mount points (the first is the root)
+const BTRFS_SUBVOLUMES: &[(&str, &str)] = &[("root", "/"), ("var", "/var"), ("home", "/var/home")];
+/// Where the root subvolume is mounted during installation
+const BTRFS_ROOT_MOUNT: &str = "/run/bootc/btrfs-root";
+
+/// The mounts of the non-root subvolumes created by `--btrfs-subvolumes`.
+fn btrfs_subvolume_mounts(uuid: &str) -> Vec<MountSpec> {
+    BTRFS_SUBVOLUMES
+        .iter()
+        .skip(1)
+        .map(|(name, target)| {
+            let mut m = MountSpec::new_uuid_src(uuid, target);
+            m.fstype = "btrfs".into();
+            m.push_option(&format!("subvol=/{name}"));
+            m
+        })
+        .collect()
+}
+
+/// The kernel arguments mounting the root subvolume created by `--btrfs-subvolumes`.
+fn btrfs_subvolume_kargs() -> Vec<String> {
+    let (rootvol, _) = BTRFS_SUBVOLUMES[0];
+    vec![format!("rootflags=subvol=/{rootvol}")]
+}
+
+/// Create the subvolumes for `--btrfs-subvolumes` in the top level of a btrfs filesystem,
+/// and mount the root subvolume.  Returns its mount point and the other mounts.
+#[context("Creating btrfs subvolumes")]
+fn create_btrfs_subvolumes(top: &Utf8Path) -> Result<(Utf8PathBuf, Vec<MountSpec>)> {
+    let inspect = bootc_mount::inspect_filesystem(top)?;
+    if inspect.fstype != "btrfs" {
+        anyhow::bail!("Not a btrfs filesystem: {top} ({})", inspect.fstype);
+    }
+    if let Some(subvol) = crate::utils::find_mount_option(&inspect.options, "subvol") {
+        if subvol != "/" {
+            anyhow::bail!("Not the top level subvolume: {top} (subvol={subvol})");
+        }
+    }
+    let uuid = inspect
+        .uuid
+        .as_deref()
+        .ok_or_else(|| anyhow!("No filesystem uuid found in {top}"))?;
+    let top_fd = Dir::open_ambient_dir(top, cap_std::ambient_authority())?;
+    // A separate /boot may be mounted already; it's moved into the root subvolume below.
+    let boot_is_mount = top_fd.is_mountpoint(BOOT)?.unwrap_or_default();
+    for e in top_fd.entries()? {
+        let e = DirEntryUtf8::from_cap_std(e?);
+        let name = e.file_name()?;
+        if !(name == BOOT || name == LOST_AND_FOUND) {
+            anyhow::bail!("Non-empty root filesystem; found {name:?}");
+        }
+    }
+
+    for (name, _) in BTRFS_SUBVOLUMES {
+        let path = top.join(name);
+        Task::new(format!("Creating btrfs subvolume {name}"), "btrfs")
+            .args(["subvolume", "create", path.as_str()])
+            .quiet_output()
+            .run()?;
+    }
+    let (rootvol, _) = BTRFS_SUBVOLUMES[0];
+    let rootvol_path = top.join(rootvol);
+    Task::new("Setting default btrfs subvolume", "btrfs")
+        .args(["subvolume", "set-default", rootvol_path.as_str()])
+        .run()?;
+
+    let target = Utf8PathBuf::from(BTRFS_ROOT_MOUNT);
+    std::fs::create_dir_all(&target)?;
+    Task::new("Mounting root subvolume", "mount")
+        .args(["-o", &format!("subvol=/{rootvol}"), inspect.source.as_str()])
+        .arg(target.as_str())
+        .run()?;
+    let target_boot = target.join(BOOT);
+    std::fs::create_dir(&target_boot)?;
+    if boot_is_mount {
+        Task::new("Mounting /boot", "mount")
+            .args(["--bind", top.join(BOOT).as_str(), target_boot.as_str()])
+            .run()?;
+    }
+    Ok((target, btrfs_subvolume_mounts(uuid)))
+}
+
 /// Implementation of the `bootc install to-filsystem` CLI command.
 #[context("Installing to filesystem")]
 pub(crate) async fn install_to_filesystem(
@@ -1775,6 +2285,15 @@ pub(crate) async fn install_to_filesystem(
         warn_on_host_root(&rootfs_fd)?;
     }
 
+    // Create the subvolumes and continue with the root subvolume as the target
+    let mounts = if fsopts.btrfs_subvolumes {
+        let (root_path, mounts) = create_btrfs_subvolumes(&fsopts.root_path)?;
+        fsopts.root_path = root_path;
+        mounts
+    } else {
+        Vec::new()
+    };
+
     // If we're installing to an ostree root, then find the physical root from
     // the deployment root.
     let possible_physical_root = fsopts.root_path.join("sysroot");
@@ -1809,6 +2328,14 @@ pub(crate) async fn install_to_filesystem(
                 .await??;
         }
         Some(ReplaceMode::Alongside) => clean_boot_directories(&rootfs_fd, is_already_ostree)?,
+        Some(ReplaceMode::DualBoot) => {
+            if matches!(cleanup, Cleanup::TriggerOnNextBoot) {
+                anyhow::bail!(
+                    "Cleanup of the previous installation is incompatible with dual boot"
+                );
+            }
+            require_dual_boot_target(&rootfs_fd, state.stateroot())?
+        }
         None => require_empty_rootdir(&rootfs_fd)?,
     }
 
@@ -1835,6 +2362,7 @@ pub(crate) async fn install_to_filesystem(
             .as_deref()
             .ok_or_else(|| anyhow!("No filesystem uuid found in target root"))?;
         let kargs = match inspect.fstype.as_str() {
+            "btrfs" if fsopts.btrfs_subvolumes => btrfs_subvolume_kargs(),
             "btrfs" => {
                 let subvol = crate::utils::find_mount_option(&inspect.options, "subvol");
                 subvol
@@ -1939,6 +2467,7 @@ pub(crate) async fn install_to_filesystem(
 
     let skip_finalize =
         matches!(fsopts.replace, Some(ReplaceMode::Alongside)) || fsopts.skip_finalize;
+    let dual_boot = matches!(fsopts.replace, Some(ReplaceMode::DualBoot));
     let mut rootfs = RootSetup {
         #[cfg(feature = "install-to-disk")]
         luks_device: None,
@@ -1947,14 +2476,25 @@ pub(crate) async fn install_to_filesystem(
         physical_root: rootfs_fd,
         rootfs_uuid: inspect.uuid.clone(),
         boot,
+        mounts,
         kargs,
         skip_finalize,
+        dual_boot,
+        esp_mirrors: Vec::new(),
     };
 
     install_to_filesystem_impl(&state, &mut rootfs, cleanup).await?;
 
     // Drop all data about the root except the path to ensure any file descriptors etc. are closed.
+    let root_path = rootfs.physical_root_path.clone();
     drop(rootfs);
+    if fsopts.btrfs_subvolumes {
+        Task::new_and_run(
+            "Unmounting root subvolume",
+            "umount",
+            ["-R", root_path.as_str()],
+        )?;
+    }
 
     installation_complete();
 
@@ -1975,6 +2515,7 @@ pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) ->
             replace: opts.replace,
             skip_finalize: true,
             acknowledge_destructive: opts.acknowledge_destructive,
+            btrfs_subvolumes: false,
         },
         source_opts: opts.source_opts,
         target_opts: opts.target_opts,
@@ -2014,6 +2555,35 @@ mod tests {
         assert_eq!(c.block_opts.device, "/dev/vda");
     }
 
+    #[test]
+    fn test_target_imgname() -> Result<()> {
+        let source = |imgref: &str| SourceInfo {
+            imageref: imgref.try_into().unwrap(),
+            digest: None,
+            selinux: false,
+            in_host_mountns: false,
+        };
+        let archive = source("oci-archive:/var/tmp/image.tar:latest");
+        assert!(archive.is_oci_layout());
+        assert_eq!(
+            oci_layout_path(&archive.imageref),
+            Some("/var/tmp/image.tar")
+        );
+        assert_eq!(target_imgname(&archive, None), "/var/tmp/image.tar:latest");
+        assert_eq!(
+            target_imgname(&archive, Some("quay.io/example/os")),
+            "quay.io/example/os"
+        );
+        assert!(source("oci:/var/tmp/layout").is_oci_layout());
+        let named = source("oci:/var/tmp/layout:quay.io/example/os:latest");
+        assert_eq!(target_imgname(&named, None), "quay.io/example/os:latest");
+
+        let registry = source("registry:quay.io/example/os:latest");
+        assert!(!registry.is_oci_layout());
+        assert_eq!(target_imgname(&registry, None), "quay.io/example/os:latest");
+        Ok(())
+    }
+
     #[test]
     fn test_mountspec() {
         let mut ms = MountSpec::new("/dev/vda4", "/boot");
@@ -2024,6 +2594,22 @@ mod tests {
         assert_eq!(ms.to_fstab(), "/dev/vda4 /boot auto ro,relatime 0 0");
     }
 
+    #[test]
+    fn test_btrfs_subvolumes() {
+        assert_eq!(btrfs_subvolume_kargs(), ["rootflags=subvol=/root"]);
+        let mounts = btrfs_subvolume_mounts("2e9f4241-229b-4202-8429-62d2302382e1")
+            .iter()
+            .map(|m| m.to_fstab())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mounts,
+            [
+                "UUID=2e9f4241-229b-4202-8429-62d2302382e1 /var btrfs subvol=/var 0 0",
+                "UUID=2e9f4241-229b-4202-8429-62d2302382e1 /var/home btrfs subvol=/home 0 0"
+            ]
+        );
+    }
+
     #[test]
     fn test_gather_root_args() {
         // A basic filesystem using a UUID
@@ -2067,4 +2653,21 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_require_dual_boot_target() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(require_dual_boot_target(&td, "other").is_err());
+
+        td.create_dir_all("ostree/repo")?;
+        td.create_dir_all("ostree/deploy/default/deploy/abcd.0")?;
+        td.write("ostree/deploy/default/deploy/abcd.0.origin", "")?;
+        require_dual_boot_target(&td, "other")?;
+        assert!(require_dual_boot_target(&td, "default").is_err());
+
+        // A stateroot left over from a failed installation is fine
+        td.create_dir_all("ostree/deploy/other/deploy")?;
+        require_dual_boot_target(&td, "other")?;
+        Ok(())
+    }
 }
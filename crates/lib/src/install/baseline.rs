@@ -11,6 +11,7 @@ use std::fmt::Write as _;
 use std::io::Write;
 use std::process::Command;
 use std::process::Stdio;
+use std::str::FromStr;
 
 use anyhow::Ok;
 use anyhow::{Context, Result};
@@ -24,6 +25,8 @@ use fn_error_context::context;
 use serde::{Deserialize, Serialize};
 
 use super::config::Filesystem;
+#[cfg(feature = "install-to-disk")]
+use super::config::Partitions;
 use super::MountSpec;
 use super::RootSetup;
 use super::State;
@@ -36,6 +39,9 @@ use bootc_mount::is_mounted_in_pid1_mountns;
 // This ensures we end up under 512 to be small-sized.
 pub(crate) const BOOTPN_SIZE_MB: u32 = 510;
 pub(crate) const EFIPN_SIZE_MB: u32 = 512;
+/// Space set aside for the GPT headers and partition alignment.
+#[cfg(feature = "install-to-disk")]
+const GPT_OVERHEAD_MB: u64 = 2;
 /// The GPT type for "linux"
 pub(crate) const LINUX_PARTTYPE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
 #[cfg(feature = "install-to-disk")]
@@ -59,6 +65,55 @@ impl Display for BlockSetup {
     }
 }
 
+/// The size of a partition: either a fixed size, or `rest` for all remaining space on the disk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum PartitionSize {
+    /// A fixed size in MiB
+    Mib(u64),
+    /// All remaining space
+    Rest,
+}
+
+impl FromStr for PartitionSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "rest" {
+            return Ok(Self::Rest);
+        }
+        let v = bootc_blockdev::parse_size_mib(s)
+            .with_context(|| format!("Parsing partition size {s}"))?;
+        if v == 0 {
+            anyhow::bail!("Invalid zero partition size: {s}");
+        }
+        Ok(Self::Mib(v))
+    }
+}
+
+impl TryFrom<String> for PartitionSize {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<PartitionSize> for String {
+    fn from(v: PartitionSize) -> Self {
+        v.to_string()
+    }
+}
+
+impl Display for PartitionSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mib(v) => write!(f, "{v}M"),
+            Self::Rest => f.write_str("rest"),
+        }
+    }
+}
+
 /// Options for installing to a block device
 #[derive(Debug, Clone, clap::Args, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
@@ -83,10 +138,32 @@ pub(crate) struct InstallBlockDeviceOpts {
     pub(crate) filesystem: Option<Filesystem>,
 
     /// Size of the root partition (default specifier: M).  Allowed specifiers: M (mebibytes), G (gibibytes), T (tebibytes).
+    /// The value `rest` uses all remaining space on the disk.
     ///
     /// By default, all remaining space on the disk will be used.
     #[clap(long)]
-    pub(crate) root_size: Option<String>,
+    pub(crate) root_size: Option<PartitionSize>,
+
+    /// Create a separate partition for /var of the given size; uses the same format as `--root-size`.
+    ///
+    /// Use e.g. `--root-size=20G --var-size=rest` to give the remaining space on the disk to /var.
+    #[clap(long)]
+    pub(crate) var_size: Option<PartitionSize>,
+
+    /// Size of the EFI system partition; uses the same format as `--root-size`, except that `rest`
+    /// is not allowed.  The default is 512M.
+    #[clap(long)]
+    pub(crate) esp_size: Option<PartitionSize>,
+
+    /// Additional block device on which to create a mirror of the EFI system partition,
+    /// so that the system can still boot if the target device fails; may be given
+    /// multiple times.  These devices will be wiped, and only contain the ESP.
+    ///
+    /// The mirrors are registered as EFI boot entries, and kept up to date via
+    /// `bootc internals sync-esp`.
+    #[clap(long = "mirror-esp", value_name = "DEVICE")]
+    #[serde(default)]
+    pub(crate) mirror_esp: Vec<Utf8PathBuf>,
 }
 
 impl BlockSetup {
@@ -99,6 +176,64 @@ impl BlockSetup {
     }
 }
 
+/// The resolved partition sizes for `install to-disk`.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PartitionPlan {
+    /// Size of the ESP in MiB, if the architecture uses EFI
+    pub(crate) esp: Option<u64>,
+    /// Size of the root partition
+    pub(crate) root: PartitionSize,
+    /// Size of the /var partition, if one was requested
+    pub(crate) var: Option<PartitionSize>,
+}
+
+/// Resolve the requested partition sizes, and validate them against the capacity
+/// of the disk (in bytes). `reserved` is the total size in MiB of the other
+/// partitions (e.g. BIOS boot and /boot).
+#[cfg(feature = "install-to-disk")]
+pub(crate) fn plan_partitions(
+    disk_size: u64,
+    reserved: u64,
+    uses_efi: bool,
+    requested: &Partitions,
+) -> Result<PartitionPlan> {
+    let esp = match requested.esp_size {
+        Some(PartitionSize::Rest) => anyhow::bail!("The ESP size cannot be `rest`"),
+        Some(PartitionSize::Mib(v)) if uses_efi => Some(v),
+        None if uses_efi => Some(EFIPN_SIZE_MB.into()),
+        // The configuration may be shared across architectures, so ignore this
+        _ => None,
+    };
+    let var = requested.var_size;
+    let root = match (requested.root_size, var) {
+        (Some(PartitionSize::Rest), Some(PartitionSize::Rest)) => {
+            anyhow::bail!("Only one of the root and /var partitions can use the rest of the disk")
+        }
+        (None, Some(PartitionSize::Rest)) => {
+            anyhow::bail!("A root size is required when /var uses the rest of the disk")
+        }
+        (Some(v), _) => v,
+        (None, _) => PartitionSize::Rest,
+    };
+
+    let fixed = [Some(root), var]
+        .into_iter()
+        .flatten()
+        .filter_map(|v| match v {
+            PartitionSize::Mib(v) => Some(v),
+            PartitionSize::Rest => None,
+        })
+        .chain(esp)
+        .fold(reserved + GPT_OVERHEAD_MB, |acc, v| acc.saturating_add(v));
+    let capacity = disk_size / (1024 * 1024);
+    let has_rest = root == PartitionSize::Rest || var == Some(PartitionSize::Rest);
+    if fixed > capacity || (has_rest && fixed == capacity) {
+        anyhow::bail!("Requested partitions ({fixed}MiB) exceed the disk capacity ({capacity}MiB)");
+    }
+    Ok(PartitionPlan { esp, root, var })
+}
+
 #[cfg(feature = "install-to-disk")]
 fn mkfs<'a>(
     dev: &str,
@@ -161,26 +296,11 @@ pub(crate) fn udev_settle() -> Result<()> {
     Ok(())
 }
 
-#[context("Creating rootfs")]
+/// Verify that a target device is not mounted, and that it is empty or else wipe it.
+#[context("Preparing {dev}")]
 #[cfg(feature = "install-to-disk")]
-pub(crate) fn install_create_rootfs(
-    state: &State,
-    opts: InstallBlockDeviceOpts,
-) -> Result<RootSetup> {
-    let install_config = state.install_config.as_ref();
-    let luks_name = "root";
-    // Ensure we have a root filesystem upfront
-    let root_filesystem = opts
-        .filesystem
-        .or(install_config
-            .and_then(|c| c.filesystem_root())
-            .and_then(|r| r.fstype))
-        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
-    // Verify that the target is empty (if not already wiped in particular, but it's
-    // also good to verify that the wipe worked)
-    let device = bootc_blockdev::list_dev(&opts.device)?;
-    // Canonicalize devpath
-    let devpath: Utf8PathBuf = device.path().into();
+fn prepare_device(dev: &Utf8Path, wipe: bool) -> Result<bootc_blockdev::Device> {
+    let device = bootc_blockdev::list_dev(dev)?;
 
     // Always disallow writing to mounted device
     if is_mounted_in_pid1_mountns(&device.path())? {
@@ -188,8 +308,7 @@ pub(crate) fn install_create_rootfs(
     }
 
     // Handle wiping any existing data
-    if opts.wipe {
-        let dev = &opts.device;
+    if wipe {
         for child in device.children.iter().flatten() {
             let child = child.path();
             println!("Wiping {child}");
@@ -199,10 +318,73 @@ pub(crate) fn install_create_rootfs(
         wipefs(dev)?;
     } else if device.has_children() {
         anyhow::bail!(
-            "Detected existing partitions on {}; use e.g. `wipefs` or --wipe if you intend to overwrite",
-            opts.device
+            "Detected existing partitions on {dev}; use e.g. `wipefs` or --wipe if you intend to overwrite"
         );
     }
+    Ok(device)
+}
+
+/// Create a partition table on a device holding only a mirror of the ESP.
+#[context("Creating ESP mirror on {}", device.path())]
+#[cfg(feature = "install-to-disk")]
+fn create_esp_mirror(
+    device: &bootc_blockdev::Device,
+    esp_size: u64,
+) -> Result<bootc_blockdev::PartitionTable> {
+    let devpath = Utf8PathBuf::from(device.path());
+    let capacity = device.size / (1024 * 1024);
+    if esp_size + GPT_OVERHEAD_MB > capacity {
+        anyhow::bail!("The ESP ({esp_size}MiB) exceeds the disk capacity ({capacity}MiB)");
+    }
+    let mut partitioning_buf = String::new();
+    writeln!(partitioning_buf, "label: gpt")?;
+    writeln!(partitioning_buf, "label-id: {}", uuid::Uuid::new_v4())?;
+    writeln!(
+        partitioning_buf,
+        r#"size={esp_size}MiB, type={ESP_GUID}, name="EFI-SYSTEM""#
+    )?;
+    Task::new("Initializing ESP mirror partition", "sfdisk")
+        .arg("--wipe=always")
+        .arg(device.path())
+        .quiet()
+        .run_with_stdin_buf(Some(partitioning_buf.as_bytes()))
+        .context("Failed to run sfdisk")?;
+    udev_settle()?;
+    let partitions = bootc_blockdev::partitions_of(&devpath)?;
+    let espdev = partitions.find_partno(1)?;
+    Task::new("Creating ESP mirror filesystem", "mkfs.fat")
+        .args([espdev.node.as_str(), "-n", "EFI-SYSTEM"])
+        .verbose()
+        .quiet_output()
+        .run()?;
+    Ok(partitions)
+}
+
+#[context("Creating rootfs")]
+#[cfg(feature = "install-to-disk")]
+pub(crate) fn install_create_rootfs(
+    state: &State,
+    opts: InstallBlockDeviceOpts,
+) -> Result<RootSetup> {
+    let install_config = state.install_config.as_ref();
+    let luks_name = "root";
+    // Ensure we have a root filesystem upfront
+    let root_filesystem = opts
+        .filesystem
+        .or(install_config
+            .and_then(|c| c.filesystem_root())
+            .and_then(|r| r.fstype))
+        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
+    // Verify that the target is empty (if not already wiped in particular, but it's
+    // also good to verify that the wipe worked)
+    let device = prepare_device(&opts.device, opts.wipe)?;
+    // Canonicalize devpath
+    let devpath: Utf8PathBuf = device.path().into();
+    let mirror_devices = opts
+        .mirror_esp
+        .iter()
+        .map(|dev| prepare_device(dev, opts.wipe))
+        .collect::<Result<Vec<_>>>()?;
 
     let run_bootc = Utf8Path::new(RUN_BOOTC);
     let mntdir = run_bootc.join("mounts");
@@ -229,12 +411,22 @@ pub(crate) fn install_create_rootfs(
     println!("     Serial: {serial}");
     println!("      Model: {model}");
 
-    let root_size = opts
-        .root_size
-        .as_deref()
-        .map(bootc_blockdev::parse_size_mib)
-        .transpose()
-        .context("Parsing root size")?;
+    // Sizes from the command line take precedence over the install configuration
+    let config_sizes = install_config.and_then(|c| c.partitions.as_ref());
+    let requested_sizes = Partitions {
+        root_size: opts
+            .root_size
+            .or_else(|| config_sizes.and_then(|p| p.root_size)),
+        var_size: opts
+            .var_size
+            .or_else(|| config_sizes.and_then(|p| p.var_size)),
+        esp_size: opts
+            .esp_size
+            .or_else(|| config_sizes.and_then(|p| p.esp_size)),
+    };
+    if requested_sizes.var_size.is_some() && block_setup != BlockSetup::Direct {
+        anyhow::bail!("A separate /var partition is not supported with block setup {block_setup}");
+    }
 
     // Load the policy from the container root, which also must be our install root
     let sepolicy = state.load_policy()?;
@@ -249,12 +441,14 @@ pub(crate) fn install_create_rootfs(
 
     // Generate partitioning spec as input to sfdisk
     let mut partno = 0;
+    let mut reserved_mb = 0u64;
     let mut partitioning_buf = String::new();
     writeln!(partitioning_buf, "label: gpt")?;
     let random_label = uuid::Uuid::new_v4();
     writeln!(&mut partitioning_buf, "label-id: {random_label}")?;
     if cfg!(target_arch = "x86_64") {
         partno += 1;
+        reserved_mb += 1;
         writeln!(
             &mut partitioning_buf,
             r#"size=1MiB, bootable, type=21686148-6449-6E6F-744E-656564454649, name="BIOS-BOOT""#
@@ -262,6 +456,7 @@ pub(crate) fn install_create_rootfs(
     } else if cfg!(target_arch = "powerpc64") {
         // PowerPC-PReP-boot
         partno += 1;
+        reserved_mb += 4;
         let label = PREPBOOT_LABEL;
         let uuid = PREPBOOT_GUID;
         writeln!(
@@ -274,12 +469,26 @@ pub(crate) fn install_create_rootfs(
         anyhow::bail!("Unsupported architecture: {}", std::env::consts::ARCH);
     }
 
-    let esp_partno = if super::ARCH_USES_EFI {
+    if block_setup.requires_bootpart() {
+        reserved_mb += u64::from(BOOTPN_SIZE_MB);
+    }
+    let plan = plan_partitions(
+        device.size,
+        reserved_mb,
+        super::ARCH_USES_EFI,
+        &requested_sizes,
+    )?;
+    tracing::debug!("Partition plan: {plan:?}");
+    if !mirror_devices.is_empty() && plan.esp.is_none() {
+        anyhow::bail!("Mirroring the ESP requires EFI");
+    }
+
+    let esp_partno = if let Some(esp_size) = plan.esp {
         let esp_guid = ESP_GUID;
         partno += 1;
         writeln!(
             &mut partitioning_buf,
-            r#"size={EFIPN_SIZE_MB}MiB, type={esp_guid}, name="EFI-SYSTEM""#
+            r#"size={esp_size}MiB, type={esp_guid}, name="EFI-SYSTEM""#
         )?;
         Some(partno)
     } else {
@@ -299,14 +508,28 @@ pub(crate) fn install_create_rootfs(
     } else {
         None
     };
-    let rootpn = partno + 1;
-    let root_size = root_size
-        .map(|v| Cow::Owned(format!("size={v}MiB, ")))
-        .unwrap_or_else(|| Cow::Borrowed(""));
-    writeln!(
-        &mut partitioning_buf,
-        r#"{root_size}type={LINUX_PARTTYPE}, name="root""#
-    )?;
+    let mut data_partitions = vec![("root", plan.root)];
+    data_partitions.extend(plan.var.map(|v| ("var", v)));
+    // The partition using the rest of the disk must come last
+    data_partitions.sort_by_key(|(_, size)| *size == PartitionSize::Rest);
+    let mut rootpn = 0;
+    let mut var_partno = None;
+    for (name, size) in data_partitions {
+        partno += 1;
+        if name == "var" {
+            var_partno = Some(partno);
+        } else {
+            rootpn = partno;
+        }
+        let size = match size {
+            PartitionSize::Mib(v) => Cow::Owned(format!("size={v}MiB, ")),
+            PartitionSize::Rest => Cow::Borrowed(""),
+        };
+        writeln!(
+            &mut partitioning_buf,
+            r#"{size}type={LINUX_PARTTYPE}, name="{name}""#
+        )?;
+    }
     tracing::debug!("Partitioning: {partitioning_buf}");
     Task::new("Initializing partitions", "sfdisk")
         .arg("--wipe=always")
@@ -326,7 +549,7 @@ pub(crate) fn install_create_rootfs(
     let root_partition = base_partitions.find_partno(rootpn)?;
     if root_partition.parttype.as_str() != LINUX_PARTTYPE {
         anyhow::bail!(
-            "root partition {partno} has type {}; expected {LINUX_PARTTYPE}",
+            "root partition {rootpn} has type {}; expected {LINUX_PARTTYPE}",
             root_partition.parttype.as_str()
         );
     }
@@ -404,6 +627,28 @@ pub(crate) fn install_create_rootfs(
         opts.wipe,
         mkfs_options.iter().copied(),
     )?;
+
+    // Initialize /var, if it's a separate partition
+    let var = if let Some(var_partno) = var_partno {
+        let vardev = base_partitions.find_partno(var_partno)?;
+        let var_uuid = mkfs(
+            vardev.node.as_str(),
+            root_filesystem,
+            "var",
+            opts.wipe,
+            mkfs_options.iter().copied(),
+        )
+        .context("Initializing /var")?;
+        Some(MountSpec {
+            source: format!("UUID={var_uuid}"),
+            target: "/var".into(),
+            fstype: MountSpec::AUTO.into(),
+            options: None,
+        })
+    } else {
+        None
+    };
+
     let rootarg = format!("root=UUID={root_uuid}");
     let bootsrc = boot_uuid.as_ref().map(|uuid| format!("UUID={uuid}"));
     let bootarg = bootsrc.as_deref().map(|bootsrc| format!("boot={bootsrc}"));
@@ -444,6 +689,10 @@ pub(crate) fn install_create_rootfs(
         let efifs_path = bootfs.join(crate::bootloader::EFI_DIR);
         std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
     }
+    let esp_mirrors = mirror_devices
+        .iter()
+        .map(|dev| create_esp_mirror(dev, plan.esp.unwrap_or_default()))
+        .collect::<Result<Vec<_>>>()?;
 
     let luks_device = match block_setup {
         BlockSetup::Direct => None,
@@ -457,7 +706,91 @@ pub(crate) fn install_create_rootfs(
         physical_root,
         rootfs_uuid: Some(root_uuid.to_string()),
         boot,
+        mounts: var.into_iter().collect(),
         kargs,
         skip_finalize: false,
+        dual_boot: false,
+        esp_mirrors,
     })
 }
+
+#[cfg(test)]
+#[cfg(feature = "install-to-disk")]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn test_parse_partition_size() {
+        assert_eq!(
+            "20G".parse::<PartitionSize>().unwrap(),
+            PartitionSize::Mib(20 * 1024)
+        );
+        assert_eq!(
+            "rest".parse::<PartitionSize>().unwrap(),
+            PartitionSize::Rest
+        );
+        assert_eq!(PartitionSize::Mib(512).to_string(), "512M");
+        for invalid in ["", "0", "foo", "10X"] {
+            assert!(invalid.parse::<PartitionSize>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_plan_partitions() {
+        let sizes = |root: Option<&str>, var: Option<&str>, esp: Option<&str>| Partitions {
+            root_size: root.map(|v| v.parse().unwrap()),
+            var_size: var.map(|v| v.parse().unwrap()),
+            esp_size: esp.map(|v| v.parse().unwrap()),
+        };
+        // The defaults
+        let plan = plan_partitions(100 * GIB, 1, true, &Partitions::default()).unwrap();
+        assert_eq!(
+            plan,
+            PartitionPlan {
+                esp: Some(512),
+                root: PartitionSize::Rest,
+                var: None,
+            }
+        );
+        // The ESP size is ignored without EFI
+        let plan = plan_partitions(100 * GIB, 1, false, &sizes(None, None, Some("1G"))).unwrap();
+        assert_eq!(plan.esp, None);
+        let plan = plan_partitions(
+            100 * GIB,
+            1,
+            true,
+            &sizes(Some("20G"), Some("rest"), Some("1G")),
+        )
+        .unwrap();
+        assert_eq!(
+            plan,
+            PartitionPlan {
+                esp: Some(1024),
+                root: PartitionSize::Mib(20 * 1024),
+                var: Some(PartitionSize::Rest),
+            }
+        );
+        let plan = plan_partitions(100 * GIB, 1, true, &sizes(None, Some("10G"), None)).unwrap();
+        assert_eq!(plan.root, PartitionSize::Rest);
+        assert_eq!(plan.var, Some(PartitionSize::Mib(10 * 1024)));
+
+        for (disk, root, var, esp) in [
+            // Too large
+            (10 * GIB, Some("20G"), None, None),
+            (20 * GIB, Some("10G"), Some("10G"), None),
+            // Nothing left for /var
+            (10 * GIB, Some("10G"), Some("rest"), None),
+            // Invalid combinations
+            (100 * GIB, Some("rest"), Some("rest"), None),
+            (100 * GIB, None, Some("rest"), None),
+            (100 * GIB, None, None, Some("rest")),
+        ] {
+            assert!(
+                plan_partitions(disk, 1, true, &sizes(root, var, esp)).is_err(),
+                "{root:?} {var:?} {esp:?}"
+            );
+        }
+    }
+}
@@ -14,6 +14,7 @@ use ostree_ext::{gio, ostree};
 use rustix::fs::Mode;
 use rustix::fs::OFlags;
 
+use crate::kernel_cmdline::CmdlineBuilder;
 use crate::utils::deployment_fd;
 
 use super::config;
@@ -54,7 +55,6 @@ fn reconcile_kargs(sysroot: &ostree::Sysroot, deployment: &ostree::Deployment) -
         .map(|s| s.as_str())
         .unwrap_or_default();
     tracing::debug!("current_kargs={current_kargs}");
-    let current_kargs = ostree::KernelArgs::from_string(&current_kargs);
 
     // Keep this in sync with install_container
     let install_config = config::load_config()?;
@@ -65,12 +65,19 @@ fn reconcile_kargs(sysroot: &ostree::Sysroot, deployment: &ostree::Deployment) -
         .flatten()
         .map(|s| s.as_str())
         .collect::<Vec<_>>();
-    let kargsd = crate::bootc_kargs::get_kargs_in_root(deployment_root, std::env::consts::ARCH)?;
-    let kargsd = kargsd.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    // Anaconda installs onto the machine it is running on, so hardware matches
+    // in kargs.d are evaluated against it
+    let kargsd = crate::bootc_kargs::get_kargs_in_root(
+        deployment_root,
+        &crate::bootc_kargs::HostProperties::for_install(false),
+    )?;
 
-    current_kargs.append_argv(&install_config_kargs);
-    current_kargs.append_argv(&kargsd);
-    let new_kargs = current_kargs.to_string();
+    let mut new_kargs = CmdlineBuilder::new();
+    new_kargs
+        .extend_cmdline(current_kargs)
+        .extend(install_config_kargs)
+        .extend(&kargsd);
+    let new_kargs = new_kargs.to_string();
     tracing::debug!("new_kargs={new_kargs}");
 
     sysroot.deployment_set_kargs_in_place(deployment, Some(&new_kargs), cancellable)?;
@@ -299,7 +306,7 @@ pub(crate) async fn impl_completion(
         // having a hard dependency on it.
         let imgstorage =
             &crate::imgstorage::Storage::create(&sysroot_dir, &rundir, sepolicy.as_ref())?;
-        crate::boundimage::pull_images_impl(imgstorage, bound_images)
+        crate::boundimage::pull_images_impl(imgstorage, bound_images, None)
             .await
             .context("pulling bound images")?;
     }
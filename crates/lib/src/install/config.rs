@@ -8,7 +8,7 @@ use fn_error_context::context;
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "install-to-disk")]
-use super::baseline::BlockSetup;
+use super::baseline::{BlockSetup, PartitionSize};
 
 /// Properties of the environment, such as the system architecture
 /// Left open for future properties such as `platform.id`
@@ -31,6 +31,16 @@ impl std::fmt::Display for Filesystem {
     }
 }
 
+/// The bootloader to install.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Bootloader {
+    /// Install via bootupd (GRUB, with shim on EFI systems)
+    Bootupd,
+    /// Install systemd-boot into the ESP, and keep the boot entries synchronized there
+    SystemdBoot,
+}
+
 /// The toplevel config entry for installation configs stored
 /// in bootc/install (e.g. /etc/bootc/install/05-custom.toml)
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -58,6 +68,33 @@ pub(crate) struct BasicFilesystems {
     // pub(crate) esp: Option<FilesystemCustomization>,
 }
 
+/// Partition sizes for `bootc install to-disk`; see `--root-size` etc.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Partitions {
+    pub(crate) root_size: Option<PartitionSize>,
+    pub(crate) var_size: Option<PartitionSize>,
+    pub(crate) esp_size: Option<PartitionSize>,
+}
+
+/// GRUB hardening, written to `/boot/grub2/user.cfg` when GRUB is installed.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct GrubConfig {
+    /// The GRUB superuser; defaults to `root`
+    pub(crate) superuser: Option<String>,
+    /// A PBKDF2 password hash, as generated by `grub2-mkpasswd-pbkdf2`.  Setting this
+    /// requires the password to edit menu entries or use the GRUB shell.
+    pub(crate) password: Option<String>,
+    /// Menu timeout in seconds
+    pub(crate) timeout: Option<u32>,
+    /// Hide the menu unless a key is pressed during the timeout
+    pub(crate) hide_menu: Option<bool>,
+    /// Fall back to the previous deployment if a new one fails to boot repeatedly
+    pub(crate) boot_counting: Option<bool>,
+}
+
 /// The serialized [install] section
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename = "install", rename_all = "kebab-case", deny_unknown_fields)]
@@ -68,11 +105,24 @@ pub(crate) struct InstallConfiguration {
     #[cfg(feature = "install-to-disk")]
     pub(crate) block: Option<Vec<BlockSetup>>,
     pub(crate) filesystem: Option<BasicFilesystems>,
+    /// Partition sizes for `to-disk`
+    #[cfg(feature = "install-to-disk")]
+    pub(crate) partitions: Option<Partitions>,
     /// Kernel arguments, applied at installation time
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) kargs: Option<Vec<String>>,
     /// Supported architectures for this configuration
     pub(crate) match_architectures: Option<Vec<String>>,
+    /// The bootloader to install; defaults to bootupd (or zipl on s390x)
+    pub(crate) bootloader: Option<Bootloader>,
+    /// GRUB password and menu configuration
+    pub(crate) grub: Option<GrubConfig>,
+    /// Timezone of the installed system, e.g. `Europe/Berlin`
+    pub(crate) timezone: Option<String>,
+    /// Locale (`LANG`) of the installed system, e.g. `en_US.UTF-8`
+    pub(crate) locale: Option<String>,
+    /// Virtual console keymap of the installed system, e.g. `us`
+    pub(crate) keymap: Option<String>,
 }
 
 fn merge_basic<T>(s: &mut Option<T>, o: Option<T>, _env: &EnvProperties) {
@@ -119,6 +169,27 @@ impl Mergeable for BasicFilesystems {
     }
 }
 
+#[cfg(feature = "install-to-disk")]
+impl Mergeable for Partitions {
+    /// Apply any values in other, overriding any existing values in `self`.
+    fn merge(&mut self, other: Self, env: &EnvProperties) {
+        merge_basic(&mut self.root_size, other.root_size, env);
+        merge_basic(&mut self.var_size, other.var_size, env);
+        merge_basic(&mut self.esp_size, other.esp_size, env);
+    }
+}
+
+impl Mergeable for GrubConfig {
+    /// Apply any values in other, overriding any existing values in `self`.
+    fn merge(&mut self, other: Self, env: &EnvProperties) {
+        merge_basic(&mut self.superuser, other.superuser, env);
+        merge_basic(&mut self.password, other.password, env);
+        merge_basic(&mut self.timeout, other.timeout, env);
+        merge_basic(&mut self.hide_menu, other.hide_menu, env);
+        merge_basic(&mut self.boot_counting, other.boot_counting, env);
+    }
+}
+
 impl Mergeable for InstallConfiguration {
     /// Apply any values in other, overriding any existing values in `self`.
     fn merge(&mut self, other: Self, env: &EnvProperties) {
@@ -133,6 +204,13 @@ impl Mergeable for InstallConfiguration {
             #[cfg(feature = "install-to-disk")]
             merge_basic(&mut self.block, other.block, env);
             self.filesystem.merge(other.filesystem, env);
+            #[cfg(feature = "install-to-disk")]
+            self.partitions.merge(other.partitions, env);
+            merge_basic(&mut self.bootloader, other.bootloader, env);
+            self.grub.merge(other.grub, env);
+            merge_basic(&mut self.timezone, other.timezone, env);
+            merge_basic(&mut self.locale, other.locale, env);
+            merge_basic(&mut self.keymap, other.keymap, env);
             if let Some(other_kargs) = other.kargs {
                 self.kargs
                     .get_or_insert_with(Default::default)
@@ -172,6 +250,9 @@ impl InstallConfiguration {
     // Remove all configuration which is handled by `install to-filesystem`.
     pub(crate) fn filter_to_external(&mut self) {
         self.kargs.take();
+        self.timezone.take();
+        self.locale.take();
+        self.keymap.take();
     }
 
     #[cfg(feature = "install-to-disk")]
@@ -305,6 +386,149 @@ kargs = ["console=ttyS0", "foo=bar"]
         )
     }
 
+    #[test]
+    fn test_parse_grub() {
+        let env = EnvProperties {
+            sys_arch: "x86_64".to_string(),
+        };
+        let c: InstallConfigurationToplevel = toml::from_str(
+            r##"[install.grub]
+password = "grub.pbkdf2.sha512.10000.AA.BB"
+timeout = 5
+"##,
+        )
+        .unwrap();
+        let mut install = c.install.unwrap();
+        install.merge(
+            InstallConfiguration {
+                grub: Some(GrubConfig {
+                    timeout: Some(0),
+                    hide_menu: Some(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            &env,
+        );
+        assert_eq!(
+            install.grub.unwrap(),
+            GrubConfig {
+                superuser: None,
+                password: Some("grub.pbkdf2.sha512.10000.AA.BB".into()),
+                timeout: Some(0),
+                hide_menu: Some(true),
+                boot_counting: None,
+            }
+        );
+        assert!(toml::from_str::<InstallConfigurationToplevel>(
+            "[install.grub]\npasswd = \"foo\"\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_system_settings() {
+        let env = EnvProperties {
+            sys_arch: "x86_64".to_string(),
+        };
+        let c: InstallConfigurationToplevel = toml::from_str(
+            r##"[install]
+timezone = "UTC"
+keymap = "us"
+"##,
+        )
+        .unwrap();
+        let mut install = c.install.unwrap();
+        install.merge(
+            InstallConfiguration {
+                timezone: Some("Europe/Berlin".into()),
+                locale: Some("de_DE.UTF-8".into()),
+                ..Default::default()
+            },
+            &env,
+        );
+        assert_eq!(install.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(install.locale.as_deref(), Some("de_DE.UTF-8"));
+        assert_eq!(install.keymap.as_deref(), Some("us"));
+        install.filter_to_external();
+        assert!(install.timezone.is_none() && install.locale.is_none() && install.keymap.is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "install-to-disk")]
+    fn test_parse_partitions() {
+        let env = EnvProperties {
+            sys_arch: "x86_64".to_string(),
+        };
+        let c: InstallConfigurationToplevel = toml::from_str(
+            r##"[install.partitions]
+root-size = "20G"
+var-size = "rest"
+"##,
+        )
+        .unwrap();
+        let mut install = c.install.unwrap();
+        let partitions = install.partitions.as_ref().unwrap();
+        assert_eq!(partitions.root_size, Some(PartitionSize::Mib(20 * 1024)));
+        assert_eq!(partitions.var_size, Some(PartitionSize::Rest));
+        install.merge(
+            InstallConfiguration {
+                partitions: Some(Partitions {
+                    esp_size: Some(PartitionSize::Mib(1024)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            &env,
+        );
+        assert_eq!(
+            install.partitions.unwrap(),
+            Partitions {
+                root_size: Some(PartitionSize::Mib(20 * 1024)),
+                var_size: Some(PartitionSize::Rest),
+                esp_size: Some(PartitionSize::Mib(1024)),
+            }
+        );
+        assert!(toml::from_str::<InstallConfigurationToplevel>(
+            r##"[install.partitions]
+root-size = "lots"
+"##
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_bootloader() {
+        let env = EnvProperties {
+            sys_arch: "x86_64".to_string(),
+        };
+        let c: InstallConfigurationToplevel = toml::from_str(
+            r##"[install]
+bootloader = "systemd-boot"
+"##,
+        )
+        .unwrap();
+        let mut install = c.install.unwrap();
+        assert_eq!(install.bootloader, Some(Bootloader::SystemdBoot));
+        // Unset values don't override
+        install.merge(Default::default(), &env);
+        assert_eq!(install.bootloader, Some(Bootloader::SystemdBoot));
+        install.merge(
+            InstallConfiguration {
+                bootloader: Some(Bootloader::Bootupd),
+                ..Default::default()
+            },
+            &env,
+        );
+        assert_eq!(install.bootloader, Some(Bootloader::Bootupd));
+        assert!(toml::from_str::<InstallConfigurationToplevel>(
+            r##"[install]
+bootloader = "lilo"
+"##
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_parse_filesystems() {
         let env = EnvProperties {
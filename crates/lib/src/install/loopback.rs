@@ -0,0 +1,279 @@
+//! # Producing disk image artifacts
+//!
+//! `bootc install to-loopback-image` wraps `bootc install to-disk --via-loopback`:
+//! it allocates a sparse raw file, installs to it, optionally converts the result
+//! to qcow2 (via `qemu-img`) and compresses it, and writes a JSON manifest
+//! describing the produced artifact.
+
+use std::fs::File;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use super::baseline::{InstallBlockDeviceOpts, PartitionSize};
+use super::config::Filesystem;
+use super::report::InstallReport;
+use super::{InstallConfigOpts, InstallSourceOpts, InstallTargetOpts, InstallToDiskOpts};
+use crate::task::Task;
+
+/// The suffix of the default manifest path
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// The format of the produced disk image.
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ImageFormat {
+    /// A raw disk image
+    #[default]
+    Raw,
+    /// A qcow2 disk image
+    Qcow2,
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, clap::Parser, PartialEq, Eq)]
+pub(crate) struct InstallToLoopbackImageOpts {
+    /// The path of the disk image to create; it must not exist.
+    pub(crate) output: Utf8PathBuf,
+
+    /// The size of the disk (default specifier: M).  Allowed specifiers: M (mebibytes),
+    /// G (gibibytes), T (tebibytes).
+    #[clap(long, default_value = "10G", value_parser = parse_disk_size)]
+    pub(crate) size: u64,
+
+    /// The format of the disk image.
+    #[clap(long, value_enum, default_value_t)]
+    pub(crate) format: ImageFormat,
+
+    /// Compress the disk image: qcow2 images use qcow2 compression, and raw images
+    /// are compressed with zstd.
+    #[clap(long)]
+    pub(crate) compress: bool,
+
+    /// Where to write the JSON manifest describing the disk image; defaults to the
+    /// output path with a `.manifest.json` suffix.
+    #[clap(long)]
+    pub(crate) manifest: Option<Utf8PathBuf>,
+
+    /// Target root filesystem type.
+    #[clap(long, value_enum)]
+    pub(crate) filesystem: Option<Filesystem>,
+
+    /// Size of the root partition; see `bootc install to-disk --root-size`.
+    #[clap(long)]
+    pub(crate) root_size: Option<PartitionSize>,
+
+    /// Create a separate partition for /var; see `bootc install to-disk --var-size`.
+    #[clap(long)]
+    pub(crate) var_size: Option<PartitionSize>,
+
+    #[clap(flatten)]
+    pub(crate) source_opts: InstallSourceOpts,
+
+    #[clap(flatten)]
+    pub(crate) target_opts: InstallTargetOpts,
+
+    #[clap(flatten)]
+    pub(crate) config_opts: InstallConfigOpts,
+}
+
+/// Parse a disk size into MiB.
+fn parse_disk_size(s: &str) -> Result<u64> {
+    let v = bootc_blockdev::parse_size_mib(s).with_context(|| format!("Parsing size {s}"))?;
+    if v == 0 {
+        anyhow::bail!("Invalid zero size: {s}");
+    }
+    Ok(v)
+}
+
+/// The manifest describing a produced disk image.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct ArtifactManifest {
+    /// The file name of the disk image
+    path: String,
+    format: ImageFormat,
+    /// The compression applied, if any
+    compression: Option<&'static str>,
+    /// The size of the disk image file in bytes
+    size: u64,
+    /// The size of the disk in bytes
+    virtual_size: u64,
+    /// The SHA-256 of the disk image file
+    sha256: String,
+    /// Pull spec of the installed image
+    image: Option<String>,
+    /// The manifest digest of the installed image
+    digest: Option<String>,
+    /// The version of the installed image
+    version: Option<String>,
+}
+
+fn default_manifest_path(output: &Utf8Path) -> Utf8PathBuf {
+    format!("{output}{MANIFEST_SUFFIX}").into()
+}
+
+/// The compression used for the given format, if requested.
+fn compression(format: ImageFormat, compress: bool) -> Option<&'static str> {
+    match (format, compress) {
+        (_, false) => None,
+        (ImageFormat::Raw, true) => Some("zstd"),
+        (ImageFormat::Qcow2, true) => Some("qcow2"),
+    }
+}
+
+fn sha256_file(path: &Utf8Path) -> Result<String> {
+    let mut f = File::open(path).with_context(|| format!("Opening {path}"))?;
+    let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())?;
+    std::io::copy(&mut f, &mut hasher).with_context(|| format!("Reading {path}"))?;
+    Ok(hex::encode(&*hasher.finish()?))
+}
+
+/// Convert or compress the installed raw disk image into the output artifact.
+fn convert(raw: &Utf8Path, output: &Utf8Path, format: ImageFormat, compress: bool) -> Result<()> {
+    match format {
+        ImageFormat::Qcow2 => Task::new("Converting to qcow2", "qemu-img")
+            .args(["convert", "-f", "raw", "-O", "qcow2"])
+            .args(compress.then_some("-c"))
+            .args([raw.as_str(), output.as_str()])
+            .run(),
+        ImageFormat::Raw if compress => Task::new("Compressing", "zstd")
+            .args(["-q", "-T0", raw.as_str(), "-o", output.as_str()])
+            .run(),
+        ImageFormat::Raw => Ok(()),
+    }
+}
+
+/// Implementation of the `bootc install to-loopback-image` CLI command.
+#[context("Installing to disk image")]
+pub(crate) async fn install_to_loopback_image(opts: InstallToLoopbackImageOpts) -> Result<()> {
+    let InstallToLoopbackImageOpts {
+        output,
+        size,
+        format,
+        compress,
+        manifest,
+        filesystem,
+        root_size,
+        var_size,
+        source_opts,
+        target_opts,
+        config_opts,
+    } = opts;
+    if output.symlink_metadata().is_ok() {
+        anyhow::bail!("Output already exists: {output}");
+    }
+    let virtual_size = size
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| anyhow::anyhow!("Invalid size: {size}M"))?;
+    // A plain raw image is installed to directly, otherwise we install to a
+    // temporary file alongside the output.
+    let direct = format == ImageFormat::Raw && !compress;
+    let raw = if direct {
+        output.clone()
+    } else {
+        Utf8PathBuf::from(format!("{output}.tmp.raw"))
+    };
+    File::create_new(&raw)
+        .and_then(|f| f.set_len(virtual_size))
+        .with_context(|| format!("Allocating {raw}"))?;
+
+    let disk_opts = InstallToDiskOpts {
+        block_opts: InstallBlockDeviceOpts {
+            device: raw.clone(),
+            wipe: true,
+            block_setup: None,
+            filesystem,
+            root_size,
+            var_size,
+            esp_size: None,
+            mirror_esp: Vec::new(),
+        },
+        source_opts,
+        target_opts,
+        config_opts,
+        via_loopback: true,
+    };
+    let r = match super::install_to_disk_impl(disk_opts).await {
+        Ok(report) => convert(&raw, &output, format, compress).map(|()| report),
+        Err(e) => Err(e),
+    };
+    if !direct || r.is_err() {
+        let _ = std::fs::remove_file(&raw);
+    }
+    let report: InstallReport = match r {
+        Ok(r) => r,
+        Err(e) => {
+            let _ = std::fs::remove_file(&output);
+            return Err(e);
+        }
+    };
+
+    let manifest_path = manifest.unwrap_or_else(|| default_manifest_path(&output));
+    let manifest = ArtifactManifest {
+        path: output.file_name().unwrap_or(output.as_str()).to_owned(),
+        format,
+        compression: compression(format, compress),
+        size: std::fs::metadata(&output)?.len(),
+        virtual_size,
+        sha256: sha256_file(&output)?,
+        image: report.image,
+        digest: report.digest,
+        version: report.version,
+    };
+    let contents = serde_json::to_string_pretty(&manifest)?;
+    std::fs::write(&manifest_path, format!("{contents}\n"))
+        .with_context(|| format!("Writing {manifest_path}"))?;
+    println!("Wrote: {output}");
+    println!("Wrote: {manifest_path}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifact_manifest() -> Result<()> {
+        assert_eq!(parse_disk_size("10G")?, 10240);
+        assert!(parse_disk_size("0").is_err());
+        assert!(parse_disk_size("rest").is_err());
+        assert_eq!(
+            default_manifest_path("out/disk.qcow2".into()),
+            "out/disk.qcow2.manifest.json"
+        );
+        assert_eq!(compression(ImageFormat::Raw, false), None);
+        assert_eq!(compression(ImageFormat::Raw, true), Some("zstd"));
+        assert_eq!(compression(ImageFormat::Qcow2, true), Some("qcow2"));
+
+        let td = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(td.path()).unwrap().join("disk.raw");
+        std::fs::write(&path, b"hello")?;
+        assert_eq!(
+            sha256_file(&path)?,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        let v = serde_json::to_value(ArtifactManifest {
+            path: "disk.raw".into(),
+            format: ImageFormat::Qcow2,
+            compression: None,
+            size: 5,
+            virtual_size: 10,
+            sha256: String::new(),
+            image: None,
+            digest: None,
+            version: None,
+        })?;
+        assert_eq!(v["format"], "qcow2");
+        assert_eq!(v["virtual-size"], 10);
+        Ok(())
+    }
+}
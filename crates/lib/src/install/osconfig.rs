@@ -1,5 +1,5 @@
 use std::borrow::Cow;
-use std::io::Write;
+use std::io::{Read, Write};
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
@@ -10,6 +10,434 @@ use ostree_ext::ostree;
 
 const ETC_TMPFILES: &str = "etc/tmpfiles.d";
 const ROOT_SSH_TMPFILE: &str = "bootc-root-ssh.conf";
+/// Where we drop public keys used by the containers signature policy.
+const ETC_PKI_CONTAINERS: &str = "etc/pki/containers";
+/// Where we drop GPG keys used for ostree remotes.
+const ETC_PKI_OSTREE: &str = "etc/pki/ostree";
+const CONTAINERS_POLICY: &str = "etc/containers/policy.json";
+const OSTREE_REMOTES_D: &str = "etc/ostree/remotes.d";
+const ETC_LOCALTIME: &str = "etc/localtime";
+const ZONEINFO: &str = "usr/share/zoneinfo";
+const LOCALE_CONF: &str = "etc/locale.conf";
+const VCONSOLE_CONF: &str = "etc/vconsole.conf";
+/// The cloud-init NoCloud seed directory, relative to the stateroot (i.e. in `/var`).
+const NOCLOUD_SEED: &str = "var/lib/cloud/seed/nocloud";
+/// The NoCloud `meta-data`; cloud-init requires an instance ID.
+const NOCLOUD_META_DATA: &str = "instance-id: iid-bootc-install\n";
+/// Where Ignition reads a config provided on the boot partition.
+const BOOT_IGNITION: &str = "boot/ignition";
+const IGNITION_CONFIG: &str = "config.ign";
+/// Requests that Ignition runs on the next boot; the bootloader configuration of
+/// the image must add the `ignition.firstboot` kernel argument if it exists, and
+/// `ignition-firstboot-complete.service` removes it.
+const IGNITION_FIRSTBOOT: &str = "boot/ignition.firstboot";
+
+/// Basic localization settings for the target root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct SystemSettings {
+    /// A timezone name from the tz database, e.g. `Europe/Berlin`
+    pub(crate) timezone: Option<String>,
+    /// The `LANG` of the system locale, e.g. `en_US.UTF-8`
+    pub(crate) locale: Option<String>,
+    /// The virtual console keymap, e.g. `de-nodeadkeys`
+    pub(crate) keymap: Option<String>,
+}
+
+impl SystemSettings {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.timezone.is_none() && self.locale.is_none() && self.keymap.is_none()
+    }
+
+    /// Reject values which cannot be written into an environment-style
+    /// configuration file or the zoneinfo path.
+    pub(crate) fn validate(&self) -> Result<()> {
+        for (name, v) in [
+            ("timezone", &self.timezone),
+            ("locale", &self.locale),
+            ("keymap", &self.keymap),
+        ] {
+            let Some(v) = v.as_deref() else {
+                continue;
+            };
+            if v.is_empty()
+                || v.starts_with(['/', '.', '-'])
+                || v.split('/').any(|c| c == "..")
+                || v.chars().any(|c| {
+                    !(c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '-' | '_' | '+' | '@'))
+                })
+            {
+                anyhow::bail!("Invalid {name}: {v:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set `key` in the contents of an environment-style file (such as `/etc/locale.conf`),
+/// replacing any existing assignment and preserving other lines.
+fn set_env_var(contents: &str, key: &str, value: &str) -> String {
+    let mut r = String::new();
+    let mut found = false;
+    for line in contents.lines() {
+        let is_key = line.split_once('=').is_some_and(|(k, _)| k.trim() == key);
+        if is_key {
+            if found {
+                continue;
+            }
+            found = true;
+            r.push_str(&format!("{key}={value}"));
+        } else {
+            r.push_str(line);
+        }
+        r.push('\n');
+    }
+    if !found {
+        r.push_str(&format!("{key}={value}\n"));
+    }
+    r
+}
+
+fn update_env_file(
+    root: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+    path: &str,
+    key: &str,
+    value: &str,
+) -> Result<()> {
+    let mut orig = String::new();
+    if let Some(mut f) = root.open_optional(path)? {
+        f.read_to_string(&mut orig)
+            .with_context(|| format!("Reading {path}"))?;
+    }
+    let contents = set_env_var(&orig, key, value);
+    crate::lsm::atomic_replace_labeled(root, path, 0o644.into(), sepolicy, |w| {
+        w.write_all(contents.as_bytes()).map_err(Into::into)
+    })?;
+    println!("Updated: {path}");
+    Ok(())
+}
+
+/// Configure the timezone, locale and console keymap of the target root,
+/// as `systemd-firstboot` would: `/etc/localtime`, `/etc/locale.conf`
+/// and `/etc/vconsole.conf`.
+#[context("Configuring system settings")]
+pub(crate) fn inject_system_settings(
+    root: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+    settings: &SystemSettings,
+) -> Result<()> {
+    settings.validate()?;
+    if let Some(tz) = settings.timezone.as_deref() {
+        let zonefile = format!("{ZONEINFO}/{tz}");
+        if !root.try_exists(&zonefile)? {
+            anyhow::bail!("Unknown timezone {tz}: {zonefile} not found in target");
+        }
+        root.remove_file_optional(ETC_LOCALTIME)?;
+        root.symlink(format!("../{zonefile}"), ETC_LOCALTIME)
+            .with_context(|| format!("Creating {ETC_LOCALTIME}"))?;
+        if let Some(policy) = sepolicy {
+            let path = Utf8Path::new(ETC_LOCALTIME);
+            let meta = root.symlink_metadata(path)?;
+            crate::lsm::relabel(root, &meta, path, None, policy)?;
+        }
+        println!("Updated: {ETC_LOCALTIME}");
+    }
+    if let Some(locale) = settings.locale.as_deref() {
+        update_env_file(root, sepolicy, LOCALE_CONF, "LANG", locale)?;
+    }
+    if let Some(keymap) = settings.keymap.as_deref() {
+        update_env_file(root, sepolicy, VCONSOLE_CONF, "KEYMAP", keymap)?;
+    }
+    Ok(())
+}
+
+/// The kind of trust store a provided public key should be enrolled into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrustedKeyType {
+    /// A sigstore public key for the containers signature policy
+    Sigstore,
+    /// A GPG public key for the containers signature policy
+    Gpg,
+    /// A GPG public key for an ostree remote
+    OstreeRemoteGpg,
+}
+
+/// A public key to enroll into the target root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TrustedKey {
+    pub(crate) ty: TrustedKeyType,
+    /// For container keys, the registry scope (e.g. `quay.io/example`); for
+    /// ostree keys, the remote name.
+    pub(crate) scope: String,
+    /// The raw key data
+    pub(crate) contents: Vec<u8>,
+}
+
+impl TrustedKey {
+    /// Parse a command line argument of the form `SCOPE=PATH`, reading
+    /// the key contents from the provided path.
+    pub(crate) fn from_arg(ty: TrustedKeyType, arg: &str) -> Result<Self> {
+        let (scope, path) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected SCOPE=PATH, found: {arg}"))?;
+        if scope.is_empty() {
+            anyhow::bail!("Empty scope in trusted key argument: {arg}");
+        }
+        if ty == TrustedKeyType::OstreeRemoteGpg && scope.contains('/') {
+            anyhow::bail!("Invalid ostree remote name: {scope}");
+        }
+        let contents = std::fs::read(path).with_context(|| format!("Reading {path}"))?;
+        Ok(Self {
+            ty,
+            scope: scope.to_owned(),
+            contents,
+        })
+    }
+
+    /// The filename used for this key in its trust store directory.
+    fn filename(&self) -> String {
+        let name = self.scope.replace(['/', ':'], "_");
+        match self.ty {
+            TrustedKeyType::Sigstore => format!("bootc-{name}.pub"),
+            TrustedKeyType::Gpg | TrustedKeyType::OstreeRemoteGpg => format!("bootc-{name}.gpg"),
+        }
+    }
+}
+
+/// Add a requirement for the given key to the containers signature policy.
+fn policy_add_key(policy: &mut serde_json::Value, key: &TrustedKey, keypath: &str) -> Result<()> {
+    let requirement = match key.ty {
+        TrustedKeyType::Sigstore => serde_json::json!({
+            "type": "sigstoreSigned",
+            "keyPath": keypath,
+            "signedIdentity": { "type": "matchRepository" },
+        }),
+        TrustedKeyType::Gpg => serde_json::json!({
+            "type": "signedBy",
+            "keyType": "GPGKeys",
+            "keyPath": keypath,
+        }),
+        TrustedKeyType::OstreeRemoteGpg => {
+            anyhow::bail!(
+                "ostree remote key {} is not a container policy key",
+                key.scope
+            )
+        }
+    };
+    let policy = policy
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Expected object in {CONTAINERS_POLICY}"))?;
+    let transports = policy
+        .entry("transports")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Expected object for transports"))?;
+    let docker = transports
+        .entry("docker")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Expected object for docker transport"))?;
+    let scope = docker
+        .entry(key.scope.as_str())
+        .or_insert_with(|| serde_json::json!([]))
+        .as_array_mut()
+        .ok_or_else(|| anyhow::anyhow!("Expected array for scope {}", key.scope))?;
+    // Enrolling a key implies signatures are required, so drop any
+    // blanket acceptance for this scope.
+    scope.retain(|v| v.get("type").and_then(|v| v.as_str()) != Some("insecureAcceptAnything"));
+    if !scope.contains(&requirement) {
+        scope.push(requirement);
+    }
+    Ok(())
+}
+
+/// Write the provided public keys into the relevant trust stores of the target root:
+/// the containers signature policy (`/etc/containers/policy.json`) for container
+/// image keys, and the ostree remote configuration for ostree remote keys.
+#[context("Enrolling trusted keys")]
+pub(crate) fn inject_trusted_keys(
+    root: &Dir,
+    sepolicy: Option<&ostree::SePolicy>,
+    keys: &[TrustedKey],
+) -> Result<()> {
+    let (ostree_keys, container_keys): (Vec<_>, Vec<_>) = keys
+        .iter()
+        .partition(|k| k.ty == TrustedKeyType::OstreeRemoteGpg);
+
+    // Load and validate all ostree remote configurations up front, so that
+    // an unknown remote doesn't leave the target with a partially updated policy.
+    let ostree_keys = ostree_keys
+        .into_iter()
+        .map(|key| {
+            let remote = key.scope.as_str();
+            let confpath = format!("{OSTREE_REMOTES_D}/{remote}.conf");
+            let mut conf = String::new();
+            root.open_optional(&confpath)?
+                .ok_or_else(|| anyhow::anyhow!("No ostree remote {remote} found in target"))?
+                .read_to_string(&mut conf)
+                .with_context(|| format!("Reading {confpath}"))?;
+            let kf = ostree::glib::KeyFile::new();
+            kf.load_from_data(&conf, ostree::glib::KeyFileFlags::KEEP_COMMENTS)
+                .with_context(|| format!("Parsing {confpath}"))?;
+            let group = format!("remote \"{remote}\"");
+            if !kf.has_group(&group) {
+                anyhow::bail!("No ostree remote {remote} found in {confpath}");
+            }
+            Ok((key, confpath, group, kf))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if !container_keys.is_empty() {
+        let mut policy: serde_json::Value = match root.open_optional(CONTAINERS_POLICY)? {
+            Some(f) => serde_json::from_reader(std::io::BufReader::new(f))
+                .with_context(|| format!("Parsing {CONTAINERS_POLICY}"))?,
+            None => serde_json::json!({ "default": [{ "type": "insecureAcceptAnything" }] }),
+        };
+        crate::lsm::ensure_dir_labeled(root, "etc/pki", None, 0o755.into(), sepolicy)?;
+        crate::lsm::ensure_dir_labeled(root, ETC_PKI_CONTAINERS, None, 0o755.into(), sepolicy)?;
+        for key in container_keys {
+            let path = format!("{ETC_PKI_CONTAINERS}/{}", key.filename());
+            crate::lsm::atomic_replace_labeled(root, &path, 0o644.into(), sepolicy, |w| {
+                w.write_all(&key.contents).map_err(Into::into)
+            })?;
+            policy_add_key(&mut policy, key, &format!("/{path}"))?;
+            println!("Injected: {path}");
+        }
+        crate::lsm::ensure_dir_labeled(root, "etc/containers", None, 0o755.into(), sepolicy)?;
+        crate::lsm::atomic_replace_labeled(root, CONTAINERS_POLICY, 0o644.into(), sepolicy, |w| {
+            serde_json::to_writer_pretty(&mut *w, &policy)?;
+            w.write_all(b"\n").map_err(Into::into)
+        })?;
+        println!("Updated: {CONTAINERS_POLICY}");
+    }
+
+    if !ostree_keys.is_empty() {
+        crate::lsm::ensure_dir_labeled(root, "etc/pki", None, 0o755.into(), sepolicy)?;
+        crate::lsm::ensure_dir_labeled(root, ETC_PKI_OSTREE, None, 0o755.into(), sepolicy)?;
+        for (key, confpath, group, kf) in ostree_keys {
+            let path = format!("{ETC_PKI_OSTREE}/{}", key.filename());
+            crate::lsm::atomic_replace_labeled(root, &path, 0o644.into(), sepolicy, |w| {
+                w.write_all(&key.contents).map_err(Into::into)
+            })?;
+            kf.set_string(&group, "gpgkeypath", &format!("/{path}"));
+            kf.set_boolean(&group, "gpg-verify", true);
+            crate::lsm::atomic_replace_labeled(root, &confpath, 0o644.into(), sepolicy, |w| {
+                w.write_all(kf.to_data().as_bytes()).map_err(Into::into)
+            })?;
+            println!("Injected: {path}");
+        }
+    }
+
+    Ok(())
+}
+
+/// A first boot provisioning configuration passed through to the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ProvisioningFile {
+    /// cloud-init user-data, written as a NoCloud seed in `/var`
+    CloudInit(String),
+    /// An Ignition config, written to `/boot/ignition/config.ign`, along
+    /// with the `/boot/ignition.firstboot` stamp
+    Ignition(String),
+}
+
+impl ProvisioningFile {
+    /// Detect and validate the format of a provisioning file.
+    pub(crate) fn parse(contents: String) -> Result<Self> {
+        if let Some(body) = contents.strip_prefix("#cloud-config") {
+            if !body.is_empty() && !body.starts_with(['\n', '\r']) {
+                anyhow::bail!("Invalid cloud-config header");
+            }
+            let v: serde_yaml::Value =
+                serde_yaml::from_str(body).context("Parsing cloud-config")?;
+            if !(v.is_mapping() || v.is_null()) {
+                anyhow::bail!("Invalid cloud-config: expected a mapping");
+            }
+            return Ok(Self::CloudInit(contents));
+        }
+        // Other user-data formats with a header, such as shell scripts
+        if contents.starts_with("#!") {
+            return Ok(Self::CloudInit(contents));
+        }
+        let v: serde_json::Value = serde_json::from_str(&contents).map_err(|_| {
+            anyhow::anyhow!(
+                "Unrecognized provisioning file; expected cloud-init user-data or an Ignition config"
+            )
+        })?;
+        let version = v
+            .get("ignition")
+            .and_then(|v| v.get("version"))
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid Ignition config: missing ignition.version"))?;
+        tracing::debug!("Found Ignition config version {version}");
+        Ok(Self::Ignition(contents))
+    }
+}
+
+/// Create `path` and its parents, labeling any new directories.
+fn ensure_dirs_labeled(root: &Dir, path: &str, sepolicy: Option<&ostree::SePolicy>) -> Result<()> {
+    let mut ancestors = Utf8Path::new(path).ancestors().collect::<Vec<_>>();
+    ancestors.reverse();
+    for d in ancestors.into_iter().filter(|d| !d.as_str().is_empty()) {
+        if !root.try_exists(d)? {
+            crate::lsm::ensure_dir_labeled(root, d, None, 0o755.into(), sepolicy)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a provisioning file where the corresponding first boot tool finds it;
+/// `physical_root` is the target root filesystem, with `/boot` mounted.
+#[context("Injecting provisioning file")]
+pub(crate) fn inject_provisioning_file(
+    physical_root: &Dir,
+    stateroot: &str,
+    sepolicy: Option<&ostree::SePolicy>,
+    provisioning: &ProvisioningFile,
+) -> Result<()> {
+    match provisioning {
+        ProvisioningFile::CloudInit(contents) => {
+            // The stateroot directory is the root for `/var`, so labels are looked up
+            // for the paths as seen in the booted system.
+            let root = physical_root
+                .open_dir(format!("ostree/deploy/{stateroot}"))
+                .with_context(|| format!("Opening stateroot {stateroot}"))?;
+            ensure_dirs_labeled(&root, NOCLOUD_SEED, sepolicy)?;
+            let files = [
+                ("user-data", contents.as_str(), 0o600),
+                ("meta-data", NOCLOUD_META_DATA, 0o644),
+            ];
+            for (name, contents, mode) in files {
+                let path = format!("{NOCLOUD_SEED}/{name}");
+                crate::lsm::atomic_replace_labeled(&root, &path, mode.into(), sepolicy, |w| {
+                    w.write_all(contents.as_bytes()).map_err(Into::into)
+                })?;
+                println!("Injected: /{path}");
+            }
+        }
+        ProvisioningFile::Ignition(contents) => {
+            ensure_dirs_labeled(physical_root, BOOT_IGNITION, sepolicy)?;
+            let path = format!("{BOOT_IGNITION}/{IGNITION_CONFIG}");
+            crate::lsm::atomic_replace_labeled(
+                physical_root,
+                &path,
+                0o600.into(),
+                sepolicy,
+                |w| w.write_all(contents.as_bytes()).map_err(Into::into),
+            )?;
+            println!("Injected: /{path}");
+            crate::lsm::atomic_replace_labeled(
+                physical_root,
+                IGNITION_FIRSTBOOT,
+                0o644.into(),
+                sepolicy,
+                |_| Ok(()),
+            )?;
+            println!("Injected: /{IGNITION_FIRSTBOOT}");
+        }
+    }
+    Ok(())
+}
 
 #[context("Injecting root authorized_keys")]
 pub(crate) fn inject_root_ssh_authorized_keys(
@@ -54,6 +482,193 @@ pub(crate) fn inject_root_ssh_authorized_keys(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_provisioning_file() -> Result<()> {
+        let cloudconfig = "#cloud-config\nusers:\n  - name: admin\n";
+        assert_eq!(
+            ProvisioningFile::parse(cloudconfig.into())?,
+            ProvisioningFile::CloudInit(cloudconfig.into())
+        );
+        let script = "#!/bin/sh\necho hello\n";
+        assert!(matches!(
+            ProvisioningFile::parse(script.into())?,
+            ProvisioningFile::CloudInit(_)
+        ));
+        let ign = r#"{"ignition": {"version": "3.4.0"}}"#;
+        assert_eq!(
+            ProvisioningFile::parse(ign.into())?,
+            ProvisioningFile::Ignition(ign.into())
+        );
+        for invalid in [
+            "",
+            "users: []",
+            "#cloud-configx\n",
+            "#cloud-config\n- foo\n",
+            "#cloud-config\nusers: [\n",
+            r#"{"version": "3.4.0"}"#,
+        ] {
+            assert!(
+                ProvisioningFile::parse(invalid.into()).is_err(),
+                "{invalid}"
+            );
+        }
+
+        let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        root.create_dir_all("ostree/deploy/default/var")?;
+        let p = ProvisioningFile::parse(cloudconfig.into())?;
+        inject_provisioning_file(root, "default", None, &p)?;
+        assert_eq!(
+            root.read_to_string("ostree/deploy/default/var/lib/cloud/seed/nocloud/user-data")?,
+            cloudconfig
+        );
+        assert!(root.try_exists("ostree/deploy/default/var/lib/cloud/seed/nocloud/meta-data")?);
+        let p = ProvisioningFile::parse(ign.into())?;
+        inject_provisioning_file(root, "default", None, &p)?;
+        assert_eq!(root.read_to_string("boot/ignition/config.ign")?, ign);
+        assert!(root.try_exists("boot/ignition.firstboot")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inject_trusted_keys() -> Result<()> {
+        let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        root.create_dir_all("etc/containers")?;
+        root.write(
+            CONTAINERS_POLICY,
+            r#"{"default":[{"type":"reject"}],"transports":{"docker":{"quay.io/example":[{"type":"insecureAcceptAnything"}]}}}"#,
+        )?;
+        root.create_dir_all(OSTREE_REMOTES_D)?;
+        root.write(
+            format!("{OSTREE_REMOTES_D}/myremote.conf"),
+            "[remote \"myremote\"]\nurl=https://example.com/repo\n",
+        )?;
+        let keys = [
+            TrustedKey {
+                ty: TrustedKeyType::Sigstore,
+                scope: "quay.io/example".into(),
+                contents: b"sigstore key".to_vec(),
+            },
+            TrustedKey {
+                ty: TrustedKeyType::OstreeRemoteGpg,
+                scope: "myremote".into(),
+                contents: b"gpg key".to_vec(),
+            },
+        ];
+        inject_trusted_keys(root, None, &keys).unwrap();
+        // Idempotence
+        inject_trusted_keys(root, None, &keys).unwrap();
+
+        assert_eq!(
+            root.read("etc/pki/containers/bootc-quay.io_example.pub")?,
+            b"sigstore key"
+        );
+        let policy: serde_json::Value = serde_json::from_slice(&root.read(CONTAINERS_POLICY)?)?;
+        similar_asserts::assert_eq!(
+            policy,
+            serde_json::json!({
+                "default": [{ "type": "reject" }],
+                "transports": { "docker": { "quay.io/example": [{
+                    "type": "sigstoreSigned",
+                    "keyPath": "/etc/pki/containers/bootc-quay.io_example.pub",
+                    "signedIdentity": { "type": "matchRepository" },
+                }]}}
+            })
+        );
+
+        assert_eq!(root.read("etc/pki/ostree/bootc-myremote.gpg")?, b"gpg key");
+        let conf = root.read_to_string(format!("{OSTREE_REMOTES_D}/myremote.conf"))?;
+        assert!(conf.contains("gpgkeypath=/etc/pki/ostree/bootc-myremote.gpg"));
+        assert!(conf.contains("url=https://example.com/repo"));
+
+        // An unknown remote is an error
+        let unknown = TrustedKey {
+            ty: TrustedKeyType::OstreeRemoteGpg,
+            scope: "unknown".into(),
+            contents: b"gpg key".to_vec(),
+        };
+        assert!(inject_trusted_keys(root, None, &[unknown]).is_err());
+
+        // A config file without the matching remote group is an error too, and
+        // nothing is written for the other keys
+        root.write(
+            format!("{OSTREE_REMOTES_D}/other.conf"),
+            "[remote \"misnamed\"]\nurl=https://example.com/repo\n",
+        )?;
+        let policy_before = root.read(CONTAINERS_POLICY)?;
+        let keys = [
+            TrustedKey {
+                ty: TrustedKeyType::Gpg,
+                scope: "quay.io/other".into(),
+                contents: b"gpg key".to_vec(),
+            },
+            TrustedKey {
+                ty: TrustedKeyType::OstreeRemoteGpg,
+                scope: "other".into(),
+                contents: b"gpg key".to_vec(),
+            },
+        ];
+        assert!(inject_trusted_keys(root, None, &keys).is_err());
+        assert_eq!(root.read(CONTAINERS_POLICY)?, policy_before);
+        assert!(!root.try_exists("etc/pki/containers/bootc-quay.io_other.gpg")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_env_var() {
+        assert_eq!(set_env_var("", "LANG", "C.UTF-8"), "LANG=C.UTF-8\n");
+        assert_eq!(
+            set_env_var(
+                "# comment\nKEYMAP=us\nFONT=eurlatgr\nKEYMAP=fr\n",
+                "KEYMAP",
+                "de"
+            ),
+            "# comment\nKEYMAP=de\nFONT=eurlatgr\n"
+        );
+        assert_eq!(
+            set_env_var("FONT=eurlatgr", "KEYMAP", "de"),
+            "FONT=eurlatgr\nKEYMAP=de\n"
+        );
+    }
+
+    #[test]
+    fn test_inject_system_settings() -> Result<()> {
+        let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        root.create_dir_all("etc")?;
+        root.create_dir_all("usr/share/zoneinfo/Europe")?;
+        root.write("usr/share/zoneinfo/Europe/Berlin", "")?;
+        root.symlink("../usr/share/zoneinfo/UTC", ETC_LOCALTIME)?;
+        root.write(VCONSOLE_CONF, "KEYMAP=us\nFONT=eurlatgr\n")?;
+        let settings = SystemSettings {
+            timezone: Some("Europe/Berlin".into()),
+            locale: Some("de_DE.UTF-8".into()),
+            keymap: Some("de-nodeadkeys".into()),
+        };
+        inject_system_settings(root, None, &settings)?;
+        assert_eq!(
+            root.read_link(ETC_LOCALTIME)?.to_str().unwrap(),
+            "../usr/share/zoneinfo/Europe/Berlin"
+        );
+        assert_eq!(root.read_to_string(LOCALE_CONF)?, "LANG=de_DE.UTF-8\n");
+        assert_eq!(
+            root.read_to_string(VCONSOLE_CONF)?,
+            "KEYMAP=de-nodeadkeys\nFONT=eurlatgr\n"
+        );
+
+        let unknown = SystemSettings {
+            timezone: Some("Mars/Olympus_Mons".into()),
+            ..Default::default()
+        };
+        assert!(inject_system_settings(root, None, &unknown).is_err());
+        for invalid in ["../../etc/shadow", "en_US\nFOO=bar", "", "a b"] {
+            let s = SystemSettings {
+                locale: Some(invalid.into()),
+                ..Default::default()
+            };
+            assert!(s.validate().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_inject_root_ssh_symlinked() -> Result<()> {
         let root = &cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
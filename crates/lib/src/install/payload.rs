@@ -0,0 +1,277 @@
+//! # Installing from a local payload directory
+//!
+//! For offline installs (e.g. from installation media via Anaconda) the
+//! images can be provided in a directory instead of a registry or container
+//! storage:
+//!
+//! - `image/`: An OCI image layout holding the bootc image
+//! - `bound-images/`: An optional OCI image layout holding the logically bound
+//!   images, each manifest annotated (as `org.opencontainers.image.ref.name`)
+//!   with the image reference used in its `.image` or `.container` file
+//!
+//! Every blob is verified against its digest before anything is installed.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::oci_spec::image::{Descriptor, ImageIndex, ANNOTATION_REF_NAME};
+
+use crate::boundimage::BoundImage;
+
+/// The OCI image layout holding the bootc image
+pub(crate) const IMAGE: &str = "image";
+/// The OCI image layout holding the logically bound images
+pub(crate) const BOUND_IMAGES: &str = "bound-images";
+const BLOBS: &str = "blobs/sha256";
+
+/// Verify every blob in an OCI image layout against its digest, and that
+/// every manifest in the index is present.
+fn verify_layout(d: &Dir) -> Result<ImageIndex> {
+    let blobs = d
+        .open_dir(BLOBS)
+        .with_context(|| format!("Opening {BLOBS}"))?;
+    for ent in blobs.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid blob name: {name:?}"))?;
+        let found = crate::update_bundle::sha256_hex(blobs.open(name)?)?;
+        anyhow::ensure!(
+            found == name,
+            "Blob sha256:{name} has digest sha256:{found}"
+        );
+    }
+    let index: ImageIndex = serde_json::from_reader(std::io::BufReader::new(
+        d.open("index.json").context("Opening index.json")?,
+    ))
+    .context("Parsing index.json")?;
+    for manifest in index.manifests() {
+        let digest = manifest.digest();
+        anyhow::ensure!(
+            digest.algorithm().to_string() == "sha256",
+            "Unsupported digest algorithm: {digest}"
+        );
+        anyhow::ensure!(
+            blobs.try_exists(digest.digest())?,
+            "Missing manifest {digest}"
+        );
+    }
+    Ok(index)
+}
+
+/// The image reference a manifest is annotated with, if any.
+fn ref_name(desc: &Descriptor) -> Option<&str> {
+    desc.annotations()
+        .as_ref()
+        .and_then(|a| a.get(ANNOTATION_REF_NAME))
+        .map(|s| s.as_str())
+}
+
+/// A verified payload directory.
+#[derive(Debug)]
+pub(crate) struct Payload {
+    path: Utf8PathBuf,
+    /// The image reference the bootc image is annotated with, if any
+    pub(crate) image_ref: Option<String>,
+    /// The manifest digest of the bootc image
+    pub(crate) image_digest: String,
+    /// The bound images in the payload, mapped to their manifest digest
+    bound_images: HashMap<String, String>,
+}
+
+impl Payload {
+    /// Open and verify the payload at `path`.
+    #[context("Opening payload {path}")]
+    pub(crate) fn open(path: &Utf8Path) -> Result<Self> {
+        // The path is used in `oci:<path>:<ref>` references
+        anyhow::ensure!(!path.as_str().contains(':'), "Invalid path: {path}");
+        let path = path.canonicalize_utf8()?;
+        let d = Dir::open_ambient_dir(&path, cap_std::ambient_authority())?;
+        let image = d
+            .open_dir(IMAGE)
+            .with_context(|| format!("Opening {IMAGE}"))?;
+        let index = verify_layout(&image).with_context(|| format!("Verifying {IMAGE}"))?;
+        let [manifest] = index.manifests().as_slice() else {
+            anyhow::bail!(
+                "Expected exactly one manifest in {IMAGE}, found {}",
+                index.manifests().len()
+            );
+        };
+        let image_ref = ref_name(manifest).map(ToOwned::to_owned);
+        let image_digest = manifest.digest().to_string();
+        let bound_images = match d.open_dir_optional(BOUND_IMAGES)? {
+            Some(bound) => verify_layout(&bound)
+                .with_context(|| format!("Verifying {BOUND_IMAGES}"))?
+                .manifests()
+                .iter()
+                .map(|m| {
+                    let name = ref_name(m).ok_or_else(|| {
+                        anyhow::anyhow!("Manifest {} has no image reference", m.digest())
+                    })?;
+                    Ok((name.to_owned(), m.digest().to_string()))
+                })
+                .collect::<Result<_>>()?,
+            None => Default::default(),
+        };
+        Ok(Self {
+            path,
+            image_ref,
+            image_digest,
+            bound_images,
+        })
+    }
+
+    /// The reference to the bootc image, for use as the install source.
+    pub(crate) fn source_imgref(&self) -> String {
+        format!("oci:{}/{IMAGE}", self.path)
+    }
+
+    /// Find each bound image in the payload, returning the image reference
+    /// to copy from along with the image name. For images pinned by digest,
+    /// the digest in the payload must match.
+    pub(crate) fn resolve_bound_images(
+        &self,
+        images: &[BoundImage],
+    ) -> Result<Vec<(String, String)>> {
+        images
+            .iter()
+            .map(|image| {
+                let name = image.image.as_str();
+                let normalized = crate::boundimage::normalize_image_name(name);
+                let (refname, digest) = [name, normalized.as_ref()]
+                    .into_iter()
+                    .find_map(|n| self.bound_images.get_key_value(n))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Bound image {name} is not in {}/{BOUND_IMAGES}", self.path)
+                    })?;
+                if let Some((_, pinned)) = name.split_once('@') {
+                    anyhow::ensure!(
+                        digest == pinned,
+                        "Bound image {name} has digest {digest} in the payload"
+                    );
+                }
+                let src = format!("oci:{}/{BOUND_IMAGES}:{refname}", self.path);
+                Ok((src, name.to_owned()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use cap_std_ext::cap_tempfile;
+
+    /// Write a minimal OCI layout with a manifest for each name.
+    fn write_layout(d: &Dir, names: &[Option<&str>]) -> Result<Vec<String>> {
+        d.create_dir_all(BLOBS)?;
+        let mut manifests = Vec::new();
+        let mut digests = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let buf = format!("{{\"schemaVersion\":2,\"n\":{i}}}");
+            let digest = hex::encode(openssl::sha::sha256(buf.as_bytes()));
+            d.write(format!("{BLOBS}/{digest}"), &buf)?;
+            let annotations = name
+                .map(|n| format!(",\"annotations\":{{\"{ANNOTATION_REF_NAME}\":\"{n}\"}}"))
+                .unwrap_or_default();
+            manifests.push(format!(
+                "{{\"mediaType\":\"application/vnd.oci.image.manifest.v1+json\",\"digest\":\"sha256:{digest}\",\"size\":{}{annotations}}}",
+                buf.len()
+            ));
+            digests.push(format!("sha256:{digest}"));
+        }
+        let mut index = d.create("index.json")?;
+        write!(
+            index,
+            "{{\"schemaVersion\":2,\"manifests\":[{}]}}",
+            manifests.join(",")
+        )?;
+        Ok(digests)
+    }
+
+    fn bound(image: &str) -> BoundImage {
+        BoundImage {
+            image: image.into(),
+            auth_file: None,
+        }
+    }
+
+    #[test]
+    fn test_payload() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let path = td.path().join("payload");
+        let path = Utf8Path::from_path(&path).unwrap();
+        td.create_dir_all("payload/image")?;
+        let [image_digest] = write_layout(
+            &td.open_dir("payload/image")?,
+            &[Some("quay.io/example/os:latest")],
+        )?
+        .try_into()
+        .unwrap();
+        // No bound images
+        let payload = Payload::open(path)?;
+        assert_eq!(
+            payload.image_ref.as_deref(),
+            Some("quay.io/example/os:latest")
+        );
+        assert_eq!(payload.image_digest, image_digest);
+        assert_eq!(payload.source_imgref(), format!("oci:{path}/image"));
+        assert!(payload.resolve_bound_images(&[]).unwrap().is_empty());
+        assert!(payload
+            .resolve_bound_images(&[bound("quay.io/example/app")])
+            .is_err());
+
+        td.create_dir_all("payload/bound-images")?;
+        let digests = write_layout(
+            &td.open_dir("payload/bound-images")?,
+            &[
+                Some("quay.io/example/app:latest"),
+                Some("quay.io/example/db"),
+            ],
+        )?;
+        let payload = Payload::open(path)?;
+        let resolved = payload.resolve_bound_images(&[
+            bound("quay.io/example/app"),
+            bound(&format!("quay.io/example/db@{}", digests[1])),
+        ])?;
+        assert_eq!(
+            resolved[0],
+            (
+                format!("oci:{path}/bound-images:quay.io/example/app:latest"),
+                "quay.io/example/app".to_owned()
+            )
+        );
+        // A pinned image needs a matching digest
+        assert!(payload
+            .resolve_bound_images(&[bound(&format!("quay.io/example/db@{}", digests[0]))])
+            .is_err());
+
+        // Corrupted blobs are detected
+        let blob = digests[0].trim_start_matches("sha256:");
+        td.write(format!("payload/bound-images/{BLOBS}/{blob}"), "corrupt")?;
+        assert!(Payload::open(path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_invalid() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let path = Utf8Path::from_path(td.path()).unwrap();
+        // No image
+        assert!(Payload::open(path).is_err());
+        // Multiple images
+        td.create_dir_all("image")?;
+        write_layout(&td.open_dir("image")?, &[None, None])?;
+        assert!(Payload::open(path).is_err());
+        assert!(Payload::open(Utf8Path::new("/foo:bar")).is_err());
+        Ok(())
+    }
+}
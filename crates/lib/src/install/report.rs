@@ -0,0 +1,166 @@
+//! # A machine-readable summary of a completed installation
+//!
+//! This is written to `/run/bootc/install-report.json` once an installation
+//! has finished, so that provisioning tools can pick up e.g. the partition
+//! UUIDs and the image digest without scraping the console output.
+
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use serde::Serialize;
+
+use super::RUN_BOOTC;
+
+/// The file name of the install report in [`RUN_BOOTC`]
+const INSTALL_REPORT: &str = "install-report.json";
+
+/// How the bootloader was set up.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ReportBootloader {
+    /// Installed via bootupd
+    Bootupd,
+    /// Installed via `bootctl install`
+    SystemdBoot,
+    /// Installed via zipl (s390x)
+    Zipl,
+    /// The existing bootloader was preserved (dual boot)
+    Preserved,
+}
+
+/// A partition of the target block device.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ReportPartition {
+    pub(crate) node: String,
+    #[serde(rename = "type")]
+    pub(crate) parttype: String,
+    pub(crate) uuid: Option<String>,
+    pub(crate) name: Option<String>,
+}
+
+impl From<&bootc_blockdev::Partition> for ReportPartition {
+    fn from(p: &bootc_blockdev::Partition) -> Self {
+        Self {
+            node: p.node.clone(),
+            parttype: p.parttype.clone(),
+            uuid: p.uuid.clone(),
+            name: p.name.clone(),
+        }
+    }
+}
+
+/// The duration of a phase of the installation.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ReportPhase {
+    pub(crate) name: &'static str,
+    pub(crate) seconds: f64,
+}
+
+/// The machine-readable install report.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct InstallReport {
+    /// Pull spec of the installed image
+    pub(crate) image: Option<String>,
+    /// The manifest digest of the installed image
+    pub(crate) digest: Option<String>,
+    /// The version of the installed image
+    pub(crate) version: Option<String>,
+    pub(crate) stateroot: String,
+    /// The kernel arguments of the deployment
+    pub(crate) kargs: Vec<String>,
+    pub(crate) bootloader: Option<ReportBootloader>,
+    /// The state of SELinux at install time
+    pub(crate) selinux: &'static str,
+    /// The target block device
+    pub(crate) device: String,
+    pub(crate) partitions: Vec<ReportPartition>,
+    /// The filesystem UUID of the root filesystem
+    pub(crate) root_uuid: Option<String>,
+    /// The filesystem UUID used to find `/boot`
+    pub(crate) boot_uuid: String,
+    /// The phases of the installation, in order
+    pub(crate) phases: Vec<ReportPhase>,
+    pub(crate) total_seconds: f64,
+    #[serde(skip)]
+    started: Instant,
+    #[serde(skip)]
+    last: Instant,
+}
+
+impl InstallReport {
+    /// Create a new report for an installation which started at `started`.
+    pub(crate) fn new(started: Instant) -> Self {
+        Self {
+            image: None,
+            digest: None,
+            version: None,
+            stateroot: String::new(),
+            kargs: Vec::new(),
+            bootloader: None,
+            selinux: "",
+            device: String::new(),
+            partitions: Vec::new(),
+            root_uuid: None,
+            boot_uuid: String::new(),
+            phases: Vec::new(),
+            total_seconds: 0.0,
+            started,
+            last: started,
+        }
+    }
+
+    /// Record the end of a phase, which started at the end of the previous one.
+    pub(crate) fn finish_phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push(ReportPhase {
+            name,
+            seconds: now.duration_since(self.last).as_secs_f64(),
+        });
+        self.last = now;
+        self.total_seconds = now.duration_since(self.started).as_secs_f64();
+    }
+
+    /// Write the report to `/run/bootc`, and print it if requested.
+    #[context("Writing install report")]
+    pub(crate) fn write(&self, print: bool) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        std::fs::create_dir_all(RUN_BOOTC)?;
+        let path = format!("{RUN_BOOTC}/{INSTALL_REPORT}");
+        std::fs::write(&path, format!("{contents}\n"))
+            .with_context(|| format!("Writing {path}"))?;
+        if print {
+            println!("{contents}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = InstallReport::new(Instant::now());
+        report.finish_phase("deploy");
+        report.finish_phase("bootloader");
+        assert_eq!(
+            report.phases.iter().map(|p| p.name).collect::<Vec<_>>(),
+            ["deploy", "bootloader"]
+        );
+        let total: f64 = report.phases.iter().map(|p| p.seconds).sum();
+        assert!((report.total_seconds - total).abs() < 1e-6);
+
+        report.bootloader = Some(ReportBootloader::SystemdBoot);
+        report.root_uuid = Some("1234".into());
+        let v = serde_json::to_value(&report).unwrap();
+        assert_eq!(v["bootloader"], "systemd-boot");
+        assert_eq!(v["root-uuid"], "1234");
+        assert_eq!(v["phases"][0]["name"], "deploy");
+        assert!(v.get("started").is_none());
+    }
+}
@@ -1,9 +1,46 @@
 //! Thin wrapper for systemd journaling; these APIs are no-ops
 //! when not running under systemd.  Only use them when
+//!
+//! The major deployment lifecycle events are logged with the stable `MESSAGE_ID`s
+//! below (documented in `systemd/bootc.catalog`) and structured fields identifying
+//! the deployment and image, so that they can be queried with e.g.
+//! `journalctl MESSAGE_ID=... BOOTC_MANIFEST_DIGEST=...`.
 
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 
+use ostree_ext::container as ostree_container;
+use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::ostree;
+
+/// A new deployment was staged for the next boot.
+pub(crate) const STAGED_ID: &str = "0d5f2a8c3e7b4a91b6c4e1f9a2d7c385";
+/// A reboot into the staged deployment was initiated.
+pub(crate) const FINALIZING_ID: &str = "6a1c9e4f7b2d4c08a5e3f8b1d6c2e947";
+/// The rollback deployment was queued for the next boot.
+pub(crate) const ROLLBACK_ID: &str = "26f3b1eb24464d12aa5e7b544a6b5468";
+/// Unreferenced images were garbage collected.
+pub(crate) const GC_ID: &str = "b3e7d1a5c9f24e6b8d0a4c2f7e1b5d93";
+/// A rollback was queued automatically because a monitored unit failed.
+pub(crate) const AUTO_ROLLBACK_ID: &str = "9e3b7d2c5a1f4e8bb6d0c4a7f1e2d3b9";
+/// The bootloader fell back from a deployment which failed to boot.
+pub(crate) const BOOT_FALLBACK_ID: &str = "4c8e1f5a9b2d4e7f8a3c6b1d0e9f2a75";
+/// A deployment older than the booted one was staged.
+pub(crate) const DOWNGRADE_ID: &str = "5b8e3f0a9c6d4e21b7f4a2c8d1e9f603";
+/// A pre-flight check failed.
+pub(crate) const CHECK_FAILED_ID: &str = "c7b1e1a0f2a44f4f9d2f8e4d4e7a6c51";
+/// `/etc` and `/var` were relabeled after a policy change.
+pub(crate) const RELABEL_ID: &str = "4c8e1f6a2b9d4e0f8a7c3d5b6e1f2a90";
+/// A new stateroot was switched to.
+pub(crate) const STATEROOT_ID: &str = "f4a9d2c6e8b14e2f9a3b7c1d5e6f8a20";
+
+/// The digest of the image manifest.
+pub(crate) const FIELD_MANIFEST_DIGEST: &str = "BOOTC_MANIFEST_DIGEST";
+/// The deployment, as identified in `/ostree/bootc/lifecycle.json`.
+pub(crate) const FIELD_DEPLOYMENT_ID: &str = "BOOTC_DEPLOYMENT_ID";
+/// The image reference.
+pub(crate) const FIELD_IMAGE: &str = "BOOTC_IMAGE";
+
 /// Set to true if we failed to write to the journal once
 static EMITTED_JOURNAL_ERROR: AtomicBool = AtomicBool::new(false);
 
@@ -34,3 +71,61 @@ pub(crate) fn journal_print(priority: libsystemd::logging::Priority, msg: &str)
     let vars: HashMap<&str, &str> = HashMap::new();
     journal_send(priority, msg, vars.into_iter())
 }
+
+/// The structured fields identifying a deployment: its ID, and the image
+/// digest and reference if it is container based.
+pub(crate) fn deployment_fields(
+    repo: &ostree::Repo,
+    deployment: &ostree::Deployment,
+) -> Vec<(&'static str, String)> {
+    let mut r = vec![(
+        FIELD_DEPLOYMENT_ID,
+        crate::lifecycle::deployment_id(deployment),
+    )];
+    if let Ok(state) = ostree_container::store::query_image_commit(repo, &deployment.csum()) {
+        r.push((FIELD_MANIFEST_DIGEST, state.manifest_digest.to_string()));
+    }
+    let image = deployment
+        .origin()
+        .and_then(|o| {
+            o.optional_string("origin", ostree_container::deploy::ORIGIN_CONTAINER)
+                .ok()
+        })
+        .flatten();
+    if let Some(image) = image {
+        r.push((FIELD_IMAGE, image.to_string()));
+    }
+    r
+}
+
+/// Log a lifecycle event with the given `MESSAGE_ID` and structured fields.
+pub(crate) fn journal_event(
+    priority: libsystemd::logging::Priority,
+    id: &str,
+    msg: &str,
+    fields: &[(&'static str, String)],
+) {
+    let vars = [("MESSAGE_ID", id)]
+        .into_iter()
+        .chain(fields.iter().map(|(k, v)| (*k, v.as_str())));
+    journal_send(priority, msg, vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_ids() {
+        let ids = [STAGED_ID, FINALIZING_ID, ROLLBACK_ID, GC_ID];
+        for id in ids {
+            assert_eq!(id.len(), 32, "{id}");
+            assert!(
+                id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')),
+                "{id}"
+            );
+        }
+        let unique = ids.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(unique.len(), ids.len());
+    }
+}
@@ -2,8 +2,10 @@
 //!
 //! This module provides functionality for parsing and working with kernel command line
 //! arguments, supporting both key-only switches and key-value pairs with proper quote handling.
+//! [`CmdlineBuilder`] merges kernel arguments from multiple sources.
 
 use std::borrow::Cow;
+use std::fmt::Display;
 
 use anyhow::Result;
 
@@ -11,6 +13,29 @@ use anyhow::Result;
 pub(crate) const INITRD_ARG_PREFIX: &str = "rd.";
 /// The kernel argument for configuring the rootfs flags.
 pub(crate) const ROOTFLAGS: &str = "rootflags";
+/// Keys which are meaningful when repeated with different values (e.g. one
+/// `console=` per output device), so every distinct value is kept when merging.
+const MULTI_VALUE_KEYS: &[&str] = &[
+    "console",
+    "ip",
+    "nameserver",
+    "rd.route",
+    "rd.luks.uuid",
+    "rd.luks.name",
+    "rd.luks.options",
+    "rd.lvm.lv",
+    "rd.lvm.vg",
+    "rd.md.uuid",
+    "rd.dasd",
+    "rd.zfcp",
+    "rd.znet",
+    "rd.driver.pre",
+    "systemd.mask",
+    "systemd.wants",
+    "systemd.setenv",
+    "rd.systemd.mask",
+    "rd.systemd.wants",
+];
 
 /// A parsed kernel command line.
 ///
@@ -124,6 +149,115 @@ impl<'a> Cmdline<'a> {
     }
 }
 
+/// Merges kernel arguments from multiple sources into a single command line.
+///
+/// Sources are added in increasing order of precedence.  The parameters of a
+/// single source are kept as is, including repeated keys (e.g.
+/// `hugepagesz=1G hugepages=4 hugepagesz=2M hugepages=512`).  For most keys,
+/// a source overrides all the values of that key from earlier sources, at the
+/// position where the key first appeared; for keys in [`MULTI_VALUE_KEYS`]
+/// each distinct value is kept.  Keys are compared treating dashes and
+/// underscores as equivalent.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct CmdlineBuilder {
+    params: Vec<String>,
+}
+
+impl CmdlineBuilder {
+    /// Create an empty command line.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_multi_value(key: &ParameterKeyStr) -> bool {
+        MULTI_VALUE_KEYS.iter().any(|k| ParameterKeyStr(k) == *key)
+    }
+
+    /// Add a single parameter as its own source.
+    pub(crate) fn push(&mut self, param: &str) -> &mut Self {
+        self.extend([param])
+    }
+
+    /// Add the parameters of a source, which take precedence over those added previously.
+    pub(crate) fn extend<I, S>(&mut self, params: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let new = params
+            .into_iter()
+            .map(|p| p.as_ref().to_owned())
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>();
+        let previous = std::mem::take(&mut self.params);
+        let mut added = vec![false; new.len()];
+        for existing in previous.iter() {
+            let old = ParameterStr::from(existing.as_str());
+            let overridden = !Self::is_multi_value(&old.key)
+                && new
+                    .iter()
+                    .any(|p| ParameterStr::from(p.as_str()).key == old.key);
+            if !overridden {
+                self.params.push(existing.clone());
+                continue;
+            }
+            // Replace the first occurrence with all the new values, and drop any others
+            for (p, added) in new.iter().zip(added.iter_mut()) {
+                if !*added && ParameterStr::from(p.as_str()).key == old.key {
+                    self.params.push(p.clone());
+                    *added = true;
+                }
+            }
+        }
+        for (p, added) in new.into_iter().zip(added) {
+            // Values of multi-value keys from earlier sources are not repeated
+            if !added && !previous.contains(&p) {
+                self.params.push(p);
+            }
+        }
+        self
+    }
+
+    /// Add the parameters of a full command line as a source, honoring quoting.
+    pub(crate) fn extend_cmdline(&mut self, cmdline: &str) -> &mut Self {
+        let cmdline = Cmdline::from(cmdline);
+        let params = cmdline
+            .iter()
+            .filter_map(|p| p.to_str())
+            .map(|p| p.parameter)
+            .collect::<Vec<_>>();
+        self.extend(params)
+    }
+
+    /// Remove parameters. A parameter with a value (`key=value`) must match
+    /// exactly; one without a value removes all parameters with that key.
+    /// Returns whether anything was removed.
+    pub(crate) fn remove(&mut self, param: &str) -> bool {
+        let target = ParameterStr::from(param);
+        let orig_len = self.params.len();
+        self.params.retain(|existing| {
+            let p = ParameterStr::from(existing.as_str());
+            if target.value.is_some() {
+                p != target
+            } else {
+                p.key != target.key
+            }
+        });
+        self.params.len() != orig_len
+    }
+
+    /// The merged parameters, in order.
+    pub(crate) fn into_vec(self) -> Vec<String> {
+        self.params
+    }
+}
+
+impl Display for CmdlineBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.params.join(" "))
+    }
+}
+
 /// A single kernel command line parameter key
 ///
 /// Handles quoted values and treats dashes and underscores in keys as equivalent.
@@ -563,6 +697,45 @@ mod tests {
         assert_ne!(k1, k2);
     }
 
+    #[test]
+    fn test_cmdline_builder() {
+        let mut b = CmdlineBuilder::new();
+        b.extend(["root=UUID=abc", "rw", "console=tty0", "quiet"])
+            .extend(["console=ttyS0,115200", "log-level=3", "quiet"])
+            .extend(["rw", "log_level=7", "console=tty0", "console=ttyS0,115200"]);
+        assert_eq!(
+            b.to_string(),
+            "root=UUID=abc rw console=tty0 quiet console=ttyS0,115200 log_level=7"
+        );
+
+        // A later source overrides all the values of a key from an existing command line
+        let mut b = CmdlineBuilder::new();
+        b.extend_cmdline("foo=1  bar=\"a b\" foo=2 rd.lvm.lv=vg/root rd.lvm.lv=vg/swap")
+            .push("foo=3");
+        assert_eq!(
+            b.clone().into_vec(),
+            [
+                "foo=3",
+                "bar=\"a b\"",
+                "rd.lvm.lv=vg/root",
+                "rd.lvm.lv=vg/swap"
+            ]
+        );
+
+        assert!(b.remove("rd.lvm.lv=vg/swap"));
+        assert!(!b.remove("rd.lvm.lv=vg/swap"));
+        assert!(b.remove("foo"));
+        assert_eq!(b.into_vec(), ["bar=\"a b\"", "rd.lvm.lv=vg/root"]);
+
+        // Repeated keys within a source are kept
+        let hugepages = "hugepagesz=1G hugepages=4 hugepagesz=2M hugepages=4";
+        let mut b = CmdlineBuilder::new();
+        b.extend_cmdline("quiet").extend_cmdline(hugepages);
+        assert_eq!(b.to_string(), format!("quiet {hugepages}"));
+        b.extend(["hugepagesz=2M", "hugepages=512", "quiet"]);
+        assert_eq!(b.to_string(), "quiet hugepagesz=2M hugepages=512");
+    }
+
     #[test]
     fn test_kargs_non_utf8() {
         let non_utf8_val = b"an_invalid_key=\xff";
@@ -5,28 +5,56 @@
 //! bootable container images.
 
 pub(crate) mod bootc_kargs;
+mod bootcount;
 mod boundimage;
 mod cfsctl;
+mod checks;
 pub mod cli;
+mod clock;
+mod configcheck;
 pub(crate) mod deploy;
+mod diff;
+mod downgrade;
+mod etc;
+pub mod events;
+mod export;
+mod extensions;
 pub(crate) mod fsck;
 pub(crate) mod generator;
 mod glyph;
+mod health;
+mod history;
+mod hooks;
+mod hostconfig;
+mod ima;
 mod image;
 mod imgstorage;
 pub(crate) mod journal;
 mod k8sapitypes;
+mod lifecycle;
 mod lints;
 mod lsm;
 pub(crate) mod metadata;
+mod offline;
+mod platform;
 mod podman;
 mod progress_jsonl;
 mod reboot;
+mod rechunk;
+mod reconcile;
+mod relabel;
+mod retry;
+mod rollout;
+mod secrets;
 pub mod spec;
+mod stateroot;
 mod status;
 mod store;
 mod task;
+mod update_bundle;
+mod usroverlay;
 mod utils;
+mod version;
 
 #[cfg(feature = "docgen")]
 mod docgen;
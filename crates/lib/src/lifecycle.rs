@@ -0,0 +1,548 @@
+//! # Deployment lifecycle
+//!
+//! Each deployment moves through an explicit set of states:
+//!
+//! ```text
+//! Staged -> Finalizing -> Booted -> Validated
+//!    |           |           |          |
+//!    +-----------+-----------+----------+--> RolledBack
+//! ```
+//!
+//! Transitions are persisted in `/ostree/bootc/lifecycle.json`, keyed by an
+//! opaque deployment identifier, and shown by `bootc status`. Some transitions
+//! are recorded when bootc performs them (staging, initiating a reboot, a
+//! rollback, `boot-complete.target`); the others (the system booted into a
+//! deployment, or fell back to a previous one) are inferred by reconciling the
+//! recorded states with the current deployments.
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::{Deserialize, Serialize};
+
+use crate::spec::{BootEntry, DeploymentState, Host, LifecycleTransition};
+use crate::store::Storage;
+
+/// The state file, relative to the physical root.
+const LIFECYCLE_PATH: &str = "ostree/bootc/lifecycle.json";
+/// How many records of deployments which no longer exist to keep.
+const MAX_RETIRED: usize = 16;
+
+/// A transition which is not part of the state machine.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Invalid lifecycle transition for deployment {id}: {} -> {to}", display_state(.from))]
+pub(crate) struct InvalidTransition {
+    id: String,
+    from: Option<DeploymentState>,
+    to: DeploymentState,
+}
+
+fn display_state(state: &Option<DeploymentState>) -> String {
+    state.map_or_else(|| "none".to_owned(), |s| s.to_string())
+}
+
+/// The recorded transitions of a deployment.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct DeploymentRecord {
+    id: String,
+    /// Set once the deployment no longer exists
+    #[serde(default)]
+    retired: bool,
+    transitions: Vec<LifecycleTransition>,
+}
+
+/// The persisted lifecycle state of all deployments.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Lifecycle {
+    deployments: Vec<DeploymentRecord>,
+}
+
+/// Whether the state machine allows moving from `from` to `to`.
+fn allowed(from: Option<DeploymentState>, to: DeploymentState) -> bool {
+    use DeploymentState::*;
+    matches!(
+        (from, to),
+        (None, Staged)
+            // A deployment which predates lifecycle tracking
+            | (None, Booted)
+            | (Some(Staged), Finalizing | Booted | RolledBack)
+            | (Some(Finalizing), Booted | RolledBack)
+            | (Some(Booted), Validated | RolledBack)
+            | (Some(Validated), RolledBack)
+            // A queued rollback was reverted
+            | (Some(RolledBack), Booted)
+    )
+}
+
+/// The identifier of an ostree deployment.
+pub(crate) fn deployment_id(deployment: &ostree::Deployment) -> String {
+    format!(
+        "{}/{}.{}",
+        deployment.osname(),
+        deployment.csum(),
+        deployment.deployserial()
+    )
+}
+
+/// The identifier of a boot entry, if it is ostree based.
+pub(crate) fn entry_id(entry: &BootEntry) -> Option<String> {
+    let ostree = entry.ostree.as_ref()?;
+    Some(format!(
+        "{}/{}.{}",
+        ostree.stateroot, ostree.checksum, ostree.deploy_serial
+    ))
+}
+
+impl Lifecycle {
+    fn record(&self, id: &str) -> Option<&DeploymentRecord> {
+        self.deployments.iter().find(|r| !r.retired && r.id == id)
+    }
+
+    /// The current state of a deployment.
+    pub(crate) fn state(&self, id: &str) -> Option<DeploymentState> {
+        self.record(id)
+            .and_then(|r| r.transitions.last())
+            .map(|t| t.state)
+    }
+
+    /// The recorded transitions of a deployment, oldest first.
+    pub(crate) fn transitions(&self, id: &str) -> &[LifecycleTransition] {
+        self.record(id)
+            .map(|r| r.transitions.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Move a deployment to a new state.
+    pub(crate) fn transition(
+        &mut self,
+        id: &str,
+        to: DeploymentState,
+        now: DateTime<Utc>,
+    ) -> Result<(), InvalidTransition> {
+        let from = self.state(id);
+        if !allowed(from, to) {
+            return Err(InvalidTransition {
+                id: id.to_owned(),
+                from,
+                to,
+            });
+        }
+        let transition = LifecycleTransition {
+            state: to,
+            timestamp: now,
+            downgrade: None,
+            reason: None,
+        };
+        match self
+            .deployments
+            .iter_mut()
+            .find(|r| !r.retired && r.id == id)
+        {
+            Some(r) => r.transitions.push(transition),
+            None => self.deployments.push(DeploymentRecord {
+                id: id.to_owned(),
+                retired: false,
+                transitions: vec![transition],
+            }),
+        }
+        Ok(())
+    }
+
+    /// Mark the most recent transition of a deployment as a downgrade; returns
+    /// false if there is no record of the deployment.
+    pub(crate) fn set_downgrade(&mut self, id: &str, description: &str) -> bool {
+        let Some(t) = self
+            .deployments
+            .iter_mut()
+            .find(|r| !r.retired && r.id == id)
+            .and_then(|r| r.transitions.last_mut())
+        else {
+            return false;
+        };
+        t.downgrade = Some(description.to_owned());
+        true
+    }
+
+    /// Set the reason of the most recent transition of a deployment; returns
+    /// false if there is no record of the deployment.
+    pub(crate) fn set_reason(&mut self, id: &str, reason: &str) -> bool {
+        let Some(t) = self
+            .deployments
+            .iter_mut()
+            .find(|r| !r.retired && r.id == id)
+            .and_then(|r| r.transitions.last_mut())
+        else {
+            return false;
+        };
+        t.reason = Some(reason.to_owned());
+        true
+    }
+
+    /// Infer the transitions which happened outside of bootc, given the
+    /// current deployments and when the system was booted; returns whether
+    /// anything changed.
+    pub(crate) fn reconcile(
+        &mut self,
+        existing: &[String],
+        booted: Option<&str>,
+        staged: Option<&str>,
+        boot_time: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, InvalidTransition> {
+        use DeploymentState::*;
+        let mut changed = false;
+        for r in self.deployments.iter_mut().filter(|r| !r.retired) {
+            if !existing.contains(&r.id) {
+                r.retired = true;
+                changed = true;
+            }
+        }
+        if let Some(booted) = booted {
+            let last = self.record(booted).and_then(|r| r.transitions.last());
+            let rebooted = match last {
+                None => true,
+                Some(t) => match t.state {
+                    Staged | Finalizing => true,
+                    // A rolled back deployment which was booted again (e.g. chosen in
+                    // the bootloader menu); a rollback queued during this boot is pending.
+                    RolledBack => t.timestamp < boot_time,
+                    Booted | Validated => false,
+                },
+            };
+            if rebooted {
+                self.transition(booted, Booted, now)?;
+                changed = true;
+            }
+        }
+        // A deployment which was finalized but isn't booted failed to boot,
+        // or the system was booted into another one.
+        let abandoned = self
+            .deployments
+            .iter()
+            .filter(|r| {
+                !r.retired && Some(r.id.as_str()) != booted && Some(r.id.as_str()) != staged
+            })
+            .filter(|r| {
+                matches!(
+                    r.transitions.last().map(|t| t.state),
+                    Some(Staged | Finalizing)
+                )
+            })
+            .map(|r| r.id.clone())
+            .collect::<Vec<_>>();
+        for id in abandoned {
+            self.transition(&id, RolledBack, now)?;
+            changed = true;
+        }
+        // Only keep the most recent retired records
+        let retired = self.deployments.iter().filter(|r| r.retired).count();
+        let mut excess = retired.saturating_sub(MAX_RETIRED);
+        self.deployments.retain(|r| {
+            if r.retired && excess > 0 {
+                excess -= 1;
+                false
+            } else {
+                true
+            }
+        });
+        Ok(changed)
+    }
+}
+
+fn load(root: &Dir) -> Result<Lifecycle> {
+    let Some(f) = root.open_optional(LIFECYCLE_PATH)? else {
+        return Ok(Lifecycle::default());
+    };
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {LIFECYCLE_PATH}"))
+}
+
+fn store(root: &Dir, lifecycle: &Lifecycle) -> Result<()> {
+    root.create_dir_all(crate::store::BOOTC_ROOT)?;
+    root.atomic_write(LIFECYCLE_PATH, serde_json::to_vec(lifecycle)?)
+        .with_context(|| format!("Writing {LIFECYCLE_PATH}"))
+}
+
+/// Load the lifecycle state, reconciled with the current deployments;
+/// the returned flag is set if reconciling changed it.
+fn load_current(sysroot: &Storage, now: DateTime<Utc>) -> Result<(Lifecycle, bool)> {
+    let root = &crate::utils::sysroot_dir(sysroot)?;
+    let mut lifecycle = load(root)?;
+    let existing = sysroot
+        .deployments()
+        .iter()
+        .map(deployment_id)
+        .collect::<Vec<_>>();
+    let booted = sysroot.booted_deployment().map(|d| deployment_id(&d));
+    let staged = sysroot.staged_deployment().map(|d| deployment_id(&d));
+    let boot_time = now - chrono::Duration::from_std(crate::utils::uptime()?)?;
+    let changed = lifecycle.reconcile(
+        &existing,
+        booted.as_deref(),
+        staged.as_deref(),
+        boot_time,
+        now,
+    )?;
+    Ok((lifecycle, changed))
+}
+
+/// Record a transition of `deployment` to `state`.
+#[context("Recording deployment state")]
+pub(crate) fn record(
+    sysroot: &Storage,
+    deployment: &ostree::Deployment,
+    state: DeploymentState,
+) -> Result<()> {
+    let now = Utc::now();
+    let (mut lifecycle, _) = load_current(sysroot, now)?;
+    lifecycle.transition(&deployment_id(deployment), state, now)?;
+    store(&crate::utils::sysroot_dir(sysroot)?, &lifecycle)
+}
+
+/// Record that a reboot into the staged deployment, if any, was initiated.
+pub(crate) fn record_finalizing(sysroot: &Storage) -> Result<()> {
+    let Some(staged) = sysroot.staged_deployment() else {
+        return Ok(());
+    };
+    crate::journal::journal_event(
+        libsystemd::logging::Priority::Notice,
+        crate::journal::FINALIZING_ID,
+        "Rebooting into staged deployment",
+        &crate::journal::deployment_fields(&sysroot.repo(), &staged),
+    );
+    record(sysroot, &staged, DeploymentState::Finalizing)
+}
+
+/// Record that the staged deployment is a downgrade.
+#[context("Recording downgrade")]
+pub(crate) fn record_downgrade(sysroot: &Storage, description: &str) -> Result<()> {
+    let Some(staged) = sysroot.staged_deployment() else {
+        return Ok(());
+    };
+    let (mut lifecycle, _) = load_current(sysroot, Utc::now())?;
+    if lifecycle.set_downgrade(&deployment_id(&staged), description) {
+        store(&crate::utils::sysroot_dir(sysroot)?, &lifecycle)?;
+    }
+    Ok(())
+}
+
+/// Record a rollback away from the booted deployment, or that a queued
+/// rollback was reverted.
+pub(crate) fn record_rollback(
+    sysroot: &Storage,
+    booted: &ostree::Deployment,
+    reverting: bool,
+) -> Result<()> {
+    let now = Utc::now();
+    let (mut lifecycle, _) = load_current(sysroot, now)?;
+    let id = deployment_id(booted);
+    let to = if reverting {
+        DeploymentState::Booted
+    } else {
+        DeploymentState::RolledBack
+    };
+    // e.g. reverting a rollback which was queued before tracking started
+    if allowed(lifecycle.state(&id), to) {
+        lifecycle.transition(&id, to, now)?;
+    }
+    store(&crate::utils::sysroot_dir(sysroot)?, &lifecycle)
+}
+
+/// Record why `deployment` made its most recent transition.
+#[context("Recording transition reason")]
+pub(crate) fn record_reason(
+    sysroot: &Storage,
+    deployment: &ostree::Deployment,
+    reason: &str,
+) -> Result<()> {
+    let (mut lifecycle, _) = load_current(sysroot, Utc::now())?;
+    if lifecycle.set_reason(&deployment_id(deployment), reason) {
+        store(&crate::utils::sysroot_dir(sysroot)?, &lifecycle)?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc internals mark-validated`, invoked once
+/// `boot-complete.target` is reached.
+#[context("Marking booted deployment as validated")]
+pub(crate) fn mark_validated(sysroot: &Storage) -> Result<()> {
+    let booted = sysroot.require_booted_deployment()?;
+    let now = Utc::now();
+    let (mut lifecycle, changed) = load_current(sysroot, now)?;
+    let id = deployment_id(&booted);
+    if lifecycle.state(&id) == Some(DeploymentState::Booted) {
+        lifecycle.transition(&id, DeploymentState::Validated, now)?;
+    } else if !changed {
+        tracing::debug!("Not marking {id} as validated");
+        return Ok(());
+    }
+    store(&crate::utils::sysroot_dir(sysroot)?, &lifecycle)
+}
+
+/// Fill in the lifecycle transitions of each boot entry; this does not
+/// persist anything inferred from the current deployments.
+pub(crate) fn apply_to_host(sysroot: &Storage, host: &mut Host) -> Result<()> {
+    let (lifecycle, _) = load_current(sysroot, Utc::now())?;
+    let status = &mut host.status;
+    let entries = [&mut status.staged, &mut status.booted, &mut status.rollback]
+        .into_iter()
+        .flat_map(|e| e.as_mut())
+        .chain(status.other_deployments.iter_mut());
+    for entry in entries {
+        if let Some(id) = entry_id(entry) {
+            entry.lifecycle = lifecycle.transitions(&id).to_vec();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+    use DeploymentState::*;
+
+    fn t(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn states(l: &Lifecycle, id: &str) -> Vec<DeploymentState> {
+        l.transitions(id).iter().map(|t| t.state).collect()
+    }
+
+    #[test]
+    fn test_transitions() {
+        let now = t("2025-03-01T12:00:00Z");
+        let mut l = Lifecycle::default();
+        l.transition("a", Staged, now).unwrap();
+        l.transition("a", Finalizing, now).unwrap();
+        l.transition("a", Booted, now).unwrap();
+        l.transition("a", Validated, now).unwrap();
+        assert_eq!(states(&l, "a"), [Staged, Finalizing, Booted, Validated]);
+        let e = l.transition("a", Staged, now).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid lifecycle transition for deployment a: validated -> staged"
+        );
+        assert!(l.transition("b", Validated, now).is_err());
+        assert_eq!(l.state("b"), None);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let boot = t("2025-03-01T11:00:00Z");
+        let now = t("2025-03-01T12:00:00Z");
+        let existing = ["old".to_owned(), "new".to_owned()];
+        let mut l = Lifecycle::default();
+        // The booted deployment predates tracking
+        assert!(l
+            .reconcile(&existing[..1], Some("old"), None, boot, now)
+            .unwrap());
+        l.transition("new", Staged, now).unwrap();
+        // Still staged; nothing to do
+        assert!(!l
+            .reconcile(&existing, Some("old"), Some("new"), boot, now)
+            .unwrap());
+        l.transition("new", Finalizing, now).unwrap();
+        // We're booted into it
+        assert!(l
+            .reconcile(&existing, Some("new"), None, boot, now)
+            .unwrap());
+        assert_eq!(states(&l, "new"), [Staged, Finalizing, Booted]);
+
+        // A failed boot falls back to the previous deployment
+        let mut l = Lifecycle::default();
+        l.transition("old", Booted, now).unwrap();
+        l.transition("new", Staged, now).unwrap();
+        l.transition("new", Finalizing, now).unwrap();
+        assert!(l
+            .reconcile(&existing, Some("old"), None, boot, now)
+            .unwrap());
+        assert_eq!(l.state("new"), Some(RolledBack));
+        assert_eq!(l.state("old"), Some(Booted));
+
+        // Deployments which were removed are retired
+        assert!(l
+            .reconcile(&existing[..1], Some("old"), None, boot, now)
+            .unwrap());
+        assert_eq!(l.state("new"), None);
+        // Another deployment with the same id starts fresh
+        l.transition("new", Staged, now).unwrap();
+        assert_eq!(states(&l, "new"), [Staged]);
+
+        // A rollback queued during this boot is still pending
+        let mut l = Lifecycle::default();
+        l.transition("old", Booted, boot).unwrap();
+        l.transition("old", RolledBack, now).unwrap();
+        assert!(!l
+            .reconcile(&existing[..1], Some("old"), None, boot, now)
+            .unwrap());
+        assert_eq!(l.state("old"), Some(RolledBack));
+        // But once booted again, the deployment is in use
+        let later = t("2025-03-01T13:00:00Z");
+        assert!(l
+            .reconcile(&existing[..1], Some("old"), None, later, later)
+            .unwrap());
+        assert_eq!(states(&l, "old"), [Booted, RolledBack, Booted]);
+    }
+
+    #[test]
+    fn test_retired_limit() {
+        let now = t("2025-03-01T12:00:00Z");
+        let mut l = Lifecycle::default();
+        for i in 0..(MAX_RETIRED + 4) {
+            l.transition(&i.to_string(), Staged, now).unwrap();
+        }
+        l.reconcile(&[], None, None, now, now).unwrap();
+        assert_eq!(l.deployments.len(), MAX_RETIRED);
+        assert_eq!(l.deployments[0].id, "4");
+    }
+
+    #[test]
+    fn test_load_store() -> Result<()> {
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load(td)?, Lifecycle::default());
+        let mut l = Lifecycle::default();
+        l.transition("a", Staged, t("2025-03-01T12:00:00Z"))?;
+        store(td, &l)?;
+        assert_eq!(load(td)?, l);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_downgrade() {
+        let now = t("2025-03-01T12:00:00Z");
+        let mut l = Lifecycle::default();
+        assert!(!l.set_downgrade("a", "version 2 -> 1"));
+        l.transition("a", Staged, now).unwrap();
+        assert!(l.set_downgrade("a", "version 2 -> 1"));
+        l.transition("a", Finalizing, now).unwrap();
+        let downgrades = l
+            .transitions("a")
+            .iter()
+            .map(|t| t.downgrade.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(downgrades, [Some("version 2 -> 1"), None]);
+    }
+
+    #[test]
+    fn test_set_reason() {
+        let now = t("2025-03-01T12:00:00Z");
+        let mut l = Lifecycle::default();
+        assert!(!l.set_reason("a", "app.service failed"));
+        l.transition("a", Booted, now).unwrap();
+        l.transition("a", RolledBack, now).unwrap();
+        assert!(l.set_reason("a", "app.service failed"));
+        let reasons = l
+            .transitions("a")
+            .iter()
+            .map(|t| t.reason.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, [None, Some("app.service failed")]);
+    }
+}
@@ -8,36 +8,55 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::env::consts::ARCH;
 use std::fmt::{Display, Write as WriteFmt};
+use std::io::{Seek, Write as _};
 use std::num::NonZeroUsize;
 use std::ops::ControlFlow;
+use std::os::fd::OwnedFd;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
 
-use anyhow::Result;
-use bootc_utils::PathQuotedDisplay;
+use anyhow::{Context, Result};
+use bootc_utils::{CommandRunExt, PathQuotedDisplay};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
-use cap_std_ext::cap_std::fs::MetadataExt;
+use cap_std_ext::cap_std::fs::{MetadataExt, PermissionsExt};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
 use cap_std_ext::dirext::WalkConfiguration;
 use cap_std_ext::dirext::{CapStdExtDirExt as _, WalkComponent};
 use fn_error_context::context;
 use indoc::indoc;
 use linkme::distributed_slice;
 use ostree_ext::ostree_prepareroot;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 /// Reference to embedded default baseimage content that should exist.
 const BASEIMAGE_REF: &str = "usr/share/doc/bootc/baseimage/base";
 // https://systemd.io/API_FILE_SYSTEMS/ with /var added for us
 const API_DIRS: &[&str] = &["dev", "proc", "sys", "run", "tmp", "var"];
 
+/// External lint executables, relative to the target root.
+const EXTERNAL_LINTS_DIR: &str = "usr/lib/bootc/lints.d";
+/// The version of the protocol spoken with external lints.
+const EXTERNAL_LINT_PROTOCOL_VERSION: u32 = 1;
+/// The target root is passed to external lints via this fd.
+const EXTERNAL_LINT_ROOT_FD: i32 = 3;
+
+/// Image-provided configuration for lints, relative to the target root.
+const LINT_CONFIG: &str = "usr/lib/bootc/lint-config.toml";
+
 /// Only output this many items by default
 const DEFAULT_TRUNCATED_OUTPUT: NonZeroUsize = const { NonZeroUsize::new(5).unwrap() };
 
 /// A lint check has failed.
 #[derive(thiserror::Error, Debug)]
-struct LintError(String);
+struct LintError {
+    msg: String,
+    /// The number of items omitted from the message
+    truncated: usize,
+}
 
 /// The outer error is for unexpected fatal runtime problems; the
 /// inner error is for the lint failing in an expected way.
@@ -54,15 +73,26 @@ fn lint_err(msg: impl AsRef<str>) -> LintResult {
     Ok(Err(LintError::new(msg)))
 }
 
+/// We found a lint failure, whose message omits `truncated` items.
+fn lint_err_truncated(msg: impl AsRef<str>, truncated: usize) -> LintResult {
+    Ok(Err(LintError {
+        msg: msg.as_ref().to_owned(),
+        truncated,
+    }))
+}
+
 impl std::fmt::Display for LintError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.0)
+        f.write_str(&self.msg)
     }
 }
 
 impl LintError {
     fn new(msg: impl AsRef<str>) -> Self {
-        Self(msg.as_ref().to_owned())
+        Self {
+            msg: msg.as_ref().to_owned(),
+            truncated: 0,
+        }
     }
 }
 
@@ -87,9 +117,9 @@ enum LintFnTy {
 pub(crate) static LINTS: [Lint];
 
 /// The classification of a lint type.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
-enum LintType {
+pub(crate) enum LintType {
     /// If this fails, it is known to be fatal - the system will not install or
     /// is effectively guaranteed to fail at runtime.
     Fatal,
@@ -97,6 +127,24 @@ enum LintType {
     Warning,
 }
 
+/// The output format of `bootc container lint`.
+#[derive(clap::ValueEnum, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[clap(rename_all = "lowercase")]
+pub(crate) enum LintFormat {
+    /// Human readable text; YAML for `--list`
+    #[default]
+    Human,
+    /// JSON, with a stable structure
+    Json,
+}
+
+impl std::fmt::Display for LintFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use clap::ValueEnum;
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub(crate) enum WarningDisposition {
     AllowWarnings,
@@ -183,45 +231,291 @@ impl Lint {
     }
 }
 
-pub(crate) fn lint_list(output: impl std::io::Write) -> Result<()> {
-    // Dump in yaml format by default, it's readable enough
-    serde_yaml::to_writer(output, &*LINTS)?;
+pub(crate) fn lint_list(mut output: impl std::io::Write, format: LintFormat) -> Result<()> {
+    match format {
+        // Dump in yaml format by default, it's readable enough
+        LintFormat::Human => serde_yaml::to_writer(output, &*LINTS)?,
+        LintFormat::Json => {
+            serde_json::to_writer_pretty(&mut output, &*LINTS)?;
+            writeln!(output)?;
+        }
+    }
     Ok(())
 }
 
-#[derive(Debug)]
+/// The status of a single lint.
+#[derive(Debug, Copy, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LintStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+/// The result of a single lint; the `name` and `type` fields are shared
+/// with the output of `--list`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct LintOutcome {
+    name: String,
+    #[serde(rename = "type")]
+    ty: LintType,
+    status: LintStatus,
+    /// Set if the lint failed
+    message: Option<String>,
+    /// The number of items omitted from the message
+    truncated: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
 struct LintExecutionResult {
     warnings: usize,
     passed: usize,
     skipped: usize,
     fatal: usize,
+    results: Vec<LintOutcome>,
+}
+
+impl LintExecutionResult {
+    /// Print and count the result of a single lint.
+    fn record(
+        &mut self,
+        mut output: impl std::io::Write,
+        name: &str,
+        ty: &LintType,
+        r: std::result::Result<(), LintError>,
+    ) -> Result<()> {
+        let mut outcome = LintOutcome {
+            name: name.to_owned(),
+            ty: *ty,
+            status: LintStatus::Passed,
+            message: None,
+            truncated: 0,
+        };
+        if let Err(e) = r {
+            match ty {
+                LintType::Fatal => {
+                    writeln!(output, "Failed lint: {name}: {e}")?;
+                    self.fatal += 1;
+                }
+                LintType::Warning => {
+                    writeln!(output, "Lint warning: {name}: {e}")?;
+                    self.warnings += 1;
+                }
+            }
+            outcome.status = LintStatus::Failed;
+            outcome.truncated = e.truncated;
+            outcome.message = Some(e.msg);
+        } else {
+            // We'll be quiet for now
+            tracing::debug!("OK {name} (type={ty:?})");
+            self.passed += 1;
+        }
+        self.results.push(outcome);
+        Ok(())
+    }
+
+    /// Count a skipped lint.
+    fn record_skipped(&mut self, name: &str, ty: LintType) {
+        self.skipped += 1;
+        self.results.push(LintOutcome {
+            name: name.to_owned(),
+            ty,
+            status: LintStatus::Skipped,
+            message: None,
+            truncated: 0,
+        });
+    }
+}
+
+/// The toplevel of [`LINT_CONFIG`].
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LintConfigToplevel {
+    lints: Option<LintConfig>,
+}
+
+/// The `[lints]` section of [`LINT_CONFIG`].
+#[derive(Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct LintConfig {
+    /// Lints which are skipped by default
+    #[serde(default)]
+    skip: Vec<String>,
+    /// Overrides for the type of individual lints
+    #[serde(default)]
+    severity: BTreeMap<String, LintType>,
+}
+
+impl LintConfig {
+    /// The effective type of a lint.
+    fn severity(&self, name: &str, default: LintType) -> LintType {
+        self.severity.get(name).copied().unwrap_or(default)
+    }
+
+    /// Apply overrides from the command line, which take precedence.
+    fn apply(&mut self, overrides: &LintOverrides) {
+        self.skip.retain(|name| !overrides.include.contains(name));
+        self.skip.extend(overrides.skip.iter().cloned());
+        self.severity.extend(overrides.severity.iter().cloned());
+    }
+}
+
+/// Overrides of the [`LINT_CONFIG`] from the command line.
+#[derive(Debug, Default)]
+pub(crate) struct LintOverrides {
+    /// Lints to skip, in addition to the configured ones
+    pub(crate) skip: Vec<String>,
+    /// Lints to run even if they are configured to be skipped
+    pub(crate) include: Vec<String>,
+    /// Overrides for the type of individual lints
+    pub(crate) severity: Vec<(String, LintType)>,
+}
+
+/// Parse a `<lint>=<type>` severity override.
+pub(crate) fn parse_severity_override(s: &str) -> Result<(String, LintType)> {
+    let Some((name, ty)) = s.split_once('=') else {
+        anyhow::bail!("Expected <lint>=<type>: {s}");
+    };
+    let ty = match ty {
+        "fatal" => LintType::Fatal,
+        "warning" => LintType::Warning,
+        o => anyhow::bail!("Invalid lint type: {o}"),
+    };
+    Ok((name.to_owned(), ty))
+}
+
+/// Load the lint configuration shipped in the target root, if any.
+#[context("Loading {LINT_CONFIG}")]
+fn load_lint_config(root: &Dir) -> Result<LintConfig> {
+    let Some(f) = root.open_optional(LINT_CONFIG)? else {
+        return Ok(LintConfig::default());
+    };
+    let buf = std::io::read_to_string(f)?;
+    let c: LintConfigToplevel = toml::from_str(&buf)?;
+    Ok(c.lints.unwrap_or_default())
+}
+
+/// The request passed as JSON on stdin to an external lint.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExternalLintRequest<'a> {
+    /// Always [`EXTERNAL_LINT_PROTOCOL_VERSION`]
+    version: u32,
+    /// The path to the target root
+    root: &'a str,
+    root_type: RootType,
+    no_truncate: bool,
+}
+
+/// The response written as JSON to stdout by an external lint.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExternalLintResponse {
+    results: Vec<ExternalLintResult>,
+}
+
+/// The result of one check performed by an external lint.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExternalLintResult {
+    name: String,
+    #[serde(rename = "type")]
+    ty: LintType,
+    /// If set, the check failed with this message
+    error: Option<String>,
 }
 
-// Helper function to format items with optional truncation
+/// Find the external lint executables in the target root, sorted by name.
+fn find_external_lints(root: &Dir) -> Result<Vec<String>> {
+    let Some(d) = root.open_dir_optional(EXTERNAL_LINTS_DIR)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            anyhow::bail!("Invalid non-UTF8 filename in {EXTERNAL_LINTS_DIR}: {name:?}");
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follow symlinks
+        let meta = d.metadata(name)?;
+        if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+            tracing::debug!("Ignoring non-executable {name}");
+            continue;
+        }
+        r.push(name.to_owned());
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Run an external lint; it is executed from the target root, which is also
+/// passed to it as a file descriptor.
+#[context("Running external lint {name}")]
+fn run_external_lint(
+    root: &Dir,
+    name: &str,
+    root_type: RootType,
+    config: &LintExecutionConfig,
+) -> Result<Vec<ExternalLintResult>> {
+    let rootpath = format!("/proc/self/fd/{EXTERNAL_LINT_ROOT_FD}");
+    let req = ExternalLintRequest {
+        version: EXTERNAL_LINT_PROTOCOL_VERSION,
+        root: &rootpath,
+        root_type,
+        no_truncate: config.no_truncate,
+    };
+    let mut stdin = tempfile::tempfile()?;
+    serde_json::to_writer(&mut stdin, &req)?;
+    stdin.flush()?;
+    stdin.seek(std::io::SeekFrom::Start(0))?;
+    let mut cmd = Command::new(format!("{rootpath}/{EXTERNAL_LINTS_DIR}/{name}"));
+    cmd.stdin(stdin);
+    cmd.take_fd_n(
+        Arc::new(OwnedFd::from(root.try_clone()?)),
+        EXTERNAL_LINT_ROOT_FD,
+    );
+    let resp: ExternalLintResponse = cmd.run_and_parse_json().context("Parsing response")?;
+    for r in resp.results.iter() {
+        anyhow::ensure!(!r.name.is_empty(), "Empty lint name");
+        if LINTS.iter().any(|l| l.name == r.name) {
+            anyhow::bail!("Lint {} conflicts with a builtin lint", r.name);
+        }
+    }
+    Ok(resp.results)
+}
+
+// Helper function to format items with optional truncation; returns the
+// number of items omitted.
 fn format_items<T>(
     config: &LintExecutionConfig,
     header: &str,
     items: impl Iterator<Item = T>,
     o: &mut String,
-) -> Result<()>
+) -> Result<usize>
 where
     T: Display,
 {
     let mut items = items.into_iter();
     if config.no_truncate {
         let Some(first) = items.next() else {
-            return Ok(());
+            return Ok(0);
         };
         writeln!(o, "{header}:")?;
         writeln!(o, "  {first}")?;
         for item in items {
             writeln!(o, "  {item}")?;
         }
-        return Ok(());
+        Ok(0)
     } else {
         let Some((samples, rest)) = bootc_utils::collect_until(items, DEFAULT_TRUNCATED_OUTPUT)
         else {
-            return Ok(());
+            return Ok(0);
         };
         writeln!(o, "{header}:")?;
         for item in samples {
@@ -230,8 +524,8 @@ where
         if rest > 0 {
             writeln!(o, "  ...and {rest} more")?;
         }
+        Ok(rest)
     }
-    Ok(())
 }
 
 // Helper to build a lint error message from multiple sections.
@@ -247,21 +541,21 @@ where
 {
     let mut msg = String::new();
     // SAFETY: Writing to a string can't fail
-    format_items(config, header, items, &mut msg).unwrap();
-    lint_err(msg)
+    let truncated = format_items(config, header, items, &mut msg).unwrap();
+    lint_err_truncated(msg, truncated)
 }
 
-fn lint_inner<'skip>(
+fn lint_inner(
     root: &Dir,
     root_type: RootType,
     config: &LintExecutionConfig,
-    skip: impl IntoIterator<Item = &'skip str>,
+    overrides: &LintOverrides,
     mut output: impl std::io::Write,
 ) -> Result<LintExecutionResult> {
-    let mut fatal = 0usize;
-    let mut warnings = 0usize;
-    let mut passed = 0usize;
-    let skip: std::collections::HashSet<_> = skip.into_iter().collect();
+    let mut r = LintExecutionResult::default();
+    let mut lint_config = load_lint_config(root)?;
+    lint_config.apply(overrides);
+    let skip: std::collections::HashSet<_> = lint_config.skip.iter().map(String::as_str).collect();
     let (mut applicable_lints, skipped_lints): (Vec<_>, Vec<_>) = LINTS.iter().partition(|lint| {
         if skip.contains(lint.name) {
             return false;
@@ -273,8 +567,9 @@ fn lint_inner<'skip>(
         }
         true
     });
-    // SAFETY: Length must be smaller.
-    let skipped = skipped_lints.len();
+    for lint in skipped_lints {
+        r.record_skipped(lint.name, lint_config.severity(lint.name, lint.ty));
+    }
     // Default to predictablility here
     applicable_lints.sort_by(|a, b| a.name.cmp(b.name));
     // Split the lints by type
@@ -330,59 +625,95 @@ fn lint_inner<'skip>(
     results.extend(recursive_errors.into_iter().map(|(lint, e)| (lint, e)));
     // Any recursive lint still in this list succeeded.
     results.extend(recursive_lints.into_iter().map(|lint| (lint, lint_ok())));
-    for (lint, r) in results {
+    for (lint, lint_r) in results {
         let name = lint.name;
-        let r = match r {
+        let lint_r = match lint_r {
             Ok(r) => r,
             Err(e) => anyhow::bail!("Unexpected runtime error running lint {name}: {e}"),
         };
+        let ty = lint_config.severity(name, lint.ty);
+        r.record(&mut output, name, &ty, lint_r)?;
+    }
 
-        if let Err(e) = r {
-            match lint.ty {
-                LintType::Fatal => {
-                    writeln!(output, "Failed lint: {name}: {e}")?;
-                    fatal += 1;
-                }
-                LintType::Warning => {
-                    writeln!(output, "Lint warning: {name}: {e}")?;
-                    warnings += 1;
-                }
+    // External lints are run after all builtin lints. They are executables
+    // from the target root, so they are only run when that is the running
+    // root (i.e. inside the container being built); we don't want to execute
+    // binaries from an arbitrary `--rootfs` on the host.
+    let external = find_external_lints(root)?;
+    if root_type == RootType::Running {
+        record_external_lints(root, &external, config, &skip, &lint_config, &mut r, output)?;
+    } else if !external.is_empty() {
+        writeln!(
+            output,
+            "Not running {} external lint(s) for an alternative root",
+            external.len()
+        )?;
+    }
+
+    Ok(r)
+}
+
+/// Run the provided external lints, recording their results.
+fn record_external_lints(
+    root: &Dir,
+    external: &[String],
+    config: &LintExecutionConfig,
+    skip: &std::collections::HashSet<&str>,
+    lint_config: &LintConfig,
+    r: &mut LintExecutionResult,
+    mut output: impl std::io::Write,
+) -> Result<()> {
+    for name in external {
+        for ext_r in run_external_lint(root, name, RootType::Running, config)? {
+            if skip.contains(ext_r.name.as_str()) {
+                r.record_skipped(&ext_r.name, lint_config.severity(&ext_r.name, ext_r.ty));
+                continue;
             }
-        } else {
-            // We'll be quiet for now
-            tracing::debug!("OK {name} (type={:?})", lint.ty);
-            passed += 1;
+            let lint_r = match ext_r.error {
+                Some(e) => Err(LintError::new(e)),
+                None => Ok(()),
+            };
+            let ty = lint_config.severity(&ext_r.name, ext_r.ty);
+            r.record(&mut output, &ext_r.name, &ty, lint_r)?;
         }
     }
-
-    Ok(LintExecutionResult {
-        passed,
-        skipped,
-        warnings,
-        fatal,
-    })
+    Ok(())
 }
 
 #[context("Linting")]
-pub(crate) fn lint<'skip>(
+pub(crate) fn lint(
     root: &Dir,
     warning_disposition: WarningDisposition,
     root_type: RootType,
-    skip: impl IntoIterator<Item = &'skip str>,
+    overrides: &LintOverrides,
     mut output: impl std::io::Write,
     no_truncate: bool,
+    format: LintFormat,
 ) -> Result<()> {
     let config = LintExecutionConfig { no_truncate };
-    let r = lint_inner(root, root_type, &config, skip, &mut output)?;
+    let fatal_count = |r: &LintExecutionResult| {
+        if matches!(warning_disposition, WarningDisposition::FatalWarnings) {
+            r.fatal + r.warnings
+        } else {
+            r.fatal
+        }
+    };
+    if format == LintFormat::Json {
+        let r = lint_inner(root, root_type, &config, overrides, std::io::sink())?;
+        serde_json::to_writer_pretty(&mut output, &r)?;
+        writeln!(output)?;
+        let fatal = fatal_count(&r);
+        if fatal > 0 {
+            anyhow::bail!("Checks failed: {}", fatal)
+        }
+        return Ok(());
+    }
+    let r = lint_inner(root, root_type, &config, overrides, &mut output)?;
     writeln!(output, "Checks passed: {}", r.passed)?;
     if r.skipped > 0 {
         writeln!(output, "Checks skipped: {}", r.skipped)?;
     }
-    let fatal = if matches!(warning_disposition, WarningDisposition::FatalWarnings) {
-        r.fatal + r.warnings
-    } else {
-        r.fatal
-    };
+    let fatal = fatal_count(&r);
     if r.warnings > 0 {
         writeln!(output, "Warnings: {}", r.warnings)?;
     }
@@ -465,7 +796,8 @@ static LINT_KARGS: Lint = Lint::new_fatal(
     check_parse_kargs,
 );
 fn check_parse_kargs(root: &Dir, _config: &LintExecutionConfig) -> LintResult {
-    let args = crate::bootc_kargs::get_kargs_in_root(root, ARCH)?;
+    let host = crate::bootc_kargs::HostProperties::for_arch(ARCH);
+    let args = crate::bootc_kargs::get_kargs_in_root(root, &host)?;
     tracing::debug!("found kargs: {args:?}");
     lint_ok()
 }
@@ -704,11 +1036,11 @@ fn check_var_tmpfiles(_root: &Dir, config: &LintExecutionConfig) -> LintResult {
     }
     let mut msg = String::new();
     let header = "Found content in /var missing systemd tmpfiles.d entries";
-    format_items(config, header, r.tmpfiles.iter().map(|v| v as &_), &mut msg)?;
+    let mut truncated = format_items(config, header, r.tmpfiles.iter().map(|v| v as &_), &mut msg)?;
     let header = "Found non-directory/non-symlink files in /var";
     let items = r.unsupported.iter().map(PathQuotedDisplay::new);
-    format_items(config, header, items, &mut msg)?;
-    lint_err(msg)
+    truncated += format_items(config, header, items, &mut msg)?;
+    lint_err_truncated(msg, truncated)
 }
 
 #[distributed_slice(LINTS)]
@@ -736,10 +1068,10 @@ fn check_sysusers(rootfs: &Dir, config: &LintExecutionConfig) -> LintResult {
     let mut msg = String::new();
     let header = "Found /etc/passwd entry without corresponding systemd sysusers.d";
     let items = r.missing_users.iter().map(|v| v as &dyn std::fmt::Display);
-    format_items(config, header, items, &mut msg)?;
+    let mut truncated = format_items(config, header, items, &mut msg)?;
     let header = "Found /etc/group entry without corresponding systemd sysusers.d";
-    format_items(config, header, r.missing_groups.into_iter(), &mut msg)?;
-    lint_err(msg)
+    truncated += format_items(config, header, r.missing_groups.into_iter(), &mut msg)?;
+    lint_err_truncated(msg, truncated)
 }
 
 #[distributed_slice(LINTS)]
@@ -772,10 +1104,158 @@ fn check_boot(root: &Dir, config: &LintExecutionConfig) -> LintResult {
     format_lint_err_from_items(config, header, items)
 }
 
+/// Files holding machine identity; an empty file or `uninitialized` is expected.
+const MACHINE_ID_FILES: &[&str] = &["etc/machine-id", "var/lib/dbus/machine-id"];
+/// Per-machine state, as (directory, filename prefix, filename suffix).
+const PER_MACHINE_FILES: &[(&str, &str, &str)] = &[
+    ("etc/ssh", "ssh_host_", ""),
+    ("var/lib/dhclient", "", ".lease"),
+    ("var/lib/dhclient", "", ".leases"),
+    ("var/lib/dhcp", "", ".leases"),
+    ("var/lib/NetworkManager", "", ".lease"),
+    ("var/lib/systemd", "random-seed", ""),
+    ("var/lib/systemd", "credential.secret", ""),
+];
+
+#[distributed_slice(LINTS)]
+static LINT_PER_MACHINE_STATE: Lint = Lint::new_warning(
+    "per-machine-state",
+    indoc! { r#"
+Check for state which should be unique to each machine, such as a populated
+/etc/machine-id, SSH host keys, DHCP leases or the systemd random seed.
+This must never be shipped in an image, as every system installed from it
+would share it. Consider making this fatal via /usr/lib/bootc/lint-config.toml.
+"#},
+    check_per_machine_state,
+);
+fn check_per_machine_state(root: &Dir, config: &LintExecutionConfig) -> LintResult {
+    let mut found = BTreeSet::new();
+    for path in MACHINE_ID_FILES {
+        let Some(meta) = root.symlink_metadata_optional(path)? else {
+            continue;
+        };
+        if !meta.is_file() || meta.size() == 0 {
+            continue;
+        }
+        let contents = root.read_to_string(path)?;
+        let contents = contents.trim();
+        if !(contents.is_empty() || contents == "uninitialized") {
+            found.insert(Utf8PathBuf::from(format!("/{path}")));
+        }
+    }
+    for (dir, prefix, suffix) in PER_MACHINE_FILES {
+        let Some(d) = root.open_dir_optional(dir)? else {
+            continue;
+        };
+        for ent in d.entries_utf8()? {
+            let ent = ent?;
+            let name = ent.file_name()?;
+            if !ent.file_type()?.is_file() {
+                continue;
+            }
+            if name.starts_with(prefix) && name.ends_with(suffix) {
+                found.insert(Utf8Path::new("/").join(dir).join(name));
+            }
+        }
+    }
+    if found.is_empty() {
+        return lint_ok();
+    }
+    let header = "Found per-machine state";
+    let items = found.iter().map(PathQuotedDisplay::new);
+    format_lint_err_from_items(config, header, items)
+}
+
+/// Well-known programs which are expected to be setuid/setgid or have file capabilities.
+const PRIVILEGED_ALLOWLIST: &[&str] = &[
+    "/usr/bin/at",
+    "/usr/bin/chage",
+    "/usr/bin/chfn",
+    "/usr/bin/chsh",
+    "/usr/bin/crontab",
+    "/usr/bin/fusermount",
+    "/usr/bin/fusermount3",
+    "/usr/bin/gpasswd",
+    "/usr/bin/mount",
+    "/usr/bin/newgidmap",
+    "/usr/bin/newgrp",
+    "/usr/bin/newuidmap",
+    "/usr/bin/passwd",
+    "/usr/bin/ping",
+    "/usr/bin/pkexec",
+    "/usr/bin/ssh-agent",
+    "/usr/bin/su",
+    "/usr/bin/sudo",
+    "/usr/bin/umount",
+    "/usr/bin/write",
+    "/usr/lib/polkit-1/polkit-agent-helper-1",
+    "/usr/libexec/dbus-1/dbus-daemon-launch-helper",
+    "/usr/libexec/openssh/ssh-keysign",
+    "/usr/sbin/arping",
+    "/usr/sbin/clockdiff",
+    "/usr/sbin/grub2-set-bootflag",
+    "/usr/sbin/mount.nfs",
+    "/usr/sbin/pam_timestamp_check",
+    "/usr/sbin/unix_chkpwd",
+    "/usr/sbin/userhelper",
+];
+
+#[distributed_slice(LINTS)]
+static LINT_PRIVILEGED_FILES: Lint = Lint {
+    name: "privileged-files",
+    description: indoc! { r#"
+Check for setuid or setgid files, and files with capabilities (the `security.capability`
+extended attribute), other than a set of well-known programs. These often do not work
+as expected when deployed, as extended attributes may be handled differently by the
+container build and the host storage; and unexpected privileged files are a security risk.
+"#},
+    ty: LintType::Warning,
+    root_type: None,
+    f: LintFnTy::Recursive(check_privileged_files),
+};
+fn check_privileged_files(e: &WalkComponent, _config: &LintExecutionConfig) -> LintRecursiveResult {
+    use std::os::fd::AsRawFd;
+
+    if !e.file_type.is_file() {
+        return lint_ok();
+    }
+    let mode = e.dir.symlink_metadata(e.filename)?.mode();
+    let fdpath = Path::new(&format!("/proc/self/fd/{}", e.dir.as_raw_fd())).join(e.filename);
+    // A zero-length buffer just queries the size
+    let mut buf = [0u8; 0];
+    let has_caps = match rustix::fs::lgetxattr(&fdpath, "security.capability", &mut buf) {
+        Ok(_) => true,
+        Err(rustix::io::Errno::NODATA | rustix::io::Errno::OPNOTSUPP) => false,
+        Err(err) => return Err(err.into()),
+    };
+    let kinds = [
+        (mode & libc::S_ISUID != 0, "setuid"),
+        (mode & libc::S_ISGID != 0, "setgid"),
+        (has_caps, "capabilities"),
+    ]
+    .into_iter()
+    .filter_map(|(v, name)| v.then_some(name))
+    .collect::<Vec<_>>();
+    let allowed = e
+        .path
+        .to_str()
+        .is_some_and(|p| PRIVILEGED_ALLOWLIST.contains(&p));
+    if kinds.is_empty() || allowed {
+        return lint_ok();
+    }
+    lint_err(format!(
+        "Found unexpected privileged file {} ({})",
+        PathQuotedDisplay::new(&e.path),
+        kinds.join(", ")
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::LazyLock;
 
+    use cap_std::fs::Permissions;
+
     use super::*;
 
     static ALTROOT_LINTS: LazyLock<usize> = LazyLock::new(|| {
@@ -847,10 +1327,86 @@ mod tests {
         let mut out = Vec::new();
         let warnings = WarningDisposition::FatalWarnings;
         let root_type = RootType::Alternative;
-        lint(root, warnings, root_type, [], &mut out, config.no_truncate).unwrap();
+        let format = LintFormat::Human;
+        lint(
+            root,
+            warnings,
+            root_type,
+            &LintOverrides::default(),
+            &mut out,
+            config.no_truncate,
+            format,
+        )
+        .unwrap();
         root.create_dir_all("var/run/foo")?;
         let mut out = Vec::new();
-        assert!(lint(root, warnings, root_type, [], &mut out, config.no_truncate).is_err());
+        assert!(lint(
+            root,
+            warnings,
+            root_type,
+            &LintOverrides::default(),
+            &mut out,
+            config.no_truncate,
+            format
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_json() -> Result<()> {
+        let root = &passing_fixture()?;
+        let warnings = WarningDisposition::AllowWarnings;
+        let root_type = RootType::Alternative;
+        root.create_dir_all("var/log/dnf")?;
+        for i in 0..(DEFAULT_TRUNCATED_OUTPUT.get() + 2) {
+            root.write(format!("var/log/dnf/dnf{i}.log"), b"dummy dnf log")?;
+        }
+        let mut out = Vec::new();
+        lint(
+            root,
+            warnings,
+            root_type,
+            &LintOverrides {
+                skip: vec!["var-run".into()],
+                ..Default::default()
+            },
+            &mut out,
+            false,
+            LintFormat::Json,
+        )?;
+        let v: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(v["warnings"], 1);
+        assert_eq!(v["fatal"], 0);
+        let results = v["results"].as_array().unwrap();
+        assert_eq!(results.len(), LINTS.len());
+        let find = |name: &str| results.iter().find(|r| r["name"] == name).unwrap();
+        let varlog = find("var-log");
+        assert_eq!(varlog["type"], "warning");
+        assert_eq!(varlog["status"], "failed");
+        assert_eq!(varlog["truncated"], 2);
+        assert!(varlog["message"]
+            .as_str()
+            .unwrap()
+            .contains("non-empty logfiles"));
+        assert_eq!(find("var-run")["status"], "skipped");
+        assert_eq!(find("var-run")["message"], serde_json::Value::Null);
+
+        // A failure is still an error, after the output has been written
+        let mut out = Vec::new();
+        let warnings = WarningDisposition::FatalWarnings;
+        assert!(lint(
+            root,
+            warnings,
+            root_type,
+            &LintOverrides::default(),
+            &mut out,
+            false,
+            LintFormat::Json
+        )
+        .is_err());
+        let v: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(v["warnings"], 1);
         Ok(())
     }
 
@@ -862,14 +1418,18 @@ mod tests {
         // Verify that all lints run
         let mut out = Vec::new();
         let root_type = RootType::Alternative;
-        let r = lint_inner(root, root_type, config, [], &mut out).unwrap();
+        let r = lint_inner(root, root_type, config, &LintOverrides::default(), &mut out).unwrap();
         let running_only_lints = LINTS.len().checked_sub(*ALTROOT_LINTS).unwrap();
         assert_eq!(r.warnings, 0);
         assert_eq!(r.fatal, 0);
         assert_eq!(r.skipped, running_only_lints);
         assert_eq!(r.passed, *ALTROOT_LINTS);
 
-        let r = lint_inner(root, root_type, config, ["var-log"], &mut out).unwrap();
+        let overrides = &LintOverrides {
+            skip: vec!["var-log".into()],
+            ..Default::default()
+        };
+        let r = lint_inner(root, root_type, config, overrides, &mut out).unwrap();
         // Trigger a failure in var-log by creating a non-empty log file.
         root.create_dir_all("var/log/dnf")?;
         root.write("var/log/dnf/dnf.log", b"dummy dnf log")?;
@@ -880,7 +1440,7 @@ mod tests {
 
         // But verify that not skipping it results in a warning
         let mut out = Vec::new();
-        let r = lint_inner(root, root_type, config, [], &mut out).unwrap();
+        let r = lint_inner(root, root_type, config, &LintOverrides::default(), &mut out).unwrap();
         assert_eq!(r.passed, ALTROOT_LINTS.checked_sub(1).unwrap());
         assert_eq!(r.fatal, 0);
         assert_eq!(r.skipped, running_only_lints);
@@ -888,6 +1448,146 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_lint_config() -> Result<()> {
+        let root = &passing_fixture()?;
+        let config = &LintExecutionConfig::default();
+        let defaults = &LintOverrides::default();
+        let root_type = RootType::Alternative;
+        let running_only_lints = LINTS.len().checked_sub(*ALTROOT_LINTS).unwrap();
+        assert_eq!(load_lint_config(root)?, LintConfig::default());
+
+        // Trigger a warning in var-log, and promote it to fatal
+        root.create_dir_all("var/log/dnf")?;
+        root.write("var/log/dnf/dnf.log", b"dummy dnf log")?;
+        root.create_dir_all("usr/lib/bootc")?;
+        root.write(
+            LINT_CONFIG,
+            indoc! { r#"
+            [lints]
+            severity = { var-log = "fatal" }
+            "# },
+        )?;
+        let r = lint_inner(root, root_type, config, defaults, &mut Vec::new())?;
+        assert_eq!(r.fatal, 1);
+        assert_eq!(r.warnings, 0);
+
+        // Now skip it by default
+        root.write(
+            LINT_CONFIG,
+            indoc! { r#"
+            [lints]
+            skip = ["var-log"]
+            severity = { var-log = "fatal", var-run = "warning" }
+            "# },
+        )?;
+        let r = lint_inner(root, root_type, config, defaults, &mut Vec::new())?;
+        assert_eq!(r.fatal, 0);
+        assert_eq!(r.skipped, running_only_lints + 1);
+
+        // Demoted lints are reported as warnings
+        root.create_dir_all("var/run/foo")?;
+        let r = lint_inner(root, root_type, config, defaults, &mut Vec::new())?;
+        assert_eq!(r.fatal, 0);
+        assert_eq!(r.warnings, 1);
+        // But --fatal-warnings still applies
+        let warnings = WarningDisposition::FatalWarnings;
+        let mut out = Vec::new();
+        let format = LintFormat::Human;
+        assert!(lint(root, warnings, root_type, defaults, &mut out, false, format).is_err());
+
+        // Command line flags take precedence over the configuration
+        let overrides = &LintOverrides {
+            include: vec!["var-log".into()],
+            severity: vec![("var-run".into(), LintType::Fatal)],
+            ..Default::default()
+        };
+        let r = lint_inner(root, root_type, config, overrides, &mut Vec::new())?;
+        assert_eq!(r.fatal, 2);
+        assert_eq!(r.warnings, 0);
+        assert_eq!(r.skipped, running_only_lints);
+        let overrides = &LintOverrides {
+            skip: vec!["var-run".into()],
+            severity: vec![("var-log".into(), LintType::Warning)],
+            include: vec!["var-log".into()],
+        };
+        let r = lint_inner(root, root_type, config, overrides, &mut Vec::new())?;
+        assert_eq!(r.fatal, 0);
+        assert_eq!(r.warnings, 1);
+        assert_eq!(r.skipped, running_only_lints + 1);
+
+        root.write(LINT_CONFIG, "[lints]\nunknown = true\n")?;
+        assert!(load_lint_config(root).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_severity_override() {
+        let (name, ty) = parse_severity_override("var-log=fatal").unwrap();
+        assert_eq!(name, "var-log");
+        assert_eq!(ty, LintType::Fatal);
+        for v in ["var-log", "var-log=error", "var-log:warning"] {
+            assert!(parse_severity_override(v).is_err(), "{v}");
+        }
+    }
+
+    #[test]
+    fn test_external_lints() -> Result<()> {
+        let root = &passing_fixture()?;
+        let config = &LintExecutionConfig::default();
+        let root_type = RootType::Alternative;
+        root.create_dir_all(EXTERNAL_LINTS_DIR)?;
+        let script = indoc! { r#"
+            #!/bin/sh
+            set -eu
+            grep -q '"version":1' -
+            test -d /proc/self/fd/3/usr/lib/bootc/lints.d
+            echo '{"results": [
+              {"name": "example-ok", "type": "fatal"},
+              {"name": "example-warn", "type": "warning", "error": "something is off"}
+            ]}'
+        "# };
+        let path = format!("{EXTERNAL_LINTS_DIR}/10-example");
+        root.atomic_write_with_perms(&path, script, Permissions::from_mode(0o755))?;
+        // Not executable, so ignored
+        root.write(format!("{EXTERNAL_LINTS_DIR}/README"), "docs")?;
+
+        let external = &find_external_lints(root)?;
+        assert_eq!(external, &["10-example"]);
+
+        // External lints are not run for an alternative root
+        let mut out = Vec::new();
+        let r = lint_inner(root, root_type, config, &LintOverrides::default(), &mut out)?;
+        assert_eq!(r.passed, *ALTROOT_LINTS);
+        assert_eq!(r.warnings, 0);
+        let out = String::from_utf8(out)?;
+        assert!(out.contains("Not running 1 external lint(s) for an alternative root"));
+
+        let lint_config = &LintConfig::default();
+        let mut out = Vec::new();
+        let mut r = LintExecutionResult::default();
+        let skip = Default::default();
+        record_external_lints(root, external, config, &skip, lint_config, &mut r, &mut out)?;
+        assert_eq!(r.passed, 1);
+        assert_eq!(r.warnings, 1);
+        let out = String::from_utf8(out)?;
+        assert!(out.contains("Lint warning: example-warn: something is off"));
+
+        let mut r = LintExecutionResult::default();
+        let skip = ["example-warn"].into_iter().collect();
+        let out = std::io::sink();
+        record_external_lints(root, external, config, &skip, lint_config, &mut r, out)?;
+        assert_eq!(r.warnings, 0);
+        assert_eq!(r.skipped, 1);
+
+        // Names may not conflict with builtin lints
+        let script =
+            "#!/bin/sh\necho '{\"results\": [{\"name\": \"var-run\", \"type\": \"fatal\"}]}'\n";
+        root.atomic_write_with_perms(&path, script, Permissions::from_mode(0o755))?;
+        assert!(run_external_lint(root, "10-example", RootType::Running, config).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_kernel_lint() -> Result<()> {
         let root = &fixture()?;
@@ -982,6 +1682,52 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_per_machine_state() -> Result<()> {
+        let root = &passing_fixture()?;
+        let config = &LintExecutionConfig::default();
+        check_per_machine_state(root, config).unwrap().unwrap();
+        root.create_dir_all("etc/ssh")?;
+        root.write("etc/machine-id", "uninitialized\n")?;
+        root.write("etc/ssh/sshd_config", "")?;
+        check_per_machine_state(root, config).unwrap().unwrap();
+
+        root.write("etc/machine-id", "a2a8ec23d4ed4d6b9a6c6e6b1d57a4b1\n")?;
+        root.write("etc/ssh/ssh_host_ed25519_key", "key")?;
+        root.create_dir_all("var/lib/NetworkManager")?;
+        root.write("var/lib/NetworkManager/internal-eth0.lease", "lease")?;
+        let Err(e) = check_per_machine_state(root, config).unwrap() else {
+            unreachable!()
+        };
+        let e = e.to_string();
+        for path in [
+            "/etc/machine-id",
+            "/etc/ssh/ssh_host_ed25519_key",
+            "/var/lib/NetworkManager/internal-eth0.lease",
+        ] {
+            assert!(e.contains(path), "{e}");
+        }
+        assert!(!e.contains("sshd_config"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_privileged_files() -> Result<()> {
+        let root = &passing_fixture()?;
+        let config = &LintExecutionConfig::default();
+        run_recursive_lint(root, check_privileged_files, config)?.unwrap();
+        root.create_dir_all("usr/bin")?;
+        root.atomic_write_with_perms("usr/bin/su", "su", Permissions::from_mode(0o4755))?;
+        root.atomic_write_with_perms("usr/bin/true", "true", Permissions::from_mode(0o755))?;
+        run_recursive_lint(root, check_privileged_files, config)?.unwrap();
+        root.atomic_write_with_perms("usr/bin/foo", "foo", Permissions::from_mode(0o4755))?;
+        let Err(e) = run_recursive_lint(root, check_privileged_files, config)? else {
+            unreachable!()
+        };
+        assert!(e.to_string().contains("/usr/bin/foo (setuid)"), "{e}");
+        Ok(())
+    }
+
     fn run_recursive_lint(
         root: &Dir,
         f: LintRecursiveFn,
@@ -1143,9 +1889,13 @@ mod tests {
     #[test]
     fn test_list() {
         let mut r = Vec::new();
-        lint_list(&mut r).unwrap();
+        lint_list(&mut r, LintFormat::Human).unwrap();
         let lints: Vec<serde_yaml::Value> = serde_yaml::from_slice(&r).unwrap();
         assert_eq!(lints.len(), LINTS.len());
+        let mut r = Vec::new();
+        lint_list(&mut r, LintFormat::Json).unwrap();
+        let lints: Vec<serde_json::Value> = serde_json::from_slice(&r).unwrap();
+        assert_eq!(lints.len(), LINTS.len());
     }
 
     #[test]
@@ -2,3 +2,5 @@
 pub(crate) const BOOTC_COMPAT_LABEL: &str = "containers.bootc";
 /// The current single well-known value for the label.
 pub(crate) const COMPAT_LABEL_V1: &str = "1";
+/// An optional label on images declaring the stateroot they prefer to be deployed into.
+pub(crate) const STATEROOT_LABEL: &str = "containers.bootc.stateroot";
@@ -0,0 +1,255 @@
+//! # Offline maintenance of disk images
+//!
+//! Implementation of `--target-image`, which runs a mutating operation
+//! (e.g. `bootc upgrade`) against the installation in a disk image file
+//! instead of the booted system. The image is attached (via loopback for
+//! raw images, or `qemu-nbd` for qcow2), its root and `/boot` partitions
+//! are mounted in our private mount namespace, and the ostree sysroot
+//! there is used in place of the booted one.
+//!
+//! As nothing runs at shutdown for an offline image, new deployments
+//! are written directly instead of being staged.
+
+use std::io::Read;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use bootc_blockdev::{LoopbackDevice, PartitionTable};
+use bootc_utils::CommandRunExt;
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+
+/// Where the target image is mounted.
+const TARGET_MOUNT: &str = "/run/bootc/offline";
+/// The magic number at the start of a qcow2 image.
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+/// The partition labels used by `bootc install to-disk`.
+const ROOT_LABEL: &str = "root";
+const BOOT_LABEL: &str = "boot";
+/// The maximum number of nbd devices we'll look at.
+const MAX_NBD: u32 = 16;
+
+static TARGET: OnceLock<Target> = OnceLock::new();
+
+/// Options for operating on a disk image.
+#[derive(Debug, Default, clap::Args, PartialEq, Eq)]
+pub(crate) struct TargetImageOpts {
+    /// Operate on the installation in this disk image file (raw or qcow2) instead
+    /// of the booted system.  The image must not be in use.
+    #[clap(long)]
+    pub(crate) target_image: Option<Utf8PathBuf>,
+}
+
+/// The currently attached target image.
+#[derive(Debug)]
+pub(crate) struct Target {
+    /// The block device for the whole disk
+    pub(crate) device: Utf8PathBuf,
+    /// Where the root filesystem (the ostree physical root) is mounted
+    pub(crate) sysroot: Utf8PathBuf,
+}
+
+/// Return the target image, if we're operating on one.
+pub(crate) fn target() -> Option<&'static Target> {
+    TARGET.get()
+}
+
+enum Attachment {
+    Loopback(LoopbackDevice),
+    Nbd(Utf8PathBuf),
+}
+
+/// An attached and mounted disk image; dropping this unmounts and detaches it.
+pub(crate) struct TargetImage {
+    attachment: Option<Attachment>,
+    /// Mounted filesystems, in the order they were mounted
+    mounts: Vec<Utf8PathBuf>,
+}
+
+fn is_qcow2(path: &Utf8Path) -> Result<bool> {
+    let mut f = std::fs::File::open(path).with_context(|| format!("Opening {path}"))?;
+    let mut buf = [0u8; QCOW2_MAGIC.len()];
+    match f.read_exact(&mut buf) {
+        Ok(()) => Ok(buf == QCOW2_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Find the root and (optional) /boot partitions.
+fn find_partitions(table: &PartitionTable) -> Result<(&Utf8Path, Option<&Utf8Path>)> {
+    let by_label = |label: &str| {
+        table
+            .partitions
+            .iter()
+            .find(|p| p.name.as_deref() == Some(label))
+            .map(|p| p.path())
+    };
+    let root = by_label(ROOT_LABEL).ok_or_else(|| {
+        anyhow::anyhow!(
+            "No partition labeled {ROOT_LABEL} found in {}",
+            table.path()
+        )
+    })?;
+    Ok((root, by_label(BOOT_LABEL)))
+}
+
+/// Attach a qcow2 image to the first free nbd device.
+fn attach_nbd(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    // This is a no-op if the module is already loaded
+    Command::new("modprobe")
+        .args(["nbd", &format!("nbds_max={MAX_NBD}")])
+        .run_capture_stderr()?;
+    for i in 0..MAX_NBD {
+        let size = std::fs::read_to_string(format!("/sys/block/nbd{i}/size"))
+            .with_context(|| format!("Querying nbd{i}"))?;
+        if size.trim() != "0" {
+            continue;
+        }
+        let dev = Utf8PathBuf::from(format!("/dev/nbd{i}"));
+        Command::new("qemu-nbd")
+            .args(["--connect", dev.as_str(), "--discard=unmap"])
+            .arg(path)
+            .run_capture_stderr()?;
+        return Ok(dev);
+    }
+    anyhow::bail!("No free nbd device found")
+}
+
+fn unmount(target: &Utf8Path) -> Result<()> {
+    Command::new("umount")
+        .arg(target)
+        .run_capture_stderr()
+        .with_context(|| format!("Unmounting {target}"))
+}
+
+impl TargetImage {
+    /// Attach and mount the disk image, and make it the target of later operations.
+    ///
+    /// This must be called after entering a private mount namespace.
+    #[context("Opening target image {path}")]
+    pub(crate) fn open(path: &Utf8Path) -> Result<Self> {
+        let mut r = Self {
+            attachment: None,
+            mounts: Vec::new(),
+        };
+        let device = if is_qcow2(path)? {
+            let dev = attach_nbd(path)?;
+            r.attachment = Some(Attachment::Nbd(dev.clone()));
+            // Wait for the partitions to show up
+            crate::install::baseline::udev_settle()?;
+            dev
+        } else {
+            let dev = LoopbackDevice::new(path.as_std_path())?;
+            let path = dev.path().to_owned();
+            r.attachment = Some(Attachment::Loopback(dev));
+            path
+        };
+        let table = bootc_blockdev::partitions_of(&device)?;
+        let (root, boot) = find_partitions(&table)?;
+
+        let sysroot = Utf8PathBuf::from(TARGET_MOUNT);
+        std::fs::create_dir_all(&sysroot)?;
+        bootc_mount::mount(root.as_str(), &sysroot)?;
+        r.mounts.push(sysroot.clone());
+        if let Some(boot) = boot {
+            let target = sysroot.join("boot");
+            bootc_mount::mount(boot.as_str(), &target)?;
+            r.mounts.push(target);
+        }
+        anyhow::ensure!(
+            sysroot.join("ostree/deploy").try_exists()?,
+            "No ostree deployments found in {path}"
+        );
+
+        TARGET
+            .set(Target { device, sysroot })
+            .map_err(|_| anyhow::anyhow!("A target image is already open"))?;
+        Ok(r)
+    }
+
+    fn close_impl(&mut self) -> Result<()> {
+        while let Some(m) = self.mounts.pop() {
+            unmount(&m)?;
+        }
+        match self.attachment.take() {
+            Some(Attachment::Loopback(dev)) => dev.close()?,
+            Some(Attachment::Nbd(dev)) => {
+                Command::new("qemu-nbd")
+                    .args(["--disconnect", dev.as_str()])
+                    .run_capture_stderr()?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TargetImage {
+    fn drop(&mut self) {
+        if let Err(e) = self.close_impl() {
+            tracing::warn!("Failed to close target image: {e:#}");
+        }
+    }
+}
+
+/// If a target image was requested, enter a private mount namespace and open it.
+/// The returned value must be kept alive for the duration of the operation.
+pub(crate) fn open_requested(opts: &TargetImageOpts) -> Result<Option<TargetImage>> {
+    let Some(path) = opts.target_image.as_deref() else {
+        return Ok(None);
+    };
+    crate::cli::require_root(false)?;
+    // Note that this may re-execute the process, so must come before anything else.
+    crate::cli::ensure_self_unshared_mount_namespace()?;
+    TargetImage::open(path).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_qcow2() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path()).unwrap();
+        let qcow2 = td.join("disk.qcow2");
+        std::fs::write(&qcow2, b"QFI\xfb\x00\x00\x00\x03")?;
+        assert!(is_qcow2(&qcow2)?);
+        let raw = td.join("disk.raw");
+        std::fs::write(&raw, [0u8; 512])?;
+        assert!(!is_qcow2(&raw)?);
+        let empty = td.join("empty");
+        std::fs::write(&empty, b"")?;
+        assert!(!is_qcow2(&empty)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_partitions() -> Result<()> {
+        let table: PartitionTable = serde_json::from_str(
+            r#"{
+                "label": "gpt",
+                "id": "A67AA901-2C72-4818-B098-7F1CAC127279",
+                "device": "/dev/loop0",
+                "partitions": [
+                    {"node": "/dev/loop0p1", "start": 2048, "size": 2048, "type": "21686148-6449-6E6F-744E-656564454649", "name": "BIOS-BOOT"},
+                    {"node": "/dev/loop0p2", "start": 4096, "size": 1048576, "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "name": "EFI-SYSTEM"},
+                    {"node": "/dev/loop0p3", "start": 1052672, "size": 1044480, "type": "0FC63DAF-8483-4772-8E79-3D69D8477DE4", "name": "boot"},
+                    {"node": "/dev/loop0p4", "start": 2097152, "size": 18874335, "type": "0FC63DAF-8483-4772-8E79-3D69D8477DE4", "name": "root"}
+                ]
+            }"#,
+        )?;
+        let (root, boot) = find_partitions(&table)?;
+        assert_eq!(root, "/dev/loop0p4");
+        assert_eq!(boot.unwrap(), "/dev/loop0p3");
+
+        let table = PartitionTable {
+            partitions: table.partitions.into_iter().take(3).collect(),
+            ..table
+        };
+        assert!(find_partitions(&table).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! # Selecting a platform from multi-platform images
+//!
+//! By default, an image which is a manifest list (OCI image index) is resolved
+//! to the variant matching the host. `--platform` selects a different one; this
+//! is recorded in the deployment origin so that later upgrades keep using it.
+
+use std::os::fd::OwnedFd;
+use std::process::Command;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use ostree_ext::oci_spec::image::{Arch, Os, Platform, PlatformBuilder};
+use ostree_ext::ostree;
+use serde::Deserialize;
+
+use crate::deploy::PullOptions;
+use crate::spec::ImageReference;
+
+const AUTHFILE_FD: i32 = 3;
+
+/// Parse a platform of the form `OS/ARCH[/VARIANT]`, e.g. `linux/arm64/v8`.
+pub(crate) fn parse(s: &str) -> Result<Platform> {
+    let mut parts = s.split('/');
+    let (Some(os), Some(arch)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("Invalid platform {s}: expected OS/ARCH[/VARIANT]");
+    };
+    let variant = parts.next();
+    anyhow::ensure!(
+        !os.is_empty() && !arch.is_empty() && variant != Some("") && parts.next().is_none(),
+        "Invalid platform {s}: expected OS/ARCH[/VARIANT]"
+    );
+    let mut b = PlatformBuilder::default();
+    b = b.os(Os::from(os)).architecture(Arch::from(arch));
+    if let Some(variant) = variant {
+        b = b.variant(variant.to_owned());
+    }
+    Ok(b.build()?)
+}
+
+/// Format a platform as `OS/ARCH[/VARIANT]`.
+pub(crate) fn format(p: &Platform) -> String {
+    let mut r = format!("{}/{}", p.os(), p.architecture());
+    if let Some(variant) = p.variant() {
+        r.push('/');
+        r.push_str(variant);
+    }
+    r
+}
+
+/// Whether `requested` selects the platform `candidate`; a requested platform
+/// without a variant matches any variant.
+fn matches(requested: &Platform, candidate: &Platform) -> bool {
+    requested.os() == candidate.os()
+        && requested.architecture() == candidate.architecture()
+        && (requested.variant().is_none() || requested.variant() == candidate.variant())
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    platform: Option<IndexPlatform>,
+}
+
+#[derive(Deserialize)]
+struct IndexPlatform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Option<Vec<IndexEntry>>,
+}
+
+/// Return the platforms in a raw manifest, which is empty unless it is a
+/// manifest list. Entries such as attestations (`unknown/unknown`) are skipped.
+fn platforms_of_raw_manifest(buf: &[u8]) -> Result<Vec<Platform>> {
+    let index: Index = serde_json::from_slice(buf).context("Parsing manifest")?;
+    let mut r = Vec::new();
+    for p in index
+        .manifests
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|m| m.platform)
+        .filter(|p| p.os != "unknown" && p.architecture != "unknown")
+    {
+        let mut b = PlatformBuilder::default()
+            .os(Os::from(p.os.as_str()))
+            .architecture(Arch::from(p.architecture.as_str()));
+        if let Some(variant) = p.variant.filter(|v| !v.is_empty()) {
+            b = b.variant(variant);
+        }
+        let p = b.build()?;
+        if !r.contains(&p) {
+            r.push(p);
+        }
+    }
+    Ok(r)
+}
+
+/// Query the platforms provided by an image. This is only supported for the
+/// registry transport, and is empty if the image is not a manifest list.
+pub(crate) fn query_available(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    opts: &PullOptions,
+) -> Result<Vec<Platform>> {
+    if imgref.transport != "registry" {
+        return Ok(Vec::new());
+    }
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let mut cmd = Command::new("skopeo");
+    cmd.args(["inspect", "--raw"]);
+    // An authfile given on the command line takes precedence over a host-level pull
+    // secret, which in turn takes precedence over the global authfile
+    if let Some(authfile) = opts.authfile.as_ref() {
+        cmd.arg("--authfile").arg(authfile);
+    } else if let Some(auth) = crate::secrets::open_registry_auth(repo)? {
+        cmd.take_fd_n(Arc::new(OwnedFd::from(auth)), AUTHFILE_FD);
+        cmd.args(["--authfile", &format!("/proc/self/fd/{AUTHFILE_FD}")]);
+    } else if let Some((authfile, _fd)) = ostree_ext::globals::get_global_authfile(root)? {
+        cmd.arg("--authfile").arg(authfile.as_str());
+    }
+    if let Some(certdir) =
+        ostree_ext::container::registry_certificate_directory(root, &imgref.image)?
+    {
+        cmd.arg("--cert-dir").arg(certdir.as_str());
+    }
+    cmd.arg(format!("docker://{}", imgref.image));
+    let buf = cmd.run_get_string()?;
+    platforms_of_raw_manifest(buf.as_bytes())
+}
+
+/// Verify that `requested` is one of the `available` platforms (if known).
+pub(crate) fn check(requested: &Platform, available: &[Platform]) -> Result<()> {
+    if available.is_empty() || available.iter().any(|p| matches(requested, p)) {
+        return Ok(());
+    }
+    let available = available.iter().map(format).collect::<Vec<_>>().join(", ");
+    anyhow::bail!(
+        "Image has no {} variant (available: {available})",
+        format(requested)
+    )
+}
+
+/// Check that the image provides the platform selected in `opts` (if any) before
+/// pulling it. Any error querying the image is ignored here; it will be reported
+/// by the pull.
+pub(crate) fn preflight(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+    opts: &PullOptions,
+) -> Result<()> {
+    let Some(requested) = opts.platform.as_ref() else {
+        return Ok(());
+    };
+    let available = match query_available(repo, imgref, opts) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::debug!("Failed to query platforms of {imgref:#}: {e:#}");
+            return Ok(());
+        }
+    };
+    check(requested, &available).with_context(|| format!("Fetching {imgref:#}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for s in ["linux/amd64", "linux/arm64/v8", "linux/s390x"] {
+            assert_eq!(format(&parse(s).unwrap()), s);
+        }
+        let p = parse("linux/arm64").unwrap();
+        assert_eq!(p.architecture(), &Arch::ARM64);
+        assert!(p.variant().is_none());
+        for s in [
+            "",
+            "linux",
+            "linux/",
+            "/amd64",
+            "linux/arm64/",
+            "linux/arm/v7/x",
+        ] {
+            assert!(parse(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let index = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {"digest": "sha256:a", "size": 1, "platform": {"os": "linux", "architecture": "amd64"}},
+                {"digest": "sha256:b", "size": 1, "platform": {"os": "linux", "architecture": "arm64", "variant": "v8"}},
+                {"digest": "sha256:c", "size": 1, "platform": {"os": "unknown", "architecture": "unknown"}}
+            ]
+        }"#;
+        let available = platforms_of_raw_manifest(index.as_bytes())?;
+        assert_eq!(
+            available.iter().map(format).collect::<Vec<_>>(),
+            ["linux/amd64", "linux/arm64/v8"]
+        );
+        check(&parse("linux/amd64")?, &available)?;
+        check(&parse("linux/arm64")?, &available)?;
+        check(&parse("linux/arm64/v8")?, &available)?;
+        let e = check(&parse("linux/ppc64le")?, &available).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Image has no linux/ppc64le variant (available: linux/amd64, linux/arm64/v8)"
+        );
+        assert!(check(&parse("linux/arm64/v9")?, &available).is_err());
+        // A single-platform image
+        let manifest = r#"{"schemaVersion": 2, "config": {}, "layers": []}"#;
+        let available = platforms_of_raw_manifest(manifest.as_bytes())?;
+        assert!(available.is_empty());
+        check(&parse("linux/ppc64le")?, &available)?;
+        Ok(())
+    }
+}
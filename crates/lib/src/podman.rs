@@ -19,6 +19,18 @@ pub(crate) struct Inspect {
 pub(crate) struct ImageListEntry {
     pub(crate) id: String,
     pub(crate) names: Option<Vec<String>>,
+    /// The manifest digest
+    #[serde(default)]
+    pub(crate) digest: Option<String>,
+    /// All known manifest digests (e.g. of a manifest list and the image)
+    #[serde(default)]
+    pub(crate) digests: Option<Vec<String>>,
+    /// The size in bytes
+    #[serde(default)]
+    pub(crate) size: Option<u64>,
+    /// The creation time, in seconds since the epoch
+    #[serde(default)]
+    pub(crate) created: Option<i64>,
 }
 
 /// Given an image ID, return its manifest digest
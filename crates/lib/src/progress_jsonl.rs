@@ -17,8 +17,30 @@ use tokio::sync::Mutex;
 // Maximum number of times per second that an event will be written.
 const REFRESH_HZ: u16 = 5;
 
-/// Semantic version of the protocol.
-const API_VERSION: &str = "0.1.0";
+/// Version of the progress protocol, negotiated via `--json-fd-version`.
+///
+/// Version 2 is a superset of version 1, adding [`Event::Phase`], [`Event::Layer`],
+/// [`Event::Retry`] and [`Event::CheckFailed`]. Fields will not be removed or change meaning within a version.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub(crate) enum ProgressVersion {
+    /// The initial (unstable) protocol.
+    #[default]
+    #[value(name = "1")]
+    V1,
+    /// The stable protocol with per-layer, phase and retry events.
+    #[value(name = "2")]
+    V2,
+}
+
+impl ProgressVersion {
+    /// Semantic version of the protocol, sent in [`Event::Start`].
+    pub(crate) fn api_version(&self) -> &'static str {
+        match self {
+            ProgressVersion::V1 => "0.1.0",
+            ProgressVersion::V2 => "2.0.0",
+        }
+    }
+}
 
 /// An incremental update to e.g. a container image layer download.
 /// The first time a given "subtask" name is seen, a new progress bar should be created.
@@ -135,6 +157,68 @@ pub enum Event<'t> {
         /// The currently running subtasks.
         subtasks: Vec<SubTaskStep<'t>>,
     },
+    /// A transition between phases of an operation; since version 2.
+    Phase {
+        /// The new phase: one of `pulling`, `importing`, `staging`, `cleanup` or `complete`.
+        #[serde(borrow)]
+        phase: Cow<'t, str>,
+        /// A human readable description of the phase if i18n is not available.
+        #[serde(borrow)]
+        description: Cow<'t, str>,
+    },
+    /// Byte level progress for a single container image layer; since version 2.
+    Layer {
+        /// The full digest of the layer (e.g. `sha256:...`).
+        #[serde(borrow)]
+        digest: Cow<'t, str>,
+        /// A machine readable type for the layer (e.g., "ostree_chunk", "layer").
+        #[serde(borrow)]
+        layer_type: Cow<'t, str>,
+        /// The number of bytes already fetched.
+        bytes: u64,
+        /// Total number of bytes (the compressed size of the layer).
+        bytes_total: u64,
+        /// True once the layer has been fetched.
+        completed: bool,
+    },
+    /// An operation failed and is being retried; since version 2.
+    Retry {
+        /// A machine readable type (e.g., pulling) for the task being retried.
+        #[serde(borrow)]
+        task: Cow<'t, str>,
+        /// A human and machine readable unique identifier for the task.
+        #[serde(borrow)]
+        id: Cow<'t, str>,
+        /// The attempt which is starting, beginning at 2 for the first retry.
+        attempt: u32,
+        /// A human readable description of the error which caused the retry.
+        #[serde(borrow)]
+        error: Cow<'t, str>,
+    },
+    /// A pre-flight check failed, aborting the operation; since version 2.
+    CheckFailed {
+        /// A machine readable identifier for the check (e.g., disk-space, script:NAME).
+        #[serde(borrow)]
+        check: Cow<'t, str>,
+        /// A human readable description of why the check failed.
+        #[serde(borrow)]
+        reason: Cow<'t, str>,
+    },
+}
+
+impl Event<'_> {
+    /// The first protocol version which includes this event.
+    pub(crate) fn min_version(&self) -> ProgressVersion {
+        match self {
+            Event::Start { .. } | Event::ProgressBytes { .. } | Event::ProgressSteps { .. } => {
+                ProgressVersion::V1
+            }
+            Event::Phase { .. }
+            | Event::Layer { .. }
+            | Event::Retry { .. }
+            | Event::CheckFailed { .. } => ProgressVersion::V2,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -164,6 +248,7 @@ struct ProgressWriterInner {
 #[derive(Clone, Debug, Default)]
 pub(crate) struct ProgressWriter {
     inner: Arc<Mutex<Option<ProgressWriterInner>>>,
+    version: ProgressVersion,
 }
 
 impl TryFrom<OwnedFd> for ProgressWriter {
@@ -184,6 +269,7 @@ impl From<Sender> for ProgressWriter {
         };
         Self {
             inner: Arc::new(Some(inner).into()),
+            version: Default::default(),
         }
     }
 }
@@ -198,6 +284,13 @@ impl TryFrom<RawProgressFd> for ProgressWriter {
 }
 
 impl ProgressWriter {
+    /// Use the provided protocol version; events introduced in later
+    /// versions will not be sent.
+    pub(crate) fn with_version(mut self, version: ProgressVersion) -> Self {
+        self.version = version;
+        self
+    }
+
     /// Serialize the target value as a single line of JSON and write it.
     async fn send_impl_inner<T: Serialize>(inner: &mut ProgressWriterInner, v: T) -> Result<()> {
         // canon_json is guaranteed not to output newlines here
@@ -222,7 +315,7 @@ impl ProgressWriter {
         if !inner.sent_start {
             inner.sent_start = true;
             let start = Event::Start {
-                version: API_VERSION.into(),
+                version: self.version.api_version().into(),
             };
             Self::send_impl_inner(inner, &start).await?;
         }
@@ -247,6 +340,9 @@ impl ProgressWriter {
 
     /// Send an event.
     pub(crate) async fn send(&self, event: Event<'_>) {
+        if event.min_version() > self.version {
+            return;
+        }
         if let Err(e) = self.send_impl(event, true).await {
             eprintln!("Failed to write to jsonl: {}", e);
             // Stop writing to fd but let process continue
@@ -257,6 +353,9 @@ impl ProgressWriter {
 
     /// Send an event that can be dropped.
     pub(crate) async fn send_lossy(&self, event: Event<'_>) {
+        if event.min_version() > self.version {
+            return;
+        }
         if let Err(e) = self.send_impl(event, false).await {
             eprintln!("Failed to write to jsonl: {}", e);
             // Stop writing to fd but let process continue
@@ -336,7 +435,7 @@ mod test {
                 let expected_value = if !got_first {
                     got_first = true;
                     &Event::Start {
-                        version: API_VERSION.into(),
+                        version: ProgressVersion::V1.api_version().into(),
                     }
                 } else {
                     expected.next().unwrap()
@@ -348,4 +447,54 @@ mod test {
         tokio::try_join!(sender, receiver)?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_versions() -> Result<()> {
+        let phase = Event::Phase {
+            phase: "pulling".into(),
+            description: "Pulling image".into(),
+        };
+        let steps = Event::ProgressSteps {
+            task: "sometask".into(),
+            description: "somedesc".into(),
+            id: "someid".into(),
+            steps_cached: 0,
+            steps: 0,
+            steps_total: 1,
+            subtasks: Vec::new(),
+        };
+        for version in [ProgressVersion::V1, ProgressVersion::V2] {
+            let (send, recv) = tokio::net::unix::pipe::pipe()?;
+            let events = [phase.clone(), steps.clone()];
+            let sender = async move {
+                let w = ProgressWriter::try_from(send)?.with_version(version);
+                for event in events {
+                    w.send(event).await;
+                }
+                anyhow::Ok(())
+            };
+            let receiver = async move {
+                let mut lines = BufReader::new(recv).lines();
+                let mut found = Vec::new();
+                while let Some(line) = lines.next_line().await? {
+                    found.push(line);
+                }
+                anyhow::Ok(found)
+            };
+            let ((), found) = tokio::try_join!(sender, receiver)?;
+            let found = found
+                .iter()
+                .map(|l| serde_json::from_str::<Event>(l))
+                .collect::<serde_json::Result<Vec<_>>>()?;
+            let start = Event::Start {
+                version: version.api_version().into(),
+            };
+            let expected = match version {
+                ProgressVersion::V1 => vec![start, steps.clone()],
+                ProgressVersion::V2 => vec![start, phase.clone(), steps.clone()],
+            };
+            assert_eq!(found, expected);
+        }
+        Ok(())
+    }
 }
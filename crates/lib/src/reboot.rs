@@ -2,32 +2,91 @@
 
 use std::{io::Write, process::Command};
 
+use anyhow::Result;
 use bootc_utils::CommandRunExt;
 use fn_error_context::context;
+use ostree_ext::{gio, ostree};
 
-/// Initiate a system reboot.
+use crate::store::Storage;
+
+/// Whether to only restart userspace (`systemctl soft-reboot`) when applying a change.
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum SoftRebootMode {
+    /// Fail if the kernel, initramfs or kernel arguments changed
+    Required,
+    /// Perform a soft reboot if possible, otherwise a full reboot
+    Auto,
+}
+
+/// Run the provided `systemctl` verb in a transient unit, and wait to be terminated.
 /// This function will only return in case of error.
-#[context("Initiating reboot")]
-pub(crate) fn reboot() -> anyhow::Result<()> {
+fn systemctl_and_wait(args: &[&str]) -> Result<()> {
     // Flush output streams
     let _ = std::io::stdout().flush();
     let _ = std::io::stderr().flush();
     Command::new("systemd-run")
-        .args([
-            "--quiet",
-            "--",
-            "systemctl",
-            "reboot",
-            "--message=Initiated by bootc",
-        ])
+        .args(["--quiet", "--", "systemctl"])
+        .args(args)
         .run_capture_stderr()?;
     // We expect to be terminated via SIGTERM here. We sleep
     // instead of exiting an exit would necessarily appear
     // racy to calling processes in that sometimes we'd
     // win the race to exit, other times might get killed
     // via SIGTERM.
-    tracing::debug!("Initiated reboot, sleeping");
+    tracing::debug!("Initiated {}, sleeping", args[0]);
     loop {
         std::thread::park();
     }
 }
+
+/// Initiate a system reboot.
+/// This function will only return in case of error.
+#[context("Initiating reboot")]
+pub(crate) fn reboot() -> Result<()> {
+    systemctl_and_wait(&["reboot", "--message=Initiated by bootc"])
+}
+
+/// Initiate a soft reboot into `target`, which must be soft reboot capable.
+/// This function will only return in case of error.
+#[context("Initiating soft reboot")]
+fn soft_reboot(sysroot: &Storage, target: &ostree::Deployment) -> Result<()> {
+    let index = target.index().to_string();
+    // ostree takes the sysroot lock to set up the next root.
+    sysroot.sysroot.unlock();
+    Command::new("ostree")
+        .args(["admin", "prepare-soft-reboot", index.as_str()])
+        .run_capture_stderr()?;
+    systemctl_and_wait(&["soft-reboot"])
+}
+
+/// Restart into the default deployment for the next boot (i.e. a staged
+/// deployment or a queued rollback). With `soft`, a soft reboot is performed
+/// if the kernel, initramfs and kernel arguments are unchanged.
+/// This function will only return in case of error.
+pub(crate) fn reboot_into_default(sysroot: &Storage, soft: Option<SoftRebootMode>) -> Result<()> {
+    let Some(mode) = soft else {
+        return reboot();
+    };
+    // Pick up a newly staged deployment or reordering
+    sysroot.load(gio::Cancellable::NONE)?;
+    let booted = sysroot.require_booted_deployment()?;
+    let target = sysroot
+        .deployments()
+        .into_iter()
+        .next()
+        .filter(|d| !d.equal(&booted));
+    let reason = match target {
+        Some(target) if crate::status::soft_reboot_capable(&booted, &target) => {
+            return soft_reboot(sysroot, &target);
+        }
+        Some(_) => "the kernel, initramfs or kernel arguments changed",
+        None => "no new deployment is queued",
+    };
+    match mode {
+        SoftRebootMode::Required => anyhow::bail!("Cannot soft reboot: {reason}"),
+        SoftRebootMode::Auto => {
+            println!("Cannot soft reboot ({reason}); rebooting");
+            reboot()
+        }
+    }
+}
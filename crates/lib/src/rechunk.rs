@@ -0,0 +1,388 @@
+//! # Splitting a root filesystem into content-stable layers
+//!
+//! Container builds typically produce a bootc image whose content
+//! is all in a single (or a few large) layers, which makes every update
+//! a full download. This takes such a root filesystem, commits it to a
+//! temporary ostree repository, and encapsulates it again using the ostree-ext
+//! chunking logic, grouping files by the package which owns them according
+//! to the rpm database in the image.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::os::fd::{AsFd, OwnedFd};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::chunking::ObjectMetaSized;
+use ostree_ext::container::{Config, ImageReference};
+use ostree_ext::objectsource::{ObjectMeta, ObjectSourceMeta};
+use ostree_ext::oci_spec::image as oci_image;
+use ostree_ext::ostree::{self, gio};
+use ostree_ext::prelude::*;
+
+/// The rpm database, relative to the root filesystem.
+const RPMDB: &str = "usr/lib/sysimage/rpm";
+/// The ref for the root filesystem as imported from tar.
+const IMPORT_REF: &str = "bootc/rechunk/import";
+/// The ref for the relabeled commit which we export.
+const COMMIT_REF: &str = "bootc/rechunk";
+/// The component for files not owned by any package.
+const UNPACKAGED: &str = "unpackaged";
+
+/// A package from the rpm database.
+#[derive(Debug, PartialEq, Eq)]
+struct Package {
+    name: String,
+    nevra: String,
+    srcrpm: String,
+    buildtime: u64,
+}
+
+/// Parse the output of `rpm -qa` with one tab-separated
+/// `name nevra sourcerpm buildtime` line per package.
+fn parse_packages(s: &str) -> Result<Vec<Package>> {
+    s.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let mut parts = l.split('\t');
+            let mut next = || {
+                parts
+                    .next()
+                    .ok_or_else(|| anyhow!("Invalid package line: {l}"))
+            };
+            let name = next()?.to_owned();
+            let nevra = next()?.to_owned();
+            let srcrpm = next()?.to_owned();
+            let buildtime = next()?
+                .parse()
+                .with_context(|| format!("Parsing buildtime: {l}"))?;
+            Ok(Package {
+                name,
+                nevra,
+                srcrpm,
+                buildtime,
+            })
+        })
+        .collect()
+}
+
+/// Parse the output of `rpm -qa` with one tab-separated `name path` line
+/// per file, into a mapping from path to owning package. For paths owned
+/// by multiple packages the first one wins.
+fn parse_file_owners(s: &str) -> HashMap<String, String> {
+    let mut r = HashMap::new();
+    for (name, path) in s.lines().filter_map(|l| l.split_once('\t')) {
+        if !path.starts_with('/') {
+            continue;
+        }
+        r.entry(path.to_owned()).or_insert_with(|| name.to_owned());
+    }
+    r
+}
+
+/// Map a path in the ostree commit back to the path recorded in the rpm database;
+/// this undoes the `/etc` and `/var` relocation done when importing.
+fn rpm_path(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix("/usr/etc/") {
+        format!("/etc/{rest}")
+    } else if let Some(rest) = path.strip_prefix("/usr/share/factory/var/") {
+        format!("/var/{rest}")
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Gather the path and content object checksum of each non-directory in the commit.
+fn walk_commit(
+    path: &mut Utf8PathBuf,
+    dir: &gio::File,
+    out: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let e = dir.enumerate_children(
+        "standard::name,standard::type",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        cancellable,
+    )?;
+    for child in e {
+        let childi = child?;
+        let name: Utf8PathBuf = childi.name().try_into()?;
+        let child = dir.child(&name);
+        path.push(&name);
+        match childi.file_type() {
+            gio::FileType::Regular | gio::FileType::SymbolicLink => {
+                let child = child.downcast::<ostree::RepoFile>().unwrap();
+                out.push((path.to_string(), child.checksum().to_string()));
+            }
+            gio::FileType::Directory => walk_commit(path, &child, out)?,
+            o => anyhow::bail!("Unhandled file type: {o:?}"),
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Assign each content object to a package owning one of its paths. Each
+/// package becomes a component; newer builds get a larger change time offset,
+/// and unowned files are treated as changing most frequently.
+fn build_contentmeta(
+    packages: &[Package],
+    owners: &HashMap<String, String>,
+    objects: &[(String, String)],
+) -> ObjectMeta {
+    let packages: HashMap<&str, &Package> = packages.iter().map(|p| (p.name.as_str(), p)).collect();
+    let oldest = packages.values().map(|p| p.buildtime).min().unwrap_or(0);
+    let mut ret = ObjectMeta::default();
+    let mut components = HashMap::<&str, Rc<str>>::new();
+    for (path, checksum) in objects {
+        let owner = owners
+            .get(&rpm_path(path))
+            .map(|s| s.as_str())
+            .filter(|name| packages.contains_key(name))
+            .unwrap_or(UNPACKAGED);
+        let id = components
+            .entry(owner)
+            .or_insert_with(|| Rc::from(owner))
+            .clone();
+        match ret.map.get_mut(checksum) {
+            // Prefer a package over unpackaged for identical content
+            Some(existing) if &**existing == UNPACKAGED => *existing = id,
+            Some(_) => {}
+            None => {
+                ret.map.insert(checksum.clone(), id);
+            }
+        }
+    }
+    let used: HashSet<&str> = ret.map.values().map(|id| &**id).collect();
+    for (name, id) in components {
+        if !used.contains(name) {
+            continue;
+        }
+        let meta = match packages.get(name) {
+            Some(pkg) => ObjectSourceMeta {
+                identifier: id,
+                name: Rc::from(pkg.nevra.as_str()),
+                srcid: Rc::from(pkg.srcrpm.as_str()),
+                change_time_offset: ((pkg.buildtime - oldest) / 3600)
+                    .try_into()
+                    .unwrap_or(u32::MAX),
+                change_frequency: 1,
+            },
+            None => ObjectSourceMeta {
+                identifier: id.clone(),
+                name: id.clone(),
+                srcid: id,
+                change_time_offset: u32::MAX,
+                change_frequency: u32::MAX,
+            },
+        };
+        ret.set.insert(meta);
+    }
+    ret
+}
+
+/// Query the rpm database in the target root, if any.
+fn query_rpmdb(rootfs: &Utf8Path) -> Result<Option<(Vec<Package>, HashMap<String, String>)>> {
+    let d = Dir::open_ambient_dir(rootfs, cap_std::ambient_authority())?;
+    if !d.try_exists(RPMDB)? {
+        return Ok(None);
+    }
+    let dbpath = format!("--dbpath={rootfs}/{RPMDB}");
+    let packages = Command::new("rpm")
+        .args([dbpath.as_str(), "-qa", "--qf"])
+        .arg("%{NAME}\t%{NEVRA}\t%{SOURCERPM}\t%{BUILDTIME}\n")
+        .run_get_string()
+        .context("Querying packages")?;
+    let packages = parse_packages(&packages)?;
+    let files = Command::new("rpm")
+        .args([dbpath.as_str(), "-qa", "--qf"])
+        .arg("[%{NAME}\t%{FILENAMES}\n]")
+        .run_get_string()
+        .context("Querying files")?;
+    Ok(Some((packages, parse_file_owners(&files))))
+}
+
+/// Commit the root filesystem into the repository, returning the commit.
+#[context("Importing {rootfs}")]
+async fn import_rootfs(repo: &ostree::Repo, rootfs: &Utf8Path) -> Result<String> {
+    let mut tar = tokio::process::Command::new("tar")
+        .args(["--xattrs", "--xattrs-include=*", "--numeric-owner", "-C"])
+        .arg(rootfs.as_str())
+        .args(["-cf", "-", "."])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    // Safety: We passed piped() above
+    let stdout = tar.stdout.take().unwrap();
+    let opts = ostree_ext::tar::WriteTarOptions {
+        allow_nonusr: true,
+        ..Default::default()
+    };
+    let r = ostree_ext::tar::write_tar(
+        repo,
+        stdout,
+        oci_image::MediaType::ImageLayer,
+        IMPORT_REF,
+        Some(opts),
+    )
+    .await?;
+    let st = tar.wait().await?;
+    if !st.success() {
+        anyhow::bail!("tar failed: {st:?}");
+    }
+    for (prefix, n) in r.filtered {
+        tracing::debug!("Filtered {n} paths in {prefix}");
+    }
+    // The xattrs from the build environment are not necessarily the right ones;
+    // relabel using the policy shipped in the image itself.
+    let repofd = repo.dfd_as_file()?;
+    Command::new("ostree")
+        .args([
+            "commit",
+            "--repo=/proc/self/fd/3",
+            "--no-bindings",
+            "--selinux-policy-from-base",
+            &format!("--tree=ref={}", r.commit),
+            "--branch",
+            COMMIT_REF,
+        ])
+        .take_fd_n(Arc::new(OwnedFd::from(repofd)), 3)
+        .run_capture_stderr()?;
+    let commit = repo.require_rev(COMMIT_REF)?;
+    Ok(commit.to_string())
+}
+
+/// Re-encapsulate the root filesystem at `rootfs` into a chunked image at `dest`.
+#[context("Rechunking {rootfs}")]
+pub(crate) async fn rechunk(
+    rootfs: &Utf8Path,
+    dest: &ImageReference,
+    max_layers: Option<NonZeroU32>,
+    labels: BTreeMap<String, String>,
+    container_config: Option<&Utf8Path>,
+) -> Result<()> {
+    let container_config: Option<oci_image::Config> = container_config
+        .map(|p| {
+            let f = std::fs::File::open(p).with_context(|| format!("Opening {p}"))?;
+            serde_json::from_reader(std::io::BufReader::new(f))
+                .with_context(|| format!("Parsing {p}"))
+        })
+        .transpose()?;
+    let tempdir = tempfile::tempdir_in("/var/tmp")?;
+    let td = Dir::open_ambient_dir(tempdir.path(), cap_std::ambient_authority())?;
+    let repo = ostree::Repo::create_at_dir(td.as_fd(), ".", ostree::RepoMode::BareUser, None)
+        .context("Creating temporary repository")?;
+    let commit = import_rootfs(&repo, rootfs).await?;
+
+    let contentmeta = match query_rpmdb(rootfs)? {
+        Some((packages, owners)) => {
+            let (root, _) = repo.read_commit(&commit, gio::Cancellable::NONE)?;
+            let mut objects = Vec::new();
+            walk_commit(&mut Utf8PathBuf::from("/"), &root, &mut objects)?;
+            let meta = build_contentmeta(&packages, &owners, &objects);
+            println!(
+                "Found {} packages owning {} objects",
+                packages.len(),
+                meta.map.len()
+            );
+            Some(ObjectMetaSized::compute_sizes(&repo, meta)?)
+        }
+        None => {
+            println!("No rpm database found; chunking by directory");
+            None
+        }
+    };
+    let mut labels = labels;
+    labels
+        .entry(crate::metadata::BOOTC_COMPAT_LABEL.to_owned())
+        .or_insert_with(|| crate::metadata::COMPAT_LABEL_V1.to_owned());
+    let config = Config {
+        labels: Some(labels),
+        cmd: None,
+    };
+    let opts = ostree_ext::container::ExportOpts {
+        container_config,
+        package_contentmeta: contentmeta.as_ref(),
+        max_layers,
+        directory_chunk_size: contentmeta
+            .is_none()
+            .then_some(ostree_ext::chunking::DEFAULT_DIRECTORY_CHUNK_SIZE),
+        reproducible: true,
+        ..Default::default()
+    };
+    let digest =
+        ostree_ext::container::encapsulate(&repo, &commit, &config, Some(opts), dest).await?;
+    println!("Pushed: {dest} {digest}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_contentmeta() -> Result<()> {
+        let packages = parse_packages(indoc::indoc! {"
+            bash\tbash-5.2.26-3.fc40.x86_64\tbash-5.2.26-3.fc40.src.rpm\t1700000000
+            setup\tsetup-2.14.5-2.fc40.noarch\tsetup-2.14.5-2.fc40.src.rpm\t1700007200
+        "})?;
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[1].buildtime, 1700007200);
+        assert!(parse_packages("bash\tbash-5.2").is_err());
+
+        let owners = parse_file_owners(indoc::indoc! {"
+            bash\t/usr/bin/bash
+            bash\t/usr/bin/sh
+            bash\t/usr/bin/sh-copy
+            setup\t/etc/passwd
+            setup\t(contains no files)
+            other\t/usr/bin/sh
+        "});
+        assert_eq!(owners.len(), 4);
+        assert_eq!(owners["/usr/bin/sh"], "bash");
+
+        let objects = [
+            ("/usr/bin/bash", "aa"),
+            ("/usr/bin/sh", "bb"),
+            ("/usr/etc/passwd", "cc"),
+            ("/usr/lib/os-release", "dd"),
+            // Same content as bash; the package wins over unpackaged
+            ("/usr/libexec/bash-copy", "aa"),
+            ("/usr/libexec/sh-copy", "ee"),
+            ("/usr/bin/sh-copy", "ee"),
+        ]
+        .map(|(p, c)| (p.to_owned(), c.to_owned()));
+        let meta = build_contentmeta(&packages, &owners, &objects);
+        assert_eq!(meta.map.len(), 5);
+        assert_eq!(&*meta.map["ee"], "bash");
+        assert_eq!(&*meta.map["aa"], "bash");
+        assert_eq!(&*meta.map["cc"], "setup");
+        assert_eq!(&*meta.map["dd"], UNPACKAGED);
+        assert_eq!(meta.set.len(), 3);
+        let setup = meta.set.iter().find(|m| &*m.identifier == "setup").unwrap();
+        assert_eq!(&*setup.name, "setup-2.14.5-2.fc40.noarch");
+        assert_eq!(setup.change_time_offset, 2);
+        let unpackaged = meta
+            .set
+            .iter()
+            .find(|m| &*m.identifier == UNPACKAGED)
+            .unwrap();
+        assert_eq!(unpackaged.change_frequency, u32::MAX);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpm_path() {
+        assert_eq!(rpm_path("/usr/etc/passwd"), "/etc/passwd");
+        assert_eq!(rpm_path("/usr/share/factory/var/lib/x"), "/var/lib/x");
+        assert_eq!(rpm_path("/usr/bin/bash"), "/usr/bin/bash");
+    }
+}
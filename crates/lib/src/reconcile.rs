@@ -0,0 +1,161 @@
+//! # Reconciling the host specification
+//!
+//! `bootc edit` accepts a desired [`HostSpec`]; this computes the minimal
+//! set of operations which move the system from its current state to it.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::bootc_kargs::KargsDelta;
+use crate::spec::{HostSpec, ImageReference};
+
+/// A single operation of a [`Plan`].
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "operation", rename_all = "kebab-case")]
+pub(crate) enum Operation {
+    /// Swap the boot order of the booted and rollback deployments
+    Rollback,
+    /// Fetch and stage a new image, applying a kernel argument change in the same deployment
+    Stage {
+        image: ImageReference,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        kargs: Option<KargsDelta>,
+    },
+    /// Queue new kernel arguments, on top of the staged deployment if there is one
+    SetKargs {
+        kargs: Vec<String>,
+        #[serde(flatten)]
+        delta: KargsDelta,
+    },
+}
+
+/// The operations needed to reach a desired host specification, in order.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(crate) struct Plan {
+    pub(crate) operations: Vec<Operation>,
+}
+
+impl Plan {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+/// Compute the operations which move from `current` (whose staged or booted deployment
+/// uses `current_kargs`) to `desired`.
+pub(crate) fn plan(
+    current: &HostSpec,
+    current_kargs: &[String],
+    desired: &HostSpec,
+) -> Result<Plan> {
+    current.verify_transition(desired)?;
+    let mut operations = Vec::new();
+    let kargs = desired
+        .kernel_arguments
+        .as_ref()
+        .filter(|k| k.as_slice() != current_kargs);
+    if current.boot_order != desired.boot_order {
+        if kargs.is_some() {
+            anyhow::bail!("Invalid state transition: rollback and kernel argument change");
+        }
+        operations.push(Operation::Rollback);
+    }
+    let delta = kargs.map(|k| KargsDelta::new(current_kargs, k));
+    if current.image != desired.image {
+        let image = desired
+            .image
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Missing image in specification"))?;
+        operations.push(Operation::Stage {
+            image,
+            kargs: delta,
+        });
+    } else if let (Some(kargs), Some(delta)) = (kargs, delta) {
+        operations.push(Operation::SetKargs {
+            kargs: kargs.clone(),
+            delta,
+        });
+    }
+    Ok(Plan { operations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::BootOrder;
+
+    fn spec(image: &str, kargs: Option<&[&str]>) -> HostSpec {
+        HostSpec {
+            image: Some(ImageReference {
+                image: image.into(),
+                transport: "registry".into(),
+                signature: None,
+            }),
+            boot_order: BootOrder::Default,
+            kernel_arguments: kargs.map(|k| k.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn test_plan() -> Result<()> {
+        let current_kargs = ["rw", "console=ttyS0"].map(String::from);
+        let current = spec("quay.io/example/os:1", None);
+
+        // No changes; the kernel arguments may be unset or unchanged
+        assert!(plan(&current, &current_kargs, &current)?.is_empty());
+        let same = spec("quay.io/example/os:1", Some(&["rw", "console=ttyS0"]));
+        assert!(plan(&current, &current_kargs, &same)?.is_empty());
+
+        let desired = spec("quay.io/example/os:2", None);
+        assert_eq!(
+            plan(&current, &current_kargs, &desired)?.operations,
+            [Operation::Stage {
+                image: desired.image.clone().unwrap(),
+                kargs: None,
+            }]
+        );
+
+        let desired = spec("quay.io/example/os:1", Some(&["rw", "quiet"]));
+        let delta = KargsDelta {
+            added: vec!["quiet".into()],
+            removed: vec!["console=ttyS0".into()],
+        };
+        let p = plan(&current, &current_kargs, &desired)?;
+        assert_eq!(
+            p.operations,
+            [Operation::SetKargs {
+                kargs: vec!["rw".into(), "quiet".into()],
+                delta: delta.clone(),
+            }]
+        );
+        let v = serde_json::to_value(&p)?;
+        assert_eq!(v["operations"][0]["operation"], "set-kargs");
+        assert_eq!(v["operations"][0]["added"][0], "quiet");
+
+        // With a new image, the kernel arguments are changed in the same deployment
+        let desired = spec("quay.io/example/os:2", Some(&["rw", "quiet"]));
+        let p = plan(&current, &current_kargs, &desired)?;
+        assert_eq!(
+            p.operations,
+            [Operation::Stage {
+                image: desired.image.clone().unwrap(),
+                kargs: Some(delta),
+            }]
+        );
+        let v = serde_json::to_value(&p)?;
+        assert_eq!(v["operations"][0]["kargs"]["removed"][0], "console=ttyS0");
+
+        let mut desired = current.clone();
+        desired.boot_order = BootOrder::Rollback;
+        assert_eq!(
+            plan(&current, &current_kargs, &desired)?.operations,
+            [Operation::Rollback]
+        );
+        desired.kernel_arguments = Some(vec!["rw".into()]);
+        assert!(plan(&current, &current_kargs, &desired).is_err());
+        desired.kernel_arguments = None;
+        desired.image = spec("quay.io/example/os:2", None).image;
+        assert!(plan(&current, &current_kargs, &desired).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! # Relabeling after SELinux policy changes
+//!
+//! When a new deployment ships a different SELinux policy than the deployment
+//! its `/etc` is merged from, files in `/etc` and `/var` may carry labels that
+//! are stale under the new policy. When staging, bootc compares the policy
+//! checksums and, if they differ, records the deployment in
+//! `/ostree/bootc/relabel.json`. On its first boot, `bootc-relabel.service`
+//! (via `bootc internals relabel-after-policy-change`) runs `restorecon` over
+//! `/etc` and `/var` and clears the record.
+//!
+//! Unlike a full autorelabel, this leaves the read-only `/usr` alone, so it
+//! also works when the root is a composefs mount. A pending relabel is shown
+//! by `bootc status`.
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::{Deserialize, Serialize};
+
+use crate::lifecycle::{deployment_id, entry_id};
+use crate::spec::Host;
+use crate::store::Storage;
+use crate::task::Task;
+
+/// The state file, relative to the physical root.
+const RELABEL_PATH: &str = "ostree/bootc/relabel.json";
+/// The directories which hold machine-local state carried across deployments
+const RELABEL_DIRS: &[&str] = &["/etc", "/var"];
+
+/// Deployments which need to be relabeled on their first boot.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct RelabelState {
+    /// Deployment identifiers, as used for the lifecycle state
+    pending: Vec<String>,
+}
+
+impl RelabelState {
+    /// Add a deployment, dropping any which no longer exist.
+    fn add(&mut self, id: &str, existing: &[String]) {
+        self.pending.retain(|p| p != id && existing.contains(p));
+        self.pending.push(id.to_owned());
+    }
+
+    /// Remove a deployment, returning whether it was pending.
+    fn take(&mut self, id: &str) -> bool {
+        let n = self.pending.len();
+        self.pending.retain(|p| p != id);
+        n != self.pending.len()
+    }
+}
+
+fn load(root: &Dir) -> Result<RelabelState> {
+    let Some(f) = root.open_optional(RELABEL_PATH)? else {
+        return Ok(RelabelState::default());
+    };
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Parsing {RELABEL_PATH}"))
+}
+
+fn store(root: &Dir, state: &RelabelState) -> Result<()> {
+    if state.pending.is_empty() {
+        root.remove_file_optional(RELABEL_PATH)?;
+        return Ok(());
+    }
+    root.create_dir_all(crate::store::BOOTC_ROOT)?;
+    root.atomic_write(RELABEL_PATH, serde_json::to_vec(state)?)
+        .with_context(|| format!("Writing {RELABEL_PATH}"))
+}
+
+/// The checksum of the SELinux policy in a root, if any.
+fn policy_checksum(root: &Dir) -> Result<Option<String>> {
+    let policy = crate::lsm::new_sepolicy_at(root)?;
+    Ok(policy.and_then(|p| p.csum()).map(|c| c.to_string()))
+}
+
+/// Record that `deployment` needs relabeling if its SELinux policy differs from
+/// that of `merge`, the deployment its `/etc` is merged from.
+#[context("Checking for SELinux policy changes")]
+pub(crate) fn schedule_if_policy_changed(
+    sysroot: &Storage,
+    merge: Option<&ostree::Deployment>,
+    deployment: &ostree::Deployment,
+) -> Result<()> {
+    let Some(merge) = merge else {
+        return Ok(());
+    };
+    let old = policy_checksum(&crate::utils::deployment_fd(sysroot, merge)?)?;
+    let new = policy_checksum(&crate::utils::deployment_fd(sysroot, deployment)?)?;
+    let Some(new) = new else {
+        return Ok(());
+    };
+    if old.as_deref() == Some(new.as_str()) {
+        return Ok(());
+    }
+    tracing::debug!("SELinux policy changed: {old:?} -> {new}");
+    let root = &crate::utils::sysroot_dir(sysroot)?;
+    let existing = sysroot
+        .deployments()
+        .iter()
+        .map(deployment_id)
+        .collect::<Vec<_>>();
+    let mut state = load(root)?;
+    state.add(&deployment_id(deployment), &existing);
+    store(root, &state)?;
+    println!("SELinux policy changed; /etc and /var will be relabeled on first boot");
+    Ok(())
+}
+
+/// Mark the boot entries which are pending a relabel.
+pub(crate) fn apply_to_host(sysroot: &Storage, host: &mut Host) -> Result<()> {
+    let state = load(&crate::utils::sysroot_dir(sysroot)?)?;
+    if state.pending.is_empty() {
+        return Ok(());
+    }
+    let status = &mut host.status;
+    let entries = [&mut status.staged, &mut status.booted, &mut status.rollback]
+        .into_iter()
+        .flat_map(|e| e.as_mut())
+        .chain(status.other_deployments.iter_mut());
+    for entry in entries {
+        if let Some(id) = entry_id(entry) {
+            entry.relabel_pending = state.pending.contains(&id);
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc internals relabel-after-policy-change`: relabel
+/// `/etc` and `/var` if the booted deployment is pending a relabel.
+#[context("Relabeling after SELinux policy change")]
+pub(crate) fn relabel_booted(sysroot: &Storage) -> Result<()> {
+    let booted = sysroot.require_booted_deployment()?;
+    let root = &crate::utils::sysroot_dir(sysroot)?;
+    let mut state = load(root)?;
+    if !state.take(&deployment_id(&booted)) {
+        println!("No relabel pending");
+        return Ok(());
+    }
+    if crate::lsm::selinux_enabled()? {
+        Task::new("Relabeling /etc and /var", "restorecon")
+            .args(["-R"])
+            .args(RELABEL_DIRS)
+            .run()?;
+        crate::journal::journal_send(
+            libsystemd::logging::Priority::Notice,
+            "Relabeled /etc and /var after SELinux policy change",
+            [("MESSAGE_ID", crate::journal::RELABEL_ID)].into_iter(),
+        );
+    } else {
+        println!("SELinux is disabled; skipping relabel");
+    }
+    store(root, &state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn test_state() -> Result<()> {
+        let mut state = RelabelState::default();
+        state.add("default/a.0", &[]);
+        assert_eq!(state.pending, ["default/a.0"]);
+        // Deployments which no longer exist are dropped
+        state.add("default/b.0", &["default/b.0".into()]);
+        assert_eq!(state.pending, ["default/b.0"]);
+        state.add("default/c.0", &["default/b.0".into(), "default/c.0".into()]);
+        assert_eq!(state.pending, ["default/b.0", "default/c.0"]);
+        assert!(state.take("default/b.0"));
+        assert!(!state.take("default/b.0"));
+        assert_eq!(state.pending, ["default/c.0"]);
+
+        let td = &cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(load(td)?, RelabelState::default());
+        store(td, &state)?;
+        assert_eq!(load(td)?, state);
+        // An empty state removes the file
+        store(td, &RelabelState::default())?;
+        assert!(!td.try_exists(RELABEL_PATH)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! # Retrying image pulls
+//!
+//! Transient network failures (e.g. a connection reset, or a registry returning
+//! 503) should not abort an update. The whole pull is retried with exponential
+//! backoff; since each fetched layer is stored as it completes, a retry only
+//! fetches the layers which were not yet complete. A partially fetched layer is
+//! fetched again from the start, as the image proxy does not support resuming
+//! a blob at an offset.
+//!
+//! Registry errors reach us as messages from the image proxy, so apart from
+//! I/O errors of our own, a failure is classified as transient by its message.
+//!
+//! This can be configured in `/etc/bootc/pull.toml` (or `/usr/lib/bootc/pull.toml`):
+//!
+//! ```toml
+//! [pull]
+//! # The number of retries after the first attempt; 0 disables retrying
+//! retries = 5
+//! # The delay before the first retry, doubled for each subsequent one
+//! initial-delay = "2s"
+//! # The maximum delay between retries
+//! max-delay = "1m"
+//! ```
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use fn_error_context::context;
+use ostree_ext::container::RegistryErrorKind;
+use serde::Deserialize;
+
+/// The name of the configuration file and section; see [`crate::hostconfig`]
+const CONFIG_NAME: &str = "pull";
+/// The default number of retries
+const DEFAULT_RETRIES: u32 = 3;
+/// The default delay before the first retry
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(2);
+/// The default maximum delay between retries
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Substrings of errors which indicate a (likely) transient network failure.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "connection reset",
+    "connection refused",
+    "broken pipe",
+    "timed out",
+    "timeout",
+    "unexpected eof",
+    "network is unreachable",
+    "no route to host",
+    "temporary failure in name resolution",
+    "too many requests",
+    "502 bad gateway",
+    "503 service unavailable",
+    "504 gateway timeout",
+];
+
+/// The `[pull]` section.
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct PullConfigFile {
+    retries: Option<u32>,
+    initial_delay: Option<String>,
+    max_delay: Option<String>,
+}
+
+/// The effective retry configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct RetryConfig {
+    /// The number of retries after the first attempt
+    pub(crate) retries: u32,
+    /// The delay before the first retry
+    pub(crate) initial_delay: Duration,
+    /// The maximum delay between retries
+    pub(crate) max_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The delay before the given attempt, where the first retry is attempt 2.
+    pub(crate) fn delay_for(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(2).min(31);
+        self.initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration> {
+    let d = bootc_utils::parse_duration(s)?;
+    d.to_std().with_context(|| format!("Invalid duration: {s}"))
+}
+
+fn parse_config(buf: &str) -> Result<RetryConfig> {
+    let c: PullConfigFile = crate::hostconfig::parse_section(buf, CONFIG_NAME)?;
+    let mut r = RetryConfig::default();
+    if let Some(v) = c.retries {
+        r.retries = v;
+    }
+    if let Some(v) = c.initial_delay.as_deref() {
+        r.initial_delay = parse_duration(v)?;
+    }
+    if let Some(v) = c.max_delay.as_deref() {
+        r.max_delay = parse_duration(v)?;
+    }
+    Ok(r)
+}
+
+/// Load the retry configuration from the target root.
+#[context("Loading pull configuration")]
+pub(crate) fn load_config(root: &Dir) -> Result<RetryConfig> {
+    crate::hostconfig::load(root, CONFIG_NAME, parse_config)
+}
+
+/// Whether an I/O error indicates a (likely) transient network failure.
+fn is_transient_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+/// Whether an error from pulling an image is likely to be transient, and hence
+/// worth retrying. Authentication, TLS and clock problems are never retried.
+pub(crate) fn is_transient(e: &anyhow::Error) -> bool {
+    if RegistryErrorKind::classify(e).is_some()
+        || e.chain()
+            .any(|e| e.downcast_ref::<crate::clock::ClockError>().is_some())
+    {
+        return false;
+    }
+    if e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(is_transient_io)
+    {
+        return true;
+    }
+    let msg = format!("{e:#}").to_lowercase();
+    TRANSIENT_ERRORS.iter().any(|s| msg.contains(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        assert_eq!(parse_config("")?, RetryConfig::default());
+        let c = parse_config(indoc::indoc! { r#"
+            [pull]
+            retries = 5
+            initial-delay = "1s"
+            max-delay = "10s"
+        "# })?;
+        assert_eq!(
+            c,
+            RetryConfig {
+                retries: 5,
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(10),
+            }
+        );
+        assert!(parse_config("[pull]\nretries = -1").is_err());
+        assert!(parse_config("[pull]\nfoo = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_delay_for() {
+        let c = RetryConfig {
+            retries: 10,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+        };
+        let delays = (2..=7)
+            .map(|i| c.delay_for(i).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(c.delay_for(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let e = anyhow::anyhow!("reading blob sha256:abcd: read tcp: connection reset by peer")
+            .context("Pulling");
+        assert!(is_transient(&e));
+        let e = anyhow::anyhow!("received unexpected HTTP status: 503 Service Unavailable");
+        assert!(is_transient(&e));
+        let e = anyhow::anyhow!("reading manifest: unauthorized: access to the requested resource is not authorized; timeout");
+        assert!(!is_transient(&e));
+        let e = anyhow::anyhow!("No space left on device");
+        assert!(!is_transient(&e));
+        let e = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+            .context("Fetching layer");
+        assert!(is_transient(&e));
+        let e = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            .context("Fetching layer");
+        assert!(!is_transient(&e));
+    }
+}
@@ -0,0 +1,471 @@
+//! # Update rollout policy
+//!
+//! Hosts may be configured with a rollout policy in `/etc/bootc/rollout.toml`
+//! (or `/usr/lib/bootc/rollout.toml`) which controls when an update published
+//! to the registry should be applied:
+//!
+//! ```toml
+//! [rollout]
+//! channel = "stable"
+//!
+//! [rollout.channels.stable]
+//! delay = "2d"
+//!
+//! [rollout.waves]
+//! count = 4
+//! interval = "1d"
+//!
+//! [[rollout.windows]]
+//! days = ["mon", "tue", "wed", "thu"]
+//! start = "02:00"
+//! end = "05:00"
+//!
+//! [rollout.health]
+//! units = ["my-app.service"]
+//! ```
+//!
+//! Hosts are assigned to a wave based on a hash of the machine ID. All times are UTC.
+//!
+//! With `--respect-rollout`, `bootc upgrade` downloads updates as usual, but
+//! does not stage one which the policy holds; the image creation timestamp is
+//! used as its publication time.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::parse_duration;
+use cap_std_ext::cap_std::fs::Dir;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use fn_error_context::context;
+use serde::Deserialize;
+
+/// The name of the configuration file and section; see [`crate::hostconfig`]
+const CONFIG_NAME: &str = "rollout";
+/// We only look this far ahead for an update window
+const MAX_WINDOW_SEARCH_DAYS: i64 = 8;
+
+/// The `[rollout]` section.
+#[derive(Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct RolloutConfig {
+    /// The channel this host follows
+    pub(crate) channel: Option<String>,
+    /// Per-channel configuration
+    #[serde(default)]
+    pub(crate) channels: BTreeMap<String, Channel>,
+    /// Staged rollout across the fleet
+    pub(crate) waves: Option<Waves>,
+    /// Times at which updates may be applied; if empty, at any time.
+    #[serde(default)]
+    pub(crate) windows: Vec<Window>,
+    /// Conditions on this host which must hold for an update to proceed
+    pub(crate) health: Option<Health>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Channel {
+    /// Minimum age of an update before it is taken, e.g. `2d`.
+    pub(crate) delay: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Waves {
+    /// Number of waves the fleet is split into
+    pub(crate) count: u32,
+    /// Delay between the start of each wave, e.g. `1d`.
+    pub(crate) interval: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Window {
+    /// Days of the week (e.g. `mon`); if unset, every day.
+    pub(crate) days: Option<Vec<String>>,
+    /// Start time, `HH:MM`
+    pub(crate) start: String,
+    /// End time, `HH:MM`; may be earlier than the start to span midnight.
+    pub(crate) end: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Health {
+    /// systemd units which must be active
+    #[serde(default)]
+    pub(crate) units: Vec<String>,
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(s, "%H:%M").with_context(|| format!("Parsing time: {s}"))
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday> {
+    s.parse::<Weekday>()
+        .map_err(|_| anyhow::anyhow!("Invalid day of week: {s}"))
+}
+
+/// Load the rollout configuration from the target root, if any.
+#[context("Loading rollout configuration")]
+pub(crate) fn load_config(root: &Dir) -> Result<Option<RolloutConfig>> {
+    crate::hostconfig::load(root, CONFIG_NAME, parse_config)
+}
+
+fn parse_config(buf: &str) -> Result<Option<RolloutConfig>> {
+    crate::hostconfig::parse_section(buf, CONFIG_NAME)
+}
+
+/// Assign a host to a wave based on its machine ID.
+pub(crate) fn wave_for_machine_id(machine_id: &str, count: u32) -> u32 {
+    if count == 0 {
+        return 0;
+    }
+    let digest = openssl::sha::sha256(machine_id.trim().as_bytes());
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&digest[0..8]);
+    (u64::from_be_bytes(v) % u64::from(count)) as u32
+}
+
+/// The result of simulating a rollout.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Simulation {
+    pub(crate) channel: Option<String>,
+    pub(crate) wave: Option<(u32, u32)>,
+    /// When the update is first eligible, considering channel delay and wave
+    pub(crate) eligible: DateTime<Utc>,
+    /// When the update would be applied, considering update windows
+    pub(crate) scheduled: Option<DateTime<Utc>>,
+}
+
+/// Find the earliest time at or after `from` within one of the windows.
+fn next_window(windows: &[Window], from: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    if windows.is_empty() {
+        return Ok(Some(from));
+    }
+    let mut best: Option<DateTime<Utc>> = None;
+    for w in windows {
+        let days = w
+            .days
+            .iter()
+            .flatten()
+            .map(|d| parse_weekday(d))
+            .collect::<Result<Vec<_>>>()?;
+        let start = parse_time(&w.start)?;
+        let end = parse_time(&w.end)?;
+        // Start a day early to handle windows spanning midnight
+        for offset in -1..MAX_WINDOW_SEARCH_DAYS {
+            let date = from.date_naive() + Duration::days(offset);
+            if !days.is_empty() && !days.contains(&date.weekday()) {
+                continue;
+            }
+            let wstart = date.and_time(start).and_utc();
+            let mut wend = date.and_time(end).and_utc();
+            if end <= start {
+                wend += Duration::days(1);
+            }
+            if from >= wend {
+                continue;
+            }
+            let candidate = wstart.max(from);
+            if best.is_none_or(|b| candidate < b) {
+                best = Some(candidate);
+            }
+            break;
+        }
+    }
+    Ok(best)
+}
+
+/// Compute when a host with the provided configuration would take an update
+/// published at `published`.
+pub(crate) fn simulate(
+    config: &RolloutConfig,
+    machine_id: &str,
+    wave_override: Option<u32>,
+    published: DateTime<Utc>,
+) -> Result<Simulation> {
+    let mut eligible = published;
+    let channel = config.channel.clone();
+    if let Some(channel) = channel.as_deref() {
+        let Some(c) = config.channels.get(channel) else {
+            anyhow::bail!("Unknown channel: {channel}");
+        };
+        if let Some(delay) = c.delay.as_deref() {
+            eligible += parse_duration(delay)?;
+        }
+    }
+    let wave = if let Some(waves) = config.waves.as_ref() {
+        anyhow::ensure!(waves.count > 0, "Wave count must be nonzero");
+        let wave = wave_override.unwrap_or_else(|| wave_for_machine_id(machine_id, waves.count));
+        anyhow::ensure!(wave < waves.count, "Invalid wave {wave}");
+        eligible += parse_duration(&waves.interval)? * (wave as i32);
+        Some((wave, waves.count))
+    } else {
+        None
+    };
+    let scheduled = next_window(&config.windows, eligible)?;
+    Ok(Simulation {
+        channel,
+        wave,
+        eligible,
+        scheduled,
+    })
+}
+
+/// Determine whether an update published at `published` should be held at `now`,
+/// returning the reason if so. Health gates are not considered here.
+fn held_reason(
+    config: &RolloutConfig,
+    machine_id: &str,
+    published: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Result<Option<String>> {
+    if let Some(published) = published {
+        let sim = simulate(config, machine_id, None, published)?;
+        if sim.eligible > now {
+            return Ok(Some(format!("not eligible until {}", sim.eligible)));
+        }
+    } else {
+        tracing::warn!("Update has no creation timestamp; ignoring rollout delays");
+    }
+    match next_window(&config.windows, now)? {
+        Some(t) if t == now => Ok(None),
+        Some(t) => Ok(Some(format!("outside of update windows until {t}"))),
+        None => Ok(Some(format!(
+            "no update window within {MAX_WINDOW_SEARCH_DAYS} days"
+        ))),
+    }
+}
+
+fn unit_active(unit: &str) -> Result<bool> {
+    let r = Command::new("systemctl")
+        .args(["is-active", "--quiet", unit])
+        .status()?
+        .success();
+    Ok(r)
+}
+
+/// Check the rollout configuration of the host for an update published at `published`,
+/// returning the reason it should be held, if any.
+#[context("Checking rollout policy")]
+pub(crate) fn check_update(root: &Dir, published: Option<DateTime<Utc>>) -> Result<Option<String>> {
+    let Some(config) = load_config(root)? else {
+        return Ok(None);
+    };
+    let machine_id = root
+        .read_to_string("etc/machine-id")
+        .context("Reading /etc/machine-id")?;
+    let now = std::time::SystemTime::now().into();
+    if let Some(reason) = held_reason(&config, &machine_id, published, now)? {
+        return Ok(Some(reason));
+    }
+    for unit in config.health.iter().flat_map(|h| h.units.iter()) {
+        if !unit_active(unit)? {
+            return Ok(Some(format!("health gate {unit} is not active")));
+        }
+    }
+    Ok(None)
+}
+
+/// Print the result of a simulation.
+fn print_simulation(mut out: impl Write, published: DateTime<Utc>, sim: &Simulation) -> Result<()> {
+    writeln!(out, "Published: {published}")?;
+    writeln!(
+        out,
+        "Channel: {}",
+        sim.channel.as_deref().unwrap_or("<none>")
+    )?;
+    if let Some((wave, count)) = sim.wave {
+        writeln!(out, "Wave: {} of {count}", wave + 1)?;
+    }
+    writeln!(out, "Eligible: {}", sim.eligible)?;
+    match sim.scheduled {
+        Some(t) => writeln!(out, "Scheduled: {t}")?,
+        None => writeln!(
+            out,
+            "Scheduled: no update window within {MAX_WINDOW_SEARCH_DAYS} days"
+        )?,
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc internals simulate-rollout`
+pub(crate) fn simulate_rollout(
+    root: &Dir,
+    published: Option<&str>,
+    wave: Option<u32>,
+) -> Result<()> {
+    let config =
+        load_config(root)?.ok_or_else(|| anyhow::anyhow!("No rollout configuration found"))?;
+    let published = published
+        .map(|p| {
+            DateTime::parse_from_rfc3339(p)
+                .map(|t| t.with_timezone(&Utc))
+                .with_context(|| format!("Parsing {p}"))
+        })
+        .transpose()?
+        .unwrap_or_else(|| std::time::SystemTime::now().into());
+    let machine_id = root
+        .read_to_string("etc/machine-id")
+        .context("Reading /etc/machine-id")?;
+    let sim = simulate(&config, &machine_id, wave, published)?;
+    let mut stdout = std::io::stdout().lock();
+    print_simulation(&mut stdout, published, &sim)?;
+    for unit in config.health.iter().flat_map(|h| h.units.iter()) {
+        let state = if unit_active(unit)? {
+            "active"
+        } else {
+            "inactive (update would be held)"
+        };
+        writeln!(stdout, "Health gate {unit}: {state}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = indoc::indoc! { r#"
+        [rollout]
+        channel = "stable"
+
+        [rollout.channels.stable]
+        delay = "2d"
+
+        [rollout.channels.canary]
+
+        [rollout.waves]
+        count = 4
+        interval = "1d"
+
+        [[rollout.windows]]
+        days = ["mon", "tue", "wed", "thu"]
+        start = "02:00"
+        end = "05:00"
+
+        [rollout.health]
+        units = ["my-app.service"]
+    "#};
+
+    fn t(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let c = parse_config(CONFIG)?.unwrap();
+        assert_eq!(c.channel.as_deref(), Some("stable"));
+        assert_eq!(c.waves.as_ref().unwrap().count, 4);
+        assert_eq!(c.health.as_ref().unwrap().units, ["my-app.service"]);
+        assert!(parse_config("")?.is_none());
+        assert!(parse_config("[rollout]\nfoo = 1\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate() -> Result<()> {
+        let c = parse_config(CONFIG)?.unwrap();
+        // Published on a Friday at noon; 2 day delay puts us at Sunday noon.
+        let published = t("2024-03-01T12:00:00Z");
+        let sim = simulate(&c, "", Some(0), published)?;
+        assert_eq!(sim.wave, Some((0, 4)));
+        assert_eq!(sim.eligible, t("2024-03-03T12:00:00Z"));
+        // The next window is Monday morning
+        assert_eq!(sim.scheduled, Some(t("2024-03-04T02:00:00Z")));
+
+        // Wave 2 is eligible Tuesday noon, after the window; so Wednesday
+        let sim = simulate(&c, "", Some(2), published)?;
+        assert_eq!(sim.eligible, t("2024-03-05T12:00:00Z"));
+        assert_eq!(sim.scheduled, Some(t("2024-03-06T02:00:00Z")));
+
+        // Wave 3 is eligible Wednesday noon; the next window is Thursday
+        let sim = simulate(&c, "", Some(3), published)?;
+        assert_eq!(sim.scheduled, Some(t("2024-03-07T02:00:00Z")));
+
+        // Eligible during a window
+        let sim = simulate(&c, "", Some(0), t("2024-03-02T03:00:00Z"))?;
+        assert_eq!(sim.scheduled, Some(t("2024-03-04T03:00:00Z")));
+
+        assert!(simulate(&c, "", Some(4), published).is_err());
+
+        // No delay, waves or windows
+        let c = RolloutConfig {
+            channel: Some("canary".into()),
+            channels: BTreeMap::from([("canary".into(), Channel { delay: None })]),
+            ..Default::default()
+        };
+        let sim = simulate(&c, "", None, published)?;
+        assert_eq!(sim.wave, None);
+        assert_eq!(sim.scheduled, Some(published));
+        Ok(())
+    }
+
+    #[test]
+    fn test_held_reason() -> Result<()> {
+        let c = parse_config(CONFIG)?.unwrap();
+        // Wave assignment uses the machine ID; find one in the first wave.
+        let machine_id = (0..)
+            .map(|i| format!("{i:032x}"))
+            .find(|m| wave_for_machine_id(m, 4) == 0)
+            .unwrap();
+        let published = Some(t("2024-03-01T12:00:00Z"));
+        // Within the channel delay
+        let r = held_reason(&c, &machine_id, published, t("2024-03-02T03:00:00Z"))?;
+        assert_eq!(
+            r.as_deref(),
+            Some("not eligible until 2024-03-03 12:00:00 UTC")
+        );
+        // Eligible, but outside of a window
+        let r = held_reason(&c, &machine_id, published, t("2024-03-04T06:00:00Z"))?;
+        assert_eq!(
+            r.as_deref(),
+            Some("outside of update windows until 2024-03-05 02:00:00 UTC")
+        );
+        // Eligible and within a window
+        assert_eq!(
+            held_reason(&c, &machine_id, published, t("2024-03-04T03:00:00Z"))?,
+            None
+        );
+        // Without a timestamp, only the windows apply
+        assert_eq!(
+            held_reason(&c, &machine_id, None, t("2024-03-01T03:00:00Z"))?,
+            Some("outside of update windows until 2024-03-04 02:00:00 UTC".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_windows_midnight() -> Result<()> {
+        let windows = [Window {
+            days: None,
+            start: "22:00".into(),
+            end: "02:00".into(),
+        }];
+        assert_eq!(
+            next_window(&windows, t("2024-03-01T12:00:00Z"))?,
+            Some(t("2024-03-01T22:00:00Z"))
+        );
+        // Inside the window which started the previous day
+        assert_eq!(
+            next_window(&windows, t("2024-03-02T01:00:00Z"))?,
+            Some(t("2024-03-02T01:00:00Z"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wave_for_machine_id() {
+        let a = wave_for_machine_id("0123456789abcdef0123456789abcdef\n", 4);
+        assert!(a < 4);
+        assert_eq!(
+            a,
+            wave_for_machine_id("0123456789abcdef0123456789abcdef", 4)
+        );
+        assert_eq!(wave_for_machine_id("foo", 1), 0);
+        assert_eq!(wave_for_machine_id("foo", 0), 0);
+    }
+}
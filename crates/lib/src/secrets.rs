@@ -0,0 +1,298 @@
+//! # Host secrets
+//!
+//! Implementation of `bootc secrets`. Secrets are stored in the physical root
+//! (`/sysroot/ostree/bootc/secrets`) and are only accessible to root; they are
+//! not part of any image or deployment, so they are preserved across updates
+//! and rollbacks. A secret can additionally be exposed to containers (such as
+//! logically bound images) via `podman secret`.
+//!
+//! The secret named [`REGISTRY_AUTH`] is special: if present, it is used as
+//! the registry authentication file when fetching images.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::{Dir, Permissions, PermissionsExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use comfy_table::{presets::NOTHING, Table};
+use fn_error_context::context;
+use ostree_ext::ostree;
+use serde::Serialize;
+
+use crate::cli::ImageListFormat;
+use crate::task::Task;
+
+/// The secrets directory, relative to the physical root.
+pub(crate) const SECRETS: &str = "ostree/bootc/secrets";
+/// Holds an empty file for each secret which is also exported to podman.
+const PODMAN_EXPORTED: &str = ".podman";
+/// The secret used as the registry authentication file.
+pub(crate) const REGISTRY_AUTH: &str = "registry-auth";
+/// Matches podman's limit on secret names.
+const MAX_NAME_LEN: usize = 253;
+
+/// Information about a secret; the value is intentionally not included.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SecretInfo {
+    /// The name of the secret
+    pub(crate) name: String,
+    /// Size of the value in bytes
+    pub(crate) size: u64,
+    /// Whether the secret is also available as a podman secret
+    pub(crate) podman: bool,
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        anyhow::bail!("Invalid secret name length: {}", name.len());
+    }
+    if name.starts_with('.') {
+        anyhow::bail!("Invalid secret name {name}: must not start with `.`");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        anyhow::bail!("Invalid character {c:?} in secret name {name}");
+    }
+    Ok(())
+}
+
+/// Open (creating if necessary) the secrets directory in the physical root.
+fn open_secrets(root: &Dir) -> Result<Dir> {
+    root.create_dir_all(format!("{SECRETS}/{PODMAN_EXPORTED}"))?;
+    // Always reset the mode, in case something else created the directory
+    root.set_permissions(SECRETS, Permissions::from_mode(0o700))?;
+    root.open_dir(SECRETS).context("Opening secrets")
+}
+
+/// Store a secret, replacing any existing value. The caller is responsible for
+/// exporting it to podman if `podman` is set. Returns whether the previous value
+/// was exported to podman.
+#[context("Setting secret {name}")]
+pub(crate) fn set_in(root: &Dir, name: &str, value: &[u8], podman: bool) -> Result<bool> {
+    validate_name(name)?;
+    let d = open_secrets(root)?;
+    d.atomic_write_with_perms(name, value, Permissions::from_mode(0o600))?;
+    let marker = format!("{PODMAN_EXPORTED}/{name}");
+    let was_exported = d.try_exists(&marker)?;
+    if podman {
+        d.atomic_write(&marker, "")?;
+    } else {
+        d.remove_file_optional(&marker)?;
+    }
+    Ok(was_exported)
+}
+
+/// List the stored secrets, sorted by name.
+pub(crate) fn list_in(root: &Dir) -> Result<Vec<SecretInfo>> {
+    let Some(d) = root.open_dir_optional(SECRETS)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        if !ent.file_type()?.is_file() {
+            continue;
+        }
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let podman = d.try_exists(format!("{PODMAN_EXPORTED}/{name}"))?;
+        r.push(SecretInfo {
+            name: name.to_owned(),
+            size: ent.metadata()?.len(),
+            podman,
+        });
+    }
+    r.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(r)
+}
+
+/// Remove a secret. Returns whether it was exported to podman, or `None` if
+/// there was no such secret.
+#[context("Removing secret {name}")]
+pub(crate) fn remove_in(root: &Dir, name: &str) -> Result<Option<bool>> {
+    validate_name(name)?;
+    let Some(d) = root.open_dir_optional(SECRETS)? else {
+        return Ok(None);
+    };
+    if !d.remove_file_optional(name)? {
+        return Ok(None);
+    }
+    Ok(Some(d.remove_file_optional(format!(
+        "{PODMAN_EXPORTED}/{name}"
+    ))?))
+}
+
+/// Open the registry authentication secret in the provided physical root, if set.
+///
+/// Secrets are only readable by root; for other users (e.g. `bootc upgrade --check`
+/// run unprivileged) this is treated as if no secret was set.
+pub(crate) fn open_registry_auth_in(root: &Dir) -> Result<Option<std::fs::File>> {
+    match root.open_optional(format!("{SECRETS}/{REGISTRY_AUTH}")) {
+        Ok(f) => Ok(f.map(|f| f.into_std())),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            tracing::debug!("Cannot read registry authentication secret: {e}");
+            Ok(None)
+        }
+        Err(e) => Err(e).context("Opening registry authentication secret"),
+    }
+}
+
+/// Open the registry authentication secret for the sysroot containing `repo`, if set.
+///
+/// As with [`open_registry_auth_in`], a secret we can't read is treated as unset.
+pub(crate) fn open_registry_auth(repo: &ostree::Repo) -> Result<Option<std::fs::File>> {
+    use rustix::fs::{Mode, OFlags};
+    // The repository is always `ostree/repo` in the physical root, a sibling
+    // of `ostree/bootc`.
+    let path = format!("../bootc/secrets/{REGISTRY_AUTH}");
+    match rustix::fs::openat(
+        repo.dfd_borrow(),
+        path.as_str(),
+        OFlags::RDONLY | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(fd) => Ok(Some(fd.into())),
+        Err(rustix::io::Errno::NOENT) => Ok(None),
+        Err(rustix::io::Errno::ACCESS) => {
+            tracing::debug!("Cannot read registry authentication secret: permission denied");
+            Ok(None)
+        }
+        Err(e) => Err(e).context("Opening registry authentication secret"),
+    }
+}
+
+fn podman_create(name: &str, value: &[u8]) -> Result<()> {
+    Task::new(format!("Creating podman secret {name}"), "podman")
+        .args(["secret", "create", "--replace", name, "-"])
+        .quiet()
+        .run_with_stdin_buf(Some(value))
+}
+
+fn podman_remove(name: &str) -> Result<()> {
+    Task::new(format!("Removing podman secret {name}"), "podman")
+        .args(["secret", "rm", "--ignore", name])
+        .quiet()
+        .run()
+}
+
+/// Implementation of `bootc secrets set`.
+pub(crate) async fn set(name: &str, from_file: Option<&Utf8Path>, podman: bool) -> Result<()> {
+    validate_name(name)?;
+    let value = if let Some(path) = from_file {
+        std::fs::read(path).with_context(|| format!("Reading {path}"))?
+    } else {
+        let mut buf = Vec::new();
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut buf)
+            .context("Reading stdin")?;
+        buf
+    };
+    let storage = crate::cli::get_storage().await?;
+    let was_exported = set_in(&storage.physical_root, name, &value, podman)?;
+    if podman {
+        podman_create(name, &value)?;
+    } else if was_exported {
+        podman_remove(name)?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc secrets list`.
+pub(crate) async fn list(format: ImageListFormat) -> Result<()> {
+    let storage = crate::cli::get_storage().await?;
+    let secrets = list_in(&storage.physical_root)?;
+    match format {
+        ImageListFormat::Table => {
+            let mut table = Table::new();
+            table
+                .load_preset(NOTHING)
+                .set_header(["NAME", "SIZE", "PODMAN"]);
+            for s in secrets {
+                table.add_row([s.name, s.size.to_string(), s.podman.to_string()]);
+            }
+            println!("{table}");
+        }
+        ImageListFormat::Json => {
+            let mut stdout = std::io::stdout();
+            serde_json::to_writer_pretty(&mut stdout, &secrets)?;
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc secrets remove`.
+pub(crate) async fn remove(name: &str) -> Result<()> {
+    let storage = crate::cli::get_storage().await?;
+    match remove_in(&storage.physical_root, name)? {
+        Some(true) => podman_remove(name),
+        Some(false) => Ok(()),
+        None => anyhow::bail!("No such secret: {name}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    #[test]
+    fn test_validate_name() {
+        for valid in ["registry-auth", "join_token", "a.b", "X1"] {
+            validate_name(valid).unwrap();
+        }
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        for invalid in ["", ".podman", "a/b", "..", "foo bar", long.as_str()] {
+            assert!(validate_name(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_secrets() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(list_in(&td)?.is_empty());
+        assert!(!set_in(&td, "token", b"secret", false)?);
+        assert!(!set_in(&td, REGISTRY_AUTH, b"{}", true)?);
+        let mode = td.metadata(SECRETS)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        let mode = td
+            .metadata(format!("{SECRETS}/token"))?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            list_in(&td)?,
+            [
+                SecretInfo {
+                    name: REGISTRY_AUTH.into(),
+                    size: 2,
+                    podman: true
+                },
+                SecretInfo {
+                    name: "token".into(),
+                    size: 6,
+                    podman: false
+                }
+            ]
+        );
+        // Replacing the value can also stop exporting it
+        assert!(set_in(&td, REGISTRY_AUTH, b"{ }", false)?);
+        assert!(!list_in(&td)?[0].podman);
+        assert_eq!(td.read(format!("{SECRETS}/{REGISTRY_AUTH}"))?, b"{ }");
+
+        assert!(open_registry_auth_in(&td)?.is_some());
+        assert_eq!(remove_in(&td, "token")?, Some(false));
+        assert_eq!(remove_in(&td, "token")?, None);
+        assert_eq!(list_in(&td)?.len(), 1);
+        Ok(())
+    }
+}
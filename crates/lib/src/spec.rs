@@ -12,7 +12,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{k8sapitypes, status::Slot};
 
-const API_VERSION: &str = "org.containers.bootc/v1";
+pub(crate) const API_VERSION: &str = "org.containers.bootc/v1";
 const KIND: &str = "BootcHost";
 /// The default object name we use; there's only one.
 pub(crate) const OBJECT_NAME: &str = "host";
@@ -65,6 +65,11 @@ pub struct HostSpec {
     /// If set, and there is a rollback deployment, it will be set for the next boot.
     #[serde(default)]
     pub boot_order: BootOrder,
+    /// The complete list of kernel arguments for the next boot; if unset, they are left
+    /// unchanged. When a new image is staged, the difference from the current kernel
+    /// arguments is applied on top of those computed for the new image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_arguments: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -150,6 +155,12 @@ pub struct ImageStatus {
     pub image_digest: String,
     /// The hardware architecture of this image
     pub architecture: String,
+    /// The platform (`OS/ARCH[/VARIANT]`) explicitly selected via `--platform`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// The platforms provided by the image, if it is a manifest list and `--platform` was used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub available_platforms: Vec<String>,
 }
 
 /// A bootable entry
@@ -164,6 +175,164 @@ pub struct BootEntryOstree {
     pub deploy_serial: u32,
 }
 
+/// Physical disk space used by a deployment
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentUsage {
+    /// Bytes used only by this deployment
+    pub exclusive: u64,
+    /// Bytes shared with other deployments
+    pub shared: u64,
+    /// Usage of each layer of the container image, in manifest order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<LayerUsage>,
+}
+
+/// Physical disk space used by a layer of a container image
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerUsage {
+    /// The digest of the layer
+    pub digest: String,
+    /// Bytes used only by this layer
+    pub exclusive: u64,
+    /// Bytes shared with other layers
+    pub shared: u64,
+}
+
+/// A state in the lifecycle of a deployment.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeploymentState {
+    /// Queued for the next boot
+    Staged,
+    /// A reboot into the deployment was initiated
+    Finalizing,
+    /// The system has booted into the deployment
+    Booted,
+    /// The system reached `boot-complete.target` in the deployment
+    Validated,
+    /// A rollback away from the deployment was performed, or it failed to boot
+    RolledBack,
+}
+
+impl Display for DeploymentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Staged => "staged",
+            Self::Finalizing => "finalizing",
+            Self::Booted => "booted",
+            Self::Validated => "validated",
+            Self::RolledBack => "rolled-back",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The kind of restart required to boot into the deployment queued for the next boot.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum RebootRequired {
+    /// No deployment is queued, or it is identical to the booted one
+    #[default]
+    No,
+    /// Only userspace changed; a soft reboot (`systemctl soft-reboot`) is sufficient
+    SoftReboot,
+    /// The kernel, initramfs or kernel arguments changed
+    Reboot,
+}
+
+/// A recorded change of a deployment's lifecycle state
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LifecycleTransition {
+    /// The new state
+    pub state: DeploymentState,
+    /// When the transition happened
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Set if the deployment was staged from an image older than the booted one;
+    /// describes the compared property, e.g. `version 42.1 -> 41.9`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downgrade: Option<String>,
+    /// Why the transition happened, e.g. the unit whose failure triggered an
+    /// automatic rollback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A systemd-sysext image managed by `bootc extension`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionStatus {
+    /// The name of the extension
+    pub name: String,
+    /// The version of the extension, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Whether the extension is merged into `/usr` at boot
+    pub enabled: bool,
+    /// Whether the extension supports the booted image
+    pub compatible: bool,
+    /// Whether the extension supports the staged image, if there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staged_compatible: Option<bool>,
+}
+
+/// A writable overlay on `/usr`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UsrOverlayStatus {
+    /// Whether the overlay is re-applied on boot
+    pub persistent: bool,
+    /// Whether any changes were made in the overlay
+    pub dirty: bool,
+    /// The physical size of the files written to the overlay, in bytes
+    pub bytes_written: u64,
+    /// The maximum size of the overlay in bytes, if limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_limit: Option<u64>,
+}
+
+/// The state of a logically bound image
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundImageStatus {
+    /// The image reference, as written in the bound image definition
+    pub image: String,
+    /// Whether the image is present in the bootc container storage
+    pub present: bool,
+    /// The manifest digest of the stored image, if present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Set if the image repository is pinned with `bootc image pin`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pinned: Option<BoundImagePin>,
+}
+
+impl BoundImageStatus {
+    /// Whether the image is pinned, and the reference or the stored image
+    /// differs from the pinned one.
+    pub fn pin_divergent(&self) -> bool {
+        self.pinned.as_ref().is_some_and(|pin| {
+            pin.image != self.image
+                || self
+                    .digest
+                    .as_deref()
+                    .is_some_and(|digest| digest != pin.digest)
+        })
+    }
+}
+
+/// A logically bound image held at a specific version
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundImagePin {
+    /// The image reference which was pinned
+    pub image: String,
+    /// The manifest digest the image is held at
+    pub digest: String,
+}
+
 /// A bootable entry
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
@@ -176,11 +345,29 @@ pub struct BootEntry {
     pub incompatible: bool,
     /// Whether this entry will be subject to garbage collection
     pub pinned: bool,
+    /// Whether this entry can be switched to from the booted one with a userspace
+    /// restart (`systemctl soft-reboot`), as the kernel, initramfs and kernel arguments
+    /// are unchanged
+    #[serde(default)]
+    pub soft_reboot_capable: bool,
+    /// Whether `/etc` and `/var` will be relabeled on the first boot of this entry,
+    /// as its SELinux policy differs from that of the deployment it was staged from
+    #[serde(default)]
+    pub relabel_pending: bool,
     /// The container storage backend
     #[serde(default)]
     pub store: Option<Store>,
     /// If this boot entry is ostree based, the corresponding state
     pub ostree: Option<BootEntryOstree>,
+    /// Disk usage of this entry; only computed on request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<DeploymentUsage>,
+    /// Logically bound images referenced by this entry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bound_images: Vec<BoundImageStatus>,
+    /// Recorded lifecycle transitions, oldest first; the last is the current state
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lifecycle: Vec<LifecycleTransition>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -210,6 +397,16 @@ pub struct HostStatus {
     /// Set to true if the rollback entry is queued for the next boot.
     #[serde(default)]
     pub rollback_queued: bool,
+    /// Whether a restart is required to boot into the staged deployment or queued rollback
+    #[serde(default)]
+    pub reboot_required: RebootRequired,
+    /// System extensions managed by `bootc extension`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub extensions: Vec<ExtensionStatus>,
+    /// Set if a writable overlay is mounted on `/usr` via `bootc usr-overlay`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usr_overlay: Option<UsrOverlayStatus>,
 
     /// The detected type of system
     #[serde(rename = "type")]
@@ -518,8 +715,13 @@ mod tests {
                 cached_update: None,
                 incompatible: false,
                 pinned: false,
+                soft_reboot_capable: false,
+                relabel_pending: false,
                 store: None,
                 ostree: None,
+                usage: None,
+                bound_images: Vec::new(),
+                lifecycle: Vec::new(),
             }
         }
 
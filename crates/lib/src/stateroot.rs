@@ -0,0 +1,211 @@
+//! # Stateroots
+//!
+//! Implementation of `bootc stateroot`. A stateroot (`/ostree/deploy/<name>`)
+//! holds a `/var` and the deployments which use it; multiple stateroots allow
+//! fully separate operating system installations sharing a root filesystem.
+
+use anyhow::Result;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use comfy_table::{presets::NOTHING, Table};
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::cli::ImageListFormat;
+use crate::spec::DeploymentUsage;
+use crate::store::accounting::{compute_stateroot_usage, dir_size};
+
+/// The stateroots directory, relative to the physical root.
+const DEPLOY: &str = "ostree/deploy";
+
+/// Information about a stateroot.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct StaterootInfo {
+    /// The name of the stateroot
+    pub(crate) name: String,
+    /// Number of deployments (including a staged one) using the stateroot
+    pub(crate) deployments: usize,
+    /// Whether the booted deployment uses this stateroot
+    pub(crate) booted: bool,
+    /// Physical size of the stateroot's `/var`
+    pub(crate) var_size: u64,
+    /// Space used in the ostree repository by the deployments
+    pub(crate) usage: DeploymentUsage,
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') {
+        anyhow::bail!("Invalid stateroot name: {name:?}");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        anyhow::bail!("Invalid character {c:?} in stateroot name {name}");
+    }
+    Ok(())
+}
+
+/// Return the names of the stateroots in the physical root, sorted.
+pub(crate) fn list_in(root: &Dir) -> Result<Vec<String>> {
+    let Some(d) = root.open_dir_optional(DEPLOY)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        if !ent.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(name) = ent.file_name().to_str() {
+            r.push(name.to_owned());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Verify that no deployment (given by its stateroot) uses `name`.
+fn require_unused<'a>(name: &str, osnames: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    let n = osnames.into_iter().filter(|v| *v == name).count();
+    if n > 0 {
+        anyhow::bail!("Stateroot {name} is in use by {n} deployment(s)");
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc stateroot list`.
+pub(crate) async fn list(format: ImageListFormat) -> Result<()> {
+    let storage = crate::cli::get_storage().await?;
+    let names = list_in(&storage.physical_root)?;
+    let usage = compute_stateroot_usage(&storage, &names)?;
+    let deployments = storage.deployments();
+    let booted = storage.booted_deployment();
+    let stateroots = names
+        .into_iter()
+        .zip(usage)
+        .map(|(name, usage)| {
+            let var_size = storage
+                .physical_root
+                .open_dir_optional(format!("{DEPLOY}/{name}/var"))?
+                .map(|d| dir_size(&d))
+                .transpose()?
+                .unwrap_or_default();
+            Ok(StaterootInfo {
+                deployments: deployments
+                    .iter()
+                    .filter(|d| d.osname() == name.as_str())
+                    .count(),
+                booted: booted.as_ref().is_some_and(|d| d.osname() == name.as_str()),
+                var_size,
+                usage,
+                name,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    match format {
+        ImageListFormat::Table => {
+            let mut table = Table::new();
+            table.load_preset(NOTHING).set_header([
+                "NAME",
+                "DEPLOYMENTS",
+                "BOOTED",
+                "VAR",
+                "EXCLUSIVE",
+                "SHARED",
+            ]);
+            for s in stateroots {
+                table.add_row([
+                    s.name,
+                    s.deployments.to_string(),
+                    s.booted.to_string(),
+                    indicatif::HumanBytes(s.var_size).to_string(),
+                    indicatif::HumanBytes(s.usage.exclusive).to_string(),
+                    indicatif::HumanBytes(s.usage.shared).to_string(),
+                ]);
+            }
+            println!("{table}");
+        }
+        ImageListFormat::Json => {
+            let mut stdout = std::io::stdout();
+            serde_json::to_writer_pretty(&mut stdout, &stateroots)?;
+        }
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc stateroot create`.
+#[context("Creating stateroot {name}")]
+pub(crate) async fn create(name: &str) -> Result<()> {
+    validate_name(name)?;
+    let storage = crate::cli::get_storage().await?;
+    if storage
+        .physical_root
+        .try_exists(format!("{DEPLOY}/{name}"))?
+    {
+        anyhow::bail!("Stateroot {name} already exists");
+    }
+    storage
+        .sysroot
+        .init_osname(name, ostree_ext::gio::Cancellable::NONE)?;
+    println!("Created stateroot {name}");
+    Ok(())
+}
+
+/// Implementation of `bootc stateroot delete`.
+#[context("Deleting stateroot {name}")]
+pub(crate) async fn delete(name: &str) -> Result<()> {
+    validate_name(name)?;
+    let storage = crate::cli::get_storage().await?;
+    let path = format!("{DEPLOY}/{name}");
+    if !storage.physical_root.try_exists(&path)? {
+        anyhow::bail!("No such stateroot: {name}");
+    }
+    let osnames = storage
+        .deployments()
+        .iter()
+        .map(|d| d.osname())
+        .collect::<Vec<_>>();
+    require_unused(name, osnames.iter().map(|v| v.as_str()))?;
+    storage.physical_root.remove_dir_all(&path)?;
+    println!("Deleted stateroot {name}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    #[test]
+    fn test_validate_name() {
+        for valid in ["default", "fedora-41", "os_2", "a.b"] {
+            validate_name(valid).unwrap();
+        }
+        for invalid in ["", ".", "..", "a/b", "foo bar"] {
+            assert!(validate_name(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_list_in() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(list_in(&td)?.is_empty());
+        td.create_dir_all("ostree/deploy/default/var")?;
+        td.create_dir_all("ostree/deploy/alt/deploy")?;
+        td.write("ostree/deploy/stray", "")?;
+        assert_eq!(list_in(&td)?, ["alt", "default"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_unused() {
+        require_unused("alt", ["default", "default"]).unwrap();
+        let e = require_unused("default", ["default", "alt", "default"]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Stateroot default is in use by 2 deployment(s)"
+        );
+    }
+}
@@ -16,8 +16,9 @@ use ostree_ext::oci_spec;
 use ostree_ext::ostree;
 
 use crate::cli::OutputFormat;
-use crate::spec::{BootEntry, BootOrder, Host, HostSpec, HostStatus, HostType};
-use crate::spec::{ImageReference, ImageSignature};
+use crate::spec::UsrOverlayStatus;
+use crate::spec::{BootEntry, BootOrder, Host, HostSpec, HostStatus, HostType, RebootRequired};
+use crate::spec::{BoundImageStatus, ExtensionStatus, ImageReference, ImageSignature};
 use crate::store::{CachedImageStatus, ContainerImageStore, Storage};
 
 impl From<ostree_container::SignatureSource> for ImageSignature {
@@ -150,12 +151,17 @@ fn boot_entry_from_deployment(
         incompatible,
         store,
         pinned: deployment.is_pinned(),
+        soft_reboot_capable: false,
+        relabel_pending: false,
         ostree: Some(crate::spec::BootEntryOstree {
             checksum: deployment.csum().into(),
             // SAFETY: The deployserial is really unsigned
             deploy_serial: deployment.deployserial().try_into().unwrap(),
             stateroot: deployment.stateroot().into(),
         }),
+        usage: None,
+        bound_images: Vec::new(),
+        lifecycle: Vec::new(),
     };
     Ok(r)
 }
@@ -177,6 +183,52 @@ impl BootEntry {
     }
 }
 
+/// Compare kernel arguments, ignoring the `ostree=` argument which always
+/// differs between deployments.
+fn same_kargs(a: &str, b: &str) -> bool {
+    let filtered = |s: &'_ str| {
+        s.split_ascii_whitespace()
+            .filter(|k| !k.starts_with("ostree="))
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>()
+    };
+    filtered(a) == filtered(b)
+}
+
+/// Whether `target` can be switched to from `booted` with a userspace restart
+/// (`systemctl soft-reboot`): the `bootcsum` (the digest of the kernel and initramfs)
+/// and the kernel arguments must be unchanged.
+pub(crate) fn soft_reboot_capable(
+    booted: &ostree::Deployment,
+    target: &ostree::Deployment,
+) -> bool {
+    let kargs = |d: &ostree::Deployment| {
+        d.bootconfig()
+            .and_then(|b| b.get("options"))
+            .map(|s| s.to_string())
+            .unwrap_or_default()
+    };
+    booted.bootcsum() == target.bootcsum() && same_kargs(&kargs(booted), &kargs(target))
+}
+
+/// The kind of restart required to boot from `booted` into `next`, the
+/// deployment queued for the next boot (if any).
+fn reboot_required(booted: Option<&BootEntry>, next: Option<&BootEntry>) -> RebootRequired {
+    let (Some(booted), Some(next)) = (booted, next) else {
+        return RebootRequired::No;
+    };
+    if !next.soft_reboot_capable {
+        return RebootRequired::Reboot;
+    }
+    let digest = |e: &BootEntry| e.image.as_ref().map(|i| i.image_digest.clone());
+    let checksum = |e: &BootEntry| e.ostree.as_ref().map(|o| o.checksum.clone());
+    if digest(booted) == digest(next) && checksum(booted) == checksum(next) {
+        RebootRequired::No
+    } else {
+        RebootRequired::SoftReboot
+    }
+}
+
 /// A variant of [`get_status`] that requires a booted deployment.
 pub(crate) fn get_status_require_booted(
     sysroot: &Storage,
@@ -228,7 +280,7 @@ pub(crate) fn get_status(
         other,
     };
 
-    let staged = deployments
+    let mut staged = deployments
         .staged
         .as_ref()
         .map(|d| boot_entry_from_deployment(sysroot, d))
@@ -239,12 +291,26 @@ pub(crate) fn get_status(
         .map(|d| boot_entry_from_deployment(sysroot, d))
         .transpose()
         .context("Booted deployment")?;
-    let rollback = deployments
+    let mut rollback = deployments
         .rollback
         .as_ref()
         .map(|d| boot_entry_from_deployment(sysroot, d))
         .transpose()
         .context("Rollback deployment")?;
+    if let Some(booted_deployment) = booted_deployment {
+        for (entry, deployment) in [
+            (staged.as_mut(), deployments.staged.as_ref()),
+            (rollback.as_mut(), deployments.rollback.as_ref()),
+        ] {
+            if let (Some(entry), Some(deployment)) = (entry, deployment) {
+                entry.soft_reboot_capable = soft_reboot_capable(booted_deployment, deployment);
+            }
+        }
+    }
+    let next = staged
+        .as_ref()
+        .or(rollback.as_ref().filter(|_| rollback_queued));
+    let reboot_required = reboot_required(booted.as_ref(), next);
     let other_deployments = deployments
         .other
         .iter()
@@ -258,6 +324,7 @@ pub(crate) fn get_status(
         .map(|img| HostSpec {
             image: Some(img.image.clone()),
             boot_order,
+            kernel_arguments: None,
         })
         .unwrap_or_default();
 
@@ -279,11 +346,88 @@ pub(crate) fn get_status(
         rollback,
         other_deployments,
         rollback_queued,
+        reboot_required,
         ty,
+        ..Default::default()
     };
     Ok((deployments, host))
 }
 
+/// Fill in the disk usage for each boot entry.
+fn apply_usage(host: &mut Host, usage: &crate::store::accounting::UsageReport) {
+    let status = &mut host.status;
+    let entries = [&mut status.staged, &mut status.booted, &mut status.rollback]
+        .into_iter()
+        .flat_map(|e| e.as_mut())
+        .chain(status.other_deployments.iter_mut());
+    for entry in entries {
+        let Some(ostree) = entry.ostree.as_ref() else {
+            continue;
+        };
+        let key = (ostree.checksum.clone(), ostree.deploy_serial);
+        entry.usage = usage.deployments.get(&key).cloned();
+    }
+}
+
+/// Look up `image` in the bootc container storage.
+fn bound_image_status(
+    image: &str,
+    stored: &[crate::podman::ImageListEntry],
+    pins: &crate::boundimage::BoundImagePins,
+) -> BoundImageStatus {
+    let found = crate::boundimage::find_stored(image, stored);
+    BoundImageStatus {
+        image: image.to_owned(),
+        present: found.is_some(),
+        digest: found.and_then(|e| e.digest.clone()),
+        pinned: crate::boundimage::pin_for(image, pins).cloned(),
+    }
+}
+
+/// Fill in the logically bound images of each boot entry, and whether they
+/// have been pulled into the bootc container storage. On error, `host` is unchanged.
+#[context("Querying bound images")]
+async fn apply_bound_images(sysroot: &Storage, host: &mut Host) -> Result<()> {
+    let mut bound = std::collections::HashMap::new();
+    for deployment in sysroot.deployments() {
+        let images = crate::boundimage::query_bound_images_for_deployment(sysroot, &deployment)?;
+        if images.is_empty() {
+            continue;
+        }
+        // SAFETY: The deployserial is really unsigned
+        let serial: u32 = deployment.deployserial().try_into().unwrap();
+        bound.insert((deployment.csum().to_string(), serial), images);
+    }
+    // Avoid invoking podman at all in the common case
+    if bound.is_empty() {
+        return Ok(());
+    }
+    let stored = match sysroot.get_imgstore_if_exists()? {
+        Some(imgstore) => imgstore.list_images().await?,
+        None => Vec::new(),
+    };
+    let pins = crate::boundimage::load_pins(&sysroot.physical_root)?;
+    let status = &mut host.status;
+    let entries = [&mut status.staged, &mut status.booted, &mut status.rollback]
+        .into_iter()
+        .flat_map(|e| e.as_mut())
+        .chain(status.other_deployments.iter_mut());
+    for entry in entries {
+        let Some(ostree) = entry.ostree.as_ref() else {
+            continue;
+        };
+        let key = (ostree.checksum.clone(), ostree.deploy_serial);
+        let Some(images) = bound.get(&key) else {
+            continue;
+        };
+        entry.bound_images = images
+            .iter()
+            .map(|i| bound_image_status(&i.image, &stored, &pins))
+            .collect();
+    }
+    Ok(())
+}
+
 /// Implementation of the `bootc status` CLI command.
 #[context("Status")]
 pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
@@ -292,12 +436,38 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
         0 | 1 => {}
         o => anyhow::bail!("Unsupported format version: {o}"),
     };
+    let mut composefs_usage = None;
+    let mut layers = Vec::new();
     let mut host = if !ostree_booted()? {
         Default::default()
     } else {
         let sysroot = super::cli::get_storage().await?;
         let booted_deployment = sysroot.booted_deployment();
-        let (_deployments, host) = get_status(&sysroot, booted_deployment.as_ref())?;
+        let (_deployments, mut host) = get_status(&sysroot, booted_deployment.as_ref())?;
+        // This queries podman; don't fail status entirely if that doesn't work.
+        if let Err(e) = apply_bound_images(&sysroot, &mut host).await {
+            eprintln!("warning: Failed to query bound images: {e:#}");
+        }
+        crate::lifecycle::apply_to_host(&sysroot, &mut host)?;
+        crate::relabel::apply_to_host(&sysroot, &mut host)?;
+        crate::extensions::apply_to_host(&sysroot.physical_root, &mut host)?;
+        crate::usroverlay::apply_to_host(&mut host)?;
+        if opts.show_usage {
+            let usage = crate::store::accounting::compute_usage(&sysroot)?;
+            apply_usage(&mut host, &usage);
+            composefs_usage = Some(usage.composefs);
+        }
+        if opts.layers {
+            let repo = &sysroot.repo();
+            for (slot, entry) in [
+                (Slot::Staged, host.status.staged.as_ref()),
+                (Slot::Booted, host.status.booted.as_ref()),
+            ] {
+                if let Some(img) = entry.map(|e| e.query_image(repo)).transpose()?.flatten() {
+                    layers.push((slot, layers_of_image(&img.manifest, &img.configuration)));
+                }
+            }
+        }
         host
     };
 
@@ -320,12 +490,25 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
         OutputFormat::Yaml
     };
     let format = opts.format.unwrap_or(legacy_opt);
+    if opts.layers && format != OutputFormat::HumanReadable {
+        anyhow::bail!("--layers is only supported for human readable output");
+    }
     match format {
         OutputFormat::Json => host
             .to_canon_json_writer(&mut out)
             .map_err(anyhow::Error::new),
         OutputFormat::Yaml => serde_yaml::to_writer(&mut out, &host).map_err(anyhow::Error::new),
-        OutputFormat::HumanReadable => human_readable_output(&mut out, &host, opts.verbose),
+        OutputFormat::HumanReadable => human_readable_output(&mut out, &host, opts.verbose)
+            .and_then(|()| {
+                if let Some(n) = composefs_usage.filter(|&n| n > 0) {
+                    writeln!(out, "\nComposefs objects: {}", indicatif::HumanBytes(n))?;
+                }
+                for (slot, layers) in layers.iter() {
+                    writeln!(out)?;
+                    render_layers(&mut out, slot, layers)?;
+                }
+                Ok(())
+            }),
     }
     .context("Writing to stdout")?;
 
@@ -359,6 +542,182 @@ fn write_row_name(mut out: impl Write, s: &str, prefix_len: usize) -> Result<()>
     Ok(())
 }
 
+/// A layer of an image, as presented by `bootc status --layers`.
+#[derive(Debug, PartialEq, Eq)]
+struct LayerInfo {
+    digest: String,
+    size: u64,
+    created_by: Option<String>,
+    components: Option<String>,
+}
+
+/// Gather the layers of an image, pairing them with their history entries.
+fn layers_of_image(
+    manifest: &oci_spec::image::ImageManifest,
+    config: &oci_spec::image::ImageConfiguration,
+) -> Vec<LayerInfo> {
+    // History entries for empty layers don't correspond to a layer in the manifest.
+    let mut history = config
+        .history()
+        .iter()
+        .filter(|h| !h.empty_layer().unwrap_or_default());
+    manifest
+        .layers()
+        .iter()
+        .map(|layer| {
+            let created_by = history.next().and_then(|h| h.created_by().clone());
+            let components = layer
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get(ostree_container::CONTENT_ANNOTATION))
+                .cloned();
+            LayerInfo {
+                digest: layer.digest().to_string(),
+                size: layer.size(),
+                created_by,
+                components,
+            }
+        })
+        .collect()
+}
+
+/// Render the layers of an image as a table.
+fn render_layers(mut out: impl Write, slot: &Slot, layers: &[LayerInfo]) -> Result<()> {
+    writeln!(out, "Layers ({slot}): {}", layers.len())?;
+    let mut table = comfy_table::Table::new();
+    table
+        .load_preset(comfy_table::presets::NOTHING)
+        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+        .set_header(["DIGEST", "SIZE", "CREATED BY", "COMPONENTS"]);
+    for layer in layers {
+        table.add_row([
+            layer.digest.clone(),
+            indicatif::HumanBytes(layer.size).to_string(),
+            layer.created_by.clone().unwrap_or_default(),
+            layer.components.clone().unwrap_or_default(),
+        ]);
+    }
+    writeln!(out, "{table}")?;
+    Ok(())
+}
+
+/// Render the disk usage of a deployment; if verbose, also the usage of each layer.
+fn render_usage(
+    mut out: impl Write,
+    usage: &crate::spec::DeploymentUsage,
+    prefix_len: usize,
+    verbose: bool,
+) -> Result<()> {
+    write_row_name(&mut out, "Disk usage", prefix_len)?;
+    writeln!(
+        out,
+        "{} exclusive, {} shared",
+        indicatif::HumanBytes(usage.exclusive),
+        indicatif::HumanBytes(usage.shared)
+    )?;
+    if verbose {
+        for layer in usage.layers.iter() {
+            write_row_name(&mut out, "Layer", prefix_len)?;
+            writeln!(
+                out,
+                "{} ({} exclusive, {} shared)",
+                layer.digest,
+                indicatif::HumanBytes(layer.exclusive),
+                indicatif::HumanBytes(layer.shared)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Render the logically bound images of a deployment; unless verbose,
+/// only the missing ones are listed individually.
+fn render_bound_images(
+    mut out: impl Write,
+    images: &[BoundImageStatus],
+    prefix_len: usize,
+    verbose: bool,
+) -> Result<()> {
+    if images.is_empty() {
+        return Ok(());
+    }
+    let missing = images.iter().filter(|i| !i.present).count();
+    write_row_name(&mut out, "Bound images", prefix_len)?;
+    if missing > 0 {
+        writeln!(out, "{} ({missing} missing)", images.len())?;
+    } else {
+        writeln!(out, "{}", images.len())?;
+    }
+    for image in images
+        .iter()
+        .filter(|i| verbose || !i.present || i.pinned.is_some())
+    {
+        // Align with the row values
+        write!(out, "{:width$}", "", width = prefix_len + 2)?;
+        match (image.present, image.digest.as_deref()) {
+            (false, _) => write!(out, "{} (missing)", image.image)?,
+            (true, Some(digest)) => write!(out, "{} ({digest})", image.image)?,
+            (true, None) => write!(out, "{}", image.image)?,
+        }
+        match image.pinned.as_ref() {
+            Some(pin) if image.pin_divergent() => {
+                writeln!(out, " [pinned: {}@{}, divergent]", pin.image, pin.digest)?
+            }
+            Some(_) => writeln!(out, " [pinned]")?,
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+/// Render the lifecycle state of a deployment; in verbose mode, all
+/// recorded transitions.
+fn render_lifecycle(
+    mut out: impl Write,
+    transitions: &[crate::spec::LifecycleTransition],
+    prefix_len: usize,
+    verbose: bool,
+) -> Result<()> {
+    let Some(current) = transitions.last() else {
+        return Ok(());
+    };
+    let fmt = |t: &crate::spec::LifecycleTransition| {
+        format!("{} ({})", t.state, t.timestamp.format("%Y-%m-%dT%H:%M:%SZ"))
+    };
+    write_row_name(&mut out, "State", prefix_len)?;
+    if verbose {
+        let all = transitions.iter().map(fmt).collect::<Vec<_>>();
+        writeln!(out, "{}", all.join(" -> "))?;
+    } else {
+        writeln!(out, "{}", fmt(current))?;
+    }
+    if let Some(downgrade) = transitions.iter().find_map(|t| t.downgrade.as_deref()) {
+        write_row_name(&mut out, "Downgrade", prefix_len)?;
+        writeln!(out, "{downgrade}")?;
+    }
+    if let Some(reason) = current.reason.as_deref() {
+        write_row_name(&mut out, "Reason", prefix_len)?;
+        writeln!(out, "{reason}")?;
+    }
+    Ok(())
+}
+
+/// Surface a deployment in a different stateroot than the booted one.
+fn render_foreign_stateroot(
+    mut out: impl Write,
+    entry: &crate::spec::BootEntry,
+    booted_stateroot: Option<&str>,
+    prefix_len: usize,
+) -> Result<()> {
+    if let (Some(ostree), Some(booted_stateroot)) = (&entry.ostree, booted_stateroot) {
+        if ostree.stateroot != booted_stateroot {
+            write_row_name(&mut out, "StateRoot", prefix_len)?;
+            writeln!(out, "{} (booted: {booted_stateroot})", ostree.stateroot)?;
+        }
+    }
+    Ok(())
+}
+
 /// Helper function to render verbose ostree information
 fn render_verbose_ostree_info(
     mut out: impl Write,
@@ -387,6 +746,7 @@ fn human_render_slot(
     slot: Option<Slot>,
     entry: &crate::spec::BootEntry,
     image: &crate::spec::ImageStatus,
+    booted_stateroot: Option<&str>,
     verbose: bool,
 ) -> Result<()> {
     let transport = &image.image.transport;
@@ -412,6 +772,16 @@ fn human_render_slot(
     let digest = &image.image_digest;
     writeln!(out, "{digest} ({arch})")?;
 
+    if let Some(platform) = image.platform.as_deref() {
+        write_row_name(&mut out, "Platform", prefix_len)?;
+        if image.available_platforms.is_empty() {
+            writeln!(out, "{platform}")?;
+        } else {
+            let available = image.available_platforms.join(", ");
+            writeln!(out, "{platform} (available: {available})")?;
+        }
+    }
+
     // Format the timestamp without nanoseconds since those are just irrelevant noise for human
     // consumption - that time scale should basically never matter for container builds.
     let timestamp = image
@@ -433,10 +803,7 @@ fn human_render_slot(
         writeln!(out, "{timestamp}")?;
     }
 
-    if entry.pinned {
-        write_row_name(&mut out, "Pinned", prefix_len)?;
-        writeln!(out, "yes")?;
-    }
+    render_entry_state(&mut out, entry, booted_stateroot, prefix_len, verbose)?;
 
     if verbose {
         // Show additional information in verbose mode similar to rpm-ostree
@@ -470,12 +837,51 @@ fn human_render_slot(
     Ok(())
 }
 
+/// Write the state of a boot entry common to image and ostree entries.
+fn render_entry_state(
+    mut out: impl Write,
+    entry: &crate::spec::BootEntry,
+    booted_stateroot: Option<&str>,
+    prefix_len: usize,
+    verbose: bool,
+) -> Result<()> {
+    if entry.pinned {
+        write_row_name(&mut out, "Pinned", prefix_len)?;
+        writeln!(out, "yes")?;
+    }
+
+    if entry.soft_reboot_capable {
+        write_row_name(&mut out, "Soft reboot", prefix_len)?;
+        writeln!(out, "capable")?;
+    }
+
+    if entry.relabel_pending {
+        write_row_name(&mut out, "SELinux relabel", prefix_len)?;
+        writeln!(out, "pending")?;
+    }
+
+    if let Some(usage) = entry.usage.as_ref() {
+        render_usage(&mut out, usage, prefix_len, verbose)?;
+    }
+
+    render_bound_images(&mut out, &entry.bound_images, prefix_len, verbose)?;
+    render_lifecycle(&mut out, &entry.lifecycle, prefix_len, verbose)?;
+
+    // Verbose mode shows the stateroot unconditionally below.
+    if !verbose {
+        render_foreign_stateroot(&mut out, entry, booted_stateroot, prefix_len)?;
+    }
+
+    Ok(())
+}
+
 /// Output a rendering of a non-container boot entry.
 fn human_render_slot_ostree(
     mut out: impl Write,
     slot: Option<Slot>,
     entry: &crate::spec::BootEntry,
     ostree_commit: &str,
+    booted_stateroot: Option<&str>,
     verbose: bool,
 ) -> Result<()> {
     // TODO consider rendering more ostree stuff here like rpm-ostree status does
@@ -490,10 +896,7 @@ fn human_render_slot_ostree(
     write_row_name(&mut out, "Commit", prefix_len)?;
     writeln!(out, "{ostree_commit}")?;
 
-    if entry.pinned {
-        write_row_name(&mut out, "Pinned", prefix_len)?;
-        writeln!(out, "yes")?;
-    }
+    render_entry_state(&mut out, entry, booted_stateroot, prefix_len, verbose)?;
 
     if verbose {
         // Show additional information in verbose mode similar to rpm-ostree
@@ -506,7 +909,57 @@ fn human_render_slot_ostree(
     Ok(())
 }
 
+/// Write the system extensions, noting those which do not support the booted or staged image.
+fn render_extensions(mut out: impl Write, extensions: &[ExtensionStatus]) -> Result<()> {
+    writeln!(out, "Extensions: {}", extensions.len())?;
+    for ext in extensions {
+        write!(out, "  {}", ext.name)?;
+        if let Some(version) = ext.version.as_deref() {
+            write!(out, " {version}")?;
+        }
+        let mut notes = vec![if ext.enabled { "enabled" } else { "disabled" }];
+        if !ext.compatible {
+            notes.push("incompatible with booted image");
+        }
+        if ext.staged_compatible == Some(false) {
+            notes.push("incompatible with staged image");
+        }
+        writeln!(out, " ({})", notes.join(", "))?;
+    }
+    Ok(())
+}
+
+/// Write the state of the writable overlay on /usr.
+fn render_usr_overlay(mut out: impl Write, overlay: &UsrOverlayStatus) -> Result<()> {
+    let kind = if overlay.persistent {
+        "persistent"
+    } else {
+        "transient"
+    };
+    let state = if overlay.dirty {
+        "modified"
+    } else {
+        "unmodified"
+    };
+    write!(
+        out,
+        "/usr overlay: {kind}, {state} ({} written",
+        indicatif::HumanBytes(overlay.bytes_written)
+    )?;
+    if let Some(limit) = overlay.size_limit {
+        write!(out, ", limit {}", indicatif::HumanBytes(limit))?;
+    }
+    writeln!(out, ")")?;
+    Ok(())
+}
+
 fn human_readable_output_booted(mut out: impl Write, host: &Host, verbose: bool) -> Result<()> {
+    let booted_stateroot = host
+        .status
+        .booted
+        .as_ref()
+        .and_then(|b| b.ostree.as_ref())
+        .map(|o| o.stateroot.as_str());
     let mut first = true;
     for (slot_name, status) in [
         (Slot::Staged, &host.status.staged),
@@ -520,13 +973,21 @@ fn human_readable_output_booted(mut out: impl Write, host: &Host, verbose: bool)
                 writeln!(out)?;
             }
             if let Some(image) = &host_status.image {
-                human_render_slot(&mut out, Some(slot_name), host_status, image, verbose)?;
+                human_render_slot(
+                    &mut out,
+                    Some(slot_name),
+                    host_status,
+                    image,
+                    booted_stateroot,
+                    verbose,
+                )?;
             } else if let Some(ostree) = host_status.ostree.as_ref() {
                 human_render_slot_ostree(
                     &mut out,
                     Some(slot_name),
                     host_status,
                     &ostree.checksum,
+                    booted_stateroot,
                     verbose,
                 )?;
             } else {
@@ -540,13 +1001,40 @@ fn human_readable_output_booted(mut out: impl Write, host: &Host, verbose: bool)
             writeln!(out)?;
 
             if let Some(image) = &entry.image {
-                human_render_slot(&mut out, None, entry, image, verbose)?;
+                human_render_slot(&mut out, None, entry, image, booted_stateroot, verbose)?;
             } else if let Some(ostree) = entry.ostree.as_ref() {
-                human_render_slot_ostree(&mut out, None, entry, &ostree.checksum, verbose)?;
+                human_render_slot_ostree(
+                    &mut out,
+                    None,
+                    entry,
+                    &ostree.checksum,
+                    booted_stateroot,
+                    verbose,
+                )?;
             }
         }
     }
 
+    let reboot = match host.status.reboot_required {
+        RebootRequired::No => None,
+        RebootRequired::SoftReboot => Some("soft reboot"),
+        RebootRequired::Reboot => Some("yes"),
+    };
+    if let Some(reboot) = reboot {
+        writeln!(out)?;
+        writeln!(out, "Reboot required: {reboot}")?;
+    }
+
+    if !host.status.extensions.is_empty() {
+        writeln!(out)?;
+        render_extensions(&mut out, &host.status.extensions)?;
+    }
+
+    if let Some(overlay) = host.status.usr_overlay.as_ref() {
+        writeln!(out)?;
+        render_usr_overlay(&mut out, overlay)?;
+    }
+
     Ok(())
 }
 
@@ -709,6 +1197,369 @@ mod tests {
         similar_asserts::assert_eq!(w, expected);
     }
 
+    #[test]
+    fn test_layers_of_image() {
+        let manifest: oci_spec::image::ImageManifest = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+                "size": 100,
+            },
+            "layers": [
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                    "size": 2048,
+                    "annotations": { "ostree.components": "kernel,systemd" },
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                    "size": 4096,
+                },
+            ],
+        }))
+        .unwrap();
+        let config: oci_spec::image::ImageConfiguration =
+            serde_json::from_value(serde_json::json!({
+                "architecture": "amd64",
+                "os": "linux",
+                "rootfs": { "type": "layers", "diff_ids": [] },
+                "history": [
+                    { "created_by": "ostree export" },
+                    { "created_by": "LABEL foo=bar", "empty_layer": true },
+                    { "created_by": "RUN dnf install -y vim" },
+                ],
+            }))
+            .unwrap();
+        let layers = layers_of_image(&manifest, &config);
+        assert_eq!(
+            layers,
+            [
+                LayerInfo {
+                    digest:
+                        "sha256:1111111111111111111111111111111111111111111111111111111111111111"
+                            .into(),
+                    size: 2048,
+                    created_by: Some("ostree export".into()),
+                    components: Some("kernel,systemd".into()),
+                },
+                LayerInfo {
+                    digest:
+                        "sha256:2222222222222222222222222222222222222222222222222222222222222222"
+                            .into(),
+                    size: 4096,
+                    created_by: Some("RUN dnf install -y vim".into()),
+                    components: None,
+                },
+            ]
+        );
+        let mut w = Vec::new();
+        render_layers(&mut w, &Slot::Booted, &layers).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.starts_with("Layers (booted): 2\n"));
+        assert!(w.contains("RUN dnf install -y vim"));
+    }
+
+    #[test]
+    fn test_bound_image_status() {
+        let stored = [crate::podman::ImageListEntry {
+            id: "abc".into(),
+            names: Some(vec!["quay.io/example/app:latest".into()]),
+            digest: Some("sha256:1111".into()),
+            digests: Some(vec!["sha256:1111".into(), "sha256:2222".into()]),
+            size: None,
+            created: None,
+        }];
+        let pins = Default::default();
+        let s = bound_image_status("quay.io/example/app", &stored, &pins);
+        assert!(s.present);
+        assert_eq!(s.digest.as_deref(), Some("sha256:1111"));
+        assert!(bound_image_status("quay.io/example/app:latest", &stored, &pins).present);
+        assert!(bound_image_status("quay.io/example/app@sha256:2222", &stored, &pins).present);
+        assert!(!bound_image_status("quay.io/example/app:v2", &stored, &pins).present);
+        assert!(!bound_image_status("quay.io/example/other@sha256:3333", &stored, &pins).present);
+        assert!(!bound_image_status("localhost:5000/app", &[], &pins).present);
+
+        let pins = crate::boundimage::BoundImagePins::from([(
+            "quay.io/example/app".to_owned(),
+            crate::spec::BoundImagePin {
+                image: "quay.io/example/app:latest".into(),
+                digest: "sha256:1111".into(),
+            },
+        )]);
+        let s = bound_image_status("quay.io/example/app:latest", &stored, &pins);
+        assert!(s.pinned.is_some() && !s.pin_divergent());
+        let s = bound_image_status("quay.io/example/app:v2", &stored, &pins);
+        assert!(s.pin_divergent());
+    }
+
+    #[test]
+    fn test_human_readable_bound_images() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.booted.as_mut().unwrap().bound_images = vec![
+            BoundImageStatus {
+                image: "quay.io/example/app:latest".into(),
+                present: true,
+                digest: Some("sha256:1111".into()),
+                pinned: None,
+            },
+            BoundImageStatus {
+                image: "quay.io/example/db:latest".into(),
+                present: false,
+                digest: None,
+                pinned: None,
+            },
+            BoundImageStatus {
+                image: "quay.io/example/web:v2".into(),
+                present: true,
+                digest: Some("sha256:3333".into()),
+                pinned: Some(crate::spec::BoundImagePin {
+                    image: "quay.io/example/web:v1".into(),
+                    digest: "sha256:3333".into(),
+                }),
+            },
+        ];
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("Bound images: 3 (1 missing)\n"));
+        assert!(w.contains("quay.io/example/db:latest (missing)\n"));
+        assert!(w.contains(
+            "quay.io/example/web:v2 (sha256:3333) [pinned: quay.io/example/web:v1@sha256:3333, divergent]\n"
+        ));
+        assert!(!w.contains("quay.io/example/app:latest"));
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, true).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("quay.io/example/app:latest (sha256:1111)\n"));
+    }
+
+    #[test]
+    fn test_human_readable_platform() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        let image = host.status.booted.as_mut().unwrap().image.as_mut().unwrap();
+        image.platform = Some("linux/arm64".into());
+        image.available_platforms = vec!["linux/amd64".into(), "linux/arm64".into()];
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("Platform: linux/arm64 (available: linux/amd64, linux/arm64)\n"));
+        // Not shown unless explicitly selected
+        assert_eq!(w.matches("Platform:").count(), 1);
+    }
+
+    #[test]
+    fn test_human_readable_lifecycle() {
+        use crate::spec::{DeploymentState, LifecycleTransition};
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.booted.as_mut().unwrap().lifecycle = vec![
+            LifecycleTransition {
+                state: DeploymentState::Staged,
+                timestamp: "2025-03-01T12:00:00Z".parse().unwrap(),
+                downgrade: Some("version 42.1 -> 41.9".into()),
+                reason: None,
+            },
+            LifecycleTransition {
+                state: DeploymentState::Booted,
+                timestamp: "2025-03-01T12:30:00Z".parse().unwrap(),
+                downgrade: None,
+                reason: None,
+            },
+        ];
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("State: booted (2025-03-01T12:30:00Z)\n"));
+        assert!(w.contains("Downgrade: version 42.1 -> 41.9\n"));
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, true).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(
+            w.contains("State: staged (2025-03-01T12:00:00Z) -> booted (2025-03-01T12:30:00Z)\n")
+        );
+        assert!(!w.contains("Reason:"));
+        host.status
+            .booted
+            .as_mut()
+            .unwrap()
+            .lifecycle
+            .push(LifecycleTransition {
+                state: DeploymentState::RolledBack,
+                timestamp: "2025-03-01T12:35:00Z".parse().unwrap(),
+                downgrade: None,
+                reason: Some("app.service failed (result: exit-code)".into()),
+            });
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("Reason: app.service failed (result: exit-code)\n"));
+    }
+
+    #[test]
+    fn test_human_readable_extensions() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.extensions = vec![
+            ExtensionStatus {
+                name: "debug-tools".into(),
+                version: Some("1.2".into()),
+                enabled: true,
+                compatible: true,
+                staged_compatible: Some(false),
+            },
+            ExtensionStatus {
+                name: "vendor".into(),
+                version: None,
+                enabled: false,
+                compatible: false,
+                staged_compatible: Some(true),
+            },
+        ];
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.ends_with(indoc::indoc! { "
+
+            Extensions: 2
+              debug-tools 1.2 (enabled, incompatible with staged image)
+              vendor (disabled, incompatible with booted image)
+        "}));
+    }
+
+    #[test]
+    fn test_human_readable_usr_overlay() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.usr_overlay = Some(UsrOverlayStatus {
+            persistent: false,
+            dirty: true,
+            bytes_written: 1024 * 1024,
+            size_limit: Some(512 * 1024 * 1024),
+        });
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.ends_with(indoc::indoc! { "
+
+            /usr overlay: transient, modified (1.00 MiB written, limit 512.00 MiB)
+        "}));
+    }
+
+    #[test]
+    fn test_human_readable_usage() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.booted.as_mut().unwrap().usage = Some(crate::spec::DeploymentUsage {
+            exclusive: 1024 * 1024,
+            shared: 2048,
+            layers: vec![crate::spec::LayerUsage {
+                digest: "sha256:abcd".into(),
+                exclusive: 1024,
+                shared: 0,
+            }],
+        });
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("Disk usage: 1.00 MiB exclusive, 2.00 KiB shared\n"));
+        assert!(!w.contains("Layer:"));
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, true).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("Layer: sha256:abcd (1.00 KiB exclusive, 0 B shared)\n"));
+    }
+
+    #[test]
+    fn test_same_kargs() {
+        assert!(same_kargs(
+            "root=UUID=abc rw ostree=/ostree/boot.1/default/aaa/0",
+            "root=UUID=abc rw  ostree=/ostree/boot.0/default/bbb/0"
+        ));
+        assert!(!same_kargs(
+            "root=UUID=abc rw ostree=/ostree/boot.1/default/aaa/0",
+            "root=UUID=abc rw nosmt ostree=/ostree/boot.0/default/bbb/0"
+        ));
+    }
+
+    #[test]
+    fn test_reboot_required() {
+        let host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        let booted = host.status.booted.as_ref().unwrap();
+        let mut staged = host.status.staged.clone().unwrap();
+        assert_eq!(reboot_required(Some(booted), None), RebootRequired::No);
+        assert_eq!(
+            reboot_required(Some(booted), Some(&staged)),
+            RebootRequired::Reboot
+        );
+        staged.soft_reboot_capable = true;
+        assert_eq!(
+            reboot_required(Some(booted), Some(&staged)),
+            RebootRequired::SoftReboot
+        );
+        // An identical deployment
+        let mut same = booted.clone();
+        same.soft_reboot_capable = true;
+        assert_eq!(
+            reboot_required(Some(booted), Some(&same)),
+            RebootRequired::No
+        );
+    }
+
+    #[test]
+    fn test_human_readable_reboot_required() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        let w = |host: &Host| {
+            let mut w = Vec::new();
+            human_readable_output(&mut w, host, false).unwrap();
+            String::from_utf8(w).unwrap()
+        };
+        assert!(!w(&host).contains("Reboot required"));
+        host.status.reboot_required = RebootRequired::SoftReboot;
+        assert!(w(&host).contains("\nReboot required: soft reboot\n"));
+        host.status.reboot_required = RebootRequired::Reboot;
+        assert!(w(&host).contains("\nReboot required: yes\n"));
+    }
+
+    #[test]
+    fn test_human_readable_soft_reboot() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        host.status.staged.as_mut().unwrap().soft_reboot_capable = true;
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        assert!(w.contains("Soft reboot: capable\n"));
+    }
+
+    #[test]
+    fn test_human_readable_staged_stateroot() {
+        let mut host: Host =
+            serde_yaml::from_str(include_str!("fixtures/spec-staged-booted.yaml")).unwrap();
+        let staged = host.status.staged.as_mut().unwrap();
+        staged.ostree.as_mut().unwrap().stateroot = "fedora".into();
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &host, false).unwrap();
+        let w = String::from_utf8(w).unwrap();
+        let expected = indoc::indoc! { r"
+            Staged image: quay.io/example/someimage:latest
+                  Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566 (arm64)
+                 Version: nightly (2023-10-14T19:22:15Z)
+               StateRoot: fedora (booted: default)
+
+          ● Booted image: quay.io/example/someimage:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34 (arm64)
+                 Version: nightly (2023-09-30T19:22:16Z)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
     #[test]
     fn test_human_readable_verbose_spec() {
         // Test verbose output includes additional fields
@@ -0,0 +1,268 @@
+//! # Disk usage accounting
+//!
+//! Attribute the physical space used by objects in the ostree repository
+//! to the deployments which reference them.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use anyhow::Result;
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::keyfileext::KeyFileExt;
+use ostree_ext::ostree;
+
+use super::{Storage, COMPOSEFS};
+use crate::spec::{DeploymentUsage, LayerUsage};
+
+/// The physical size of a file (i.e. allocated blocks, not the apparent size).
+pub(super) fn physical_size(meta: &cap_std_ext::cap_std::fs::Metadata) -> u64 {
+    meta.blocks() * 512
+}
+
+/// Given the set of objects referenced by each deployment, compute the exclusive
+/// and shared usage for each.
+fn attribute_usage<K: Eq + Hash>(
+    sets: &[HashSet<K>],
+    mut size_of: impl FnMut(&K) -> Result<u64>,
+) -> Result<Vec<DeploymentUsage>> {
+    let mut refcounts: HashMap<&K, u32> = HashMap::new();
+    for k in sets.iter().flatten() {
+        *refcounts.entry(k).or_default() += 1;
+    }
+    let mut sizes: HashMap<&K, u64> = HashMap::new();
+    for &k in refcounts.keys() {
+        sizes.insert(k, size_of(k)?);
+    }
+    let r = sets
+        .iter()
+        .map(|set| {
+            let mut usage = DeploymentUsage::default();
+            for k in set {
+                // SAFETY: We computed both for all keys above
+                let size = sizes[k];
+                if refcounts[k] > 1 {
+                    usage.shared += size;
+                } else {
+                    usage.exclusive += size;
+                }
+            }
+            usage
+        })
+        .collect();
+    Ok(r)
+}
+
+/// Compute the physical size of a loose object in the repository.
+fn object_size(objects: &Dir, obj: &ostree::ObjectName) -> Result<u64> {
+    let checksum = obj.checksum();
+    let ext = ostree::object_type_to_string(obj.object_type());
+    let path = format!("{}/{}.{ext}", &checksum[0..2], &checksum[2..]);
+    let size = objects
+        .symlink_metadata_optional(&path)?
+        .map(|m| physical_size(&m))
+        .unwrap_or_default();
+    Ok(size)
+}
+
+/// Compute the total physical size of all files in a directory.
+pub(crate) fn dir_size(d: &Dir) -> Result<u64> {
+    let mut r = 0;
+    for ent in d.entries()? {
+        let ent = ent?;
+        let meta = ent.metadata()?;
+        if meta.is_dir() {
+            r += dir_size(&ent.open_dir()?)?;
+        } else {
+            r += physical_size(&meta);
+        }
+    }
+    Ok(r)
+}
+
+/// Return the digests of the layers of the container image of a deployment, along
+/// with the ostree commit caching each layer.  Layers which are not stored
+/// separately (e.g. because they were squashed on import) are omitted.
+fn deployment_layers(
+    repo: &ostree::Repo,
+    deployment: &ostree::Deployment,
+) -> Result<Vec<(String, String)>> {
+    let is_container = deployment
+        .origin()
+        .map(|o| o.optional_string("origin", ostree_container::deploy::ORIGIN_CONTAINER))
+        .transpose()?
+        .flatten()
+        .is_some();
+    if !is_container {
+        return Ok(Vec::new());
+    }
+    let image = ostree_container::store::query_image_commit(repo, &deployment.csum())?;
+    let mut r = Vec::new();
+    for layer in image.manifest.layers() {
+        if let Some(commit) = ostree_container::store::query_layer_commit(repo, layer)? {
+            r.push((layer.digest().to_string(), commit));
+        }
+    }
+    Ok(r)
+}
+
+/// Attribute physical space to the layers of the container image of each
+/// deployment; objects referenced from more than one layer are shared.
+fn compute_layer_usage(
+    repo: &ostree::Repo,
+    objects: &Dir,
+    deployments: &[ostree::Deployment],
+) -> Result<Vec<Vec<LayerUsage>>> {
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let layers = deployments
+        .iter()
+        .map(|d| deployment_layers(repo, d))
+        .collect::<Result<Vec<_>>>()?;
+    // Layers are deduplicated across images by their commit
+    let mut commits = layers
+        .iter()
+        .flatten()
+        .map(|(_, commit)| commit.clone())
+        .collect::<Vec<_>>();
+    commits.sort_unstable();
+    commits.dedup();
+    let sets = commits
+        .iter()
+        .map(|c| Ok(repo.traverse_commit(c, 0, cancellable)?))
+        .collect::<Result<Vec<_>>>()?;
+    let usage = attribute_usage(&sets, |obj| object_size(objects, obj))?;
+    let usage = commits.into_iter().zip(usage).collect::<HashMap<_, _>>();
+    let r = layers
+        .into_iter()
+        .map(|layers| {
+            layers
+                .into_iter()
+                .map(|(digest, commit)| {
+                    // SAFETY: We computed the usage for all layer commits above
+                    let usage = &usage[&commit];
+                    LayerUsage {
+                        digest,
+                        exclusive: usage.exclusive,
+                        shared: usage.shared,
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    Ok(r)
+}
+
+/// Disk usage of the system storage.
+#[derive(Debug)]
+pub(crate) struct UsageReport {
+    /// Per deployment usage, indexed by the deployment checksum and serial
+    pub(crate) deployments: HashMap<(String, u32), DeploymentUsage>,
+    /// Physical space used by the composefs objects directory
+    pub(crate) composefs: u64,
+}
+
+/// Walk the ostree repository and composefs objects directory, attributing
+/// physical space to each deployment.
+#[context("Computing disk usage")]
+pub(crate) fn compute_usage(storage: &Storage) -> Result<UsageReport> {
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let repo = &storage.repo();
+    let deployments = storage.deployments();
+    let sets = deployments
+        .iter()
+        .map(|d| Ok(repo.traverse_commit(&d.csum(), 0, cancellable)?))
+        .collect::<Result<Vec<_>>>()?;
+    let objects = storage.physical_root.open_dir("ostree/repo/objects")?;
+    let usage = attribute_usage(&sets, |obj| object_size(&objects, obj))?;
+    let layers = compute_layer_usage(repo, &objects, &deployments)?;
+    let deployments = deployments
+        .iter()
+        .zip(usage)
+        .zip(layers)
+        .map(|((d, usage), layers)| {
+            // SAFETY: The deployserial is really unsigned
+            let serial = d.deployserial().try_into().unwrap();
+            (
+                (d.csum().to_string(), serial),
+                DeploymentUsage { layers, ..usage },
+            )
+        })
+        .collect();
+    let composefs = storage
+        .physical_root
+        .open_dir_optional(format!("{COMPOSEFS}/objects"))?
+        .map(|d| dir_size(&d))
+        .transpose()?
+        .unwrap_or_default();
+    Ok(UsageReport {
+        deployments,
+        composefs,
+    })
+}
+
+/// Attribute physical space in the ostree repository to the deployments of each
+/// of `stateroots`; objects referenced from more than one stateroot are shared.
+#[context("Computing stateroot disk usage")]
+pub(crate) fn compute_stateroot_usage(
+    storage: &Storage,
+    stateroots: &[String],
+) -> Result<Vec<DeploymentUsage>> {
+    let cancellable = ostree::gio::Cancellable::NONE;
+    let repo = &storage.repo();
+    let deployments = storage.deployments();
+    let sets = stateroots
+        .iter()
+        .map(|stateroot| {
+            let mut set = HashSet::new();
+            for d in deployments
+                .iter()
+                .filter(|d| d.osname() == stateroot.as_str())
+            {
+                set.extend(repo.traverse_commit(&d.csum(), 0, cancellable)?);
+            }
+            Ok(set)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let objects = storage.physical_root.open_dir("ostree/repo/objects")?;
+    attribute_usage(&sets, |obj| object_size(&objects, obj))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute_usage() -> Result<()> {
+        let sizes = HashMap::from([("a", 10u64), ("b", 20), ("c", 40), ("d", 80)]);
+        let sets = [
+            HashSet::from(["a", "b", "c"]),
+            HashSet::from(["b", "c", "d"]),
+            HashSet::from(["c"]),
+        ];
+        let usage = attribute_usage(&sets, |k| Ok(sizes[k]))?;
+        assert_eq!(
+            usage,
+            [
+                DeploymentUsage {
+                    exclusive: 10,
+                    shared: 60,
+                    ..Default::default()
+                },
+                DeploymentUsage {
+                    exclusive: 80,
+                    shared: 60,
+                    ..Default::default()
+                },
+                DeploymentUsage {
+                    exclusive: 0,
+                    shared: 40,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(attribute_usage::<&str>(&[], |_| unreachable!())?.is_empty());
+        Ok(())
+    }
+}
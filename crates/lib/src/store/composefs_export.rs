@@ -0,0 +1,377 @@
+//! # Exporting images from the composefs repository
+//!
+//! An image pulled into the composefs repository is stored as a splitstream
+//! `oci-config-sha256:<digest>` holding its configuration, which references a
+//! splitstream `oci-layer-sha256:<diffid>` for each layer tarball. This
+//! reassembles them into an OCI image layout (or an `oci-archive`), so that
+//! exactly what is installed on a host can be copied to another one or
+//! captured for analysis.
+//!
+//! The layers are exported uncompressed, so the manifest (and hence its digest)
+//! differs from the one originally pulled; the configuration and the layer
+//! content (the diffids) are identical.
+
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::oci_spec::image::{
+    Descriptor, ImageConfiguration, ImageManifest, ImageManifestBuilder, MediaType,
+    PlatformBuilder, SCHEMA_VERSION,
+};
+use ostree_ext::ocidir::OciDir;
+
+use super::{ComposefsRepository, Storage, COMPOSEFS};
+
+const STREAMS: &str = "streams";
+/// Named references live in this subdirectory of streams/
+const REFS: &str = "refs";
+const CONFIG_PREFIX: &str = "oci-config-";
+const LAYER_PREFIX: &str = "oci-layer-";
+
+/// Where to write an exported image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExportTarget {
+    /// An OCI image layout directory
+    Oci(Utf8PathBuf),
+    /// A tarball of an OCI image layout
+    OciArchive(Utf8PathBuf),
+}
+
+impl FromStr for ExportTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (transport, path) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Missing transport in {s}"))?;
+        anyhow::ensure!(!path.is_empty(), "Missing path in {s}");
+        match transport {
+            "oci" => Ok(Self::Oci(path.into())),
+            "oci-archive" => Ok(Self::OciArchive(path.into())),
+            o => anyhow::bail!("Unsupported transport {o}; expected oci or oci-archive"),
+        }
+    }
+}
+
+/// An image which can be exported.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ExportableImage {
+    /// The digest of the image configuration
+    pub(crate) config: String,
+    /// Named references to the image, relative to `streams/refs`
+    pub(crate) refs: Vec<String>,
+}
+
+/// Recursively collect the references in `d` (relative to `prefix`), mapped
+/// to the name of the stream they target.
+fn collect_refs(d: &Dir, prefix: &str, out: &mut Vec<(String, String)>) -> Result<()> {
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let path = if prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{prefix}/{name}")
+        };
+        if ent.file_type()?.is_dir() {
+            collect_refs(&ent.open_dir()?, &path, out)?;
+            continue;
+        }
+        let target = d.read_link_contents(name)?;
+        if let Some(target) = target.file_name().and_then(|v| v.to_str()) {
+            out.push((path, target.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// List the images in the `streams/` directory of a composefs repository.
+pub(crate) fn list_images(streams: &Dir) -> Result<Vec<ExportableImage>> {
+    let mut refs = Vec::new();
+    if let Some(d) = streams.open_dir_optional(REFS)? {
+        collect_refs(&d, "", &mut refs)?;
+    }
+    let mut r = Vec::new();
+    for ent in streams.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(config) = name.to_str().and_then(|v| v.strip_prefix(CONFIG_PREFIX)) else {
+            continue;
+        };
+        let stream = format!("{CONFIG_PREFIX}{config}");
+        let mut names: Vec<_> = refs
+            .iter()
+            .filter(|(_, target)| *target == stream)
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        r.push(ExportableImage {
+            config: config.to_owned(),
+            refs: names,
+        });
+    }
+    r.sort_by(|a, b| a.config.cmp(&b.config));
+    Ok(r)
+}
+
+/// Find the configuration stream for `image`, which may be a named reference,
+/// a configuration digest, or the stream name itself.
+fn resolve_config(streams: &Dir, image: &str) -> Result<String> {
+    let stream = if image.starts_with(CONFIG_PREFIX) {
+        image.to_owned()
+    } else if image.starts_with("sha256:") {
+        format!("{CONFIG_PREFIX}{image}")
+    } else {
+        let path = format!("{REFS}/{image}");
+        let target = streams
+            .read_link_contents(&path)
+            .with_context(|| format!("No such image: {image}"))?;
+        target
+            .file_name()
+            .and_then(|v| v.to_str())
+            .filter(|v| v.starts_with(CONFIG_PREFIX))
+            .ok_or_else(|| anyhow::anyhow!("{image} does not reference an image configuration"))?
+            .to_owned()
+    };
+    anyhow::ensure!(
+        streams.symlink_metadata_optional(&stream)?.is_some(),
+        "No such image: {image}"
+    );
+    Ok(stream)
+}
+
+/// Return the configuration digest of `image` (see [`resolve_config`]) if it is
+/// present in the composefs repository of `physical_root`.
+pub(crate) fn find_image(physical_root: &Dir, image: &str) -> Result<Option<String>> {
+    let Some(streams) = physical_root.open_dir_optional(format!("{COMPOSEFS}/{STREAMS}"))? else {
+        return Ok(None);
+    };
+    let Ok(stream) = resolve_config(&streams, image) else {
+        return Ok(None);
+    };
+    // SAFETY: resolve_config always returns a configuration stream
+    Ok(Some(stream.strip_prefix(CONFIG_PREFIX).unwrap().to_owned()))
+}
+
+/// Build the manifest for an image with uncompressed layers.
+fn build_manifest(config: Descriptor, layers: Vec<Descriptor>) -> Result<ImageManifest> {
+    Ok(ImageManifestBuilder::default()
+        .schema_version(SCHEMA_VERSION)
+        .media_type(MediaType::ImageManifest)
+        .config(config)
+        .layers(layers)
+        .build()?)
+}
+
+/// Write the image with configuration stream `config_stream` as an OCI layout in `dest`.
+#[context("Exporting {config_stream}")]
+fn export_to_dir(
+    repo: &ComposefsRepository,
+    config_stream: &str,
+    ref_name: Option<&str>,
+    dest: Dir,
+) -> Result<()> {
+    let oci = OciDir::ensure(dest)?;
+
+    let mut config_buf = Vec::new();
+    repo.merge_splitstream(config_stream, None, &mut config_buf)?;
+    let config = ImageConfiguration::from_reader(config_buf.as_slice())
+        .context("Parsing image configuration")?;
+
+    let mut layers = Vec::new();
+    for diffid in config.rootfs().diff_ids() {
+        let name = format!("{LAYER_PREFIX}{diffid}");
+        let mut w = oci.create_blob()?;
+        repo.merge_splitstream(&name, None, &mut w)
+            .with_context(|| format!("Exporting layer {diffid}"))?;
+        let layer = w
+            .complete()?
+            .descriptor()
+            .media_type(MediaType::ImageLayer)
+            .build()?;
+        // The layer is uncompressed, so its digest is the diffid
+        anyhow::ensure!(
+            layer.digest().to_string() == *diffid,
+            "Layer {diffid} has unexpected digest {}",
+            layer.digest()
+        );
+        layers.push(layer);
+    }
+
+    // Write the configuration verbatim rather than reserializing it, so that its
+    // digest is unchanged.
+    let mut w = oci.create_blob()?;
+    w.write_all(&config_buf)?;
+    let config_desc = w
+        .complete()?
+        .descriptor()
+        .media_type(MediaType::ImageConfig)
+        .build()?;
+    let expected = config_stream.trim_start_matches(CONFIG_PREFIX);
+    anyhow::ensure!(
+        config_desc.digest().to_string() == expected,
+        "Configuration has unexpected digest {}",
+        config_desc.digest()
+    );
+
+    let manifest = build_manifest(config_desc, layers)?;
+    let platform = PlatformBuilder::default()
+        .architecture(config.architecture().clone())
+        .os(config.os().clone())
+        .build()?;
+    if let Some(ref_name) = ref_name {
+        oci.insert_manifest(manifest, Some(ref_name), platform)?;
+    } else {
+        oci.replace_with_single_manifest(manifest, platform)?;
+    }
+    Ok(())
+}
+
+/// Export `image` from the composefs repository to `target`.
+#[context("Exporting image")]
+pub(crate) fn export(
+    storage: &Storage,
+    image: &str,
+    ref_name: Option<&str>,
+    target: &ExportTarget,
+) -> Result<()> {
+    let streams = storage
+        .physical_root
+        .open_dir_optional(format!("{COMPOSEFS}/{STREAMS}"))?
+        .ok_or_else(|| anyhow::anyhow!("No composefs repository"))?;
+    let config_stream = resolve_config(&streams, image)?;
+    let repo = &storage.get_ensure_composefs()?;
+    match target {
+        ExportTarget::Oci(path) => {
+            std::fs::create_dir_all(path).with_context(|| format!("Creating {path}"))?;
+            let dest = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+            export_to_dir(repo, &config_stream, ref_name, dest)?;
+        }
+        ExportTarget::OciArchive(path) => {
+            let td = tempfile::tempdir_in(parent_or_cwd(path))?;
+            let dest = Dir::open_ambient_dir(td.path(), cap_std::ambient_authority())?;
+            export_to_dir(repo, &config_stream, ref_name, dest)?;
+            let f = std::fs::File::create(path).with_context(|| format!("Creating {path}"))?;
+            let mut tar = tar::Builder::new(std::io::BufWriter::new(f));
+            tar.follow_symlinks(false);
+            tar.append_dir_all(".", td.path())?;
+            tar.into_inner()?
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+        }
+    }
+    println!("Exported {config_stream}");
+    Ok(())
+}
+
+fn parent_or_cwd(path: &Utf8Path) -> &Utf8Path {
+    path.parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."))
+}
+
+/// Print the images which can be exported.
+pub(crate) fn print_images(storage: &Storage, mut out: impl std::io::Write) -> Result<()> {
+    let images = match storage
+        .physical_root
+        .open_dir_optional(format!("{COMPOSEFS}/{STREAMS}"))?
+    {
+        Some(streams) => list_images(&streams)?,
+        None => Vec::new(),
+    };
+    if images.is_empty() {
+        writeln!(out, "No images in the composefs repository")?;
+    }
+    for image in images {
+        writeln!(out, "{}", image.config)?;
+        for r in image.refs {
+            writeln!(out, "  {r}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST_A: &str =
+        "sha256:2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+    const DIGEST_B: &str =
+        "sha256:fcde2b2edba56bf408601fb721fe9b5c338d10ee429ea04fae5511b68fbf8fb9";
+
+    #[test]
+    fn test_export_target() -> Result<()> {
+        assert_eq!(
+            ExportTarget::from_str("oci:/var/tmp/img")?,
+            ExportTarget::Oci("/var/tmp/img".into())
+        );
+        assert_eq!(
+            ExportTarget::from_str("oci-archive:img.tar")?,
+            ExportTarget::OciArchive("img.tar".into())
+        );
+        for bad in ["/var/tmp/img", "oci:", "docker://quay.io/foo"] {
+            assert!(ExportTarget::from_str(bad).is_err(), "{bad}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_and_resolve() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("refs/oci")?;
+        let config_a = format!("{CONFIG_PREFIX}{DIGEST_A}");
+        let config_b = format!("{CONFIG_PREFIX}{DIGEST_B}");
+        td.symlink_contents("../objects/aa/01", &config_a)?;
+        td.symlink_contents("../objects/aa/02", &config_b)?;
+        td.symlink_contents("../objects/bb/01", format!("{LAYER_PREFIX}{DIGEST_A}"))?;
+        td.symlink_contents(format!("../../{config_a}"), "refs/oci/myimage")?;
+
+        let images = list_images(&td)?;
+        assert_eq!(
+            images,
+            [
+                ExportableImage {
+                    config: DIGEST_A.into(),
+                    refs: vec!["oci/myimage".into()],
+                },
+                ExportableImage {
+                    config: DIGEST_B.into(),
+                    refs: vec![],
+                },
+            ]
+        );
+
+        assert_eq!(resolve_config(&td, "oci/myimage")?, config_a);
+        assert_eq!(resolve_config(&td, DIGEST_B)?, config_b);
+        assert_eq!(resolve_config(&td, &config_b)?, config_b);
+        assert!(resolve_config(&td, "oci/other").is_err());
+        assert!(resolve_config(&td, &DIGEST_A.replace('2', "3")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_image() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert_eq!(find_image(&td, DIGEST_A)?, None);
+        td.create_dir_all(format!("{COMPOSEFS}/{STREAMS}/{REFS}"))?;
+        let streams = td.open_dir(format!("{COMPOSEFS}/{STREAMS}"))?;
+        let config_a = format!("{CONFIG_PREFIX}{DIGEST_A}");
+        streams.symlink_contents("../objects/aa/01", &config_a)?;
+        streams.symlink_contents(format!("../{config_a}"), "refs/myimage")?;
+        assert_eq!(find_image(&td, "myimage")?.as_deref(), Some(DIGEST_A));
+        assert_eq!(find_image(&td, DIGEST_A)?.as_deref(), Some(DIGEST_A));
+        assert_eq!(find_image(&td, DIGEST_B)?, None);
+        Ok(())
+    }
+}
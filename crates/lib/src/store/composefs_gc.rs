@@ -0,0 +1,383 @@
+//! # Garbage collection for the composefs repository
+//!
+//! The composefs repository is content addressed; images and splitstreams
+//! are symbolic links into the `objects/` directory. Deployments are rooted
+//! by their state directory in `state/deploy/<image id>`, and named images
+//! and streams by the links in `images/refs` and `streams/refs`. Anything not
+//! reachable from one of these roots is garbage.
+//!
+//! Deployments are normally managed by ostree, in which case there is no
+//! `state/deploy` and the repository is only used as an image store; it is
+//! then only pruned on request (`bootc internals cleanup-composefs`), as
+//! an empty set of deployments doesn't mean nothing is in use.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use composefs::fsverity::FsVerityHashValue;
+use fn_error_context::context;
+use ostree_ext::composefs;
+use serde::Serialize;
+
+use super::accounting::physical_size;
+use super::{Storage, COMPOSEFS};
+
+/// The directory holding per-deployment state, relative to the physical root
+pub(crate) const STATE_DEPLOY: &str = "state/deploy";
+const IMAGES: &str = "images";
+const STREAMS: &str = "streams";
+const OBJECTS: &str = "objects";
+/// Named references live in this subdirectory of images/ and streams/
+const REFS: &str = "refs";
+
+/// The result of a garbage collection pass.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComposefsGcReport {
+    /// Unreferenced images
+    pub(crate) images: Vec<String>,
+    /// Unreferenced splitstreams
+    pub(crate) streams: Vec<String>,
+    /// Unreferenced objects
+    pub(crate) objects: Vec<String>,
+    /// Physical size of the unreferenced objects
+    pub(crate) size: u64,
+}
+
+impl ComposefsGcReport {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.images.is_empty() && self.streams.is_empty() && self.objects.is_empty()
+    }
+}
+
+/// Given a link to `../objects/xx/yyyy`, return the object ID `xxyyyy`.
+fn object_of_link(target: &Path) -> Option<String> {
+    let mut components = target.iter().rev();
+    let rest = components.next()?.to_str()?;
+    let prefix = components.next()?.to_str()?;
+    (prefix.len() == 2).then(|| format!("{prefix}{rest}"))
+}
+
+/// Return the entries in a category directory (images/ or streams/), mapped
+/// to the object they link to. The refs/ subdirectory is skipped.
+fn list_category(d: &Dir) -> Result<Vec<(String, Option<String>)>> {
+    let mut r = Vec::new();
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name == REFS {
+            continue;
+        }
+        let target = d.read_link_contents(name)?;
+        r.push((name.to_owned(), object_of_link(&target)));
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Recursively collect the names targeted by the symbolic links in a refs/ directory.
+fn collect_refs(d: &Dir, out: &mut HashSet<String>) -> Result<()> {
+    for ent in d.entries()? {
+        let ent = ent?;
+        let name = ent.file_name();
+        if ent.file_type()?.is_dir() {
+            collect_refs(&ent.open_dir()?, out)?;
+            continue;
+        }
+        let target = d.read_link_contents(&name)?;
+        if let Some(target) = target.file_name().and_then(|v| v.to_str()) {
+            out.insert(target.to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Compute the unreferenced content in a composefs repository.
+///
+/// The roots are the images in `deployed`, plus any images and streams with a
+/// named reference.
+/// The provided closures enumerate the objects referenced by an image and by a
+/// stream respectively.
+fn plan_gc(
+    repo: &Dir,
+    deployed: &HashSet<String>,
+    mut objects_for_image: impl FnMut(&str) -> Result<HashSet<String>>,
+    mut objects_for_stream: impl FnMut(&str) -> Result<HashSet<String>>,
+) -> Result<ComposefsGcReport> {
+    let mut report = ComposefsGcReport::default();
+    let mut live = HashSet::new();
+
+    if let Some(images) = repo.open_dir_optional(IMAGES)? {
+        let mut named = HashSet::new();
+        if let Some(refs) = images.open_dir_optional(REFS)? {
+            collect_refs(&refs, &mut named)?;
+        }
+        for (name, obj) in list_category(&images)? {
+            if deployed.contains(&name) || named.contains(&name) {
+                live.extend(obj);
+                live.extend(objects_for_image(&name)?);
+            } else {
+                report.images.push(name);
+            }
+        }
+    }
+
+    if let Some(streams) = repo.open_dir_optional(STREAMS)? {
+        let mut roots = HashSet::new();
+        if let Some(refs) = streams.open_dir_optional(REFS)? {
+            collect_refs(&refs, &mut roots)?;
+        }
+        let entries = list_category(&streams)?;
+        // Streams may reference other streams (e.g. a config and its layers), so
+        // iterate until we stop finding new ones.
+        let mut visited = HashSet::new();
+        loop {
+            let mut changed = false;
+            for (name, obj) in entries.iter() {
+                let Some(obj) = obj else { continue };
+                if visited.contains(name.as_str()) || !(roots.contains(name) || live.contains(obj))
+                {
+                    continue;
+                }
+                visited.insert(name.as_str());
+                live.insert(obj.clone());
+                live.extend(objects_for_stream(name)?);
+                changed = true;
+            }
+            if !changed {
+                break;
+            }
+        }
+        report.streams = entries
+            .iter()
+            .filter(|(name, _)| !visited.contains(name.as_str()))
+            .map(|(name, _)| name.clone())
+            .collect();
+    }
+
+    if let Some(objects) = repo.open_dir_optional(OBJECTS)? {
+        for ent in objects.entries()? {
+            let ent = ent?;
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+            let prefix = ent.file_name();
+            let Some(prefix) = prefix.to_str() else {
+                continue;
+            };
+            let subdir = ent.open_dir()?;
+            for obj in subdir.entries()? {
+                let obj = obj?;
+                let name = obj.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                let id = format!("{prefix}{name}");
+                if !live.contains(&id) {
+                    report.size += physical_size(&obj.metadata()?);
+                    report.objects.push(id);
+                }
+            }
+        }
+        report.objects.sort();
+    }
+
+    Ok(report)
+}
+
+/// Remove the content found by [`plan_gc`].
+fn execute_gc(repo: &Dir, report: &ComposefsGcReport) -> Result<()> {
+    for (category, names) in [(IMAGES, &report.images), (STREAMS, &report.streams)] {
+        for name in names {
+            repo.remove_file_optional(format!("{category}/{name}"))
+                .with_context(|| format!("Removing {category}/{name}"))?;
+        }
+    }
+    for obj in report.objects.iter() {
+        let path = format!("{OBJECTS}/{}/{}", &obj[0..2], &obj[2..]);
+        repo.remove_file_optional(&path)
+            .with_context(|| format!("Removing {path}"))?;
+    }
+    Ok(())
+}
+
+/// Find and (unless `dry_run` is set) prune content in the composefs repository
+/// which is not referenced by any deployment or named reference. Returns `None`
+/// if there is no composefs repository.
+#[context("Garbage collecting composefs repository")]
+pub(crate) fn gc(storage: &Storage, dry_run: bool) -> Result<Option<ComposefsGcReport>> {
+    let Some(repodir) = storage.physical_root.open_dir_optional(COMPOSEFS)? else {
+        return Ok(None);
+    };
+    let repo = storage.get_ensure_composefs()?;
+    let mut deployed = HashSet::new();
+    if let Some(d) = storage.physical_root.open_dir_optional(STATE_DEPLOY)? {
+        for ent in d.entries()? {
+            let ent = ent?;
+            if let Some(name) = ent.file_name().to_str() {
+                deployed.insert(name.to_owned());
+            }
+        }
+    }
+    let report = plan_gc(
+        &repodir,
+        &deployed,
+        |name| {
+            let objects = repo.objects_for_image(name)?;
+            Ok(objects.iter().map(|o| o.to_hex()).collect())
+        },
+        |name| {
+            let mut r = HashSet::new();
+            repo.open_stream(name, None)?.get_object_refs(|id| {
+                r.insert(id.to_hex());
+            })?;
+            Ok(r)
+        },
+    )?;
+    if !dry_run {
+        execute_gc(&repodir, &report)?;
+    }
+    Ok(Some(report))
+}
+
+/// Prune the composefs repository after the deployments changed, if they
+/// are rooted in [`STATE_DEPLOY`].
+pub(crate) fn auto_gc(storage: &Storage) -> Result<()> {
+    if storage
+        .physical_root
+        .symlink_metadata_optional(STATE_DEPLOY)?
+        .is_none()
+    {
+        tracing::debug!("No {STATE_DEPLOY}; skipping composefs garbage collection");
+        return Ok(());
+    }
+    if let Some(report) = gc(storage, false)? {
+        if !report.is_empty() {
+            print_report(std::io::stdout().lock(), &report, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print a human readable summary of a garbage collection pass.
+pub(crate) fn print_report(
+    mut out: impl std::io::Write,
+    report: &ComposefsGcReport,
+    dry_run: bool,
+) -> Result<()> {
+    if report.is_empty() {
+        writeln!(out, "No unreferenced composefs content")?;
+        return Ok(());
+    }
+    let verb = if dry_run { "Would prune" } else { "Pruned" };
+    for image in report.images.iter() {
+        writeln!(out, "{verb} image: {image}")?;
+    }
+    for stream in report.streams.iter() {
+        writeln!(out, "{verb} stream: {stream}")?;
+    }
+    let size = indicatif::HumanBytes(report.size);
+    writeln!(
+        out,
+        "{verb} composefs: images: {}, streams: {}, objects: {} ({size})",
+        report.images.len(),
+        report.streams.len(),
+        report.objects.len()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    fn add_object(d: &Dir, id: &str) -> Result<()> {
+        d.create_dir_all(format!("objects/{}", &id[0..2]))?;
+        d.write(format!("objects/{}/{}", &id[0..2], &id[2..]), id)?;
+        Ok(())
+    }
+
+    fn link(d: &Dir, path: &str, target: &str) -> Result<()> {
+        d.symlink_contents(target, path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_gc() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        for id in [
+            "aa01", "aa02", "aa03", "bb01", "bb02", "bb03", "cc01", "cc02", "dd01", "ee01",
+        ] {
+            add_object(&td, id)?;
+        }
+        td.create_dir_all("images/refs")?;
+        td.create_dir_all("streams/refs/oci")?;
+        // Deployed image aa01, referencing bb01
+        link(&td, "images/aa01", "../objects/aa/01")?;
+        // Old image aa02, referencing bb02
+        link(&td, "images/aa02", "../objects/aa/02")?;
+        // Named image aa03, referencing bb03
+        link(&td, "images/aa03", "../objects/aa/03")?;
+        link(&td, "images/refs/myimage", "../aa03")?;
+        // A named config stream referencing a layer stream
+        link(&td, "streams/config", "../objects/cc/01")?;
+        link(&td, "streams/refs/oci/myimage", "../../../config")?;
+        link(&td, "streams/layer", "../objects/cc/02")?;
+        // A leaked stream
+        link(&td, "streams/leaked", "../objects/ee/01")?;
+
+        let deployed = HashSet::from(["aa01".to_owned()]);
+        let report = plan_gc(
+            &td,
+            &deployed,
+            |name| {
+                Ok(match name {
+                    "aa01" => HashSet::from(["bb01".to_owned()]),
+                    "aa03" => HashSet::from(["bb03".to_owned()]),
+                    o => unreachable!("{o}"),
+                })
+            },
+            |name| {
+                Ok(match name {
+                    "config" => HashSet::from(["cc02".to_owned()]),
+                    "layer" => HashSet::from(["dd01".to_owned()]),
+                    o => unreachable!("{o}"),
+                })
+            },
+        )?;
+        assert_eq!(report.images, ["aa02"]);
+        assert_eq!(report.streams, ["leaked"]);
+        assert_eq!(report.objects, ["aa02", "bb02", "ee01"]);
+
+        execute_gc(&td, &report)?;
+        assert!(!td.exists("images/aa02"));
+        assert!(td.exists("images/aa01"));
+        assert!(td.exists("images/aa03"));
+        assert!(td.exists("objects/bb/03"));
+        assert!(td.symlink_metadata_optional("streams/leaked")?.is_none());
+        assert!(!td.exists("objects/bb/02"));
+        assert!(td.exists("objects/dd/01"));
+
+        // Nothing further to do
+        let report = plan_gc(
+            &td,
+            &deployed,
+            |_| Ok(HashSet::from(["bb01".to_owned(), "bb03".to_owned()])),
+            |name| {
+                Ok(match name {
+                    "config" => HashSet::from(["cc02".to_owned()]),
+                    _ => HashSet::from(["dd01".to_owned()]),
+                })
+            },
+        )?;
+        assert!(report.is_empty());
+        Ok(())
+    }
+}
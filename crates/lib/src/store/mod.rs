@@ -20,6 +20,9 @@ use crate::lsm;
 use crate::spec::ImageStatus;
 use crate::utils::deployment_fd;
 
+pub(crate) mod accounting;
+pub(crate) mod composefs_export;
+pub(crate) mod composefs_gc;
 mod ostree_container;
 
 /// See https://github.com/containers/composefs-rs/issues/159
@@ -54,6 +57,9 @@ pub(crate) struct Storage {
     /// This is a stub abstraction that tries to hide ostree
     /// that we aren't really using right now
     pub store: Box<dyn ContainerImageStoreImpl>,
+
+    /// True if we're operating on a disk image; see [`crate::offline`]
+    offline: bool,
 }
 
 #[derive(Default)]
@@ -121,9 +127,31 @@ impl Storage {
             composefs: Default::default(),
             store,
             imgstore: Default::default(),
+            offline: crate::offline::target().is_some(),
         })
     }
 
+    /// Returns true if we're operating on a disk image instead of the booted system.
+    pub(crate) fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// The booted deployment. When operating on a disk image, this is the
+    /// default deployment, i.e. the one which will be booted.
+    pub(crate) fn booted_deployment(&self) -> Option<ostree::Deployment> {
+        if self.offline {
+            self.sysroot.deployments().into_iter().next()
+        } else {
+            self.sysroot.booted_deployment()
+        }
+    }
+
+    /// Like [`Self::booted_deployment`], but returns an error if there isn't one.
+    pub(crate) fn require_booted_deployment(&self) -> Result<ostree::Deployment> {
+        self.booted_deployment()
+            .ok_or_else(|| anyhow::anyhow!("Not booted into an OSTree system"))
+    }
+
     /// Access the image storage; will automatically initialize it if necessary.
     pub(crate) fn get_ensure_imgstore(&self) -> Result<&crate::imgstorage::Storage> {
         if let Some(imgstore) = self.imgstore.get() {
@@ -153,6 +181,20 @@ impl Storage {
         Ok(self.imgstore.get_or_init(|| imgstore))
     }
 
+    /// Access the image storage only if it has already been created.
+    pub(crate) fn get_imgstore_if_exists(&self) -> Result<Option<&crate::imgstorage::Storage>> {
+        if let Some(imgstore) = self.imgstore.get() {
+            return Ok(Some(imgstore));
+        }
+        let sysroot_dir = crate::utils::sysroot_dir(&self.sysroot)?;
+        let subpath = format!("{BOOTC_ROOT}/{}", crate::imgstorage::SUBPATH);
+        if !sysroot_dir.try_exists(&subpath)? {
+            return Ok(None);
+        }
+        let imgstore = crate::imgstorage::Storage::open(&sysroot_dir, &self.run)?;
+        Ok(Some(self.imgstore.get_or_init(|| imgstore)))
+    }
+
     pub(crate) fn get_ensure_composefs(&self) -> Result<Arc<ComposefsRepository>> {
         if let Some(composefs) = self.composefs.get() {
             return Ok(Arc::clone(composefs));
@@ -180,7 +222,7 @@ impl Storage {
 
     /// Update the mtime on the storage root directory
     #[context("Updating storage root mtime")]
-    pub(crate) fn update_mtime(&self) -> Result<()> {
+    fn update_mtime(&self) -> Result<()> {
         let sysroot_dir =
             crate::utils::sysroot_dir(&self.sysroot).context("Reopen sysroot directory")?;
 
@@ -189,6 +231,20 @@ impl Storage {
             .context("update_timestamps")
             .map_err(Into::into)
     }
+
+    /// Record that the system status changed: update the storage root mtime
+    /// (watched by `bootc-status-updated.path`) and write a new event
+    /// for inotify-based watchers; see [`crate::events`].
+    pub(crate) fn status_changed(&self, reason: crate::events::StatusChangeReason) -> Result<()> {
+        self.update_mtime()?;
+        let sysroot_dir =
+            crate::utils::sysroot_dir(&self.sysroot).context("Reopen sysroot directory")?;
+        crate::events::record(&sysroot_dir, reason)?;
+        if let Err(e) = crate::history::record_change(&self.sysroot, reason) {
+            tracing::warn!("{e:#}");
+        }
+        Ok(())
+    }
 }
 
 impl ContainerImageStore for ostree::Deployment {
@@ -29,8 +29,10 @@ impl super::ContainerImageStoreImpl for OstreeContainerStore {
         let cached = imgstate.cached_update.map(|cached| {
             create_imagestatus(image.clone(), &cached.manifest_digest, &cached.config)
         });
-        let imagestatus =
+        let mut imagestatus =
             create_imagestatus(image, &imgstate.manifest_digest, &imgstate.configuration);
+        (imagestatus.platform, imagestatus.available_platforms) =
+            crate::deploy::origin_platform(deployment)?;
 
         Ok(CachedImageStatus {
             image: Some(imagestatus),
@@ -62,6 +64,8 @@ fn create_imagestatus(
         timestamp,
         image_digest: manifest_digest.to_string(),
         architecture,
+        platform: None,
+        available_platforms: Vec::new(),
     }
 }
 
@@ -0,0 +1,491 @@
+//! # Offline update bundles
+//!
+//! An update bundle is a single (uncompressed) tar file holding a container image
+//! in `oci-archive` format along with metadata describing it, so that systems
+//! without network access can be updated via `bootc update-bundle apply`.
+//!
+//! The bundle contains:
+//!
+//! - `bootc-bundle.json`: The [`BundleMetadata`], including the SHA-256 of the archive
+//! - `bootc-bundle.json.sig`: An optional detached signature of the metadata
+//! - `image.ociarchive`: The image
+//!
+//! Systems which apply a bundle track the image reference recorded in the metadata,
+//! so later online updates work as if the image had been pulled from the registry.
+
+use std::io::{Read, Write};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::pkey::{HasPrivate, HasPublic, Id, PKey, PKeyRef};
+use openssl::sign::{Signer, Verifier};
+use ostree_ext::container as ostree_container;
+use serde::{Deserialize, Serialize};
+
+use crate::deploy::{PullOptions, RequiredHostSpec};
+use crate::events::StatusChangeReason;
+use crate::progress_jsonl::ProgressWriter;
+use crate::spec::{ImageReference, ImageSignature};
+
+/// The current bundle format version
+const BUNDLE_VERSION: u32 = 1;
+const METADATA: &str = "bootc-bundle.json";
+const SIGNATURE: &str = "bootc-bundle.json.sig";
+const ARCHIVE: &str = "image.ociarchive";
+/// Where we unpack the image when applying a bundle
+const UNPACK_DIR: &str = "/var/tmp";
+
+/// Metadata describing an update bundle.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BundleMetadata {
+    /// The bundle format version
+    pub(crate) version: u32,
+    /// The image the bundle was created from
+    pub(crate) image: String,
+    /// The transport of the image
+    pub(crate) transport: String,
+    /// The manifest digest of the image in the archive
+    pub(crate) manifest_digest: String,
+    /// The SHA-256 of the archive
+    pub(crate) archive_sha256: String,
+    /// When the bundle was created
+    pub(crate) created: chrono::DateTime<chrono::Utc>,
+}
+
+/// Compute the hex SHA-256 of the provided reader.
+pub(crate) fn sha256_hex(mut r: impl Read) -> Result<String> {
+    let mut h = Hasher::new(MessageDigest::sha256())?;
+    std::io::copy(&mut r, &mut h)?;
+    Ok(hex::encode(h.finish()?))
+}
+
+/// Sign the provided data. Ed25519 keys sign the data directly; other
+/// key types sign its SHA-256.
+fn sign<T: HasPrivate>(key: &PKeyRef<T>, data: &[u8]) -> Result<Vec<u8>> {
+    let mut signer = if key.id() == Id::ED25519 {
+        Signer::new_without_digest(key)?
+    } else {
+        Signer::new(MessageDigest::sha256(), key)?
+    };
+    Ok(signer.sign_oneshot_to_vec(data)?)
+}
+
+/// Verify a signature created by [`sign`].
+fn verify<T: HasPublic>(key: &PKeyRef<T>, data: &[u8], signature: &[u8]) -> Result<()> {
+    let mut verifier = if key.id() == Id::ED25519 {
+        Verifier::new_without_digest(key)?
+    } else {
+        Verifier::new(MessageDigest::sha256(), key)?
+    };
+    if !verifier.verify_oneshot(signature, data)? {
+        anyhow::bail!("Invalid bundle signature");
+    }
+    Ok(())
+}
+
+/// Check the bundle signature against the host's signature policy, returning
+/// whether the metadata was verified.
+///
+/// If the host verifies the signatures of the images it tracks, the bundle
+/// must be signed and verified with `verify_key`; there is no way to check
+/// the image itself against the host's policy as it is imported from an archive.
+fn check_signature<T: HasPublic>(
+    verify_key: Option<&PKeyRef<T>>,
+    metadata: &[u8],
+    signature: Option<&[u8]>,
+    policy: Option<&ImageSignature>,
+) -> Result<bool> {
+    match (verify_key, signature) {
+        (Some(key), Some(sig)) => {
+            verify(key, metadata, sig)?;
+            return Ok(true);
+        }
+        (Some(_), None) => anyhow::bail!("Bundle is not signed"),
+        (None, _) => {}
+    }
+    match policy {
+        Some(ImageSignature::Insecure) | None => {}
+        Some(_) => anyhow::bail!(
+            "This host verifies image signatures; --verify-key is required to apply a bundle"
+        ),
+    }
+    if signature.is_some() {
+        eprintln!("warning: Bundle signature not verified (no key provided)");
+    }
+    Ok(false)
+}
+
+/// Write a bundle with the provided (serialized) metadata, signature and archive.
+fn write_bundle(
+    out: impl Write,
+    metadata: &[u8],
+    signature: Option<&[u8]>,
+    archive: &Utf8Path,
+) -> Result<()> {
+    let mut b = tar::Builder::new(out);
+    let entries = [(METADATA, Some(metadata)), (SIGNATURE, signature)];
+    for (name, data) in entries {
+        let Some(data) = data else {
+            continue;
+        };
+        let mut h = tar::Header::new_gnu();
+        h.set_size(data.len() as u64);
+        h.set_mode(0o644);
+        h.set_cksum();
+        b.append_data(&mut h, name, data)?;
+    }
+    let mut f = std::fs::File::open(archive).with_context(|| format!("Opening {archive}"))?;
+    b.append_file(ARCHIVE, &mut f)?;
+    b.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// The contents of a bundle; the archive is written to a file.
+#[derive(Debug)]
+struct UnpackedBundle {
+    metadata: Vec<u8>,
+    signature: Option<Vec<u8>>,
+}
+
+/// Read a bundle, writing the image archive to `archive` in `dest`.
+fn read_bundle(input: impl Read, dest: &Dir, archive: &str) -> Result<UnpackedBundle> {
+    let mut metadata = None;
+    let mut signature = None;
+    let mut found_archive = false;
+    let mut a = tar::Archive::new(input);
+    for entry in a.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?;
+        let path = path
+            .to_str()
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| anyhow!("Invalid non-UTF8 path in bundle: {path:?}"))?;
+        match path.as_str() {
+            METADATA | SIGNATURE => {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf)?;
+                if path == METADATA {
+                    metadata = Some(buf);
+                } else {
+                    signature = Some(buf);
+                }
+            }
+            ARCHIVE => {
+                let mut f = dest.create(archive)?;
+                std::io::copy(&mut entry, &mut f)?;
+                found_archive = true;
+            }
+            o => anyhow::bail!("Unexpected entry in bundle: {o}"),
+        }
+    }
+    let metadata = metadata.ok_or_else(|| anyhow!("Missing {METADATA} in bundle"))?;
+    anyhow::ensure!(found_archive, "Missing {ARCHIVE} in bundle");
+    Ok(UnpackedBundle {
+        metadata,
+        signature,
+    })
+}
+
+/// Parse and validate the bundle metadata.
+fn parse_metadata(buf: &[u8]) -> Result<BundleMetadata> {
+    let meta: BundleMetadata = serde_json::from_slice(buf).context("Parsing bundle metadata")?;
+    anyhow::ensure!(
+        meta.version == BUNDLE_VERSION,
+        "Unsupported bundle version {} (expected {BUNDLE_VERSION})",
+        meta.version
+    );
+    Ok(meta)
+}
+
+/// Implementation of `bootc update-bundle create`.
+#[context("Creating update bundle")]
+pub(crate) fn create(
+    transport: &str,
+    image: &str,
+    output: &Utf8Path,
+    sign_key: Option<&Utf8Path>,
+) -> Result<()> {
+    let transport = ostree_container::Transport::try_from(transport)?;
+    let src = ostree_container::ImageReference {
+        transport,
+        name: image.to_owned(),
+    };
+    let sign_key = sign_key
+        .map(|p| -> Result<_> {
+            let buf = std::fs::read(p).with_context(|| format!("Reading {p}"))?;
+            Ok(PKey::private_key_from_pem(&buf)?)
+        })
+        .transpose()?;
+    let parent = output
+        .parent()
+        .filter(|p| !p.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."));
+    let td = tempfile::Builder::new()
+        .prefix(".bootc-bundle")
+        .tempdir_in(parent)?;
+    let td_path = Utf8Path::from_path(td.path()).ok_or_else(|| anyhow!("Non-UTF8 path"))?;
+    let archive = td_path.join(ARCHIVE);
+    let digestfile = td_path.join("digest");
+
+    println!("Copying {src} ...");
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let mut cmd = Command::new("skopeo");
+    cmd.arg("copy").arg("--digestfile").arg(&digestfile);
+    if let Some(certdir) = ostree_container::registry_certificate_directory(root, &src.name)? {
+        cmd.arg("--src-cert-dir").arg(certdir);
+    }
+    cmd.arg(src.to_string())
+        .arg(format!("oci-archive:{archive}"))
+        .run_inherited_with_cmd_context()?;
+    let manifest_digest = std::fs::read_to_string(&digestfile)?.trim().to_owned();
+
+    let archive_sha256 = sha256_hex(std::fs::File::open(&archive)?)?;
+    let meta = BundleMetadata {
+        version: BUNDLE_VERSION,
+        image: image.to_owned(),
+        transport: transport.serializable_name().to_owned(),
+        manifest_digest,
+        archive_sha256,
+        created: std::time::SystemTime::now().into(),
+    };
+    let meta_buf = serde_json::to_vec_pretty(&meta)?;
+    let signature = sign_key.map(|k| sign(&k, &meta_buf)).transpose()?;
+
+    let mut out = tempfile::NamedTempFile::new_in(parent)?;
+    write_bundle(
+        std::io::BufWriter::new(out.as_file_mut()),
+        &meta_buf,
+        signature.as_deref(),
+        &archive,
+    )?;
+    out.persist(output)
+        .with_context(|| format!("Writing {output}"))?;
+    println!("Wrote {output}");
+    println!("  Digest: {}", meta.manifest_digest);
+    if signature.is_none() {
+        println!("  (unsigned)");
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc update-bundle apply`.
+#[context("Applying update bundle")]
+pub(crate) async fn apply(
+    bundle: &Utf8Path,
+    verify_key: Option<&Utf8Path>,
+    quiet: bool,
+    prog: ProgressWriter,
+) -> Result<()> {
+    let verify_key = verify_key
+        .map(|p| -> Result<_> {
+            let buf = std::fs::read(p).with_context(|| format!("Reading {p}"))?;
+            Ok(PKey::public_key_from_pem(&buf)?)
+        })
+        .transpose()?;
+    let sysroot = &crate::cli::get_storage().await?;
+    let repo = &sysroot.repo();
+
+    let td = tempfile::Builder::new()
+        .prefix("bootc-bundle")
+        .tempdir_in(UNPACK_DIR)?;
+    let td_path = Utf8Path::from_path(td.path()).ok_or_else(|| anyhow!("Non-UTF8 path"))?;
+    let td_dir = Dir::open_ambient_dir(td_path, cap_std::ambient_authority())?;
+    let input = std::fs::File::open(bundle).with_context(|| format!("Opening {bundle}"))?;
+    let unpacked = read_bundle(std::io::BufReader::new(input), &td_dir, ARCHIVE)?;
+
+    let (booted_deployment, _deployments, host) =
+        crate::status::get_status_require_booted(sysroot)?;
+    let current = host.spec.image.as_ref();
+    // Keep the existing signature policy for later (online) updates.
+    let signature = current.and_then(|i| i.signature.clone());
+    let verified = check_signature(
+        verify_key.as_deref(),
+        &unpacked.metadata,
+        unpacked.signature.as_deref(),
+        signature.as_ref(),
+    )?;
+    let meta = parse_metadata(&unpacked.metadata)?;
+    // Without a verified signature, the image reference in the metadata can't
+    // be trusted; don't let it change what the host tracks.
+    if !verified {
+        let tracked =
+            current.is_some_and(|i| i.image == meta.image && i.transport == meta.transport);
+        anyhow::ensure!(
+            tracked,
+            "Unverified bundle for {} does not match the tracked image; --verify-key is required",
+            meta.image
+        );
+    }
+    let archive_sha256 = sha256_hex(td_dir.open(ARCHIVE)?)?;
+    anyhow::ensure!(
+        archive_sha256 == meta.archive_sha256,
+        "Bundle archive checksum mismatch: expected {} found {archive_sha256}",
+        meta.archive_sha256
+    );
+    // The signature is valid; a bogus creation time here indicates a clock problem.
+    crate::clock::verify_timestamp("Bundle creation", meta.created)?;
+
+    let target = ImageReference {
+        image: meta.image.clone(),
+        transport: meta.transport.clone(),
+        signature,
+    };
+    let local = ImageReference {
+        image: td_path.join(ARCHIVE).into_string(),
+        transport: ostree_container::Transport::OciArchive
+            .serializable_name()
+            .to_owned(),
+        signature: None,
+    };
+    let mut new_spec = host.spec.clone();
+    new_spec.image = Some(target.clone());
+    let new_spec = RequiredHostSpec::from_spec(&new_spec)?;
+
+    println!("Applying bundle for {target:#} (created {})", meta.created);
+    let target_ostree =
+        ostree_container::OstreeImageReference::from(target.clone().canonicalize()?);
+    let fetched = crate::deploy::pull(
+        repo,
+        &local,
+        Some(&target_ostree),
+        quiet,
+        false,
+        &PullOptions::default(),
+        prog.clone(),
+    )
+    .await?;
+    anyhow::ensure!(
+        fetched.manifest_digest.to_string() == meta.manifest_digest,
+        "Bundle manifest digest mismatch: expected {} found {}",
+        meta.manifest_digest,
+        fetched.manifest_digest
+    );
+
+    let is_current = |e: Option<&crate::spec::BootEntry>| {
+        e.and_then(|e| e.image.as_ref()).is_some_and(|i| {
+            i.image.image == target.image && i.image_digest == meta.manifest_digest
+        })
+    };
+    if is_current(host.status.staged.as_ref()) {
+        println!("Staged update present, not changed.");
+        return Ok(());
+    } else if is_current(host.status.booted.as_ref()) {
+        println!("Bundle image is already booted.");
+        return Ok(());
+    }
+
+    let stateroot = booted_deployment.osname();
+    crate::deploy::stage(
+        sysroot,
+        &stateroot,
+        &fetched,
+        &new_spec,
+        &PullOptions::default(),
+        prog,
+    )
+    .await?;
+    sysroot.status_changed(StatusChangeReason::Upgrade)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    fn test_metadata() -> BundleMetadata {
+        BundleMetadata {
+            version: BUNDLE_VERSION,
+            image: "quay.io/example/os:latest".into(),
+            transport: "registry".into(),
+            manifest_digest: "sha256:0123".into(),
+            archive_sha256: sha256_hex(&b"archive"[..]).unwrap(),
+            created: chrono::DateTime::from_timestamp(1700000000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_bundle_roundtrip() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let tmp = tempfile::tempdir()?;
+        let src = Utf8Path::from_path(tmp.path())
+            .unwrap()
+            .join("src.ociarchive");
+        std::fs::write(&src, "archive")?;
+        let meta = serde_json::to_vec(&test_metadata())?;
+
+        let mut buf = Vec::new();
+        write_bundle(&mut buf, &meta, Some(b"sig"), &src)?;
+        let r = read_bundle(&buf[..], &td, "out")?;
+        assert_eq!(r.metadata, meta);
+        assert_eq!(r.signature.as_deref(), Some(&b"sig"[..]));
+        assert_eq!(td.read_to_string("out")?, "archive");
+        assert_eq!(parse_metadata(&r.metadata)?, test_metadata());
+        assert_eq!(sha256_hex(td.open("out")?)?, test_metadata().archive_sha256);
+
+        // Unsigned
+        let mut buf = Vec::new();
+        write_bundle(&mut buf, &meta, None, &src)?;
+        assert!(read_bundle(&buf[..], &td, "out")?.signature.is_none());
+
+        // Unknown future versions are rejected
+        let mut m = test_metadata();
+        m.version = 2;
+        assert!(parse_metadata(&serde_json::to_vec(&m)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_verify() -> Result<()> {
+        let meta = serde_json::to_vec(&test_metadata())?;
+        let ec = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?;
+        for key in [PKey::generate_ed25519()?, PKey::from_ec_key(ec)?] {
+            let public = PKey::public_key_from_pem(&key.public_key_to_pem()?)?;
+            let sig = sign(&key, &meta)?;
+            verify(&public, &meta, &sig)?;
+            let mut tampered = meta.clone();
+            tampered[0] ^= 1;
+            assert!(verify(&public, &tampered, &sig).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_signature() -> Result<()> {
+        let meta = serde_json::to_vec(&test_metadata())?;
+        let key = PKey::generate_ed25519()?;
+        let public = PKey::public_key_from_pem(&key.public_key_to_pem()?)?;
+        let sig = sign(&key, &meta)?;
+        let policy = ImageSignature::ContainerPolicy;
+        let none: Option<&PKeyRef<openssl::pkey::Public>> = None;
+
+        assert!(check_signature(
+            Some(&public),
+            &meta,
+            Some(&sig),
+            Some(&policy)
+        )?);
+        assert!(check_signature(Some(&public), &meta, None, None).is_err());
+        // Hosts with a signature policy fail closed without a key
+        assert!(check_signature(none, &meta, None, Some(&policy)).is_err());
+        assert!(check_signature(none, &meta, Some(&sig), Some(&policy)).is_err());
+        let remote = ImageSignature::OstreeRemote("os".into());
+        assert!(check_signature(none, &meta, None, Some(&remote)).is_err());
+        // Otherwise the bundle is accepted, but not verified
+        assert!(!check_signature(none, &meta, None, None)?);
+        assert!(!check_signature(
+            none,
+            &meta,
+            Some(&sig),
+            Some(&ImageSignature::Insecure)
+        )?);
+        Ok(())
+    }
+}
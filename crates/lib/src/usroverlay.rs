@@ -0,0 +1,322 @@
+//! # Writable overlay for /usr
+//!
+//! Implementation of `bootc usr-overlay`. The default mode is a transient
+//! overlay (backed by `ostree admin unlock`) which is discarded on reboot;
+//! with `--size-limit` the transient overlay is instead backed by a tmpfs of
+//! that size. The persistent mode stores the upper directory in the physical root
+//! and is re-applied at boot via a unit generated by [`crate::generator`].
+
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use bootc_utils::CommandRunExt;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree;
+
+use crate::spec::{Host, UsrOverlayStatus};
+
+/// The persistent overlay state, relative to the physical root
+pub(crate) const DEV_OVERLAY: &str = "ostree/bootc/dev-overlay";
+/// Records the deployment the overlay was created for
+const DEPLOYMENT_STAMP: &str = "deployment";
+/// If present, the overlay state is discarded at the next boot instead of being applied
+const RESET_STAMP: &str = "reset";
+const UPPER: &str = "upper";
+const WORK: &str = "work";
+/// The physical root, as seen from the booted system
+const SYSROOT: &str = "/sysroot";
+/// We need a writable mount of the physical root for the overlay upper directory
+const SYSROOT_RW: &str = "/run/bootc/dev-overlay-sysroot";
+/// The unit which applies the persistent overlay at boot
+pub(crate) const DEV_OVERLAY_UNIT: &str = "bootc-dev-overlay.service";
+/// The tmpfs backing a size limited transient overlay
+const TRANSIENT_TMPFS: &str = "/run/bootc/usr-overlay";
+
+/// An identifier for a deployment that is stable across reboots.
+fn deployment_id(deployment: &ostree::Deployment) -> String {
+    format!("{}.{}", deployment.csum(), deployment.deployserial())
+}
+
+/// Returns true if /usr is already a distinct (overlay) mount.
+fn usr_is_overlaid() -> Result<bool> {
+    let root = std::fs::metadata("/")?;
+    let usr = std::fs::metadata("/usr")?;
+    Ok(root.dev() != usr.dev())
+}
+
+fn booted_deployment() -> Result<ostree::Deployment> {
+    let sysroot = ostree::Sysroot::new_default();
+    sysroot.load(ostree::gio::Cancellable::NONE)?;
+    Ok(sysroot.require_booted_deployment()?)
+}
+
+/// Check whether persistent overlay state in `overlay` applies to the deployment
+/// with the provided identifier.
+fn overlay_matches(overlay: &Dir, id: &str) -> Result<bool> {
+    let Some(mut f) = overlay.open_optional(DEPLOYMENT_STAMP)? else {
+        return Ok(false);
+    };
+    let mut stamp = String::new();
+    std::io::Read::read_to_string(&mut f, &mut stamp)?;
+    Ok(stamp.trim() == id)
+}
+
+/// Make a writable mount of the physical root, run the provided function with it,
+/// and then unmount it again.
+fn with_writable_sysroot<T>(f: impl FnOnce(&Utf8Path) -> Result<T>) -> Result<T> {
+    let target = Utf8Path::new(SYSROOT_RW);
+    std::fs::create_dir_all(target)?;
+    Command::new("mount")
+        .args(["--bind", SYSROOT, SYSROOT_RW])
+        .run_capture_stderr()?;
+    let r = Command::new("mount")
+        .args(["-o", "remount,bind,rw", SYSROOT_RW])
+        .run_capture_stderr()
+        .and_then(|()| f(target));
+    // The overlay mount holds its own reference, so we can always drop this.
+    Command::new("umount")
+        .args(["-l", SYSROOT_RW])
+        .run_capture_stderr()?;
+    r
+}
+
+/// Mount the overlay using the state in the (writable) physical root.
+fn mount_overlay(sysroot: &Utf8Path) -> Result<()> {
+    let base = sysroot.join(DEV_OVERLAY);
+    let upper = base.join(UPPER);
+    let work = base.join(WORK);
+    Command::new("mount")
+        .args(["-t", "overlay", "overlay", "-o"])
+        .arg(format!("lowerdir=/usr,upperdir={upper},workdir={work}"))
+        .arg("/usr")
+        .run_capture_stderr()
+}
+
+/// Implementation of `bootc usr-overlay`
+pub(crate) fn transient(size_limit: Option<&str>) -> Result<()> {
+    let Some(size_limit) = size_limit else {
+        // This is just a pass-through today.  At some point we may make this a libostree API
+        // or even oxidize it.
+        return Err(Command::new("ostree")
+            .args(["admin", "unlock"])
+            .exec()
+            .into());
+    };
+    transient_with_limit(size_limit)
+}
+
+/// Mount a transient overlay backed by a tmpfs of the given size (in any format
+/// accepted by the tmpfs `size=` option, e.g. `512M` or `10%`).
+#[context("Creating transient /usr overlay")]
+fn transient_with_limit(size_limit: &str) -> Result<()> {
+    crate::cli::require_root(false)?;
+    if usr_is_overlaid()? {
+        anyhow::bail!("/usr is already an overlay");
+    }
+    let base = Utf8Path::new(TRANSIENT_TMPFS);
+    std::fs::create_dir_all(base)?;
+    Command::new("mount")
+        .args(["-t", "tmpfs", "-o"])
+        .arg(format!("size={size_limit},mode=0755"))
+        .args(["tmpfs", TRANSIENT_TMPFS])
+        .run_capture_stderr()?;
+    let upper = base.join(UPPER);
+    let work = base.join(WORK);
+    let r = std::fs::create_dir(&upper)
+        .and_then(|()| std::fs::create_dir(&work))
+        .map_err(anyhow::Error::from)
+        .and_then(|()| {
+            Command::new("mount")
+                .args(["-t", "overlay", "overlay", "-o"])
+                .arg(format!("lowerdir=/usr,upperdir={upper},workdir={work}"))
+                .arg("/usr")
+                .run_capture_stderr()
+        });
+    if let Err(e) = r {
+        let _ = Command::new("umount")
+            .arg(TRANSIENT_TMPFS)
+            .run_capture_stderr();
+        return Err(e);
+    }
+    println!("Development mode enabled.  A writable overlayfs is now mounted on /usr");
+    println!("limited to {size_limit}.  All changes there will be discarded on reboot.");
+    Ok(())
+}
+
+/// Extract the upper directory from the mount options of an overlay filesystem.
+fn overlay_upperdir(options: &str) -> Option<&str> {
+    options.split(',').find_map(|o| o.strip_prefix("upperdir="))
+}
+
+/// Return the state of the writable overlay on `/usr`, if any.
+#[context("Querying /usr overlay")]
+pub(crate) fn query() -> Result<Option<UsrOverlayStatus>> {
+    if !usr_is_overlaid()? {
+        return Ok(None);
+    }
+    let fs = bootc_mount::inspect_filesystem(Utf8Path::new("/usr"))?;
+    if fs.fstype != "overlay" {
+        return Ok(None);
+    }
+    let Some(upperdir) = overlay_upperdir(&fs.options) else {
+        // A read-only overlay, e.g. from systemd-sysext
+        return Ok(None);
+    };
+    let persistent = upperdir.ends_with(&format!("{DEV_OVERLAY}/{UPPER}"));
+    // The writable mount of the physical root used to set up a persistent overlay
+    // is detached, so look at the upper directory through /sysroot.
+    let upperdir = if persistent {
+        format!("{SYSROOT}/{DEV_OVERLAY}/{UPPER}")
+    } else {
+        upperdir.to_owned()
+    };
+    let authority = cap_std_ext::cap_std::ambient_authority();
+    let (dirty, bytes_written) = match Dir::open_ambient_dir(&upperdir, authority) {
+        Ok(upper) => (
+            upper.entries()?.next().is_some(),
+            crate::store::accounting::dir_size(&upper)?,
+        ),
+        Err(e) => {
+            tracing::debug!("Failed to open {upperdir}: {e}");
+            (false, 0)
+        }
+    };
+    let size_limit = if upperdir.starts_with(TRANSIENT_TMPFS) {
+        let st = rustix::fs::statvfs(TRANSIENT_TMPFS)?;
+        Some(st.f_blocks * st.f_frsize)
+    } else {
+        None
+    };
+    Ok(Some(UsrOverlayStatus {
+        persistent,
+        dirty,
+        bytes_written,
+        size_limit,
+    }))
+}
+
+/// Fill in the state of the `/usr` overlay in the host status.
+pub(crate) fn apply_to_host(host: &mut Host) -> Result<()> {
+    host.status.usr_overlay = query()?;
+    Ok(())
+}
+
+/// Implementation of `bootc usr-overlay --persistent`
+#[context("Creating persistent /usr overlay")]
+pub(crate) fn persistent() -> Result<()> {
+    crate::cli::require_root(false)?;
+    if usr_is_overlaid()? {
+        anyhow::bail!("/usr is already an overlay");
+    }
+    let id = deployment_id(&booted_deployment()?);
+    with_writable_sysroot(|sysroot| {
+        let d = Dir::open_ambient_dir(sysroot, cap_std_ext::cap_std::ambient_authority())?;
+        d.create_dir_all(DEV_OVERLAY)?;
+        let overlay = d.open_dir(DEV_OVERLAY)?;
+        if overlay.try_exists(DEPLOYMENT_STAMP)? && !overlay_matches(&overlay, &id)? {
+            anyhow::bail!(
+                "Found a persistent overlay for a different deployment; use `bootc usr-overlay reset` to discard it"
+            );
+        }
+        overlay.create_dir_all(UPPER)?;
+        overlay.create_dir_all(WORK)?;
+        overlay.atomic_write(DEPLOYMENT_STAMP, &id)?;
+        mount_overlay(sysroot)
+    })?;
+    println!("Persistent overlay mounted on /usr; it will be re-applied on boot.");
+    println!("Use `bootc usr-overlay reset` to discard it.");
+    Ok(())
+}
+
+/// Implementation of `bootc internals apply-dev-overlay`, run at boot.
+#[context("Applying persistent /usr overlay")]
+pub(crate) fn apply() -> Result<()> {
+    if usr_is_overlaid()? {
+        tracing::debug!("/usr is already an overlay");
+        return Ok(());
+    }
+    let sysroot = Dir::open_ambient_dir(SYSROOT, cap_std_ext::cap_std::ambient_authority())?;
+    let Some(overlay) = sysroot.open_dir_optional(DEV_OVERLAY)? else {
+        return Ok(());
+    };
+    if overlay.try_exists(RESET_STAMP)? {
+        return with_writable_sysroot(|sysroot| {
+            let d = Dir::open_ambient_dir(sysroot, cap_std_ext::cap_std::ambient_authority())?;
+            d.remove_all_optional(DEV_OVERLAY)
+                .context("Removing overlay state")?;
+            Ok(())
+        });
+    }
+    let id = deployment_id(&booted_deployment()?);
+    if !overlay_matches(&overlay, &id)? {
+        // Don't apply changes made on top of a different deployment, e.g. after an upgrade.
+        tracing::warn!("Persistent /usr overlay is for a different deployment; not applying it");
+        return Ok(());
+    }
+    with_writable_sysroot(mount_overlay)
+}
+
+/// Implementation of `bootc usr-overlay reset`
+#[context("Discarding persistent /usr overlay")]
+pub(crate) fn reset() -> Result<()> {
+    crate::cli::require_root(false)?;
+    let sysroot = Dir::open_ambient_dir(SYSROOT, cap_std_ext::cap_std::ambient_authority())?;
+    if !sysroot.try_exists(DEV_OVERLAY)? {
+        println!("No persistent overlay found");
+        return Ok(());
+    }
+    // The upper and work directories must not be modified while the overlay is
+    // mounted, so in that case the removal is deferred to the next boot.
+    let mounted = query()?.is_some_and(|s| s.persistent);
+    with_writable_sysroot(|sysroot| {
+        let d = Dir::open_ambient_dir(sysroot, cap_std_ext::cap_std::ambient_authority())?;
+        if mounted {
+            d.atomic_write(format!("{DEV_OVERLAY}/{RESET_STAMP}"), "")?;
+        } else {
+            d.remove_all_optional(DEV_OVERLAY)
+                .context("Removing overlay state")?;
+        }
+        Ok(())
+    })?;
+    if mounted {
+        println!("Persistent overlay will be discarded on reboot.");
+    } else {
+        println!("Persistent overlay discarded.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    #[test]
+    fn test_overlay_upperdir() {
+        assert_eq!(
+            overlay_upperdir(
+                "rw,relatime,lowerdir=/usr,upperdir=/run/bootc/usr-overlay/upper,workdir=/run/bootc/usr-overlay/work"
+            ),
+            Some("/run/bootc/usr-overlay/upper")
+        );
+        assert_eq!(
+            overlay_upperdir("ro,relatime,lowerdir=/usr:/run/extensions"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_overlay_matches() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(!overlay_matches(&td, "abc.0")?);
+        td.write(DEPLOYMENT_STAMP, "abc.0\n")?;
+        assert!(overlay_matches(&td, "abc.0")?);
+        assert!(!overlay_matches(&td, "abc.1")?);
+        Ok(())
+    }
+}
@@ -131,6 +131,17 @@ pub(crate) fn sigpolicy_from_opt(enforce_container_verification: bool) -> Signat
     }
 }
 
+/// The time since boot.
+pub(crate) fn uptime() -> Result<Duration> {
+    let buf = std::fs::read_to_string("/proc/uptime")?;
+    let secs = buf
+        .split_whitespace()
+        .next()
+        .and_then(|v| v.parse::<f64>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid /proc/uptime: {buf}"))?;
+    Ok(Duration::from_secs_f64(secs))
+}
+
 /// Output a warning message that we want to be quite visible.
 /// The process (thread) execution will be delayed for a short time.
 pub(crate) fn medium_visibility_warning(s: &str) {
@@ -0,0 +1,134 @@
+//! # Machine readable version information
+//!
+//! Implementation of `bootc --version --format=json`, which allows tools to
+//! check for capabilities rather than parsing the human readable version.
+
+use std::ffi::OsString;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::progress_jsonl::ProgressVersion;
+
+/// Schema versions supported by this binary.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SchemaVersions {
+    /// Supported `apiVersion` values for `bootc status --json`
+    pub(crate) host: Vec<&'static str>,
+    /// Supported `--progress-fd` protocol versions
+    pub(crate) progress: Vec<&'static str>,
+}
+
+/// The output of `bootc --version --format=json`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct VersionInfo {
+    /// The bootc version
+    pub(crate) version: &'static str,
+    /// The git commit this binary was built from, if known
+    pub(crate) git_commit: Option<&'static str>,
+    /// Enabled build-time features
+    pub(crate) features: Vec<&'static str>,
+    /// Supported storage backends
+    pub(crate) backends: Vec<&'static str>,
+    /// Supported schema versions
+    pub(crate) schemas: SchemaVersions,
+}
+
+impl VersionInfo {
+    pub(crate) fn new() -> Self {
+        let features = [
+            ("install-to-disk", cfg!(feature = "install-to-disk")),
+            ("grub", cfg!(feature = "grub")),
+            ("rhsm", cfg!(feature = "rhsm")),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect();
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("BOOTC_GIT_COMMIT"),
+            features,
+            backends: vec!["ostree"],
+            schemas: SchemaVersions {
+                host: vec![crate::spec::API_VERSION],
+                progress: ProgressVersion::value_variants()
+                    .iter()
+                    .map(|v| v.api_version())
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// Check for `--version` (or `-V`) combined with `--format=json`, which clap
+/// doesn't support as `--version` is handled internally.
+pub(crate) fn json_requested(args: &[OsString]) -> Result<bool> {
+    let mut version = false;
+    let mut format = None;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.to_str() else {
+            return Ok(false);
+        };
+        match arg {
+            "--version" | "-V" => version = true,
+            "--format" => format = args.next().and_then(|v| v.to_str()),
+            o => match o.strip_prefix("--format=") {
+                Some(v) => format = Some(v),
+                // Anything else is handled by clap
+                None => return Ok(false),
+            },
+        }
+    }
+    match (version, format) {
+        (true, Some("json")) => Ok(true),
+        (true, Some(o)) => anyhow::bail!("Unsupported version format: {o}"),
+        _ => Ok(false),
+    }
+}
+
+/// Print the version information as JSON.
+pub(crate) fn print_json() -> Result<()> {
+    use std::io::Write;
+
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &VersionInfo::new())?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(args: &[&str]) -> Result<bool> {
+        let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+        json_requested(&args)
+    }
+
+    #[test]
+    fn test_json_requested() -> Result<()> {
+        assert!(check(&["bootc", "--version", "--format=json"])?);
+        assert!(check(&["bootc", "--format", "json", "-V"])?);
+        assert!(!check(&["bootc", "--version"])?);
+        assert!(!check(&["bootc", "status", "--format=json"])?);
+        assert!(!check(&["bootc", "--format=json"])?);
+        assert!(!check(&["bootc"])?);
+        assert!(check(&["bootc", "--version", "--format=yaml"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_info() -> Result<()> {
+        let info = VersionInfo::new();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.schemas.progress, ["0.1.0", "2.0.0"]);
+        let v = serde_json::to_value(&info)?;
+        assert_eq!(v["schemas"]["host"][0], "org.containers.bootc/v1");
+        assert!(v.get("git-commit").is_some());
+        Ok(())
+    }
+}
@@ -10,12 +10,12 @@ use std::num::NonZeroU32;
 use std::rc::Rc;
 use std::time::Instant;
 
-use crate::container::{COMPONENT_SEPARATOR, CONTENT_ANNOTATION};
+use crate::container::{COMPONENT_SEPARATOR, CONTENT_ANNOTATION, LAYER_PLAN_ANNOTATION};
 use crate::objectsource::{ContentID, ObjectMeta, ObjectMetaMap, ObjectSourceMeta};
 use crate::objgv::*;
 use crate::statistics;
-use anyhow::{anyhow, Result};
-use camino::Utf8PathBuf;
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use containers_image_proxy::oci_spec;
 use gvariant::aligned_bytes::TryAsAligned;
 use gvariant::{Marker, Structure};
@@ -30,6 +30,10 @@ pub(crate) const MAX_CHUNKS: u32 = 64;
 /// Minimum number of layers we can create in a "chunked" flow; otherwise
 /// we will just drop down to one.
 const MIN_CHUNKED_LAYERS: u32 = 4;
+/// The default target size of a layer when splitting content by directory.
+pub const DEFAULT_DIRECTORY_CHUNK_SIZE: u64 = 150 * 1024 * 1024;
+/// How deep we will descend into directories which exceed the target size.
+const MAX_DIRECTORY_DEPTH: usize = 5;
 
 /// A convenient alias for a reference-counted, immutable string.
 pub(crate) type RcStr = Rc<str>;
@@ -47,6 +51,123 @@ pub(crate) struct Chunk {
     pub(crate) content: ChunkMapping,
     pub(crate) size: u64,
     pub(crate) packages: Vec<String>,
+    /// True if this chunk was created from a [`LayerPlan`]
+    pub(crate) planned: bool,
+}
+
+/// The serialized form of a [`LayerPlan`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerPlanFile {
+    layers: Vec<LayerPlanFileEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerPlanFileEntry {
+    name: String,
+    paths: Vec<String>,
+}
+
+/// A layer of a [`LayerPlan`].
+#[derive(Debug)]
+struct PlannedLayer {
+    name: String,
+    paths: regex::Regex,
+}
+
+/// An explicit assignment of content to named layers by path, which overrides
+/// the automatic packing for the matching content, so that it always ends up
+/// in the same layer across builds.
+///
+/// This is parsed from YAML of the form:
+///
+/// ```yaml
+/// layers:
+///   - name: kernel
+///     paths:
+///       - /usr/lib/modules/**
+///   - name: firmware
+///     paths:
+///       - /usr/lib/firmware/**
+/// ```
+///
+/// In the path globs, `*` matches any characters except `/`, `**` matches any
+/// characters, and `?` matches a single character except `/`.  Content matching
+/// multiple layers is assigned to the first one.
+#[derive(Debug)]
+pub struct LayerPlan {
+    layers: Vec<PlannedLayer>,
+}
+
+/// Translate a path glob into an anchored regular expression.
+fn glob_to_regex(glob: &str) -> String {
+    let mut r = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                r.push_str(".*");
+            }
+            '*' => r.push_str("[^/]*"),
+            '?' => r.push_str("[^/]"),
+            c => r.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    r.push('$');
+    r
+}
+
+impl LayerPlan {
+    /// Parse a layer plan from YAML.
+    pub fn parse(s: &str) -> Result<Self> {
+        let file: LayerPlanFile = serde_yaml::from_str(s)?;
+        let mut names = BTreeSet::new();
+        let layers = file
+            .layers
+            .into_iter()
+            .map(|layer| {
+                anyhow::ensure!(!layer.name.is_empty(), "Empty layer name in layer plan");
+                anyhow::ensure!(
+                    names.insert(layer.name.clone()),
+                    "Duplicate layer {} in layer plan",
+                    layer.name
+                );
+                anyhow::ensure!(!layer.paths.is_empty(), "No paths for layer {}", layer.name);
+                let paths = layer
+                    .paths
+                    .iter()
+                    .map(|p| glob_to_regex(p))
+                    .collect::<Vec<_>>()
+                    .join("|");
+                let paths = regex::Regex::new(&paths)
+                    .with_context(|| format!("Parsing paths for layer {}", layer.name))?;
+                Ok(PlannedLayer {
+                    name: layer.name,
+                    paths,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { layers })
+    }
+
+    /// The number of layers in the plan.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns true if the plan has no layers.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Find the index of the layer for any of the given paths.
+    fn layer_for(&self, paths: &[Utf8PathBuf]) -> Option<usize> {
+        self.layers
+            .iter()
+            .position(|l| paths.iter().any(|p| l.paths.is_match(p.as_str())))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -294,6 +415,113 @@ impl Chunking {
         Ok(r)
     }
 
+    /// Move the content matching an explicit layer plan into its own chunks,
+    /// which are then exempt from the automatic packing.  This must be called
+    /// before [`Self::process_mapping`].
+    pub fn process_layer_plan(&mut self, plan: &LayerPlan) -> Result<()> {
+        assert!(!self.processed_mapping);
+        let mut chunks = plan
+            .layers
+            .iter()
+            .map(|l| Chunk {
+                planned: true,
+                ..Chunk::new(&l.name)
+            })
+            .collect::<Vec<_>>();
+        let matched = self
+            .remainder
+            .content
+            .iter()
+            .filter_map(|(checksum, (_, paths))| {
+                plan.layer_for(paths).map(|i| (RcStr::clone(checksum), i))
+            })
+            .collect::<Vec<_>>();
+        for (checksum, i) in matched {
+            self.remainder.move_obj(&mut chunks[i], &checksum);
+        }
+        for chunk in chunks.iter().filter(|c| c.content.is_empty()) {
+            tracing::warn!("No content matched layer {} of the layer plan", chunk.name);
+        }
+        self.chunks.extend(chunks);
+        Ok(())
+    }
+
+    /// For content without any package metadata, split the remainder into
+    /// chunks of roughly `target_size` bytes by directory.
+    ///
+    /// Objects are grouped by their top-level directory; groups larger than
+    /// the target are recursively split by subdirectory.  The groups are then
+    /// packed in path order, where a layer ends either when it reaches the
+    /// target size, or at a boundary defined by the hash of the directory name
+    /// once it is at least a quarter of the target size.  The latter means
+    /// that a change in the size of one directory generally only changes the
+    /// layer containing it, rather than shifting all subsequent layers.
+    pub fn process_by_directory(
+        &mut self,
+        target_size: u64,
+        max_layers: &Option<NonZeroU32>,
+    ) -> Result<()> {
+        assert!(!self.processed_mapping);
+        self.processed_mapping = true;
+        self.max = max_layers
+            .unwrap_or(NonZeroU32::new(MAX_CHUNKS).unwrap())
+            .get();
+        let remaining = self.remaining() as usize;
+        if remaining == 0 {
+            return Ok(());
+        }
+
+        let objects = self
+            .remainder
+            .content
+            .iter()
+            .filter_map(|(checksum, (size, paths))| {
+                paths.iter().min().map(|p| (checksum, *size, p.as_path()))
+            })
+            .collect::<Vec<_>>();
+        let mut groups = Vec::new();
+        group_by_directory(objects, 1, target_size, &mut groups);
+
+        let mut bins: Vec<(Vec<String>, u64, Vec<RcStr>)> = Vec::new();
+        let mut current: Option<(Vec<String>, u64, Vec<RcStr>)> = None;
+        for group in groups {
+            let bin = current.get_or_insert_with(Default::default);
+            bin.1 += group.size;
+            bin.2.extend(group.objects);
+            let boundary = bin.1 >= target_size
+                || (bin.1 >= target_size / 4 && is_directory_boundary(&group.key));
+            bin.0.push(group.key);
+            if boundary {
+                bins.extend(current.take());
+            }
+        }
+        bins.extend(current);
+        // If we have too many, fold the excess into the last one we can create
+        if bins.len() > remaining {
+            let excess = bins.split_off(remaining);
+            let last = bins.last_mut().unwrap();
+            for (keys, size, objects) in excess {
+                last.0.extend(keys);
+                last.1 += size;
+                last.2.extend(objects);
+            }
+        }
+
+        for (keys, _, objects) in bins {
+            let name = match keys.as_slice() {
+                [] => unreachable!(),
+                [k] => format!("/{k}"),
+                [first, .., last] => format!("/{first} through /{last}"),
+            };
+            let mut chunk = Chunk::new(&name);
+            for obj in objects {
+                self.remainder.move_obj(&mut chunk, &obj);
+            }
+            self.chunks.push(chunk);
+        }
+        Ok(())
+    }
+
     fn remaining(&self) -> u32 {
         self.max.saturating_sub(self.chunks.len() as u32)
     }
@@ -447,6 +675,54 @@ impl Chunking {
     }
 }
 
+/// A set of objects sharing a directory.
+#[derive(Debug)]
+struct DirectoryGroup {
+    /// The directory, without the leading `/`
+    key: String,
+    size: u64,
+    objects: Vec<RcStr>,
+}
+
+/// Group objects by the first `depth` directory components of their path, in
+/// sorted order, recursively splitting any group larger than `target_size`.
+fn group_by_directory(
+    objects: Vec<(&RcStr, u64, &Utf8Path)>,
+    depth: usize,
+    target_size: u64,
+    out: &mut Vec<DirectoryGroup>,
+) {
+    let mut by_key: BTreeMap<String, Vec<(&RcStr, u64, &Utf8Path)>> = BTreeMap::new();
+    for obj in objects {
+        let components = obj.2.as_str().trim_start_matches('/').split('/');
+        // Exclude the file name itself
+        let n = components.clone().count().saturating_sub(1).min(depth);
+        let key = components.take(n).collect::<Vec<_>>().join("/");
+        by_key.entry(key).or_default().push(obj);
+    }
+    for (key, objects) in by_key {
+        let size = objects.iter().map(|o| o.1).sum::<u64>();
+        let deeper = objects
+            .iter()
+            .any(|o| o.2.as_str().trim_start_matches('/').split('/').count() > depth + 1);
+        if size > target_size && deeper && depth < MAX_DIRECTORY_DEPTH {
+            group_by_directory(objects, depth + 1, target_size, out);
+        } else {
+            out.push(DirectoryGroup {
+                key,
+                size,
+                objects: objects.into_iter().map(|o| RcStr::clone(o.0)).collect(),
+            });
+        }
+    }
+}
+
+/// Returns true if a layer may end after the given directory; this is
+/// true for one in four directories.
+fn is_directory_boundary(key: &str) -> bool {
+    openssl::sha::sha256(key.as_bytes())[0] % 4 == 0
+}
+
 #[cfg(test)]
 fn components_size(components: &[&ObjectSourceMetaSized]) -> u64 {
     components.iter().map(|k| k.size).sum()
@@ -600,10 +876,17 @@ fn basic_packing_with_prior_build<'a>(
 
     // The first layer is the ostree commit, which will always be different for different builds,
     // so we ignore it.  For the remaining layers, extract the components/packages in each one.
+    // Layers from a layer plan are not subject to packing, so they are skipped too.
     let curr_build: Result<Vec<Vec<String>>> = prior_build
         .layers()
         .iter()
         .skip(1)
+        .filter(|layer| {
+            !layer
+                .annotations()
+                .as_ref()
+                .is_some_and(|annos| annos.contains_key(LAYER_PLAN_ANNOTATION))
+        })
         .map(|layer| -> Result<_> {
             let annotation_layer = layer
                 .annotations()
@@ -1110,6 +1393,183 @@ mod test {
         Ok((contentmeta, regular_meta, exclusive_meta, chunking))
     }
 
+    #[test]
+    fn test_glob_to_regex() {
+        let m = |glob: &str, path: &str| {
+            regex::Regex::new(&glob_to_regex(glob))
+                .unwrap()
+                .is_match(path)
+        };
+        assert!(m("/usr/lib/modules/**", "/usr/lib/modules/6.1/vmlinuz"));
+        assert!(!m(
+            "/usr/lib/modules/**",
+            "/usr/lib/modules-load.d/foo.conf"
+        ));
+        assert!(m("/usr/bin/*", "/usr/bin/bash"));
+        assert!(!m("/usr/bin/*", "/usr/bin/sub/bash"));
+        assert!(m("/usr/lib/lib?.so", "/usr/lib/libc.so"));
+        assert!(!m("/usr/lib/lib?.so", "/usr/lib/libcc.so"));
+        assert!(m("/usr/share/a+b", "/usr/share/a+b"));
+        assert!(!m("/usr/share/a+b", "/usr/share/aab"));
+    }
+
+    #[test]
+    fn test_layer_plan() -> Result<()> {
+        let plan = LayerPlan::parse(indoc::indoc! { "
+            layers:
+              - name: kernel
+                paths:
+                  - /usr/lib/modules/**
+              - name: tools
+                paths:
+                  - /usr/bin/*
+                  - /usr/lib/modules/*/tool
+        " })?;
+        assert_eq!(plan.len(), 2);
+
+        let mut chunking = Chunking::default();
+        for (checksum, size, path) in [
+            ("a", 100, "/usr/lib/modules/6.1/vmlinuz"),
+            ("b", 10, "/usr/lib/modules/6.1/tool"),
+            ("c", 20, "/usr/bin/bash"),
+            ("d", 30, "/usr/share/doc/README"),
+        ] {
+            chunking
+                .remainder
+                .content
+                .insert(RcStr::from(checksum), (size, vec![path.into()]));
+            chunking.remainder.size += size;
+        }
+        chunking.process_layer_plan(&plan)?;
+
+        assert_eq!(chunking.chunks.len(), 2);
+        let kernel = &chunking.chunks[0];
+        assert_eq!(kernel.name, "kernel");
+        assert!(kernel.planned);
+        // The first matching layer wins
+        assert_eq!(
+            kernel.content.keys().map(|k| &**k).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(kernel.size, 110);
+        let tools = &chunking.chunks[1];
+        assert_eq!(
+            tools.content.keys().map(|k| &**k).collect::<Vec<_>>(),
+            ["c"]
+        );
+        assert_eq!(
+            chunking
+                .remainder
+                .content
+                .keys()
+                .map(|k| &**k)
+                .collect::<Vec<_>>(),
+            ["d"]
+        );
+        assert_eq!(chunking.remainder.size, 30);
+
+        for invalid in [
+            "layers: [{name: a, paths: []}]",
+            "layers: [{name: a, paths: [/a]}, {name: a, paths: [/b]}]",
+            "layers: [{name: a, paths: [/a], extra: 1}]",
+        ] {
+            assert!(LayerPlan::parse(invalid).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    fn directory_chunking(objects: &[(&str, u64, &str)]) -> Chunking {
+        let mut chunking = Chunking::default();
+        for &(checksum, size, path) in objects {
+            chunking
+                .remainder
+                .content
+                .insert(RcStr::from(checksum), (size, vec![path.into()]));
+            chunking.remainder.size += size;
+        }
+        chunking
+    }
+
+    #[test]
+    fn test_process_by_directory() -> Result<()> {
+        let objects = [
+            ("a", 10, "/usr/bin/bash"),
+            ("b", 10, "/usr/bin/ls"),
+            ("c", 60, "/usr/lib/modules/6.1/vmlinuz"),
+            ("d", 50, "/usr/lib/modules/6.1/kernel/foo.ko"),
+            ("e", 5, "/usr/lib/os-release"),
+            ("f", 30, "/usr/share/doc/README"),
+            ("g", 1, "/usr/etc/hostname"),
+        ];
+        let mut chunking = directory_chunking(&objects);
+        chunking.process_by_directory(100, &None)?;
+        assert!(chunking.remainder.content.is_empty());
+        assert_eq!(chunking.remainder.size, 0);
+        let total: u64 = chunking.chunks.iter().map(|c| c.size).sum();
+        assert_eq!(total, 166);
+        assert_eq!(
+            chunking
+                .chunks
+                .iter()
+                .map(|c| c.content.len())
+                .sum::<usize>(),
+            objects.len()
+        );
+        // /usr/lib/modules/6.1 exceeds the target on its own, so it is split further
+        // and the layers never exceed the target by more than one directory.
+        for chunk in &chunking.chunks {
+            assert!(chunk.size <= 100 + 60, "{}: {}", chunk.name, chunk.size);
+            assert!(chunk.name.starts_with("/usr"));
+        }
+
+        // The assignment is deterministic
+        let mut again = directory_chunking(&objects);
+        again.process_by_directory(100, &None)?;
+        let names = |c: &Chunking| c.chunks.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&chunking), names(&again));
+
+        // The number of layers is capped
+        let mut capped = directory_chunking(&objects);
+        capped.process_by_directory(1, &NonZeroU32::new(2))?;
+        assert_eq!(capped.chunks.len(), 2);
+        assert!(capped.remainder.content.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_by_directory() {
+        let objects = [
+            ("a", 10, "/usr/bin/bash"),
+            ("b", 60, "/usr/lib/modules/6.1/vmlinuz"),
+            ("c", 50, "/usr/lib/modules/6.2/vmlinuz"),
+            ("d", 5, "/usr/lib/os-release"),
+            ("e", 1, "/toplevel"),
+        ];
+        let chunking = directory_chunking(&objects);
+        let objects = chunking
+            .remainder
+            .content
+            .iter()
+            .map(|(k, v)| (k, v.0, v.1[0].as_path()))
+            .collect();
+        let mut groups = Vec::new();
+        group_by_directory(objects, 1, 100, &mut groups);
+        let groups = groups
+            .iter()
+            .map(|g| (g.key.as_str(), g.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            [
+                ("", 1),
+                ("usr/bin", 10),
+                ("usr/lib", 5),
+                ("usr/lib/modules/6.1", 60),
+                ("usr/lib/modules/6.2", 50),
+            ]
+        );
+    }
+
     #[test]
     fn test_exclusive_chunks() -> Result<()> {
         // Test that exclusive chunks are created first and get their own layers
@@ -21,7 +21,7 @@ use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
-use std::num::NonZeroU32;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::PathBuf;
 use std::process::Command;
 use tokio::sync::mpsc::Receiver;
@@ -76,6 +76,10 @@ pub(crate) struct ExportOpts {
     #[clap(long, hide(true))]
     format_version: u32,
 
+    /// Generate byte-identical output for identical commits.
+    #[clap(long)]
+    reproducible: bool,
+
     /// The ostree ref or commit to export
     rev: String,
 }
@@ -172,9 +176,25 @@ pub(crate) enum ContainerOpts {
         #[clap(long)]
         compression_fast: bool,
 
+        /// The layer compression format: one of gzip, zstd or zstd:chunked.
+        #[clap(long, default_value = "gzip")]
+        compression_format: crate::container::LayerCompression,
+
         /// Path to a JSON-formatted content meta object.
         #[clap(long)]
         contentmeta: Option<Utf8PathBuf>,
+
+        /// Path to a YAML-formatted layer plan, assigning content to named layers by path.
+        #[clap(long)]
+        layer_plan: Option<Utf8PathBuf>,
+
+        /// Generate byte-identical layers for identical commits.
+        #[clap(long)]
+        reproducible: bool,
+
+        /// Without content meta, split the content into layers by directory.
+        #[clap(long, conflicts_with = "contentmeta")]
+        chunk_by_directory: bool,
     },
 
     /// Perform build-time checking and canonicalization.
@@ -254,6 +274,11 @@ pub(crate) enum ContainerImageOpts {
         /// the new manifest.
         #[clap(long)]
         check: Option<Utf8PathBuf>,
+
+        /// Squash consecutive small derived layers so that at most this many
+        /// commits are unioned when merging the image.
+        #[clap(long)]
+        squash_layers: Option<NonZeroUsize>,
     },
 
     /// Output metadata about an already stored container image.
@@ -595,6 +620,7 @@ fn tar_export(opts: &ExportOpts) -> Result<()> {
     let repo = parse_repo(&opts.repo)?;
     #[allow(clippy::needless_update)]
     let subopts = crate::tar::ExportOptions {
+        reproducible: opts.reproducible,
         ..Default::default()
     };
     crate::tar::export_commit(&repo, opts.rev.as_str(), std::io::stdout(), Some(subopts))?;
@@ -770,7 +796,11 @@ async fn container_export(
     container_config: Option<Utf8PathBuf>,
     cmd: Option<Vec<String>>,
     compression_fast: bool,
+    compression_format: crate::container::LayerCompression,
     package_contentmeta: Option<Utf8PathBuf>,
+    layer_plan: Option<Utf8PathBuf>,
+    reproducible: bool,
+    chunk_by_directory: bool,
 ) -> Result<()> {
     let container_config = if let Some(container_config) = container_config {
         serde_json::from_reader(File::open(container_config).map(BufReader::new)?)?
@@ -827,10 +857,23 @@ async fn container_export(
         labels.extend(raw.labels.into_iter().flatten());
     }
 
+    let layer_plan = layer_plan
+        .map(|path| {
+            let contents = std::fs::read_to_string(&path)?;
+            crate::chunking::LayerPlan::parse(&contents)
+                .with_context(|| format!("Parsing layer plan {path}"))
+        })
+        .transpose()?;
+
     // Use enough layers so that each package ends in its own layer
     // while respecting the layer ordering.
     let max_layers = if let Some(contentmeta_data) = &contentmeta_data {
-        NonZeroU32::new((contentmeta_data.sizes.len() + 1).try_into().unwrap())
+        let planned = layer_plan.as_ref().map(|p| p.len()).unwrap_or_default();
+        NonZeroU32::new(
+            (contentmeta_data.sizes.len() + planned + 1)
+                .try_into()
+                .unwrap(),
+        )
     } else {
         None
     };
@@ -846,9 +889,14 @@ async fn container_export(
         container_config,
         authfile,
         skip_compression: compression_fast, // TODO rename this in the struct at the next semver break
+        layer_compression: compression_format,
         package_contentmeta: contentmeta_data.as_ref(),
         max_layers,
         created,
+        layer_plan: layer_plan.as_ref(),
+        reproducible,
+        directory_chunk_size: chunk_by_directory
+            .then_some(crate::chunking::DEFAULT_DIRECTORY_CHUNK_SIZE),
         ..Default::default()
     };
     let pushed = crate::container::encapsulate(repo, rev, &config, Some(opts), imgref).await?;
@@ -871,8 +919,12 @@ async fn container_store(
     proxyopts: ContainerProxyOpts,
     quiet: bool,
     check: Option<Utf8PathBuf>,
+    squash_layers: Option<NonZeroUsize>,
 ) -> Result<()> {
     let mut imp = ImageImporter::new(repo, imgref, proxyopts.into()).await?;
+    if let Some(max) = squash_layers {
+        imp.set_squash_layers(max);
+    }
     let prep = match imp.prepare().await? {
         PrepareResult::AlreadyPresent(c) => {
             write_digest_file(ostree_digestfile, &c.merge_commit)?;
@@ -1072,7 +1124,11 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 config,
                 cmd,
                 compression_fast,
+                compression_format,
                 contentmeta,
+                layer_plan,
+                reproducible,
+                chunk_by_directory,
             } => {
                 let labels: Result<BTreeMap<_, _>> = labels
                     .into_iter()
@@ -1095,7 +1151,11 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     config,
                     cmd,
                     compression_fast,
+                    compression_format,
                     contentmeta,
+                    layer_plan,
+                    reproducible,
+                    chunk_by_directory,
                 )
                 .await
             }
@@ -1114,10 +1174,19 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     proxyopts,
                     quiet,
                     check,
+                    squash_layers,
                 } => {
                     let repo = parse_repo(&repo)?;
-                    container_store(&repo, &imgref, ostree_digestfile, proxyopts, quiet, check)
-                        .await
+                    container_store(
+                        &repo,
+                        &imgref,
+                        ostree_digestfile,
+                        proxyopts,
+                        quiet,
+                        check,
+                        squash_layers,
+                    )
+                    .await
                 }
                 ContainerImageOpts::Reexport {
                     repo,
@@ -1,8 +1,10 @@
 //! APIs for creating container images from OSTree commits
 
 use super::{ImageReference, SignatureSource, OSTREE_COMMIT_LABEL};
-use super::{OstreeImageReference, Transport, COMPONENT_SEPARATOR, CONTENT_ANNOTATION};
-use crate::chunking::{Chunk, Chunking, ObjectMetaSized};
+use super::{
+    OstreeImageReference, Transport, COMPONENT_SEPARATOR, CONTENT_ANNOTATION, LAYER_PLAN_ANNOTATION,
+};
+use crate::chunking::{Chunk, Chunking, LayerPlan, ObjectMetaSized};
 use crate::container::skopeo;
 use crate::tar as ostree_tar;
 use anyhow::{anyhow, Context, Result};
@@ -15,10 +17,11 @@ use flate2::Compression;
 use fn_error_context::context;
 use gio::glib;
 use oci_spec::image as oci_image;
-use ocidir::{Layer, OciDir};
+use ocidir::{GzipLayerWriter, Layer, OciDir, ZstdLayerWriter};
 use ostree::gio;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
 use std::num::NonZeroU32;
 use tracing::instrument;
 
@@ -87,22 +90,24 @@ fn export_chunks(
     ociw: &mut OciDir,
     chunks: Vec<Chunk>,
     opts: &ExportOpts,
-) -> Result<Vec<(Layer, String, Vec<String>)>> {
+) -> Result<Vec<(Layer, Chunk)>> {
     chunks
         .into_iter()
         .enumerate()
-        .map(|(i, chunk)| -> Result<_> {
-            let mut w = ociw.create_layer(Some(opts.compression()))?;
+        .map(|(i, mut chunk)| -> Result<_> {
+            let mut w = opts.create_layer(ociw)?;
+            let content = std::mem::take(&mut chunk.content);
             ostree_tar::export_chunk(
                 repo,
                 commit,
-                chunk.content,
+                content,
                 &mut w,
                 opts.tar_create_parent_dirs,
+                opts.tar_options(),
             )
             .with_context(|| format!("Exporting chunk {i}"))?;
             let w = w.into_inner()?;
-            Ok((w.complete()?, chunk.name, chunk.packages))
+            Ok((w.complete()?, chunk))
         })
         .collect()
 }
@@ -122,16 +127,15 @@ pub(crate) fn export_chunked(
     description: &str,
 ) -> Result<()> {
     let layers = export_chunks(repo, commit, ociw, chunking.take_chunks(), opts)?;
-    let compression = Some(opts.compression());
-
     // In V1, the ostree layer comes first
-    let mut w = ociw.create_layer(compression)?;
+    let mut w = opts.create_layer(ociw)?;
     ostree_tar::export_final_chunk(
         repo,
         commit,
         chunking.remainder,
         &mut w,
         opts.tar_create_parent_dirs,
+        opts.tar_options(),
     )?;
     let w = w.into_inner()?;
     let ostree_layer = w.complete()?;
@@ -162,16 +166,20 @@ pub(crate) fn export_chunked(
     // Add the component/content layers
     let mut buf = [0; 8];
     let sep = COMPONENT_SEPARATOR.encode_utf8(&mut buf);
-    for (layer, name, mut packages) in layers {
+    for (layer, mut chunk) in layers {
         let mut annotation_component_layer = HashMap::new();
-        packages.sort();
-        annotation_component_layer.insert(CONTENT_ANNOTATION.to_string(), packages.join(sep));
+        chunk.packages.sort();
+        annotation_component_layer.insert(CONTENT_ANNOTATION.to_string(), chunk.packages.join(sep));
+        if chunk.planned {
+            annotation_component_layer
+                .insert(LAYER_PLAN_ANNOTATION.to_string(), chunk.name.clone());
+        }
         ociw.push_layer_full(
             manifest,
             imgcfg,
             layer,
             Some(annotation_component_layer),
-            name.as_str(),
+            chunk.name.as_str(),
             created,
         );
     }
@@ -238,24 +246,22 @@ fn build_oci(
 
     let mut manifest = writer.new_empty_manifest()?.build().unwrap();
 
-    let chunking = opts
-        .package_contentmeta
-        .as_ref()
-        .map(|meta| {
-            crate::chunking::Chunking::from_mapping(
-                repo,
-                commit,
-                meta,
-                &opts.max_layers,
-                opts.prior_build,
-                opts.specific_contentmeta,
-            )
-        })
-        .transpose()?;
-    // If no chunking was provided, create a logical single chunk.
-    let chunking = chunking
-        .map(Ok)
-        .unwrap_or_else(|| crate::chunking::Chunking::new(repo, commit))?;
+    // Start with a logical single chunk, then split out the content from
+    // the layer plan and the content metadata, if provided.
+    let mut chunking = crate::chunking::Chunking::new(repo, commit)?;
+    if let Some(plan) = opts.layer_plan {
+        chunking.process_layer_plan(plan)?;
+    }
+    if let Some(meta) = opts.package_contentmeta {
+        chunking.process_mapping(
+            meta,
+            &opts.max_layers,
+            opts.prior_build,
+            opts.specific_contentmeta,
+        )?;
+    } else if let Some(target_size) = opts.directory_chunk_size {
+        chunking.process_by_directory(target_size, &opts.max_layers)?;
+    }
 
     if let Some(version) = commit_meta.lookup::<String>("version")? {
         if opts.legacy_version_label {
@@ -348,7 +354,11 @@ async fn build_impl(
     if dest.transport == Transport::ContainerStorage {
         opts.skip_compression = true;
     }
-    let digest = if dest.transport == Transport::OciDir {
+    // zstd:chunked requires a table of contents which is generated by skopeo, so
+    // in that case we always go through a copy.
+    let digest = if dest.transport == Transport::OciDir
+        && opts.layer_compression != LayerCompression::ZstdChunked
+    {
         let (path, tag) = parse_oci_path_and_tag(dest.name.as_str());
         tracing::debug!("using OCI path={path} tag={tag:?}");
         if !Utf8Path::new(path).exists() {
@@ -368,6 +378,8 @@ async fn build_impl(
 
         // Minor TODO: refactor to avoid clone
         let authfile = opts.authfile.clone();
+        let dest_compress_format = (opts.layer_compression == LayerCompression::ZstdChunked)
+            .then_some(LayerCompression::ZstdChunked.to_string());
         build_oci(repo, ostree_ref, &mut ocidir, None, config, opts)?;
         drop(ocidir);
 
@@ -382,6 +394,7 @@ async fn build_impl(
             dest,
             authfile.as_deref(),
             Some((std::sync::Arc::new(tempdir.try_clone()?.into()), target_fd)),
+            dest_compress_format.as_deref(),
             false,
         )
         .await?;
@@ -403,12 +416,85 @@ async fn build_impl(
     }
 }
 
+/// The compression format for the generated layers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LayerCompression {
+    /// Compress with gzip; this is the most widely supported format.
+    #[default]
+    Gzip,
+    /// Compress with zstd, which is significantly faster.
+    Zstd,
+    /// Compress with zstd, adding metadata in skippable frames
+    /// which allows clients to fetch individual files.
+    ZstdChunked,
+}
+
+impl std::fmt::Display for LayerCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            LayerCompression::Gzip => "gzip",
+            LayerCompression::Zstd => "zstd",
+            LayerCompression::ZstdChunked => "zstd:chunked",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::str::FromStr for LayerCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let r = match s {
+            "gzip" => LayerCompression::Gzip,
+            "zstd" => LayerCompression::Zstd,
+            "zstd:chunked" => LayerCompression::ZstdChunked,
+            o => anyhow::bail!("Unknown layer compression: {o}"),
+        };
+        Ok(r)
+    }
+}
+
+/// A layer being written in one of the supported compression formats.
+enum LayerWriter<'a> {
+    Gzip(GzipLayerWriter<'a>),
+    Zstd(ZstdLayerWriter<'a>),
+}
+
+impl Write for LayerWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            LayerWriter::Gzip(w) => w.write(buf),
+            LayerWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LayerWriter::Gzip(w) => w.flush(),
+            LayerWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl LayerWriter<'_> {
+    fn complete(self) -> Result<Layer> {
+        let r = match self {
+            LayerWriter::Gzip(w) => w.complete()?,
+            LayerWriter::Zstd(w) => w.complete()?,
+        };
+        Ok(r)
+    }
+}
+
 /// Options controlling commit export into OCI
 #[derive(Clone, Debug, Default)]
 #[non_exhaustive]
 pub struct ExportOpts<'m, 'o> {
-    /// If true, do not perform gzip compression of the tar layers.
+    /// If true, compress the tar layers at the fastest level.
     pub skip_compression: bool,
+    /// The compression format for the tar layers.
+    pub layer_compression: LayerCompression,
     /// A set of commit metadata keys to copy as image labels.
     pub copy_meta_keys: Vec<String>,
     /// A set of optionally-present commit metadata keys to copy as image labels.
@@ -431,10 +517,19 @@ pub struct ExportOpts<'m, 'o> {
     pub package_contentmeta: Option<&'o ObjectMetaSized>,
     /// Metadata for exclusive components that should have their own layers.
     pub specific_contentmeta: Option<&'o ObjectMetaSized>,
+    /// An explicit assignment of content to layers by path, which takes
+    /// precedence over the above.
+    pub layer_plan: Option<&'o LayerPlan>,
+    /// In the absence of package metadata, split the content into layers of
+    /// roughly this size by directory.
+    pub directory_chunk_size: Option<u64>,
     /// Sets the created tag in the image manifest.
     pub created: Option<String>,
     /// Whether to explicitly create all parent directories in the tar layers.
     pub tar_create_parent_dirs: bool,
+    /// Generate byte-identical tar layers for identical commits; see
+    /// [`crate::tar::ExportOptions::reproducible`].
+    pub reproducible: bool,
 }
 
 impl ExportOpts<'_, '_> {
@@ -446,6 +541,34 @@ impl ExportOpts<'_, '_> {
             Compression::default()
         }
     }
+
+    /// Return the options for the tar stream of each layer.
+    fn tar_options(&self) -> ostree_tar::ExportOptions {
+        ostree_tar::ExportOptions {
+            reproducible: self.reproducible,
+        }
+    }
+
+    /// Return the zstd compression level to use, as configured by the export options.
+    fn zstd_compression(&self) -> Option<i32> {
+        self.skip_compression.then_some(1)
+    }
+
+    /// Create a new tar layer using the configured compression.
+    ///
+    /// For zstd:chunked, this writes plain zstd; the layers are recompressed
+    /// by skopeo when copying to the destination.
+    fn create_layer<'a>(&self, ociw: &'a OciDir) -> Result<tar::Builder<LayerWriter<'a>>> {
+        let w = match self.layer_compression {
+            LayerCompression::Gzip => {
+                LayerWriter::Gzip(ociw.create_gzip_layer(Some(self.compression()))?)
+            }
+            LayerCompression::Zstd | LayerCompression::ZstdChunked => {
+                LayerWriter::Zstd(ociw.create_layer_zstd(self.zstd_compression())?)
+            }
+        };
+        Ok(tar::Builder::new(w))
+    }
 }
 
 /// Given an OSTree repository and ref, generate a container image.
@@ -477,4 +600,17 @@ mod tests {
         );
         assert_eq!(parse_oci_path_and_tag(untagged), ("/foo/bar", Some("baz")));
     }
+
+    #[test]
+    fn test_layer_compression() {
+        for v in [
+            LayerCompression::Gzip,
+            LayerCompression::Zstd,
+            LayerCompression::ZstdChunked,
+        ] {
+            assert_eq!(v.to_string().parse::<LayerCompression>().unwrap(), v);
+        }
+        assert_eq!(LayerCompression::default(), LayerCompression::Gzip);
+        assert!("zstd:foo".parse::<LayerCompression>().is_err());
+    }
 }
@@ -43,7 +43,10 @@ pub const OSTREE_COMMIT_LABEL: &str = "ostree.commit";
 
 /// The name of an annotation attached to a layer which names the packages/components
 /// which are part of it.
-pub(crate) const CONTENT_ANNOTATION: &str = "ostree.components";
+pub const CONTENT_ANNOTATION: &str = "ostree.components";
+/// The name of an annotation attached to a layer which was assigned its content
+/// by a [`crate::chunking::LayerPlan`]; the value is the name of the layer.
+pub const LAYER_PLAN_ANNOTATION: &str = "ostree.layer-plan";
 /// The character we use to separate values in [`CONTENT_ANNOTATION`].
 pub(crate) const COMPONENT_SEPARATOR: char = ',';
 
@@ -422,10 +425,12 @@ pub fn merge_default_container_proxy_opts_with_isolation(
     config: &mut containers_image_proxy::ImageProxyConfig,
     isolation_user: Option<&str>,
 ) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    // The certificate directory itself is set per image by `tls::apply_registry_tls`.
+    let tls = tls::get_registry_tls(root)?;
     let auth_specified =
         config.auth_anonymous || config.authfile.is_some() || config.auth_data.is_some();
     if !auth_specified {
-        let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
         config.auth_data = crate::globals::get_global_authfile(root)?.map(|a| a.1);
         // If there's no auth data, then force on anonymous pulls to ensure
         // that the container stack doesn't try to find it in the standard
@@ -441,6 +446,9 @@ pub fn merge_default_container_proxy_opts_with_isolation(
         .is_none()
         .then_some(isolation_user.as_ref())
         .flatten();
+    // The client key is (and should be) only readable by root; we can't
+    // drop privileges if we need it.
+    let isolation_user = isolation_user.filter(|_| tls.client_key.is_none());
     if let Some(user) = isolation_user {
         // Read the default authfile if it exists and pass it via file descriptor
         // which will ensure it's readable when we drop privileges.
@@ -453,6 +461,36 @@ pub fn merge_default_container_proxy_opts_with_isolation(
     Ok(())
 }
 
+/// Select `platform` from multi-platform images (manifest lists) instead of the
+/// host platform; this must be applied after the default configuration.
+pub(crate) fn apply_platform(
+    config: &mut containers_image_proxy::ImageProxyConfig,
+    platform: &oci_spec::image::Platform,
+) {
+    let mut cmd = config
+        .skopeo_cmd
+        .take()
+        .unwrap_or_else(|| std::process::Command::new("skopeo"));
+    if !cmd.get_args().any(|a| a == "--override-arch") {
+        cmd.args(skopeo_platform_args(platform));
+    }
+    config.skopeo_cmd = Some(cmd);
+}
+
+/// The skopeo global options to select `platform` from manifest lists.
+fn skopeo_platform_args(platform: &oci_spec::image::Platform) -> Vec<String> {
+    let mut r = vec![
+        "--override-os".to_owned(),
+        platform.os().to_string(),
+        "--override-arch".to_owned(),
+        platform.architecture().to_string(),
+    ];
+    if let Some(variant) = platform.variant() {
+        r.extend(["--override-variant".to_owned(), variant.clone()]);
+    }
+    r
+}
+
 /// Convenience helper to return the labels, if present.
 pub(crate) fn labels_of(
     config: &oci_spec::image::ImageConfiguration,
@@ -479,6 +517,8 @@ mod unencapsulate;
 pub use unencapsulate::*;
 mod skopeo;
 pub mod store;
+mod tls;
+pub use tls::*;
 mod update_detachedmeta;
 pub use update_detachedmeta::*;
 
@@ -643,4 +683,26 @@ mod tests {
         super::merge_default_container_proxy_opts_with_isolation(&mut c, Some("foo")).unwrap();
         assert_eq!(c.skopeo_cmd.unwrap().get_program(), "skopeo");
     }
+
+    #[test]
+    fn test_skopeo_platform_args() {
+        use oci_spec::image::{Arch, Os, PlatformBuilder};
+        let p = PlatformBuilder::default()
+            .os(Os::Linux)
+            .architecture(Arch::ARM64)
+            .variant("v8".to_owned())
+            .build()
+            .unwrap();
+        assert_eq!(
+            super::skopeo_platform_args(&p),
+            [
+                "--override-os",
+                "linux",
+                "--override-arch",
+                "arm64",
+                "--override-variant",
+                "v8"
+            ]
+        );
+    }
 }
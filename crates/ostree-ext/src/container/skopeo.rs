@@ -69,6 +69,7 @@ pub(crate) async fn copy(
     dest: &ImageReference,
     authfile: Option<&Path>,
     add_fd: Option<(std::sync::Arc<OwnedFd>, i32)>,
+    dest_compress_format: Option<&str>,
     progress: bool,
 ) -> Result<oci_image::Digest> {
     let digestfile = tempfile::NamedTempFile::new()?;
@@ -86,6 +87,11 @@ pub(crate) async fn copy(
         cmd.arg("--authfile");
         cmd.arg(authfile);
     }
+    if let Some(format) = dest_compress_format {
+        // Recompress layers even if they already use e.g. plain zstd
+        cmd.arg(format!("--dest-compress-format={format}"));
+        cmd.arg("--force-compress-format");
+    }
     cmd.args(&[src.to_string(), dest.to_string()]);
     let mut cmd = tokio::process::Command::from(cmd);
     cmd.kill_on_drop(true);
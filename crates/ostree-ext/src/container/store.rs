@@ -33,7 +33,8 @@ use ostree::{gio, glib};
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Write as _;
 use std::iter::FromIterator;
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::Arc;
 use tokio::sync::mpsc::{Receiver, Sender};
 
 /// Configuration for the proxy.
@@ -46,6 +47,8 @@ pub use containers_image_proxy::ImageProxyConfig;
 const LAYER_PREFIX: &str = "ostree/container/blob";
 /// The ostree ref prefix for image references.
 const IMAGE_PREFIX: &str = "ostree/container/image";
+/// The ostree ref prefix for commits which squash several derived layers.
+const SQUASHED_PREFIX: &str = "ostree/container/squashed";
 /// The ostree ref prefix for "base" image references that are used by derived images.
 /// If you maintain tooling which is locally building derived commits, write a ref
 /// with this prefix that is owned by your code.  It's a best practice to prefix the
@@ -58,6 +61,10 @@ pub(crate) const META_MANIFEST_DIGEST: &str = "ostree.manifest-digest";
 const META_MANIFEST: &str = "ostree.manifest";
 /// The key injected into the merge commit with the image configuration serialized as JSON.
 const META_CONFIG: &str = "ostree.container.image-config";
+/// The key injected into a squashed commit with the digests of the layers it contains.
+const META_SQUASHED_LAYERS: &str = "ostree.container.squashed-layers";
+/// Derived layers larger than this are never squashed.
+const SQUASH_MAX_LAYER_SIZE: u64 = 16 * 1024 * 1024;
 /// The type used to store content filtering information.
 pub type MetaFilteredData = HashMap<String, HashMap<String, u32>>;
 
@@ -65,6 +72,8 @@ pub type MetaFilteredData = HashMap<String, HashMap<String, u32>>;
 const OSTREE_BASE_DEPLOYMENT_REFS: &[&str] = &["ostree/0", "ostree/1"];
 /// A layering violation we'll carry for a bit to band-aid over https://github.com/coreos/rpm-ostree/issues/4185
 const RPMOSTREE_BASE_REFS: &[&str] = &["rpmostree/base"];
+/// When prefetching layers, keep at least this much space free in the repository.
+const PREFETCH_RESERVED_SPACE: u64 = 1024 * 1024 * 1024;
 
 /// Convert e.g. sha256:12345... into `/ostree/container/blob/sha256_2B12345...`.
 fn ref_for_blob_digest(d: &str) -> Result<String> {
@@ -77,7 +86,8 @@ fn ref_for_layer(l: &oci_image::Descriptor) -> Result<String> {
 }
 
 /// Convert e.g. sha256:12345... into `/ostree/container/blob/sha256_2B12345...`.
-fn ref_for_image(l: &ImageReference) -> Result<String> {
+/// Compute the ostree ref used to store the given image reference.
+pub fn ref_for_image(l: &ImageReference) -> Result<String> {
     refescape::prefix_escape_for_ref(IMAGE_PREFIX, &l.to_string())
 }
 
@@ -185,13 +195,30 @@ pub struct ImageImporter {
     require_bootable: bool,
     /// Do not attempt to contact the network
     offline: bool,
+    /// Do not write to the repository when preparing
+    readonly: bool,
+    /// The number of layers downloaded concurrently; any beyond the one being
+    /// imported are spooled to temporary files
+    fetch_jobs: NonZeroUsize,
+    /// If set, limits the total bandwidth used to download layers
+    bandwidth_limiter: Option<Arc<super::unencapsulate::BandwidthLimiter>>,
     /// If true, we have ostree v2024.3 or newer.
     ostree_v2024_3: bool,
+    /// If set, squash small derived layers into at most this many commits
+    squash_layers: Option<NonZeroUsize>,
 
     layer_progress: Option<Sender<ImportProgress>>,
     layer_byte_progress: Option<tokio::sync::watch::Sender<Option<LayerProgress>>>,
 }
 
+/// A layer downloaded ahead of time; see [`ImageImporter::set_fetch_jobs`].
+#[derive(Debug)]
+struct SpooledLayer {
+    digest: Digest,
+    file: tokio::fs::File,
+    media_type: oci_image::MediaType,
+}
+
 /// Result of invoking [`ImageImporter::prepare`].
 #[derive(Debug)]
 pub enum PrepareResult {
@@ -325,6 +352,14 @@ pub(crate) fn query_layer(
     })
 }
 
+/// Return the ostree commit which caches the given layer, if present.
+pub fn query_layer_commit(
+    repo: &ostree::Repo,
+    layer: &oci_image::Descriptor,
+) -> Result<Option<String>> {
+    Ok(query_layer(repo, layer.clone())?.commit)
+}
+
 #[context("Reading manifest data from commit")]
 fn manifest_data_from_commitmeta(
     commit_meta: &glib::VariantDict,
@@ -493,12 +528,24 @@ impl ImageImporter {
     const CACHED_KEY_CONFIG: &'static str = "ostree-ext.cached.config";
 
     /// Create a new importer.
-    #[context("Creating importer")]
     pub async fn new(
+        repo: &ostree::Repo,
+        imgref: &OstreeImageReference,
+        config: ImageProxyConfig,
+    ) -> Result<Self> {
+        Self::new_with_platform(repo, imgref, config, None).await
+    }
+
+    /// Create a new importer which selects `platform` (if set) from multi-platform
+    /// images (manifest lists), instead of the host platform.
+    #[context("Creating importer")]
+    pub async fn new_with_platform(
         repo: &ostree::Repo,
         imgref: &OstreeImageReference,
         mut config: ImageProxyConfig,
+        platform: Option<&oci_image::Platform>,
     ) -> Result<Self> {
+        super::tls::apply_registry_tls(&mut config, &imgref.imgref)?;
         if imgref.imgref.transport == Transport::ContainerStorage {
             // Fetching from containers-storage, may require privileges to read files
             merge_default_container_proxy_opts_with_isolation(&mut config, None)?;
@@ -506,6 +553,9 @@ impl ImageImporter {
             // Apply our defaults to the proxy config
             merge_default_container_proxy_opts(&mut config)?;
         }
+        if let Some(platform) = platform {
+            super::apply_platform(&mut config, platform);
+        }
         let proxy = ImageProxy::new_with_config(config).await?;
 
         system_repo_journal_print(
@@ -524,6 +574,10 @@ impl ImageImporter {
             disable_gc: false,
             require_bootable: false,
             offline: false,
+            readonly: false,
+            fetch_jobs: NonZeroUsize::MIN,
+            bandwidth_limiter: None,
+            squash_layers: None,
             imgref: imgref.clone(),
             layer_progress: None,
             layer_byte_progress: None,
@@ -547,6 +601,51 @@ impl ImageImporter {
         self.offline = true;
     }
 
+    /// Do not write to the repository in [`Self::prepare`]; in particular, the
+    /// pending manifest and configuration are not cached. This allows checking
+    /// for updates with read-only access to the repository.
+    pub fn set_readonly(&mut self) {
+        self.readonly = true;
+    }
+
+    /// Download the next layer to a temporary file while the current one is
+    /// being imported, so that unpacking overlaps with downloading. This is
+    /// skipped for a layer if there is not enough free space in the repository.
+    pub fn set_prefetch_layers(&mut self) {
+        self.fetch_jobs = self.fetch_jobs.max(NonZeroUsize::new(2).unwrap());
+    }
+
+    /// Download up to `jobs` layers concurrently; as with [`Self::set_prefetch_layers`],
+    /// layers after the one being imported are downloaded to temporary files if there
+    /// is enough space. The default is 1.
+    pub fn set_fetch_jobs(&mut self, jobs: NonZeroUsize) {
+        self.fetch_jobs = jobs;
+    }
+
+    /// Limit the total bandwidth used to download layers (including those being
+    /// prefetched) to `bytes_per_sec`.
+    pub fn set_bandwidth_limit(&mut self, bytes_per_sec: NonZeroU64) {
+        self.bandwidth_limiter = Some(Arc::new(super::unencapsulate::BandwidthLimiter::new(
+            bytes_per_sec,
+        )));
+    }
+
+    /// The number of layers to download ahead of the one being imported.
+    fn prefetch_depth(&self) -> usize {
+        self.fetch_jobs.get() - 1
+    }
+
+    /// Squash consecutive small derived layers into shared commits, so that at most
+    /// (if possible) `max` commits are unioned to create the merge commit.
+    ///
+    /// Each layer is still stored in its own commit, so the mapping from the manifest
+    /// to the stored layers is unchanged. Layers larger than 16 MiB or containing
+    /// whiteouts are not squashed. The squashed commits are cached and reused when
+    /// importing images which share the same layers.
+    pub fn set_squash_layers(&mut self, max: NonZeroUsize) {
+        self.squash_layers = Some(max);
+    }
+
     /// Require that the image has the bootable metadata field
     pub fn require_bootable(&mut self) {
         self.require_bootable = true;
@@ -756,7 +855,7 @@ impl ImageImporter {
 
         // If there is a currently fetched image, cache the new pending manifest+config
         // as detached commit metadata, so that future fetches can query it offline.
-        if let Some(previous_state) = previous_state.as_ref() {
+        if let Some(previous_state) = previous_state.as_ref().filter(|_| !self.readonly) {
             self.cache_pending(
                 previous_state.merge_commit.as_str(),
                 &manifest_digest,
@@ -777,6 +876,147 @@ impl ImageImporter {
         Ok(PrepareResult::Ready(imp))
     }
 
+    /// Return the directory used to hold prefetched layers, if prefetching is enabled.
+    fn prefetch_dir(&self) -> Result<Option<Dir>> {
+        if self.prefetch_depth() == 0 {
+            return Ok(None);
+        }
+        let repodir = Dir::reopen_dir(&self.repo.dfd_borrow())?;
+        Ok(Some(repodir.open_dir("tmp")?))
+    }
+
+    /// Download a layer into an anonymous temporary file in `dir`, if there is space.
+    #[context("Prefetching layer {}", layer.digest())]
+    async fn spool_layer(
+        &self,
+        dir: &Dir,
+        img: &OpenedImage,
+        manifest: &ImageManifest,
+        layer: &Descriptor,
+        layer_info: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
+    ) -> Result<Option<SpooledLayer>> {
+        use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+        let st = rustix::fs::fstatvfs(dir)?;
+        let avail = st.f_bavail.saturating_mul(st.f_frsize);
+        if avail < layer.size().saturating_add(PREFETCH_RESERVED_SPACE) {
+            tracing::debug!("Not prefetching {}: insufficient space", layer.digest());
+            return Ok(None);
+        }
+        let (mut blob, driver, media_type) = fetch_layer(
+            &self.proxy,
+            img,
+            manifest,
+            layer,
+            None,
+            layer_info,
+            self.imgref.imgref.transport,
+            self.bandwidth_limiter.as_ref(),
+        )
+        .await?;
+        let f = cap_std_ext::cap_tempfile::TempFile::new_anonymous(dir)?;
+        let mut f = tokio::fs::File::from_std(f.into_std());
+        let copy = async {
+            tokio::io::copy_buf(&mut blob, &mut f).await?;
+            f.flush().await?;
+            anyhow::Ok(())
+        };
+        super::unencapsulate::join_fetch(copy, driver).await?;
+        f.seek(std::io::SeekFrom::Start(0)).await?;
+        Ok(Some(SpooledLayer {
+            digest: layer.digest().clone(),
+            file: f,
+            media_type,
+        }))
+    }
+
+    /// Run `import` (importing a layer) while concurrently prefetching the next
+    /// layers from `to_fetch`, such that up to [`Self::prefetch_depth`] layers are
+    /// `spooled`. Failure to prefetch is not fatal; the layer will be fetched normally.
+    #[allow(clippy::too_many_arguments)]
+    async fn import_with_prefetch<'a, T>(
+        &self,
+        prefetch_dir: Option<&Dir>,
+        img: &OpenedImage,
+        manifest: &ImageManifest,
+        to_fetch: &mut impl Iterator<Item = &'a Descriptor>,
+        spooled: &mut Vec<SpooledLayer>,
+        layer_info: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
+        import: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let next = match prefetch_dir {
+            Some(_) => to_fetch
+                .take(self.prefetch_depth().saturating_sub(spooled.len()))
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        let spool = next.into_iter().map(|next| async move {
+            let dir = prefetch_dir?;
+            self.spool_layer(dir, img, manifest, next, layer_info)
+                .await
+                .inspect_err(|e| tracing::debug!("{e:#}"))
+                .ok()
+                .flatten()
+        });
+        let (r, new) = tokio::join!(import, futures_util::future::join_all(spool));
+        spooled.extend(new.into_iter().flatten());
+        r
+    }
+
+    /// Fetch a layer, using the prefetched copy if there is one.
+    async fn fetch_layer_or_spooled<'a>(
+        &'a self,
+        spooled: &mut Vec<SpooledLayer>,
+        img: &OpenedImage,
+        manifest: &ImageManifest,
+        layer: &'a Descriptor,
+        layer_info: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
+    ) -> Result<(
+        Box<dyn tokio::io::AsyncBufRead + Send + Unpin>,
+        impl std::future::Future<Output = Result<()>> + 'a,
+        oci_image::MediaType,
+    )> {
+        use futures_util::future::Either;
+
+        let spooled = spooled
+            .iter()
+            .position(|s| &s.digest == layer.digest())
+            .map(|i| spooled.remove(i));
+        if let Some(spooled) = spooled {
+            tracing::debug!("Using prefetched layer {}", layer.digest());
+            if let Some(p) = self.layer_byte_progress.as_ref() {
+                let layer_index = manifest
+                    .layers()
+                    .iter()
+                    .position(|x| x == layer)
+                    .unwrap_or_default();
+                p.send_replace(Some(LayerProgress {
+                    layer_index,
+                    fetched: layer.size(),
+                    total: layer.size(),
+                }));
+            }
+            let blob = Box::new(tokio::io::BufReader::new(spooled.file));
+            return Ok((
+                blob,
+                Either::Right(futures_util::future::ok(())),
+                spooled.media_type,
+            ));
+        }
+        let (blob, driver, media_type) = fetch_layer(
+            &self.proxy,
+            img,
+            manifest,
+            layer,
+            self.layer_byte_progress.as_ref(),
+            layer_info,
+            self.imgref.imgref.transport,
+            self.bandwidth_limiter.as_ref(),
+        )
+        .await?;
+        Ok((blob, Either::Left(driver), media_type))
+    }
+
     /// Extract the base ostree commit.
     #[context("Unencapsulating base")]
     pub(crate) async fn unencapsulate_base(
@@ -806,6 +1046,17 @@ impl ImageImporter {
             return Ok(());
         };
         let des_layers = self.proxy.get_layer_info(&import.proxy_img).await?;
+        let prefetch_dir = self.prefetch_dir()?;
+        // The layers we need to fetch, in order; used to find the next one to prefetch.
+        let to_fetch = import
+            .ostree_layers
+            .iter()
+            .chain(std::iter::once(&*commit_layer))
+            .filter(|l| l.commit.is_none())
+            .map(|l| l.layer.clone())
+            .collect::<Vec<_>>();
+        let mut to_fetch = to_fetch.iter().skip(1);
+        let mut spooled = Vec::new();
         for layer in import.ostree_layers.iter_mut() {
             if layer.commit.is_some() {
                 continue;
@@ -814,16 +1065,15 @@ impl ImageImporter {
                 p.send(ImportProgress::OstreeChunkStarted(layer.layer.clone()))
                     .await?;
             }
-            let (blob, driver, media_type) = fetch_layer(
-                &self.proxy,
-                &import.proxy_img,
-                &import.manifest,
-                &layer.layer,
-                self.layer_byte_progress.as_ref(),
-                des_layers.as_ref(),
-                self.imgref.imgref.transport,
-            )
-            .await?;
+            let (blob, driver, media_type) = self
+                .fetch_layer_or_spooled(
+                    &mut spooled,
+                    &import.proxy_img,
+                    &import.manifest,
+                    &layer.layer,
+                    des_layers.as_ref(),
+                )
+                .await?;
             let repo = self.repo.clone();
             let target_ref = layer.ostree_ref.clone();
             let import_task =
@@ -847,7 +1097,17 @@ impl ImageImporter {
                     Ok::<_, anyhow::Error>(commit)
                 })
                 .map_err(|e| e.context(format!("Layer {}", layer.layer.digest())));
-            let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
+            let commit = self
+                .import_with_prefetch(
+                    prefetch_dir.as_ref(),
+                    &import.proxy_img,
+                    &import.manifest,
+                    &mut to_fetch,
+                    &mut spooled,
+                    des_layers.as_ref(),
+                    super::unencapsulate::join_fetch(import_task, driver),
+                )
+                .await?;
             layer.commit = commit;
             if let Some(p) = self.layer_progress.as_ref() {
                 p.send(ImportProgress::OstreeChunkCompleted(layer.layer.clone()))
@@ -861,16 +1121,15 @@ impl ImageImporter {
                 ))
                 .await?;
             }
-            let (blob, driver, media_type) = fetch_layer(
-                &self.proxy,
-                &import.proxy_img,
-                &import.manifest,
-                &commit_layer.layer,
-                self.layer_byte_progress.as_ref(),
-                des_layers.as_ref(),
-                self.imgref.imgref.transport,
-            )
-            .await?;
+            let (blob, driver, media_type) = self
+                .fetch_layer_or_spooled(
+                    &mut spooled,
+                    &import.proxy_img,
+                    &import.manifest,
+                    &commit_layer.layer,
+                    des_layers.as_ref(),
+                )
+                .await?;
             let repo = self.repo.clone();
             let target_ref = commit_layer.ostree_ref.clone();
             let import_task =
@@ -949,7 +1208,7 @@ impl ImageImporter {
         // there to label all following layers.
         self.unencapsulate_base(&mut import, false, true).await?;
         let des_layers = self.proxy.get_layer_info(&import.proxy_img).await?;
-        let proxy = self.proxy;
+        let prefetch_dir = self.prefetch_dir()?;
         let target_imgref = self.target_imgref.as_ref().unwrap_or(&self.imgref);
         let base_commit = import
             .ostree_commit_layer
@@ -971,7 +1230,20 @@ impl ImageImporter {
         let mut layer_commits = Vec::new();
         let mut layer_filtered_content: Option<MetaFilteredData> = None;
         let have_derived_layers = !import.layers.is_empty();
+        let squash_candidates = import
+            .layers
+            .iter()
+            .map(|l| (l.layer.digest().to_string(), l.layer.size()))
+            .collect::<Vec<_>>();
         tracing::debug!("Processing layers: {}", import.layers.len());
+        let to_fetch = import
+            .layers
+            .iter()
+            .filter(|l| l.commit.is_none())
+            .map(|l| l.layer.clone())
+            .collect::<Vec<_>>();
+        let mut to_fetch = to_fetch.iter().skip(1);
+        let mut spooled = Vec::new();
         for layer in import.layers {
             if let Some(c) = layer.commit {
                 tracing::debug!("Reusing fetched commit {}", c);
@@ -981,16 +1253,15 @@ impl ImageImporter {
                     p.send(ImportProgress::DerivedLayerStarted(layer.layer.clone()))
                         .await?;
                 }
-                let (blob, driver, media_type) = super::unencapsulate::fetch_layer(
-                    &proxy,
-                    &import.proxy_img,
-                    &import.manifest,
-                    &layer.layer,
-                    self.layer_byte_progress.as_ref(),
-                    des_layers.as_ref(),
-                    self.imgref.imgref.transport,
-                )
-                .await?;
+                let (blob, driver, media_type) = self
+                    .fetch_layer_or_spooled(
+                        &mut spooled,
+                        &import.proxy_img,
+                        &import.manifest,
+                        &layer.layer,
+                        des_layers.as_ref(),
+                    )
+                    .await?;
                 // An important aspect of this is that we SELinux label the derived layers using
                 // the base policy.
                 let opts = crate::tar::WriteTarOptions {
@@ -1006,7 +1277,16 @@ impl ImageImporter {
                     layer.ostree_ref.as_str(),
                     Some(opts),
                 );
-                let r = super::unencapsulate::join_fetch(r, driver)
+                let r = self
+                    .import_with_prefetch(
+                        prefetch_dir.as_ref(),
+                        &import.proxy_img,
+                        &import.manifest,
+                        &mut to_fetch,
+                        &mut spooled,
+                        des_layers.as_ref(),
+                        super::unencapsulate::join_fetch(r, driver),
+                    )
                     .await
                     .with_context(|| format!("Parsing layer blob {}", layer.layer.digest()))?;
                 tracing::debug!("Imported layer: {}", r.commit.as_str());
@@ -1039,6 +1319,7 @@ impl ImageImporter {
 
         // TODO change the imageproxy API to ensure this happens automatically when
         // the image reference is dropped
+        let proxy = self.proxy;
         proxy.close_image(&import.proxy_img).await?;
 
         // We're done with the proxy, make sure it didn't have any errors.
@@ -1072,6 +1353,7 @@ impl ImageImporter {
             .unwrap_or_else(|| chrono::offset::Utc::now().timestamp() as u64);
         // Destructure to transfer ownership to thread
         let repo = self.repo;
+        let squash_layers = self.squash_layers;
         let mut state = crate::tokio_util::spawn_blocking_cancellable_flatten(
             move |cancellable| -> Result<Box<LayeredImageState>> {
                 use rustix::fd::AsRawFd;
@@ -1080,6 +1362,12 @@ impl ImageImporter {
                 let repo = &repo;
                 let txn = repo.auto_transaction(cancellable)?;
 
+                let layer_commits = if let Some(max) = squash_layers {
+                    squash_layer_commits(repo, &squash_candidates, layer_commits, max, cancellable)?
+                } else {
+                    layer_commits
+                };
+
                 let devino = ostree::RepoDevInoCache::new();
                 let repodir = Dir::reopen_dir(&repo.dfd_borrow())?;
                 let repo_tmp = repodir.open_dir("tmp")?;
@@ -1611,6 +1899,7 @@ pub async fn export(
         dest_imgref,
         authfile,
         Some((std::sync::Arc::new(tempdir.try_clone()?.into()), target_fd)),
+        None,
         opts.progress_to_stdout,
     )
     .await
@@ -1670,6 +1959,116 @@ pub fn gc_image_layers(repo: &ostree::Repo) -> Result<u32> {
 }
 
 #[context("Pruning image layers")]
+/// Partition derived layers into groups which are unioned into a single commit:
+/// runs of consecutive layers which are `eligible` for squashing are split into
+/// groups of equal size, such that there are at most `max` groups if possible.
+fn squash_groups(eligible: &[bool], max: NonZeroUsize) -> Vec<std::ops::Range<usize>> {
+    let n = eligible.len();
+    let per_group = n.div_ceil(max.get()).max(1);
+    let mut r = Vec::new();
+    let mut i = 0;
+    while i < n {
+        let mut end = i + 1;
+        if eligible[i] {
+            while end < n && eligible[end] && end - i < per_group {
+                end += 1;
+            }
+        }
+        r.push(i..end);
+        i = end;
+    }
+    r
+}
+
+/// Returns true if the tree contains whiteouts (`.wh.` files), which can only
+/// be processed in layer order when checking out.
+fn tree_has_whiteouts(dir: &gio::File, cancellable: Option<&gio::Cancellable>) -> Result<bool> {
+    let e = dir.enumerate_children(
+        "standard::name,standard::type",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        cancellable,
+    )?;
+    for info in e.clone() {
+        let info = &info?;
+        if info.name().to_string_lossy().starts_with(".wh.") {
+            return Ok(true);
+        }
+        if info.file_type() == gio::FileType::Directory
+            && tree_has_whiteouts(&e.child(info), cancellable)?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Compute the ref for the commit squashing the given layers.
+fn ref_for_squashed_layers(digests: &[String]) -> Result<String> {
+    let digest = openssl::sha::sha256(digests.join("\n").as_bytes());
+    refescape::prefix_escape_for_ref(SQUASHED_PREFIX, &format!("sha256:{}", hex::encode(digest)))
+}
+
+/// Replace groups of consecutive small derived layer commits with a single
+/// commit unioning them; see [`ImageImporter::set_squash_layers`]. The
+/// `layers` are the digest and size of each layer.
+fn squash_layer_commits(
+    repo: &ostree::Repo,
+    layers: &[(String, u64)],
+    commits: Vec<String>,
+    max: NonZeroUsize,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<Vec<String>> {
+    if commits.len() <= max.get() {
+        return Ok(commits);
+    }
+    let eligible = layers
+        .iter()
+        .zip(commits.iter())
+        .map(|((_, size), commit)| {
+            if *size > SQUASH_MAX_LAYER_SIZE {
+                return Ok(false);
+            }
+            let root = repo.read_commit(commit, cancellable)?.0;
+            Ok(!tree_has_whiteouts(&root, cancellable)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut r = Vec::new();
+    for group in squash_groups(&eligible, max) {
+        if group.len() == 1 {
+            r.push(commits[group.start].clone());
+            continue;
+        }
+        let digests = layers[group.clone()]
+            .iter()
+            .map(|(d, _)| d.clone())
+            .collect::<Vec<_>>();
+        let squashed_ref = ref_for_squashed_layers(&digests)?;
+        if let Some(commit) = repo.resolve_rev(&squashed_ref, true)? {
+            tracing::debug!("Reusing squashed commit {commit}");
+            r.push(commit.to_string());
+            continue;
+        }
+        let mt = ostree::MutableTree::new();
+        for commit in &commits[group.clone()] {
+            let root = repo.read_commit(commit, cancellable)?.0;
+            repo.write_directory_to_mtree(&root, &mt, None, cancellable)
+                .with_context(|| format!("Squashing layer {commit}"))?;
+        }
+        let root = repo.write_mtree(&mt, cancellable)?;
+        let root = root.downcast::<ostree::RepoFile>().unwrap();
+        let mut metadata = BTreeMap::new();
+        metadata.insert(META_SQUASHED_LAYERS, digests.to_variant());
+        let metadata = metadata.to_variant();
+        let commit = repo
+            .write_commit_with_time(None, None, None, Some(&metadata), &root, 0, cancellable)
+            .context("Writing squashed commit")?;
+        tracing::debug!("Squashed {} layers into {commit}", group.len());
+        repo.transaction_set_ref(None, &squashed_ref, Some(commit.as_str()));
+        r.push(commit.to_string());
+    }
+    Ok(r)
+}
+
 fn gc_image_layers_impl(
     repo: &ostree::Repo,
     cancellable: Option<&gio::Cancellable>,
@@ -1691,6 +2090,26 @@ fn gc_image_layers_impl(
         }
     }
     tracing::debug!("Referenced layers: {}", referenced_layers.len());
+    let mut pruned = 0u32;
+    // Squashed commits are pruned along with any of the layers they contain
+    let squashed = repo.list_refs_ext(
+        Some(SQUASHED_PREFIX),
+        ostree::RepoListRefsExtFlags::empty(),
+        cancellable,
+    )?;
+    for (squashed_ref, commit) in squashed {
+        let commit = repo.load_commit(&commit)?.0;
+        let commit_meta = &glib::VariantDict::new(Some(&commit.child_value(0)));
+        let digests = commit_meta
+            .lookup::<Vec<String>>(META_SQUASHED_LAYERS)?
+            .unwrap_or_default();
+        if !digests.is_empty() && digests.iter().all(|d| referenced_layers.contains(d)) {
+            continue;
+        }
+        pruned += 1;
+        tracing::debug!("Pruning: {}", squashed_ref.as_str());
+        repo.set_ref_immediate(None, squashed_ref.as_str(), None, cancellable)?;
+    }
     let found_layers = repo
         .list_refs_ext(
             Some(LAYER_PREFIX),
@@ -1700,7 +2119,6 @@ fn gc_image_layers_impl(
         .into_iter()
         .map(|v| v.0);
     tracing::debug!("Found layers: {}", found_layers.len());
-    let mut pruned = 0u32;
     for layer_ref in found_layers {
         let layer_digest = refescape::unprefix_unescape_ref(LAYER_PREFIX, &layer_ref)?;
         if referenced_layers.remove(layer_digest.as_str()) {
@@ -2059,4 +2477,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_squash_groups() {
+        let max = |n| NonZeroUsize::new(n).unwrap();
+        // Few enough layers
+        assert_eq!(squash_groups(&[true, true], max(2)), [0..1, 1..2]);
+        assert_eq!(squash_groups(&[true; 6], max(2)), [0..3, 3..6]);
+        assert_eq!(squash_groups(&[true; 7], max(2)), [0..4, 4..7]);
+        assert_eq!(squash_groups(&[true; 5], max(1)), [0..5]);
+        // Ineligible layers end a group
+        assert_eq!(
+            squash_groups(&[true, true, false, true, true, true], max(3)),
+            [0..2, 2..3, 3..5, 5..6]
+        );
+        assert_eq!(squash_groups(&[false; 3], max(1)), [0..1, 1..2, 2..3]);
+        assert!(squash_groups(&[], max(1)).is_empty());
+    }
+
+    #[test]
+    fn test_ref_for_squashed_layers() -> Result<()> {
+        let a = ref_for_squashed_layers(&["sha256:a".into(), "sha256:b".into()])?;
+        let b = ref_for_squashed_layers(&["sha256:b".into(), "sha256:a".into()])?;
+        assert!(a.starts_with("ostree/container/squashed/sha256_3A_"));
+        assert_ne!(a, b);
+        Ok(())
+    }
 }
@@ -0,0 +1,380 @@
+//! # TLS configuration for registries
+//!
+//! Support for a user-provided CA bundle and client certificate for
+//! talking to registries, e.g. internal registries protected by mutual TLS.
+//!
+//! The containers/image stack expects these in a "certificate directory"
+//! (see `containers-certs.d(5)`); we synthesize one per registry with links to
+//! the configured files. An explicit certificate directory replaces the
+//! per-registry lookup in `/etc/containers/certs.d`, so the synthesized
+//! directory also includes the files found there for the registry.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use serde::Deserialize;
+
+/// The name of the global configuration file, found in the same
+/// locations as `auth.json`.
+pub(crate) const REGISTRY_TLS_CONFIG: &str = "registry-tls.json";
+
+/// TLS configuration for registries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RegistryTlsConfig {
+    /// Path to a PEM bundle of additional certificate authorities to trust.
+    pub ca_bundle: Option<Utf8PathBuf>,
+    /// Path to a PEM client certificate.
+    pub client_cert: Option<Utf8PathBuf>,
+    /// Path to the PEM private key for the client certificate.
+    pub client_key: Option<Utf8PathBuf>,
+}
+
+/// The per-registry certificate directories consulted by the containers/image
+/// stack when no certificate directory is given, in order of precedence.
+const CERTS_D: &[&str] = &["etc/containers/certs.d", "etc/docker/certs.d"];
+
+/// Set by [`set_registry_tls_override`].
+static OVERRIDE: OnceLock<RegistryTlsConfig> = OnceLock::new();
+/// The certificate directories we generated, by registry; these live for the
+/// whole process as each proxy instance may need them.
+static CERTDIRS: Mutex<BTreeMap<String, tempfile::TempDir>> = Mutex::new(BTreeMap::new());
+
+/// The registry host of an image name in the `docker://` transport (which may
+/// be included), e.g. `quay.io` for `quay.io/exampleos/os:latest`.
+pub(crate) fn registry_of(name: &str) -> &str {
+    let name = name.strip_prefix("docker:").unwrap_or(name);
+    let name = name.trim_start_matches("//");
+    match name.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => "docker.io",
+    }
+}
+
+impl RegistryTlsConfig {
+    /// Returns true if nothing is configured.
+    pub fn is_empty(&self) -> bool {
+        self.ca_bundle.is_none() && self.client_cert.is_none() && self.client_key.is_none()
+    }
+
+    /// Apply values set in `other` on top of this configuration.
+    pub fn merge(&mut self, other: RegistryTlsConfig) {
+        if other.ca_bundle.is_some() {
+            self.ca_bundle = other.ca_bundle;
+        }
+        if other.client_cert.is_some() {
+            self.client_cert = other.client_cert;
+        }
+        if other.client_key.is_some() {
+            self.client_key = other.client_key;
+        }
+    }
+
+    /// Check that the configuration is usable.
+    pub fn validate(&self) -> Result<()> {
+        match (self.client_cert.as_ref(), self.client_key.as_ref()) {
+            (Some(_), None) => anyhow::bail!("A client certificate requires a client key"),
+            (None, Some(_)) => anyhow::bail!("A client key requires a client certificate"),
+            _ => {}
+        }
+        for p in [&self.ca_bundle, &self.client_cert, &self.client_key]
+            .into_iter()
+            .flatten()
+        {
+            std::fs::metadata(p).with_context(|| format!("Accessing TLS file {p}"))?;
+        }
+        Ok(())
+    }
+
+    /// Populate a certificate directory for `registry` in the format expected by
+    /// the containers/image stack, starting from the files in the per-registry
+    /// directories of `root`.
+    pub(crate) fn populate_certificate_directory(
+        &self,
+        dir: &Dir,
+        root: &Dir,
+        registry: &str,
+    ) -> Result<()> {
+        for certs_d in CERTS_D {
+            let path = format!("{certs_d}/{registry}");
+            let Some(src) = root.open_dir_optional(&path)? else {
+                continue;
+            };
+            for ent in src.entries()? {
+                let ent = ent?;
+                let name = ent.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if !src.metadata(name)?.is_file() || dir.symlink_metadata_optional(name)?.is_some()
+                {
+                    continue;
+                }
+                let buf = src
+                    .read(name)
+                    .with_context(|| format!("Reading /{path}/{name}"))?;
+                dir.write(name, buf)?;
+            }
+        }
+        // Named so as not to replace the files copied above
+        let files = [
+            (&self.ca_bundle, "registry-tls-ca.crt"),
+            (&self.client_cert, "registry-tls.cert"),
+            (&self.client_key, "registry-tls.key"),
+        ];
+        for (src, name) in files {
+            let Some(src) = src else {
+                continue;
+            };
+            let src = src
+                .canonicalize_utf8()
+                .with_context(|| format!("Accessing TLS file {src}"))?;
+            dir.symlink_contents(src.as_std_path(), name)
+                .with_context(|| format!("Linking {name}"))?;
+        }
+        Ok(())
+    }
+
+    /// Return a certificate directory for this configuration and `registry`,
+    /// creating it if needed.
+    fn certificate_directory(&self, root: &Dir, registry: &str) -> Result<Utf8PathBuf> {
+        let mut dirs = CERTDIRS.lock().unwrap();
+        if let Some(d) = dirs.get(registry) {
+            return Ok(Utf8PathBuf::try_from(d.path().to_owned())?);
+        }
+        let td = tempfile::Builder::new()
+            .prefix("ostree-ext-certs")
+            .tempdir()?;
+        let d = Dir::open_ambient_dir(td.path(), cap_std_ext::cap_std::ambient_authority())?;
+        self.populate_certificate_directory(&d, root, registry)?;
+        let path = Utf8PathBuf::try_from(td.path().to_owned())?;
+        dirs.insert(registry.to_owned(), td);
+        Ok(path)
+    }
+}
+
+/// Configure the proxy to use the effective TLS configuration for the registry
+/// of `imgref`, unless a certificate directory was already set explicitly.
+pub(crate) fn apply_registry_tls(
+    config: &mut containers_image_proxy::ImageProxyConfig,
+    imgref: &super::ImageReference,
+) -> Result<()> {
+    if imgref.transport != super::Transport::Registry || config.certificate_directory.is_some() {
+        return Ok(());
+    }
+    let root = &Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    if let Some(d) = registry_certificate_directory(root, &imgref.name)? {
+        config.certificate_directory = Some(d.into());
+    }
+    Ok(())
+}
+
+/// Set a process-wide TLS configuration (e.g. from the command line), which takes
+/// precedence over the global configuration file. This may only be called once.
+pub fn set_registry_tls_override(config: RegistryTlsConfig) -> Result<()> {
+    config.validate()?;
+    OVERRIDE
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("Registry TLS configuration already set"))
+}
+
+/// Load the effective TLS configuration: the global configuration file,
+/// with any process-wide override applied on top.
+pub(crate) fn get_registry_tls(root: &Dir) -> Result<RegistryTlsConfig> {
+    let mut r = RegistryTlsConfig::default();
+    if let Some((path, f)) = crate::globals::get_global_registry_tls(root)? {
+        r = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("Parsing {path}"))?;
+    }
+    if let Some(o) = OVERRIDE.get() {
+        r.merge(o.clone());
+    }
+    Ok(r)
+}
+
+/// Return the certificate directory for the effective TLS configuration and the
+/// registry of `image` (in the `docker://` transport), if any is configured.
+/// This is intended for tools other than the image proxy (e.g. `podman pull --cert-dir`).
+pub fn registry_certificate_directory(root: &Dir, image: &str) -> Result<Option<Utf8PathBuf>> {
+    let tls = get_registry_tls(root)?;
+    if tls.is_empty() {
+        return Ok(None);
+    }
+    tls.validate()?;
+    tls.certificate_directory(root, registry_of(image))
+        .map(Some)
+}
+
+/// Substrings of errors from the containers/image stack which indicate a TLS failure.
+const TLS_ERRORS: &[&str] = &[
+    "x509:",
+    "tls:",
+    "certificate signed by unknown authority",
+    "remote error: tls",
+];
+
+/// Substrings of errors from the containers/image stack which indicate a certificate
+/// was rejected because of its validity period; these are checked before [`TLS_ERRORS`].
+const VALIDITY_ERRORS: &[&str] = &[
+    "certificate has expired or is not yet valid",
+    "certificate is not yet valid",
+    "certificate has expired",
+];
+
+/// Substrings of errors from the containers/image stack which indicate an authentication failure.
+const AUTH_ERRORS: &[&str] = &["unauthorized", "authentication required", "403 forbidden"];
+
+/// The class of a registry error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryErrorKind {
+    /// The TLS handshake failed, e.g. the CA is unknown or a client certificate is required.
+    Tls,
+    /// A certificate was outside its validity period; either it has expired or
+    /// the system clock is wrong.
+    Validity,
+    /// Authentication or authorization failed.
+    Auth,
+}
+
+impl RegistryErrorKind {
+    /// Classify an error from fetching an image, if possible.
+    pub fn classify(e: &anyhow::Error) -> Option<Self> {
+        let msg = format!("{e:#}").to_lowercase();
+        if VALIDITY_ERRORS.iter().any(|s| msg.contains(s)) {
+            Some(Self::Validity)
+        } else if TLS_ERRORS.iter().any(|s| msg.contains(s)) {
+            Some(Self::Tls)
+        } else if AUTH_ERRORS.iter().any(|s| msg.contains(s)) {
+            Some(Self::Auth)
+        } else {
+            None
+        }
+    }
+
+    /// A hint describing how to resolve the error.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::Tls => "TLS error communicating with the registry; check the CA bundle and client certificate configuration",
+            Self::Validity => "Certificate validity check failed; the certificate has expired or the system clock is wrong",
+            Self::Auth => "Authentication with the registry failed; check the registry credentials",
+        }
+    }
+}
+
+/// Add context to an error from fetching an image distinguishing TLS failures,
+/// certificate validity (possibly clock) failures and authentication failures.
+pub fn annotate_registry_error(e: anyhow::Error) -> anyhow::Error {
+    match RegistryErrorKind::classify(&e) {
+        Some(kind) => e.context(kind.hint()),
+        None => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::{cap_std, cap_tempfile};
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let c: RegistryTlsConfig = serde_json::from_str(
+            r#"{"ca-bundle": "/etc/pki/ca.pem", "client-cert": "/etc/pki/client.pem"}"#,
+        )?;
+        assert_eq!(c.ca_bundle.as_deref().unwrap(), "/etc/pki/ca.pem");
+        assert!(c.validate().is_err());
+        assert!(serde_json::from_str::<RegistryTlsConfig>(r#"{"ca": "/foo"}"#).is_err());
+
+        let mut c = RegistryTlsConfig::default();
+        assert!(c.is_empty());
+        c.validate()?;
+        c.merge(RegistryTlsConfig {
+            ca_bundle: Some("/a".into()),
+            ..Default::default()
+        });
+        c.merge(RegistryTlsConfig {
+            client_key: Some("/b".into()),
+            ..Default::default()
+        });
+        assert_eq!(c.ca_bundle.as_deref().unwrap(), "/a");
+        assert_eq!(c.client_key.as_deref().unwrap(), "/b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry_of() {
+        assert_eq!(registry_of("quay.io/exampleos/os:latest"), "quay.io");
+        assert_eq!(registry_of("docker://localhost:5000/os"), "localhost:5000");
+        assert_eq!(registry_of("localhost/os"), "localhost");
+        assert_eq!(registry_of("library/fedora"), "docker.io");
+        assert_eq!(registry_of("fedora"), "docker.io");
+    }
+
+    #[test]
+    fn test_populate_certificate_directory() -> Result<()> {
+        let td = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        let root = cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        root.create_dir_all("etc/containers/certs.d/quay.io")?;
+        root.write("etc/containers/certs.d/quay.io/ca.crt", "registry ca")?;
+        root.create_dir_all("etc/docker/certs.d/quay.io")?;
+        root.write("etc/docker/certs.d/quay.io/ca.crt", "docker ca")?;
+        root.write("etc/docker/certs.d/quay.io/other.crt", "other ca")?;
+        let src = tempfile::tempdir()?;
+        let src = camino::Utf8Path::from_path(src.path()).unwrap();
+        std::fs::write(src.join("ca.pem"), "ca")?;
+        std::fs::write(src.join("cert.pem"), "cert")?;
+        std::fs::write(src.join("key.pem"), "key")?;
+        let c = RegistryTlsConfig {
+            ca_bundle: Some(src.join("ca.pem")),
+            client_cert: Some(src.join("cert.pem")),
+            client_key: Some(src.join("key.pem")),
+        };
+        c.validate()?;
+        c.populate_certificate_directory(&td, &root, "quay.io")?;
+        let mut names = td
+            .entries()?
+            .map(|e| Ok(e?.file_name().into_string().unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "ca.crt",
+                "other.crt",
+                "registry-tls-ca.crt",
+                "registry-tls.cert",
+                "registry-tls.key"
+            ]
+        );
+        assert_eq!(td.read_to_string("ca.crt")?, "registry ca");
+        let target = td.read_link_contents("registry-tls.key")?;
+        assert_eq!(std::fs::read_to_string(target)?, "key");
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify() {
+        let e = anyhow::anyhow!("pinging container registry: Get \"https://example.com/v2/\": tls: failed to verify certificate: x509: certificate signed by unknown authority");
+        assert_eq!(
+            RegistryErrorKind::classify(&e),
+            Some(RegistryErrorKind::Tls)
+        );
+        let e = anyhow::anyhow!("reading manifest latest: unauthorized: access to the requested resource is not authorized");
+        assert_eq!(
+            RegistryErrorKind::classify(&e),
+            Some(RegistryErrorKind::Auth)
+        );
+        let e = anyhow::anyhow!("pinging container registry: Get \"https://example.com/v2/\": tls: failed to verify certificate: x509: certificate has expired or is not yet valid: current time 2020-01-01T00:00:00Z is before 2024-05-01T00:00:00Z");
+        assert_eq!(
+            RegistryErrorKind::classify(&e),
+            Some(RegistryErrorKind::Validity)
+        );
+        let e = anyhow::anyhow!("manifest unknown");
+        assert_eq!(RegistryErrorKind::classify(&e), None);
+        let e = annotate_registry_error(anyhow::anyhow!("remote error: tls: certificate required"));
+        assert!(format!("{e:#}").starts_with("TLS error"));
+    }
+}
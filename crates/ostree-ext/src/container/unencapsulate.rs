@@ -38,7 +38,10 @@ use containers_image_proxy::{ImageProxy, OpenedImage};
 use fn_error_context::context;
 use futures_util::{Future, FutureExt};
 use oci_spec::image::{self as oci_image, Digest};
+use std::num::NonZeroU64;
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::{
     io::{AsyncBufRead, AsyncRead},
     sync::watch::{Receiver, Sender},
@@ -93,6 +96,76 @@ impl<T: AsyncRead> AsyncRead for ProgressReader<T> {
     }
 }
 
+/// A bandwidth limit shared by all readers using it.
+#[derive(Debug)]
+pub(crate) struct BandwidthLimiter {
+    bytes_per_sec: NonZeroU64,
+    /// When the bandwidth used so far is paid for
+    next: Mutex<Option<tokio::time::Instant>>,
+}
+
+impl BandwidthLimiter {
+    pub(crate) fn new(bytes_per_sec: NonZeroU64) -> Self {
+        Self {
+            bytes_per_sec,
+            next: Default::default(),
+        }
+    }
+
+    /// Account for `n` bytes which were read, returning the time until which
+    /// reading must pause to stay within the limit.
+    fn reserve(&self, n: usize) -> tokio::time::Instant {
+        let now = tokio::time::Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let start = next.map_or(now, |t| t.max(now));
+        let end = start + Duration::from_secs_f64(n as f64 / self.bytes_per_sec.get() as f64);
+        *next = Some(end);
+        end
+    }
+}
+
+/// A read wrapper which limits the bandwidth; this applies backpressure to
+/// the image proxy, and in turn the network connection.
+#[pin_project::pin_project]
+pub(crate) struct LimitedReader<T> {
+    #[pin]
+    reader: T,
+    limiter: Arc<BandwidthLimiter>,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<T: AsyncRead> LimitedReader<T> {
+    pub(crate) fn new(reader: T, limiter: Arc<BandwidthLimiter>) -> Self {
+        Self {
+            reader,
+            limiter,
+            sleep: None,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for LimitedReader<T> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.project();
+        if let Some(sleep) = this.sleep.as_mut() {
+            std::task::ready!(sleep.as_mut().poll(cx));
+            *this.sleep = None;
+        }
+        let len = buf.filled().len();
+        std::task::ready!(this.reader.poll_read(cx, buf))?;
+        let read = buf.filled().len() - len;
+        if read > 0 {
+            let until = this.limiter.reserve(read);
+            *this.sleep = Some(Box::pin(tokio::time::sleep_until(until)));
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
 async fn fetch_manifest_impl(
     proxy: &mut ImageProxy,
     imgref: &OstreeImageReference,
@@ -103,12 +176,20 @@ async fn fetch_manifest_impl(
     Ok((manifest, oci_image::Digest::from_str(digest.as_str())?))
 }
 
+/// Create a proxy for fetching `imgref` with our default configuration applied.
+async fn new_proxy(imgref: &OstreeImageReference) -> Result<ImageProxy> {
+    let mut config = Default::default();
+    super::tls::apply_registry_tls(&mut config, &imgref.imgref)?;
+    super::merge_default_container_proxy_opts(&mut config)?;
+    Ok(ImageProxy::new_with_config(config).await?)
+}
+
 /// Download the manifest for a target image and its sha256 digest.
 #[context("Fetching manifest")]
 pub async fn fetch_manifest(
     imgref: &OstreeImageReference,
 ) -> Result<(oci_image::ImageManifest, oci_image::Digest)> {
-    let mut proxy = ImageProxy::new().await?;
+    let mut proxy = new_proxy(imgref).await?;
     fetch_manifest_impl(&mut proxy, imgref).await
 }
 
@@ -121,7 +202,7 @@ pub async fn fetch_manifest_and_config(
     oci_image::Digest,
     oci_image::ImageConfiguration,
 )> {
-    let proxy = ImageProxy::new().await?;
+    let proxy = new_proxy(imgref).await?;
     let oi = &proxy.open_image(&imgref.imgref.to_string()).await?;
     let (digest, manifest) = proxy.fetch_manifest(oi).await?;
     let digest = oci_image::Digest::from_str(&digest)?;
@@ -186,6 +267,7 @@ pub async fn unencapsulate(repo: &ostree::Repo, imgref: &OstreeImageReference) -
 }
 
 /// A wrapper for [`get_blob`] which fetches a layer and decompresses it.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn fetch_layer<'a>(
     proxy: &'a ImageProxy,
     img: &OpenedImage,
@@ -194,6 +276,7 @@ pub(crate) async fn fetch_layer<'a>(
     progress: Option<&'a Sender<Option<store::LayerProgress>>>,
     layer_info: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
     transport_src: Transport,
+    limiter: Option<&Arc<BandwidthLimiter>>,
 ) -> Result<(
     Box<dyn AsyncBufRead + Send + Unpin>,
     impl Future<Output = Result<()>> + 'a,
@@ -224,6 +307,13 @@ pub(crate) async fn fetch_layer<'a>(
     };
 
     let driver = async { driver.await.map_err(Into::into) };
+    let blob: Box<dyn AsyncBufRead + Send + Unpin> = match limiter {
+        Some(limiter) => Box::new(tokio::io::BufReader::new(LimitedReader::new(
+            blob,
+            Arc::clone(limiter),
+        ))),
+        None => Box::new(blob),
+    };
 
     if let Some(progress) = progress {
         let (readprogress, mut readwatch) = ProgressReader::new(blob);
@@ -243,6 +333,22 @@ pub(crate) async fn fetch_layer<'a>(
         let driver = futures_util::future::join(readproxy, driver).map(|r| r.1);
         Ok((reader, Either::Left(driver), media_type))
     } else {
-        Ok((Box::new(blob), Either::Right(driver), media_type))
+        Ok((blob, Either::Right(driver), media_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_limiter() {
+        let limiter = BandwidthLimiter::new(NonZeroU64::new(1000).unwrap());
+        let now = tokio::time::Instant::now();
+        let a = limiter.reserve(500);
+        assert!(a >= now + Duration::from_millis(500));
+        // Reservations accumulate, also across readers
+        let b = limiter.reserve(1000);
+        assert_eq!(b - a, Duration::from_secs(1));
     }
 }
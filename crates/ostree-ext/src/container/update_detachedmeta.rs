@@ -29,7 +29,7 @@ pub async fn update_detached_metadata(
     };
 
     // Full copy of the source image
-    let pulled_digest = skopeo::copy(src, &tempsrc_ref, None, None, false)
+    let pulled_digest = skopeo::copy(src, &tempsrc_ref, None, None, None, false)
         .await
         .context("Creating temporary copy to OCI dir")?;
 
@@ -131,7 +131,7 @@ pub async fn update_detached_metadata(
 
     // Finally, copy the mutated image back to the target.  For chunked images,
     // because we only changed one layer, skopeo should know not to re-upload shared blobs.
-    crate::container::skopeo::copy(&tempsrc_ref, dest, None, None, false)
+    crate::container::skopeo::copy(&tempsrc_ref, dest, None, None, None, false)
         .await
         .context("Copying to destination")
 }
@@ -92,6 +92,14 @@ fn get_global_authfile_impl(root: &RootDir, am_uid0: bool) -> Result<Option<(Utf
     paths.open_file(root, "auth.json")
 }
 
+/// Return the path to the global registry TLS configuration file, if it exists.
+pub(crate) fn get_global_registry_tls(root: &Dir) -> Result<Option<(Utf8PathBuf, File)>> {
+    let root = &RootDir::new(root, ".")?;
+    let am_uid0 = rustix::process::getuid() == rustix::process::Uid::ROOT;
+    let paths = get_config_paths(am_uid0);
+    paths.open_file(root, crate::container::REGISTRY_TLS_CONFIG)
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Read;
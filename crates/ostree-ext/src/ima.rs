@@ -13,7 +13,7 @@ use gvariant::aligned_bytes::TryAsAligned;
 use gvariant::{gv, Marker, Structure};
 use ostree::gio;
 use rustix::fd::BorrowedFd;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::CString;
 use std::fs::File;
 use std::io::Seek;
@@ -284,3 +284,61 @@ pub fn ima_sign(repo: &ostree::Repo, ostree_ref: &str, opts: &ImaOpts) -> Result
     let writer = &mut CommitRewriter::new(repo, opts)?;
     writer.map_commit(ostree_ref)
 }
+
+/// Recursively collect regular files in a dirtree without an IMA signature.
+fn find_unsigned_in_dirtree(
+    repo: &ostree::Repo,
+    checksum: &str,
+    path: &str,
+    signed: &mut HashSet<String>,
+    limit: usize,
+    r: &mut Vec<String>,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let v = &repo.load_variant(ostree::ObjectType::DirTree, checksum)?;
+    let v = v.data_as_bytes();
+    let v = v.try_as_aligned()?;
+    let v = gv_dirtree!().cast(v);
+    let (files, dirs) = v.to_tuple();
+    for file in files {
+        if r.len() >= limit {
+            return Ok(());
+        }
+        let (name, csum) = file.to_tuple();
+        let csum = hex::encode(csum);
+        if signed.contains(&csum) {
+            continue;
+        }
+        let (instream, _, xattrs) = repo.load_file(&csum, cancellable)?;
+        // Symbolic links can't be signed
+        if instream.is_none() || xattrs_to_map(&xattrs).contains_key(IMA_XATTR.as_bytes()) {
+            signed.insert(csum);
+        } else {
+            r.push(format!("{path}/{}", name.to_str()));
+        }
+    }
+    for item in dirs {
+        let (name, contents_csum, _) = item.to_tuple();
+        let path = format!("{path}/{}", name.to_str());
+        find_unsigned_in_dirtree(repo, &hex::encode(contents_csum), &path, signed, limit, r)?;
+    }
+    Ok(())
+}
+
+/// Find up to `limit` regular files in an OSTree commit without an IMA signature,
+/// e.g. to verify the result of [`ima_sign`].
+///
+/// As the composefs image of a deployment is generated from the extended attributes
+/// in the commit, a commit without unsigned files yields a fully signed composefs image.
+#[context("Finding unsigned files in {}", rev)]
+pub fn find_unsigned(repo: &ostree::Repo, rev: &str, limit: usize) -> Result<Vec<String>> {
+    let checksum = repo.require_rev(rev)?;
+    let (commit_v, _) = repo.load_commit(&checksum)?;
+    let commit_bytes = commit_v.data_as_bytes();
+    let commit_bytes = commit_bytes.try_as_aligned()?;
+    let commit = gv_commit!().cast(commit_bytes);
+    let contents = hex::encode(commit.to_tuple().6);
+    let mut r = Vec::new();
+    find_unsigned_in_dirtree(repo, &contents, "", &mut HashSet::new(), limit, &mut r)?;
+    Ok(r)
+}
@@ -19,6 +19,7 @@
 pub use composefs;
 pub use containers_image_proxy;
 pub use containers_image_proxy::oci_spec;
+pub use ocidir;
 pub use ostree;
 pub use ostree::gio;
 pub use ostree::gio::glib;
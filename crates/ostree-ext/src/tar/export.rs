@@ -116,7 +116,6 @@ struct OstreeTarWriter<'a, W: std::io::Write> {
     commit_checksum: &'a str,
     commit_object: glib::Variant,
     out: &'a mut tar::Builder<W>,
-    #[allow(dead_code)]
     options: ExportOptions,
     wrote_initdirs: bool,
     /// True if we're only writing directories
@@ -433,6 +432,9 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
             }
             pax_extensions.push((format!("SCHILY.xattr.{k}"), v));
         }
+        if self.options.reproducible {
+            canonicalize_pax_extensions(&mut pax_extensions);
+        }
         self.out
             .append_pax_extensions(pax_extensions.iter().map(|(k, v)| (k.as_str(), *v)))?;
         Ok(())
@@ -446,6 +448,11 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         let (instream, meta, xattrs) = self.repo.load_file(checksum, gio::Cancellable::NONE)?;
 
         let mut h = tar::Header::new_gnu();
+        if self.options.reproducible {
+            // This is already the default, but be explicit that we never
+            // propagate timestamps.
+            h.set_mtime(0);
+        }
         h.set_uid(meta.attribute_uint32("unix::uid") as u64);
         h.set_gid(meta.attribute_uint32("unix::gid") as u64);
         let mode = meta.attribute_uint32("unix::mode");
@@ -696,9 +703,25 @@ fn impl_export<W: std::io::Write>(
     Ok(())
 }
 
+/// Sort PAX extension records by key, keeping only the last value for each key,
+/// so that the resulting header does not depend on the input order.
+fn canonicalize_pax_extensions<V>(exts: &mut Vec<(String, V)>) {
+    // A stable sort keeps the input order of duplicates, so we can keep the last one.
+    exts.sort_by(|a, b| a.0.cmp(&b.0));
+    exts.reverse();
+    exts.dedup_by(|a, b| a.0 == b.0);
+    exts.reverse();
+}
+
 /// Configuration for tar export.
-#[derive(Debug, PartialEq, Eq, Default)]
-pub struct ExportOptions;
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+#[non_exhaustive]
+pub struct ExportOptions {
+    /// Generate byte-identical output for identical commits, independent of
+    /// the machine doing the export: content is written in a fixed order,
+    /// timestamps are zeroed, and extended attributes are sorted.
+    pub reproducible: bool,
+}
 
 /// Export an ostree commit to an (uncompressed) tar archive stream.
 #[context("Exporting commit")]
@@ -734,7 +757,10 @@ fn write_chunk<W: std::io::Write>(
         .repo
         .read_commit(&writer.commit_checksum, gio::Cancellable::NONE)?
         .0;
-    for (checksum, (_size, paths)) in chunk.into_iter() {
+    for (checksum, (_size, mut paths)) in chunk.into_iter() {
+        if writer.options.reproducible {
+            paths.sort();
+        }
         let (objpath, h) = writer.append_content(checksum.borrow())?;
         for path in paths.iter() {
             let path = path_for_tar_v1(path);
@@ -755,11 +781,9 @@ pub(crate) fn export_chunk<W: std::io::Write>(
     chunk: chunking::ChunkMapping,
     out: &mut tar::Builder<W>,
     create_parent_dirs: bool,
+    options: ExportOptions,
 ) -> Result<()> {
-    // For chunking, we default to format version 1
-    #[allow(clippy::needless_update)]
-    let opts = ExportOptions;
-    let writer = &mut OstreeTarWriter::new(repo, commit, out, opts)?;
+    let writer = &mut OstreeTarWriter::new(repo, commit, out, options)?;
     writer.write_repo_structure()?;
     write_chunk(writer, chunk, create_parent_dirs)
 }
@@ -772,8 +796,8 @@ pub(crate) fn export_final_chunk<W: std::io::Write>(
     remainder: chunking::Chunk,
     out: &mut tar::Builder<W>,
     create_parent_dirs: bool,
+    options: ExportOptions,
 ) -> Result<()> {
-    let options = ExportOptions;
     let writer = &mut OstreeTarWriter::new(repo, commit_checksum, out, options)?;
     // For the final chunk, output the commit object, plus all ostree metadata objects along with
     // the containing directories.
@@ -924,6 +948,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_canonicalize_pax_extensions() {
+        let mut exts = vec![
+            ("SCHILY.xattr.user.b".to_string(), "1"),
+            ("SCHILY.xattr.user.a".to_string(), "2"),
+            ("SCHILY.xattr.user.b".to_string(), "3"),
+            ("SCHILY.xattr.security.ima".to_string(), "4"),
+        ];
+        canonicalize_pax_extensions(&mut exts);
+        assert_eq!(
+            exts,
+            [
+                ("SCHILY.xattr.security.ima".to_string(), "4"),
+                ("SCHILY.xattr.user.a".to_string(), "2"),
+                ("SCHILY.xattr.user.b".to_string(), "3"),
+            ]
+        );
+    }
+
     #[test]
     fn test_denormal_symlink() {
         let normal = ["/", "/usr", "../usr/bin/blah"];
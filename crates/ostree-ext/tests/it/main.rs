@@ -81,6 +81,15 @@ fn test_cli_fns() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_ima_find_unsigned() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let unsigned = ostree_ext::ima::find_unsigned(fixture.srcrepo(), fixture.testref(), 2)?;
+    assert_eq!(unsigned.len(), 2);
+    assert!(unsigned.iter().all(|p| p.starts_with('/')));
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_tar_import_empty() -> Result<()> {
     let fixture = Fixture::new_v1()?;
@@ -111,6 +120,46 @@ async fn test_tar_export_reproducible() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_container_export_reproducible() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let contentmeta = fixture.get_object_meta().context("Computing object meta")?;
+    let contentmeta = ObjectMetaSized::compute_sizes(fixture.srcrepo(), contentmeta)?;
+    let mut layer_digests = Vec::new();
+    for name in ["reproducible1.oci", "reproducible2.oci"] {
+        let path = fixture.path.join(name);
+        let imgref = ImageReference {
+            transport: Transport::OciDir,
+            name: path.to_string(),
+        };
+        let mut opts = ExportOpts::default();
+        opts.max_layers = std::num::NonZeroU32::new(PKGS_V0_LEN as u32);
+        opts.package_contentmeta = Some(&contentmeta);
+        opts.reproducible = true;
+        ostree_ext::container::encapsulate(
+            fixture.srcrepo(),
+            fixture.testref(),
+            &Config::default(),
+            Some(opts),
+            &imgref,
+        )
+        .await?;
+        let d = Dir::open_ambient_dir(&path, cap_std::ambient_authority())?;
+        let d = ocidir::OciDir::open(d)?;
+        let idx = d.read_index()?;
+        let desc = idx.manifests().first().unwrap();
+        let manifest: oci_image::ImageManifest = d.read_json_blob(desc)?;
+        let digests = manifest
+            .layers()
+            .iter()
+            .map(|l| l.digest().to_string())
+            .collect::<Vec<_>>();
+        layer_digests.push(digests);
+    }
+    assert_eq!(layer_digests[0], layer_digests[1]);
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_tar_import_signed() -> Result<()> {
     let fixture = Fixture::new_v1()?;
@@ -1757,6 +1806,70 @@ async fn test_container_zstd_chunked() -> Result<()> {
     test_non_gzip("zstd:chunked").await
 }
 
+/// Implementation of a test case for encapsulating with zstd or zstd:chunked compression
+async fn test_encapsulate_zstd(compression: ostree_ext::container::LayerCompression) -> Result<()> {
+    if !check_skopeo() {
+        return Ok(());
+    }
+    let fixture = Fixture::new_v1()?;
+    let path = &fixture.path.join("zstd-encapsulated.oci");
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: path.to_string(),
+    };
+    let mut opts = ExportOpts::default();
+    opts.layer_compression = compression;
+    ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        fixture.testref(),
+        &Config::default(),
+        Some(opts),
+        &imgref,
+    )
+    .await?;
+
+    let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+    let d = ocidir::OciDir::open(d)?;
+    let idx = d.read_index()?;
+    let desc = idx.manifests().first().unwrap();
+    let manifest: oci_image::ImageManifest = d.read_json_blob(desc).unwrap();
+    let chunked = compression == ostree_ext::container::LayerCompression::ZstdChunked;
+    for layer in manifest.layers() {
+        assert_eq!(layer.media_type(), &oci_image::MediaType::ImageLayerZstd);
+        // The table of contents added by skopeo for zstd:chunked
+        let checksum = layer
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get("io.github.containers.zstd-chunked.manifest-checksum"));
+        assert_eq!(checksum.is_some(), chunked);
+    }
+
+    let imgref = &OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let mut imp = store::ImageImporter::new(fixture.destrepo(), imgref, Default::default()).await?;
+    let prep = match imp.prepare().await.context("Init prep derived")? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let _ = imp.import(prep).await.unwrap();
+
+    Ok(())
+}
+
+/// Test encapsulating directly with zstd compression
+#[tokio::test]
+async fn test_container_encapsulate_zstd() -> Result<()> {
+    test_encapsulate_zstd(ostree_ext::container::LayerCompression::Zstd).await
+}
+
+/// Test encapsulating with zstd:chunked compression, which goes through skopeo
+#[tokio::test]
+async fn test_container_encapsulate_zstd_chunked() -> Result<()> {
+    test_encapsulate_zstd(ostree_ext::container::LayerCompression::ZstdChunked).await
+}
+
 /// Test for https://github.com/ostreedev/ostree-rs-ext/issues/405
 /// We need to handle the case of modified hardlinks into /sysroot
 #[tokio::test]
@@ -1,7 +1,42 @@
 use clap::Parser;
 
+use crate::network::NetworkSelection;
+
 #[derive(Parser)]
 pub(crate) struct Cli {
     /// The bootc container image to install, e.g. quay.io/fedora/fedora-bootc:41
-    pub(crate) bootc_image: String,
+    ///
+    /// This may instead be provided as `bootc_image` in the answers file.
+    pub(crate) bootc_image: Option<String>,
+
+    /// Also offer the public SSH keys of a GitHub (gh:<user>) or GitLab (gl:<user>) user.
+    #[clap(long)]
+    pub(crate) ssh_import_id: Vec<String>,
+
+    /// Don't prompt for which SSH keys to import; instead use the keys from these sources,
+    /// e.g. local:root, sssd:alice or gh:octocat. Use `all` for every source found, or `none`.
+    #[clap(long)]
+    pub(crate) ssh_keys_from: Option<Vec<String>>,
+
+    /// Which NetworkManager connection profiles to preserve in the new system.
+    /// Without this, the profiles to preserve are prompted for, with those using
+    /// a static IP configuration preselected.
+    #[clap(long, value_enum)]
+    pub(crate) preserve_network: Option<NetworkSelection>,
+
+    /// Migrate all local users (other than root) with a login shell to the new system,
+    /// instead of prompting for which to migrate.
+    #[clap(long)]
+    pub(crate) all_users: bool,
+
+    /// Read answers from a TOML file, with the keys `bootc_image`, `ssh_import_id`,
+    /// `ssh_keys_from`, `preserve_network`, `all_users` and `yes`. Command line arguments
+    /// take precedence.
+    #[clap(long)]
+    pub(crate) answers: Option<String>,
+
+    /// Don't prompt for confirmation, and reboot immediately once done. The SSH key
+    /// sources must then be given via `--ssh-keys-from`.
+    #[clap(long, short = 'y')]
+    pub(crate) yes: bool,
 }
@@ -2,6 +2,8 @@ use anyhow::{ensure, Context, Result};
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 
+use crate::network::NetworkSelection;
+
 mod cli;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -10,18 +12,90 @@ pub(crate) struct ReinstallConfig {
     /// The bootc image to install on the system.
     pub(crate) bootc_image: String,
 
+    /// `ssh-import-id` style identifiers whose public keys are also offered, e.g. `gh:octocat`.
+    #[serde(default)]
+    pub(crate) ssh_import_id: Vec<String>,
+
+    /// If set, import the keys from these sources without prompting.
+    #[serde(default)]
+    pub(crate) ssh_keys_from: Option<Vec<String>>,
+
+    /// If set, preserve these network connection profiles without prompting.
+    #[serde(default)]
+    pub(crate) preserve_network: Option<NetworkSelection>,
+
+    /// Migrate all local users without prompting.
+    #[serde(default)]
+    pub(crate) all_users: bool,
+
+    /// Don't prompt for confirmation.
+    #[serde(default)]
+    pub(crate) yes: bool,
+
     /// The raw CLI arguments that were used to invoke the program. None if the config was loaded
     /// from a file.
     #[serde(skip_deserializing)]
     cli_flags: Option<Vec<String>>,
 }
 
+/// Answers provided up front via `--answers`, so the tool can be run by automation.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Answers {
+    bootc_image: Option<String>,
+    #[serde(default)]
+    ssh_import_id: Vec<String>,
+    ssh_keys_from: Option<Vec<String>>,
+    preserve_network: Option<NetworkSelection>,
+    #[serde(default)]
+    all_users: bool,
+    #[serde(default)]
+    yes: bool,
+}
+
 impl ReinstallConfig {
-    pub fn parse_from_cli(cli: cli::Cli) -> Self {
-        Self {
-            bootc_image: cli.bootc_image,
-            cli_flags: Some(std::env::args().collect::<Vec<String>>()),
-        }
+    pub fn parse_from_cli(cli: cli::Cli) -> Result<Self> {
+        let answers = cli
+            .answers
+            .as_deref()
+            .map(|path| {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading answers file {path}"))?;
+                toml::from_str(&contents).with_context(|| format!("parsing answers file {path}"))
+            })
+            .transpose()?
+            .unwrap_or_default();
+        Self::merge(cli, answers, std::env::args().collect())
+    }
+
+    /// Combine the command line with the answers file; the command line takes precedence.
+    fn merge(cli: cli::Cli, answers: Answers, cli_flags: Vec<String>) -> Result<Self> {
+        let config = Self {
+            bootc_image: cli
+                .bootc_image
+                .or(answers.bootc_image)
+                .context("an image to install is required")?,
+            ssh_import_id: if cli.ssh_import_id.is_empty() {
+                answers.ssh_import_id
+            } else {
+                cli.ssh_import_id
+            },
+            ssh_keys_from: cli.ssh_keys_from.or(answers.ssh_keys_from),
+            preserve_network: cli.preserve_network.or(answers.preserve_network),
+            all_users: cli.all_users || answers.all_users,
+            yes: cli.yes || answers.yes,
+            cli_flags: Some(cli_flags),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.yes || self.ssh_keys_from.is_some(),
+            "the SSH key sources (ssh_keys_from) must be given when not prompting"
+        );
+        Ok(())
     }
 
     pub fn load() -> Result<Self> {
@@ -29,13 +103,15 @@ impl ReinstallConfig {
             Ok(config_path) => {
                 ensure_no_cli_args()?;
 
-                serde_yaml::from_slice(
+                let config: Self = serde_yaml::from_slice(
                     &std::fs::read(&config_path)
                         .context("reading BOOTC_REINSTALL_CONFIG file {config_path}")?,
                 )
-                .context("parsing BOOTC_REINSTALL_CONFIG file {config_path}")?
+                .context("parsing BOOTC_REINSTALL_CONFIG file {config_path}")?;
+                config.validate()?;
+                config
             }
-            Err(_) => ReinstallConfig::parse_from_cli(cli::Cli::parse()),
+            Err(_) => ReinstallConfig::parse_from_cli(cli::Cli::parse())?,
         })
     }
 }
@@ -50,3 +126,32 @@ fn ensure_no_cli_args() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_answers() -> Result<()> {
+        let answers: Answers = toml::from_str(indoc::indoc! { r#"
+            bootc_image = "quay.io/fedora/fedora-bootc:41"
+            ssh_keys_from = ["local:root"]
+            preserve_network = "static"
+            yes = true
+        "#})?;
+        let cli = cli::Cli::try_parse_from(["system-reinstall-bootc", "--ssh-keys-from", "none"])?;
+        let config = ReinstallConfig::merge(cli, answers, Vec::new())?;
+        assert_eq!(config.bootc_image, "quay.io/fedora/fedora-bootc:41");
+        assert_eq!(config.ssh_keys_from.unwrap(), ["none"]);
+        assert_eq!(config.preserve_network, Some(NetworkSelection::Static));
+        assert!(config.yes);
+
+        assert!(toml::from_str::<Answers>("image = \"foo\"").is_err());
+        let cli = cli::Cli::try_parse_from(["system-reinstall-bootc"])?;
+        assert!(ReinstallConfig::merge(cli, Answers::default(), Vec::new()).is_err());
+        // Key sources are required when not prompting
+        let cli = cli::Cli::try_parse_from(["system-reinstall-bootc", "--yes", "quay.io/example"])?;
+        assert!(ReinstallConfig::merge(cli, Answers::default(), Vec::new()).is_err());
+        Ok(())
+    }
+}
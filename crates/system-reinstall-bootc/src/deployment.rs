@@ -0,0 +1,97 @@
+//! Helpers for writing configuration into the newly installed deployment.
+
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Escape a tmpfiles.d path or argument, using C-style escapes for anything other
+/// than printable ASCII.
+pub(crate) fn escape_tmpfiles_arg(contents: &[u8]) -> String {
+    let mut r = String::with_capacity(contents.len());
+    for &b in contents {
+        if b.is_ascii_graphic() && b != b'\\' {
+            r.push(b as char);
+        } else {
+            // Writing to a String cannot fail
+            write!(r, "\\x{b:02x}").unwrap();
+        }
+    }
+    r
+}
+
+/// Find the `/etc` of the newly installed deployment in the host root.
+fn find_new_deployment_etc(root: &Path) -> Result<PathBuf> {
+    let mut newest: Option<(std::time::SystemTime, PathBuf)> = None;
+    for stateroot in std::fs::read_dir(root.join("ostree/deploy")).context("reading deployments")? {
+        let Ok(deployments) = std::fs::read_dir(stateroot?.path().join("deploy")) else {
+            continue;
+        };
+        for deployment in deployments {
+            let deployment = deployment?;
+            if !deployment.file_type()?.is_dir() {
+                continue;
+            }
+            let modified = deployment.metadata()?.modified()?;
+            if newest.as_ref().map_or(true, |(t, _)| modified > *t) {
+                newest = Some((modified, deployment.path().join("etc")));
+            }
+        }
+    }
+    newest
+        .map(|(_, etc)| etc)
+        .context("no deployment found after installation")
+}
+
+/// Write a file relative to the `/etc` of the newly installed deployment,
+/// returning its full path.
+pub(crate) fn write_etc(path: &str, contents: impl AsRef<[u8]>, mode: u32) -> Result<PathBuf> {
+    let path = find_new_deployment_etc(Path::new("/"))?.join(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&path)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, contents.as_ref()))
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(path)
+}
+
+/// Create a symbolic link relative to the `/etc` of the newly installed deployment.
+pub(crate) fn symlink_etc(path: &str, target: &str) -> Result<()> {
+    let path = find_new_deployment_etc(Path::new("/"))?.join(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::os::unix::fs::symlink(target, &path)
+        .with_context(|| format!("creating {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escape_tmpfiles_arg() {
+        assert_eq!(escape_tmpfiles_arg(b"a-b.conf"), "a-b.conf");
+        assert_eq!(escape_tmpfiles_arg(b"a b\\c\n"), r"a\x20b\x5cc\x0a");
+    }
+
+    #[test]
+    fn test_find_new_deployment_etc() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert!(find_new_deployment_etc(td.path()).is_err());
+        let deploy = td.path().join("ostree/deploy/default/deploy");
+        std::fs::create_dir_all(deploy.join("abcd.0/etc"))?;
+        std::fs::write(deploy.join("abcd.0.origin"), "")?;
+        assert_eq!(
+            find_new_deployment_etc(td.path())?,
+            deploy.join("abcd.0/etc")
+        );
+        Ok(())
+    }
+}
@@ -6,7 +6,9 @@ use rustix::process::getuid;
 
 mod btrfs;
 mod config;
+mod deployment;
 mod lvm;
+mod network;
 mod podman;
 mod prompt;
 pub(crate) mod users;
@@ -37,9 +39,15 @@ fn run() -> Result<()> {
 
     tracing::trace!("ssh_key_file_path: {}", ssh_key_file_path);
 
-    prompt::get_ssh_keys(ssh_key_file_path)?;
+    prompt::get_ssh_keys(&config, ssh_key_file_path)?;
 
-    prompt::mount_warning()?;
+    let connections = network::get_connections()?;
+    let connections = prompt::select_network_connections(&config, &connections)?;
+
+    let local_users = users::get_local_users()?;
+    let local_users = prompt::select_local_users(&config, &local_users)?;
+
+    prompt::mount_warning(config.yes)?;
 
     let mut reinstall_podman_command =
         podman::reinstall_command(&config.bootc_image, ssh_key_file_path)?;
@@ -52,13 +60,16 @@ fn run() -> Result<()> {
     println!();
     println!("After reboot, the current root will be available in the /sysroot directory. Existing mounts will not be automatically mounted by the bootc system unless they are defined in the bootc image. Some automatic cleanup of the previous root will be performed.");
 
-    prompt::temporary_developer_protection_prompt()?;
+    prompt::temporary_developer_protection_prompt(config.yes)?;
 
     reinstall_podman_command
         .run_inherited_with_cmd_context()
         .context("running reinstall command")?;
 
-    prompt::reboot()?;
+    network::inject(&connections)?;
+    users::inject(&local_users)?;
+
+    prompt::reboot(config.yes)?;
 
     std::process::Command::new("reboot").run_capture_stderr()?;
 
@@ -0,0 +1,182 @@
+//! Preserve the NetworkManager connection profiles of the host, so that a
+//! reinstalled machine keeps e.g. its static IP configuration.
+//!
+//! The selected profiles are copied into the `/etc` of the new deployment,
+//! readable only by root as they may contain secrets such as Wi-Fi passwords.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::deployment::write_etc;
+
+/// NetworkManager keyfile connection profiles.
+const NM_SYSTEM_CONNECTIONS: &str = "/etc/NetworkManager/system-connections";
+/// Legacy ifcfg-rh profiles, which are not migrated.
+const NETWORK_SCRIPTS: &str = "/etc/sysconfig/network-scripts";
+
+/// Which connection profiles to preserve.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum NetworkSelection {
+    /// All connection profiles
+    All,
+    /// Only profiles with a static IPv4 or IPv6 configuration
+    Static,
+    /// No connection profiles
+    None,
+}
+
+/// A NetworkManager keyfile connection profile.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Connection {
+    /// The file name of the profile
+    pub(crate) file_name: String,
+    /// The connection id
+    pub(crate) id: String,
+    /// The connection type, e.g. `ethernet`
+    pub(crate) kind: String,
+    /// Whether an IPv4 or IPv6 address is configured statically
+    pub(crate) static_ip: bool,
+    pub(crate) contents: Vec<u8>,
+}
+
+impl Display for Connection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ip = if self.static_ip {
+            "static"
+        } else {
+            "automatic"
+        };
+        write!(f, "{} ({}, {ip} IP)", self.id, self.kind)
+    }
+}
+
+impl Connection {
+    /// Parse the relevant keys from a keyfile profile.
+    pub(crate) fn parse(file_name: &str, contents: Vec<u8>) -> Self {
+        let text = String::from_utf8_lossy(&contents);
+        let mut section = "";
+        let mut id = None;
+        let mut kind = None;
+        let mut static_ip = false;
+        for line in text.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name;
+                continue;
+            }
+            let Some((k, v)) = line.split_once('=') else {
+                continue;
+            };
+            match (section, k.trim(), v.trim()) {
+                ("connection", "id", v) => id = Some(v.to_owned()),
+                ("connection", "type", v) => kind = Some(v.to_owned()),
+                ("ipv4" | "ipv6", "method", "manual") => static_ip = true,
+                _ => {}
+            }
+        }
+        let file_stem = file_name.strip_suffix(".nmconnection").unwrap_or(file_name);
+        Self {
+            file_name: file_name.to_owned(),
+            id: id.unwrap_or_else(|| file_stem.to_owned()),
+            kind: kind.unwrap_or_else(|| "unknown".to_owned()),
+            static_ip,
+            contents,
+        }
+    }
+}
+
+/// Find the NetworkManager connection profiles of the host, sorted by file name.
+pub(crate) fn get_connections() -> Result<Vec<Connection>> {
+    if Path::new(NETWORK_SCRIPTS).read_dir().is_ok_and(|mut d| {
+        d.any(|e| {
+            e.is_ok_and(|e| {
+                e.file_name()
+                    .to_str()
+                    .is_some_and(|n| n.starts_with("ifcfg-") && n != "ifcfg-lo")
+            })
+        })
+    }) {
+        println!("NOTICE: ifcfg network profiles in {NETWORK_SCRIPTS} are not preserved; use `nmcli connection migrate` to convert them first.");
+    }
+    let entries = match std::fs::read_dir(NM_SYSTEM_CONNECTIONS) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("reading {NM_SYSTEM_CONNECTIONS}")),
+    };
+    let mut connections = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        let contents = std::fs::read(entry.path()).with_context(|| format!("reading {name}"))?;
+        connections.push(Connection::parse(&name, contents));
+    }
+    connections.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(connections)
+}
+
+/// Write the selected connection profiles into the newly installed system.
+pub(crate) fn inject(connections: &[&Connection]) -> Result<()> {
+    if connections.is_empty() {
+        return Ok(());
+    }
+    let etc_relative = NM_SYSTEM_CONNECTIONS.trim_start_matches("/etc/");
+    let mut dir = None;
+    for c in connections {
+        // NetworkManager ignores profiles readable by other users
+        let path = write_etc(
+            &format!("{etc_relative}/{}", c.file_name),
+            &c.contents,
+            0o600,
+        )?;
+        dir = path.parent().map(ToOwned::to_owned);
+    }
+    if let Some(dir) = dir {
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        println!(
+            "Preserved {} network connection profile(s) in {}",
+            connections.len(),
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STATIC: &str = indoc::indoc! { "
+        [connection]
+        id=Wired connection 1
+        type=ethernet
+        interface-name=enp1s0
+
+        [ipv4]
+        address1=192.0.2.10/24,192.0.2.1
+        method=manual
+
+        [ipv6]
+        method=auto
+    "};
+
+    #[test]
+    fn test_parse_connection() {
+        let c = Connection::parse("Wired connection 1.nmconnection", STATIC.into());
+        assert_eq!(c.id, "Wired connection 1");
+        assert_eq!(c.kind, "ethernet");
+        assert!(c.static_ip);
+        assert_eq!(c.to_string(), "Wired connection 1 (ethernet, static IP)");
+
+        let c = Connection::parse("wlan.nmconnection", b"[ipv4]\nmethod=auto\n".to_vec());
+        assert_eq!(c.id, "wlan");
+        assert!(!c.static_ip);
+    }
+}
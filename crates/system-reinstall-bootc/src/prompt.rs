@@ -1,4 +1,6 @@
-use crate::{btrfs, lvm, prompt, users::get_all_users_keys};
+use crate::network::{Connection, NetworkSelection};
+use crate::users::LocalUser;
+use crate::{btrfs, config::ReinstallConfig, lvm, prompt, users::get_all_users_keys};
 use anyhow::{ensure, Context, Result};
 
 use crossterm::event::{self, Event};
@@ -8,14 +10,35 @@ const NO_SSH_PROMPT: &str = "None of the users on this system found have authori
     if your image doesn't use cloud-init or other means to set up users, \
     you may not be able to log in after reinstalling. Do you want to continue?";
 
+/// Select key sources by id (see [`crate::users::UserKeys::id`]) without prompting.
+fn select_users_by_id<'a>(
+    all_users: &'a [crate::users::UserKeys],
+    ids: &[String],
+) -> Result<Vec<&'a crate::users::UserKeys>> {
+    if ids.iter().any(|id| id == "all") {
+        return Ok(all_users.iter().collect());
+    }
+    ids.iter()
+        .filter(|id| id.as_str() != "none")
+        .map(|id| {
+            all_users.iter().find(|u| &u.id() == id).with_context(|| {
+                let available = all_users
+                    .iter()
+                    .map(|u| u.id())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("No SSH keys found for {id} (available: {available})")
+            })
+        })
+        .collect()
+}
+
 fn prompt_single_user(user: &crate::users::UserKeys) -> Result<Vec<&crate::users::UserKeys>> {
     let prompt = indoc::formatdoc! {
-        "Found only one user ({user}) with {num_keys} SSH authorized keys.
+        "Found only one source of SSH keys: {user}.
         Would you like to import its SSH authorized keys
         into the root user on the new bootc system?
         Then you can login as root@ using those keys.",
-        user = user.user,
-        num_keys = user.num_keys(),
     };
     let answer = ask_yes_no(&prompt, true)?;
     Ok(if answer { vec![&user] } else { vec![] })
@@ -24,12 +47,12 @@ fn prompt_single_user(user: &crate::users::UserKeys) -> Result<Vec<&crate::users
 fn prompt_user_selection(
     all_users: &[crate::users::UserKeys],
 ) -> Result<Vec<&crate::users::UserKeys>> {
-    let keys: Vec<String> = all_users.iter().map(|x| x.user.clone()).collect();
+    let keys: Vec<String> = all_users.iter().map(|x| x.to_string()).collect();
 
     // TODO: Handle https://github.com/console-rs/dialoguer/issues/77
     let selected_user_indices: Vec<usize> = dialoguer::MultiSelect::new()
         .with_prompt(indoc::indoc! {
-            "Select which user's SSH authorized keys you want to import into
+            "Select which SSH authorized keys you want to import into
             the root user of the new bootc system.
             Then you can login as root@ using those keys.
             (arrow keys to move, space to select)",
@@ -44,7 +67,77 @@ fn prompt_user_selection(
         .collect())
 }
 
-pub(crate) fn reboot() -> Result<()> {
+/// Select the network connection profiles to preserve, prompting unless
+/// the selection was provided in the configuration. When not prompting,
+/// profiles with a static IP configuration are preserved by default.
+pub(crate) fn select_network_connections<'a>(
+    config: &ReinstallConfig,
+    connections: &'a [Connection],
+) -> Result<Vec<&'a Connection>> {
+    let selection = match config.preserve_network {
+        Some(selection) => selection,
+        None if config.yes => NetworkSelection::Static,
+        None if connections.is_empty() => return Ok(Vec::new()),
+        None => {
+            let items = connections
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>();
+            let defaults = connections.iter().map(|c| c.static_ip).collect::<Vec<_>>();
+            let selected = dialoguer::MultiSelect::new()
+                .with_prompt(indoc::indoc! {
+                    "Select which network connection profiles to preserve
+                    in the new bootc system.
+                    (arrow keys to move, space to select)",
+                })
+                .items(&items)
+                .defaults(&defaults)
+                .interact()?;
+            // Safe indexing because we know the indices are valid
+            return Ok(selected.into_iter().map(|i| &connections[i]).collect());
+        }
+    };
+    Ok(connections
+        .iter()
+        .filter(|c| match selection {
+            NetworkSelection::All => true,
+            NetworkSelection::Static => c.static_ip,
+            NetworkSelection::None => false,
+        })
+        .collect())
+}
+
+/// Select the local users to migrate to the new system, prompting unless
+/// `all_users` or `yes` is set; with only `yes`, no users are migrated.
+pub(crate) fn select_local_users<'a>(
+    config: &ReinstallConfig,
+    users: &'a [LocalUser],
+) -> Result<Vec<&'a LocalUser>> {
+    if config.all_users {
+        return Ok(users.iter().collect());
+    }
+    if config.yes || users.is_empty() {
+        return Ok(Vec::new());
+    }
+    let items = users.iter().map(|u| u.to_string()).collect::<Vec<_>>();
+    let selected = dialoguer::MultiSelect::new()
+        .with_prompt(indoc::indoc! {
+            "Select which local users to create in the new bootc system,
+            with their password, groups and sudo rules.
+            Home directories are created empty from /etc/skel.
+            (arrow keys to move, space to select)",
+        })
+        .items(&items)
+        .interact()?;
+    // Safe indexing because we know the indices are valid
+    Ok(selected.into_iter().map(|i| &users[i]).collect())
+}
+
+pub(crate) fn reboot(yes: bool) -> Result<()> {
+    if yes {
+        println!("Operation complete, rebooting.");
+        return Ok(());
+    }
     let delay_seconds = 10;
     println!(
         "Operation complete, rebooting in {delay_seconds} seconds. Press Ctrl-C to cancel reboot, or press enter to continue immediately.",
@@ -68,11 +161,15 @@ pub(crate) fn reboot() -> Result<()> {
 
 /// Temporary safety mechanism to stop devs from running it on their dev machine. TODO: Discuss
 /// final prompting UX in https://github.com/bootc-dev/bootc/discussions/1060
-pub(crate) fn temporary_developer_protection_prompt() -> Result<()> {
+pub(crate) fn temporary_developer_protection_prompt(yes: bool) -> Result<()> {
     // Print an empty line so that the warning stands out from the rest of the output
     println!();
 
     let prompt = "NOTICE: This will replace the installed operating system and reboot. Are you sure you want to continue?";
+    if yes {
+        println!("{prompt} yes (--yes)");
+        return Ok(());
+    }
     let answer = ask_yes_no(prompt, false)?;
 
     if !answer {
@@ -103,7 +200,7 @@ pub(crate) fn press_enter() {
     }
 }
 
-pub(crate) fn mount_warning() -> Result<()> {
+pub(crate) fn mount_warning(yes: bool) -> Result<()> {
     let mut mounts = btrfs::check_root_siblings()?;
     mounts.extend(lvm::check_root_siblings()?);
 
@@ -114,36 +211,41 @@ pub(crate) fn mount_warning() -> Result<()> {
         for m in mounts {
             println!("{m}");
         }
-        press_enter();
+        if !yes {
+            press_enter();
+        }
     }
 
     Ok(())
 }
 
-/// Gather authorized keys for all user's of the host system
-/// prompt the user to select which users's keys will be imported
-/// into the target system's root user's authorized_keys file
+/// Gather authorized keys for all user's of the host system (and any
+/// requested GitHub/GitLab users), and prompt the user to select which
+/// will be imported into the target system's root user's authorized_keys
+/// file, unless the selection was provided in the configuration.
 ///
 /// The keys are stored in a temporary file which is passed to
 /// the podman run invocation to be used by
 /// `bootc install to-existing-root --root-ssh-authorized-keys`
-pub(crate) fn get_ssh_keys(temp_key_file_path: &str) -> Result<()> {
-    let users = get_all_users_keys()?;
-    if users.is_empty() {
+pub(crate) fn get_ssh_keys(config: &ReinstallConfig, temp_key_file_path: &str) -> Result<()> {
+    let users = get_all_users_keys(&config.ssh_import_id)?;
+    let selected_users = if let Some(ids) = config.ssh_keys_from.as_deref() {
+        select_users_by_id(&users, ids)?
+    } else if users.is_empty() {
         ensure!(
             prompt::ask_yes_no(NO_SSH_PROMPT, false)?,
             "cancelled by user"
         );
 
         return Ok(());
-    }
-
-    let selected_users = if users.len() == 1 {
+    } else if users.len() == 1 {
         prompt_single_user(&users[0])?
     } else {
         prompt_user_selection(&users)?
     };
 
+    // The same key may be found via multiple sources
+    let mut seen = std::collections::HashSet::new();
     let keys = selected_users
         .into_iter()
         .flat_map(|user| &user.authorized_keys)
@@ -155,6 +257,7 @@ pub(crate) fn get_ssh_keys(temp_key_file_path: &str) -> Result<()> {
             key_copy.options = None;
             key_copy.to_key_format() + "\n"
         })
+        .filter(|key| seen.insert(key.clone()))
         .collect::<String>();
 
     tracing::trace!("keys: {:?}", keys);
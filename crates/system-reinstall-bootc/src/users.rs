@@ -1,4 +1,4 @@
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use bootc_utils::CommandRunExt;
 use bootc_utils::PathQuotedDisplay;
 use openssh_keys::PublicKey;
@@ -13,10 +13,20 @@ use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fs::File;
 use std::io::BufReader;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::process::CommandExt;
 use std::process::Command;
 use uzers::os::unix::UserExt;
 
+use crate::deployment::{escape_tmpfiles_arg, symlink_etc, write_etc};
+
+/// Users in /etc/passwd with a login shell and a uid at or above this may have keys.
+const MIN_REGULAR_UID: u32 = 1000;
+/// The overflow uid, used by `nobody`.
+const NOBODY_UID: u32 = 65534;
+/// Queries SSH keys from SSSD (e.g. FreeIPA).
+const SSS_SSH_AUTHORIZEDKEYS: &str = "sss_ssh_authorizedkeys";
+
 fn loginctl_users() -> Result<BTreeSet<String>> {
     let loginctl_raw_output = loginctl_run_compat()?;
 
@@ -46,6 +56,23 @@ fn loginctl_parse(users: Value) -> Result<BTreeSet<String>> {
         .context("error parsing users")
 }
 
+/// Find users with a login shell in the contents of /etc/passwd.
+fn passwd_login_users(passwd: &str) -> BTreeSet<String> {
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let [name, _, uid, _, _, _, shell] = fields.as_slice() else {
+                return None;
+            };
+            let uid: u32 = uid.parse().ok()?;
+            let regular = uid == 0 || (uid >= MIN_REGULAR_UID && uid != NOBODY_UID);
+            let login = !(shell.ends_with("/nologin") || shell.ends_with("/false"));
+            (regular && login).then(|| name.to_string())
+        })
+        .collect()
+}
+
 /// Run `loginctl` with some compatibility maneuvers to get JSON output
 fn loginctl_run_compat() -> Result<Value> {
     let mut command = Command::new("loginctl");
@@ -84,9 +111,34 @@ impl Drop for UidChange {
     }
 }
 
+/// Where a set of SSH keys was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KeySource {
+    /// A local user's authorized keys, as configured for sshd
+    Local,
+    /// A user's keys in SSSD, e.g. from FreeIPA
+    Sssd,
+    /// A GitHub user's public keys
+    GitHub,
+    /// A GitLab user's public keys
+    GitLab,
+}
+
+impl KeySource {
+    fn prefix(&self) -> &'static str {
+        match self {
+            KeySource::Local => "local",
+            KeySource::Sssd => "sssd",
+            KeySource::GitHub => "gh",
+            KeySource::GitLab => "gl",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct UserKeys {
     pub(crate) user: String,
+    pub(crate) source: KeySource,
     pub(crate) authorized_keys: Vec<PublicKey>,
 }
 
@@ -94,19 +146,100 @@ impl UserKeys {
     pub(crate) fn num_keys(&self) -> usize {
         self.authorized_keys.len()
     }
+
+    /// A stable identifier for non-interactive selection, e.g. `local:root` or `gh:octocat`.
+    pub(crate) fn id(&self) -> String {
+        format!("{}:{}", self.source.prefix(), self.user)
+    }
 }
 
 impl Display for UserKeys {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.source {
+            KeySource::Local => "User",
+            KeySource::Sssd => "SSSD user",
+            KeySource::GitHub => "GitHub user",
+            KeySource::GitLab => "GitLab user",
+        };
         write!(
             f,
-            "User {} ({} authorized keys)",
+            "{kind} {} ({} authorized keys)",
             self.user,
             self.num_keys()
         )
     }
 }
 
+/// A forge whose users' public keys can be imported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImportSource {
+    GitHub,
+    GitLab,
+}
+
+impl From<ImportSource> for KeySource {
+    fn from(source: ImportSource) -> Self {
+        match source {
+            ImportSource::GitHub => KeySource::GitHub,
+            ImportSource::GitLab => KeySource::GitLab,
+        }
+    }
+}
+
+/// Parse an `ssh-import-id` style identifier, e.g. `gh:octocat`.
+pub(crate) fn parse_import_id(id: &str) -> Result<(ImportSource, &str)> {
+    let (prefix, user) = id
+        .split_once(':')
+        .with_context(|| format!("Invalid import id {id}: expected gh:<user> or gl:<user>"))?;
+    let source = match prefix {
+        "gh" => ImportSource::GitHub,
+        "gl" => ImportSource::GitLab,
+        o => anyhow::bail!("Unsupported import id source {o} in {id}"),
+    };
+    ensure!(
+        !user.is_empty()
+            && user
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')),
+        "Invalid user name in import id {id}"
+    );
+    Ok((source, user))
+}
+
+/// Fetch the public keys of a GitHub or GitLab user.
+fn get_keys_from_import_id(source: ImportSource, user: &str) -> Result<Vec<PublicKey>> {
+    let url = match source {
+        ImportSource::GitHub => format!("https://github.com/{user}.keys"),
+        ImportSource::GitLab => format!("https://gitlab.com/{user}.keys"),
+    };
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--proto", "=https", &url])
+        .run_get_output()
+        .with_context(|| format!("fetching {url}"))?;
+    let keys = PublicKey::read_keys(output).with_context(|| format!("parsing {url}"))?;
+    Ok(keys)
+}
+
+/// Query SSSD for a user's keys; returns nothing if SSSD isn't in use.
+fn get_keys_from_sssd(user: &str) -> Result<Vec<PublicKey>> {
+    if which::which(SSS_SSH_AUTHORIZEDKEYS).is_err() {
+        return Ok(Vec::new());
+    }
+    let output = match Command::new(SSS_SSH_AUTHORIZEDKEYS)
+        .arg(user)
+        .run_get_output()
+    {
+        Ok(o) => o,
+        Err(e) => {
+            // Not every user is known to SSSD
+            tracing::debug!("Skipping SSSD keys for user {user}: {e:#}");
+            return Ok(Vec::new());
+        }
+    };
+    Ok(PublicKey::read_keys(output)?)
+}
+
 #[derive(Debug)]
 struct SshdConfig<'a> {
     authorized_keys_files: Vec<&'a str>,
@@ -185,8 +318,12 @@ fn get_keys_from_command(command: &str, command_user: &str) -> Result<Vec<Public
     Ok(keys)
 }
 
-pub(crate) fn get_all_users_keys() -> Result<Vec<UserKeys>> {
-    let loginctl_user_names = loginctl_users().context("enumerate users")?;
+/// Find SSH keys from all local users (those with a session, and those in /etc/passwd
+/// with a login shell), SSSD, and the provided `ssh-import-id` style identifiers.
+pub(crate) fn get_all_users_keys(import_ids: &[String]) -> Result<Vec<UserKeys>> {
+    let mut user_names = loginctl_users().context("enumerate users")?;
+    let passwd = std::fs::read_to_string("/etc/passwd").context("reading /etc/passwd")?;
+    user_names.extend(passwd_login_users(&passwd));
 
     let mut all_users_authorized_keys = Vec::new();
 
@@ -199,7 +336,7 @@ pub(crate) fn get_all_users_keys() -> Result<Vec<UserKeys>> {
     let sshd_config = SshdConfig::parse(sshd_output.as_str())?;
     tracing::debug!("parsed sshd config: {:?}", sshd_config);
 
-    for user_name in loginctl_user_names {
+    for user_name in user_names {
         let user_info = uzers::get_user_by_name(user_name.as_str())
             .context(format!("user {} not found", user_name))?;
 
@@ -222,6 +359,15 @@ pub(crate) fn get_all_users_keys() -> Result<Vec<UserKeys>> {
             .to_str()
             .context("user name is not valid utf-8")?;
 
+        let sssd_keys = get_keys_from_sssd(user_name)?;
+        if !sssd_keys.is_empty() {
+            all_users_authorized_keys.push(UserKeys {
+                user: user_name.to_string(),
+                source: KeySource::Sssd,
+                authorized_keys: sssd_keys,
+            });
+        }
+
         if user_authorized_keys.is_empty() {
             tracing::debug!(
                 "Skipping user {} because it has no SSH authorized_keys",
@@ -232,6 +378,7 @@ pub(crate) fn get_all_users_keys() -> Result<Vec<UserKeys>> {
 
         let user_keys = UserKeys {
             user: user_name.to_string(),
+            source: KeySource::Local,
             authorized_keys: user_authorized_keys,
         };
 
@@ -244,9 +391,312 @@ pub(crate) fn get_all_users_keys() -> Result<Vec<UserKeys>> {
         all_users_authorized_keys.push(user_keys);
     }
 
+    for id in import_ids {
+        let (source, user) = parse_import_id(id)?;
+        let keys = get_keys_from_import_id(source, user)?;
+        ensure!(!keys.is_empty(), "No SSH keys found for {id}");
+        all_users_authorized_keys.push(UserKeys {
+            user: user.to_string(),
+            source: source.into(),
+            authorized_keys: keys,
+        });
+    }
+
     Ok(all_users_authorized_keys)
 }
 
+/// Written in the `/etc` of the new deployment to create migrated users.
+const SYSUSERS_CONF: &str = "sysusers.d/system-reinstall-bootc-users.conf";
+/// Written in the `/etc` of the new deployment to create home directories and sudoers drop-ins;
+/// removed by [`FIRSTBOOT_UNIT`] once applied.
+const USERS_TMPFILES_CONF: &str = "tmpfiles.d/system-reinstall-bootc-users.conf";
+/// Passes the password hashes of migrated users to systemd-sysusers as credentials.
+const SYSUSERS_CREDENTIALS_CONF: &str =
+    "systemd/system/systemd-sysusers.service.d/50-system-reinstall-bootc.conf";
+/// Holds the password hashes of migrated users until the first boot, readable only by root.
+const CREDENTIALS_DIR: &str = "system-reinstall-bootc/credentials";
+/// Runs once on the first boot to set up home directories and remove the credentials.
+const FIRSTBOOT_UNIT: &str = "system-reinstall-bootc-users.service";
+const SUDOERS_D: &str = "/etc/sudoers.d";
+const SKEL: &str = "/etc/skel";
+
+/// A local user of the host which may be migrated to the new system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LocalUser {
+    pub(crate) name: String,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    /// The name of the primary group
+    pub(crate) group: String,
+    pub(crate) gecos: String,
+    pub(crate) home: String,
+    pub(crate) shell: String,
+    /// The password hash from /etc/shadow, if a password is set
+    pub(crate) password_hash: Option<String>,
+    /// Supplementary groups, e.g. `wheel`
+    pub(crate) groups: Vec<String>,
+    /// Drop-ins in /etc/sudoers.d granting this user privileges, by file name
+    pub(crate) sudoers: Vec<(String, Vec<u8>)>,
+}
+
+impl Display for LocalUser {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (uid {}", self.name, self.uid)?;
+        if !self.groups.is_empty() {
+            write!(f, ", groups {}", self.groups.join(","))?;
+        }
+        if self.password_hash.is_none() {
+            write!(f, ", no password")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Parse the regular users (excluding root) with a login shell from the contents
+/// of /etc/passwd, /etc/group and /etc/shadow.
+fn parse_local_users(passwd: &str, group: &str, shadow: &str) -> Vec<LocalUser> {
+    let groups = group
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let [name, _, gid, members] = fields.as_slice() else {
+                return None;
+            };
+            Some((*name, gid.parse::<u32>().ok()?, *members))
+        })
+        .collect::<Vec<_>>();
+    let hashes = shadow
+        .lines()
+        .filter_map(|line| {
+            let (name, rest) = line.split_once(':')?;
+            let hash = rest.split(':').next()?;
+            // Skip empty, locked-without-password (`!`, `!!`, `*`) and similar entries
+            hash.contains('$').then_some((name, hash))
+        })
+        .collect::<BTreeMap<_, _>>();
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let [name, _, uid, gid, gecos, home, shell] = fields.as_slice() else {
+                return None;
+            };
+            let uid: u32 = uid.parse().ok()?;
+            let gid: u32 = gid.parse().ok()?;
+            let regular = uid >= MIN_REGULAR_UID && uid != NOBODY_UID;
+            let login = !(shell.ends_with("/nologin") || shell.ends_with("/false"));
+            if !(regular && login) {
+                return None;
+            }
+            let group = groups
+                .iter()
+                .find(|(_, g, _)| *g == gid)
+                .map(|(n, _, _)| n.to_string())
+                .unwrap_or_else(|| name.to_string());
+            let supplementary = groups
+                .iter()
+                .filter(|(n, _, members)| *n != group && members.split(',').any(|m| m == *name))
+                .map(|(n, _, _)| n.to_string())
+                .collect();
+            Some(LocalUser {
+                name: name.to_string(),
+                uid,
+                gid,
+                group,
+                gecos: gecos.to_string(),
+                home: home.to_string(),
+                shell: shell.to_string(),
+                password_hash: hashes.get(name).map(|h| h.to_string()),
+                groups: supplementary,
+                sudoers: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+/// Whether a sudoers drop-in has a rule for the given user.
+fn sudoers_mentions(contents: &str, user: &str) -> bool {
+    contents
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| !line.starts_with('#'))
+        .any(|line| line.split_whitespace().next() == Some(user))
+}
+
+/// Find the local users of the host which may be migrated to the new system.
+pub(crate) fn get_local_users() -> Result<Vec<LocalUser>> {
+    let passwd = std::fs::read_to_string("/etc/passwd").context("reading /etc/passwd")?;
+    let group = std::fs::read_to_string("/etc/group").context("reading /etc/group")?;
+    let shadow = std::fs::read_to_string("/etc/shadow").context("reading /etc/shadow")?;
+    let mut users = parse_local_users(&passwd, &group, &shadow);
+
+    let mut sudoers = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(SUDOERS_D) {
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let Some(name) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            let contents =
+                std::fs::read(entry.path()).with_context(|| format!("reading {name}"))?;
+            sudoers.push((name, contents));
+        }
+    }
+    sudoers.sort();
+    for user in users.iter_mut() {
+        user.sudoers = sudoers
+            .iter()
+            .filter(|(_, c)| sudoers_mentions(&String::from_utf8_lossy(c), &user.name))
+            .cloned()
+            .collect();
+    }
+
+    Ok(users)
+}
+
+/// A sysusers.d snippet creating the users, their primary and supplementary groups.
+fn sysusers_for(users: &[&LocalUser]) -> String {
+    let mut r = String::new();
+    for user in users {
+        let gecos = if user.gecos.is_empty() {
+            "-".to_string()
+        } else {
+            format!("\"{}\"", user.gecos.replace('"', ""))
+        };
+        r.push_str(&format!("g {} {}\n", user.group, user.gid));
+        r.push_str(&format!(
+            "u {} {}:{} {gecos} {} {}\n",
+            user.name, user.uid, user.gid, user.home, user.shell
+        ));
+        for group in &user.groups {
+            r.push_str(&format!("m {} {group}\n", user.name));
+        }
+    }
+    r
+}
+
+/// The name of the credential passing the password hash of a user to systemd-sysusers.
+fn credential_name(user: &LocalUser) -> String {
+    format!("passwd.hashed-password.{}", user.name)
+}
+
+/// A systemd-sysusers.service drop-in loading the password hashes as credentials
+/// from [`CREDENTIALS_DIR`], or `None` if no user has a password.
+fn sysusers_credentials_for(users: &[&LocalUser]) -> Option<String> {
+    let lines = users
+        .iter()
+        .filter(|user| user.password_hash.is_some())
+        .map(|user| {
+            let name = credential_name(user);
+            format!("LoadCredential={name}:/etc/{CREDENTIALS_DIR}/{name}\n")
+        })
+        .collect::<String>();
+    (!lines.is_empty()).then(|| format!("[Service]\n{lines}"))
+}
+
+/// Quote an argument for a systemd unit command line.
+fn quote_unit_arg(arg: &str) -> String {
+    let arg = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{arg}\"")
+}
+
+/// A unit which runs once on the first boot, after the users and their home
+/// directories are created, to give them ownership of their home directories.
+/// It then removes the password hashes, the tmpfiles.d snippet (so deleted
+/// homes and sudoers drop-ins aren't recreated on later boots) and itself.
+fn firstboot_unit_for(users: &[&LocalUser]) -> String {
+    let mut r = indoc::indoc! { "
+        [Unit]
+        Description=Finish migrating users from the previous system
+        After=systemd-sysusers.service systemd-tmpfiles-setup.service
+        Before=systemd-user-sessions.service
+
+        [Service]
+        Type=oneshot
+    " }
+    .to_string();
+    for user in users {
+        let home = quote_unit_arg(&user.home);
+        r.push_str(&format!(
+            "ExecStart=chown -R {}:{} {home}\n",
+            user.uid, user.gid
+        ));
+        r.push_str(&format!("ExecStart=chmod 0700 {home}\n"));
+    }
+    r.push_str(&format!(
+        "ExecStart=rm -rf /etc/{CREDENTIALS_DIR} /etc/{SYSUSERS_CREDENTIALS_CONF} \
+         /etc/{USERS_TMPFILES_CONF} /etc/systemd/system/multi-user.target.wants/{FIRSTBOOT_UNIT} \
+         /etc/systemd/system/{FIRSTBOOT_UNIT}\n"
+    ));
+    r.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+    r
+}
+
+/// A tmpfiles.d snippet creating the home directories from /etc/skel,
+/// and recreating the sudoers drop-ins. Ownership of the home directories
+/// is set once by [`firstboot_unit_for`].
+fn users_tmpfiles_for(users: &[&LocalUser]) -> String {
+    let mut r = String::new();
+    let mut seen = BTreeSet::new();
+    for user in users {
+        let home = escape_tmpfiles_arg(user.home.as_bytes());
+        r.push_str(&format!("C {home} - - - - {SKEL}\n"));
+        for (file_name, contents) in &user.sudoers {
+            // A drop-in may grant privileges to several users
+            if !seen.insert(file_name) {
+                continue;
+            }
+            r.push_str(&format!(
+                "f {SUDOERS_D}/{} 0440 root root - {}\n",
+                escape_tmpfiles_arg(file_name.as_bytes()),
+                escape_tmpfiles_arg(contents)
+            ));
+        }
+    }
+    r
+}
+
+/// Write the configuration creating the selected users into the newly installed system.
+pub(crate) fn inject(users: &[&LocalUser]) -> Result<()> {
+    if users.is_empty() {
+        return Ok(());
+    }
+    write_etc(SYSUSERS_CONF, &sysusers_for(users), 0o644)?;
+    write_etc(USERS_TMPFILES_CONF, &users_tmpfiles_for(users), 0o644)?;
+    if let Some(credentials) = sysusers_credentials_for(users) {
+        for user in users {
+            let Some(hash) = user.password_hash.as_deref() else {
+                continue;
+            };
+            let name = credential_name(user);
+            // The password hashes must only be readable by root
+            let path = write_etc(&format!("{CREDENTIALS_DIR}/{name}"), hash, 0o600)?;
+            if let Some(parent) = path.parent() {
+                std::fs::set_permissions(parent, std::fs::Permissions::from_mode(0o700))?;
+            }
+        }
+        write_etc(SYSUSERS_CREDENTIALS_CONF, &credentials, 0o644)?;
+    }
+    write_etc(
+        &format!("systemd/system/{FIRSTBOOT_UNIT}"),
+        &firstboot_unit_for(users),
+        0o644,
+    )?;
+    symlink_etc(
+        &format!("systemd/system/multi-user.target.wants/{FIRSTBOOT_UNIT}"),
+        &format!("../{FIRSTBOOT_UNIT}"),
+    )?;
+    println!("Migrated {} user(s) to the new system", users.len());
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -261,4 +711,136 @@ mod test {
         assert!(result.contains("root"));
         assert!(result.contains("foo-doe"));
     }
+
+    #[test]
+    fn test_passwd_login_users() {
+        let passwd = indoc::indoc! { "
+            root:x:0:0:Super User:/root:/bin/bash
+            bin:x:1:1:bin:/bin:/usr/sbin/nologin
+            sshd:x:74:74:Privilege-separated SSH:/usr/share/empty.sshd:/usr/sbin/nologin
+            nobody:x:65534:65534:Kernel Overflow User:/:/bin/sh
+            foo-doe:x:1000:1000::/home/foo-doe:/bin/bash
+            svc:x:1001:1001::/var/lib/svc:/bin/false
+            invalid line
+        " };
+        let users = passwd_login_users(passwd);
+        assert_eq!(users.into_iter().collect::<Vec<_>>(), ["foo-doe", "root"]);
+    }
+
+    #[test]
+    fn test_parse_import_id() {
+        assert_eq!(
+            parse_import_id("gh:octocat").unwrap(),
+            (ImportSource::GitHub, "octocat")
+        );
+        assert_eq!(
+            parse_import_id("gl:foo.bar").unwrap(),
+            (ImportSource::GitLab, "foo.bar")
+        );
+        for invalid in ["octocat", "lp:foo", "gh:", "gh:../foo/bar", "gh:a b"] {
+            assert!(parse_import_id(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    const PASSWD: &str = indoc::indoc! { "
+        root:x:0:0:Super User:/root:/bin/bash
+        sshd:x:74:74:Privilege-separated SSH:/usr/share/empty.sshd:/usr/sbin/nologin
+        foo-doe:x:1000:1000:Foo Doe:/home/foo-doe:/bin/bash
+        bar:x:1001:100::/home/bar:/bin/zsh
+        svc:x:1002:1002::/var/lib/svc:/bin/false
+    " };
+    const GROUP: &str = indoc::indoc! { "
+        root:x:0:
+        wheel:x:10:foo-doe
+        users:x:100:
+        foo-doe:x:1000:
+        docker:x:981:bar,foo-doe
+    " };
+    const SHADOW: &str = indoc::indoc! { "
+        root:!::0:99999:7:::
+        foo-doe:$6$salt$hash:19000:0:99999:7:::
+        bar:!!:19000:0:99999:7:::
+    " };
+
+    #[test]
+    fn test_parse_local_users() {
+        let users = parse_local_users(PASSWD, GROUP, SHADOW);
+        assert_eq!(users.len(), 2);
+        let foo = &users[0];
+        assert_eq!(foo.name, "foo-doe");
+        assert_eq!((foo.uid, foo.gid), (1000, 1000));
+        assert_eq!(foo.group, "foo-doe");
+        assert_eq!(foo.password_hash.as_deref(), Some("$6$salt$hash"));
+        assert_eq!(foo.groups, ["wheel", "docker"]);
+        assert_eq!(foo.to_string(), "foo-doe (uid 1000, groups wheel,docker)");
+        let bar = &users[1];
+        assert_eq!(bar.group, "users");
+        assert_eq!(bar.password_hash, None);
+        assert_eq!(bar.groups, ["docker"]);
+        assert_eq!(
+            bar.to_string(),
+            "bar (uid 1001, groups docker, no password)"
+        );
+    }
+
+    #[test]
+    fn test_sudoers_mentions() {
+        let contents = indoc::indoc! { "
+            # foo-doe ALL=(ALL) ALL
+            Defaults:bar !requiretty
+              bar ALL=(ALL) NOPASSWD: ALL
+        " };
+        assert!(sudoers_mentions(contents, "bar"));
+        assert!(!sudoers_mentions(contents, "foo-doe"));
+    }
+
+    #[test]
+    fn test_users_config() {
+        let mut users = parse_local_users(PASSWD, GROUP, SHADOW);
+        users[1].sudoers = vec![("bar".into(), b"bar ALL=(ALL) ALL\n".to_vec())];
+        let users = users.iter().collect::<Vec<_>>();
+        assert_eq!(
+            sysusers_for(&users),
+            indoc::indoc! { r#"
+                g foo-doe 1000
+                u foo-doe 1000:1000 "Foo Doe" /home/foo-doe /bin/bash
+                m foo-doe wheel
+                m foo-doe docker
+                g users 100
+                u bar 1001:100 - /home/bar /bin/zsh
+                m bar docker
+            "# }
+        );
+        assert_eq!(
+            sysusers_credentials_for(&users).unwrap(),
+            "[Service]\nLoadCredential=passwd.hashed-password.foo-doe:/etc/system-reinstall-bootc/credentials/passwd.hashed-password.foo-doe\n"
+        );
+        assert_eq!(sysusers_credentials_for(&users[1..]), None);
+        assert_eq!(
+            users_tmpfiles_for(&users[1..]),
+            indoc::indoc! { r"
+                C /home/bar - - - - /etc/skel
+                f /etc/sudoers.d/bar 0440 root root - bar\x20ALL=(ALL)\x20ALL\x0a
+            " }
+        );
+        assert_eq!(
+            firstboot_unit_for(&users[1..]),
+            indoc::indoc! { r#"
+                [Unit]
+                Description=Finish migrating users from the previous system
+                After=systemd-sysusers.service systemd-tmpfiles-setup.service
+                Before=systemd-user-sessions.service
+
+                [Service]
+                Type=oneshot
+                ExecStart=chown -R 1001:100 "/home/bar"
+                ExecStart=chmod 0700 "/home/bar"
+                ExecStart=rm -rf /etc/system-reinstall-bootc/credentials /etc/systemd/system/systemd-sysusers.service.d/50-system-reinstall-bootc.conf /etc/tmpfiles.d/system-reinstall-bootc-users.conf /etc/systemd/system/multi-user.target.wants/system-reinstall-bootc-users.service /etc/systemd/system/system-reinstall-bootc-users.service
+
+                [Install]
+                WantedBy=multi-user.target
+            "# }
+        );
+        assert_eq!(quote_unit_arg("/home/a b%$"), r#""/home/a b%%$$""#);
+    }
 }
@@ -0,0 +1,38 @@
+use anyhow::{Context, Result};
+use chrono::Duration;
+
+/// Parse a duration such as `30m`, `6h`, `2d` or `1w`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| anyhow::anyhow!("Missing unit in duration: {s}"))?;
+    let (n, unit) = s.split_at(split);
+    let n: i64 = n
+        .parse()
+        .with_context(|| format!("Parsing duration: {s}"))?;
+    let r = match unit {
+        "s" => Duration::seconds(n),
+        "m" => Duration::minutes(n),
+        "h" => Duration::hours(n),
+        "d" => Duration::days(n),
+        "w" => Duration::weeks(n),
+        o => anyhow::bail!("Unknown unit in duration {s}: {o}"),
+    };
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() -> Result<()> {
+        assert_eq!(parse_duration("30m")?, Duration::minutes(30));
+        assert_eq!(parse_duration("2d")?, Duration::days(2));
+        assert_eq!(parse_duration("1w")?, Duration::days(7));
+        assert!(parse_duration("2").is_err());
+        assert!(parse_duration("2y").is_err());
+        Ok(())
+    }
+}
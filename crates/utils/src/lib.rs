@@ -4,6 +4,8 @@
 //!
 mod command;
 pub use command::*;
+mod duration;
+pub use duration::*;
 mod path;
 pub use path::*;
 mod iterators;